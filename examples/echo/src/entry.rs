@@ -15,7 +15,7 @@
 
 use crate::show::*;
 use reverie_helper::common::local_state::ProcessState;
-use reverie_helper::counter::{note_syscall, NoteInfo};
+use reverie_helper::counter::{note_syscall, sample_syscall, NoteInfo};
 use reverie_helper::syscalls::*;
 
 use reverie_helper::*;
@@ -59,6 +59,10 @@ pub extern "C" fn captured_syscall(
 ) -> i64 {
     let sc = SyscallNo::from(no);
     note_syscall(p, no, NoteInfo::SyscallEntry);
+    // syscalls not sampled are counted only, see `REVERIE_SAMPLING`
+    if !sample_syscall(p, no) {
+        return unsafe { untraced_syscall(no, a0, a1, a2, a3, a4, a5) };
+    }
 
     let tid = unsafe { syscall!(SYS_gettid).unwrap() };
 
@@ -0,0 +1,445 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! architecture of tracees
+//!
+//! registers and instructions the tracer relies on, by `Arch`: `X86_64`
+//! and `Aarch64`, `Host` being the one of the tracer (and tracees).
+//! registers are read and written by `PTRACE_GETREGSET`/`SETREGSET`
+//! (`NT_PRSTATUS`), as of `getregs`/`setregs`.
+//!
+//! on aarch64:
+//!
+//! - syscalls are `svc #0`, the number in `x8`, arguments in `x0`-`x5` and
+//!   the return value in `x0`, the first argument is lost at syscall exit.
+//! - registers are `user_pt_regs` (`UserPtRegs`), without the syscall
+//!   number of `orig_rax`: the syscall of a seccomp stop is changed by
+//!   `Arch::setregs_at_syscall` (`NT_ARM_SYSTEM_CALL`).
+//! - the return address of calls is in `x30`, not on the stack.
+//! - stubs jump with `ldr x17, #8; br x17`, x17 being the scratch register
+//!   of veneers (IP1).
+//!
+//! `TracedTask` (syscall stops, breakpoints, injected calls and syscalls),
+//! the stubs and `remote::try_untraced_syscall` go through `Host`. NB: the
+//! syscall hooks of the preloader (hence syscall patching), `sysemu`, ticks
+//! and the vdso patches are still x86-64 only.
+
+use nix::sys::ptrace;
+use nix::unistd::Pid;
+use std::mem;
+
+/// `NT_PRSTATUS` regset, general purpose registers
+const NT_PRSTATUS: libc::c_int = 1;
+/// `NT_ARM_SYSTEM_CALL` regset, syscall number of aarch64
+const NT_ARM_SYSTEM_CALL: libc::c_int = 0x404;
+
+/// architecture of tracees, see module doc
+pub trait Arch: Sized {
+    /// registers of `NT_PRSTATUS`
+    type Regs: Copy;
+    const NAME: &'static str;
+    /// the syscall instruction
+    const SYSCALL_INSN: &'static [u8];
+    /// the breakpoint instruction
+    const BREAKPOINT_INSN: &'static [u8];
+    /// size of `extended_jump`
+    const EXTENDED_JUMP_SIZE: usize;
+    /// offsets of the syscall sequences at the head of the private page
+    /// (`0x7000_0000`), see `syscall_sequences`: the untraced syscall
+    /// (allowed by the seccomp filter), the traced one, then calls of them
+    /// followed by a breakpoint, to inject syscalls
+    const UNTRACED_SYSCALL: u64;
+    const TRACED_SYSCALL: u64;
+    const CALL_UNTRACED_SYSCALL: u64;
+    const CALL_TRACED_SYSCALL: u64;
+
+    /// syscall number of a syscall stop
+    fn syscall_nr(regs: &Self::Regs) -> u64;
+    /// syscall number of a syscall not entered yet, at the syscall
+    /// instruction
+    fn pending_syscall_nr(regs: &Self::Regs) -> u64;
+    fn syscall_args(regs: &Self::Regs) -> [u64; 6];
+    fn retval(regs: &Self::Regs) -> i64;
+    fn set_retval(regs: &mut Self::Regs, retval: i64);
+    fn pc(regs: &Self::Regs) -> u64;
+    fn set_pc(regs: &mut Self::Regs, pc: u64);
+    fn sp(regs: &Self::Regs) -> u64;
+    /// set registers of syscall `nr` with `args`, to be entered
+    fn set_syscall(regs: &mut Self::Regs, nr: u64, args: &[u64; 6]);
+    /// the pending syscall (see `pending_syscall_nr`) as if entered, i.e.:
+    /// registers of a syscall stop
+    fn enter_syscall(regs: &mut Self::Regs);
+    /// the syscall of a syscall stop pending again, as if not entered
+    fn leave_syscall(regs: &mut Self::Regs);
+    /// the syscall of a syscall stop skipped by the kernel, see
+    /// `setregs_at_syscall`
+    fn skip_syscall(regs: &mut Self::Regs);
+    /// call `entry` with `arg`, returning to `ra`. returns the stack slot
+    /// the return address must be written to, if not in a register.
+    fn set_call(
+        regs: &mut Self::Regs,
+        entry: u64,
+        arg: u64,
+        ra: u64,
+    ) -> Option<u64>;
+
+    /// absolute jump to `target`, from anywhere
+    fn extended_jump(target: u64) -> Vec<u8>;
+    /// code of the syscall sequences, see `UNTRACED_SYSCALL`
+    fn syscall_sequences() -> Vec<u8>;
+
+    /// ip the seccomp filter allows syscalls of, the untraced syscall of
+    /// the private page at `page`
+    fn untraced_syscall_ip(page: u64) -> u64 {
+        page + Self::UNTRACED_SYSCALL + Self::SYSCALL_INSN.len() as u64
+    }
+
+    /// whether `insn` starts with a syscall instruction
+    fn is_syscall_insn(insn: &[u8]) -> bool {
+        insn.starts_with(Self::SYSCALL_INSN)
+    }
+    /// address of the syscall instruction of a syscall stop at `pc`
+    fn syscall_site(pc: u64) -> u64 {
+        pc - Self::SYSCALL_INSN.len() as u64
+    }
+    /// address of the breakpoint of a `SIGTRAP` stop at `pc`
+    fn breakpoint_site(pc: u64) -> u64;
+
+    /// set registers of `tid`, stopped entering a syscall, the syscall
+    /// being changed to `syscall_nr` of `regs`
+    fn setregs_at_syscall(tid: Pid, regs: Self::Regs) -> nix::Result<()> {
+        setregs::<Self>(tid, regs)
+    }
+}
+
+/// registers of `Host`
+pub type HostRegs = <Host as Arch>::Regs;
+
+pub struct X86_64;
+
+#[cfg(target_arch = "x86_64")]
+impl Arch for X86_64 {
+    type Regs = libc::user_regs_struct;
+    const NAME: &'static str = "x86_64";
+    const SYSCALL_INSN: &'static [u8] = &[0x0f, 0x05];
+    // int3
+    const BREAKPOINT_INSN: &'static [u8] = &[0xcc];
+    const EXTENDED_JUMP_SIZE: usize = 14;
+    const UNTRACED_SYSCALL: u64 = 0x0;
+    const TRACED_SYSCALL: u64 = 0x4;
+    const CALL_UNTRACED_SYSCALL: u64 = 0x8;
+    const CALL_TRACED_SYSCALL: u64 = 0x10;
+
+    fn syscall_nr(regs: &Self::Regs) -> u64 {
+        regs.orig_rax
+    }
+    fn pending_syscall_nr(regs: &Self::Regs) -> u64 {
+        regs.rax
+    }
+    fn syscall_args(regs: &Self::Regs) -> [u64; 6] {
+        [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9]
+    }
+    fn retval(regs: &Self::Regs) -> i64 {
+        regs.rax as i64
+    }
+    fn set_retval(regs: &mut Self::Regs, retval: i64) {
+        regs.rax = retval as u64;
+    }
+    fn pc(regs: &Self::Regs) -> u64 {
+        regs.rip
+    }
+    fn set_pc(regs: &mut Self::Regs, pc: u64) {
+        regs.rip = pc;
+    }
+    fn sp(regs: &Self::Regs) -> u64 {
+        regs.rsp
+    }
+    fn breakpoint_site(pc: u64) -> u64 {
+        pc - Self::BREAKPOINT_INSN.len() as u64
+    }
+    fn set_syscall(regs: &mut Self::Regs, nr: u64, args: &[u64; 6]) {
+        regs.orig_rax = nr;
+        regs.rax = nr;
+        regs.rdi = args[0];
+        regs.rsi = args[1];
+        regs.rdx = args[2];
+        regs.r10 = args[3];
+        regs.r8 = args[4];
+        regs.r9 = args[5];
+    }
+    fn enter_syscall(regs: &mut Self::Regs) {
+        regs.orig_rax = regs.rax;
+    }
+    fn leave_syscall(regs: &mut Self::Regs) {
+        regs.rax = regs.orig_rax;
+    }
+    fn skip_syscall(regs: &mut Self::Regs) {
+        regs.orig_rax = -1i64 as u64;
+    }
+    fn set_call(
+        regs: &mut Self::Regs,
+        entry: u64,
+        arg: u64,
+        _ra: u64,
+    ) -> Option<u64> {
+        regs.rsp -= mem::size_of::<u64>() as u64;
+        regs.rip = entry;
+        regs.rdi = arg;
+        Some(regs.rsp)
+    }
+
+    fn extended_jump(target: u64) -> Vec<u8> {
+        // jmp *0x0(%rip); .quad target
+        let mut jump = vec![0xff, 0x25, 0x00, 0x00, 0x00, 0x00];
+        jump.extend_from_slice(&target.to_le_bytes());
+        jump
+    }
+    fn syscall_sequences() -> Vec<u8> {
+        // syscall; retq; nop (x2), callq 0x0 (0x4); int3; xchg %ax,%ax (x2)
+        [
+            0x90c3_050f_90c3_050fu64,
+            0x9066_ccff_ffff_f3e8,
+            0x9066_ccff_ffff_efe8,
+        ]
+        .iter()
+        .flat_map(|word| word.to_le_bytes().to_vec())
+        .collect()
+    }
+}
+
+/// `user_pt_regs` of aarch64
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UserPtRegs {
+    pub regs: [u64; 31],
+    pub sp: u64,
+    pub pc: u64,
+    pub pstate: u64,
+}
+
+pub struct Aarch64;
+
+// instructions of aarch64, little endian
+// ldr x17, #8
+const LDR_X17_8: u32 = 0x5800_0051;
+// br x17
+const BR_X17: u32 = 0xd61f_0220;
+// svc #0
+const SVC_0: u32 = 0xd400_0001;
+// ret
+const RET: u32 = 0xd65f_03c0;
+// bl .-16
+const BL_BACK_16: u32 = 0x97ff_fffc;
+// brk #0
+const BRK_0: u32 = 0xd420_0000;
+
+fn aarch64_code(insns: &[u32]) -> Vec<u8> {
+    insns
+        .iter()
+        .flat_map(|insn| insn.to_le_bytes().to_vec())
+        .collect()
+}
+
+impl Arch for Aarch64 {
+    type Regs = UserPtRegs;
+    const NAME: &'static str = "aarch64";
+    const SYSCALL_INSN: &'static [u8] = &[0x01, 0x00, 0x00, 0xd4];
+    // brk #0
+    const BREAKPOINT_INSN: &'static [u8] = &[0x00, 0x00, 0x20, 0xd4];
+    const EXTENDED_JUMP_SIZE: usize = 16;
+    const UNTRACED_SYSCALL: u64 = 0x0;
+    const TRACED_SYSCALL: u64 = 0x8;
+    const CALL_UNTRACED_SYSCALL: u64 = 0x10;
+    const CALL_TRACED_SYSCALL: u64 = 0x18;
+
+    fn syscall_nr(regs: &Self::Regs) -> u64 {
+        regs.regs[8]
+    }
+    fn pending_syscall_nr(regs: &Self::Regs) -> u64 {
+        regs.regs[8]
+    }
+    fn syscall_args(regs: &Self::Regs) -> [u64; 6] {
+        let mut args = [0; 6];
+        args.copy_from_slice(&regs.regs[0..6]);
+        args
+    }
+    fn retval(regs: &Self::Regs) -> i64 {
+        regs.regs[0] as i64
+    }
+    fn set_retval(regs: &mut Self::Regs, retval: i64) {
+        regs.regs[0] = retval as u64;
+    }
+    fn pc(regs: &Self::Regs) -> u64 {
+        regs.pc
+    }
+    fn set_pc(regs: &mut Self::Regs, pc: u64) {
+        regs.pc = pc;
+    }
+    fn sp(regs: &Self::Regs) -> u64 {
+        regs.sp
+    }
+    // `brk` stops at the breakpoint
+    fn breakpoint_site(pc: u64) -> u64 {
+        pc
+    }
+    fn set_syscall(regs: &mut Self::Regs, nr: u64, args: &[u64; 6]) {
+        regs.regs[8] = nr;
+        regs.regs[0..6].copy_from_slice(args);
+    }
+    // the syscall number stays in `x8`
+    fn enter_syscall(_regs: &mut Self::Regs) {}
+    fn leave_syscall(_regs: &mut Self::Regs) {}
+    fn skip_syscall(regs: &mut Self::Regs) {
+        regs.regs[8] = -1i64 as u64;
+    }
+    fn set_call(
+        regs: &mut Self::Regs,
+        entry: u64,
+        arg: u64,
+        ra: u64,
+    ) -> Option<u64> {
+        regs.pc = entry;
+        regs.regs[0] = arg;
+        regs.regs[30] = ra;
+        None
+    }
+
+    fn setregs_at_syscall(tid: Pid, regs: Self::Regs) -> nix::Result<()> {
+        setregs::<Self>(tid, regs)?;
+        let mut nr = regs.regs[8] as i32;
+        regset(
+            libc::PTRACE_SETREGSET,
+            tid,
+            NT_ARM_SYSTEM_CALL,
+            &mut nr as *mut i32 as *mut libc::c_void,
+            mem::size_of::<i32>(),
+        )
+    }
+
+    fn extended_jump(target: u64) -> Vec<u8> {
+        let mut jump = aarch64_code(&[LDR_X17_8, BR_X17]);
+        jump.extend_from_slice(&target.to_le_bytes());
+        jump
+    }
+    fn syscall_sequences() -> Vec<u8> {
+        let seq = [SVC_0, RET];
+        let call = [BL_BACK_16, BRK_0];
+        aarch64_code(&[seq, seq, call, call].concat())
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+pub type Host = X86_64;
+#[cfg(target_arch = "aarch64")]
+pub type Host = Aarch64;
+
+fn regset(
+    request: libc::c_uint,
+    tid: Pid,
+    set: libc::c_int,
+    base: *mut libc::c_void,
+    len: usize,
+) -> nix::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: base,
+        iov_len: len,
+    };
+    let ret = unsafe {
+        libc::ptrace(
+            request,
+            tid.as_raw(),
+            set as *mut libc::c_void,
+            &mut iov as *mut libc::iovec as *mut libc::c_void,
+        )
+    };
+    nix::errno::Errno::result(ret).map(drop)
+}
+
+/// registers of stopped `tid`
+pub fn getregs<A: Arch>(tid: Pid) -> nix::Result<A::Regs> {
+    // registers are plain integers
+    let mut regs: A::Regs = unsafe { mem::zeroed() };
+    regset(
+        libc::PTRACE_GETREGSET,
+        tid,
+        NT_PRSTATUS,
+        &mut regs as *mut A::Regs as *mut libc::c_void,
+        mem::size_of::<A::Regs>(),
+    )?;
+    Ok(regs)
+}
+
+/// set registers of stopped `tid`
+pub fn setregs<A: Arch>(tid: Pid, regs: A::Regs) -> nix::Result<()> {
+    let mut regs = regs;
+    regset(
+        libc::PTRACE_SETREGSET,
+        tid,
+        NT_PRSTATUS,
+        &mut regs as *mut A::Regs as *mut libc::c_void,
+        mem::size_of::<A::Regs>(),
+    )
+}
+
+/// resume stopped `tid` with registers `regs`
+pub fn cont_with<A: Arch>(tid: Pid, regs: A::Regs) -> nix::Result<()> {
+    setregs::<A>(tid, regs)?;
+    ptrace::cont(tid, None)
+}
+
+#[test]
+fn arch_sanity_check() {
+    // svc #0
+    assert_eq!(Aarch64::SYSCALL_INSN, &0xd400_0001u32.to_le_bytes());
+    assert!(Aarch64::is_syscall_insn(&[0x01, 0x00, 0x00, 0xd4, 0xc0]));
+    // svc #1
+    assert!(!Aarch64::is_syscall_insn(&[0x21, 0x00, 0x00, 0xd4]));
+    assert_eq!(Aarch64::syscall_site(0x1004), 0x1000);
+    assert_eq!(Aarch64::breakpoint_site(0x1000), 0x1000);
+    let jump = Aarch64::extended_jump(0x1234_5678_9abc_def0);
+    assert_eq!(jump.len(), Aarch64::EXTENDED_JUMP_SIZE);
+    assert_eq!(&jump[8..], &0x1234_5678_9abc_def0u64.to_le_bytes());
+    assert_eq!(mem::size_of::<UserPtRegs>(), 34 * 8);
+    let mut regs = UserPtRegs::default();
+    Aarch64::set_syscall(&mut regs, 63, &[3, 0x1000, 16, 0, 0, 0]);
+    assert_eq!(Aarch64::syscall_nr(&regs), 63);
+    assert_eq!(Aarch64::syscall_args(&regs)[1], 0x1000);
+    Aarch64::skip_syscall(&mut regs);
+    assert_eq!(Aarch64::syscall_nr(&regs), -1i64 as u64);
+    assert_eq!(Aarch64::set_call(&mut regs, 0x2000, 7, 0x1004), None);
+    assert_eq!((regs.pc, regs.regs[0], regs.regs[30]), (0x2000, 7, 0x1004));
+    let mut regs: HostRegs = unsafe { mem::zeroed() };
+    Host::set_syscall(&mut regs, 39, &[0; 6]);
+    Host::enter_syscall(&mut regs);
+    assert_eq!(Host::syscall_nr(&regs), 39);
+    Host::set_retval(&mut regs, -38);
+    Host::leave_syscall(&mut regs);
+    assert_eq!(Host::pending_syscall_nr(&regs), 39);
+    assert_eq!(Host::extended_jump(0).len(), Host::EXTENDED_JUMP_SIZE);
+    let seq = Aarch64::syscall_sequences();
+    let at = |offset: u64| &seq[offset as usize..];
+    assert!(Aarch64::is_syscall_insn(at(Aarch64::UNTRACED_SYSCALL)));
+    assert!(Aarch64::is_syscall_insn(at(Aarch64::TRACED_SYSCALL)));
+    assert!(at(Aarch64::CALL_UNTRACED_SYSCALL)[4..]
+        .starts_with(Aarch64::BREAKPOINT_INSN));
+    assert_eq!(Aarch64::untraced_syscall_ip(0x7000_0000), 0x7000_0004);
+    #[cfg(target_arch = "x86_64")]
+    {
+        let seq = X86_64::syscall_sequences();
+        let at = |offset: u64| &seq[offset as usize..];
+        assert!(X86_64::is_syscall_insn(at(X86_64::UNTRACED_SYSCALL)));
+        assert!(X86_64::is_syscall_insn(at(X86_64::TRACED_SYSCALL)));
+        assert_eq!(X86_64::untraced_syscall_ip(0x7000_0000), 0x7000_0002);
+    }
+    assert!(Host::is_syscall_insn(Host::SYSCALL_INSN));
+}
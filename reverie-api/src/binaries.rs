@@ -0,0 +1,34 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! binary provenance
+//!
+//! with `TaskEventCB::hash_binaries`, the program each process execs, and
+//! each shared library it loads, is hashed, and reported by a
+//! `BinaryImage` (following the `Exec`, or the `Mapping` of the library),
+//! so that traces can be correlated to exact binary versions.
+
+use std::path::PathBuf;
+
+/// a program, or a shared library, loaded by a process, see
+/// `Event::BinaryLoaded`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BinaryImage {
+    pub path: PathBuf,
+    /// the program exec'ed, rather than a shared library
+    pub main: bool,
+    /// size of the file, in bytes
+    pub size: u64,
+    /// sha256 of the file, in hex, see `fileless::sha256`
+    pub sha256: String,
+}
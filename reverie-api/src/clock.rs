@@ -0,0 +1,94 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! high resolution tracer clock
+//!
+//! all event timestamps are taken from `CLOCK_MONOTONIC_RAW`, which is not
+//! subject to NTP slewing, hence durations are accurate. `ClockCalibration`
+//! maps a raw timestamp back to the clocks visible by the tracee, so that
+//! events can be correlated with external profilers.
+
+use std::time::Duration;
+
+fn clock_nanos(clock: libc::clockid_t) -> u64 {
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+    let r = unsafe { libc::clock_gettime(clock, &mut ts) };
+    assert_eq!(r, 0);
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// nanoseconds since an unspecified point, from `CLOCK_MONOTONIC_RAW`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    /// current time of `CLOCK_MONOTONIC_RAW`
+    pub fn now() -> Self {
+        Timestamp(clock_nanos(libc::CLOCK_MONOTONIC_RAW))
+    }
+    pub fn from_nanos(nanos: u64) -> Self {
+        Timestamp(nanos)
+    }
+    pub fn as_nanos(self) -> u64 {
+        self.0
+    }
+    /// time elapsed from `earlier`, zero if `earlier` is later than `self`
+    pub fn duration_since(self, earlier: Timestamp) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+}
+
+/// correlates tracer time (`CLOCK_MONOTONIC_RAW`) with tracee-visible time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockCalibration {
+    /// tracer raw timestamp when the calibration is taken
+    pub raw: Timestamp,
+    /// `CLOCK_MONOTONIC` (nanoseconds) at `raw`
+    pub monotonic: u64,
+    /// `CLOCK_REALTIME` (nanoseconds since epoch) at `raw`
+    pub realtime: u64,
+}
+
+impl ClockCalibration {
+    /// sample all clocks, the raw clock is sampled twice and the midpoint
+    /// is used to reduce the skew caused by the sampling itself.
+    pub fn now() -> Self {
+        let before = clock_nanos(libc::CLOCK_MONOTONIC_RAW);
+        let monotonic = clock_nanos(libc::CLOCK_MONOTONIC);
+        let realtime = clock_nanos(libc::CLOCK_REALTIME);
+        let after = clock_nanos(libc::CLOCK_MONOTONIC_RAW);
+        ClockCalibration {
+            raw: Timestamp(before + (after - before) / 2),
+            monotonic,
+            realtime,
+        }
+    }
+    /// convert `ts` into tracee `CLOCK_MONOTONIC` nanoseconds
+    pub fn to_monotonic(&self, ts: Timestamp) -> u64 {
+        (self.monotonic as i64 + (ts.0 as i64 - self.raw.0 as i64)) as u64
+    }
+    /// convert `ts` into tracee `CLOCK_REALTIME` nanoseconds
+    pub fn to_realtime(&self, ts: Timestamp) -> u64 {
+        (self.realtime as i64 + (ts.0 as i64 - self.raw.0 as i64)) as u64
+    }
+}
+
+#[test]
+fn timestamp_sanity_check() {
+    let t0 = Timestamp::now();
+    let calib = ClockCalibration::now();
+    let t1 = Timestamp::now();
+    assert!(t0 <= calib.raw && calib.raw <= t1);
+    assert_eq!(calib.to_monotonic(calib.raw), calib.monotonic);
+    assert_eq!(t0.duration_since(t1), Duration::from_nanos(0));
+}
@@ -0,0 +1,220 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! code coverage of unmodified binaries
+//!
+//! `CoverageSpec` selects the modules (programs or shared libraries) to
+//! cover, and optionally their blocks, by file offset. blocks executed are
+//! collected in a `Coverage`, written in the drcov format (as of DynamoRIO)
+//! understood by coverage explorers, see `Coverage::write_drcov`.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// a block to cover, at `offset` in the file of its module
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Block {
+    pub offset: u64,
+    pub size: u16,
+}
+
+/// modules to cover, see `TaskEventCB::coverage`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CoverageSpec {
+    /// modules, by path or by file name
+    pub modules: Vec<String>,
+    /// blocks of modules (as in `modules`), function entries otherwise
+    pub blocks: HashMap<String, Vec<Block>>,
+}
+
+impl CoverageSpec {
+    pub fn new(modules: Vec<String>) -> Self {
+        CoverageSpec {
+            modules,
+            blocks: HashMap::new(),
+        }
+    }
+
+    /// whether `path` is a module to cover
+    pub fn is_selected(&self, path: &Path) -> bool {
+        self.modules.iter().any(|module| {
+            let module = Path::new(module);
+            if module.is_absolute() {
+                path == module
+            } else {
+                path.file_name() == Some(module.as_os_str())
+            }
+        })
+    }
+
+    /// blocks listed of module `path`, `None` if not listed
+    pub fn blocks_of(&self, path: &Path) -> Option<&[Block]> {
+        let name = path.file_name().and_then(|name| name.to_str());
+        path.to_str()
+            .and_then(|path| self.blocks.get(path))
+            .or_else(|| self.blocks.get(name?))
+            .map(|blocks| blocks.as_slice())
+    }
+
+    /// add blocks of a block list: `MODULE OFFSET [SIZE]` per line, with
+    /// offsets in hex. `#` starts a comment.
+    pub fn add_blocks(&mut self, list: &str) -> io::Result<()> {
+        let invalid = |line: &str| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid block: {:?}", line),
+            )
+        };
+        for line in list.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (module, offset, size) = match fields.as_slice() {
+                [module, offset] => (module, offset, "1"),
+                [module, offset, size] => (module, offset, *size),
+                _ => return Err(invalid(line)),
+            };
+            let offset =
+                u64::from_str_radix(offset.trim_start_matches("0x"), 16);
+            let size = u16::from_str_radix(size.trim_start_matches("0x"), 16);
+            let (offset, size) = match (offset, size) {
+                (Ok(offset), Ok(size)) => (offset, size),
+                _ => return Err(invalid(line)),
+            };
+            let blocks = self.blocks.entry(module.to_string()).or_default();
+            blocks.push(Block { offset, size });
+        }
+        Ok(())
+    }
+}
+
+/// a module covered, mapped at `base` up to `end`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverageModule {
+    pub path: PathBuf,
+    pub base: u64,
+    pub end: u64,
+}
+
+/// blocks executed, by module
+#[derive(Debug, Default)]
+pub struct Coverage {
+    modules: Vec<CoverageModule>,
+    /// `(module, offset from its base, size)`
+    blocks: BTreeSet<(u16, u32, u16)>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// id of module `path`, mapped at `base` up to `end` the first time
+    pub fn module(&mut self, path: &Path, base: u64, end: u64) -> u16 {
+        if let Some(id) = self.modules.iter().position(|m| m.path == path) {
+            return id as u16;
+        }
+        self.modules.push(CoverageModule {
+            path: path.to_path_buf(),
+            base,
+            end,
+        });
+        (self.modules.len() - 1) as u16
+    }
+
+    /// block of `size` at `offset` from the base of `module` executed
+    pub fn hit(&mut self, module: u16, offset: u32, size: u16) {
+        self.blocks.insert((module, offset, size));
+    }
+
+    pub fn modules(&self) -> &[CoverageModule] {
+        &self.modules
+    }
+
+    /// number of blocks executed
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// write coverage as a drcov (version 2) log to `out`
+    pub fn write_drcov<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "DRCOV VERSION: 2")?;
+        writeln!(out, "DRCOV FLAVOR: drcov")?;
+        writeln!(out, "Module Table: version 2, count {}", self.modules.len())?;
+        writeln!(
+            out,
+            "Columns: id, base, end, entry, checksum, timestamp, path"
+        )?;
+        for (id, module) in self.modules.iter().enumerate() {
+            writeln!(
+                out,
+                "{:3}, {:#018x}, {:#018x}, {:#018x}, {:#010x}, {:#010x}, {}",
+                id,
+                module.base,
+                module.end,
+                0,
+                0,
+                0,
+                module.path.display()
+            )?;
+        }
+        writeln!(out, "BB Table: {} bbs", self.blocks.len())?;
+        for (module, offset, size) in &self.blocks {
+            out.write_all(&offset.to_le_bytes())?;
+            out.write_all(&size.to_le_bytes())?;
+            out.write_all(&module.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn coverage_sanity_check() {
+    let mut spec = CoverageSpec::new(vec![String::from("libc.so.6")]);
+    let list = "# offsets in libc\nlibc.so.6 0x1000\nlibc.so.6 2000 10\n";
+    spec.add_blocks(list).unwrap();
+    assert!(spec.add_blocks("libc.so.6 xyz").is_err());
+    let libc = Path::new("/lib/x86_64-linux-gnu/libc.so.6");
+    assert!(spec.is_selected(libc));
+    assert!(!spec.is_selected(Path::new("/bin/true")));
+    let blocks = spec.blocks_of(libc).unwrap();
+    assert_eq!(
+        blocks[1],
+        Block {
+            offset: 0x2000,
+            size: 0x10
+        }
+    );
+
+    let mut coverage = Coverage::new();
+    let id = coverage.module(libc, 0x7000_0000, 0x7020_0000);
+    assert_eq!(coverage.module(libc, 0, 0), id);
+    coverage.hit(id, 0x1000, 1);
+    coverage.hit(id, 0x1000, 1);
+    assert_eq!(coverage.len(), 1);
+    let mut log = Vec::new();
+    coverage.write_drcov(&mut log).unwrap();
+    let header = b"BB Table: 1 bbs\n";
+    let at = log.len() - 8 - header.len();
+    assert_eq!(&log[at..at + header.len()], header);
+    assert_eq!(&log[log.len() - 8..], &[0, 0x10, 0, 0, 1, 0, 0, 0]);
+    let text = String::from_utf8_lossy(&log);
+    assert!(text.contains("  0, 0x0000000070000000, 0x0000000070200000"));
+}
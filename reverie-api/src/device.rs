@@ -0,0 +1,1374 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! virtual devices: fds backed by the tool
+//!
+//! `open("/dev/reverie/<name>")` (or `socket`, if the tool provides
+//! sockets) returns a virtual fd, which no file of the kernel backs: `read`,
+//! `write`, `readv`, `writev`, `ioctl` and `close` of it are emulated by
+//! the tool's `VirtualDevice`, see `VirtualDevices`. so are `fstat`,
+//! `lseek` (`ESPIPE`), `mmap` (`ENODEV`) and the file status flags of
+//! `fcntl`.
+//!
+//! virtual fds are backed by real eventfds of the tracee, which reserve
+//! their numbers, and which the tracer signals while the device is
+//! readable (`VirtualDevice::poll`): readiness syscalls (`poll`, `select`,
+//! `epoll_wait`, ...) are left to the kernel, and block in the tracee, not
+//! the tracer. readiness is re-evaluated after each syscall emulated, of
+//! any process: the tracer holds a duplicate of each eventfd, by
+//! `pidfd_getfd` (linux 5.6).
+//!
+//! real sockets `connect`ed (or `sendto`) an address claimed by a connect
+//! handler are taken over by a virtual device: the kernel never sees the
+//! connection, nor any traffic of the socket after. `recvfrom` and
+//! `sendmmsg` of virtual fds are emulated too. opens of redirected paths
+//! open another file instead, see `VirtualDevices::redirect`.
+//!
+//! virtual fds passed by `sendmsg` (`SCM_RIGHTS`) over real unix sockets
+//! pass their eventfd: the fd table of the process receiving it is
+//! updated at `recvmsg` exit (by `kcmp`), so that it shares the virtual
+//! device.
+//!
+//! the kernel runs `dup`, `dup2`, `dup3` and `F_DUPFD` of virtual fds,
+//! duplicating the eventfd: the fd table is updated at syscall exit, as it
+//! is copied to the child at `fork` (or `clone` without `CLONE_FILES`) exit
+//! of the parent. after `exec`, virtual fds the kernel closed, i.e.:
+//! `O_CLOEXEC` ones, are dropped (by `kcmp`).
+//!
+//! NB: virtual fds are always writable (`POLLOUT`), as eventfds.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use nix::unistd::Pid;
+use syscalls::SyscallNo;
+
+use crate::emulate::*;
+use crate::guest::*;
+use crate::remote::{syscall_retval, SyscallArgs};
+
+/// directory of virtual devices
+pub const VIRTUAL_DEVICE_DIR: &str = "/dev/reverie";
+
+// syscalls of pidfds, linux 5.3 and 5.6
+const SYS_PIDFD_OPEN: libc::c_long = 434;
+const SYS_PIDFD_GETFD: libc::c_long = 438;
+
+// `kcmp` type comparing files
+const KCMP_FILE: libc::c_long = 0;
+
+/// syscalls to emulate for virtual devices
+pub const VIRTUAL_DEVICE_SYSCALLS: &[SyscallNo] = &[
+    SyscallNo::SYS_open,
+    SyscallNo::SYS_openat,
+    SyscallNo::SYS_socket,
+    SyscallNo::SYS_read,
+    SyscallNo::SYS_write,
+    SyscallNo::SYS_readv,
+    SyscallNo::SYS_writev,
+    SyscallNo::SYS_ioctl,
+    SyscallNo::SYS_connect,
+    SyscallNo::SYS_sendto,
+    SyscallNo::SYS_recvfrom,
+    SyscallNo::SYS_sendmsg,
+    SyscallNo::SYS_recvmsg,
+    SyscallNo::SYS_sendmmsg,
+    SyscallNo::SYS_close,
+    SyscallNo::SYS_dup,
+    SyscallNo::SYS_dup2,
+    SyscallNo::SYS_dup3,
+    SyscallNo::SYS_fcntl,
+    SyscallNo::SYS_fstat,
+    SyscallNo::SYS_lseek,
+    SyscallNo::SYS_mmap,
+    SyscallNo::SYS_fork,
+    SyscallNo::SYS_vfork,
+    SyscallNo::SYS_clone,
+    SyscallNo::SYS_execve,
+    SyscallNo::SYS_execveat,
+];
+
+/// injects syscall `SyscallNo` into the task whose syscall is emulated
+pub type InjectFn<'a> = &'a dyn Fn(SyscallNo, &SyscallArgs) -> i64;
+
+/// a device implemented by the tool, errors are returned to the tracee
+/// as `-errno` (`EIO` unless an os error).
+pub trait VirtualDevice {
+    /// read up to `len` bytes
+    fn read(&mut self, len: usize) -> Result<Vec<u8>>;
+    /// write `buf`, returns number of bytes written
+    fn write(&mut self, buf: &[u8]) -> Result<usize>;
+    /// ready `events` (as of `poll`), always ready by default. only
+    /// `POLLIN` is reflected to the tracee, see module doc
+    fn poll(&mut self, events: i16) -> i16 {
+        events & (libc::POLLIN | libc::POLLOUT)
+    }
+    /// `ioctl` `request`, `memory` is the tracee's for pointer `arg`s
+    fn ioctl(
+        &mut self,
+        _request: u64,
+        _arg: u64,
+        _memory: &dyn TaskMemory,
+    ) -> Result<i64> {
+        Err(Error::from_raw_os_error(libc::ENOTTY))
+    }
+    /// address of the peer (as of `recvfrom`), for sockets
+    fn peer(&self) -> Option<SocketAddr> {
+        None
+    }
+    /// `st_mode` (as of `fstat`), a socket if it has a peer, a character
+    /// device otherwise
+    fn mode(&self) -> u32 {
+        match self.peer() {
+            Some(_) => libc::S_IFSOCK | 0o777,
+            None => libc::S_IFCHR | 0o666,
+        }
+    }
+}
+
+pub type DeviceRef = Rc<RefCell<Box<dyn VirtualDevice>>>;
+
+/// opens device `name` (relative to `VIRTUAL_DEVICE_DIR`), `None` if no
+/// such device.
+pub type DeviceOpenFn = Box<dyn FnMut(&str) -> Option<Box<dyn VirtualDevice>>>;
+
+/// creates a socket of `domain`, `type` and `protocol`, `None` for the
+/// kernel to create it.
+pub type SocketOpenFn =
+    Box<dyn FnMut(i32, i32, i32) -> Option<Box<dyn VirtualDevice>>>;
+
+/// takes over a socket of `type` connecting to `SocketAddr`, `None` for
+/// the kernel to connect it.
+pub type ConnectFn =
+    Box<dyn FnMut(&SocketAddr, i32) -> Option<Box<dyn VirtualDevice>>>;
+
+/// virtual devices, and the virtual fds of traced processes
+pub struct VirtualDevices {
+    open_device: DeviceOpenFn,
+    open_socket: Option<SocketOpenFn>,
+    connect_handlers: Vec<ConnectFn>,
+    redirects: HashMap<PathBuf, PathBuf>,
+    /// installed in the first traced process
+    installed: HashMap<i32, DeviceRef>,
+    fd_tables: HashMap<Pid, HashMap<i32, DeviceRef>>,
+    /// processes using the fd table of another, see `Task::files`
+    shared_files: HashMap<Pid, Pid>,
+    backings: Vec<Backing>,
+}
+
+// eventfd backing the virtual fds of `device`, signaled while `readable`
+struct Backing {
+    device: DeviceRef,
+    eventfd: File,
+    readable: bool,
+}
+
+fn os_error(errno: i32) -> Error {
+    Error::from_raw_os_error(errno)
+}
+
+fn errno_of(err: &Error) -> i64 {
+    -(err.raw_os_error().unwrap_or(libc::EIO) as i64)
+}
+
+fn result_of(res: Result<i64>) -> i64 {
+    res.unwrap_or_else(|err| errno_of(&err))
+}
+
+// result of an injected syscall
+fn check(retval: i64) -> Result<i64> {
+    if retval < 0 && retval > -4096 {
+        Err(os_error(-retval as i32))
+    } else {
+        Ok(retval)
+    }
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(word)
+}
+
+// `fd` of process `pid`, duplicated into the tracer
+fn getfd(pid: Pid, fd: i32) -> Result<File> {
+    let pidfd = unsafe { libc::syscall(SYS_PIDFD_OPEN, pid.as_raw(), 0) };
+    if pidfd < 0 {
+        return Err(Error::last_os_error());
+    }
+    let res = unsafe { libc::syscall(SYS_PIDFD_GETFD, pidfd, fd, 0) };
+    let err = Error::last_os_error();
+    unsafe { libc::close(pidfd as i32) };
+    if res < 0 {
+        Err(err)
+    } else {
+        Ok(unsafe { File::from_raw_fd(res as i32) })
+    }
+}
+
+// whether `fd` of process `pid` is `file` of the tracer
+fn is_same_file(pid: Pid, fd: i32, file: &File) -> bool {
+    let tracer = nix::unistd::getpid().as_raw();
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_kcmp,
+            pid.as_raw(),
+            tracer,
+            KCMP_FILE,
+            fd,
+            file.as_raw_fd(),
+        )
+    };
+    res == 0
+}
+
+// runs `f` with `size` bytes of scratch memory mapped in the tracee, at
+// the address given (if any)
+fn with_scratch<T>(
+    inject: InjectFn,
+    size: usize,
+    f: impl FnOnce(u64) -> Result<T>,
+) -> Result<T> {
+    if size == 0 {
+        return f(0);
+    }
+    let args = SyscallArgs::from(
+        0,
+        size as u64,
+        (libc::PROT_READ | libc::PROT_WRITE) as u64,
+        (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as u64,
+        -1i64 as u64,
+        0,
+    );
+    let addr = check(inject(SyscallNo::SYS_mmap, &args))? as u64;
+    let res = f(addr);
+    let args = SyscallArgs::from(addr, size as u64, 0, 0, 0, 0);
+    inject(SyscallNo::SYS_munmap, &args);
+    res
+}
+
+// `SO_TYPE` of real socket `fd`
+fn socket_type(
+    memory: &dyn TaskMemory,
+    inject: InjectFn,
+    fd: i32,
+) -> Result<i32> {
+    with_scratch(inject, 8, |optval| {
+        let optlen = optval + 4;
+        memory.write_bytes(optlen, &4u32.to_le_bytes())?;
+        let args = SyscallArgs::from(
+            fd as u64,
+            libc::SOL_SOCKET as u64,
+            libc::SO_TYPE as u64,
+            optval,
+            optlen,
+            0,
+        );
+        check(inject(SyscallNo::SYS_getsockopt, &args))?;
+        let bytes = memory.read_bytes(optval, 4)?;
+        Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    })
+}
+
+/// `struct sockaddr_in` or `struct sockaddr_in6` of `len` bytes at `addr`
+pub fn read_sockaddr(
+    memory: &dyn TaskMemory,
+    addr: u64,
+    len: usize,
+) -> Option<SocketAddr> {
+    if addr == 0 {
+        return None;
+    }
+    let bytes = memory.read_bytes(addr, len).ok()?;
+    let family = u16::from_le_bytes([*bytes.first()?, *bytes.get(1)?]);
+    let port = u16::from_be_bytes([*bytes.get(2)?, *bytes.get(3)?]);
+    match family as i32 {
+        libc::AF_INET => {
+            let mut ip = [0u8; 4];
+            ip.copy_from_slice(bytes.get(4..8)?);
+            Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        }
+        libc::AF_INET6 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(bytes.get(8..24)?);
+            Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        }
+        _ => None,
+    }
+}
+
+fn sockaddr_bytes(addr: &SocketAddr) -> Vec<u8> {
+    let family = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let mut bytes = Vec::from(&(family as u16).to_le_bytes()[..]);
+    bytes.extend_from_slice(&addr.port().to_be_bytes());
+    match addr {
+        SocketAddr::V4(addr) => {
+            bytes.extend_from_slice(&addr.ip().octets());
+            bytes.extend_from_slice(&[0; 8]);
+        }
+        SocketAddr::V6(addr) => {
+            bytes.extend_from_slice(&addr.flowinfo().to_be_bytes());
+            bytes.extend_from_slice(&addr.ip().octets());
+            bytes.extend_from_slice(&addr.scope_id().to_le_bytes());
+        }
+    }
+    bytes
+}
+
+// write `addr` to `sockaddr`, truncated to `*addrlen`, which is updated
+fn write_sockaddr(
+    memory: &dyn TaskMemory,
+    sockaddr: u64,
+    addrlen: u64,
+    addr: &SocketAddr,
+) -> Result<()> {
+    let bytes = sockaddr_bytes(addr);
+    let len = memory.read_bytes(addrlen, 4)?;
+    let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    memory.write_bytes(sockaddr, &bytes[..std::cmp::min(len, bytes.len())])?;
+    memory.write_bytes(addrlen, &(bytes.len() as u32).to_le_bytes())
+}
+
+// `struct mmsghdr` is `{ struct msghdr msg_hdr; unsigned msg_len; }` (64
+// bytes), with `msg_iov` and `msg_iovlen` at 16 and 24. messages sent are
+// counted, errors only returned if none was.
+fn send_mmsg(
+    device: &DeviceRef,
+    memory: &dyn TaskMemory,
+    msgvec: u64,
+    vlen: usize,
+) -> Result<i64> {
+    let mut sent = 0;
+    for k in 0..vlen {
+        let mmsghdr = msgvec + 64 * k as u64;
+        let res = memory.read_bytes(mmsghdr, 64).and_then(|hdr| {
+            let iovecs = read_iovecs(
+                memory,
+                u64_at(&hdr, 16),
+                u64_at(&hdr, 24) as usize,
+            )?;
+            let mut bytes = Vec::new();
+            for (base, len) in iovecs {
+                bytes.extend(memory.read_bytes(base, len)?);
+            }
+            let n = device.borrow_mut().write(&bytes)?;
+            memory.write_bytes(mmsghdr + 56, &(n as u32).to_le_bytes())
+        });
+        match res {
+            Ok(()) => sent += 1,
+            Err(_) if sent > 0 => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(sent)
+}
+
+// `recvmsg` of virtual socket: `msg_name` is its peer, no control messages
+// are received.
+fn recv_msg(
+    device: &DeviceRef,
+    memory: &dyn TaskMemory,
+    addr: u64,
+) -> Result<i64> {
+    let msg = libc::msghdr::read(memory, addr)?;
+    let iovecs = read_iovecs(memory, msg.msg_iov as u64, msg.msg_iovlen)?;
+    let len = iovecs.iter().map(|(_, len)| len).sum();
+    let bytes = device.borrow_mut().read(len)?;
+    let mut offset = 0;
+    for (base, len) in iovecs {
+        if offset == bytes.len() {
+            break;
+        }
+        let end = std::cmp::min(offset + len, bytes.len());
+        memory.write_bytes(base, &bytes[offset..end])?;
+        offset = end;
+    }
+    // `msg_namelen` at 8, `msg_controllen` at 40 and `msg_flags` at 48
+    let peer = device.borrow().peer();
+    match peer {
+        Some(peer) if !msg.msg_name.is_null() => {
+            write_sockaddr(memory, msg.msg_name as u64, addr + 8, &peer)?
+        }
+        _ => memory.write_bytes(addr + 8, &0u32.to_le_bytes())?,
+    }
+    memory.write_bytes(addr + 40, &0u64.to_le_bytes())?;
+    memory.write_bytes(addr + 48, &0i32.to_le_bytes())?;
+    Ok(bytes.len() as i64)
+}
+
+/// NUL terminated string at `addr`
+pub fn read_cstring(memory: &dyn TaskMemory, addr: u64) -> Result<String> {
+    const CHUNK: usize = 64;
+    let mut bytes = Vec::new();
+    while bytes.len() < libc::PATH_MAX as usize {
+        let chunk = memory.read_bytes(addr + bytes.len() as u64, CHUNK)?;
+        match chunk.iter().position(|c| *c == 0) {
+            Some(end) => {
+                bytes.extend_from_slice(&chunk[..end]);
+                return String::from_utf8(bytes)
+                    .map_err(|err| Error::new(ErrorKind::InvalidData, err));
+            }
+            None => bytes.extend_from_slice(&chunk),
+        }
+    }
+    Err(os_error(libc::ENAMETOOLONG))
+}
+
+impl VirtualDevices {
+    pub fn new(open_device: DeviceOpenFn) -> Self {
+        VirtualDevices {
+            open_device,
+            open_socket: None,
+            connect_handlers: Vec::new(),
+            redirects: HashMap::new(),
+            installed: HashMap::new(),
+            fd_tables: HashMap::new(),
+            shared_files: HashMap::new(),
+            backings: Vec::new(),
+        }
+    }
+
+    /// set `open_socket` to create virtual sockets
+    pub fn set_socket_handler(&mut self, open_socket: SocketOpenFn) {
+        self.open_socket = Some(open_socket);
+    }
+
+    /// add `connect` handler, to take over sockets connecting to the
+    /// addresses it claims
+    pub fn add_connect_handler(&mut self, connect: ConnectFn) {
+        self.connect_handlers.push(connect);
+    }
+
+    /// open `to` instead of (absolute) `path`, `to` may be a virtual device
+    pub fn redirect<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, path: P, to: Q) {
+        self.redirects
+            .insert(path.as_ref().to_path_buf(), to.as_ref().to_path_buf());
+    }
+
+    /// install `device` as `fd` (i.e.: stdin) of the first traced process,
+    /// and inherited by its children
+    pub fn install(&mut self, fd: i32, device: Box<dyn VirtualDevice>) {
+        self.installed.insert(fd, Rc::new(RefCell::new(device)));
+    }
+
+    /// syscall emulation of virtual devices
+    pub fn into_emulation(self, mode: EmulationMode) -> SyscallEmulation {
+        let devices = Rc::new(RefCell::new(self));
+        let exits = devices.clone();
+        let mut emulation = SyscallEmulation::new(
+            mode,
+            Vec::from(VIRTUAL_DEVICE_SYSCALLS),
+            Box::new(move |task, memory, syscall, args| {
+                let inject = |nr, args: &SyscallArgs| {
+                    syscall_retval(task.inject_syscall(nr, args))
+                };
+                let mut devices = devices.borrow_mut();
+                devices.share_files(task.getpid(), task.files());
+                devices.emulate(
+                    task.getpid(),
+                    task.getppid(),
+                    memory,
+                    &inject,
+                    syscall,
+                    args,
+                )
+            }),
+        );
+        emulation.set_exit_handler(Box::new(
+            move |task, memory, syscall, args, retval| {
+                let mut devices = exits.borrow_mut();
+                devices.share_files(task.getpid(), task.files());
+                devices.syscall_exit(
+                    task.getpid(),
+                    task.getppid(),
+                    memory,
+                    syscall,
+                    args,
+                    retval,
+                )
+            },
+        ));
+        emulation
+    }
+
+    /// process `pid` uses the fd table of process `files`, shared by
+    /// `clone` with `CLONE_FILES`, or its own: a copy, once unshared.
+    pub fn share_files(&mut self, pid: Pid, files: Pid) {
+        if files != pid {
+            self.shared_files.insert(pid, files);
+        } else if let Some(owner) = self.shared_files.remove(&pid) {
+            if let Some(table) = self.fd_tables.get(&owner).cloned() {
+                self.fd_tables.insert(pid, table);
+            }
+        }
+    }
+
+    // fd table of process `pid`, inherited from `ppid` if first seen before
+    // the `fork` exit of its parent, see `forked`
+    fn fd_table(
+        &mut self,
+        pid: Pid,
+        ppid: Pid,
+    ) -> &mut HashMap<i32, DeviceRef> {
+        let pid = self.shared_files.get(&pid).cloned().unwrap_or(pid);
+        if !self.fd_tables.contains_key(&pid) {
+            let table = match self.fd_tables.get(&ppid) {
+                Some(table) => table.clone(),
+                None if self.fd_tables.is_empty() => self.installed.clone(),
+                None => HashMap::new(),
+            };
+            self.fd_tables.insert(pid, table);
+        }
+        self.fd_tables.get_mut(&pid).unwrap()
+    }
+
+    fn device(&mut self, pid: Pid, ppid: Pid, fd: u64) -> Option<DeviceRef> {
+        self.fd_table(pid, ppid).get(&(fd as i32)).cloned()
+    }
+
+    // back `device` by an eventfd of process `pid`, as fd `at` (replacing
+    // it) or the lowest fd free, with `O_CLOEXEC` of `flags`
+    fn back(
+        &mut self,
+        pid: Pid,
+        inject: InjectFn,
+        device: DeviceRef,
+        at: Option<i32>,
+        flags: u64,
+    ) -> Result<i32> {
+        let cloexec = flags & libc::O_CLOEXEC as u64;
+        let nonblock = libc::EFD_NONBLOCK as u64;
+        let args = SyscallArgs::from(0, nonblock | cloexec, 0, 0, 0, 0);
+        let eventfd = check(inject(SyscallNo::SYS_eventfd2, &args))? as i32;
+        let close = |fd: i32| {
+            let args = SyscallArgs::from(fd as u64, 0, 0, 0, 0, 0);
+            inject(SyscallNo::SYS_close, &args);
+        };
+        let fd = match at {
+            Some(at) if at != eventfd => {
+                let args = SyscallArgs::from(
+                    eventfd as u64,
+                    at as u64,
+                    cloexec,
+                    0,
+                    0,
+                    0,
+                );
+                let res = check(inject(SyscallNo::SYS_dup3, &args));
+                close(eventfd);
+                res? as i32
+            }
+            _ => eventfd,
+        };
+        let eventfd = getfd(pid, fd).inspect_err(|_| {
+            if at.is_none() {
+                close(fd);
+            }
+        })?;
+        self.backings.push(Backing {
+            device,
+            eventfd,
+            readable: false,
+        });
+        Ok(fd)
+    }
+
+    // signal the eventfds of devices readable, and reset the others. those
+    // of devices no longer open are closed.
+    fn signal_readiness(&mut self) {
+        self.backings
+            .retain(|backing| Rc::strong_count(&backing.device) > 1);
+        for backing in self.backings.iter_mut() {
+            let revents = backing.device.borrow_mut().poll(libc::POLLIN);
+            let readable = revents & libc::POLLIN != 0;
+            if readable == backing.readable {
+                continue;
+            }
+            let mut count = 1u64.to_le_bytes();
+            let res = if readable {
+                (&backing.eventfd).write(&count)
+            } else {
+                (&backing.eventfd).read(&mut count)
+            };
+            if res.is_ok() {
+                backing.readable = readable;
+            }
+        }
+    }
+
+    // virtual socket connected to `addr` in place of real socket `fd`, if
+    // claimed by a connect handler
+    fn connect(
+        &mut self,
+        pid: Pid,
+        ppid: Pid,
+        memory: &dyn TaskMemory,
+        inject: InjectFn,
+        fd: i32,
+        addr: &SocketAddr,
+    ) -> Option<Result<DeviceRef>> {
+        if self.connect_handlers.is_empty() {
+            return None;
+        }
+        let ty = match socket_type(memory, inject, fd) {
+            Ok(ty) => ty,
+            Err(err) => return Some(Err(err)),
+        };
+        let device = self
+            .connect_handlers
+            .iter_mut()
+            .find_map(|connect| connect(addr, ty))?;
+        let device = Rc::new(RefCell::new(device));
+        // the socket, never connected, is replaced by the eventfd
+        let args =
+            SyscallArgs::from(fd as u64, libc::F_GETFD as u64, 0, 0, 0, 0);
+        let res =
+            check(inject(SyscallNo::SYS_fcntl, &args)).and_then(|fdflags| {
+                let flags = if fdflags & libc::FD_CLOEXEC as i64 != 0 {
+                    libc::O_CLOEXEC as u64
+                } else {
+                    0
+                };
+                self.back(pid, inject, device.clone(), Some(fd), flags)
+            });
+        if let Err(err) = res {
+            return Some(Err(err));
+        }
+        self.fd_table(pid, ppid).insert(fd, device.clone());
+        Some(Ok(device))
+    }
+
+    // virtual fd of `device`, opened with `flags`
+    fn allocate_fd(
+        &mut self,
+        pid: Pid,
+        ppid: Pid,
+        inject: InjectFn,
+        device: Box<dyn VirtualDevice>,
+        flags: u64,
+    ) -> i64 {
+        let device = Rc::new(RefCell::new(device));
+        match self.back(pid, inject, device.clone(), None, flags) {
+            Ok(fd) => {
+                self.fd_table(pid, ppid).insert(fd, device);
+                fd as i64
+            }
+            Err(err) => errno_of(&err),
+        }
+    }
+
+    // `open` of `path` with `flags` and `mode`
+    fn open(
+        &mut self,
+        pid: Pid,
+        ppid: Pid,
+        memory: &dyn TaskMemory,
+        inject: InjectFn,
+        (path, flags, mode): (u64, u64, u64),
+    ) -> Option<i64> {
+        let path = PathBuf::from(read_cstring(memory, path).ok()?);
+        let path = match self.redirects.get(&path) {
+            Some(to) if to.starts_with(VIRTUAL_DEVICE_DIR) => to.clone(),
+            Some(to) => {
+                let mut to = Vec::from(to.as_os_str().as_bytes());
+                to.push(0);
+                let res = with_scratch(inject, to.len(), |scratch| {
+                    memory.write_bytes(scratch, &to)?;
+                    let args = SyscallArgs::from(
+                        libc::AT_FDCWD as u64,
+                        scratch,
+                        flags,
+                        mode,
+                        0,
+                        0,
+                    );
+                    check(inject(SyscallNo::SYS_openat, &args))
+                });
+                return Some(result_of(res));
+            }
+            None => path,
+        };
+        let name = path.strip_prefix(VIRTUAL_DEVICE_DIR).ok()?;
+        let device = name.to_str().and_then(|name| (self.open_device)(name));
+        Some(match device {
+            Some(device) => self.allocate_fd(pid, ppid, inject, device, flags),
+            None => -libc::ENOENT as i64,
+        })
+    }
+
+    /// emulate `syscall` of process `pid` (child of `ppid`), `None` if it
+    /// does not involve virtual devices. `inject` runs syscalls in the
+    /// process, when emulation involves real fds.
+    pub fn emulate(
+        &mut self,
+        pid: Pid,
+        ppid: Pid,
+        memory: &dyn TaskMemory,
+        inject: InjectFn,
+        syscall: SyscallNo,
+        args: &SyscallArgs,
+    ) -> Option<i64> {
+        if self.fd_tables.is_empty() {
+            self.install_backings(pid, ppid, inject);
+        }
+        let res =
+            self.emulate_syscall(pid, ppid, memory, inject, syscall, args);
+        self.signal_readiness();
+        res
+    }
+
+    // back the devices installed, in the first traced process
+    fn install_backings(&mut self, pid: Pid, ppid: Pid, inject: InjectFn) {
+        let installed: Vec<(i32, DeviceRef)> = self
+            .installed
+            .iter()
+            .map(|(fd, device)| (*fd, device.clone()))
+            .collect();
+        for (fd, device) in installed {
+            if self.back(pid, inject, device, Some(fd), 0).is_err() {
+                self.installed.remove(&fd);
+            }
+        }
+        self.fd_table(pid, ppid);
+    }
+
+    fn emulate_syscall(
+        &mut self,
+        pid: Pid,
+        ppid: Pid,
+        memory: &dyn TaskMemory,
+        inject: InjectFn,
+        syscall: SyscallNo,
+        args: &SyscallArgs,
+    ) -> Option<i64> {
+        match syscall {
+            SyscallNo::SYS_open => {
+                let open = (args.arg0, args.arg1, args.arg2);
+                self.open(pid, ppid, memory, inject, open)
+            }
+            SyscallNo::SYS_openat => {
+                let open = (args.arg1, args.arg2, args.arg3);
+                self.open(pid, ppid, memory, inject, open)
+            }
+            SyscallNo::SYS_socket => {
+                let (domain, ty, protocol) =
+                    (args.arg0 as i32, args.arg1 as i32, args.arg2 as i32);
+                let device =
+                    (self.open_socket.as_mut()?)(domain, ty, protocol)?;
+                let flags = (ty & libc::SOCK_CLOEXEC) as u64;
+                Some(self.allocate_fd(pid, ppid, inject, device, flags))
+            }
+            SyscallNo::SYS_close => {
+                // the kernel closes the eventfd
+                self.fd_table(pid, ppid).remove(&(args.arg0 as i32));
+                None
+            }
+            SyscallNo::SYS_connect => {
+                let fd = args.arg0 as i32;
+                if self.device(pid, ppid, args.arg0).is_some() {
+                    return None;
+                }
+                let addr =
+                    read_sockaddr(memory, args.arg1, args.arg2 as usize)?;
+                let res = self.connect(pid, ppid, memory, inject, fd, &addr)?;
+                Some(result_of(res.map(|_| 0)))
+            }
+            SyscallNo::SYS_sendto => {
+                let fd = args.arg0 as i32;
+                let device = match self.device(pid, ppid, args.arg0) {
+                    Some(device) => device,
+                    None => {
+                        let addr = read_sockaddr(
+                            memory,
+                            args.arg4,
+                            args.arg5 as usize,
+                        )?;
+                        match self
+                            .connect(pid, ppid, memory, inject, fd, &addr)?
+                        {
+                            Ok(device) => device,
+                            Err(err) => return Some(errno_of(&err)),
+                        }
+                    }
+                };
+                let res = memory
+                    .read_bytes(args.arg1, args.arg2 as usize)
+                    .and_then(|bytes| device.borrow_mut().write(&bytes));
+                Some(result_of(res.map(|n| n as i64)))
+            }
+            SyscallNo::SYS_recvfrom => {
+                let device = self.device(pid, ppid, args.arg0)?;
+                let res = device.borrow_mut().read(args.arg2 as usize);
+                Some(result_of(res.and_then(|bytes| {
+                    memory.write_bytes(args.arg1, &bytes)?;
+                    let peer = device.borrow().peer();
+                    match peer {
+                        Some(peer) if args.arg4 != 0 => {
+                            write_sockaddr(memory, args.arg4, args.arg5, &peer)?
+                        }
+                        _ => (),
+                    }
+                    Ok(bytes.len() as i64)
+                })))
+            }
+            SyscallNo::SYS_sendmsg => match self.device(pid, ppid, args.arg0) {
+                Some(device) => {
+                    let res = read_msghdr(memory, args.arg1).and_then(|msg| {
+                        let bytes =
+                            read_iovec_data(memory, &msg.iov, usize::MAX)?;
+                        device.borrow_mut().write(&bytes)
+                    });
+                    Some(result_of(res.map(|n| n as i64)))
+                }
+                None => None,
+            },
+            SyscallNo::SYS_recvmsg => {
+                let device = self.device(pid, ppid, args.arg0)?;
+                Some(result_of(recv_msg(&device, memory, args.arg1)))
+            }
+            SyscallNo::SYS_sendmmsg => {
+                let device = self.device(pid, ppid, args.arg0)?;
+                let res =
+                    send_mmsg(&device, memory, args.arg1, args.arg2 as usize);
+                Some(result_of(res))
+            }
+            SyscallNo::SYS_read => {
+                let device = self.device(pid, ppid, args.arg0)?;
+                let res = device.borrow_mut().read(args.arg2 as usize);
+                Some(result_of(res.and_then(|bytes| {
+                    memory.write_bytes(args.arg1, &bytes)?;
+                    Ok(bytes.len() as i64)
+                })))
+            }
+            SyscallNo::SYS_write => {
+                let device = self.device(pid, ppid, args.arg0)?;
+                let res = memory
+                    .read_bytes(args.arg1, args.arg2 as usize)
+                    .and_then(|bytes| device.borrow_mut().write(&bytes));
+                Some(result_of(res.map(|n| n as i64)))
+            }
+            SyscallNo::SYS_readv => {
+                let device = self.device(pid, ppid, args.arg0)?;
+                let res = read_iovecs(memory, args.arg1, args.arg2 as usize)
+                    .and_then(|iovecs| {
+                        let mut total = 0;
+                        for (base, len) in iovecs {
+                            let bytes = device.borrow_mut().read(len)?;
+                            memory.write_bytes(base, &bytes)?;
+                            total += bytes.len() as i64;
+                            if bytes.len() < len {
+                                break;
+                            }
+                        }
+                        Ok(total)
+                    });
+                Some(result_of(res))
+            }
+            SyscallNo::SYS_writev => {
+                let device = self.device(pid, ppid, args.arg0)?;
+                let res = read_iovecs(memory, args.arg1, args.arg2 as usize)
+                    .and_then(|iovecs| {
+                        let mut bytes = Vec::new();
+                        for (base, len) in iovecs {
+                            bytes.extend(memory.read_bytes(base, len)?);
+                        }
+                        device.borrow_mut().write(&bytes)
+                    });
+                Some(result_of(res.map(|n| n as i64)))
+            }
+            SyscallNo::SYS_ioctl => {
+                let device = self.device(pid, ppid, args.arg0)?;
+                let res =
+                    device.borrow_mut().ioctl(args.arg1, args.arg2, memory);
+                Some(result_of(res))
+            }
+            SyscallNo::SYS_fstat => {
+                let device = self.device(pid, ppid, args.arg0)?;
+                let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+                stat.st_mode = device.borrow().mode();
+                stat.st_nlink = 1;
+                stat.st_blksize = 4096;
+                Some(result_of(stat.write(memory, args.arg1).map(|_| 0)))
+            }
+            SyscallNo::SYS_lseek => {
+                self.device(pid, ppid, args.arg0)?;
+                Some(-libc::ESPIPE as i64)
+            }
+            SyscallNo::SYS_mmap
+                if args.arg3 & libc::MAP_ANONYMOUS as u64 == 0 =>
+            {
+                self.device(pid, ppid, args.arg4)?;
+                Some(-libc::ENODEV as i64)
+            }
+            // the eventfd is non blocking, whatever the tracee sets.
+            // `F_DUPFD` is run by the kernel, see `syscall_exit`
+            SyscallNo::SYS_fcntl => {
+                self.device(pid, ppid, args.arg0)?;
+                match args.arg1 as i32 {
+                    libc::F_GETFL => Some(libc::O_RDWR as i64),
+                    libc::F_SETFL => Some(0),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // `fd` duplicated as `newfd` by the kernel, which closed `newfd` first
+    fn duplicated(&mut self, pid: Pid, ppid: Pid, fd: u64, newfd: i64) {
+        let table = self.fd_table(pid, ppid);
+        match table.get(&(fd as i32)).cloned() {
+            Some(device) => table.insert(newfd as i32, device),
+            None => table.remove(&(newfd as i32)),
+        };
+    }
+
+    // process `child` forked by `pid`, unless seen already
+    fn forked(&mut self, pid: Pid, ppid: Pid, child: Pid) {
+        let table = self.fd_table(pid, ppid).clone();
+        self.fd_tables.entry(child).or_insert(table);
+    }
+
+    // drop the virtual fds process `pid` closed on exec
+    fn exec_closed(&mut self, pid: Pid, ppid: Pid) {
+        let fds: Vec<(i32, DeviceRef)> = self
+            .fd_table(pid, ppid)
+            .iter()
+            .map(|(fd, device)| (*fd, device.clone()))
+            .collect();
+        for (fd, device) in fds {
+            let open = self.backings.iter().any(|backing| {
+                Rc::ptr_eq(&backing.device, &device)
+                    && is_same_file(pid, fd, &backing.eventfd)
+            });
+            if !open {
+                self.fd_table(pid, ppid).remove(&fd);
+            }
+        }
+    }
+
+    // fds received by `recvmsg`, of the eventfd of a virtual device
+    fn received(
+        &mut self,
+        pid: Pid,
+        ppid: Pid,
+        memory: &dyn TaskMemory,
+        args: &SyscallArgs,
+    ) {
+        let msg = match read_msghdr(memory, args.arg1) {
+            Ok(msg) => msg,
+            Err(_) => return,
+        };
+        let fds = msg.control.iter().filter_map(ControlMessage::rights);
+        for fd in fds.flatten() {
+            let device = self
+                .backings
+                .iter()
+                .find(|backing| is_same_file(pid, fd, &backing.eventfd))
+                .map(|backing| backing.device.clone());
+            if let Some(device) = device {
+                self.fd_table(pid, ppid).insert(fd, device);
+            }
+        }
+    }
+
+    /// `syscall` of process `pid` (child of `ppid`) returned `retval`,
+    /// the kernel having run it
+    pub fn syscall_exit(
+        &mut self,
+        pid: Pid,
+        ppid: Pid,
+        memory: &dyn TaskMemory,
+        syscall: SyscallNo,
+        args: &SyscallArgs,
+        retval: i64,
+    ) {
+        if retval < 0 || self.backings.is_empty() {
+            return;
+        }
+        let (fd, cmd) = (args.arg0, args.arg1 as i32);
+        let shared = (libc::CLONE_FILES | libc::CLONE_THREAD) as u64;
+        match syscall {
+            SyscallNo::SYS_recvmsg => self.received(pid, ppid, memory, args),
+            SyscallNo::SYS_dup | SyscallNo::SYS_dup2 | SyscallNo::SYS_dup3 => {
+                self.duplicated(pid, ppid, fd, retval)
+            }
+            SyscallNo::SYS_fcntl
+                if cmd == libc::F_DUPFD || cmd == libc::F_DUPFD_CLOEXEC =>
+            {
+                self.duplicated(pid, ppid, fd, retval)
+            }
+            SyscallNo::SYS_fork | SyscallNo::SYS_vfork if retval > 0 => {
+                self.forked(pid, ppid, Pid::from_raw(retval as i32))
+            }
+            SyscallNo::SYS_clone if retval > 0 && args.arg0 & shared == 0 => {
+                self.forked(pid, ppid, Pid::from_raw(retval as i32))
+            }
+            SyscallNo::SYS_execve | SyscallNo::SYS_execveat => {
+                self.exec_closed(pid, ppid)
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+struct FakeMemory(RefCell<Vec<u8>>);
+
+#[cfg(test)]
+impl TaskMemory for FakeMemory {
+    fn read_bytes(&self, addr: u64, size: usize) -> Result<Vec<u8>> {
+        let mem = self.0.borrow();
+        let end = std::cmp::min(addr as usize + size, mem.len());
+        Ok(Vec::from(&mem[addr as usize..end]))
+    }
+    fn write_bytes(&self, addr: u64, bytes: &[u8]) -> Result<()> {
+        let addr = addr as usize;
+        self.0.borrow_mut()[addr..addr + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+struct Echo(Vec<u8>);
+
+#[cfg(test)]
+impl VirtualDevice for Echo {
+    fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+        let n = std::cmp::min(len, self.0.len());
+        Ok(self.0.drain(..n).collect())
+    }
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn poll(&mut self, events: i16) -> i16 {
+        let readable = if self.0.is_empty() { 0 } else { libc::POLLIN };
+        events & (readable | libc::POLLOUT)
+    }
+    fn peer(&self) -> Option<SocketAddr> {
+        "127.0.0.53:53".parse().ok()
+    }
+}
+
+// syscalls injected run in the test process itself
+#[cfg(test)]
+fn inject_self(nr: SyscallNo, args: &SyscallArgs) -> i64 {
+    let res = unsafe {
+        libc::syscall(
+            nr as libc::c_long,
+            args.arg0,
+            args.arg1,
+            args.arg2,
+            args.arg3,
+            args.arg4,
+            args.arg5,
+        )
+    };
+    if res < 0 {
+        errno_of(&Error::last_os_error())
+    } else {
+        res
+    }
+}
+
+// whether `fd` of the test process is readable, by the kernel
+#[cfg(test)]
+fn is_readable(fd: u64) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd: fd as i32,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut pollfd, 1, 0) == 1 }
+}
+
+#[test]
+fn virtual_devices_sanity_check() {
+    let memory = FakeMemory(RefCell::new(vec![0; 256]));
+    memory.write_bytes(0, b"/dev/reverie/echo\0").unwrap();
+    memory.write_bytes(32, b"hello").unwrap();
+    let mut devices = VirtualDevices::new(Box::new(|name| {
+        if name == "echo" {
+            Some(Box::new(Echo(Vec::new())))
+        } else {
+            None
+        }
+    }));
+    let (pid, ppid) = (nix::unistd::getpid(), Pid::from_raw(1));
+    let mut emulate = |syscall, a0, a1, a2| {
+        let args = SyscallArgs::from(a0, a1, a2, 0, 0, 0);
+        devices.emulate(pid, ppid, &memory, &inject_self, syscall, &args)
+    };
+    let fd = emulate(SyscallNo::SYS_open, 0, 0, 0).unwrap();
+    assert!(fd >= 0);
+    let fd = fd as u64;
+    assert!(!is_readable(fd));
+    assert_eq!(emulate(SyscallNo::SYS_write, fd, 32, 5), Some(5));
+    assert!(is_readable(fd));
+    assert_eq!(emulate(SyscallNo::SYS_read, fd, 64, 16), Some(5));
+    assert!(!is_readable(fd));
+    assert_eq!(emulate(SyscallNo::SYS_read, 0, 64, 16), None);
+    // the kernel closes the eventfd
+    assert_eq!(emulate(SyscallNo::SYS_close, fd, 0, 0), None);
+    nix::unistd::close(fd as i32).unwrap();
+    assert_eq!(emulate(SyscallNo::SYS_read, fd, 64, 16), None);
+    memory.write_bytes(0, b"/dev/reverie/none\0").unwrap();
+    assert_eq!(
+        emulate(SyscallNo::SYS_open, 0, 0, 0),
+        Some(-libc::ENOENT as i64)
+    );
+    assert_eq!(memory.read_bytes(64, 5).unwrap(), b"hello".to_vec());
+    // a process sharing the fd table by `CLONE_FILES`, until unshared
+    memory.write_bytes(0, b"/dev/reverie/echo\0").unwrap();
+    let args = SyscallArgs::from(0, 0, 0, 0, 0, 0);
+    let open = SyscallNo::SYS_open;
+    let fd = devices.emulate(pid, ppid, &memory, &inject_self, open, &args);
+    let fd = fd.unwrap() as u64;
+    let sharer = Pid::from_raw(pid.as_raw() + 1);
+    devices.share_files(sharer, pid);
+    assert!(devices.device(sharer, sharer, fd).is_some());
+    devices.share_files(sharer, sharer);
+    devices.fd_table(pid, ppid).remove(&(fd as i32));
+    assert!(devices.device(sharer, sharer, fd).is_some());
+    assert!(devices.device(pid, ppid, fd).is_none());
+    nix::unistd::close(fd as i32).unwrap();
+}
+
+#[test]
+fn virtual_devices_fd_table_sanity_check() {
+    let memory = FakeMemory(RefCell::new(vec![0; 256]));
+    memory.write_bytes(0, b"/dev/reverie/echo\0").unwrap();
+    let mut devices =
+        VirtualDevices::new(Box::new(|_| Some(Box::new(Echo(Vec::new())))));
+    let (pid, ppid) = (nix::unistd::getpid(), Pid::from_raw(1));
+    let mut emulate = |syscall, args: &SyscallArgs| {
+        devices.emulate(pid, ppid, &memory, &inject_self, syscall, args)
+    };
+    let args = SyscallArgs::from(0, libc::O_CLOEXEC as u64, 0, 0, 0, 0);
+    let fd = emulate(SyscallNo::SYS_open, &args).unwrap() as u64;
+    // struct stat at 64
+    let args = SyscallArgs::from(fd, 64, 0, 0, 0, 0);
+    assert_eq!(emulate(SyscallNo::SYS_fstat, &args), Some(0));
+    let stat = libc::stat::read(&memory, 64).unwrap();
+    assert_eq!(stat.st_mode, libc::S_IFSOCK | 0o777);
+    let args = SyscallArgs::from(fd, 0, libc::SEEK_SET as u64, 0, 0, 0);
+    assert_eq!(
+        emulate(SyscallNo::SYS_lseek, &args),
+        Some(-libc::ESPIPE as i64)
+    );
+    let args = SyscallArgs::from(0, 4096, 0, libc::MAP_SHARED as u64, fd, 0);
+    assert_eq!(
+        emulate(SyscallNo::SYS_mmap, &args),
+        Some(-libc::ENODEV as i64)
+    );
+    let args = SyscallArgs::from(fd, libc::F_GETFL as u64, 0, 0, 0, 0);
+    assert_eq!(
+        emulate(SyscallNo::SYS_fcntl, &args),
+        Some(libc::O_RDWR as i64)
+    );
+    let args = SyscallArgs::from(fd, libc::F_GETFD as u64, 0, 0, 0, 0);
+    assert_eq!(emulate(SyscallNo::SYS_fcntl, &args), None);
+
+    // the kernel duplicates the eventfd
+    let dup = nix::unistd::dup(fd as i32).unwrap();
+    let args = SyscallArgs::from(fd, 0, 0, 0, 0, 0);
+    let exit = SyscallNo::SYS_dup;
+    devices.syscall_exit(pid, ppid, &memory, exit, &args, dup as i64);
+    assert!(devices.device(pid, ppid, dup as u64).is_some());
+    // copied at fork, not at the first syscall of the child
+    let child = Pid::from_raw(i32::MAX);
+    let exit = SyscallNo::SYS_fork;
+    devices.syscall_exit(pid, ppid, &memory, exit, &args, i32::MAX as i64);
+    devices.fd_table(pid, ppid).remove(&dup);
+    assert!(devices.device(child, pid, dup as u64).is_some());
+    // not shared with threads
+    let exit = SyscallNo::SYS_clone;
+    let flags =
+        (libc::CLONE_VM | libc::CLONE_FILES | libc::CLONE_THREAD) as u64;
+    let args = SyscallArgs::from(flags, 0, 0, 0, 0, 0);
+    let thread = i32::MAX - 1;
+    devices.syscall_exit(pid, ppid, &memory, exit, &args, thread as i64);
+    assert!(!devices.fd_tables.contains_key(&Pid::from_raw(thread)));
+    // `O_CLOEXEC` ones are closed on exec, the dup is not
+    let device = devices.device(pid, ppid, fd).unwrap();
+    devices.fd_table(pid, ppid).insert(dup, device);
+    nix::unistd::close(fd as i32).unwrap();
+    let exit = SyscallNo::SYS_execve;
+    devices.syscall_exit(pid, ppid, &memory, exit, &args, 0);
+    assert!(devices.device(pid, ppid, fd).is_none());
+    assert!(devices.device(pid, ppid, dup as u64).is_some());
+    nix::unistd::close(dup).unwrap();
+}
+
+#[test]
+fn virtual_devices_readiness_sanity_check() {
+    use nix::sys::epoll::*;
+
+    let memory = FakeMemory(RefCell::new(vec![0; 256]));
+    memory.write_bytes(0, b"/dev/reverie/echo\0").unwrap();
+    memory.write_bytes(32, b"hello").unwrap();
+    let mut devices =
+        VirtualDevices::new(Box::new(|_| Some(Box::new(Echo(Vec::new())))));
+    // a child process writes what its parent waits for
+    let parent = nix::unistd::getpid();
+    let child = Pid::from_raw(i32::MAX);
+    let mut emulate = |pid, syscall, a0, a1, a2| {
+        let args = SyscallArgs::from(a0, a1, a2, 0, 0, 0);
+        let ppid = if pid == child {
+            parent
+        } else {
+            Pid::from_raw(1)
+        };
+        devices.emulate(pid, ppid, &memory, &inject_self, syscall, &args)
+    };
+    let fd = emulate(parent, SyscallNo::SYS_open, 0, 0, 0).unwrap() as u64;
+    let epfd = epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC).unwrap();
+    let mut event = EpollEvent::new(EpollFlags::EPOLLIN, 42);
+    epoll_ctl(epfd, EpollOp::EpollCtlAdd, fd as i32, &mut event).unwrap();
+    let mut events = [EpollEvent::empty()];
+    assert_eq!(epoll_wait(epfd, &mut events, 0).unwrap(), 0);
+    // inherited from `parent`, seen first
+    assert_eq!(emulate(child, SyscallNo::SYS_write, fd, 32, 5), Some(5));
+    assert_eq!(epoll_wait(epfd, &mut events, 0).unwrap(), 1);
+    assert_eq!(events[0].data(), 42);
+    assert_eq!(emulate(parent, SyscallNo::SYS_read, fd, 64, 16), Some(5));
+    assert_eq!(epoll_wait(epfd, &mut events, 0).unwrap(), 0);
+    nix::unistd::close(epfd).unwrap();
+    nix::unistd::close(fd as i32).unwrap();
+}
+
+#[test]
+fn virtual_devices_connect_sanity_check() {
+    use nix::sys::socket::*;
+
+    let memory = FakeMemory(RefCell::new(vec![0; 256]));
+    let mut devices = VirtualDevices::new(Box::new(|_| None));
+    devices.add_connect_handler(Box::new(|addr, ty| {
+        if addr.port() == 53 && ty == libc::SOCK_DGRAM {
+            Some(Box::new(Echo(Vec::new())))
+        } else {
+            None
+        }
+    }));
+    let (pid, ppid) = (nix::unistd::getpid(), Pid::from_raw(1));
+    let inject = |nr, args: &SyscallArgs| match nr {
+        SyscallNo::SYS_mmap => 224,
+        SyscallNo::SYS_munmap => 0,
+        SyscallNo::SYS_getsockopt => {
+            let ty = libc::SOCK_DGRAM.to_le_bytes();
+            memory.write_bytes(args.arg3, &ty).unwrap();
+            0
+        }
+        nr => inject_self(nr, args),
+    };
+    let mut emulate = |syscall, a0, a1, a2, a4, a5| {
+        let args = SyscallArgs::from(a0, a1, a2, 0, a4, a5);
+        devices.emulate(pid, ppid, &memory, &inject, syscall, &args)
+    };
+    let socket = |flags| {
+        let ty = SockType::Datagram;
+        socket(AddressFamily::Inet, ty, flags, None).unwrap() as u64
+    };
+    let fd = socket(SockFlag::SOCK_CLOEXEC);
+    let dns: SocketAddr = "127.0.0.53:53".parse().unwrap();
+    let sockaddr = sockaddr_bytes(&dns);
+    memory.write_bytes(160, &sockaddr).unwrap();
+    memory.write_bytes(32, b"hello").unwrap();
+    assert_eq!(emulate(SyscallNo::SYS_connect, fd, 160, 16, 0, 0), Some(0));
+    // the socket is an eventfd now, close on exec still
+    let fdflags = nix::fcntl::fcntl(fd as i32, nix::fcntl::F_GETFD);
+    assert_eq!(fdflags.unwrap(), libc::FD_CLOEXEC);
+    assert_eq!(emulate(SyscallNo::SYS_sendto, fd, 32, 5, 0, 0), Some(5));
+    assert!(is_readable(fd));
+    // src_addr at 64, addrlen at 96
+    memory.write_bytes(96, &16u32.to_le_bytes()).unwrap();
+    assert_eq!(
+        emulate(SyscallNo::SYS_recvfrom, fd, 128, 16, 64, 96),
+        Some(5)
+    );
+    assert_eq!(memory.read_bytes(64, 16).unwrap(), sockaddr);
+    assert_eq!(memory.read_bytes(128, 5).unwrap(), b"hello".to_vec());
+    // the kernel closes the socket
+    assert_eq!(emulate(SyscallNo::SYS_close, fd, 0, 0, 0, 0), None);
+    nix::unistd::close(fd as i32).unwrap();
+    assert_eq!(emulate(SyscallNo::SYS_recvfrom, fd, 128, 16, 0, 0), None);
+    let fd = socket(SockFlag::empty());
+    let http: SocketAddr = "127.0.0.53:80".parse().unwrap();
+    memory.write_bytes(160, &sockaddr_bytes(&http)).unwrap();
+    assert_eq!(emulate(SyscallNo::SYS_connect, fd, 160, 16, 0, 0), None);
+    nix::unistd::close(fd as i32).unwrap();
+}
+
+#[test]
+fn virtual_devices_fd_passing_sanity_check() {
+    let memory = FakeMemory(RefCell::new(vec![0; 512]));
+    memory.write_bytes(200, b"/dev/reverie/echo\0").unwrap();
+    let mut devices =
+        VirtualDevices::new(Box::new(|_| Some(Box::new(Echo(Vec::new())))));
+    let (pid, ppid) = (nix::unistd::getpid(), Pid::from_raw(1));
+    let mut emulate = |syscall, args: &SyscallArgs| {
+        devices.emulate(pid, ppid, &memory, &inject_self, syscall, args)
+    };
+    let args = SyscallArgs::from(200, 0, 0, 0, 0, 0);
+    let vfd = emulate(SyscallNo::SYS_open, &args).unwrap() as i32;
+    // msghdr at 0, iovec at 64, data at 96, control at 128
+    let hdr: [u64; 7] = [0, 0, 64, 1, 128, 24, 0];
+    for (k, value) in hdr.iter().enumerate() {
+        memory
+            .write_bytes(8 * k as u64, &value.to_le_bytes())
+            .unwrap();
+    }
+    memory.write_bytes(64, &96u64.to_le_bytes()).unwrap();
+    memory.write_bytes(72, &1u64.to_le_bytes()).unwrap();
+    memory.write_bytes(96, b"x").unwrap();
+    let mut cmsg = Vec::from(&20u64.to_le_bytes()[..]);
+    cmsg.extend_from_slice(&libc::SOL_SOCKET.to_le_bytes());
+    cmsg.extend_from_slice(&libc::SCM_RIGHTS.to_le_bytes());
+    cmsg.extend_from_slice(&vfd.to_le_bytes());
+    memory.write_bytes(128, &cmsg).unwrap();
+    // the kernel passes the eventfd over real sockets
+    let args = SyscallArgs::from(1023, 0, 0, 0, 0, 0);
+    assert_eq!(emulate(SyscallNo::SYS_sendmsg, &args), None);
+
+    // received as another fd of the eventfd
+    let fd = nix::unistd::dup(vfd).unwrap();
+    memory.write_bytes(144, &fd.to_le_bytes()).unwrap();
+    let recvmsg = SyscallNo::SYS_recvmsg;
+    assert!(devices.device(pid, ppid, fd as u64).is_none());
+    devices.syscall_exit(pid, ppid, &memory, recvmsg, &args, 1);
+    assert!(devices.device(pid, ppid, fd as u64).is_some());
+    let mut emulate = |syscall, args: &SyscallArgs| {
+        devices.emulate(pid, ppid, &memory, &inject_self, syscall, args)
+    };
+    let args = SyscallArgs::from(fd as u64, 96, 1, 0, 0, 0);
+    assert_eq!(emulate(SyscallNo::SYS_write, &args), Some(1));
+    // and read back by `recvmsg` of the virtual socket
+    memory.write_bytes(96, b"?").unwrap();
+    let args = SyscallArgs::from(vfd as u64, 0, 0, 0, 0, 0);
+    assert_eq!(emulate(recvmsg, &args), Some(1));
+    assert_eq!(memory.read_bytes(96, 1).unwrap(), b"x".to_vec());
+    assert_eq!(memory.read_bytes(40, 8).unwrap(), vec![0; 8]);
+    nix::unistd::close(fd).unwrap();
+    nix::unistd::close(vfd).unwrap();
+}
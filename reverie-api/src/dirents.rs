@@ -0,0 +1,129 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! directory entries as returned by `getdents64`
+//!
+//! tools can hide or add entries of directories read by tracees, without
+//! a mount namespace, see `DirentFilterFn`. the tracer decodes the buffer
+//! returned as `Dirent`s, and writes back what the filter leaves of them.
+//! entries not fitting in the buffer of the tracee are returned by its
+//! next `getdents64` of the directory.
+//!
+//! entries are hidden from listings only: they can still be opened by
+//! name, and `seekdir` to entries added is not supported.
+
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+
+use crate::task::Task;
+
+/// `d_type` of a directory
+pub const DT_DIR: u8 = 4;
+/// `d_type` of a regular file
+pub const DT_REG: u8 = 8;
+/// `d_type` of a symbolic link
+pub const DT_LNK: u8 = 10;
+
+// offset of `d_name` in `struct linux_dirent64`
+const D_NAME: usize = 19;
+
+/// `struct linux_dirent64`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dirent {
+    pub ino: u64,
+    /// offset of the next entry, for `lseek`
+    pub off: i64,
+    /// `d_type`, i.e.: `DT_REG`
+    pub kind: u8,
+    pub name: OsString,
+}
+
+impl Dirent {
+    pub fn new<S: AsRef<OsStr>>(ino: u64, kind: u8, name: S) -> Self {
+        Dirent {
+            ino,
+            off: 0,
+            kind,
+            name: name.as_ref().to_os_string(),
+        }
+    }
+
+    /// `d_reclen`, with `d_name` nul terminated and 8 bytes aligned
+    pub fn reclen(&self) -> usize {
+        (D_NAME + self.name.len() + 1 + 7) & !7
+    }
+
+    /// decode entries of `bytes` returned by `getdents64`, until a
+    /// truncated entry if any
+    pub fn decode(bytes: &[u8]) -> Vec<Dirent> {
+        let mut entries = Vec::new();
+        let mut rest = bytes;
+        while rest.len() > D_NAME {
+            let reclen = u16::from_le_bytes([rest[16], rest[17]]) as usize;
+            if reclen <= D_NAME || reclen > rest.len() {
+                break;
+            }
+            let mut word = [0u8; 8];
+            word.copy_from_slice(&rest[0..8]);
+            let ino = u64::from_le_bytes(word);
+            word.copy_from_slice(&rest[8..16]);
+            let off = i64::from_le_bytes(word);
+            let name = &rest[D_NAME..reclen];
+            let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+            entries.push(Dirent {
+                ino,
+                off,
+                kind: rest[18],
+                name: OsString::from_vec(name[..len].to_vec()),
+            });
+            rest = &rest[reclen..];
+        }
+        entries
+    }
+
+    /// encode as `getdents64` returns
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; self.reclen()];
+        bytes[0..8].copy_from_slice(&self.ino.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.off.to_le_bytes());
+        bytes[16..18].copy_from_slice(&(self.reclen() as u16).to_le_bytes());
+        bytes[18] = self.kind;
+        let name = self.name.as_bytes();
+        bytes[D_NAME..D_NAME + name.len()].copy_from_slice(name);
+        bytes
+    }
+}
+
+/// hides, or adds entries of directory `Path` read by `getdents64`, in
+/// place. `bool` is true once the directory is read to its end: entries
+/// are empty, and those added are appended to the directory. entries added
+/// with an `off` of 0 are given the offset of the entry before them.
+pub type DirentFilterFn =
+    Box<dyn FnMut(&dyn Task, &Path, &mut Vec<Dirent>, bool)>;
+
+#[test]
+fn dirents_sanity_check() {
+    let mut dot = Dirent::new(2, DT_DIR, ".");
+    dot.off = 1;
+    let mut file = Dirent::new(42, DT_REG, "hello.txt");
+    file.off = 2;
+    assert_eq!(dot.reclen(), 24);
+    assert_eq!(file.reclen(), 32);
+    let mut bytes = dot.encode();
+    bytes.extend(file.encode());
+    assert_eq!(Dirent::decode(&bytes), vec![dot.clone(), file]);
+    // truncated
+    assert_eq!(Dirent::decode(&bytes[..40]), vec![dot]);
+    assert!(Dirent::decode(&[]).is_empty());
+}
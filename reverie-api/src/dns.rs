@@ -0,0 +1,268 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! hermetic name resolution
+//!
+//! `intercept_dns` points the resolver of traced processes (`getaddrinfo`
+//! and friends) at a synthetic name server: `/etc/resolv.conf` is
+//! redirected to a generated one, and sockets connecting (or sending) to
+//! port 53 of any address are taken over by virtual devices, which answer
+//! queries over UDP or TCP by the tool's `ResolveFn`. nothing reaches the
+//! network.
+//!
+//! only `A` and `AAAA` queries are answered with addresses, other types
+//! get empty answers. NB: `/etc/hosts` is still read by the resolver.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{Error, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+
+use crate::device::{VirtualDevice, VirtualDevices};
+
+/// the name server of the generated `resolv.conf`
+pub const NAME_SERVER: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 53);
+
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+pub const DNS_PORT: u16 = 53;
+
+/// addresses of host `name` (without trailing dot), `None` if no such
+/// host (`NXDOMAIN`).
+pub type ResolveFn = Box<dyn FnMut(&str) -> Option<Vec<IpAddr>>>;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const TTL: u32 = 60;
+const RCODE_NXDOMAIN: u16 = 3;
+
+fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *bytes.get(offset)?,
+        *bytes.get(offset + 1)?,
+    ]))
+}
+
+// single question of `query`: `(name, qtype, end of the question)`
+fn parse_question(query: &[u8]) -> Option<(String, u16, usize)> {
+    if u16_at(query, 4)? != 1 {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut k = 12;
+    loop {
+        let len = *query.get(k)? as usize;
+        k += 1;
+        if len == 0 {
+            break;
+        }
+        // no compression in questions of queries
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(query.get(k..k + len)?));
+        k += len;
+    }
+    let qtype = u16_at(query, k)?;
+    u16_at(query, k + 2)?;
+    Some((labels.join("."), qtype, k + 4))
+}
+
+/// reply to DNS `query`, answered by `resolve`. `None` if malformed.
+pub fn answer(query: &[u8], resolve: &mut ResolveFn) -> Option<Vec<u8>> {
+    let (name, qtype, end) = parse_question(query)?;
+    let addrs = resolve(&name);
+    let rdata: Vec<Vec<u8>> = addrs
+        .iter()
+        .flatten()
+        .filter_map(|addr| match (addr, qtype) {
+            (IpAddr::V4(ip), TYPE_A) => Some(ip.octets().to_vec()),
+            (IpAddr::V6(ip), TYPE_AAAA) => Some(ip.octets().to_vec()),
+            _ => None,
+        })
+        .collect();
+    let mut reply = Vec::from(&query[..end]);
+    // QR and RA, opcode and RD as queried
+    let mut flags = 0x8080 | (u16_at(query, 2)? & 0x7900);
+    if addrs.is_none() {
+        flags |= RCODE_NXDOMAIN;
+    }
+    reply[2..4].copy_from_slice(&flags.to_be_bytes());
+    reply[6..8].copy_from_slice(&(rdata.len() as u16).to_be_bytes());
+    reply[8..12].copy_from_slice(&[0; 4]);
+    for rdata in rdata {
+        // name is a pointer to the question's
+        reply.extend_from_slice(&[0xc0, 0x0c]);
+        reply.extend_from_slice(&qtype.to_be_bytes());
+        reply.extend_from_slice(&CLASS_IN.to_be_bytes());
+        reply.extend_from_slice(&TTL.to_be_bytes());
+        reply.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        reply.extend_from_slice(&rdata);
+    }
+    Some(reply)
+}
+
+/// synthetic name server, behind a socket connected to `peer`
+struct NameServer {
+    resolve: Rc<RefCell<ResolveFn>>,
+    peer: SocketAddr,
+    /// TCP (messages prefixed by their length) rather than UDP
+    stream: bool,
+    /// partial queries, for TCP
+    queries: Vec<u8>,
+    replies: VecDeque<Vec<u8>>,
+}
+
+impl NameServer {
+    fn query(&mut self, query: &[u8]) {
+        let reply = answer(query, &mut self.resolve.borrow_mut());
+        if let Some(reply) = reply {
+            if self.stream {
+                let mut framed = (reply.len() as u16).to_be_bytes().to_vec();
+                framed.extend(reply);
+                self.replies.push_back(framed);
+            } else {
+                self.replies.push_back(reply);
+            }
+        }
+    }
+}
+
+impl VirtualDevice for NameServer {
+    fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut reply = self
+            .replies
+            .pop_front()
+            .ok_or_else(|| Error::from_raw_os_error(libc::EAGAIN))?;
+        if self.stream && reply.len() > len {
+            let rest = reply.split_off(len);
+            self.replies.push_front(rest);
+        }
+        // datagrams are truncated
+        reply.truncate(len);
+        Ok(reply)
+    }
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if !self.stream {
+            self.query(buf);
+            return Ok(buf.len());
+        }
+        self.queries.extend_from_slice(buf);
+        while let Some(len) = u16_at(&self.queries, 0) {
+            let end = 2 + len as usize;
+            if self.queries.len() < end {
+                break;
+            }
+            let query: Vec<u8> = self.queries.drain(..end).skip(2).collect();
+            self.query(&query);
+        }
+        Ok(buf.len())
+    }
+    fn poll(&mut self, events: i16) -> i16 {
+        let ready = if self.replies.is_empty() {
+            libc::POLLOUT
+        } else {
+            libc::POLLIN | libc::POLLOUT
+        };
+        events & ready
+    }
+    fn peer(&self) -> Option<SocketAddr> {
+        Some(self.peer)
+    }
+}
+
+/// answer name resolution of traced processes by `resolve`, rather than by
+/// the network. the generated `resolv.conf` is left in the temp dir.
+pub fn intercept_dns(
+    devices: &mut VirtualDevices,
+    resolve: ResolveFn,
+) -> Result<()> {
+    let resolv_conf = std::env::temp_dir()
+        .join(format!("reverie-resolv.conf.{}", std::process::id()));
+    std::fs::write(&resolv_conf, format!("nameserver {}\n", NAME_SERVER))?;
+    devices.redirect(RESOLV_CONF, &resolv_conf);
+    let resolve = Rc::new(RefCell::new(resolve));
+    devices.add_connect_handler(Box::new(move |addr, ty| {
+        if addr.port() != DNS_PORT {
+            return None;
+        }
+        let stream = match ty {
+            libc::SOCK_DGRAM => false,
+            libc::SOCK_STREAM => true,
+            _ => return None,
+        };
+        Some(Box::new(NameServer {
+            resolve: resolve.clone(),
+            peer: *addr,
+            stream,
+            queries: Vec::new(),
+            replies: VecDeque::new(),
+        }))
+    }));
+    Ok(())
+}
+
+#[test]
+fn dns_answer_sanity_check() {
+    let mut resolve: ResolveFn = Box::new(|name| {
+        if name == "example.com" {
+            Some(vec![
+                IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+                IpAddr::V6("2001:db8::1".parse().unwrap()),
+            ])
+        } else {
+            None
+        }
+    });
+    let query = |name: &str, qtype: u16| {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.push(0);
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        query
+    };
+    let a = query("example.com", TYPE_A);
+    let reply = answer(&a, &mut resolve).unwrap();
+    assert_eq!(&reply[..8], &[0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1]);
+    assert_eq!(&reply[reply.len() - 6..], &[0, 4, 192, 0, 2, 1]);
+    let aaaa = query("example.com", TYPE_AAAA);
+    let reply = answer(&aaaa, &mut resolve).unwrap();
+    assert_eq!(reply.len(), aaaa.len() + 12 + 16);
+    let reply = answer(&query("nx.example.com", TYPE_A), &mut resolve);
+    assert_eq!(&reply.unwrap()[2..8], &[0x81, 0x83, 0, 1, 0, 0]);
+    assert_eq!(answer(&a[..10], &mut resolve), None);
+
+    let mut server = NameServer {
+        resolve: Rc::new(RefCell::new(resolve)),
+        peer: SocketAddr::new(NAME_SERVER.into(), DNS_PORT),
+        stream: true,
+        queries: Vec::new(),
+        replies: VecDeque::new(),
+    };
+    let mut framed = (a.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(&a);
+    assert_eq!(server.write(&framed[..5]).unwrap(), 5);
+    assert_eq!(server.poll(libc::POLLIN), 0);
+    server.write(&framed[5..]).unwrap();
+    assert_eq!(server.poll(libc::POLLIN), libc::POLLIN);
+    let len = server.read(2).unwrap();
+    let reply = server.read(512).unwrap();
+    assert_eq!(u16_at(&len, 0), Some(reply.len() as u16));
+    assert!(server.read(512).is_err());
+}
@@ -0,0 +1,226 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! syscall emulation
+//!
+//! syscalls selected by the tool are offered to its `SyscallEmulatorFn`
+//! on entry. if emulated, the kernel never runs the syscall, the tracee
+//! sees the emulator's return value instead. this is what replay, and
+//! purely virtual devices behind the tool are built on.
+
+use nix::errno::Errno;
+use std::cell::RefCell;
+use std::io::{Error, Result};
+use std::rc::Rc;
+use syscalls::SyscallNo;
+
+use crate::remote::*;
+use crate::task::Task;
+
+/// memory of the task whose syscall is emulated
+pub trait TaskMemory {
+    /// read `size` bytes at `addr`
+    fn read_bytes(&self, addr: u64, size: usize) -> Result<Vec<u8>>;
+    /// write `bytes` at `addr`
+    fn write_bytes(&self, addr: u64, bytes: &[u8]) -> Result<()>;
+}
+
+fn remote_ptr(addr: u64) -> Result<Remoteable<u8>> {
+    Remoteable::remote(addr as *mut u8)
+        .ok_or_else(|| Error::from_raw_os_error(Errno::EFAULT as i32))
+}
+
+impl<T: GuestMemoryAccess> TaskMemory for T {
+    fn read_bytes(&self, addr: u64, size: usize) -> Result<Vec<u8>> {
+        self.peek_bytes(remote_ptr(addr)?, size)
+    }
+    fn write_bytes(&self, addr: u64, bytes: &[u8]) -> Result<()> {
+        self.poke_bytes(remote_ptr(addr)?, bytes)
+    }
+}
+
+/// how syscalls to emulate are intercepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmulationMode {
+    /// from seccomp stops, the selected syscalls are never patched
+    Seccomp,
+    /// tasks are resumed by `PTRACE_SYSEMU`, which stops every syscall
+    /// (patched ones included) before the kernel runs it, as replay
+    /// requires. syscalls not emulated are run again, at the cost of
+    /// extra stops.
+    Sysemu,
+}
+
+/// returns the result of syscall `SyscallNo` (`-errno` on error), or
+/// `None` for the kernel to run the syscall after all.
+pub type SyscallEmulatorFn = Box<
+    dyn FnMut(
+        &dyn Task,
+        &dyn TaskMemory,
+        SyscallNo,
+        &SyscallArgs,
+    ) -> Option<i64>,
+>;
+
+/// called with the return value of syscalls declined by the emulator, at
+/// syscall exit.
+pub type SyscallExitFn =
+    Box<dyn FnMut(&dyn Task, &dyn TaskMemory, SyscallNo, &SyscallArgs, i64)>;
+
+/// whether selected syscall `SyscallNo` of a task is trapped at seccomp
+/// stop, rather than patched (and never offered to the emulator again).
+pub type SyscallTrapFn = Box<dyn Fn(&dyn Task, SyscallNo) -> bool>;
+
+/// syscalls to emulate, and their emulator
+pub struct SyscallEmulation {
+    pub mode: EmulationMode,
+    pub syscalls: Vec<SyscallNo>,
+    pub emulator: SyscallEmulatorFn,
+    pub on_exit: Option<SyscallExitFn>,
+    pub on_trap: Option<SyscallTrapFn>,
+}
+
+impl SyscallEmulation {
+    pub fn new(
+        mode: EmulationMode,
+        syscalls: Vec<SyscallNo>,
+        emulator: SyscallEmulatorFn,
+    ) -> Self {
+        SyscallEmulation {
+            mode,
+            syscalls,
+            emulator,
+            on_exit: None,
+            on_trap: None,
+        }
+    }
+    /// set `on_exit` to see the results of syscalls declined
+    pub fn set_exit_handler(&mut self, on_exit: SyscallExitFn) {
+        self.on_exit = Some(on_exit);
+    }
+    /// set `on_trap` to trap the selected syscalls of some tasks only, in
+    /// `Seccomp` mode. all are trapped otherwise.
+    pub fn set_trap_filter(&mut self, on_trap: SyscallTrapFn) {
+        self.on_trap = Some(on_trap);
+    }
+    /// emulation by `self`, then by `other` for syscalls `self` declines.
+    /// `Sysemu` if either is.
+    pub fn chain(self, other: SyscallEmulation) -> Self {
+        let mode = if self.mode == EmulationMode::Seccomp
+            && other.mode == EmulationMode::Seccomp
+        {
+            EmulationMode::Seccomp
+        } else {
+            EmulationMode::Sysemu
+        };
+        let mut syscalls = self.syscalls.clone();
+        for syscall in &other.syscalls {
+            if !syscalls.contains(syscall) {
+                syscalls.push(*syscall);
+            }
+        }
+        let chained = Rc::new(RefCell::new((self, other)));
+        let exits = chained.clone();
+        let traps = chained.clone();
+        let emulator: SyscallEmulatorFn =
+            Box::new(move |task, memory, syscall, args| {
+                let (first, second) = &mut *chained.borrow_mut();
+                let mut res = None;
+                if first.is_emulated(syscall) {
+                    res = (first.emulator)(task, memory, syscall, args);
+                }
+                if res.is_none() && second.is_emulated(syscall) {
+                    res = (second.emulator)(task, memory, syscall, args);
+                }
+                res
+            });
+        let on_exit: SyscallExitFn =
+            Box::new(move |task, memory, syscall, args, retval| {
+                let (first, second) = &mut *exits.borrow_mut();
+                for emulation in &mut [first, second] {
+                    if !emulation.is_emulated(syscall) {
+                        continue;
+                    }
+                    if let Some(on_exit) = emulation.on_exit.as_mut() {
+                        on_exit(task, memory, syscall, args, retval);
+                    }
+                }
+            });
+        let on_trap: SyscallTrapFn = Box::new(move |task, syscall| {
+            let (first, second) = &*traps.borrow();
+            first.is_trapped(task, syscall) || second.is_trapped(task, syscall)
+        });
+        SyscallEmulation {
+            mode,
+            syscalls,
+            emulator,
+            on_exit: Some(on_exit),
+            on_trap: Some(on_trap),
+        }
+    }
+    /// whether `syscall` is offered to the emulator
+    pub fn is_emulated(&self, syscall: SyscallNo) -> bool {
+        self.syscalls.contains(&syscall)
+    }
+    /// whether `syscall` of `task` is trapped, see `set_trap_filter`
+    pub fn is_trapped(&self, task: &dyn Task, syscall: SyscallNo) -> bool {
+        self.is_emulated(syscall)
+            && self.on_trap.as_ref().map_or(true, |f| f(task, syscall))
+    }
+    /// emulate `syscall` of `task`, if selected
+    pub fn emulate<T: Task + TaskMemory>(
+        &mut self,
+        task: &T,
+        syscall: SyscallNo,
+        args: &SyscallArgs,
+    ) -> Option<i64> {
+        if !self.is_emulated(syscall) {
+            return None;
+        }
+        (self.emulator)(task, task, syscall, args)
+    }
+    /// `syscall` of `task`, declined by the emulator, returned `retval`
+    pub fn exited<T: Task + TaskMemory>(
+        &mut self,
+        task: &T,
+        syscall: SyscallNo,
+        args: &SyscallArgs,
+        retval: i64,
+    ) {
+        if !self.is_emulated(syscall) {
+            return;
+        }
+        if let Some(on_exit) = self.on_exit.as_mut() {
+            on_exit(task, task, syscall, args, retval)
+        }
+    }
+}
+
+#[test]
+fn syscall_emulation_sanity_check() {
+    let emulation = SyscallEmulation::new(
+        EmulationMode::Sysemu,
+        vec![SyscallNo::SYS_getpid],
+        Box::new(|_, _, _, _| Some(1)),
+    );
+    assert!(emulation.is_emulated(SyscallNo::SYS_getpid));
+    assert!(!emulation.is_emulated(SyscallNo::SYS_read));
+    let other = SyscallEmulation::new(
+        EmulationMode::Seccomp,
+        vec![SyscallNo::SYS_getpid, SyscallNo::SYS_read],
+        Box::new(|_, _, _, _| Some(2)),
+    );
+    let emulation = emulation.chain(other);
+    assert_eq!(emulation.mode, EmulationMode::Sysemu);
+    assert!(emulation.is_emulated(SyscallNo::SYS_read));
+}
@@ -10,9 +10,192 @@
  *  LICENSE file in the root directory of this source tree.
  */
 
+use crate::binaries::BinaryImage;
+use crate::clock::*;
+use crate::coverage::CoverageSpec;
+use crate::dirents::DirentFilterFn;
+use crate::emulate::SyscallEmulation;
+use crate::fileless::FilelessExec;
+use crate::kill::SignalFilterFn;
+use crate::mapping::*;
+use crate::probe::ProbeSpec;
+use crate::record_data::DataSelection;
+use crate::remote::SyscallArgs;
+use crate::shm::*;
 use crate::task::*;
+use crate::ticks::ExecPoint;
+use crate::violation::{Frame, ViolationAction};
+use crate::wait::WaitFilterFn;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
 use std::boxed::Box;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use syscalls::SyscallNo;
+
+/// events observed by the tracer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// clock calibration record, emitted once before any other event
+    Calibration(ClockCalibration),
+    /// syscall entered (seccomp stop)
+    SyscallEnter(SyscallNo, SyscallArgs),
+    /// syscall returned `retval`, `Duration` is the time since enter
+    SyscallExit(SyscallNo, i64, Duration),
+    /// a new program image is loaded
+    Exec,
+    /// fork returned with child pid
+    Fork(Pid),
+    /// vfork (or clone with `CLONE_VFORK`, as of `posix_spawn` and
+    /// `system`) returned with child pid: the child runs on the memory of
+    /// the task until it execs or exits.
+    Spawn(Pid),
+    /// clone returned with child tid
+    Clone(Pid),
+    /// signal to be delivered to the task
+    Signal(Signal),
+    /// task exited with exit code
+    Exited(i32),
+    /// shared memory created or mapped, see `SharedMemoryPolicy`
+    SharedMemory(SharedMemory),
+    /// child process exited, and awaits to be reaped by the task
+    Zombie(Pid),
+    /// zombie child process reaped by the task
+    Reaped(Pid),
+    /// child process orphaned by the task's exit
+    Orphaned(Pid),
+    /// memory mappings changed, see `TaskEventCB::set_mapping_handler`
+    Mapping(MappingChange),
+    /// handling the task panicked with message, it is detached stopped,
+    /// with the other threads of its process
+    Quarantined(String),
+    /// ptrace event not known to the tracer, see `UnknownEventPolicy`
+    UnknownPtraceEvent(i32),
+    /// the program exec'ed is a memfd, following `Exec`
+    FilelessExec(FilelessExec),
+    /// program or shared library loaded, following `Exec` or `Mapping`,
+    /// see `TaskEventCB::hash_binaries`
+    BinaryLoaded(BinaryImage),
+    /// task interrupted at the end of its timeslice, at the ticks given,
+    /// see `TaskEventCB::timeslice`
+    Preempted(u64),
+    /// asynchronous signal received at the point given, following `Signal`,
+    /// if ticks are counted, see `TaskEventCB::set_replay_signals`
+    AsyncSignal(Signal, ExecPoint),
+    /// data returned by the syscall in memory, following its `SyscallExit`,
+    /// see `TaskEventCB::record_data`
+    SyscallData(SyscallNo, Vec<u8>),
+    /// size and sha256 of the data returned by the syscall, instead of
+    /// `SyscallData` if not selected, see `TaskEventCB::data_selection`
+    SyscallDigest(SyscallNo, usize, String),
+    /// all tasks have been blocked in the same syscalls for `Duration`,
+    /// once until they make progress, see `TaskEventCB::idle_after`
+    Idle(Duration),
+    /// probe hit, with the values of its arguments, `None` if not read, see
+    /// `TaskEventCB::probes`
+    Probe(String, Vec<Option<u64>>),
+    /// frames of the user-space stack of the syscall, its pc first,
+    /// following its `SyscallEnter`, see `TaskEventCB::stack_frames`
+    SyscallStack(SyscallNo, Vec<Frame>),
+    /// strings the syscall is given, by argument: paths in full, buffers
+    /// up to the limit, following its `SyscallEnter`, see
+    /// `TaskEventCB::string_limit`
+    SyscallStrings(SyscallNo, Vec<(usize, Vec<u8>)>),
+}
+
+/// `Event` discriminant, without payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Calibration,
+    SyscallEnter,
+    SyscallExit,
+    Exec,
+    Fork,
+    Spawn,
+    Clone,
+    Signal,
+    Exited,
+    SharedMemory,
+    Zombie,
+    Reaped,
+    Orphaned,
+    Mapping,
+    Quarantined,
+    UnknownPtraceEvent,
+    FilelessExec,
+    BinaryLoaded,
+    Preempted,
+    AsyncSignal,
+    SyscallData,
+    SyscallDigest,
+    Idle,
+    Probe,
+    SyscallStack,
+    SyscallStrings,
+}
+
+/// number of `EventKind`s
+pub const EVENT_KINDS: usize = 26;
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::Calibration(_) => EventKind::Calibration,
+            Event::SyscallEnter(_, _) => EventKind::SyscallEnter,
+            Event::SyscallExit(_, _, _) => EventKind::SyscallExit,
+            Event::Exec => EventKind::Exec,
+            Event::Fork(_) => EventKind::Fork,
+            Event::Spawn(_) => EventKind::Spawn,
+            Event::Clone(_) => EventKind::Clone,
+            Event::Signal(_) => EventKind::Signal,
+            Event::Exited(_) => EventKind::Exited,
+            Event::SharedMemory(_) => EventKind::SharedMemory,
+            Event::Zombie(_) => EventKind::Zombie,
+            Event::Reaped(_) => EventKind::Reaped,
+            Event::Orphaned(_) => EventKind::Orphaned,
+            Event::Mapping(_) => EventKind::Mapping,
+            Event::Quarantined(_) => EventKind::Quarantined,
+            Event::UnknownPtraceEvent(_) => EventKind::UnknownPtraceEvent,
+            Event::FilelessExec(_) => EventKind::FilelessExec,
+            Event::BinaryLoaded(_) => EventKind::BinaryLoaded,
+            Event::Preempted(_) => EventKind::Preempted,
+            Event::AsyncSignal(_, _) => EventKind::AsyncSignal,
+            Event::SyscallData(_, _) => EventKind::SyscallData,
+            Event::SyscallDigest(_, _, _) => EventKind::SyscallDigest,
+            Event::Idle(_) => EventKind::Idle,
+            Event::Probe(_, _) => EventKind::Probe,
+            Event::SyscallStack(_, _) => EventKind::SyscallStack,
+            Event::SyscallStrings(_, _) => EventKind::SyscallStrings,
+        }
+    }
+}
+
+/// `Event` with the task it belongs to, and when it was observed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimedEvent {
+    pub tid: Pid,
+    pub at: Timestamp,
+    /// ticks of the task when observed, see `TaskEventCB::ticks`
+    pub ticks: Option<u64>,
+    pub event: Event,
+}
+
+pub type EventSink = Box<dyn FnMut(&TimedEvent)>;
+
+/// returns seccomp bpf bytecode to install into a task which just exec'ed
+/// the given program, `None` keeps the inherited filter only. programs
+/// exec'ed from a memfd are given by their saved image, if saved, see
+/// `TaskEventCB::fileless_dir`.
+///
+/// NB: filters are stacked, the new filter cannot untrace syscalls traced by
+/// inherited filters, and must whitelist the untraced syscall ip
+/// (`0x7000_0002`), or tracer injected syscalls would trap as well.
+pub type ExecFilterFn = Box<dyn FnMut(&dyn Task, &Path) -> Option<Vec<u64>>>;
+
+/// returns the next asynchronous signal recorded for task `tid`, and the
+/// point to deliver it at, `None` if none left
+pub type ReplaySignalFn = Box<dyn FnMut(Pid) -> Option<(Signal, ExecPoint)>>;
 
 pub type EventHandler = Box<dyn FnMut(&dyn Task) -> io::Result<()>>;
 
@@ -30,6 +213,62 @@ pub struct TaskEventCB {
     pub on_task_fork: Box<dyn FnMut(&mut dyn Task) -> io::Result<()>>,
     pub on_task_clone: Box<dyn FnMut(&mut dyn Task) -> io::Result<()>>,
     pub on_task_exit: Box<dyn FnOnce(i32) -> io::Result<()>>,
+    /// receives every `TimedEvent`, if set
+    pub on_event: Option<EventSink>,
+    /// seccomp filter to install on exec, if set
+    pub on_exec_filter: Option<ExecFilterFn>,
+    /// rewrites child status returned by `wait4`/`waitid`, if set
+    pub on_wait_filter: Option<WaitFilterFn>,
+    /// hides, or adds entries of directories read by `getdents64`, if set
+    pub on_dirent_filter: Option<DirentFilterFn>,
+    /// decides whether signals sent by tracees are allowed, if set
+    pub on_signal_filter: Option<SignalFilterFn>,
+    /// called with memory mapping changes, if set
+    pub on_mapping_change: Option<MappingChangeFn>,
+    /// syscalls emulated by the tool, if set
+    pub on_syscall_emulation: Option<SyscallEmulation>,
+    /// asynchronous signals to replay, if set
+    pub on_replay_signal: Option<ReplaySignalFn>,
+    /// how shared memory syscalls are handled
+    pub shared_memory: SharedMemoryPolicy,
+    /// whether fd provenance is tracked, at the cost of ptracing syscalls
+    /// changing fds
+    pub fd_provenance: bool,
+    /// what to do with syscalls denied by a policy
+    pub violation: ViolationAction,
+    /// how writable and executable mappings are handled
+    pub wx_policy: WxPolicy,
+    /// programs `wx_policy` does not apply to, see `is_wx_allowed`
+    pub wx_allowlist: Vec<String>,
+    /// directory images of fileless execs are saved to, see `FilelessExec`
+    pub fileless_dir: Option<PathBuf>,
+    /// whether programs and shared libraries loaded are hashed, at the cost
+    /// of ptracing `mmap`s, see `BinaryImage`
+    pub hash_binaries: bool,
+    /// modules to collect coverage of, at the cost of ptracing `mmap`s
+    pub coverage: Option<CoverageSpec>,
+    /// whether conditional branches retired by tasks ("ticks") are counted,
+    /// and given with their events
+    pub ticks: bool,
+    /// ticks tasks are preempted after, reported by `Event::Preempted`, if
+    /// ticks are counted
+    pub timeslice: Option<u64>,
+    /// whether data returned by syscalls reading into memory is given by
+    /// `Event::SyscallData`, at the cost of ptracing them
+    pub record_data: bool,
+    /// data given in full if recorded, by digest otherwise
+    pub data_selection: DataSelection,
+    /// time all tasks are blocked in syscalls before `Event::Idle`, if set
+    pub idle_after: Option<Duration>,
+    /// probes planted, at the cost of ptracing `mmap`s, see `ProbeSpec`
+    pub probes: Vec<ProbeSpec>,
+    /// frames of the user-space stack captured at syscall entry, at the
+    /// cost of reading it, reported by `Event::SyscallStack`, if set
+    pub stack_frames: Option<usize>,
+    /// bytes of buffers given to syscalls read at syscall entry (as
+    /// strace's `-s`), with paths, reported by `Event::SyscallStrings`, if
+    /// set
+    pub string_limit: Option<usize>,
 }
 
 impl TaskEventCB {
@@ -44,6 +283,128 @@ impl TaskEventCB {
             on_task_fork: forkfn,
             on_task_clone: clonefn,
             on_task_exit: exitfn,
+            on_event: None,
+            on_exec_filter: None,
+            on_wait_filter: None,
+            on_dirent_filter: None,
+            on_signal_filter: None,
+            on_mapping_change: None,
+            on_syscall_emulation: None,
+            on_replay_signal: None,
+            shared_memory: SharedMemoryPolicy::default(),
+            fd_provenance: false,
+            violation: ViolationAction::default(),
+            wx_policy: WxPolicy::default(),
+            wx_allowlist: DEFAULT_WX_ALLOWLIST
+                .iter()
+                .map(|program| program.to_string())
+                .collect(),
+            fileless_dir: None,
+            hash_binaries: false,
+            coverage: None,
+            ticks: false,
+            timeslice: None,
+            record_data: false,
+            data_selection: DataSelection::default(),
+            idle_after: None,
+            probes: Vec::new(),
+            stack_frames: None,
+            string_limit: None,
+        }
+    }
+
+    /// set `sink` to receive all `TimedEvent`s
+    pub fn set_event_sink(&mut self, sink: EventSink) {
+        self.on_event = Some(sink);
+    }
+
+    /// add `sink` to receive all `TimedEvent`s, after the sink set (if any)
+    pub fn add_event_sink(&mut self, mut sink: EventSink) {
+        self.on_event = match self.on_event.take() {
+            None => Some(sink),
+            Some(mut first) => Some(Box::new(move |event| {
+                first(event);
+                sink(event);
+            })),
+        };
+    }
+
+    /// set `filter` to (re)generate seccomp filters on exec
+    pub fn set_exec_filter(&mut self, filter: ExecFilterFn) {
+        self.on_exec_filter = Some(filter);
+    }
+
+    /// set `filter` to rewrite child status `wait`ed by traced parents
+    pub fn set_wait_filter(&mut self, filter: WaitFilterFn) {
+        self.on_wait_filter = Some(filter);
+    }
+
+    /// set `filter` to hide, or add entries of directories listed by
+    /// tracees
+    pub fn set_dirent_filter(&mut self, filter: DirentFilterFn) {
+        self.on_dirent_filter = Some(filter);
+    }
+
+    /// set `filter` to observe (and veto) signals sent by tracees
+    pub fn set_signal_filter(&mut self, filter: SignalFilterFn) {
+        self.on_signal_filter = Some(filter);
+    }
+
+    /// set `handler` to be called with memory mapping changes, which are
+    /// also reported as `Event::Mapping`. `mmap`, `mremap`, `mprotect`,
+    /// `pkey_mprotect` and `munmap` are never patched once set.
+    pub fn set_mapping_handler(&mut self, handler: MappingChangeFn) {
+        self.on_mapping_change = Some(handler);
+    }
+
+    /// set `emulation` to emulate the selected syscalls, which are still
+    /// reported as `Event::SyscallEnter` and `Event::SyscallExit`
+    pub fn set_syscall_emulation(&mut self, emulation: SyscallEmulation) {
+        self.on_syscall_emulation = Some(emulation);
+    }
+
+    /// chain `emulation` after the syscall emulation set (if any), see
+    /// `SyscallEmulation::chain`
+    pub fn chain_syscall_emulation(&mut self, emulation: SyscallEmulation) {
+        let emulation = match self.on_syscall_emulation.take() {
+            Some(existing) => existing.chain(emulation),
+            None => emulation,
+        };
+        self.set_syscall_emulation(emulation);
+    }
+
+    /// chain `emulation`, and add `sink` observing its events, as tools
+    /// tracing syscalls by emulation do
+    pub fn add_syscall_emulation(
+        &mut self,
+        emulation: SyscallEmulation,
+        sink: EventSink,
+    ) {
+        self.chain_syscall_emulation(emulation);
+        self.add_event_sink(sink);
+    }
+
+    /// set `signals` to replay asynchronous signals, by `Event::AsyncSignal`
+    /// of a recording, if ticks are counted: they are delivered at the
+    /// same `ExecPoint`, and asynchronous signals received are suppressed.
+    pub fn set_replay_signals(&mut self, signals: ReplaySignalFn) {
+        self.on_replay_signal = Some(signals);
+    }
+
+    /// pass `event` to the event sink (if any)
+    pub fn emit(&mut self, tid: Pid, at: Timestamp, event: Event) {
+        self.emit_timed(&TimedEvent {
+            tid,
+            at,
+            ticks: None,
+            event,
+        });
+    }
+
+    /// pass `event` to the event sink (if any)
+    pub fn emit_timed(&mut self, event: &TimedEvent) {
+        if let Some(sink) = self.on_event.as_mut() {
+            sink(event);
         }
     }
 }
@@ -0,0 +1,204 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! fileless execution
+//!
+//! programs can be exec'ed with no path on disk: written to a memfd
+//! (`memfd_create`), then exec'ed by fd (`fexecve`, or `execve` of
+//! `/proc/self/fd/<fd>`). the image is captured when such a program is
+//! exec'ed: `FilelessExec` has its sha256, and where it was saved, if
+//! saved, so that it can be audited (or analyzed) afterwards.
+
+use std::path::{Path, PathBuf};
+
+/// a program exec'ed from a memfd, see `Event::FilelessExec`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilelessExec {
+    /// name the memfd was created with
+    pub memfd: String,
+    /// size of the image, in bytes
+    pub size: u64,
+    /// sha256 of the image, in hex
+    pub sha256: String,
+    /// copy of the image, named by its sha256, see `TaskEventCB::fileless_dir`
+    pub saved: Option<PathBuf>,
+}
+
+/// name of the memfd `path` (of `/proc/<pid>/exe`, or of an fd) is, `None`
+/// if not a memfd
+pub fn memfd_name(path: &Path) -> Option<&str> {
+    let path = path.to_str()?.strip_prefix("/memfd:")?;
+    Some(path.strip_suffix(" (deleted)").unwrap_or(path))
+}
+
+const K: [u32; 64] = [
+    0x428a_2f98,
+    0x7137_4491,
+    0xb5c0_fbcf,
+    0xe9b5_dba5,
+    0x3956_c25b,
+    0x59f1_11f1,
+    0x923f_82a4,
+    0xab1c_5ed5,
+    0xd807_aa98,
+    0x1283_5b01,
+    0x2431_85be,
+    0x550c_7dc3,
+    0x72be_5d74,
+    0x80de_b1fe,
+    0x9bdc_06a7,
+    0xc19b_f174,
+    0xe49b_69c1,
+    0xefbe_4786,
+    0x0fc1_9dc6,
+    0x240c_a1cc,
+    0x2de9_2c6f,
+    0x4a74_84aa,
+    0x5cb0_a9dc,
+    0x76f9_88da,
+    0x983e_5152,
+    0xa831_c66d,
+    0xb003_27c8,
+    0xbf59_7fc7,
+    0xc6e0_0bf3,
+    0xd5a7_9147,
+    0x06ca_6351,
+    0x1429_2967,
+    0x27b7_0a85,
+    0x2e1b_2138,
+    0x4d2c_6dfc,
+    0x5338_0d13,
+    0x650a_7354,
+    0x766a_0abb,
+    0x81c2_c92e,
+    0x9272_2c85,
+    0xa2bf_e8a1,
+    0xa81a_664b,
+    0xc24b_8b70,
+    0xc76c_51a3,
+    0xd192_e819,
+    0xd699_0624,
+    0xf40e_3585,
+    0x106a_a070,
+    0x19a4_c116,
+    0x1e37_6c08,
+    0x2748_774c,
+    0x34b0_bcb5,
+    0x391c_0cb3,
+    0x4ed8_aa4a,
+    0x5b9c_ca4f,
+    0x682e_6ff3,
+    0x748f_82ee,
+    0x78a5_636f,
+    0x84c8_7814,
+    0x8cc7_0208,
+    0x90be_fffa,
+    0xa450_6ceb,
+    0xbef9_a3f7,
+    0xc671_78f2,
+];
+
+const H: [u32; 8] = [
+    0x6a09_e667,
+    0xbb67_ae85,
+    0x3c6e_f372,
+    0xa54f_f53a,
+    0x510e_527f,
+    0x9b05_688c,
+    0x1f83_d9ab,
+    0x5be0_cd19,
+];
+
+fn sha256_block(state: &mut [u32; 8], block: &[u8]) {
+    let mut w = [0u32; 64];
+    for (k, word) in block.chunks(4).enumerate() {
+        w[k] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for k in 16..64 {
+        let s0 = w[k - 15].rotate_right(7)
+            ^ w[k - 15].rotate_right(18)
+            ^ (w[k - 15] >> 3);
+        let s1 = w[k - 2].rotate_right(17)
+            ^ w[k - 2].rotate_right(19)
+            ^ (w[k - 2] >> 10);
+        w[k] = w[k - 16]
+            .wrapping_add(s0)
+            .wrapping_add(w[k - 7])
+            .wrapping_add(s1);
+    }
+    let mut v = *state;
+    for k in 0..64 {
+        let s1 = v[4].rotate_right(6)
+            ^ v[4].rotate_right(11)
+            ^ v[4].rotate_right(25);
+        let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+        let t1 = v[7]
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(K[k])
+            .wrapping_add(w[k]);
+        let s0 = v[0].rotate_right(2)
+            ^ v[0].rotate_right(13)
+            ^ v[0].rotate_right(22);
+        let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+        let t2 = s0.wrapping_add(maj);
+        v.copy_within(0..7, 1);
+        v[4] = v[4].wrapping_add(t1);
+        v[0] = t1.wrapping_add(t2);
+    }
+    for (h, v) in state.iter_mut().zip(v.iter()) {
+        *h = h.wrapping_add(*v);
+    }
+}
+
+/// sha256 of `bytes`, in hex
+pub fn sha256(bytes: &[u8]) -> String {
+    let mut state = H;
+    let mut chunks = bytes.chunks_exact(64);
+    for block in &mut chunks {
+        sha256_block(&mut state, block);
+    }
+    // padding: 0x80, zeros, then the size in bits
+    let mut last = Vec::from(chunks.remainder());
+    last.push(0x80);
+    last.resize(if last.len() > 56 { 120 } else { 56 }, 0);
+    last.extend_from_slice(&(bytes.len() as u64 * 8).to_be_bytes());
+    for block in last.chunks(64) {
+        sha256_block(&mut state, block);
+    }
+    state.iter().map(|h| format!("{:08x}", h)).collect()
+}
+
+#[test]
+fn fileless_sanity_check() {
+    assert_eq!(
+        sha256(b""),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+        sha256(b"abc"),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    // padded into two blocks
+    assert_eq!(
+        sha256(&[b'a'; 56]),
+        "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a"
+    );
+    assert_eq!(
+        sha256(&[b'a'; 1000]),
+        "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+    );
+    let exe = Path::new("/memfd:payload (deleted)");
+    assert_eq!(memfd_name(exe), Some("payload"));
+    assert_eq!(memfd_name(Path::new("/usr/bin/true")), None);
+}
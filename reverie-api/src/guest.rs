@@ -0,0 +1,288 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! kernel ABI structs in tracee memory
+//!
+//! `GuestStruct` reads plain old data structs (`struct stat`, `sockaddr_*`,
+//! `iovec`, `msghdr`, `timespec`, ..) from a `TaskMemory`, and is declared
+//! for other such structs with `guest_struct!`. pointers in structs are
+//! followed by `read_iovecs`, `read_iovec_data` and `read_msghdr`, so that
+//! decoders and tools don't do offset math of their own.
+
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use crate::emulate::TaskMemory;
+
+/// plain old data struct, which can be read from (and written to) tracee
+/// memory.
+///
+/// # Safety
+///
+/// any bit pattern must be a valid value of the type, i.e.: no references,
+/// `bool`s or enums.
+pub unsafe trait GuestStruct: Copy {
+    /// read the struct at `addr`
+    fn read(memory: &dyn TaskMemory, addr: u64) -> Result<Self> {
+        let size = std::mem::size_of::<Self>();
+        let bytes = memory.read_bytes(addr, size)?;
+        if bytes.len() < size {
+            return Err(Error::from_raw_os_error(libc::EFAULT));
+        }
+        Ok(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const Self) })
+    }
+
+    /// read `count` consecutive structs at `addr`
+    fn read_array(
+        memory: &dyn TaskMemory,
+        addr: u64,
+        count: usize,
+    ) -> Result<Vec<Self>> {
+        let size = std::mem::size_of::<Self>();
+        let bytes = memory.read_bytes(addr, size * count)?;
+        if bytes.len() < size * count {
+            return Err(Error::from_raw_os_error(libc::EFAULT));
+        }
+        Ok(bytes
+            .chunks(size)
+            .map(|chunk| unsafe {
+                std::ptr::read_unaligned(chunk.as_ptr() as *const Self)
+            })
+            .collect())
+    }
+
+    /// write the struct at `addr`
+    fn write(&self, memory: &dyn TaskMemory, addr: u64) -> Result<()> {
+        let size = std::mem::size_of::<Self>();
+        let bytes = unsafe {
+            std::slice::from_raw_parts(self as *const Self as *const u8, size)
+        };
+        memory.write_bytes(addr, bytes)
+    }
+}
+
+/// declare plain old data types as `GuestStruct`s, see its safety section
+#[macro_export]
+macro_rules! guest_struct {
+    ($($ty:ty),* $(,)?) => {
+        $(unsafe impl $crate::guest::GuestStruct for $ty {})*
+    };
+}
+
+guest_struct!(u8, u16, u32, u64, i8, i16, i32, i64, usize, isize);
+guest_struct!(
+    libc::stat,
+    libc::statx,
+    libc::timespec,
+    libc::timeval,
+    libc::iovec,
+    libc::msghdr,
+    libc::mmsghdr,
+    libc::cmsghdr,
+    libc::sockaddr,
+    libc::sockaddr_in,
+    libc::sockaddr_in6,
+    libc::sockaddr_un,
+    libc::sockaddr_storage,
+);
+
+/// `timespec` as a `Duration`, `None` if negative or not normalized
+pub fn timespec_duration(ts: &libc::timespec) -> Option<Duration> {
+    if ts.tv_sec < 0 || ts.tv_nsec < 0 || ts.tv_nsec >= 1_000_000_000 {
+        None
+    } else {
+        Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+    }
+}
+
+/// `timeval` as a `Duration`, `None` if negative or not normalized
+pub fn timeval_duration(tv: &libc::timeval) -> Option<Duration> {
+    if tv.tv_sec < 0 || tv.tv_usec < 0 || tv.tv_usec >= 1_000_000 {
+        None
+    } else {
+        Some(Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000))
+    }
+}
+
+/// `iovcnt` `struct iovec`s at `addr`, as `(base, len)`
+pub fn read_iovecs(
+    memory: &dyn TaskMemory,
+    addr: u64,
+    iovcnt: usize,
+) -> Result<Vec<(u64, usize)>> {
+    Ok(libc::iovec::read_array(memory, addr, iovcnt)?
+        .iter()
+        .map(|iov| (iov.iov_base as u64, iov.iov_len))
+        .collect())
+}
+
+/// bytes of `iovecs`, up to `limit` bytes
+pub fn read_iovec_data(
+    memory: &dyn TaskMemory,
+    iovecs: &[(u64, usize)],
+    limit: usize,
+) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for &(base, len) in iovecs {
+        let len = len.min(limit - bytes.len());
+        if len == 0 {
+            break;
+        }
+        bytes.extend(memory.read_bytes(base, len)?);
+    }
+    Ok(bytes)
+}
+
+/// ancillary data of a `msghdr`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlMessage {
+    pub level: i32,
+    pub kind: i32,
+    pub data: Vec<u8>,
+}
+
+impl ControlMessage {
+    /// file descriptors passed by `SCM_RIGHTS`
+    pub fn rights(&self) -> Option<Vec<RawFd>> {
+        if self.level != libc::SOL_SOCKET || self.kind != libc::SCM_RIGHTS {
+            return None;
+        }
+        Some(
+            self.data
+                .chunks_exact(4)
+                .map(|fd| RawFd::from_le_bytes([fd[0], fd[1], fd[2], fd[3]]))
+                .collect(),
+        )
+    }
+}
+
+/// `struct msghdr` with its pointers followed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// raw `sockaddr` of `msg_name`, if any
+    pub name: Option<Vec<u8>>,
+    /// `msg_iov`, as `(base, len)`
+    pub iov: Vec<(u64, usize)>,
+    pub control: Vec<ControlMessage>,
+    pub flags: i32,
+}
+
+/// `CMSG_ALIGN`, control messages are aligned to it
+pub fn cmsg_align(len: usize) -> usize {
+    let align = std::mem::size_of::<usize>();
+    (len + align - 1) & !(align - 1)
+}
+
+/// control messages of `control`, the `msg_control` buffer
+pub fn parse_control(control: &[u8]) -> Result<Vec<ControlMessage>> {
+    let header = std::mem::size_of::<libc::cmsghdr>();
+    let mut messages = Vec::new();
+    let mut offset = 0;
+    while offset + header <= control.len() {
+        let cmsg = unsafe {
+            std::ptr::read_unaligned(
+                control[offset..].as_ptr() as *const libc::cmsghdr
+            )
+        };
+        let len = cmsg.cmsg_len as usize;
+        if len < header || offset + len > control.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "bad cmsg_len"));
+        }
+        messages.push(ControlMessage {
+            level: cmsg.cmsg_level,
+            kind: cmsg.cmsg_type,
+            data: control[offset + header..offset + len].to_vec(),
+        });
+        offset += cmsg_align(len);
+    }
+    Ok(messages)
+}
+
+/// `struct msghdr` at `addr`, see `Message`
+pub fn read_msghdr(memory: &dyn TaskMemory, addr: u64) -> Result<Message> {
+    let msg = libc::msghdr::read(memory, addr)?;
+    let name = if msg.msg_name.is_null() {
+        None
+    } else {
+        let len = msg.msg_namelen as usize;
+        Some(memory.read_bytes(msg.msg_name as u64, len)?)
+    };
+    let iov = read_iovecs(memory, msg.msg_iov as u64, msg.msg_iovlen)?;
+    let control = if msg.msg_control.is_null() {
+        Vec::new()
+    } else {
+        let len = msg.msg_controllen;
+        parse_control(&memory.read_bytes(msg.msg_control as u64, len)?)?
+    };
+    Ok(Message {
+        name,
+        iov,
+        control,
+        flags: msg.msg_flags,
+    })
+}
+
+#[test]
+fn guest_struct_sanity_check() {
+    use std::cell::RefCell;
+
+    struct Memory(RefCell<Vec<u8>>);
+    impl TaskMemory for Memory {
+        fn read_bytes(&self, addr: u64, size: usize) -> Result<Vec<u8>> {
+            let mem = self.0.borrow();
+            let end = std::cmp::min(addr as usize + size, mem.len());
+            Ok(Vec::from(&mem[addr as usize..end]))
+        }
+        fn write_bytes(&self, addr: u64, bytes: &[u8]) -> Result<()> {
+            let addr = addr as usize;
+            self.0.borrow_mut()[addr..addr + bytes.len()]
+                .copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+    let memory = Memory(RefCell::new(vec![0; 512]));
+    let write_u64 = |addr, value: u64| {
+        memory.write_bytes(addr, &value.to_le_bytes()).unwrap()
+    };
+
+    write_u64(0, 3);
+    write_u64(8, 500_000_000);
+    let ts = libc::timespec::read(&memory, 0).unwrap();
+    assert_eq!(timespec_duration(&ts), Some(Duration::from_millis(3500)));
+    assert!(libc::timespec::read(&memory, 508).is_err());
+
+    // msghdr at 0, iovecs at 64, data at 128, control at 192.
+    memory.write_bytes(128, b"hello").unwrap();
+    write_u64(64, 128);
+    write_u64(72, 5);
+    write_u64(80, 131);
+    write_u64(88, 2);
+    let mut cmsg = Vec::new();
+    cmsg.extend_from_slice(&20u64.to_le_bytes());
+    cmsg.extend_from_slice(&libc::SOL_SOCKET.to_le_bytes());
+    cmsg.extend_from_slice(&libc::SCM_RIGHTS.to_le_bytes());
+    cmsg.extend_from_slice(&7i32.to_le_bytes());
+    memory.write_bytes(192, &cmsg).unwrap();
+    let hdr: [u64; 7] = [0, 0, 64, 2, 192, 24, 0];
+    for (k, value) in hdr.iter().enumerate() {
+        write_u64(8 * k as u64, *value);
+    }
+    let msg = read_msghdr(&memory, 0).unwrap();
+    assert_eq!(msg.name, None);
+    assert_eq!(msg.iov, vec![(128, 5), (131, 2)]);
+    let data = read_iovec_data(&memory, &msg.iov, 6).unwrap();
+    assert_eq!(data, b"hellol".to_vec());
+    assert_eq!(msg.control.len(), 1);
+    assert_eq!(msg.control[0].rights(), Some(vec![7]));
+}
@@ -0,0 +1,276 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! syscalls on behalf of tasks, for tools
+//!
+//! `Injector::inject_syscall` does a syscall in a task, i.e.: in the exec
+//! callback, or in a syscall emulation. the task must be stopped, and past
+//! the exec of its program: the syscall is done by the stub the tracer maps
+//! in each task. it is not traced, and registers of the task are restored
+//! after, the task does not see it, but for its side effects.
+//!
+//! helpers here copy arguments in memory (i.e.: paths) below the stack of
+//! the task, past its red zone, which is free while the task is stopped.
+//! `remote_syscall` copies them in a buffer mapped in the task instead,
+//! for any syscall.
+//!
+//! file operations as the task does them (in its mount namespace, relative
+//! to its working directory, with its credentials), i.e.: when the tracer
+//! runs in another namespace, shuttle data through a buffer mapped in the
+//! task, see `remote_read_file`.
+
+use nix::sys::ptrace;
+use std::ffi::CString;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use syscalls::SyscallNo;
+
+use crate::remote::*;
+use crate::task::Task;
+
+/// bytes below the stack pointer a leaf function may use, of the x86_64
+/// SysV ABI
+const RED_ZONE: u64 = 128;
+
+/// size of buffers mapped in tasks, to shuttle data through
+const BUFFER_SIZE: usize = 0x10000;
+
+// anonymous mapping of `BUFFER_SIZE` in a task, unmapped when dropped
+struct RemoteBuffer<'a> {
+    task: &'a dyn Task,
+    addr: u64,
+}
+
+impl<'a> RemoteBuffer<'a> {
+    fn new(task: &'a dyn Task) -> Result<Self> {
+        let addr = remote_mmap(
+            task,
+            0,
+            BUFFER_SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )?;
+        Ok(RemoteBuffer { task, addr })
+    }
+
+    fn ptr(&self) -> RemotePtr<u8> {
+        RemotePtr::new(self.addr as *mut u8).unwrap()
+    }
+}
+
+impl<'a> Drop for RemoteBuffer<'a> {
+    fn drop(&mut self) {
+        let _ = remote_munmap(self.task, self.addr, BUFFER_SIZE);
+    }
+}
+
+// copy `bytes` below the stack of `task`, returns their address
+fn push_bytes(task: &dyn Task, bytes: &[u8]) -> Result<u64> {
+    let regs = ptrace::getregs(task.gettid())
+        .map_err(|err| Error::new(ErrorKind::Other, err))?;
+    let addr = (regs.rsp - RED_ZONE - bytes.len() as u64) & !0xf;
+    let ptr = RemotePtr::new(addr as *mut u8)
+        .ok_or_else(|| Error::from_raw_os_error(libc::EFAULT))?;
+    ptrace_poke_bytes(task.gettid(), ptr, bytes)?;
+    Ok(addr)
+}
+
+/// `openat(AT_FDCWD, path, flags, mode)` in `task`, returns the fd opened,
+/// relative paths are relative to the working directory of `task`
+pub fn remote_open(
+    task: &dyn Task,
+    path: &Path,
+    flags: i32,
+    mode: u32,
+) -> Result<i32> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    let addr = push_bytes(task, path.as_bytes_with_nul())?;
+    let args = SyscallArgs::from(
+        libc::AT_FDCWD as u64,
+        addr,
+        flags as u64,
+        u64::from(mode),
+        0,
+        0,
+    );
+    let fd = task.inject_syscall(SyscallNo::SYS_openat, &args)?;
+    Ok(fd as i32)
+}
+
+/// `close(fd)` in `task`
+pub fn remote_close(task: &dyn Task, fd: i32) -> Result<()> {
+    let args = SyscallArgs::from(fd as u64, 0, 0, 0, 0, 0);
+    task.inject_syscall(SyscallNo::SYS_close, &args)?;
+    Ok(())
+}
+
+/// `mmap(addr, len, prot, flags, fd, offset)` in `task`, returns the
+/// address mapped
+pub fn remote_mmap(
+    task: &dyn Task,
+    addr: u64,
+    len: usize,
+    prot: i32,
+    flags: i32,
+    fd: i32,
+    offset: i64,
+) -> Result<u64> {
+    let args = SyscallArgs::from(
+        addr,
+        len as u64,
+        prot as u64,
+        flags as u64,
+        fd as u64,
+        offset as u64,
+    );
+    let mapped = task.inject_syscall(SyscallNo::SYS_mmap, &args)?;
+    Ok(mapped as u64)
+}
+
+/// `munmap(addr, len)` in `task`
+pub fn remote_munmap(task: &dyn Task, addr: u64, len: usize) -> Result<()> {
+    let args = SyscallArgs::from(addr, len as u64, 0, 0, 0, 0);
+    task.inject_syscall(SyscallNo::SYS_munmap, &args)?;
+    Ok(())
+}
+
+/// an argument of `remote_syscall`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoteArg<'a> {
+    /// passed as is
+    Value(u64),
+    /// copied in `task`, passed by address
+    Bytes(&'a [u8]),
+    /// zeroed buffer of a size in `task`, passed by address
+    Buffer(usize),
+}
+
+/// `syscall` in `task`, with `args` in memory copied in a buffer mapped in
+/// `task`, up to `BUFFER_SIZE`. returns what the syscall returned, and the
+/// contents of `RemoteArg::Buffer` args after it, in order.
+pub fn remote_syscall(
+    task: &dyn Task,
+    syscall: SyscallNo,
+    args: &[RemoteArg],
+) -> Result<(i64, Vec<Vec<u8>>)> {
+    if args.len() > 6 {
+        return Err(Error::from_raw_os_error(libc::E2BIG));
+    }
+    // offsets of args in the buffer, 8 bytes aligned
+    let mut offsets = Vec::with_capacity(args.len());
+    let mut size = 0;
+    for arg in args {
+        offsets.push(size);
+        size += match arg {
+            RemoteArg::Value(_) => 0,
+            RemoteArg::Bytes(bytes) => (bytes.len() + 7) & !7,
+            RemoteArg::Buffer(len) => (len + 7) & !7,
+        };
+    }
+    if size > BUFFER_SIZE {
+        return Err(Error::from_raw_os_error(libc::E2BIG));
+    }
+    let buffer = RemoteBuffer::new(task)?;
+    let mut values = [0u64; 6];
+    for (k, arg) in args.iter().enumerate() {
+        let addr = buffer.addr + offsets[k] as u64;
+        values[k] = match arg {
+            RemoteArg::Value(value) => *value,
+            RemoteArg::Bytes(bytes) => {
+                let ptr = RemotePtr::new(addr as *mut u8).unwrap();
+                ptrace_poke_bytes(task.gettid(), ptr, bytes)?;
+                addr
+            }
+            // mapped anonymous, zeroed already
+            RemoteArg::Buffer(_) => addr,
+        };
+    }
+    let [a0, a1, a2, a3, a4, a5] = values;
+    let syscall_args = SyscallArgs::from(a0, a1, a2, a3, a4, a5);
+    let retval = task.inject_syscall(syscall, &syscall_args)?;
+    let mut buffers = Vec::new();
+    for (k, arg) in args.iter().enumerate() {
+        if let RemoteArg::Buffer(len) = arg {
+            let ptr = RemotePtr::new(values[k] as *mut u8).unwrap();
+            buffers.push(ptrace_peek_bytes(task.gettid(), ptr, *len)?);
+        }
+    }
+    Ok((retval, buffers))
+}
+
+/// read file `path` as `task` does, see module doc
+pub fn remote_read_file(task: &dyn Task, path: &Path) -> Result<Vec<u8>> {
+    let fd = remote_open(task, path, libc::O_RDONLY | libc::O_CLOEXEC, 0)?;
+    let data = remote_read_fd(task, fd);
+    remote_close(task, fd)?;
+    data
+}
+
+/// read `fd` of `task` to its end
+pub fn remote_read_fd(task: &dyn Task, fd: i32) -> Result<Vec<u8>> {
+    let buffer = RemoteBuffer::new(task)?;
+    let args =
+        SyscallArgs::from(fd as u64, buffer.addr, BUFFER_SIZE as u64, 0, 0, 0);
+    let mut data = Vec::new();
+    loop {
+        let size = task.inject_syscall(SyscallNo::SYS_read, &args)? as usize;
+        if size == 0 {
+            return Ok(data);
+        }
+        data.extend(ptrace_peek_bytes(task.gettid(), buffer.ptr(), size)?);
+    }
+}
+
+/// write `data` to file `path` as `task` does (see module doc), created
+/// with `mode` if it does not exist, truncated otherwise
+pub fn remote_write_file(
+    task: &dyn Task,
+    path: &Path,
+    data: &[u8],
+    mode: u32,
+) -> Result<()> {
+    let flags =
+        libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC;
+    let fd = remote_open(task, path, flags, mode)?;
+    let written = remote_write_fd(task, fd, data);
+    remote_close(task, fd)?;
+    written
+}
+
+/// write all of `data` to `fd` of `task`
+pub fn remote_write_fd(task: &dyn Task, fd: i32, data: &[u8]) -> Result<()> {
+    let buffer = RemoteBuffer::new(task)?;
+    for chunk in data.chunks(BUFFER_SIZE) {
+        ptrace_poke_bytes(task.gettid(), buffer.ptr(), chunk)?;
+        let mut at = 0;
+        while at < chunk.len() {
+            let args = SyscallArgs::from(
+                fd as u64,
+                buffer.addr + at as u64,
+                (chunk.len() - at) as u64,
+                0,
+                0,
+                0,
+            );
+            match task.inject_syscall(SyscallNo::SYS_write, &args)? {
+                0 => return Err(Error::from(ErrorKind::WriteZero)),
+                size => at += size as usize,
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,62 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! isolating panics of task handling (and of workers)
+//!
+//! a panic handling one task is caught by `catch_panic`, so that the task
+//! is quarantined instead of the tracer aborting. locks held while
+//! panicking are poisoned: the tracer recovers them
+//! (`lock().unwrap_or_else(|e| e.into_inner())`) rather than cascading
+//! the panic to every later handler.
+
+use std::cell::Cell;
+use std::panic::AssertUnwindSafe;
+
+thread_local! {
+    static CATCHING_PANICS: Cell<bool> = Cell::new(false);
+}
+
+/// `catch_unwind` `f`, panics caught are not tracer crashes, see
+/// `trace_output`
+pub fn catch_panic<F: FnOnce() -> R, R>(f: F) -> std::thread::Result<R> {
+    let catching = CATCHING_PANICS.with(|c| c.replace(true));
+    let res = std::panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING_PANICS.with(|c| c.set(catching));
+    res
+}
+
+/// whether panics of the current thread are caught by `catch_panic`
+pub fn is_catching_panics() -> bool {
+    CATCHING_PANICS.with(Cell::get)
+}
+
+/// message of a caught panic
+pub fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        String::from(*msg)
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        String::from("unknown panic")
+    }
+}
+
+#[test]
+fn isolation_sanity_check() {
+    let panic = catch_panic(|| panic!("task {} panicked", 1)).unwrap_err();
+    assert_eq!(panic_message(&*panic), "task 1 panicked");
+    let panic = catch_panic(|| panic!("static")).unwrap_err();
+    assert_eq!(panic_message(&*panic), "static");
+    assert!(!is_catching_panics());
+    assert_eq!(catch_panic(is_catching_panics).ok(), Some(true));
+}
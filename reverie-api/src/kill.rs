@@ -0,0 +1,90 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! signals sent by tracees
+//!
+//! `kill`, `tkill`, `tgkill`, `rt_sigqueueinfo` and `rt_tgsigqueueinfo` are
+//! decoded as `SignalSend`, which tools can observe and veto, see
+//! `SignalFilterFn`. i.e.: "this tree may not signal processes outside
+//! itself".
+
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+
+use crate::task::Task;
+
+/// receiver(s) of a signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SignalTarget {
+    /// process (thread group)
+    Process(Pid),
+    /// thread `tid`, of thread group `tgid` if given
+    Thread { tgid: Option<Pid>, tid: Pid },
+    /// all processes in process group
+    ProcessGroup(Pid),
+    /// all processes the sender can signal, i.e.: `kill(-1, sig)`
+    All,
+}
+
+/// a signal sent by a tracee
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignalSend {
+    pub target: SignalTarget,
+    /// `None` when probing the target (signal 0), or unknown signal
+    pub signal: Option<Signal>,
+    /// whether the target(s) are all traced by us. always `false` for
+    /// `SignalTarget::All`.
+    pub traced: bool,
+}
+
+impl SignalTarget {
+    /// decode target of `kill(pid, ..)`, sent by a process in group `pgid`
+    pub fn from_kill(pid: i32, pgid: Pid) -> Self {
+        match pid {
+            0 => SignalTarget::ProcessGroup(pgid),
+            -1 => SignalTarget::All,
+            pid if pid < 0 => SignalTarget::ProcessGroup(Pid::from_raw(-pid)),
+            pid => SignalTarget::Process(Pid::from_raw(pid)),
+        }
+    }
+}
+
+/// what to do with a `SignalSend`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalVerdict {
+    Allow,
+    /// skip the syscall, fail with errno
+    Deny(i32),
+}
+
+/// decides `SignalVerdict` of signal sent by a task
+pub type SignalFilterFn =
+    Box<dyn FnMut(&dyn Task, &SignalSend) -> SignalVerdict>;
+
+#[test]
+fn signal_target_sanity_check() {
+    let pgid = Pid::from_raw(42);
+    assert_eq!(
+        SignalTarget::from_kill(7, pgid),
+        SignalTarget::Process(Pid::from_raw(7))
+    );
+    assert_eq!(
+        SignalTarget::from_kill(0, pgid),
+        SignalTarget::ProcessGroup(pgid)
+    );
+    assert_eq!(SignalTarget::from_kill(-1, pgid), SignalTarget::All);
+    assert_eq!(
+        SignalTarget::from_kill(-7, pgid),
+        SignalTarget::ProcessGroup(Pid::from_raw(7))
+    );
+}
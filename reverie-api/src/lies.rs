@@ -0,0 +1,661 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! ready-made lies of sandboxes, on syscall emulation, and the seccomp
+//! filter of the preloader
+//!
+//! lies are enabled by name, i.e.: `--lie root --lie cpus=2`:
+//!
+//! - `root`: uids and gids are 0, as of `get*id`, `getres*id`.
+//! - `cpus=N`: `sched_getaffinity` has the first `N` cpus only, as
+//!   `nproc` counts. `/sys/devices/system/cpu` is not faked.
+//! - `memory=BYTES`: `sysinfo` has `BYTES` of free memory, and at least as
+//!   much total memory.
+//! - `disk=FREE[:TOTAL]`: `statfs` and `fstatfs` have `FREE` bytes free
+//!   and available, of `TOTAL` bytes (at least `FREE`), on every
+//!   filesystem; as `df` and low disk space checks see. `statx` has no
+//!   capacity, writes still fail with `ENOSPC` as of the actual disk only.
+//! - `setrlimit`: setting resource limits succeeds, and does nothing.
+//! - `enosys=SYSCALL[,SYSCALL]*`: syscalls fail with `ENOSYS`, by name
+//!   (i.e.: `io_uring_setup` or `SYS_io_uring_setup`) or number. programs
+//!   take their fallback paths of older kernels, i.e.: `clone` for
+//!   `clone3`, `stat` for `statx`. the filter fails them without stops,
+//!   syscalls newer than `syscalls` knows included, see `REVERIE_ENOSYS`;
+//!   so are syscalls of the tool and reverie.
+//! - `kernel=VERSION`: pretend linux `VERSION` (i.e.: `4.19`, 3.5 or
+//!   later), the release of `uname` is `VERSION`, and syscalls added since
+//!   fail with `ENOSYS`, as of `enosys`. `/proc/version` is not faked.
+
+use std::fmt;
+use std::str::FromStr;
+
+use syscalls::SyscallNo;
+
+use crate::emulate::*;
+use crate::remote::SyscallArgs;
+
+/// a lie, see module doc
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lie {
+    Root,
+    Cpus(usize),
+    FreeMemory(u64),
+    /// free, and total bytes if given
+    Disk(u64, Option<u64>),
+    SetRlimit,
+    /// syscall numbers
+    Enosys(Vec<i32>),
+    /// major and minor version
+    Kernel(u32, u32),
+}
+
+/// syscalls newer than `statx`, not known by `syscalls`, to be failed with
+/// `ENOSYS` as of older kernels
+const NEWER_SYSCALLS: &[(&str, i32)] = &[
+    ("io_pgetevents", 333),
+    ("rseq", 334),
+    ("pidfd_send_signal", 424),
+    ("io_uring_setup", 425),
+    ("io_uring_enter", 426),
+    ("io_uring_register", 427),
+    ("open_tree", 428),
+    ("move_mount", 429),
+    ("fsopen", 430),
+    ("fsconfig", 431),
+    ("fsmount", 432),
+    ("fspick", 433),
+    ("pidfd_open", 434),
+    ("clone3", 435),
+    ("close_range", 436),
+    ("openat2", 437),
+    ("pidfd_getfd", 438),
+    ("faccessat2", 439),
+    ("process_madvise", 440),
+    ("epoll_pwait2", 441),
+    ("mount_setattr", 442),
+    ("landlock_create_ruleset", 444),
+    ("landlock_add_rule", 445),
+    ("landlock_restrict_self", 446),
+];
+
+/// syscalls added since linux 3.5, by version
+const SYSCALLS_SINCE: &[(i32, (u32, u32))] = &[
+    (312, (3, 5)),  // kcmp
+    (313, (3, 8)),  // finit_module
+    (314, (3, 14)), // sched_setattr
+    (315, (3, 14)), // sched_getattr
+    (316, (3, 15)), // renameat2
+    (317, (3, 17)), // seccomp
+    (318, (3, 17)), // getrandom
+    (319, (3, 17)), // memfd_create
+    (320, (3, 17)), // kexec_file_load
+    (321, (3, 18)), // bpf
+    (322, (3, 19)), // execveat
+    (323, (4, 3)),  // userfaultfd
+    (324, (4, 3)),  // membarrier
+    (325, (4, 4)),  // mlock2
+    (326, (4, 5)),  // copy_file_range
+    (327, (4, 6)),  // preadv2
+    (328, (4, 6)),  // pwritev2
+    (329, (4, 9)),  // pkey_mprotect
+    (330, (4, 9)),  // pkey_alloc
+    (331, (4, 9)),  // pkey_free
+    (332, (4, 11)), // statx
+    (333, (4, 18)), // io_pgetevents
+    (334, (4, 18)), // rseq
+    (424, (5, 1)),  // pidfd_send_signal
+    (425, (5, 1)),  // io_uring_setup
+    (426, (5, 1)),  // io_uring_enter
+    (427, (5, 1)),  // io_uring_register
+    (428, (5, 2)),  // open_tree
+    (429, (5, 2)),  // move_mount
+    (430, (5, 2)),  // fsopen
+    (431, (5, 2)),  // fsconfig
+    (432, (5, 2)),  // fsmount
+    (433, (5, 2)),  // fspick
+    (434, (5, 3)),  // pidfd_open
+    (435, (5, 3)),  // clone3
+    (436, (5, 9)),  // close_range
+    (437, (5, 6)),  // openat2
+    (438, (5, 6)),  // pidfd_getfd
+    (439, (5, 8)),  // faccessat2
+    (440, (5, 10)), // process_madvise
+    (441, (5, 11)), // epoll_pwait2
+    (442, (5, 12)), // mount_setattr
+    (444, (5, 13)), // landlock_create_ruleset
+    (445, (5, 13)), // landlock_add_rule
+    (446, (5, 13)), // landlock_restrict_self
+];
+
+/// syscall number by name, i.e.: `read` or `SYS_read`, or number
+pub fn syscall_of(name: &str) -> Option<i32> {
+    let max = SyscallNo::SYS_statx as i32;
+    let name = name.trim_start_matches("SYS_");
+    let known = |nr: &i32| {
+        (0..=max).contains(nr)
+            || NEWER_SYSCALLS.iter().any(|&(_, newer)| newer == *nr)
+    };
+    match name.parse::<i32>() {
+        Ok(nr) => Some(nr).filter(known),
+        Err(_) => {
+            (0..=max).find(|nr| syscall_name(*nr) == name).or_else(|| {
+                NEWER_SYSCALLS
+                    .iter()
+                    .find(|&&(newer, _)| newer == name)
+                    .map(|&(_, nr)| nr)
+            })
+        }
+    }
+}
+
+/// name of syscall `nr`, without `SYS_`
+pub fn syscall_name(nr: i32) -> String {
+    match NEWER_SYSCALLS.iter().find(|&&(_, newer)| newer == nr) {
+        Some((name, _)) => name.to_string(),
+        None if nr < 0 || nr > SyscallNo::SYS_statx as i32 => nr.to_string(),
+        None => format!("{:?}", SyscallNo::from(nr))
+            .trim_start_matches("SYS_")
+            .into(),
+    }
+}
+
+impl FromStr for Lie {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut kv = s.splitn(2, '=');
+        let (name, value) = (kv.next().unwrap_or(""), kv.next());
+        let invalid = || format!("invalid lie: {}", s);
+        match (name, value) {
+            ("root", None) => Ok(Lie::Root),
+            ("setrlimit", None) => Ok(Lie::SetRlimit),
+            ("cpus", Some(n)) => match n.parse() {
+                Ok(n) if n > 0 => Ok(Lie::Cpus(n)),
+                _ => Err(invalid()),
+            },
+            ("memory", Some(bytes)) => {
+                bytes.parse().map(Lie::FreeMemory).map_err(|_| invalid())
+            }
+            ("disk", Some(sizes)) => {
+                let mut sizes = sizes.splitn(2, ':');
+                let free = sizes.next().and_then(|free| free.parse().ok());
+                let total = sizes.next().map(|total| total.parse().ok());
+                match (free, total) {
+                    (Some(free), None) => Ok(Lie::Disk(free, None)),
+                    (Some(free), Some(Some(total))) if total >= free => {
+                        Ok(Lie::Disk(free, Some(total)))
+                    }
+                    _ => Err(invalid()),
+                }
+            }
+            ("enosys", Some(names)) => Lie::enosys(names),
+            ("kernel", Some(version)) => Lie::kernel(version),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Lie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Lie::Root => write!(f, "root"),
+            Lie::Cpus(n) => write!(f, "cpus={}", n),
+            Lie::FreeMemory(bytes) => write!(f, "memory={}", bytes),
+            Lie::Disk(free, None) => write!(f, "disk={}", free),
+            Lie::Disk(free, Some(total)) => {
+                write!(f, "disk={}:{}", free, total)
+            }
+            Lie::SetRlimit => write!(f, "setrlimit"),
+            Lie::Enosys(syscalls) => {
+                let names: Vec<_> =
+                    syscalls.iter().cloned().map(syscall_name).collect();
+                write!(f, "enosys={}", names.join(","))
+            }
+            Lie::Kernel(major, minor) => {
+                write!(f, "kernel={}.{}", major, minor)
+            }
+        }
+    }
+}
+
+/// offset of `release` of `struct utsname`, of 65 bytes
+const UTSNAME_RELEASE: u64 = 2 * 65;
+const UTSNAME_LENGTH: usize = 65;
+
+/// offsets of `freeram`, `totalram` and `mem_unit` of `struct sysinfo`
+const SYSINFO_TOTALRAM: u64 = 32;
+const SYSINFO_FREERAM: u64 = 40;
+const SYSINFO_MEM_UNIT: u64 = 104;
+
+/// offsets of `f_bsize`, `f_blocks`, `f_bfree`, `f_bavail` and `f_frsize`
+/// of `struct statfs`
+const STATFS_BSIZE: u64 = 8;
+const STATFS_BLOCKS: u64 = 16;
+const STATFS_BFREE: u64 = 24;
+const STATFS_BAVAIL: u64 = 32;
+const STATFS_FRSIZE: u64 = 72;
+
+fn read_u64(memory: &dyn TaskMemory, addr: u64) -> Option<u64> {
+    let bytes = memory.read_bytes(addr, 8).ok()?;
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&bytes);
+    Some(u64::from_le_bytes(word))
+}
+
+impl Lie {
+    /// syscalls `names`, separated by `,`, fail with `ENOSYS`
+    pub fn enosys(names: &str) -> Result<Self, String> {
+        names
+            .split(',')
+            .filter(|name| !name.trim().is_empty())
+            .map(|name| {
+                syscall_of(name.trim())
+                    .ok_or_else(|| format!("unknown syscall: {}", name))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|syscalls| {
+                if syscalls.is_empty() {
+                    Err(String::from("no syscalls to fail with ENOSYS"))
+                } else {
+                    Ok(Lie::Enosys(syscalls))
+                }
+            })
+    }
+
+    /// pretend linux `version`, i.e.: `4.19` or `4.19.0`
+    pub fn kernel(version: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid kernel version: {}", version);
+        let mut numbers = version.splitn(3, '.');
+        let major: u32 = numbers
+            .next()
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(invalid)?;
+        let minor: u32 = numbers
+            .next()
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(invalid)?;
+        if (major, minor) < (3, 5) {
+            return Err(format!("kernel {} is older than 3.5", version));
+        }
+        Ok(Lie::Kernel(major, minor))
+    }
+
+    /// syscalls failed with `ENOSYS` by the seccomp filter
+    pub fn enosys_syscalls(&self) -> Vec<i32> {
+        match self {
+            Lie::Enosys(syscalls) => syscalls.clone(),
+            Lie::Kernel(major, minor) => SYSCALLS_SINCE
+                .iter()
+                .filter(|&&(_, since)| since > (*major, *minor))
+                .map(|&(nr, _)| nr)
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// syscalls the lie is told of, by emulation
+    pub fn syscalls(&self) -> Vec<SyscallNo> {
+        use SyscallNo::*;
+        match self {
+            Lie::Root => vec![
+                SYS_getuid,
+                SYS_geteuid,
+                SYS_getgid,
+                SYS_getegid,
+                SYS_getresuid,
+                SYS_getresgid,
+            ],
+            Lie::Cpus(_) => vec![SYS_sched_getaffinity],
+            Lie::FreeMemory(_) => vec![SYS_sysinfo],
+            Lie::Disk(_, _) => vec![SYS_statfs, SYS_fstatfs],
+            Lie::SetRlimit => vec![SYS_setrlimit, SYS_prlimit64],
+            Lie::Enosys(_) => Vec::new(),
+            Lie::Kernel(_, _) => vec![SYS_uname],
+        }
+    }
+
+    /// result of `syscall` lied about, `None` to run it
+    pub fn emulate(
+        &self,
+        memory: &dyn TaskMemory,
+        syscall: SyscallNo,
+        args: &SyscallArgs,
+    ) -> Option<i64> {
+        use SyscallNo::*;
+        match (self, syscall) {
+            (Lie::Root, SYS_getresuid) | (Lie::Root, SYS_getresgid) => {
+                for addr in &[args.arg0, args.arg1, args.arg2] {
+                    if memory.write_bytes(*addr, &[0u8; 4]).is_err() {
+                        return Some(-i64::from(libc::EFAULT));
+                    }
+                }
+                Some(0)
+            }
+            (Lie::Root, _) => Some(0),
+            (Lie::SetRlimit, SYS_setrlimit) => Some(0),
+            // old limits are to be read, by the kernel
+            (Lie::SetRlimit, _) if args.arg2 != 0 && args.arg3 == 0 => Some(0),
+            _ => None,
+        }
+    }
+
+    /// `syscall` run returned `retval`, results in memory are lied about
+    pub fn exited(
+        &self,
+        memory: &dyn TaskMemory,
+        syscall: SyscallNo,
+        args: &SyscallArgs,
+        retval: i64,
+    ) {
+        if retval < 0 {
+            return;
+        }
+        match (self, syscall) {
+            (Lie::Cpus(n), SyscallNo::SYS_sched_getaffinity) => {
+                // the mask size returned, in bytes
+                let mut mask = vec![0u8; retval as usize];
+                for cpu in 0..(*n).min(mask.len() * 8) {
+                    mask[cpu / 8] |= 1 << (cpu % 8);
+                }
+                let _ = memory.write_bytes(args.arg2, &mask);
+            }
+            (Lie::Kernel(major, minor), SyscallNo::SYS_uname) => {
+                let mut release = format!("{}.{}.0", major, minor).into_bytes();
+                release.resize(UTSNAME_LENGTH, 0);
+                let _ =
+                    memory.write_bytes(args.arg0 + UTSNAME_RELEASE, &release);
+            }
+            (Lie::Disk(free, total), _) => {
+                // statfs(path, *buf), fstatfs(fd, *buf)
+                let buf = args.arg1;
+                // blocks are of `f_frsize` bytes, `f_bsize` if unset
+                let unit = read_u64(memory, buf + STATFS_FRSIZE)
+                    .filter(|&unit| unit > 0)
+                    .or_else(|| read_u64(memory, buf + STATFS_BSIZE))
+                    .unwrap_or(1)
+                    .max(1);
+                let blocks = match total {
+                    Some(total) => *total / unit,
+                    None => read_u64(memory, buf + STATFS_BLOCKS).unwrap_or(0),
+                };
+                let free = *free / unit;
+                for (offset, value) in &[
+                    (STATFS_BLOCKS, blocks.max(free)),
+                    (STATFS_BFREE, free),
+                    (STATFS_BAVAIL, free),
+                ] {
+                    let _ =
+                        memory.write_bytes(buf + offset, &value.to_le_bytes());
+                }
+            }
+            (Lie::FreeMemory(bytes), SyscallNo::SYS_sysinfo) => {
+                let info = args.arg0;
+                let unit = memory
+                    .read_bytes(info + SYSINFO_MEM_UNIT, 4)
+                    .ok()
+                    .map_or(1, |b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .max(1);
+                let free = *bytes / u64::from(unit);
+                let total = read_u64(memory, info + SYSINFO_TOTALRAM)
+                    .unwrap_or(0)
+                    .max(free);
+                let _ = memory
+                    .write_bytes(info + SYSINFO_TOTALRAM, &total.to_le_bytes());
+                let _ = memory
+                    .write_bytes(info + SYSINFO_FREERAM, &free.to_le_bytes());
+            }
+            _ => (),
+        }
+    }
+}
+
+/// emulation telling `lies`
+pub fn lies_emulation(lies: Vec<Lie>) -> SyscallEmulation {
+    let mut syscalls = Vec::new();
+    for syscall in lies.iter().flat_map(Lie::syscalls) {
+        if !syscalls.contains(&syscall) {
+            syscalls.push(syscall);
+        }
+    }
+    let exits = lies.clone();
+    let mut emulation = SyscallEmulation::new(
+        EmulationMode::Seccomp,
+        syscalls,
+        Box::new(move |_task, memory, syscall, args| {
+            lies.iter()
+                .filter(|lie| lie.syscalls().contains(&syscall))
+                .find_map(|lie| lie.emulate(memory, syscall, args))
+        }),
+    );
+    emulation.set_exit_handler(Box::new(
+        move |_task, memory, syscall, args, retval| {
+            for lie in exits.iter() {
+                if lie.syscalls().contains(&syscall) {
+                    lie.exited(memory, syscall, args, retval)
+                }
+            }
+        },
+    ));
+    emulation
+}
+
+#[cfg(test)]
+pub(crate) struct FakeMemory(pub(crate) std::cell::RefCell<Vec<u8>>);
+
+#[cfg(test)]
+impl TaskMemory for FakeMemory {
+    fn read_bytes(&self, addr: u64, size: usize) -> std::io::Result<Vec<u8>> {
+        let memory = self.0.borrow();
+        let addr = addr as usize;
+        memory
+            .get(addr..addr + size)
+            .map(|bytes| bytes.to_vec())
+            .ok_or_else(|| std::io::Error::from_raw_os_error(14))
+    }
+    fn write_bytes(&self, addr: u64, bytes: &[u8]) -> std::io::Result<()> {
+        let mut memory = self.0.borrow_mut();
+        let addr = addr as usize;
+        let dest = memory
+            .get_mut(addr..addr + bytes.len())
+            .ok_or_else(|| std::io::Error::from_raw_os_error(14))?;
+        dest.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+#[test]
+fn lies_sanity_check() {
+    let lies: Vec<Lie> = ["root", "cpus=2", "memory=4096", "setrlimit"]
+        .iter()
+        .map(|lie| lie.parse().unwrap())
+        .collect();
+    assert_eq!(lies[1], Lie::Cpus(2));
+    assert_eq!(lies[2].to_string(), "memory=4096");
+    assert!("cpus=0".parse::<Lie>().is_err());
+    assert!("root=1".parse::<Lie>().is_err());
+    let enosys: Lie = "enosys=SYS_bpf,2".parse().unwrap();
+    assert_eq!(enosys, Lie::Enosys(vec![321, 2]));
+    assert_eq!(enosys.to_string(), "enosys=bpf,open");
+    let newer = Lie::enosys("io_uring_setup,clone3,statx").unwrap();
+    assert_eq!(newer.to_string(), "enosys=io_uring_setup,clone3,statx");
+    assert_eq!(newer.enosys_syscalls(), &[425, 435, 332]);
+    assert!(newer.syscalls().is_empty());
+    assert_eq!(
+        Lie::enosys("statx,clone4"),
+        Err(String::from("unknown syscall: clone4"))
+    );
+    assert_eq!(Lie::enosys("statx,"), Ok(Lie::Enosys(vec![332])));
+    assert!(Lie::enosys("").is_err());
+    let kernel: Lie = "kernel=4.19".parse().unwrap();
+    assert_eq!(Lie::kernel("4.19.0-amd64"), Ok(kernel.clone()));
+    assert!(Lie::kernel("3.2").is_err());
+    assert!(Lie::kernel("4").is_err());
+    let enosys_419 = kernel.enosys_syscalls();
+    assert_eq!(enosys_419.first(), Some(&424));
+    assert!(enosys_419.contains(&435));
+    assert!(Lie::Kernel(5, 13).enosys_syscalls().is_empty());
+    let memory = FakeMemory(std::cell::RefCell::new(vec![0xffu8; 128]));
+    let args = |a0, a1, a2, a3| SyscallArgs::from(a0, a1, a2, a3, 0, 0);
+    let (root, cpus, free, rlimit) = (&lies[0], &lies[1], &lies[2], &lies[3]);
+    assert_eq!(
+        root.emulate(&memory, SyscallNo::SYS_geteuid, &args(0, 0, 0, 0)),
+        Some(0)
+    );
+    let getresuid =
+        root.emulate(&memory, SyscallNo::SYS_getresuid, &args(0, 4, 8, 0));
+    assert_eq!(getresuid, Some(0));
+    assert_eq!(memory.read_bytes(0, 12).unwrap(), vec![0u8; 12]);
+    cpus.exited(
+        &memory,
+        SyscallNo::SYS_sched_getaffinity,
+        &args(0, 8, 16, 0),
+        8,
+    );
+    assert_eq!(
+        memory.read_bytes(16, 8).unwrap(),
+        vec![3, 0, 0, 0, 0, 0, 0, 0]
+    );
+    memory
+        .write_bytes(SYSINFO_MEM_UNIT, &1u32.to_le_bytes())
+        .unwrap();
+    memory
+        .write_bytes(SYSINFO_TOTALRAM, &1024u64.to_le_bytes())
+        .unwrap();
+    free.exited(&memory, SyscallNo::SYS_sysinfo, &args(0, 0, 0, 0), 0);
+    let utsname = FakeMemory(std::cell::RefCell::new(vec![0xffu8; 390]));
+    kernel.exited(&utsname, SyscallNo::SYS_uname, &args(0, 0, 0, 0), 0);
+    let release = utsname.read_bytes(UTSNAME_RELEASE, 7).unwrap();
+    assert_eq!(release, b"4.19.0\0");
+    assert_eq!(read_u64(&memory, SYSINFO_FREERAM), Some(4096));
+    assert_eq!(read_u64(&memory, SYSINFO_TOTALRAM), Some(4096));
+    assert_eq!(
+        rlimit.emulate(&memory, SyscallNo::SYS_prlimit64, &args(0, 7, 64, 0)),
+        Some(0)
+    );
+    assert_eq!(
+        rlimit.emulate(&memory, SyscallNo::SYS_prlimit64, &args(0, 7, 64, 72)),
+        None
+    );
+    let disk: Lie = "disk=8192:65536".parse().unwrap();
+    assert_eq!(disk, Lie::Disk(8192, Some(65536)));
+    assert_eq!(disk.to_string(), "disk=8192:65536");
+    assert!("disk=2:1".parse::<Lie>().is_err());
+    let statfs = FakeMemory(std::cell::RefCell::new(vec![0u8; 120]));
+    statfs
+        .write_bytes(STATFS_BSIZE, &4096u64.to_le_bytes())
+        .unwrap();
+    disk.exited(&statfs, SyscallNo::SYS_fstatfs, &args(3, 0, 0, 0), 0);
+    assert_eq!(read_u64(&statfs, STATFS_BLOCKS), Some(16));
+    assert_eq!(read_u64(&statfs, STATFS_BAVAIL), Some(2));
+    let emulation = lies_emulation(lies);
+    assert!(emulation.is_emulated(SyscallNo::SYS_sysinfo));
+    assert!(!emulation.is_emulated(SyscallNo::SYS_read));
+}
+
+#[test]
+fn pretend_kernel_sanity_check() {
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+
+    let kernel = Lie::kernel("4.19").unwrap();
+    let args = SyscallArgs::from(0, 0, 0, 0, 0, 0);
+    // `struct utsname` of the running kernel, lied about
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    assert_eq!(unsafe { libc::uname(&mut uts) }, 0);
+    let size = std::mem::size_of::<libc::utsname>();
+    let bytes = unsafe {
+        std::slice::from_raw_parts(&uts as *const _ as *const u8, size)
+    };
+    let memory = FakeMemory(std::cell::RefCell::new(bytes.to_vec()));
+    kernel.exited(&memory, SyscallNo::SYS_uname, &args, 0);
+    let lied = memory.0.into_inner();
+    let field = |k: usize| {
+        let field = &lied[k * UTSNAME_LENGTH..(k + 1) * UTSNAME_LENGTH];
+        let len = field.iter().position(|c| *c == 0).unwrap();
+        String::from_utf8_lossy(&field[..len]).into_owned()
+    };
+    assert_eq!(field(0), "Linux");
+    assert_eq!(field(2), "4.19.0");
+
+    // syscalls since 4.19 fail with `ENOSYS` by the filter, older ones run
+    let mut filter = vec![0x20u64];
+    for nr in kernel.enosys_syscalls() {
+        filter.push((nr as u64) << 32 | 0x0100_0015);
+        filter.push((0x0005_0000 | libc::ENOSYS as u64) << 32 | 0x6);
+    }
+    filter.push(0x7fff_0000 << 32 | 0x6);
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr() as *mut libc::sock_filter,
+    };
+    let child = match fork().expect("fork failed") {
+        ForkResult::Child => unsafe {
+            libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0);
+            // SECCOMP_SET_MODE_FILTER
+            if libc::syscall(libc::SYS_seccomp, 1, 0, &prog) != 0 {
+                libc::_exit(1)
+            }
+            let pidfd = libc::syscall(434, libc::getpid(), 0);
+            let errno = *libc::__errno_location();
+            let name = b"lies\0".as_ptr();
+            let memfd = libc::syscall(libc::SYS_memfd_create, name, 0);
+            let ok = pidfd == -1 && errno == libc::ENOSYS && memfd >= 0;
+            libc::_exit(if ok { 0 } else { 2 })
+        },
+        ForkResult::Parent { child } => child,
+    };
+    assert_eq!(waitpid(child, None), Ok(WaitStatus::Exited(child, 0)));
+}
+
+#[test]
+fn disk_sanity_check() {
+    // `struct statfs` of the actual disk, lied about, as `df` computes
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    let root = b"/\0".as_ptr() as *const libc::c_char;
+    assert_eq!(unsafe { libc::statfs(root, &mut stat) }, 0);
+    let size = std::mem::size_of::<libc::statfs>();
+    let lie = |disk: &Lie, stat: &libc::statfs| {
+        let bytes = unsafe {
+            std::slice::from_raw_parts(stat as *const _ as *const u8, size)
+        };
+        let memory = FakeMemory(std::cell::RefCell::new(bytes.to_vec()));
+        let args = SyscallArgs::from(0, 0, 0, 0, 0, 0);
+        disk.exited(&memory, SyscallNo::SYS_statfs, &args, 0);
+        let mut lied: libc::statfs = unsafe { std::mem::zeroed() };
+        let lied_bytes = memory.0.into_inner();
+        unsafe {
+            std::ptr::copy(
+                lied_bytes.as_ptr(),
+                &mut lied as *mut _ as *mut u8,
+                size,
+            )
+        };
+        lied
+    };
+    let unit = stat.f_frsize as u64;
+    let (free, total) = (1024 * unit, 4096 * unit);
+    let lied = lie(&Lie::Disk(free, Some(total)), &stat);
+    assert_eq!(lied.f_bavail as u64 * unit, free);
+    assert_eq!(lied.f_bfree as u64 * unit, free);
+    assert_eq!(lied.f_blocks as u64 * unit, total);
+    assert_eq!(lied.f_type, stat.f_type);
+    assert_eq!(lied.f_files, stat.f_files);
+    // total bytes of the actual disk, at least as many as free
+    let lied = lie(&Lie::Disk(free, None), &stat);
+    assert_eq!(lied.f_blocks, stat.f_blocks.max(1024));
+    // failed syscalls are not lied about
+    let args = SyscallArgs::from(0, 0, 0, 0, 0, 0);
+    let memory = FakeMemory(std::cell::RefCell::new(vec![0u8; size]));
+    let enoent = -i64::from(libc::ENOENT);
+    Lie::Disk(free, None).exited(&memory, SyscallNo::SYS_statfs, &args, enoent);
+    assert_eq!(memory.0.into_inner(), vec![0u8; size]);
+}
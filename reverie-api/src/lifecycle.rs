@@ -0,0 +1,174 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! process lifecycle accounting
+//!
+//! an exited process stays a zombie until its parent `wait`s for it. the
+//! tracer keeps track of traced processes and their (traced) parents, so
+//! that children never reaped, or orphaned by their parent's exit, can be
+//! reported.
+
+use nix::unistd::Pid;
+use std::collections::{HashMap, HashSet};
+
+/// lifecycle of a traced process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TaskLifecycle {
+    /// alive, and not stopped
+    Running,
+    /// in group-stop, until `SIGCONT`
+    Stopped,
+    /// exited, awaiting its parent's `wait`
+    Zombie,
+    /// exited, and waited for
+    Reaped,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    /// traced parent, `None` if untraced or exited
+    parent: Option<Pid>,
+    lifecycle: TaskLifecycle,
+}
+
+/// lifecycle of traced processes, and their traced parents
+#[derive(Debug, Default)]
+pub struct ProcessTable {
+    procs: HashMap<Pid, Entry>,
+    /// traced parent => its (traced) children
+    children: HashMap<Pid, HashSet<Pid>>,
+}
+
+impl ProcessTable {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// new process `pid`, with traced `parent` (if any). a reused `pid`
+    /// replaces the old (reaped) process.
+    pub fn spawned(&mut self, pid: Pid, parent: Option<Pid>) {
+        let lifecycle = TaskLifecycle::Running;
+        if let Some(old) = self.procs.insert(pid, Entry { parent, lifecycle }) {
+            self.unlink(pid, old.parent);
+        }
+        if let Some(parent) = parent {
+            self.children.entry(parent).or_default().insert(pid);
+        }
+    }
+    /// lifecycle of process `pid`, `None` if never traced
+    pub fn lifecycle(&self, pid: Pid) -> Option<TaskLifecycle> {
+        self.procs.get(&pid).map(|entry| entry.lifecycle)
+    }
+    /// traced parent of process `pid`, if any
+    pub fn parent(&self, pid: Pid) -> Option<Pid> {
+        self.procs.get(&pid).and_then(|entry| entry.parent)
+    }
+    /// process `pid` entered (`true`) or left group-stop
+    pub fn stopped(&mut self, pid: Pid, stopped: bool) {
+        if let Some(entry) = self.procs.get_mut(&pid) {
+            match entry.lifecycle {
+                TaskLifecycle::Running if stopped => {
+                    entry.lifecycle = TaskLifecycle::Stopped
+                }
+                TaskLifecycle::Stopped if !stopped => {
+                    entry.lifecycle = TaskLifecycle::Running
+                }
+                _ => (),
+            }
+        }
+    }
+    /// process `pid` exited, returns its traced parent which is to reap
+    /// it. without traced parent, it is reaped by the tracer (or `init`).
+    pub fn exited(&mut self, pid: Pid) -> Option<Pid> {
+        let entry = self.procs.get_mut(&pid)?;
+        entry.lifecycle = match entry.parent {
+            Some(_) => TaskLifecycle::Zombie,
+            None => TaskLifecycle::Reaped,
+        };
+        entry.parent
+    }
+    /// zombie `pid` was waited for
+    pub fn reaped(&mut self, pid: Pid) {
+        if let Some(entry) = self.procs.get_mut(&pid) {
+            entry.lifecycle = TaskLifecycle::Reaped;
+            let parent = entry.parent.take();
+            self.unlink(pid, parent);
+        }
+    }
+    /// zombie children of `parent`
+    pub fn zombies(&self, parent: Pid) -> Vec<Pid> {
+        self.children_in(parent, TaskLifecycle::Zombie)
+    }
+    /// live children of `parent`
+    pub fn children(&self, parent: Pid) -> Vec<Pid> {
+        let mut res = self.children_in(parent, TaskLifecycle::Running);
+        res.extend(self.children_in(parent, TaskLifecycle::Stopped));
+        res
+    }
+    /// `parent` exited: its children are reparented (to `init`, or a
+    /// subreaper), and untracked from now on. returns children which are
+    /// still zombies, never reaped by `parent`, and live (orphaned) ones.
+    pub fn parent_exited(&mut self, parent: Pid) -> (Vec<Pid>, Vec<Pid>) {
+        let zombies = self.zombies(parent);
+        let orphans = self.children(parent);
+        for child in self.children.remove(&parent).unwrap_or_default() {
+            if let Some(entry) = self.procs.get_mut(&child) {
+                entry.parent = None;
+            }
+        }
+        (zombies, orphans)
+    }
+    fn children_in(&self, parent: Pid, lifecycle: TaskLifecycle) -> Vec<Pid> {
+        self.children
+            .get(&parent)
+            .into_iter()
+            .flatten()
+            .filter(|pid| self.lifecycle(**pid) == Some(lifecycle))
+            .cloned()
+            .collect()
+    }
+    fn unlink(&mut self, pid: Pid, parent: Option<Pid>) {
+        if let Some(parent) = parent {
+            if let Some(children) = self.children.get_mut(&parent) {
+                children.remove(&pid);
+                if children.is_empty() {
+                    self.children.remove(&parent);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn process_table_sanity_check() {
+    let (p1, p2, p3) = (Pid::from_raw(1), Pid::from_raw(2), Pid::from_raw(3));
+    let mut table = ProcessTable::new();
+    table.spawned(p1, None);
+    table.spawned(p2, Some(p1));
+    table.spawned(p3, Some(p1));
+    table.stopped(p3, true);
+    assert_eq!(table.lifecycle(p3), Some(TaskLifecycle::Stopped));
+    assert_eq!(table.exited(p2), Some(p1));
+    assert_eq!(table.zombies(p1), vec![p2]);
+    assert_eq!(table.children(p1), vec![p3]);
+    assert_eq!(table.parent_exited(p1), (vec![p2], vec![p3]));
+    assert_eq!(table.parent(p3), None);
+    assert_eq!(table.exited(p3), None);
+    assert_eq!(table.lifecycle(p3), Some(TaskLifecycle::Reaped));
+    assert_eq!(table.exited(p1), None);
+    assert_eq!(table.lifecycle(Pid::from_raw(4)), None);
+    table.spawned(p2, Some(p3));
+    table.exited(p2);
+    table.reaped(p2);
+    assert!(table.zombies(p3).is_empty());
+    assert_eq!(table.lifecycle(p2), Some(TaskLifecycle::Reaped));
+}
@@ -0,0 +1,168 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! memory mapping changes
+//!
+//! `mmap`, `mremap`, `mprotect` and `munmap` are decoded as
+//! `MappingChange`, so that tools (i.e.: coverage collectors, unpackers)
+//! can react to new code without polling `/proc/<pid>/maps`, see
+//! `MappingChangeFn`. a `MAP_FIXED` `mmap` is reported as the removal of
+//! the mappings it replaces, before the new mapping (if executable).
+//!
+//! mappings both writable and executable can be flagged or denied, see
+//! `WxPolicy`.
+
+use nix::sys::mman::ProtFlags;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::task::Task;
+
+/// a change of the tracee's memory mappings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MappingChange {
+    /// `mmap` with `PROT_EXEC`, of `path` unless anonymous
+    NewExecutableMapping {
+        addr: u64,
+        size: u64,
+        prot: ProtFlags,
+        path: Option<PathBuf>,
+    },
+    /// `munmap`, or the range replaced by a `MAP_FIXED` `mmap`
+    MappingRemoved { addr: u64, size: u64 },
+    /// `mremap` of `from_size` bytes at `from`, now `size` bytes at `addr`
+    MappingMoved {
+        from: u64,
+        from_size: u64,
+        addr: u64,
+        size: u64,
+    },
+    /// `mprotect` or `pkey_mprotect`
+    PermissionsChanged {
+        addr: u64,
+        size: u64,
+        prot: ProtFlags,
+    },
+}
+
+impl MappingChange {
+    /// address range affected by the change
+    pub fn range(&self) -> (u64, u64) {
+        match self {
+            MappingChange::NewExecutableMapping { addr, size, .. } => {
+                (*addr, addr + size)
+            }
+            MappingChange::MappingRemoved { addr, size } => {
+                (*addr, addr + size)
+            }
+            MappingChange::MappingMoved { addr, size, .. } => {
+                (*addr, addr + size)
+            }
+            MappingChange::PermissionsChanged { addr, size, .. } => {
+                (*addr, addr + size)
+            }
+        }
+    }
+}
+
+/// called with every `MappingChange` of a task, once the syscall returned
+pub type MappingChangeFn =
+    Box<dyn FnMut(&dyn Task, &MappingChange) -> io::Result<()>>;
+
+/// programs allowed writable and executable mappings by default: JITs
+pub const DEFAULT_WX_ALLOWLIST: &[&str] = &[
+    "java",
+    "node",
+    "luajit",
+    "pypy",
+    "pypy3",
+    "dotnet",
+    "qemu-x86_64",
+];
+
+/// what the tracer does with `mmap`/`mprotect` asking for `PROT_WRITE` and
+/// `PROT_EXEC` at once (W^X), but for programs allowlisted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WxPolicy {
+    /// no check, mapping syscalls can be patched as usual
+    Ignore,
+    /// allow, with a warning
+    Flag,
+    /// fail with `EACCES`, as selinux `execmem` does
+    Deny,
+}
+
+impl Default for WxPolicy {
+    fn default() -> Self {
+        WxPolicy::Ignore
+    }
+}
+
+impl FromStr for WxPolicy {
+    type Err = io::Error;
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "ignore" => Ok(WxPolicy::Ignore),
+            "flag" => Ok(WxPolicy::Flag),
+            "deny" => Ok(WxPolicy::Deny),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown W^X policy: {}", s),
+            )),
+        }
+    }
+}
+
+/// whether program `exe` is in `allowlist`, by path or by file name
+pub fn is_wx_allowed(exe: &Path, allowlist: &[String]) -> bool {
+    allowlist.iter().any(|allowed| {
+        let allowed = Path::new(allowed);
+        if allowed.is_absolute() {
+            exe == allowed
+        } else {
+            exe.file_name() == Some(allowed.as_os_str())
+        }
+    })
+}
+
+#[test]
+fn mapping_change_sanity_check() {
+    let change = MappingChange::PermissionsChanged {
+        addr: 0x1000,
+        size: 0x2000,
+        prot: ProtFlags::PROT_READ | ProtFlags::PROT_EXEC,
+    };
+    assert_eq!(change.range(), (0x1000, 0x3000));
+    let change = MappingChange::MappingRemoved {
+        addr: 0x1000,
+        size: 0x1000,
+    };
+    assert_eq!(change.range(), (0x1000, 0x2000));
+    let change = MappingChange::MappingMoved {
+        from: 0x1000,
+        from_size: 0x1000,
+        addr: 0x8000,
+        size: 0x3000,
+    };
+    assert_eq!(change.range(), (0x8000, 0xb000));
+
+    let allowlist: Vec<String> =
+        DEFAULT_WX_ALLOWLIST.iter().map(|s| s.to_string()).collect();
+    assert!(is_wx_allowed(Path::new("/usr/bin/node"), &allowlist));
+    assert!(!is_wx_allowed(Path::new("/usr/bin/nodejs"), &allowlist));
+    let allowlist = vec![String::from("/opt/jit/bin/vm")];
+    assert!(is_wx_allowed(Path::new("/opt/jit/bin/vm"), &allowlist));
+    assert!(!is_wx_allowed(Path::new("/usr/bin/vm"), &allowlist));
+    assert_eq!("deny".parse::<WxPolicy>().ok(), Some(WxPolicy::Deny));
+}
@@ -10,6 +10,36 @@
  *  LICENSE file in the root directory of this source tree.
  */
 
+pub mod arch;
+pub mod binaries;
+pub mod clock;
+pub mod coverage;
+pub mod device;
+pub mod dirents;
+pub mod dns;
+pub mod emulate;
 pub mod event;
+pub mod event_queue;
+pub mod fileless;
+pub mod guest;
+pub mod inject;
+pub mod isolation;
+pub mod kill;
+pub mod lies;
+pub mod lifecycle;
+pub mod mapping;
+pub mod namespaces;
+pub mod probe;
+pub mod provenance;
+pub mod ready;
+pub mod record_data;
 pub mod remote;
+pub mod search;
+pub mod shm;
+pub mod strace;
 pub mod task;
+pub mod ticks;
+pub mod trace_output;
+pub mod violation;
+pub mod wait;
+pub mod workers;
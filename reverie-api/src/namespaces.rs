@@ -0,0 +1,121 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! tracer access to namespaces of tasks
+//!
+//! `with_namespaces` runs a closure in a helper thread which entered
+//! namespaces of a task (by `/proc/PID/ns` and `setns`), i.e.: to read
+//! files, or bind sockets, as the task sees them. the tracer itself stays
+//! in its namespaces. unlike `inject::remote_read_file`, the task is not
+//! involved, it may be running, but credentials are the tracer's, and
+//! `setns` needs `CAP_SYS_ADMIN`.
+//!
+//! user namespaces can't be entered by threads of a multithreaded process,
+//! and pid namespaces only apply to children, neither are supported.
+
+use nix::sched::{self, CloneFlags};
+use nix::unistd::Pid;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::io::AsRawFd;
+use std::thread;
+
+use crate::remote::from_nix_error;
+
+/// a namespace to enter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Namespace {
+    Mount,
+    Net,
+    Uts,
+    Ipc,
+    Cgroup,
+}
+
+impl Namespace {
+    /// name of the namespace in `/proc/PID/ns`
+    pub fn name(self) -> &'static str {
+        match self {
+            Namespace::Mount => "mnt",
+            Namespace::Net => "net",
+            Namespace::Uts => "uts",
+            Namespace::Ipc => "ipc",
+            Namespace::Cgroup => "cgroup",
+        }
+    }
+
+    fn flag(self) -> CloneFlags {
+        match self {
+            Namespace::Mount => CloneFlags::CLONE_NEWNS,
+            Namespace::Net => CloneFlags::CLONE_NEWNET,
+            Namespace::Uts => CloneFlags::CLONE_NEWUTS,
+            Namespace::Ipc => CloneFlags::CLONE_NEWIPC,
+            Namespace::Cgroup => CloneFlags::CLONE_NEWCGROUP,
+        }
+    }
+}
+
+/// run `f` in `namespaces` of process `pid`, i.e.: `task.getpid()`,
+/// returns what `f` returns
+pub fn with_namespaces<F, T>(
+    pid: Pid,
+    namespaces: &[Namespace],
+    f: F,
+) -> Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    // opened before spawning, so that `pid` exiting is reported as such
+    let entered = namespaces
+        .iter()
+        .map(|ns| {
+            let file = File::open(format!("/proc/{}/ns/{}", pid, ns.name()))?;
+            Ok((file, ns.flag()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let helper = thread::Builder::new()
+        .name(format!("reverie-ns-{}", pid))
+        .spawn(move || -> Result<T> {
+            // threads sharing their fs (root, cwd) with other threads can't
+            // enter a mount namespace
+            let mount = entered
+                .iter()
+                .any(|(_, flag)| *flag == CloneFlags::CLONE_NEWNS);
+            if mount {
+                sched::unshare(CloneFlags::CLONE_FS).map_err(from_nix_error)?;
+            }
+            for (file, flag) in &entered {
+                sched::setns(file.as_raw_fd(), *flag)
+                    .map_err(from_nix_error)?;
+            }
+            Ok(f())
+        })?;
+    helper.join().map_err(|_| {
+        Error::new(ErrorKind::Other, "namespace helper panicked")
+    })?
+}
+
+#[test]
+fn with_namespaces_sanity_check() {
+    let pid = nix::unistd::getpid();
+    let hostname = || std::fs::read_to_string("/proc/sys/kernel/hostname");
+    let expected = hostname().unwrap();
+    // entering namespaces needs privileges, even namespaces of our own
+    match with_namespaces(pid, &[Namespace::Uts, Namespace::Mount], hostname) {
+        Ok(read) => assert_eq!(read.unwrap(), expected),
+        Err(err) => assert_eq!(err.raw_os_error(), Some(libc::EPERM)),
+    }
+    let exited = Pid::from_raw(i32::max_value());
+    assert!(with_namespaces(exited, &[Namespace::Net], || ()).is_err());
+}
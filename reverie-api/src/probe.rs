@@ -0,0 +1,230 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! probes at instructions of functions, a uprobes workalike without root
+//!
+//! probe `MODULE:FUNCTION+OFFSET,ARG,..` traps at `OFFSET` (in hex with
+//! `0x`, decimal otherwise) of `FUNCTION` (an ELF symbol) of `MODULE` (the
+//! program or a shared library, by path or file name) on every hit,
+//! reported by `Event::Probe` with the values of its `ARG`s: registers,
+//! i.e.: `rdi`, or 8-byte words of the stack, i.e.: `sp+8`.
+//!
+//! return probe `MODULE:FUNCTION%return,ARG,..` traps at the returns of
+//! `FUNCTION` instead, as `perf probe` does: its `ARG`s are captured at
+//! entry, and reported followed by `rax` once it returns.
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::emulate::TaskMemory;
+
+/// registers probes can capture
+pub const PROBE_REGISTERS: &[&str] = &[
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10",
+    "r11", "r12", "r13", "r14", "r15", "rip",
+];
+
+// register `name` of `regs`
+fn register(regs: &libc::user_regs_struct, name: &str) -> Option<u64> {
+    let value = match name {
+        "rax" => regs.rax,
+        "rbx" => regs.rbx,
+        "rcx" => regs.rcx,
+        "rdx" => regs.rdx,
+        "rsi" => regs.rsi,
+        "rdi" => regs.rdi,
+        "rbp" => regs.rbp,
+        "rsp" => regs.rsp,
+        "r8" => regs.r8,
+        "r9" => regs.r9,
+        "r10" => regs.r10,
+        "r11" => regs.r11,
+        "r12" => regs.r12,
+        "r13" => regs.r13,
+        "r14" => regs.r14,
+        "r15" => regs.r15,
+        "rip" => regs.rip,
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    if s.starts_with("0x") {
+        u64::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// value captured by a probe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeArg {
+    Register(String),
+    /// word at this offset from the stack pointer
+    Stack(u64),
+}
+
+impl ProbeArg {
+    /// value at the probe, with `regs` of the task, `None` if the stack
+    /// cannot be read
+    pub fn value(
+        &self,
+        regs: &libc::user_regs_struct,
+        memory: &dyn TaskMemory,
+    ) -> Option<u64> {
+        match self {
+            ProbeArg::Register(name) => register(regs, name),
+            ProbeArg::Stack(offset) => {
+                let bytes = memory.read_bytes(regs.rsp + offset, 8).ok()?;
+                let mut word = [0u8; 8];
+                word.copy_from_slice(&bytes);
+                Some(u64::from_le_bytes(word))
+            }
+        }
+    }
+}
+
+impl FromStr for ProbeArg {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if PROBE_REGISTERS.contains(&s) {
+            Ok(ProbeArg::Register(s.to_string()))
+        } else if s == "sp" {
+            Ok(ProbeArg::Stack(0))
+        } else if s.starts_with("sp+") {
+            parse_number(&s[3..])
+                .map(ProbeArg::Stack)
+                .ok_or_else(|| format!("invalid probe stack offset: {}", s))
+        } else {
+            Err(format!("invalid probe argument: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for ProbeArg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProbeArg::Register(name) => write!(f, "{}", name),
+            ProbeArg::Stack(offset) => write!(f, "sp+{}", offset),
+        }
+    }
+}
+
+/// a probe, see module doc
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeSpec {
+    /// module, by path or by file name
+    pub module: String,
+    pub function: String,
+    /// offset from the entry of `function`
+    pub offset: u64,
+    /// whether the returns of `function` are probed, see module doc
+    pub ret: bool,
+    pub args: Vec<ProbeArg>,
+}
+
+impl ProbeSpec {
+    /// whether `path` is the module probed
+    pub fn is_selected(&self, path: &Path) -> bool {
+        let module = Path::new(&self.module);
+        if module.is_absolute() {
+            path == module
+        } else {
+            path.file_name() == Some(module.as_os_str())
+        }
+    }
+
+    /// values of `args` at the probe, with `regs` of the task
+    pub fn capture(
+        &self,
+        regs: &libc::user_regs_struct,
+        memory: &dyn TaskMemory,
+    ) -> Vec<Option<u64>> {
+        self.args
+            .iter()
+            .map(|arg| arg.value(regs, memory))
+            .collect()
+    }
+}
+
+impl FromStr for ProbeSpec {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid probe: {}", s);
+        let mut parts = s.split(',');
+        let location = parts.next().unwrap_or("");
+        let colon = location.rfind(':').ok_or_else(invalid)?;
+        let (module, function) = (&location[..colon], &location[colon + 1..]);
+        let (function, ret) = match function.strip_suffix("%return") {
+            Some(function) => (function, true),
+            None => (function, false),
+        };
+        let (function, offset) = match function.find('+') {
+            Some(plus) => {
+                let offset = parse_number(&function[plus + 1..]);
+                (&function[..plus], offset.ok_or_else(invalid)?)
+            }
+            None => (function, 0),
+        };
+        if module.is_empty() || function.is_empty() || ret && offset != 0 {
+            return Err(invalid());
+        }
+        Ok(ProbeSpec {
+            module: module.to_string(),
+            function: function.to_string(),
+            offset,
+            ret,
+            args: parts.map(str::parse).collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl fmt::Display for ProbeSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.ret {
+            write!(f, "{}:{}%return", self.module, self.function)?;
+        } else {
+            let (module, function) = (&self.module, &self.function);
+            write!(f, "{}:{}+{:#x}", module, function, self.offset)?;
+        }
+        for arg in &self.args {
+            write!(f, ",{}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn probe_sanity_check() {
+    let probe: ProbeSpec = "libfoo.so:foo_open+0x1c,rdi,sp+8".parse().unwrap();
+    assert_eq!(probe.module, "libfoo.so");
+    assert_eq!((probe.function.as_str(), probe.offset), ("foo_open", 0x1c));
+    assert_eq!(
+        probe.args,
+        vec![ProbeArg::Register("rdi".to_string()), ProbeArg::Stack(8)]
+    );
+    assert_eq!(probe.to_string(), "libfoo.so:foo_open+0x1c,rdi,sp+8");
+    assert!(probe.is_selected(Path::new("/usr/lib/libfoo.so")));
+    assert!(!probe.is_selected(Path::new("/usr/lib/libfoo.so.1")));
+    let entry: ProbeSpec = "/bin/true:main".parse().unwrap();
+    assert_eq!((entry.module.as_str(), entry.offset), ("/bin/true", 0));
+    assert!("libfoo.so:foo+x".parse::<ProbeSpec>().is_err());
+    assert!("libfoo.so:foo,xmm0".parse::<ProbeSpec>().is_err());
+    assert!("foo".parse::<ProbeSpec>().is_err());
+    let ret: ProbeSpec = "libc.so.6:malloc%return,rdi".parse().unwrap();
+    assert_eq!((ret.function.as_str(), ret.ret), ("malloc", true));
+    assert_eq!(ret.to_string(), "libc.so.6:malloc%return,rdi");
+    assert!("libc.so.6:malloc+4%return".parse::<ProbeSpec>().is_err());
+}
@@ -0,0 +1,260 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! fd provenance
+//!
+//! `FdProvenance` is a graph of where each fd of each traced process came
+//! from: an `FdNode` per fd created, linked to the node it was created
+//! from (by `dup`, `fork`, `SCM_RIGHTS` or `pidfd_getfd`), so that the
+//! history of an fd can be followed across processes, see `history`.
+
+use nix::unistd::Pid;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::path::PathBuf;
+
+/// how an fd was created
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FdOrigin {
+    /// open before it was seen (i.e.: stdin), or before tracing
+    Preexisting,
+    /// created by `syscall` (`open`, `socket`, `pipe`..), `path` is of
+    /// `/proc/<pid>/fd` if any
+    Opened {
+        syscall: String,
+        path: Option<PathBuf>,
+    },
+    /// `dup`, `dup2`, `dup3` or `fcntl(F_DUPFD)` of its source
+    Duplicated,
+    /// inherited from the parent process, by `fork`
+    Inherited,
+    /// received by `recvmsg` (`SCM_RIGHTS`), the source is the fd sent if
+    /// the sender is traced
+    Received,
+    /// taken from another process by `pidfd_getfd`
+    Taken,
+}
+
+impl fmt::Display for FdOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FdOrigin::Preexisting => write!(f, "preexisting"),
+            FdOrigin::Opened {
+                syscall,
+                path: Some(path),
+            } => write!(f, "{} {}", syscall, path.display()),
+            FdOrigin::Opened {
+                syscall,
+                path: None,
+            } => write!(f, "{}", syscall),
+            FdOrigin::Duplicated => write!(f, "dup"),
+            FdOrigin::Inherited => write!(f, "fork"),
+            FdOrigin::Received => write!(f, "SCM_RIGHTS"),
+            FdOrigin::Taken => write!(f, "pidfd_getfd"),
+        }
+    }
+}
+
+/// an fd of a process, from its creation until closed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FdNode {
+    pub pid: Pid,
+    pub fd: i32,
+    pub origin: FdOrigin,
+    /// node this fd was created from, see `FdProvenance::node`
+    pub source: Option<usize>,
+    pub closed: bool,
+}
+
+/// identity of an open file description (`st_dev` and `st_ino` of the fd)
+/// in flight between `sendmsg` and `recvmsg`
+pub type FileObject = (u64, u64);
+
+/// fd provenance graph, see module doc
+///
+/// processes sharing an fd table (by `CLONE_FILES`) have their fds under
+/// the process whose table it is, see `Task::files`.
+///
+/// NB: `close_range`, `CLOEXEC` fds closed by `exec`, and fd tables
+/// unshared by `unshare` are not tracked.
+#[derive(Debug, Default)]
+pub struct FdProvenance {
+    nodes: Vec<FdNode>,
+    /// open fds, by process and fd
+    open: HashMap<(Pid, i32), usize>,
+    /// fds sent, not received yet
+    in_flight: HashMap<FileObject, VecDeque<usize>>,
+}
+
+impl FdProvenance {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn insert(
+        &mut self,
+        pid: Pid,
+        fd: i32,
+        origin: FdOrigin,
+        source: Option<usize>,
+    ) {
+        self.closed(pid, fd);
+        self.nodes.push(FdNode {
+            pid,
+            fd,
+            origin,
+            source,
+            closed: false,
+        });
+        self.open.insert((pid, fd), self.nodes.len() - 1);
+    }
+
+    // node of open `fd` of `pid`, preexisting unless seen before
+    fn open_node(&mut self, pid: Pid, fd: i32) -> usize {
+        if let Some(k) = self.open.get(&(pid, fd)) {
+            return *k;
+        }
+        self.insert(pid, fd, FdOrigin::Preexisting, None);
+        self.nodes.len() - 1
+    }
+
+    /// `fd` of `pid` created by `syscall`
+    pub fn opened(
+        &mut self,
+        pid: Pid,
+        fd: i32,
+        syscall: &str,
+        path: Option<PathBuf>,
+    ) {
+        let syscall = syscall.to_string();
+        self.insert(pid, fd, FdOrigin::Opened { syscall, path }, None);
+    }
+
+    /// `to` of `pid` duplicated from `from`
+    pub fn duplicated(&mut self, pid: Pid, from: i32, to: i32) {
+        let source = self.open_node(pid, from);
+        self.insert(pid, to, FdOrigin::Duplicated, Some(source));
+    }
+
+    /// `fd` of `pid` closed
+    pub fn closed(&mut self, pid: Pid, fd: i32) {
+        if let Some(k) = self.open.remove(&(pid, fd)) {
+            self.nodes[k].closed = true;
+        }
+    }
+
+    /// `child` forked by `parent` inherits all its fds
+    pub fn forked(&mut self, parent: Pid, child: Pid) {
+        let mut inherited: Vec<(i32, usize)> = self
+            .open
+            .iter()
+            .filter(|((pid, _), _)| *pid == parent)
+            .map(|((_, fd), k)| (*fd, *k))
+            .collect();
+        inherited.sort_unstable();
+        for (fd, k) in inherited {
+            self.insert(child, fd, FdOrigin::Inherited, Some(k));
+        }
+    }
+
+    /// process `pid` exited, all its fds are closed
+    pub fn exited(&mut self, pid: Pid) {
+        let fds: Vec<i32> = self
+            .open
+            .keys()
+            .filter(|(p, _)| *p == pid)
+            .map(|(_, fd)| *fd)
+            .collect();
+        fds.into_iter().for_each(|fd| self.closed(pid, fd));
+    }
+
+    /// `fd` of `pid`, which is `object`, sent by `sendmsg`
+    pub fn sent(&mut self, pid: Pid, fd: i32, object: FileObject) {
+        let k = self.open_node(pid, fd);
+        self.in_flight.entry(object).or_default().push_back(k);
+    }
+
+    /// `fd` of `pid`, which is `object`, received by `recvmsg`
+    pub fn received(&mut self, pid: Pid, fd: i32, object: FileObject) {
+        let mut source = None;
+        if let Some(sent) = self.in_flight.get_mut(&object) {
+            source = sent.pop_front();
+            if sent.is_empty() {
+                self.in_flight.remove(&object);
+            }
+        }
+        self.insert(pid, fd, FdOrigin::Received, source);
+    }
+
+    /// `fd` of `pid` taken from `from_fd` of `from` by `pidfd_getfd`
+    pub fn taken(&mut self, pid: Pid, fd: i32, from: Pid, from_fd: i32) {
+        let source = self.open_node(from, from_fd);
+        self.insert(pid, fd, FdOrigin::Taken, Some(source));
+    }
+
+    /// all nodes, in order of creation
+    pub fn nodes(&self) -> &[FdNode] {
+        &self.nodes
+    }
+
+    /// node `k`
+    pub fn node(&self, k: usize) -> Option<&FdNode> {
+        self.nodes.get(k)
+    }
+
+    /// open `fd` of `pid`
+    pub fn lookup(&self, pid: Pid, fd: i32) -> Option<&FdNode> {
+        self.open.get(&(pid, fd)).map(|k| &self.nodes[*k])
+    }
+
+    /// open `fd` of `pid`, then the nodes it came from, back to its origin
+    pub fn history(&self, pid: Pid, fd: i32) -> Vec<&FdNode> {
+        let mut history = Vec::new();
+        let mut next = self.open.get(&(pid, fd)).cloned();
+        while let Some(k) = next {
+            history.push(&self.nodes[k]);
+            next = self.nodes[k].source;
+        }
+        history
+    }
+}
+
+#[test]
+fn fd_provenance_sanity_check() {
+    let (p1, p2, p3) = (Pid::from_raw(1), Pid::from_raw(2), Pid::from_raw(3));
+    let mut graph = FdProvenance::new();
+    graph.opened(p1, 3, "openat", Some(PathBuf::from("/etc/hosts")));
+    graph.duplicated(p1, 3, 4);
+    graph.forked(p1, p2);
+    graph.sent(p2, 4, (1, 2));
+    graph.received(p3, 5, (1, 2));
+    let history: Vec<(Pid, i32)> = graph
+        .history(p3, 5)
+        .iter()
+        .map(|node| (node.pid, node.fd))
+        .collect();
+    assert_eq!(history, vec![(p3, 5), (p2, 4), (p1, 4), (p1, 3)]);
+    let origin = &graph.history(p3, 5)[3].origin;
+    assert_eq!(origin.to_string(), "openat /etc/hosts");
+
+    graph.taken(p3, 6, p1, 0);
+    assert_eq!(graph.lookup(p1, 0).unwrap().origin, FdOrigin::Preexisting);
+    assert_eq!(graph.history(p3, 6).len(), 2);
+    graph.exited(p1);
+    assert!(graph.lookup(p1, 3).is_none());
+    assert_eq!(graph.history(p3, 5).len(), 4);
+    graph.closed(p3, 5);
+    assert!(graph.history(p3, 5).is_empty());
+    let open = graph.nodes().iter().filter(|node| !node.closed).count();
+    assert_eq!(open, 3);
+}
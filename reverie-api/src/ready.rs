@@ -0,0 +1,324 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! startup-complete detection
+//!
+//! a program is considered started once all its `ReadyCondition`s are
+//! met, as seen by `ReadyMatcher` at syscall exit (as an emulator
+//! declining all syscalls, so that they are never patched), or from the
+//! event stream. `Readiness` is a handle to block on, from any thread:
+//!
+//! - `listen:PORT`: a socket bound to `PORT` (ipv4 or ipv6) listens.
+//! - `open:PATH`: `PATH` is opened, or a path ending with its components
+//!   if relative.
+//! - `write:STRING`: `STRING` is written by a single `write`, to any fd.
+//! - `idle`: the program is idle, see `Event::Idle`.
+
+use nix::unistd::Pid;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use syscalls::SyscallNo;
+
+use crate::device::read_cstring;
+use crate::emulate::*;
+use crate::event::*;
+use crate::remote::SyscallArgs;
+
+/// a condition of readiness, see module doc
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadyCondition {
+    Listen(u16),
+    Open(PathBuf),
+    Write(String),
+    Idle,
+}
+
+impl FromStr for ReadyCondition {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut kv = s.splitn(2, ':');
+        let (name, value) = (kv.next().unwrap_or(""), kv.next());
+        let invalid = || format!("invalid ready condition: {}", s);
+        match (name, value) {
+            ("listen", Some(port)) => port
+                .parse()
+                .map(ReadyCondition::Listen)
+                .map_err(|_| invalid()),
+            ("open", Some(path)) if !path.is_empty() => {
+                Ok(ReadyCondition::Open(PathBuf::from(path)))
+            }
+            ("write", Some(string)) if !string.is_empty() => {
+                Ok(ReadyCondition::Write(string.to_string()))
+            }
+            ("idle", None) => Ok(ReadyCondition::Idle),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for ReadyCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadyCondition::Listen(port) => write!(f, "listen:{}", port),
+            ReadyCondition::Open(path) => write!(f, "open:{}", path.display()),
+            ReadyCondition::Write(string) => write!(f, "write:{}", string),
+            ReadyCondition::Idle => write!(f, "idle"),
+        }
+    }
+}
+
+impl ReadyCondition {
+    /// syscalls to see at exit
+    pub fn syscalls(&self) -> Vec<SyscallNo> {
+        match self {
+            ReadyCondition::Listen(_) => {
+                vec![SyscallNo::SYS_bind, SyscallNo::SYS_listen]
+            }
+            ReadyCondition::Open(_) => {
+                vec![SyscallNo::SYS_open, SyscallNo::SYS_openat]
+            }
+            ReadyCondition::Write(_) => vec![SyscallNo::SYS_write],
+            ReadyCondition::Idle => Vec::new(),
+        }
+    }
+}
+
+/// whether the program is ready, shared with any thread
+#[derive(Debug, Clone, Default)]
+pub struct Readiness(Arc<(Mutex<bool>, Condvar)>);
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        *(self.0).0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    /// block until the program is ready
+    pub fn wait_ready(&self) {
+        let (ready, cond) = &*self.0;
+        let mut ready = ready.lock().unwrap_or_else(|e| e.into_inner());
+        while !*ready {
+            ready = cond.wait(ready).unwrap();
+        }
+    }
+    /// block until the program is ready, or `timeout`, false if not ready
+    pub fn wait_ready_timeout(&self, timeout: Duration) -> bool {
+        let (ready, cond) = &*self.0;
+        let ready = ready.lock().unwrap_or_else(|e| e.into_inner());
+        let (ready, _) = cond
+            .wait_timeout_while(ready, timeout, |ready| !*ready)
+            .unwrap();
+        *ready
+    }
+    fn set_ready(&self) {
+        let (ready, cond) = &*self.0;
+        *ready.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        cond.notify_all();
+    }
+}
+
+// port of `sockaddr_in` or `sockaddr_in6` at `addr`
+fn sockaddr_port(memory: &dyn TaskMemory, addr: u64) -> Option<u16> {
+    let bytes = memory.read_bytes(addr, 4).ok()?;
+    match u16::from_ne_bytes([bytes[0], bytes[1]]) as i32 {
+        libc::AF_INET | libc::AF_INET6 => {
+            Some(u16::from_be_bytes([bytes[2], bytes[3]]))
+        }
+        _ => None,
+    }
+}
+
+/// matches `ReadyCondition`s against syscalls of the program
+pub struct ReadyMatcher {
+    /// conditions, and whether met
+    conditions: Vec<(ReadyCondition, bool)>,
+    /// ports sockets are bound to, by pid and fd
+    bound: HashMap<(Pid, i32), u16>,
+    readiness: Readiness,
+    on_ready: Option<Box<dyn FnMut()>>,
+}
+
+impl ReadyMatcher {
+    pub fn new(conditions: Vec<ReadyCondition>) -> Self {
+        ReadyMatcher {
+            conditions: conditions.into_iter().map(|c| (c, false)).collect(),
+            bound: HashMap::new(),
+            readiness: Readiness::default(),
+            on_ready: None,
+        }
+    }
+
+    /// handle to wait for the program to be ready
+    pub fn readiness(&self) -> Readiness {
+        self.readiness.clone()
+    }
+
+    /// set `on_ready` to be called once ready
+    pub fn set_ready_handler(&mut self, on_ready: Box<dyn FnMut()>) {
+        self.on_ready = Some(on_ready);
+    }
+
+    fn met<F: Fn(&ReadyCondition) -> bool>(&mut self, matches: F) {
+        if self.readiness.is_ready() {
+            return;
+        }
+        for (condition, met) in self.conditions.iter_mut() {
+            *met = *met || matches(condition);
+        }
+        if self.conditions.iter().all(|(_, met)| *met) {
+            self.readiness.set_ready();
+            if let Some(on_ready) = self.on_ready.as_mut() {
+                on_ready();
+            }
+        }
+    }
+
+    /// syscall of process `pid` returned `retval`
+    pub fn exited(
+        &mut self,
+        pid: Pid,
+        memory: &dyn TaskMemory,
+        syscall: SyscallNo,
+        args: &SyscallArgs,
+        retval: i64,
+    ) {
+        if retval < 0 || self.readiness.is_ready() {
+            return;
+        }
+        match syscall {
+            SyscallNo::SYS_bind => {
+                let fd = args.arg0 as i32;
+                if let Some(port) = sockaddr_port(memory, args.arg1) {
+                    self.bound.insert((pid, fd), port);
+                }
+            }
+            SyscallNo::SYS_listen => {
+                if let Some(&port) = self.bound.get(&(pid, args.arg0 as i32)) {
+                    self.met(|c| *c == ReadyCondition::Listen(port));
+                }
+            }
+            SyscallNo::SYS_open | SyscallNo::SYS_openat => {
+                let path = if syscall == SyscallNo::SYS_open {
+                    args.arg0
+                } else {
+                    args.arg1
+                };
+                let link = format!("/proc/{}/fd/{}", pid, retval);
+                let opened = std::fs::read_link(link)
+                    .or_else(|_| read_cstring(memory, path).map(PathBuf::from));
+                if let Ok(opened) = opened {
+                    self.met(|c| match c {
+                        ReadyCondition::Open(path) => opened.ends_with(path),
+                        _ => false,
+                    });
+                }
+            }
+            SyscallNo::SYS_write if retval > 0 => {
+                let bytes = match memory.read_bytes(args.arg1, retval as usize)
+                {
+                    Ok(bytes) => bytes,
+                    Err(_) => return,
+                };
+                let written = String::from_utf8_lossy(&bytes);
+                self.met(|c| match c {
+                    ReadyCondition::Write(string) => written.contains(string),
+                    _ => false,
+                });
+            }
+            _ => (),
+        }
+    }
+
+    /// `event` observed by the tracer
+    pub fn record_event(&mut self, event: &TimedEvent) {
+        match &event.event {
+            Event::Idle(_) => self.met(|c| *c == ReadyCondition::Idle),
+            Event::Exited(_) => {
+                let pid = event.tid;
+                self.bound.retain(|&(p, _), _| p != pid);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// syscall emulation (declining all syscalls) and event sink matching
+/// with `matcher`
+pub fn ready_tracing(
+    matcher: Rc<RefCell<ReadyMatcher>>,
+) -> (SyscallEmulation, EventSink) {
+    let mut syscalls: Vec<SyscallNo> = Vec::new();
+    for (condition, _) in &matcher.borrow().conditions {
+        for syscall in condition.syscalls() {
+            if !syscalls.contains(&syscall) {
+                syscalls.push(syscall);
+            }
+        }
+    }
+    let mut emulation = SyscallEmulation::new(
+        EmulationMode::Seccomp,
+        syscalls,
+        Box::new(|_task, _memory, _syscall, _args| None),
+    );
+    let exits = matcher.clone();
+    emulation.set_exit_handler(Box::new(
+        move |task, memory, syscall, args, retval| {
+            let pid = task.getpid();
+            exits
+                .borrow_mut()
+                .exited(pid, memory, syscall, args, retval)
+        },
+    ));
+    let sink: EventSink =
+        Box::new(move |event| matcher.borrow_mut().record_event(event));
+    (emulation, sink)
+}
+
+#[test]
+fn ready_sanity_check() {
+    use crate::lies::FakeMemory;
+
+    let conditions: Vec<ReadyCondition> =
+        ["listen:8080", "open:run/app.pid", "write:started"]
+            .iter()
+            .map(|c| c.parse().unwrap())
+            .collect();
+    assert_eq!(conditions[0], ReadyCondition::Listen(8080));
+    assert_eq!(conditions[1].to_string(), "open:run/app.pid");
+    assert!("listen:http".parse::<ReadyCondition>().is_err());
+    assert!("idle:1".parse::<ReadyCondition>().is_err());
+    let mut matcher = ReadyMatcher::new(conditions);
+    let readiness = matcher.readiness();
+    let (pid, args) = (Pid::from_raw(i32::MAX), |a0, a1| {
+        SyscallArgs::from(a0, a1, 0, 0, 0, 0)
+    });
+    let mut bytes = vec![0u8; 128];
+    bytes[0..2].copy_from_slice(&(libc::AF_INET as u16).to_ne_bytes());
+    bytes[2..4].copy_from_slice(&8080u16.to_be_bytes());
+    bytes[16..34].copy_from_slice(b"/var/run/app.pid\0\0");
+    bytes[40..55].copy_from_slice(b"server started\n");
+    let memory = FakeMemory(RefCell::new(bytes));
+    matcher.exited(pid, &memory, SyscallNo::SYS_listen, &args(3, 0), 0);
+    matcher.exited(pid, &memory, SyscallNo::SYS_bind, &args(3, 0), 0);
+    matcher.exited(pid, &memory, SyscallNo::SYS_listen, &args(3, 0), 0);
+    matcher.exited(pid, &memory, SyscallNo::SYS_open, &args(16, 0), 4);
+    assert!(!readiness.is_ready());
+    assert!(!readiness.wait_ready_timeout(Duration::from_millis(1)));
+    matcher.exited(pid, &memory, SyscallNo::SYS_write, &args(1, 40), 15);
+    assert!(readiness.is_ready());
+    readiness.wait_ready();
+}
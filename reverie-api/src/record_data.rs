@@ -0,0 +1,148 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! partial recording of syscall data, see `TaskEventCB::record_data`
+//!
+//! data selected by a `DataSelection` is given in full, by
+//! `Event::SyscallData`, other data by size and sha256 only, by
+//! `Event::SyscallDigest`: enough to tell runs apart, not to replay them.
+//! i.e.: network fds only, for far smaller recordings when debugging a
+//! protocol.
+
+use std::fmt;
+use std::io::{self, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use syscalls::SyscallNo;
+
+/// data to record in full
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DataSelector {
+    /// read from fd
+    Fd(i32),
+    /// read from files under path
+    Path(PathBuf),
+    /// returned by syscall, by name, i.e.: `read`
+    Syscall(String),
+    /// read from sockets
+    Sockets,
+}
+
+impl FromStr for DataSelector {
+    type Err = io::Error;
+    /// `fd:N`, `path:PATH`, `syscall:NAME`, or `sockets`
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid data selector: {}", s),
+            )
+        };
+        let (kind, value) = match s.find(':') {
+            Some(at) => (&s[..at], &s[at + 1..]),
+            None => (s, ""),
+        };
+        match (kind, value) {
+            ("sockets", "") => Ok(DataSelector::Sockets),
+            ("fd", fd) => {
+                fd.parse().map(DataSelector::Fd).map_err(|_| invalid())
+            }
+            ("path", path) if !path.is_empty() => {
+                Ok(DataSelector::Path(PathBuf::from(path)))
+            }
+            ("syscall", name) if !name.is_empty() => {
+                Ok(DataSelector::Syscall(name.to_string()))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for DataSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataSelector::Fd(fd) => write!(f, "fd:{}", fd),
+            DataSelector::Path(path) => write!(f, "path:{}", path.display()),
+            DataSelector::Syscall(name) => write!(f, "syscall:{}", name),
+            DataSelector::Sockets => write!(f, "sockets"),
+        }
+    }
+}
+
+/// syscall data to record in full, all of it without selectors
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DataSelection {
+    pub selectors: Vec<DataSelector>,
+}
+
+impl DataSelection {
+    pub fn new(selectors: Vec<DataSelector>) -> Self {
+        DataSelection { selectors }
+    }
+
+    /// whether all data is selected
+    pub fn is_all(&self) -> bool {
+        self.selectors.is_empty()
+    }
+
+    /// whether data `syscall` returned is selected, read from `fd`, and
+    /// the file it is open on (as in `/proc/PID/fd`), if any
+    pub fn is_selected(
+        &self,
+        syscall: SyscallNo,
+        fd: Option<i32>,
+        file: Option<&Path>,
+    ) -> bool {
+        if self.is_all() {
+            return true;
+        }
+        let name = format!("{:?}", syscall);
+        let name = name.trim_start_matches("SYS_");
+        self.selectors.iter().any(|selector| match selector {
+            DataSelector::Fd(selected) => fd == Some(*selected),
+            DataSelector::Path(path) => file.map_or(false, |file| {
+                file.is_absolute() && file.starts_with(path)
+            }),
+            DataSelector::Syscall(selected) => selected == name,
+            DataSelector::Sockets => file
+                .and_then(|file| file.to_str())
+                .map_or(false, |file| file.starts_with("socket:")),
+        })
+    }
+}
+
+#[test]
+fn data_selection_sanity_check() {
+    let selectors: Vec<DataSelector> = ["sockets", "fd:7", "path:/etc"]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+    assert_eq!(selectors[1].to_string(), "fd:7");
+    assert!("fd:x".parse::<DataSelector>().is_err());
+    assert!("path:".parse::<DataSelector>().is_err());
+    let selection = DataSelection::new(selectors);
+    let read = SyscallNo::SYS_read;
+    let socket = Path::new("socket:[1234]");
+    assert!(selection.is_selected(read, Some(3), Some(socket)));
+    assert!(selection.is_selected(read, Some(7), None));
+    let passwd = Path::new("/etc/passwd");
+    assert!(selection.is_selected(read, Some(3), Some(passwd)));
+    let etcetera = Path::new("/etcetera");
+    assert!(!selection.is_selected(read, Some(3), Some(etcetera)));
+
+    let selection =
+        DataSelection::new(vec!["syscall:getdents64".parse().unwrap()]);
+    assert!(selection.is_selected(SyscallNo::SYS_getdents64, Some(3), None));
+    assert!(!selection.is_selected(read, Some(3), None));
+    assert!(DataSelection::default().is_selected(read, None, None));
+}
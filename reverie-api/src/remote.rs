@@ -23,6 +23,7 @@ use nix::unistd::Pid;
 use std::io::{Error, Result};
 use syscalls::*;
 
+use crate::arch::{self, Arch, Host, HostRegs};
 use crate::task::*;
 
 /// a pointer belongs to tracee's address space
@@ -289,9 +290,9 @@ pub trait GuestMemoryAccess {
 /// trait implements most ptrace interface.
 pub trait Ptracer: GuestMemoryAccess {
     /// get inferior user regs
-    fn getregs(&self) -> Result<libc::user_regs_struct>;
+    fn getregs(&self) -> Result<HostRegs>;
     /// set inferior user regs
-    fn setregs(&self, regs: libc::user_regs_struct) -> Result<()>;
+    fn setregs(&self, regs: HostRegs) -> Result<()>;
     /// get inferior ptrace event
     fn getevent(&self) -> Result<i64>;
     /// resume a stopped inferior
@@ -302,8 +303,12 @@ pub trait Ptracer: GuestMemoryAccess {
     fn getsiginfo(&self) -> Result<libc::siginfo_t>;
 }
 
-fn from_nix_error(err: nix::Error) -> Error {
-    Error::new(std::io::ErrorKind::Other, err)
+/// `err` as an `io::Error`, keeping its errno (if any)
+pub fn from_nix_error(err: nix::Error) -> Error {
+    match err {
+        nix::Error::Sys(errno) => Error::from_raw_os_error(errno as i32),
+        err => Error::new(std::io::ErrorKind::Other, err),
+    }
 }
 
 /// peek bytes from inferior
@@ -412,6 +417,12 @@ impl SyscallArgs {
             arg5: a5,
         }
     }
+
+    /// arguments of the syscall of a syscall stop with `regs`
+    pub fn from_regs(regs: &HostRegs) -> Self {
+        let [a0, a1, a2, a3, a4, a5] = Host::syscall_args(regs);
+        SyscallArgs::from(a0, a1, a2, a3, a4, a5)
+    }
 }
 
 // The value returned in RAX on x86_64:
@@ -433,8 +444,12 @@ pub type FunAddr = Remoteable<u64>;
 /// inject the functional calls, and instead intercept and prevent attempts by the guest
 /// to register signal handlers in the first place.
 pub trait Injector {
-    /// Inject a system call into the guest and wait for the return value
-    fn inject_syscall(&self, nr: SyscallNo, args: &SyscallArgs) -> i64;
+    /// Inject a system call into the guest and wait for the return value,
+    /// `-errno` as an error, see `syscall_result`. The guest must be
+    /// stopped, past the exec of its program. The syscall is not traced,
+    /// and the guest registers are restored after. See `crate::inject` for
+    /// helpers.
+    fn inject_syscall(&self, nr: SyscallNo, args: &SyscallArgs) -> Result<i64>;
 
     /// Look up the symbol address within the guest.
     /// only symbols from dso passwd by `--tool` is looked up
@@ -468,52 +483,123 @@ pub fn untraced_syscall(
     a4: u64,
     a5: u64,
 ) -> i64 {
+    try_untraced_syscall(task, nr, a0, a1, a2, a3, a4, a5).unwrap()
+}
+
+/// same as `untraced_syscall`, but ptrace errors are returned rather than
+/// panic. if the tracee is killed during the syscall, returns `ESRCH`, the
+/// tracee is resumed to exit if it was in `PTRACE_EVENT_EXIT` stop. signals
+/// received during the syscall are sent again after.
+#[allow(clippy::too_many_arguments)]
+pub fn try_untraced_syscall(
+    task: &dyn Task,
+    nr: SyscallNo,
+    a0: u64,
+    a1: u64,
+    a2: u64,
+    a3: u64,
+    a4: u64,
+    a5: u64,
+) -> nix::Result<i64> {
     let tid = task.gettid();
-    let mut regs = ptrace::getregs(tid).unwrap();
+    let mut regs = arch::getregs::<Host>(tid)?;
     let oldregs = regs;
 
-    let no = nr as u64;
-    regs.orig_rax = no;
-    regs.rax = no;
-    regs.rdi = a0 as u64;
-    regs.rsi = a1 as u64;
-    regs.rdx = a2 as u64;
-    regs.r10 = a3 as u64;
-    regs.r8 = a4 as u64;
-    regs.r9 = a5 as u64;
+    Host::set_syscall(&mut regs, nr as u64, &[a0, a1, a2, a3, a4, a5]);
 
-    // instruction at 0x7000_0008 must be
-    // callq 0x70000000 (5-bytes)
-    // .byte 0xcc
-    regs.rip = 0x7000_0008;
+    // call the untraced syscall of the private page (0x7000_0000), then
+    // break, see `Arch::syscall_sequences`
+    Host::set_pc(&mut regs, 0x7000_0000 + Host::CALL_UNTRACED_SYSCALL);
 
-    ptrace::setregs(task.gettid(), regs).unwrap();
-    ptrace::cont(tid, None).unwrap();
+    arch::cont_with::<Host>(tid, regs)?;
 
-    wait_sigtrap_sigchld(tid).unwrap();
+    let pending = match wait_sigtrap(tid) {
+        Ok(pending) => pending,
+        Err(err) => {
+            let _ = arch::setregs::<Host>(tid, oldregs);
+            return Err(err);
+        }
+    };
 
-    let newregs = ptrace::getregs(tid).unwrap();
-    ptrace::setregs(tid, oldregs).unwrap();
-    newregs.rax as i64
+    let newregs = arch::getregs::<Host>(tid)?;
+    arch::setregs::<Host>(tid, oldregs)?;
+    for sig in pending {
+        let _ = unsafe {
+            libc::syscall(
+                libc::SYS_tgkill,
+                task.getpid().as_raw(),
+                tid.as_raw(),
+                sig as i32,
+            )
+        };
+    }
+    Ok(Host::retval(&newregs))
 }
 
-// wait either SIGTRAP (breakpoint) or SIGCHLD.
-fn wait_sigtrap_sigchld(pid: Pid) -> Result<Option<signal::Signal>> {
-    let mut signal_to_deliver = None;
-    let status = wait::waitpid(pid, None).expect("waitpid");
-    match status {
-        WaitStatus::Stopped(_pid, signal::SIGTRAP) => (),
-        WaitStatus::Stopped(_pid, signal::SIGCHLD) => {
-            signal_to_deliver = Some(signal::SIGCHLD)
-        }
-        otherwise => {
-            panic!(
-                "task {} expecting SIGTRAP|SIGCHLD but got {:?}",
-                pid, otherwise
-            );
+/// result of a syscall returning `retval`, `-errno` as an error
+pub fn syscall_result(retval: i64) -> Result<i64> {
+    if retval as u64 > (-4096i64) as u64 {
+        Err(Error::from_raw_os_error(-retval as i32))
+    } else {
+        Ok(retval)
+    }
+}
+
+/// return value of a syscall of result `res`, errors other than of the
+/// syscall (i.e.: of ptrace) as `-EIO`
+pub fn syscall_retval(res: Result<i64>) -> i64 {
+    match res {
+        Ok(retval) => retval,
+        Err(err) => -i64::from(err.raw_os_error().unwrap_or(libc::EIO)),
+    }
+}
+
+/// same as `try_untraced_syscall`, errors (of the syscall, or ptrace) as
+/// `io::Error`s
+pub fn inject_untraced_syscall(
+    task: &dyn Task,
+    nr: SyscallNo,
+    args: &SyscallArgs,
+) -> Result<i64> {
+    let retval = try_untraced_syscall(
+        task, nr, args.arg0, args.arg1, args.arg2, args.arg3, args.arg4,
+        args.arg5,
+    )
+    .map_err(|err| match err {
+        nix::Error::Sys(errno) => Error::from_raw_os_error(errno as i32),
+        err => from_nix_error(err),
+    })?;
+    syscall_result(retval)
+}
+
+// wait the SIGTRAP (breakpoint) ending an injected syscall. signals
+// received meanwhile (i.e.: SIGCHLD) are returned, to be sent again once
+// the registers are restored; faults are `EFAULT`, other stops `EIO`.
+fn wait_sigtrap(pid: Pid) -> nix::Result<Vec<signal::Signal>> {
+    let mut pending = Vec::new();
+    loop {
+        match wait::waitpid(pid, None)? {
+            WaitStatus::Stopped(_pid, signal::SIGTRAP) => return Ok(pending),
+            WaitStatus::Stopped(_pid, signal::SIGSEGV)
+            | WaitStatus::Stopped(_pid, signal::SIGBUS)
+            | WaitStatus::Stopped(_pid, signal::SIGILL) => {
+                return Err(nix::Error::Sys(nix::errno::Errno::EFAULT));
+            }
+            WaitStatus::Stopped(_pid, sig) => {
+                pending.push(sig);
+                ptrace::cont(pid, None)?;
+            }
+            // killed, i.e.: by `exit_group` from another thread.
+            WaitStatus::PtraceEvent(_pid, signal::SIGTRAP, 6) => {
+                let _ = ptrace::cont(pid, None);
+                return Err(nix::Error::Sys(nix::errno::Errno::ESRCH));
+            }
+            WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                return Err(nix::Error::Sys(nix::errno::Errno::ESRCH));
+            }
+            _ => return Err(nix::Error::Sys(nix::errno::Errno::EIO)),
         }
-    };
-    Ok(signal_to_deliver)
+    }
 }
 
 /// inject syscall for given tracee
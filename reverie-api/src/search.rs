@@ -0,0 +1,241 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! remote memory search
+//!
+//! scans mappings of a tracee for a byte pattern (i.e.: magic values,
+//! canaries, or markers injected by a tool), reading `SEARCH_CHUNK_SIZE`
+//! bytes at a time with `process_vm_readv`, see `Task::search_memory`.
+//!
+//! NB: pages which cannot be read (i.e.: guard pages) are skipped.
+
+use nix::sys::uio;
+use nix::unistd::Pid;
+use std::io::Result;
+
+use crate::remote::RemotePtr;
+
+/// bytes read from the tracee at a time
+pub const SEARCH_CHUNK_SIZE: usize = 0x10000;
+
+// device number of `major`:`minor`, as `st_dev` of `stat`
+fn makedev(major: u64, minor: u64) -> u64 {
+    (major & 0xfff) << 8
+        | (major & !0xfff) << 32
+        | (minor & 0xff)
+        | (minor & !0xff) << 12
+}
+
+/// a mapping of `/proc/<pid>/maps`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    /// i.e.: `rw-p`
+    pub perms: String,
+    /// offset of `start` in the file mapped
+    pub offset: u64,
+    /// device and inode of the file mapped, 0 if anonymous
+    pub dev: u64,
+    pub inode: u64,
+    /// file or pseudo-path (i.e.: `[stack]`), empty if anonymous
+    pub path: String,
+}
+
+impl Region {
+    /// parse a line of `/proc/<pid>/maps`
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+        let mut range = fields.next()?.splitn(2, '-');
+        let start = u64::from_str_radix(range.next()?, 16).ok()?;
+        let end = u64::from_str_radix(range.next()?, 16).ok()?;
+        let perms = fields.next()?.to_string();
+        let offset = u64::from_str_radix(fields.next()?, 16).ok()?;
+        let mut dev = fields.next()?.splitn(2, ':');
+        let major = u64::from_str_radix(dev.next()?, 16).ok()?;
+        let minor = u64::from_str_radix(dev.next()?, 16).ok()?;
+        let inode = fields.next()?.parse().ok()?;
+        let path = fields.next().unwrap_or("").to_string();
+        Some(Region {
+            start,
+            end,
+            perms,
+            offset,
+            dev: makedev(major, minor),
+            inode,
+            path,
+        })
+    }
+
+    fn has_perm(&self, perm: char) -> bool {
+        self.perms.contains(perm)
+    }
+}
+
+/// mappings to search
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SearchRegions {
+    /// all readable mappings
+    All,
+    /// readable and writable mappings
+    Writable,
+    /// readable and executable mappings
+    Executable,
+    /// readable mappings whose path contains the string, i.e.: `[heap]`
+    Named(String),
+    /// address ranges `[start, end)`, whichever the mappings
+    Ranges(Vec<(u64, u64)>),
+}
+
+impl SearchRegions {
+    /// address ranges of `regions` to search
+    pub fn select(&self, regions: &[Region]) -> Vec<(u64, u64)> {
+        let selected = |region: &&Region| -> bool {
+            if !region.has_perm('r') {
+                return false;
+            }
+            match self {
+                SearchRegions::All | SearchRegions::Ranges(_) => true,
+                SearchRegions::Writable => region.has_perm('w'),
+                SearchRegions::Executable => region.has_perm('x'),
+                SearchRegions::Named(name) => region.path.contains(name),
+            }
+        };
+        let ranges = regions
+            .iter()
+            .filter(selected)
+            .map(|region| (region.start, region.end));
+        match self {
+            SearchRegions::Ranges(wanted) => ranges
+                .flat_map(|(start, end)| {
+                    wanted.iter().filter_map(move |&(from, to)| {
+                        let (from, to) = (from.max(start), to.min(end));
+                        if from < to {
+                            Some((from, to))
+                        } else {
+                            None
+                        }
+                    })
+                })
+                .collect(),
+            _ => ranges.collect(),
+        }
+    }
+}
+
+/// mappings of process `pid`
+pub fn read_regions(pid: Pid) -> Result<Vec<Region>> {
+    let maps = std::fs::read_to_string(format!("/proc/{}/maps", pid))?;
+    Ok(maps.lines().filter_map(Region::parse).collect())
+}
+
+// read `[addr, addr + buf.len())` of `pid`, returns bytes read, which are
+// fewer than asked when running into an unreadable page.
+fn read_chunk(pid: Pid, addr: u64, buf: &mut [u8]) -> usize {
+    let remote_iov = &[uio::RemoteIoVec {
+        base: addr as usize,
+        len: buf.len(),
+    }];
+    let local_iov = &[uio::IoVec::from_mut_slice(buf)];
+    uio::process_vm_readv(pid, local_iov, remote_iov).unwrap_or(0)
+}
+
+fn find_all(haystack: &[u8], needle: &[u8]) -> Vec<usize> {
+    haystack
+        .windows(needle.len())
+        .enumerate()
+        .filter(|(_, window)| *window == needle)
+        .map(|(k, _)| k)
+        .collect()
+}
+
+// search `[start, end)`, chunks overlap by `pattern.len() - 1` bytes, so that
+// matches across chunks are found (once).
+fn search_range(
+    pid: Pid,
+    pattern: &[u8],
+    (start, end): (u64, u64),
+    found: &mut Vec<u64>,
+) {
+    let overlap = pattern.len() as u64 - 1;
+    let mut buf = vec![0; SEARCH_CHUNK_SIZE.max(2 * pattern.len())];
+    let mut addr = start;
+    while addr + overlap < end {
+        let size = (end - addr).min(buf.len() as u64) as usize;
+        let nb = read_chunk(pid, addr, &mut buf[..size]);
+        if nb >= pattern.len() {
+            found.extend(
+                find_all(&buf[..nb], pattern)
+                    .into_iter()
+                    .map(|k| addr + k as u64),
+            );
+        }
+        if nb as u64 <= overlap {
+            // skip the page which could not be read
+            addr = (addr + nb as u64 + 0x1000) & !0xfff;
+        } else {
+            addr += nb as u64 - overlap;
+        }
+    }
+}
+
+/// addresses of `pattern` in `regions` of process `pid`, in ascending order
+pub fn search_memory(
+    pid: Pid,
+    pattern: &[u8],
+    regions: &SearchRegions,
+) -> Result<Vec<RemotePtr<u8>>> {
+    if pattern.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut found = Vec::new();
+    for range in regions.select(&read_regions(pid)?) {
+        search_range(pid, pattern, range, &mut found);
+    }
+    found.sort_unstable();
+    found.dedup();
+    Ok(found
+        .into_iter()
+        .filter_map(|addr| RemotePtr::new(addr as *mut u8))
+        .collect())
+}
+
+#[test]
+fn search_memory_sanity_check() {
+    let line = "7ffd1000-7ffd3000 rw-p 00000000 00:00 0    [stack]";
+    let region = Region::parse(line).unwrap();
+    assert_eq!((region.start, region.end), (0x7ffd_1000, 0x7ffd_3000));
+    assert_eq!(
+        (region.perms.as_str(), region.path.as_str()),
+        ("rw-p", "[stack]")
+    );
+    let line = "7f10000-7f12000 r-xp 00001000 08:02 173521 /usr/lib/libc.so";
+    let lib = Region::parse(line).unwrap();
+    assert_eq!((lib.dev, lib.inode), (0x802, 173_521));
+    assert_eq!(lib.path, "/usr/lib/libc.so");
+    let ranges = SearchRegions::Ranges(vec![(0x7ffd_2000, 0x8000_0000)]);
+    assert_eq!(ranges.select(&[region]), vec![(0x7ffd_2000, 0x7ffd_3000)]);
+
+    // a marker in memory spanning several chunks, searching ourselves.
+    let marker = b"reverie-search-marker";
+    let mut memory = vec![0u8; 3 * SEARCH_CHUNK_SIZE];
+    let at = SEARCH_CHUNK_SIZE - 4;
+    memory[at..at + marker.len()].copy_from_slice(marker);
+    let addr = memory.as_ptr() as u64 + at as u64;
+    let pid = nix::unistd::getpid();
+    let found = search_memory(pid, marker, &SearchRegions::Writable).unwrap();
+    assert!(found.iter().any(|ptr| ptr.as_ptr() as u64 == addr));
+    let only = SearchRegions::Ranges(vec![(addr - 16, addr + 64)]);
+    let found = search_memory(pid, marker, &only).unwrap();
+    assert_eq!(found.len(), 1);
+}
@@ -0,0 +1,162 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! shared memory events and policies
+//!
+//! shared memory lets tracees communicate without syscalls, which breaks
+//! record/replay and determinism. sysv shared memory (`shmget`/`shmat`),
+//! `memfd_create` and `MAP_SHARED` mappings are detected by the tracer.
+
+use nix::unistd::Pid;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// a shared memory object created or mapped by a tracee
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SharedMemory {
+    /// `shmget` returned `shmid`
+    SysvCreate { key: i32, size: u64, shmid: i32 },
+    /// `shmat` attached `shmid` at `addr`
+    SysvAttach { shmid: i32, addr: u64 },
+    /// `memfd_create` returned `fd`
+    Memfd { fd: i32, path: Option<PathBuf> },
+    /// `mmap(MAP_SHARED)` of `fd` (-1 if anonymous) at `addr`
+    Mapping {
+        fd: i32,
+        addr: u64,
+        size: u64,
+        path: Option<PathBuf>,
+    },
+}
+
+/// what the tracer does with shared memory syscalls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SharedMemoryPolicy {
+    /// no detection, shared memory syscalls can be patched as usual
+    Ignore,
+    /// allow, and report `Event::SharedMemory`
+    Annotate,
+    /// fail shared memory syscalls with `EPERM`
+    Deny,
+    /// downgrade `MAP_SHARED` mappings to `MAP_PRIVATE`. sysv shared
+    /// memory cannot be made private, hence is denied.
+    Private,
+}
+
+impl Default for SharedMemoryPolicy {
+    fn default() -> Self {
+        SharedMemoryPolicy::Ignore
+    }
+}
+
+impl FromStr for SharedMemoryPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ignore" => Ok(SharedMemoryPolicy::Ignore),
+            "annotate" => Ok(SharedMemoryPolicy::Annotate),
+            "deny" => Ok(SharedMemoryPolicy::Deny),
+            "private" => Ok(SharedMemoryPolicy::Private),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown shared memory policy: {}", s),
+            )),
+        }
+    }
+}
+
+/// identity of a shared memory object
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MemoryObject {
+    /// sysv shared memory segment
+    Sysv(i32),
+    /// file (including memfd) identified by device and inode
+    File { dev: u64, ino: u64 },
+    /// `MAP_SHARED | MAP_ANONYMOUS` mapping, by creator and address
+    Anonymous { owner: Pid, addr: u64 },
+}
+
+/// which (traced) processes map which shared memory objects
+///
+/// NB: `munmap`/`shmdt` are not tracked, a process is assumed to share an
+/// object until it execs or exits, hence sharing can be over-reported.
+#[derive(Debug, Default)]
+pub struct SharedMemoryMap {
+    objects: HashMap<MemoryObject, HashSet<Pid>>,
+}
+
+impl SharedMemoryMap {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// process `pid` mapped `object`
+    pub fn attach(&mut self, object: MemoryObject, pid: Pid) {
+        self.objects.entry(object).or_default().insert(pid);
+    }
+    /// `child` forked by `parent` inherits all its shared mappings
+    pub fn fork(&mut self, parent: Pid, child: Pid) {
+        for pids in self.objects.values_mut() {
+            if pids.contains(&parent) {
+                pids.insert(child);
+            }
+        }
+    }
+    /// process `pid` exec'ed or exited, all its mappings are gone
+    pub fn detach_all(&mut self, pid: Pid) {
+        self.objects.values_mut().for_each(|pids| {
+            pids.remove(&pid);
+        });
+        self.objects.retain(|_, pids| !pids.is_empty());
+    }
+    /// processes mapping `object`
+    pub fn sharers(&self, object: &MemoryObject) -> Vec<Pid> {
+        self.objects
+            .get(object)
+            .map(|pids| pids.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+    /// other processes which can race with `pid` through memory
+    pub fn shared_with(&self, pid: Pid) -> Vec<Pid> {
+        let mut res: HashSet<Pid> = HashSet::new();
+        self.objects
+            .values()
+            .filter(|pids| pids.contains(&pid))
+            .for_each(|pids| res.extend(pids.iter().filter(|p| **p != pid)));
+        res.into_iter().collect()
+    }
+    /// objects mapped by more than one process, with their sharers
+    pub fn shared_objects(&self) -> Vec<(MemoryObject, Vec<Pid>)> {
+        self.objects
+            .iter()
+            .filter(|(_, pids)| pids.len() > 1)
+            .map(|(obj, pids)| (*obj, pids.iter().cloned().collect()))
+            .collect()
+    }
+}
+
+#[test]
+fn shared_memory_map_sanity_check() {
+    let (p1, p2, p3) = (Pid::from_raw(1), Pid::from_raw(2), Pid::from_raw(3));
+    let mut map = SharedMemoryMap::new();
+    map.attach(MemoryObject::Sysv(7), p1);
+    map.fork(p1, p2);
+    assert_eq!(map.shared_with(p1), vec![p2]);
+    map.attach(MemoryObject::File { dev: 1, ino: 2 }, p3);
+    assert!(map.shared_with(p3).is_empty());
+    assert_eq!(map.shared_objects().len(), 1);
+    map.detach_all(p2);
+    assert!(map.shared_objects().is_empty());
+    assert_eq!(map.sharers(&MemoryObject::Sysv(7)), vec![p1]);
+}
@@ -0,0 +1,1124 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! strace compatible text output
+//!
+//! formats `TimedEvent`s as strace does, including the `-f` (pid),
+//! `-t`/`-tt`/`-ttt` (timestamp) and `-T` (time spent) conventions, so that
+//! existing strace post-processors work on reverie output. fds, common
+//! flags and strings are decoded, strings being read from tracee memory at
+//! syscall entry (`Event::SyscallStrings`), other arguments are printed in
+//! hex. buffers returned by syscalls (i.e.: of `read`) are not decoded.
+//!
+//! a syscall is printed once it returns. with `-f`, a syscall interleaved
+//! with output of other tasks is printed as `<unfinished ...>`, and its
+//! return as `<... resumed>`, as strace does.
+
+use nix::errno::Errno;
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::io::{Result, Write};
+use std::time::Duration;
+use syscalls::*;
+
+use crate::clock::*;
+use crate::device::read_cstring;
+use crate::emulate::TaskMemory;
+use crate::event::*;
+use crate::lies::syscall_name;
+use crate::remote::SyscallArgs;
+use crate::wait::ChildStatus;
+
+/// column return values are aligned to, as strace's default `-a`
+const ALIGN_COLUMN: usize = 40;
+
+/// strace options affecting the output format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StraceOptions {
+    /// `-f`: prefix lines with the task's tid
+    pub follow_forks: bool,
+    /// `-t` (1): wall clock time, `-tt` (2): with microseconds, `-ttt` (3):
+    /// seconds since epoch, with microseconds
+    pub timestamps: u8,
+    /// `-T`: time spent in syscalls
+    pub syscall_times: bool,
+}
+
+/// writes `TimedEvent`s in strace format
+pub struct StraceWriter<W> {
+    out: W,
+    options: StraceOptions,
+    calibration: Option<ClockCalibration>,
+    /// syscalls entered, not yet returned
+    pending: HashMap<Pid, Entered>,
+}
+
+// syscall entered, not yet returned
+struct Entered {
+    at: Timestamp,
+    no: SyscallNo,
+    args: SyscallArgs,
+    strings: Vec<(usize, Vec<u8>)>,
+    /// printed as `<unfinished ...>` already
+    unfinished: bool,
+}
+
+// how a syscall argument is printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arg {
+    Hex,
+    Decimal,
+    Octal,
+    /// fd, or `AT_FDCWD`
+    Fd,
+    /// nul terminated string
+    Path,
+    /// buffer, of the length given by argument
+    Buffer(usize),
+    OpenFlags,
+    Prot,
+    MapFlags,
+}
+
+/// number of arguments shown for `no`, all six if unknown
+fn syscall_nargs(no: SyscallNo) -> usize {
+    match no {
+        SYS_getpid | SYS_getppid | SYS_getuid | SYS_geteuid | SYS_getgid
+        | SYS_getegid | SYS_gettid | SYS_getpgrp | SYS_setsid | SYS_fork
+        | SYS_vfork | SYS_sched_yield | SYS_pause | SYS_sync
+        | SYS_munlockall | SYS_vhangup | SYS_inotify_init
+        | SYS_restart_syscall | SYS_rt_sigreturn => 0,
+        SYS_close
+        | SYS_dup
+        | SYS_exit
+        | SYS_exit_group
+        | SYS_brk
+        | SYS_alarm
+        | SYS_uname
+        | SYS_chdir
+        | SYS_fchdir
+        | SYS_rmdir
+        | SYS_unlink
+        | SYS_umask
+        | SYS_time
+        | SYS_times
+        | SYS_sysinfo
+        | SYS_getpgid
+        | SYS_getsid
+        | SYS_setuid
+        | SYS_setgid
+        | SYS_setfsuid
+        | SYS_setfsgid
+        | SYS_personality
+        | SYS_pipe
+        | SYS_iopl
+        | SYS_acct
+        | SYS_chroot
+        | SYS_sched_getscheduler
+        | SYS_sched_get_priority_max
+        | SYS_sched_get_priority_min
+        | SYS_mlockall
+        | SYS_epoll_create
+        | SYS_epoll_create1
+        | SYS_inotify_init1
+        | SYS_set_tid_address
+        | SYS_io_destroy
+        | SYS_shmdt
+        | SYS_fsync
+        | SYS_fdatasync
+        | SYS_syncfs
+        | SYS_unshare
+        | SYS_eventfd
+        | SYS_swapoff
+        | SYS_adjtimex
+        | SYS_timer_getoverrun
+        | SYS_timer_delete
+        | SYS_mq_unlink => 1,
+        SYS_munmap
+        | SYS_fstat
+        | SYS_stat
+        | SYS_lstat
+        | SYS_access
+        | SYS_dup2
+        | SYS_pipe2
+        | SYS_kill
+        | SYS_tkill
+        | SYS_ftruncate
+        | SYS_truncate
+        | SYS_rename
+        | SYS_mkdir
+        | SYS_creat
+        | SYS_link
+        | SYS_symlink
+        | SYS_chmod
+        | SYS_fchmod
+        | SYS_gettimeofday
+        | SYS_settimeofday
+        | SYS_getrlimit
+        | SYS_setrlimit
+        | SYS_getrusage
+        | SYS_setpgid
+        | SYS_setreuid
+        | SYS_setregid
+        | SYS_getgroups
+        | SYS_setgroups
+        | SYS_capget
+        | SYS_capset
+        | SYS_rt_sigpending
+        | SYS_rt_sigsuspend
+        | SYS_sigaltstack
+        | SYS_utime
+        | SYS_utimes
+        | SYS_statfs
+        | SYS_fstatfs
+        | SYS_getpriority
+        | SYS_sched_setparam
+        | SYS_sched_getparam
+        | SYS_sched_rr_get_interval
+        | SYS_mlock
+        | SYS_munlock
+        | SYS_arch_prctl
+        | SYS_pivot_root
+        | SYS_umount2
+        | SYS_swapon
+        | SYS_sethostname
+        | SYS_setdomainname
+        | SYS_io_setup
+        | SYS_clock_settime
+        | SYS_clock_gettime
+        | SYS_clock_getres
+        | SYS_set_robust_list
+        | SYS_timerfd_create
+        | SYS_timerfd_gettime
+        | SYS_eventfd2
+        | SYS_setns
+        | SYS_listen
+        | SYS_shutdown
+        | SYS_flock
+        | SYS_nanosleep
+        | SYS_getitimer
+        | SYS_memfd_create
+        | SYS_inotify_rm_watch
+        | SYS_timer_gettime
+        | SYS_delete_module
+        | SYS_removexattr
+        | SYS_lremovexattr
+        | SYS_fremovexattr
+        | SYS_mq_notify
+        | SYS_fanotify_init => 2,
+        SYS_read
+        | SYS_write
+        | SYS_open
+        | SYS_lseek
+        | SYS_mprotect
+        | SYS_ioctl
+        | SYS_readv
+        | SYS_writev
+        | SYS_madvise
+        | SYS_mincore
+        | SYS_msync
+        | SYS_shmget
+        | SYS_shmat
+        | SYS_shmctl
+        | SYS_dup3
+        | SYS_socket
+        | SYS_connect
+        | SYS_accept
+        | SYS_sendmsg
+        | SYS_recvmsg
+        | SYS_bind
+        | SYS_getsockname
+        | SYS_getpeername
+        | SYS_setitimer
+        | SYS_execve
+        | SYS_fcntl
+        | SYS_getdents
+        | SYS_getdents64
+        | SYS_fchown
+        | SYS_chown
+        | SYS_lchown
+        | SYS_readlink
+        | SYS_mknod
+        | SYS_getresuid
+        | SYS_getresgid
+        | SYS_setresuid
+        | SYS_setresgid
+        | SYS_rt_sigqueueinfo
+        | SYS_sched_setscheduler
+        | SYS_sched_setaffinity
+        | SYS_sched_getaffinity
+        | SYS_syslog
+        | SYS_setpriority
+        | SYS_modify_ldt
+        | SYS_tgkill
+        | SYS_init_module
+        | SYS_semget
+        | SYS_semop
+        | SYS_msgget
+        | SYS_msgctl
+        | SYS_ioprio_set
+        | SYS_ioprio_get
+        | SYS_inotify_add_watch
+        | SYS_mkdirat
+        | SYS_unlinkat
+        | SYS_fchmodat
+        | SYS_faccessat
+        | SYS_futimesat
+        | SYS_getcpu
+        | SYS_signalfd
+        | SYS_timer_create
+        | SYS_listxattr
+        | SYS_llistxattr
+        | SYS_flistxattr
+        | SYS_getrandom
+        | SYS_seccomp
+        | SYS_finit_module
+        | SYS_poll
+        | SYS_readahead
+        | SYS_io_submit
+        | SYS_io_cancel
+        | SYS_set_mempolicy
+        | SYS_sched_setattr => 3,
+        SYS_rt_sigaction
+        | SYS_rt_sigprocmask
+        | SYS_pread64
+        | SYS_pwrite64
+        | SYS_sendfile
+        | SYS_socketpair
+        | SYS_wait4
+        | SYS_newfstatat
+        | SYS_openat
+        | SYS_mknodat
+        | SYS_renameat
+        | SYS_readlinkat
+        | SYS_utimensat
+        | SYS_rt_sigtimedwait
+        | SYS_clock_nanosleep
+        | SYS_timer_settime
+        | SYS_epoll_ctl
+        | SYS_epoll_wait
+        | SYS_quotactl
+        | SYS_reboot
+        | SYS_msgsnd
+        | SYS_semctl
+        | SYS_semtimedop
+        | SYS_timerfd_settime
+        | SYS_accept4
+        | SYS_signalfd4
+        | SYS_rt_tgsigqueueinfo
+        | SYS_prlimit64
+        | SYS_fallocate
+        | SYS_sync_file_range
+        | SYS_tee
+        | SYS_vmsplice
+        | SYS_getxattr
+        | SYS_lgetxattr
+        | SYS_fgetxattr
+        | SYS_mq_open
+        | SYS_fadvise64
+        | SYS_ptrace
+        | SYS_sendmmsg
+        | SYS_sched_getattr
+        | SYS_migrate_pages
+        | SYS_request_key => 4,
+        SYS_select
+        | SYS_mremap
+        | SYS_setsockopt
+        | SYS_getsockopt
+        | SYS_prctl
+        | SYS_waitid
+        | SYS_ppoll
+        | SYS_mount
+        | SYS_setxattr
+        | SYS_lsetxattr
+        | SYS_fsetxattr
+        | SYS_linkat
+        | SYS_fchownat
+        | SYS_keyctl
+        | SYS_add_key
+        | SYS_msgrcv
+        | SYS_io_getevents
+        | SYS_perf_event_open
+        | SYS_name_to_handle_at
+        | SYS_renameat2
+        | SYS_kcmp
+        | SYS_execveat
+        | SYS_statx
+        | SYS_preadv
+        | SYS_pwritev
+        | SYS_mq_timedsend
+        | SYS_mq_timedreceive
+        | SYS_clone
+        | SYS_recvmmsg
+        | SYS_fanotify_mark
+        | SYS_get_mempolicy => 5,
+        _ => 6,
+    }
+}
+
+// how argument `k` of `no` is printed
+fn syscall_arg(no: SyscallNo, k: usize) -> Arg {
+    match (no, k) {
+        (SYS_open, 1) | (SYS_openat, 2) => Arg::OpenFlags,
+        (SYS_mmap, 2) | (SYS_mprotect, 2) | (SYS_pkey_mprotect, 2) => Arg::Prot,
+        (SYS_mmap, 3) => Arg::MapFlags,
+        (SYS_write, 1) | (SYS_pwrite64, 1) | (SYS_sendto, 1) => Arg::Buffer(2),
+        (SYS_open, 2)
+        | (SYS_openat, 3)
+        | (SYS_creat, 1)
+        | (SYS_mkdir, 1)
+        | (SYS_mkdirat, 2)
+        | (SYS_chmod, 1)
+        | (SYS_fchmod, 1)
+        | (SYS_fchmodat, 2)
+        | (SYS_umask, 0) => Arg::Octal,
+        (SYS_read, 2)
+        | (SYS_write, 2)
+        | (SYS_pread64, 2)
+        | (SYS_pwrite64, 2)
+        | (SYS_readv, 2)
+        | (SYS_writev, 2)
+        | (SYS_sendto, 2)
+        | (SYS_recvfrom, 2)
+        | (SYS_lseek, 1)
+        | (SYS_mmap, 1)
+        | (SYS_munmap, 1)
+        | (SYS_mprotect, 1)
+        | (SYS_exit, 0)
+        | (SYS_exit_group, 0)
+        | (SYS_kill, _)
+        | (SYS_tkill, _)
+        | (SYS_tgkill, _)
+        | (SYS_wait4, 0) => Arg::Decimal,
+        (SYS_mmap, 4)
+        | (SYS_dup2, 1)
+        | (SYS_dup3, 1)
+        | (SYS_sendfile, 1)
+        | (SYS_epoll_ctl, 2)
+        | (SYS_symlinkat, 1)
+        | (SYS_renameat, 2)
+        | (SYS_renameat2, 2)
+        | (SYS_linkat, 2) => Arg::Fd,
+        (SYS_read, 0)
+        | (SYS_write, 0)
+        | (SYS_close, 0)
+        | (SYS_fstat, 0)
+        | (SYS_lseek, 0)
+        | (SYS_ioctl, 0)
+        | (SYS_readv, 0)
+        | (SYS_writev, 0)
+        | (SYS_pread64, 0)
+        | (SYS_pwrite64, 0)
+        | (SYS_dup, 0)
+        | (SYS_dup2, 0)
+        | (SYS_dup3, 0)
+        | (SYS_fcntl, 0)
+        | (SYS_fsync, 0)
+        | (SYS_fdatasync, 0)
+        | (SYS_syncfs, 0)
+        | (SYS_ftruncate, 0)
+        | (SYS_fallocate, 0)
+        | (SYS_fadvise64, 0)
+        | (SYS_getdents, 0)
+        | (SYS_getdents64, 0)
+        | (SYS_fchdir, 0)
+        | (SYS_fchmod, 0)
+        | (SYS_fchown, 0)
+        | (SYS_flock, 0)
+        | (SYS_fstatfs, 0)
+        | (SYS_sendfile, 0)
+        | (SYS_connect, 0)
+        | (SYS_accept, 0)
+        | (SYS_accept4, 0)
+        | (SYS_bind, 0)
+        | (SYS_listen, 0)
+        | (SYS_sendto, 0)
+        | (SYS_recvfrom, 0)
+        | (SYS_sendmsg, 0)
+        | (SYS_recvmsg, 0)
+        | (SYS_shutdown, 0)
+        | (SYS_getsockname, 0)
+        | (SYS_getpeername, 0)
+        | (SYS_setsockopt, 0)
+        | (SYS_getsockopt, 0)
+        | (SYS_epoll_ctl, 0)
+        | (SYS_epoll_wait, 0)
+        | (SYS_openat, 0)
+        | (SYS_newfstatat, 0)
+        | (SYS_mkdirat, 0)
+        | (SYS_mknodat, 0)
+        | (SYS_unlinkat, 0)
+        | (SYS_fchmodat, 0)
+        | (SYS_fchownat, 0)
+        | (SYS_faccessat, 0)
+        | (SYS_readlinkat, 0)
+        | (SYS_renameat, 0)
+        | (SYS_renameat2, 0)
+        | (SYS_linkat, 0)
+        | (SYS_utimensat, 0)
+        | (SYS_statx, 0)
+        | (SYS_execveat, 0) => Arg::Fd,
+        (SYS_open, 0)
+        | (SYS_creat, 0)
+        | (SYS_stat, 0)
+        | (SYS_lstat, 0)
+        | (SYS_access, 0)
+        | (SYS_execve, 0)
+        | (SYS_truncate, 0)
+        | (SYS_statfs, 0)
+        | (SYS_chdir, 0)
+        | (SYS_chroot, 0)
+        | (SYS_mkdir, 0)
+        | (SYS_rmdir, 0)
+        | (SYS_unlink, 0)
+        | (SYS_mknod, 0)
+        | (SYS_chmod, 0)
+        | (SYS_chown, 0)
+        | (SYS_lchown, 0)
+        | (SYS_readlink, 0)
+        | (SYS_utime, 0)
+        | (SYS_utimes, 0)
+        | (SYS_memfd_create, 0)
+        | (SYS_rename, _)
+        | (SYS_link, _)
+        | (SYS_symlink, _)
+        | (SYS_symlinkat, 0)
+        | (SYS_symlinkat, 2)
+        | (SYS_openat, 1)
+        | (SYS_newfstatat, 1)
+        | (SYS_mkdirat, 1)
+        | (SYS_mknodat, 1)
+        | (SYS_unlinkat, 1)
+        | (SYS_fchmodat, 1)
+        | (SYS_fchownat, 1)
+        | (SYS_faccessat, 1)
+        | (SYS_readlinkat, 1)
+        | (SYS_renameat, 1)
+        | (SYS_renameat, 3)
+        | (SYS_renameat2, 1)
+        | (SYS_renameat2, 3)
+        | (SYS_linkat, 1)
+        | (SYS_linkat, 3)
+        | (SYS_utimensat, 1)
+        | (SYS_statx, 1)
+        | (SYS_execveat, 1) => Arg::Path,
+        _ => Arg::Hex,
+    }
+}
+
+fn syscall_args(args: &SyscallArgs) -> [u64; 6] {
+    [
+        args.arg0, args.arg1, args.arg2, args.arg3, args.arg4, args.arg5,
+    ]
+}
+
+/// strings syscall `no` is given, read from `memory` at its entry: paths
+/// in full, buffers up to `limit` bytes, see `Event::SyscallStrings`
+pub fn syscall_strings(
+    memory: &dyn TaskMemory,
+    no: SyscallNo,
+    args: &SyscallArgs,
+    limit: usize,
+) -> Vec<(usize, Vec<u8>)> {
+    let values = syscall_args(args);
+    let mut strings = Vec::new();
+    for (k, value) in values.iter().enumerate().take(syscall_nargs(no)) {
+        let string = match syscall_arg(no, k) {
+            _ if *value == 0 => continue,
+            Arg::Path => read_cstring(memory, *value).map(String::into_bytes),
+            Arg::Buffer(len) => {
+                let len = std::cmp::min(values[len] as usize, limit);
+                memory.read_bytes(*value, len)
+            }
+            _ => continue,
+        };
+        if let Ok(string) = string {
+            strings.push((k, string));
+        }
+    }
+    strings
+}
+
+// syscalls returning an address, printed in hex
+fn returns_address(no: SyscallNo) -> bool {
+    match no {
+        SYS_mmap | SYS_mremap | SYS_brk | SYS_shmat => true,
+        _ => false,
+    }
+}
+
+fn hex(value: u64) -> String {
+    if value == 0 {
+        String::from("0")
+    } else {
+        format!("{:#x}", value)
+    }
+}
+
+// `value` as `names` or'ed, and the remaining bits in hex
+fn flags(value: u64, names: &[(i32, &str)]) -> String {
+    let mut rest = value;
+    let mut shown: Vec<String> = Vec::new();
+    for (bits, name) in names {
+        let bits = *bits as u64;
+        if bits != 0 && rest & bits == bits {
+            shown.push(String::from(*name));
+            rest &= !bits;
+        }
+    }
+    if rest != 0 || shown.is_empty() {
+        shown.push(hex(rest));
+    }
+    shown.join("|")
+}
+
+// compound flags (`O_TMPFILE`, `O_SYNC`) first
+const OPEN_FLAGS: &[(i32, &str)] = &[
+    (libc::O_TMPFILE, "O_TMPFILE"),
+    (libc::O_SYNC, "O_SYNC"),
+    (libc::O_CREAT, "O_CREAT"),
+    (libc::O_EXCL, "O_EXCL"),
+    (libc::O_NOCTTY, "O_NOCTTY"),
+    (libc::O_TRUNC, "O_TRUNC"),
+    (libc::O_APPEND, "O_APPEND"),
+    (libc::O_NONBLOCK, "O_NONBLOCK"),
+    (libc::O_DSYNC, "O_DSYNC"),
+    (libc::O_ASYNC, "O_ASYNC"),
+    (libc::O_DIRECT, "O_DIRECT"),
+    (0o100000, "O_LARGEFILE"),
+    (libc::O_DIRECTORY, "O_DIRECTORY"),
+    (libc::O_NOFOLLOW, "O_NOFOLLOW"),
+    (libc::O_NOATIME, "O_NOATIME"),
+    (libc::O_CLOEXEC, "O_CLOEXEC"),
+    (libc::O_PATH, "O_PATH"),
+];
+
+const PROT_FLAGS: &[(i32, &str)] = &[
+    (libc::PROT_READ, "PROT_READ"),
+    (libc::PROT_WRITE, "PROT_WRITE"),
+    (libc::PROT_EXEC, "PROT_EXEC"),
+    (libc::PROT_GROWSDOWN, "PROT_GROWSDOWN"),
+    (libc::PROT_GROWSUP, "PROT_GROWSUP"),
+];
+
+const MAP_FLAGS: &[(i32, &str)] = &[
+    (libc::MAP_FIXED, "MAP_FIXED"),
+    (libc::MAP_ANONYMOUS, "MAP_ANONYMOUS"),
+    (libc::MAP_32BIT, "MAP_32BIT"),
+    (libc::MAP_GROWSDOWN, "MAP_GROWSDOWN"),
+    (libc::MAP_DENYWRITE, "MAP_DENYWRITE"),
+    (libc::MAP_EXECUTABLE, "MAP_EXECUTABLE"),
+    (libc::MAP_LOCKED, "MAP_LOCKED"),
+    (libc::MAP_NORESERVE, "MAP_NORESERVE"),
+    (libc::MAP_POPULATE, "MAP_POPULATE"),
+    (libc::MAP_NONBLOCK, "MAP_NONBLOCK"),
+    (libc::MAP_STACK, "MAP_STACK"),
+    (libc::MAP_HUGETLB, "MAP_HUGETLB"),
+];
+
+fn open_flags(value: u64) -> String {
+    let mode = match value & libc::O_ACCMODE as u64 {
+        0 => "O_RDONLY",
+        1 => "O_WRONLY",
+        2 => "O_RDWR",
+        _ => return flags(value, OPEN_FLAGS),
+    };
+    match value & !(libc::O_ACCMODE as u64) {
+        0 => String::from(mode),
+        rest => format!("{}|{}", mode, flags(rest, OPEN_FLAGS)),
+    }
+}
+
+fn prot_flags(value: u64) -> String {
+    match value {
+        0 => String::from("PROT_NONE"),
+        _ => flags(value, PROT_FLAGS),
+    }
+}
+
+fn map_flags(value: u64) -> String {
+    let kind = match value & 3 {
+        1 => "MAP_SHARED",
+        2 => "MAP_PRIVATE",
+        3 => "MAP_SHARED_VALIDATE",
+        _ => return flags(value, MAP_FLAGS),
+    };
+    match value & !3 {
+        0 => String::from(kind),
+        rest => format!("{}|{}", kind, flags(rest, MAP_FLAGS)),
+    }
+}
+
+// `bytes` as a C string literal, non printable bytes in octal
+fn quoted(bytes: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for (k, byte) in bytes.iter().enumerate() {
+        match *byte {
+            b'"' => quoted += "\\\"",
+            b'\\' => quoted += "\\\\",
+            b'\t' => quoted += "\\t",
+            b'\n' => quoted += "\\n",
+            b'\r' => quoted += "\\r",
+            0x0b => quoted += "\\v",
+            0x0c => quoted += "\\f",
+            0x20..=0x7e => quoted.push(*byte as char),
+            _ => match bytes.get(k + 1) {
+                Some(b'0'..=b'7') => quoted += &format!("\\{:03o}", byte),
+                _ => quoted += &format!("\\{:o}", byte),
+            },
+        }
+    }
+    quoted + "\""
+}
+
+// arguments of `entered` as shown, without the closing parenthesis
+fn syscall_call(entered: &Entered) -> String {
+    let (no, values) = (entered.no, syscall_args(&entered.args));
+    let mut nargs = syscall_nargs(no);
+    // the mode of `open` is shown only if used
+    if let Some(flags) = match no {
+        SYS_open => Some(values[1] as i32),
+        SYS_openat => Some(values[2] as i32),
+        _ => None,
+    } {
+        if flags & libc::O_CREAT == 0
+            && flags & libc::O_TMPFILE != libc::O_TMPFILE
+        {
+            nargs -= 1;
+        }
+    }
+    let string = |k| {
+        entered
+            .strings
+            .iter()
+            .find(|(arg, _)| *arg == k)
+            .map(|(_, string)| string)
+    };
+    let args: Vec<String> = values
+        .iter()
+        .enumerate()
+        .take(nargs)
+        .map(|(k, value)| match syscall_arg(no, k) {
+            Arg::Hex => hex(*value),
+            Arg::Decimal => format!("{}", *value as i64),
+            Arg::Octal => format!("0{:o}", value),
+            Arg::Fd if *value as i32 == libc::AT_FDCWD => {
+                String::from("AT_FDCWD")
+            }
+            Arg::Fd => format!("{}", *value as i32),
+            Arg::Path | Arg::Buffer(_) if *value == 0 => String::from("NULL"),
+            Arg::Path => string(k).map_or_else(|| hex(*value), |s| quoted(s)),
+            Arg::Buffer(len) => match string(k) {
+                Some(s) if s.len() < values[len] as usize => quoted(s) + "...",
+                Some(s) => quoted(s),
+                None => hex(*value),
+            },
+            Arg::OpenFlags => open_flags(*value),
+            Arg::Prot => prot_flags(*value),
+            Arg::MapFlags => map_flags(*value),
+        })
+        .collect();
+    format!("{}({}", syscall_name(no as i32), args.join(", "))
+}
+
+fn syscall_retval(no: SyscallNo, retval: i64) -> String {
+    if retval < 0 && retval > -4096 {
+        let errno = Errno::from_i32(-retval as i32);
+        format!("-1 {:?} ({})", errno, errno.desc())
+    } else if returns_address(no) {
+        hex(retval as u64)
+    } else {
+        format!("{}", retval)
+    }
+}
+
+// `localtime` of seconds since epoch, as (hours, minutes, seconds)
+fn local_time(secs: i64) -> (i32, i32, i32) {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let t = secs as libc::time_t;
+    unsafe { libc::localtime_r(&t, &mut tm) };
+    (tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
+
+impl<W: Write> StraceWriter<W> {
+    pub fn new(out: W, options: StraceOptions) -> Self {
+        StraceWriter {
+            out,
+            options,
+            calibration: None,
+            pending: HashMap::new(),
+        }
+    }
+
+    // `-f` and `-t` prefixes of a line of `tid` at `at`
+    fn prefix(&self, tid: Pid, at: Timestamp) -> String {
+        let mut prefix = String::new();
+        if self.options.follow_forks {
+            prefix += &format!("{:<5} ", tid);
+        }
+        if self.options.timestamps > 0 {
+            let nanos = self
+                .calibration
+                .map(|calib| calib.to_realtime(at))
+                .unwrap_or_else(|| at.as_nanos());
+            let (secs, micros) =
+                ((nanos / 1_000_000_000) as i64, nanos % 1_000_000_000 / 1000);
+            let (h, m, s) = local_time(secs);
+            prefix += &match self.options.timestamps {
+                1 => format!("{:02}:{:02}:{:02} ", h, m, s),
+                2 => format!("{:02}:{:02}:{:02}.{:06} ", h, m, s, micros),
+                _ => format!("{}.{:06} ", secs, micros),
+            };
+        }
+        prefix
+    }
+
+    // with `-f`, syscalls of tasks other than `tid` not printed yet are
+    // printed as `<unfinished ...>`, before output of `tid`
+    fn interrupt_others(&mut self, tid: Pid) -> Result<()> {
+        if !self.options.follow_forks {
+            return Ok(());
+        }
+        let mut others: Vec<(Timestamp, Pid)> = self
+            .pending
+            .iter()
+            .filter(|(other, entered)| **other != tid && !entered.unfinished)
+            .map(|(other, entered)| (entered.at, *other))
+            .collect();
+        others.sort_by_key(|(at, other)| (*at, other.as_raw()));
+        for (at, other) in others {
+            let line = self.prefix(other, at)
+                + &syscall_call(&self.pending[&other])
+                + " <unfinished ...>";
+            writeln!(self.out, "{}", line)?;
+            if let Some(entered) = self.pending.get_mut(&other) {
+                entered.unfinished = true;
+            }
+        }
+        Ok(())
+    }
+
+    // syscall `entered` by `tid`, returned at `at`
+    fn write_syscall(
+        &mut self,
+        tid: Pid,
+        at: Timestamp,
+        entered: Entered,
+        retval: Option<i64>,
+        elapsed: Option<Duration>,
+    ) -> Result<()> {
+        let no = entered.no;
+        let mut line = if entered.unfinished {
+            format!(
+                "{}<... {} resumed>)",
+                self.prefix(tid, at),
+                syscall_name(no as i32)
+            )
+        } else {
+            self.prefix(tid, entered.at) + &syscall_call(&entered) + ")"
+        };
+        if line.len() < ALIGN_COLUMN {
+            line += &" ".repeat(ALIGN_COLUMN - line.len());
+        }
+        line += " = ";
+        match retval {
+            Some(retval) => line += &syscall_retval(no, retval),
+            None => line += "?",
+        }
+        if let (true, Some(elapsed)) = (self.options.syscall_times, elapsed) {
+            line += &format!(
+                " <{}.{:06}>",
+                elapsed.as_secs(),
+                elapsed.subsec_micros()
+            );
+        }
+        writeln!(self.out, "{}", line)
+    }
+
+    /// write `event`, syscalls are written once returned
+    pub fn write_event(&mut self, event: &TimedEvent) -> Result<()> {
+        let (tid, at) = (event.tid, event.at);
+        match &event.event {
+            Event::Calibration(calib) => self.calibration = Some(*calib),
+            Event::SyscallEnter(no, args) => {
+                self.interrupt_others(tid)?;
+                if let Some(entered) = self.pending.remove(&tid) {
+                    self.write_syscall(tid, at, entered, None, None)?;
+                }
+                let entered = Entered {
+                    at,
+                    no: *no,
+                    args: *args,
+                    strings: Vec::new(),
+                    unfinished: false,
+                };
+                self.pending.insert(tid, entered);
+            }
+            Event::SyscallStrings(no, strings) => {
+                if let Some(entered) = self.pending.get_mut(&tid) {
+                    if entered.no == *no && !entered.unfinished {
+                        entered.strings = strings.clone();
+                    }
+                }
+            }
+            Event::SyscallExit(no, retval, elapsed) => {
+                self.interrupt_others(tid)?;
+                let entered =
+                    self.pending.remove(&tid).unwrap_or_else(|| Entered {
+                        at,
+                        no: *no,
+                        args: SyscallArgs::from(0, 0, 0, 0, 0, 0),
+                        strings: Vec::new(),
+                        unfinished: false,
+                    });
+                self.write_syscall(
+                    tid,
+                    at,
+                    entered,
+                    Some(*retval),
+                    Some(*elapsed),
+                )?;
+            }
+            Event::Signal(sig) => {
+                self.interrupt_others(tid)?;
+                let prefix = self.prefix(tid, at);
+                writeln!(
+                    self.out,
+                    "{}--- {:?} {{si_signo={:?}}} ---",
+                    prefix, sig, sig
+                )?;
+            }
+            Event::Exited(status) => {
+                self.interrupt_others(tid)?;
+                // `exit_group` never returns
+                if let Some(entered) = self.pending.remove(&tid) {
+                    self.write_syscall(tid, at, entered, None, None)?;
+                }
+                let prefix = self.prefix(tid, at);
+                match ChildStatus::from_wstatus(*status) {
+                    Some(ChildStatus::Signaled(sig, _)) => writeln!(
+                        self.out,
+                        "{}+++ killed by {:?} +++",
+                        prefix, sig
+                    )?,
+                    Some(ChildStatus::Exited(code)) => writeln!(
+                        self.out,
+                        "{}+++ exited with {} +++",
+                        prefix, code
+                    )?,
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write + 'static> StraceWriter<W> {
+    /// turn the writer into an `EventSink`, see `TaskEventCB::set_event_sink`
+    pub fn into_sink(mut self) -> EventSink {
+        Box::new(move |event| {
+            let _ = self.write_event(event);
+        })
+    }
+}
+
+#[test]
+fn strace_writer_sanity_check() {
+    use nix::sys::signal::Signal;
+
+    let options = StraceOptions {
+        follow_forks: true,
+        timestamps: 0,
+        syscall_times: true,
+    };
+    let mut writer = StraceWriter::new(Vec::new(), options);
+    let tid = Pid::from_raw(42);
+    let timed = |event| TimedEvent {
+        tid,
+        at: Timestamp::from_nanos(0),
+        ticks: None,
+        event,
+    };
+    let args = SyscallArgs::from(3, 0, 0, 0, 0, 0);
+    let events = [
+        Event::SyscallEnter(SYS_close, args),
+        Event::SyscallExit(SYS_close, -9, Duration::from_micros(12)),
+        Event::Signal(Signal::SIGCHLD),
+        Event::SyscallEnter(
+            SYS_exit_group,
+            SyscallArgs::from(0, 0, 0, 0, 0, 0),
+        ),
+        Event::Exited(1 << 8),
+    ];
+    for event in events.iter().cloned() {
+        writer.write_event(&timed(event)).unwrap();
+    }
+    let output = String::from_utf8(writer.out).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    let aligned = format!("{:<40} = -1 EBADF (", "42    close(3)");
+    assert!(lines[0].starts_with(&aligned));
+    assert!(lines[0].ends_with(") <0.000012>"));
+    assert_eq!(lines[1], "42    --- SIGCHLD {si_signo=SIGCHLD} ---");
+    assert!(lines[2].starts_with("42    exit_group(0) "));
+    assert!(lines[2].ends_with(" = ?"));
+    assert_eq!(lines[3], "42    +++ exited with 1 +++");
+}
+
+#[cfg(test)]
+fn strace_output(options: StraceOptions, events: Vec<(i32, Event)>) -> String {
+    let mut writer = StraceWriter::new(Vec::new(), options);
+    for (tid, event) in events {
+        let event = TimedEvent {
+            tid: Pid::from_raw(tid),
+            at: Timestamp::from_nanos(0),
+            ticks: None,
+            event,
+        };
+        writer.write_event(&event).unwrap();
+    }
+    String::from_utf8(writer.out).unwrap()
+}
+
+#[test]
+fn strace_decoding_sanity_check() {
+    let exit = |no, retval| Event::SyscallExit(no, retval, Duration::new(0, 0));
+    let (fdcwd, creat) = (libc::AT_FDCWD as u64, libc::O_CREAT as u64);
+    let cloexec = libc::O_CLOEXEC as u64;
+    let anonymous = (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as u64;
+    let events = vec![
+        (
+            1,
+            Event::SyscallEnter(
+                SYS_openat,
+                SyscallArgs::from(fdcwd, 0x1000, cloexec, 0, 0, 0),
+            ),
+        ),
+        (
+            1,
+            Event::SyscallStrings(
+                SYS_openat,
+                vec![(1, b"/etc/passwd".to_vec())],
+            ),
+        ),
+        (1, exit(SYS_openat, 3)),
+        (
+            1,
+            Event::SyscallEnter(
+                SYS_open,
+                SyscallArgs::from(0x1000, 1 | creat, 0o644, 0, 0, 0),
+            ),
+        ),
+        (
+            1,
+            Event::SyscallStrings(SYS_open, vec![(0, b"a\"b".to_vec())]),
+        ),
+        (1, exit(SYS_open, -13)),
+        (
+            1,
+            Event::SyscallEnter(
+                SYS_mmap,
+                SyscallArgs::from(0, 8192, 3, anonymous, -1i64 as u64, 0),
+            ),
+        ),
+        (1, exit(SYS_mmap, 0x7f00_0000_0000)),
+        (
+            1,
+            Event::SyscallEnter(
+                SYS_write,
+                SyscallArgs::from(1, 0x2000, 40, 0, 0, 0),
+            ),
+        ),
+        (
+            1,
+            Event::SyscallStrings(SYS_write, vec![(1, b"hi\n\x012".to_vec())]),
+        ),
+        (1, exit(SYS_write, 40)),
+        (
+            1,
+            Event::SyscallEnter(
+                SYS_write,
+                SyscallArgs::from(2, 0x2000, 4, 0, 0, 0),
+            ),
+        ),
+        (1, exit(SYS_write, 4)),
+    ];
+    let expected = [
+        "openat(AT_FDCWD, \"/etc/passwd\", O_RDONLY|O_CLOEXEC) = 3",
+        "open(\"a\\\"b\", O_WRONLY|O_CREAT, 0644)     \
+         = -1 EACCES (Permission denied)",
+        "mmap(0, 8192, PROT_READ|PROT_WRITE, MAP_PRIVATE|MAP_ANONYMOUS, -1, 0) \
+         = 0x7f0000000000",
+        "write(1, \"hi\\n\\0012\"..., 40)             = 40",
+        "write(2, 0x2000, 4)                      = 4",
+    ];
+    let output = strace_output(StraceOptions::default(), events);
+    assert_eq!(output.lines().collect::<Vec<_>>(), expected);
+}
+
+#[test]
+fn strace_unfinished_sanity_check() {
+    let exit = |no, retval| Event::SyscallExit(no, retval, Duration::new(0, 0));
+    let events = vec![
+        (
+            1,
+            Event::SyscallEnter(
+                SYS_read,
+                SyscallArgs::from(3, 0x1000, 10, 0, 0, 0),
+            ),
+        ),
+        (
+            2,
+            Event::SyscallEnter(SYS_close, SyscallArgs::from(4, 0, 0, 0, 0, 0)),
+        ),
+        (2, exit(SYS_close, 0)),
+        (1, exit(SYS_read, 10)),
+    ];
+    let options = StraceOptions {
+        follow_forks: true,
+        ..StraceOptions::default()
+    };
+    let expected = [
+        "1     read(3, 0x1000, 10 <unfinished ...>",
+        "2     close(4)                           = 0",
+        "1     <... read resumed>)                = 10",
+    ];
+    let output = strace_output(options, events);
+    assert_eq!(output.lines().collect::<Vec<_>>(), expected);
+}
+
+#[test]
+fn syscall_strings_sanity_check() {
+    struct Memory(Vec<u8>);
+    impl TaskMemory for Memory {
+        fn read_bytes(&self, addr: u64, size: usize) -> Result<Vec<u8>> {
+            let end = std::cmp::min(addr as usize + size, self.0.len());
+            Ok(self.0[addr as usize..end].to_vec())
+        }
+        fn write_bytes(&self, _: u64, _: &[u8]) -> Result<()> {
+            Ok(())
+        }
+    }
+    let mut memory = Memory(vec![0; 256]);
+    memory.0[16..21].copy_from_slice(b"/tmp\0");
+    memory.0[32..40].copy_from_slice(b"01234567");
+    let args = SyscallArgs::from(16, 0, 0, 0, 0, 0);
+    let strings = syscall_strings(&memory, SYS_chdir, &args, 4);
+    assert_eq!(strings, vec![(0, b"/tmp".to_vec())]);
+    let args = SyscallArgs::from(1, 32, 8, 0, 0, 0);
+    let strings = syscall_strings(&memory, SYS_write, &args, 4);
+    assert_eq!(strings, vec![(1, b"0123".to_vec())]);
+    let args = SyscallArgs::from(1, 0, 8, 0, 0, 0);
+    assert!(syscall_strings(&memory, SYS_write, &args, 4).is_empty());
+}
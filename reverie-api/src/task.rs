@@ -16,7 +16,8 @@ use nix::unistd::Pid;
 
 use syscalls::SyscallNo;
 
-use crate::remote::Injector;
+use crate::remote::{Injector, RemotePtr};
+use crate::search::{self, SearchRegions};
 
 pub trait GlobalState {
     fn new() -> Self
@@ -58,6 +59,20 @@ pub enum TaskState {
     Exited(Pid, i32),
 }
 
+/// scheduling priority class of a task
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TaskPriority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Default for TaskPriority {
+    fn default() -> Self {
+        TaskPriority::Normal
+    }
+}
+
 /// Task which can be scheduled by `Sched`
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RunTask<Task> {
@@ -85,5 +100,24 @@ pub trait Task: Injector {
     fn getpid(&self) -> Pid;
     fn getppid(&self) -> Pid;
     fn getpgid(&self) -> Pid;
+    /// process whose fd table the task uses: its own, but for tasks cloned
+    /// with `CLONE_FILES`, until they exec
+    fn files(&self) -> Pid {
+        self.getpid()
+    }
     fn exited(&self, exit_code: i32) -> Option<i32>;
+    /// scheduling priority, only used by priority based schedulers
+    fn task_priority(&self) -> TaskPriority {
+        TaskPriority::default()
+    }
+    /// set scheduling priority of the task
+    fn set_task_priority(&mut self, _priority: TaskPriority) {}
+    /// addresses of `pattern` in `regions` of the task's memory
+    fn search_memory(
+        &self,
+        pattern: &[u8],
+        regions: &SearchRegions,
+    ) -> std::io::Result<Vec<RemotePtr<u8>>> {
+        search::search_memory(self.getpid(), pattern, regions)
+    }
 }
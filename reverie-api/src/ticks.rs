@@ -0,0 +1,93 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! execution points, by ticks, see `TaskEventCB::ticks`
+//!
+//! ticks alone don't tell where a task is: instructions other than
+//! conditional branches retire in between. an `ExecPoint` is ticks, the
+//! instruction pointer, and a fingerprint of the general purpose registers
+//! (i.e.: to tell iterations of a loop without conditional branches
+//! apart), as in rr.
+
+use nix::sys::signal::Signal;
+
+/// where a task is in its execution
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExecPoint {
+    pub ticks: u64,
+    pub rip: u64,
+    /// fingerprint of the registers, see `fingerprint`
+    pub regs: u64,
+}
+
+impl ExecPoint {
+    /// point of a task with `ticks`, and `regs`
+    pub fn new(ticks: u64, regs: &libc::user_regs_struct) -> Self {
+        ExecPoint {
+            ticks,
+            rip: regs.rip,
+            regs: fingerprint(regs),
+        }
+    }
+}
+
+/// fnv-1a hash of the general purpose registers, but `rip`. flags and
+/// segments are left out, they may differ at the same point, i.e.: when
+/// single-stepped.
+pub fn fingerprint(regs: &libc::user_regs_struct) -> u64 {
+    let gprs = [
+        regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp,
+        regs.rsp, regs.r8, regs.r9, regs.r10, regs.r11, regs.r12, regs.r13,
+        regs.r14, regs.r15,
+    ];
+    gprs.iter()
+        .flat_map(|reg| reg.to_le_bytes().to_vec())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// whether signal `sig` of siginfo `si_code` is asynchronous: sent, or
+/// raised by the kernel regardless of the instruction the task is at.
+/// faults the task caused are synchronous, replayed by executing.
+pub fn is_async_signal(sig: Signal, si_code: i32) -> bool {
+    // `SI_USER`, `SI_QUEUE`, `SI_TKILL`, .. are sent
+    if si_code <= 0 {
+        return true;
+    }
+    !matches!(
+        sig,
+        Signal::SIGSEGV
+            | Signal::SIGBUS
+            | Signal::SIGILL
+            | Signal::SIGFPE
+            | Signal::SIGTRAP
+            | Signal::SIGSYS
+    )
+}
+
+#[test]
+fn exec_point_sanity_check() {
+    let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+    regs.rip = 0x401000;
+    let point = ExecPoint::new(42, &regs);
+    regs.eflags = 0x100;
+    assert_eq!(ExecPoint::new(42, &regs), point);
+    regs.rcx = 1;
+    assert_ne!(ExecPoint::new(42, &regs), point);
+    assert_eq!(point.rip, 0x401000);
+
+    assert!(is_async_signal(Signal::SIGALRM, 0x80));
+    assert!(is_async_signal(Signal::SIGSEGV, -6));
+    assert!(!is_async_signal(Signal::SIGSEGV, 1));
+}
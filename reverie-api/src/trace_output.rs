@@ -0,0 +1,196 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! crash-safe trace output
+//!
+//! `TraceOutput` writes each complete line (record) to the file as soon as
+//! it is written, rather than buffering it in the tracer, so that nothing
+//! but a partial last record is lost if the tracer is `SIGKILL`ed. the
+//! file is `fdatasync`ed periodically, to survive machine crashes as well.
+//!
+//! all outputs are flushed at exit, and when the tracer panics, after a
+//! crash marker record, so that partial traces can be told apart from
+//! complete ones. panics caught by `isolation::catch_panic` are not tracer
+//! crashes.
+
+use lazy_static::lazy_static;
+use std::fs::File;
+use std::io::{Result, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, Once, Weak};
+use std::time::{Duration, Instant};
+
+use crate::isolation;
+
+/// formats the crash marker record, given the panic message
+pub type CrashMarkerFn = Box<dyn Fn(&str) -> String + Send>;
+
+struct Inner {
+    file: File,
+    /// last partial line, not written yet
+    pending: Vec<u8>,
+    sync_interval: Duration,
+    last_sync: Instant,
+    crash_marker: Option<CrashMarkerFn>,
+}
+
+impl Inner {
+    fn write_lines(&mut self) -> Result<()> {
+        if let Some(end) = self.pending.iter().rposition(|c| *c == b'\n') {
+            self.file.write_all(&self.pending[..=end])?;
+            self.pending.drain(..=end);
+        }
+        if self.last_sync.elapsed() >= self.sync_interval {
+            self.sync()?;
+        }
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.file.write_all(&self.pending)?;
+        self.pending.clear();
+        self.sync()
+    }
+    fn sync(&mut self) -> Result<()> {
+        self.last_sync = Instant::now();
+        self.file.sync_data()
+    }
+    fn crashed(&mut self, reason: &str) -> Result<()> {
+        if !self.pending.is_empty() {
+            self.pending.push(b'\n');
+        }
+        if let Some(marker) = self.crash_marker.as_ref() {
+            let record = marker(reason);
+            self.pending.extend_from_slice(record.as_bytes());
+        }
+        self.flush()
+    }
+}
+
+lazy_static! {
+    static ref OUTPUTS: Mutex<Vec<Weak<Mutex<Inner>>>> = Mutex::new(Vec::new());
+}
+
+static INSTALL_HOOKS: Once = Once::new();
+
+// applies `f` to all live outputs. locks are only tried: the panicking
+// thread could be holding one.
+fn for_each_output(f: impl Fn(&mut Inner)) {
+    if let Ok(outputs) = OUTPUTS.try_lock() {
+        for output in outputs.iter().filter_map(Weak::upgrade) {
+            if let Ok(mut inner) = output.try_lock() {
+                f(&mut inner);
+            }
+        }
+    }
+}
+
+extern "C" fn flush_at_exit() {
+    for_each_output(|inner| {
+        let _ = inner.flush();
+    });
+}
+
+fn install_hooks() {
+    INSTALL_HOOKS.call_once(|| {
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if !isolation::is_catching_panics() {
+                let reason = format!("{}", info);
+                for_each_output(|inner| {
+                    let _ = inner.crashed(&reason);
+                });
+            }
+            hook(info)
+        }));
+        unsafe { libc::atexit(flush_at_exit) };
+    });
+}
+
+/// crash-safe, line oriented trace output file
+pub struct TraceOutput {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl TraceOutput {
+    /// create trace output `path`, `fdatasync`ed every `sync_interval`
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        sync_interval: Duration,
+    ) -> Result<Self> {
+        let inner = Arc::new(Mutex::new(Inner {
+            file: File::create(path)?,
+            pending: Vec::new(),
+            sync_interval,
+            last_sync: Instant::now(),
+            crash_marker: None,
+        }));
+        install_hooks();
+        let mut outputs = OUTPUTS.lock().unwrap_or_else(|e| e.into_inner());
+        outputs.retain(|output| output.strong_count() > 0);
+        outputs.push(Arc::downgrade(&inner));
+        Ok(TraceOutput { inner })
+    }
+
+    /// set the crash marker record, written when the tracer panics
+    pub fn set_crash_marker(&mut self, marker: CrashMarkerFn) {
+        self.inner
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .crash_marker = Some(marker);
+    }
+}
+
+impl Write for TraceOutput {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.pending.extend_from_slice(buf);
+        inner.write_lines()?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).flush()
+    }
+}
+
+impl Drop for TraceOutput {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            let _ = inner.flush();
+        }
+    }
+}
+
+#[test]
+fn trace_output_sanity_check() {
+    let path = std::env::temp_dir()
+        .join(format!("reverie-trace-output-{}", std::process::id()));
+    let mut output = TraceOutput::create(&path, Duration::from_secs(60))
+        .expect("create trace output");
+    output.set_crash_marker(Box::new(|reason| {
+        format!("+++ tracer crashed: {} +++\n", reason)
+    }));
+    write!(output, "line 1\nline").unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "line 1\n");
+    output
+        .inner
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .crashed("oops")
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "line 1\nline\n+++ tracer crashed: oops +++\n"
+    );
+    drop(output);
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,279 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! policy violations
+//!
+//! syscalls denied by a policy (hermetic mode, `SharedMemoryPolicy::Deny`,
+//! `WxPolicy::Deny` or a signal filter) fail with an errno by default. with
+//! `ViolationAction::Kill`, the offending process is killed instead (not
+//! the rest of the tree), and a `Violation` is reported: the syscall, a
+//! backtrace and the recent events of the task, so that denials can be
+//! acted upon.
+
+use nix::unistd::Pid;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::str::FromStr;
+use syscalls::SyscallNo;
+
+use crate::emulate::TaskMemory;
+use crate::event::*;
+use crate::guest::GuestStruct;
+use crate::remote::SyscallArgs;
+use crate::search::Region;
+
+/// frames walked by `backtrace` at most
+pub const MAX_FRAMES: usize = 32;
+
+/// events kept per task by `RecentEvents`
+pub const RECENT_EVENTS: usize = 16;
+
+/// what to do with syscalls denied by a policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationAction {
+    /// fail the syscall with the policy's errno
+    Deny,
+    /// skip the syscall, and kill the process, see `Violation`
+    Kill,
+}
+
+impl Default for ViolationAction {
+    fn default() -> Self {
+        ViolationAction::Deny
+    }
+}
+
+impl FromStr for ViolationAction {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "deny" => Ok(ViolationAction::Deny),
+            "kill" => Ok(ViolationAction::Kill),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown violation action: {}", s),
+            )),
+        }
+    }
+}
+
+/// a return address (or the faulting ip) of a backtrace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub ip: u64,
+    /// mapping of `ip`, and offset of `ip` in the file mapped
+    pub location: Option<(String, u64)>,
+}
+
+impl Frame {
+    /// frame of `ip`, located in `regions`
+    pub fn new(ip: u64, regions: &[Region]) -> Self {
+        let location = regions
+            .iter()
+            .find(|region| region.start <= ip && ip < region.end)
+            .map(|region| {
+                let path = if region.path.is_empty() {
+                    String::from("[anon]")
+                } else {
+                    region.path.clone()
+                };
+                (path, ip - region.start + region.offset)
+            });
+        Frame { ip, location }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some((path, offset)) => {
+                write!(f, "{:#x} {}+{:#x}", self.ip, path, offset)
+            }
+            None => write!(f, "{:#x} ??", self.ip),
+        }
+    }
+}
+
+/// backtrace of a task stopped with `regs`, by walking frame pointers.
+///
+/// NB: best effort, code built without frame pointers ends the walk early,
+/// or yields bogus frames.
+pub fn backtrace(
+    memory: &dyn TaskMemory,
+    regs: &libc::user_regs_struct,
+    regions: &[Region],
+) -> Vec<Frame> {
+    let mut frames = vec![Frame::new(regs.rip, regions)];
+    let mut rbp = regs.rbp;
+    while rbp != 0 && frames.len() < MAX_FRAMES {
+        // saved rbp, then the return address
+        let frame = match u64::read_array(memory, rbp, 2) {
+            Ok(frame) => frame,
+            Err(_) => break,
+        };
+        if frame[1] == 0 {
+            break;
+        }
+        frames.push(Frame::new(frame[1], regions));
+        // frames of callers are above
+        if frame[0] <= rbp {
+            break;
+        }
+        rbp = frame[0];
+    }
+    frames
+}
+
+/// last `RECENT_EVENTS` events of each task
+#[derive(Debug, Default)]
+pub struct RecentEvents {
+    events: HashMap<Pid, VecDeque<TimedEvent>>,
+}
+
+impl RecentEvents {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// keep `event`, events of tasks exited are dropped
+    pub fn push(&mut self, event: &TimedEvent) {
+        if let Event::Exited(_) = event.event {
+            self.events.remove(&event.tid);
+            return;
+        }
+        let events = self.events.entry(event.tid).or_default();
+        if events.len() == RECENT_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(event.clone());
+    }
+
+    /// recent events of `tid`, oldest first
+    pub fn of(&self, tid: Pid) -> Vec<TimedEvent> {
+        self.events
+            .get(&tid)
+            .map(|events| events.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// a syscall denied by a policy, which killed the process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub pid: Pid,
+    pub tid: Pid,
+    pub syscall: SyscallNo,
+    pub args: SyscallArgs,
+    /// policy denying the syscall, and why, i.e.: the path denied
+    pub reason: String,
+    pub backtrace: Vec<Frame>,
+    /// events of `tid` before the syscall, see `RecentEvents`
+    pub recent: Vec<TimedEvent>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let args = &self.args;
+        writeln!(
+            f,
+            "[pid {}] killed by policy violation: {}",
+            self.pid, self.reason
+        )?;
+        writeln!(
+            f,
+            "  [tid {}] {:?}({:#x}, {:#x}, {:#x}, {:#x}, {:#x}, {:#x})",
+            self.tid,
+            self.syscall,
+            args.arg0,
+            args.arg1,
+            args.arg2,
+            args.arg3,
+            args.arg4,
+            args.arg5
+        )?;
+        writeln!(f, "  backtrace:")?;
+        for (k, frame) in self.backtrace.iter().enumerate() {
+            writeln!(f, "    #{} {}", k, frame)?;
+        }
+        writeln!(f, "  recent events:")?;
+        for event in &self.recent {
+            writeln!(f, "    {:?}", event.event)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn violation_sanity_check() {
+    use std::cell::RefCell;
+
+    struct Stack(RefCell<Vec<u8>>);
+    impl TaskMemory for Stack {
+        fn read_bytes(&self, addr: u64, size: usize) -> Result<Vec<u8>> {
+            let stack = self.0.borrow();
+            let addr = addr as usize;
+            if addr + size > stack.len() {
+                return Err(Error::from_raw_os_error(libc::EFAULT));
+            }
+            Ok(Vec::from(&stack[addr..addr + size]))
+        }
+        fn write_bytes(&self, addr: u64, bytes: &[u8]) -> Result<()> {
+            let addr = addr as usize;
+            self.0.borrow_mut()[addr..addr + bytes.len()]
+                .copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+    let kill = "kill".parse::<ViolationAction>().ok();
+    assert_eq!(kill, Some(ViolationAction::Kill));
+    assert!("abort".parse::<ViolationAction>().is_err());
+
+    // two frames: at 0x10 (returning to 0x1234), and 0x40 (to 0x5678).
+    let stack = Stack(RefCell::new(vec![0; 0x100]));
+    let frames: [(u64, u64); 2] = [(0x10, 0x40), (0x40, 0)];
+    for (at, next) in frames.iter() {
+        stack.write_bytes(*at, &next.to_le_bytes()).unwrap();
+    }
+    stack.write_bytes(0x18, &0x1234u64.to_le_bytes()).unwrap();
+    stack.write_bytes(0x48, &0x5678u64.to_le_bytes()).unwrap();
+    let line = "1000-2000 r-xp 00003000 08:01 42    /usr/bin/true";
+    let regions = vec![Region::parse(line).unwrap()];
+    let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+    regs.rip = 0x1100;
+    regs.rbp = 0x10;
+    let frames = backtrace(&stack, &regs, &regions);
+    let ips: Vec<u64> = frames.iter().map(|frame| frame.ip).collect();
+    assert_eq!(ips, vec![0x1100, 0x1234, 0x5678]);
+    assert_eq!(frames[1].to_string(), "0x1234 /usr/bin/true+0x3234");
+    assert_eq!(frames[2].to_string(), "0x5678 ??");
+
+    let tid = Pid::from_raw(7);
+    let mut recent = RecentEvents::new();
+    for _ in 0..RECENT_EVENTS + 2 {
+        recent.push(&TimedEvent {
+            tid,
+            at: Default::default(),
+            ticks: None,
+            event: Event::Exec,
+        });
+    }
+    assert_eq!(recent.of(tid).len(), RECENT_EVENTS);
+    recent.push(&TimedEvent {
+        tid,
+        at: Default::default(),
+        ticks: None,
+        event: Event::Exited(0),
+    });
+    assert!(recent.of(tid).is_empty());
+}
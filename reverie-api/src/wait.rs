@@ -0,0 +1,123 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! child status as returned by `wait4`/`waitid`
+//!
+//! the tracer skips syscalls and injects (or suppresses) signals, hence a
+//! traced parent can `wait` a child status which disagrees with the view
+//! the tool intends. `ChildStatus` decodes both `wait4` status and
+//! `waitid` `siginfo_t`, so that tools can rewrite it, see `WaitFilterFn`.
+
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+
+use crate::task::Task;
+
+const CLD_EXITED: i32 = 1;
+const CLD_KILLED: i32 = 2;
+const CLD_DUMPED: i32 = 3;
+const CLD_TRAPPED: i32 = 4;
+const CLD_STOPPED: i32 = 5;
+const CLD_CONTINUED: i32 = 6;
+
+/// status of a child, as reported to its parent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChildStatus {
+    /// exited with exit code
+    Exited(i32),
+    /// killed by signal, core dumped or not
+    Signaled(Signal, bool),
+    /// stopped by signal
+    Stopped(Signal),
+    /// resumed by `SIGCONT`
+    Continued,
+}
+
+impl ChildStatus {
+    /// decode `wait4` status, `None` if unknown
+    pub fn from_wstatus(status: i32) -> Option<Self> {
+        let sig = |signo: i32| Signal::from_c_int(signo).ok();
+        if status == 0xffff {
+            Some(ChildStatus::Continued)
+        } else if status & 0x7f == 0 {
+            Some(ChildStatus::Exited((status >> 8) & 0xff))
+        } else if status & 0xff == 0x7f {
+            sig((status >> 8) & 0xff).map(ChildStatus::Stopped)
+        } else {
+            sig(status & 0x7f)
+                .map(|signal| ChildStatus::Signaled(signal, status & 0x80 != 0))
+        }
+    }
+    /// encode as `wait4` status
+    pub fn to_wstatus(self) -> i32 {
+        match self {
+            ChildStatus::Exited(code) => (code & 0xff) << 8,
+            ChildStatus::Signaled(signal, core) => {
+                signal as i32 | if core { 0x80 } else { 0 }
+            }
+            ChildStatus::Stopped(signal) => (signal as i32) << 8 | 0x7f,
+            ChildStatus::Continued => 0xffff,
+        }
+    }
+    /// decode `si_code` and `si_status` of `waitid` `siginfo_t`
+    pub fn from_siginfo(code: i32, status: i32) -> Option<Self> {
+        let sig = Signal::from_c_int(status).ok();
+        match code {
+            CLD_EXITED => Some(ChildStatus::Exited(status)),
+            CLD_KILLED => {
+                sig.map(|signal| ChildStatus::Signaled(signal, false))
+            }
+            CLD_DUMPED => sig.map(|signal| ChildStatus::Signaled(signal, true)),
+            CLD_TRAPPED | CLD_STOPPED => sig.map(ChildStatus::Stopped),
+            CLD_CONTINUED => Some(ChildStatus::Continued),
+            _ => None,
+        }
+    }
+    /// encode as (`si_code`, `si_status`) of `waitid` `siginfo_t`
+    pub fn to_siginfo(self) -> (i32, i32) {
+        match self {
+            ChildStatus::Exited(code) => (CLD_EXITED, code),
+            ChildStatus::Signaled(signal, false) => (CLD_KILLED, signal as i32),
+            ChildStatus::Signaled(signal, true) => (CLD_DUMPED, signal as i32),
+            ChildStatus::Stopped(signal) => (CLD_STOPPED, signal as i32),
+            ChildStatus::Continued => (CLD_CONTINUED, Signal::SIGCONT as i32),
+        }
+    }
+}
+
+/// rewrites `ChildStatus` of the given child, returned to the (traced)
+/// parent by `wait4`/`waitid`.
+pub type WaitFilterFn =
+    Box<dyn FnMut(&dyn Task, Pid, ChildStatus) -> ChildStatus>;
+
+#[test]
+fn child_status_sanity_check() {
+    let statuses = [
+        ChildStatus::Exited(0),
+        ChildStatus::Exited(42),
+        ChildStatus::Signaled(Signal::SIGKILL, false),
+        ChildStatus::Signaled(Signal::SIGSEGV, true),
+        ChildStatus::Stopped(Signal::SIGTSTP),
+        ChildStatus::Continued,
+    ];
+    for status in statuses.iter().cloned() {
+        let wstatus = status.to_wstatus();
+        assert_eq!(ChildStatus::from_wstatus(wstatus), Some(status));
+        let (code, si_status) = status.to_siginfo();
+        assert_eq!(ChildStatus::from_siginfo(code, si_status), Some(status));
+    }
+    assert_eq!(ChildStatus::Exited(3).to_wstatus(), 0x0300);
+    assert_eq!(ChildStatus::Stopped(Signal::SIGSTOP).to_wstatus(), 0x137f);
+    assert_eq!(ChildStatus::from_wstatus(0x0022), None);
+    assert_eq!(ChildStatus::from_siginfo(0, 0), None);
+}
@@ -0,0 +1,166 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! worker pool for blocking tracer work
+//!
+//! tracees are resumed by a single scheduler loop, work which may block
+//! (disk writes, symbolication, namespace helpers) is submitted to a
+//! `WorkerPool` instead, and run by its threads. jobs are queued in a
+//! bounded queue: `submit` never blocks, a job is handed back when the
+//! queue is full, for the caller to run it inline, later, or not at all.
+//! completions are polled, i.e.: by the scheduler loop between tasks.
+
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender};
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::isolation::catch_panic;
+
+/// work to do by a worker
+pub type Job<T> = Box<dyn FnOnce() -> T + Send>;
+
+/// a job done
+#[derive(Debug)]
+pub struct Completion<T> {
+    /// id of the job, as returned by `WorkerPool::submit`
+    pub job: usize,
+    pub name: String,
+    /// what the job returned, `None` if it panicked
+    pub result: Option<T>,
+}
+
+type Queued<T> = (usize, String, Job<T>);
+
+/// worker threads, with a bounded queue of jobs
+pub struct WorkerPool<T> {
+    jobs: Option<SyncSender<Queued<T>>>,
+    completions: Receiver<Completion<T>>,
+    workers: Vec<JoinHandle<()>>,
+    next: usize,
+    /// jobs submitted, not completed yet
+    pending: usize,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    /// pool of `workers` threads, queueing `capacity` jobs at most
+    pub fn new(workers: usize, capacity: usize) -> Self {
+        let (jobs, queue) = sync_channel::<Queued<T>>(capacity);
+        let (done, completions) = channel();
+        let queue = Arc::new(Mutex::new(queue));
+        let workers = (0..workers.max(1))
+            .map(|k| {
+                let (queue, done) = (queue.clone(), done.clone());
+                thread::Builder::new()
+                    .name(format!("reverie-worker-{}", k))
+                    .spawn(move || work(&queue, &done))
+                    .expect("spawn worker")
+            })
+            .collect();
+        WorkerPool {
+            jobs: Some(jobs),
+            completions,
+            workers,
+            next: 0,
+            pending: 0,
+        }
+    }
+
+    /// queue `job`, returns its id, or `job` back if the queue is full
+    pub fn submit(&mut self, name: &str, job: Job<T>) -> Result<usize, Job<T>> {
+        let id = self.next;
+        let jobs = self.jobs.as_ref().expect("worker pool finished");
+        match jobs.try_send((id, name.to_string(), job)) {
+            Ok(()) => {
+                self.next += 1;
+                self.pending += 1;
+                Ok(id)
+            }
+            Err(TrySendError::Full((_, _, job)))
+            | Err(TrySendError::Disconnected((_, _, job))) => Err(job),
+        }
+    }
+
+    /// jobs completed since last polled, without blocking
+    pub fn completed(&mut self) -> Vec<Completion<T>> {
+        let completed: Vec<_> = self.completions.try_iter().collect();
+        self.pending -= completed.len();
+        completed
+    }
+
+    /// jobs submitted, not completed yet
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// wait for all jobs submitted to complete, returns their completions
+    pub fn wait(&mut self) -> Vec<Completion<T>> {
+        let mut completed = Vec::new();
+        while self.pending > 0 {
+            match self.completions.recv() {
+                Ok(completion) => completed.push(completion),
+                Err(_) => break,
+            }
+            self.pending -= 1;
+        }
+        completed
+    }
+}
+
+impl<T> Drop for WorkerPool<T> {
+    fn drop(&mut self) {
+        // workers exit once the queue is drained
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn work<T>(queue: &Mutex<Receiver<Queued<T>>>, done: &Sender<Completion<T>>) {
+    loop {
+        let next = queue.lock().unwrap_or_else(|e| e.into_inner()).recv();
+        let (job, name, f) = match next {
+            Ok(queued) => queued,
+            Err(_) => return,
+        };
+        let result = catch_panic(f).ok();
+        let _ = done.send(Completion { job, name, result });
+    }
+}
+
+#[test]
+fn worker_pool_sanity_check() {
+    let mut pool: WorkerPool<usize> = WorkerPool::new(1, 1);
+    // the only worker is busy until released
+    let (started, busy) = sync_channel(1);
+    let (release, released) = sync_channel(0);
+    let first = Box::new(move || {
+        started.send(()).unwrap();
+        released.recv().map_or(0, |_| 1)
+    });
+    assert_eq!(pool.submit("first", first).ok(), Some(0));
+    busy.recv().unwrap();
+    let second = Box::new(|| panic!("second"));
+    assert_eq!(pool.submit("second", second).ok(), Some(1));
+    assert!(pool.submit("third", Box::new(|| 3)).is_err());
+    assert_eq!(pool.pending(), 2);
+    release.send(()).unwrap();
+    let completed = pool.wait();
+    assert_eq!(completed.len(), 2);
+    assert_eq!(completed[0].result, Some(1));
+    assert_eq!(completed[1].name, "second");
+    assert!(completed[1].result.is_none());
+    assert_eq!(pool.pending(), 0);
+    assert!(pool.completed().is_empty());
+}
@@ -0,0 +1,59 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! seccomp bypass file, shared by tracer and preloader
+//!
+//! the bypass file has one syscall site per line: `<exe> <ip>`, ip is the
+//! (hex) address right after the `syscall` instruction, as seen by seccomp.
+//!
+//! NB: ips are absolute, they stay valid across runs only because
+//! `run_tracee` disables ASLR (`ADDR_NO_RANDOMIZE`) for the tracees, and
+//! their children inherit it. sites trapped in processes with ASLR, i.e.:
+//! attached by `reverie attach`, are unlikely to match on later runs.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// parse a single line of the bypass file
+pub fn parse_bypass_entry(line: &str) -> Option<(PathBuf, u64)> {
+    let (exe, ip) = line.trim().rsplit_once(' ')?;
+    let ip = u64::from_str_radix(ip.trim_start_matches("0x"), 16).ok()?;
+    Some((PathBuf::from(exe), ip))
+}
+
+/// format a single line of the bypass file
+pub fn format_bypass_entry(exe: &Path, ip: u64) -> String {
+    format!("{} {:x}", exe.display(), ip)
+}
+
+/// read all entries from bypass file `path`
+pub fn read_bypass_file<P: AsRef<Path>>(path: P) -> Vec<(PathBuf, u64)> {
+    File::open(path)
+        .map(|f| {
+            BufReader::new(f)
+                .lines()
+                .filter_map(|line| parse_bypass_entry(&line.ok()?))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[test]
+fn bypass_entry_sanity_check() {
+    let exe = PathBuf::from("/bin/my prog");
+    let line = format_bypass_entry(&exe, 0x7fff_f7a3_b1e2);
+    assert_eq!(parse_bypass_entry(&line), Some((exe, 0x7fff_f7a3_b1e2)));
+    assert_eq!(parse_bypass_entry("7ffff7a3b1e2"), None);
+    assert_eq!(parse_bypass_entry("/bin/ls xyz"), None);
+}
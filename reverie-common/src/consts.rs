@@ -11,9 +11,25 @@
  */
 
 pub const REVERIE_TRACEE_PRELOAD: &str = "REVERIE_TRACEE_PRELOAD";
+pub const REVERIE_PRELOADER: &str = "REVERIE_PRELOADER";
 
 pub const REVERIE_ENV_TOOL_LOG_KEY: &str = "TOOL_LOG";
 
+pub const REVERIE_SAMPLING: &str = "REVERIE_SAMPLING";
+
+pub const REVERIE_TIERS: &str = "REVERIE_TIERS";
+
+/// syscall numbers, separated by `,`, the preloader's filter fails with
+/// `ENOSYS`
+pub const REVERIE_ENOSYS: &str = "REVERIE_ENOSYS";
+
+pub const REVERIE_ADAPTIVE_BYPASS_FILE: &str = "REVERIE_ADAPTIVE_BYPASS_FILE";
+pub const REVERIE_ADAPTIVE_BYPASS_THRESHOLD: &str =
+    "REVERIE_ADAPTIVE_BYPASS_THRESHOLD";
+pub const REVERIE_HOT_SITE_POLICY: &str = "REVERIE_HOT_SITE_POLICY";
+
+pub const REVERIE_VERIFY_PATCHES: &str = "REVERIE_VERIFY_PATCHES";
+
 pub const SYSCALL_INSN_SIZE: usize = 2;
 pub const SYSCALL_INSN_MASK: u64 = 0xffff;
 pub const SYSCALL_INSN: u64 = 0x050f;
@@ -0,0 +1,151 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! guest dispatch table, in the state page of each process
+//!
+//! each process has a page of the global state memfd (at
+//! `REVERIE_GLOBAL_STATE_FD`), shared by the tracer and the tool preloaded.
+//! from `DISPATCH_TABLE_OFFSET`, the page has one byte per syscall, read by
+//! the guest for each syscall it captures, which the tracer may write at
+//! any time: instrumentation of a syscall is enabled, disabled or moved to
+//! another tier without patching nor installing seccomp filters.
+//!
+//! a zero byte dispatches by the tier table of exec, see `REVERIE_TIERS`,
+//! otherwise see `encode`. syscalls of the `count` tier at exec are never
+//! stopped, hence never reach the guest whatever their dispatch.
+
+use std::io::Result;
+
+use nix::sys::uio::{pread, pwrite};
+
+use crate::consts;
+use crate::sampling::SAMPLING_MAX_SYSCALLS;
+use crate::tiers::HandlingTier;
+
+/// offset of the dispatch table in the state page of a process
+pub const DISPATCH_TABLE_OFFSET: usize = 0x800;
+
+/// syscalls of the dispatch table, others dispatch by their tier
+pub const DISPATCH_TABLE_SIZE: usize = SAMPLING_MAX_SYSCALLS;
+
+/// set in a dispatch byte with a tier, in the low bits
+pub const DISPATCH_TIER: u8 = 0x40;
+
+/// set in a dispatch byte when instrumentation is disabled
+pub const DISPATCH_DISABLED: u8 = 0x80;
+
+/// dispatch of a syscall, overriding its tier of exec
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dispatch {
+    /// handled at the tier given
+    Tier(HandlingTier),
+    /// run as is by the guest, neither counted nor hooked
+    Disabled,
+}
+
+/// dispatch byte of `dispatch`, `None` dispatches by the tier of exec
+pub fn encode(dispatch: Option<Dispatch>) -> u8 {
+    match dispatch {
+        None => 0,
+        Some(Dispatch::Tier(tier)) => DISPATCH_TIER | tier as u8,
+        Some(Dispatch::Disabled) => DISPATCH_DISABLED,
+    }
+}
+
+/// dispatch of dispatch byte `byte`
+pub fn decode(byte: u8) -> Option<Dispatch> {
+    if byte & DISPATCH_DISABLED != 0 {
+        return Some(Dispatch::Disabled);
+    }
+    if byte & DISPATCH_TIER == 0 {
+        return None;
+    }
+    match byte & !DISPATCH_TIER {
+        0 => Some(Dispatch::Tier(HandlingTier::Count)),
+        1 => Some(Dispatch::Tier(HandlingTier::Guest)),
+        2 => Some(Dispatch::Tier(HandlingTier::Tracer)),
+        _ => None,
+    }
+}
+
+/// offset of the state page of process `pid` in the global state memfd
+pub fn process_page_offset(pid: i32) -> i64 {
+    4096 * (i64::from(pid) - 1)
+}
+
+fn table_offset(pid: i32) -> i64 {
+    process_page_offset(pid) + DISPATCH_TABLE_OFFSET as i64
+}
+
+/// dispatch of syscall `no` of process `pid`, as of the tracer
+pub fn dispatch_of(pid: i32, no: i32) -> Option<Dispatch> {
+    if no < 0 || no as usize >= DISPATCH_TABLE_SIZE {
+        return None;
+    }
+    let mut byte = [0u8; 1];
+    let offset = table_offset(pid) + i64::from(no);
+    pread(consts::REVERIE_GLOBAL_STATE_FD, &mut byte, offset).ok()?;
+    decode(byte[0])
+}
+
+/// set dispatch of syscall `no` of process `pid`, from the tracer
+pub fn set_dispatch(
+    pid: i32,
+    no: i32,
+    dispatch: Option<Dispatch>,
+) -> Result<()> {
+    if no < 0 || no as usize >= DISPATCH_TABLE_SIZE {
+        return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    let offset = table_offset(pid) + i64::from(no);
+    pwrite(consts::REVERIE_GLOBAL_STATE_FD, &[encode(dispatch)], offset)
+        .map(|_| ())
+        .map_err(|err| {
+            std::io::Error::from_raw_os_error(
+                err.as_errno().map_or(libc::EIO, |errno| errno as i32),
+            )
+        })
+}
+
+/// dispatch by the tier table of exec, for all syscalls of process `pid`
+pub fn reset(pid: i32) {
+    let table = [0u8; DISPATCH_TABLE_SIZE];
+    let _ = pwrite(consts::REVERIE_GLOBAL_STATE_FD, &table, table_offset(pid));
+}
+
+/// process `child` forked by `parent` inherits its dispatch table
+pub fn forked(parent: i32, child: i32) {
+    let mut table = [0u8; DISPATCH_TABLE_SIZE];
+    let fd = consts::REVERIE_GLOBAL_STATE_FD;
+    if pread(fd, &mut table, table_offset(parent)).is_err() {
+        table = [0u8; DISPATCH_TABLE_SIZE];
+    }
+    let _ = pwrite(fd, &table, table_offset(child));
+}
+
+#[test]
+fn dispatch_sanity_check() {
+    let dispatches = [
+        None,
+        Some(Dispatch::Disabled),
+        Some(Dispatch::Tier(HandlingTier::Count)),
+        Some(Dispatch::Tier(HandlingTier::Guest)),
+        Some(Dispatch::Tier(HandlingTier::Tracer)),
+    ];
+    for dispatch in dispatches.iter() {
+        assert_eq!(decode(encode(*dispatch)), *dispatch);
+    }
+    assert_eq!(decode(DISPATCH_DISABLED | DISPATCH_TIER | 2), dispatches[1]);
+    assert_eq!(decode(DISPATCH_TIER | 3), None);
+    assert_eq!(process_page_offset(2), 4096);
+}
@@ -17,7 +17,11 @@
 #[macro_use]
 extern crate lazy_static;
 
+pub mod bypass;
 pub mod consts;
+pub mod dispatch;
 pub mod local_state;
 pub mod profiling;
+pub mod sampling;
 pub mod state;
+pub mod tiers;
@@ -27,6 +27,7 @@ use std::cell::{RefCell, UnsafeCell};
 use std::os::unix::io::RawFd;
 use std::ptr::{self, NonNull};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::{Arc, Mutex};
 
 #[allow(unused_imports)]
@@ -36,7 +37,10 @@ use nix::sys::mman;
 use nix::unistd::Pid;
 
 use crate::consts;
+use crate::dispatch::*;
 use crate::profiling::*;
+use crate::sampling::*;
+use crate::tiers::TierTable;
 
 /// resources belongs to threads
 #[repr(C)]
@@ -102,6 +106,10 @@ pub struct ProcessState {
     pub sockfd_write: Option<RawFd>,
 
     pub stats: SyscallStats,
+    /// shared by threads of the same process
+    pub sampler: Arc<SyscallSampler>,
+    /// guest dispatch table, see `REVERIE_TIERS`
+    pub tiers: Arc<TierTable>,
 
     pub fd_status: Arc<Mutex<HashMap<RawFd, DescriptorType>>>,
     pub thread_states: Rc<RefCell<HashMap<Pid, ThreadState>>>,
@@ -133,6 +141,8 @@ impl Default for ProcessState {
             sockfd_read: None,
             sockfd_write: None,
             stats: SyscallStats::new(),
+            sampler: Arc::new(SyscallSampler::from_env()),
+            tiers: Arc::new(TierTable::from_env()),
             fd_status: Arc::new(Mutex::new(HashMap::new())),
             thread_states: Rc::new(RefCell::new(HashMap::new())),
         }
@@ -143,6 +153,18 @@ impl ProcessState {
     pub fn new() -> Self {
         Default::default()
     }
+    /// dispatch of syscall `no` set by the tracer, if any, see `dispatch`
+    pub fn dispatch(&self, no: i32) -> Option<Dispatch> {
+        if no < 0 || no as usize >= DISPATCH_TABLE_SIZE {
+            return None;
+        }
+        let table = self.pstate_store.as_ptr() as *const AtomicU8;
+        let byte = unsafe {
+            (*table.add(DISPATCH_TABLE_OFFSET + no as usize))
+                .load(Ordering::Relaxed)
+        };
+        decode(byte)
+    }
     pub fn forked(&self) -> Self {
         ProcessState {
             nr_syscalls: self.nr_syscalls,
@@ -156,6 +178,8 @@ impl ProcessState {
                 Arc::new(Mutex::new(fd_status_copied))
             },
             stats: SyscallStats::new(),
+            sampler: Arc::new(SyscallSampler::from_env()),
+            tiers: self.tiers.clone(),
             thread_states: { Rc::new(RefCell::new(HashMap::new())) },
         }
     }
@@ -167,6 +191,8 @@ impl ProcessState {
             sockfd_read: self.sockfd_read,
             sockfd_write: self.sockfd_write,
             stats: self.stats.clone(),
+            sampler: self.sampler.clone(),
+            tiers: self.tiers.clone(),
             fd_status: self.fd_status.clone(),
             thread_states: self.thread_states.clone(),
         }
@@ -57,6 +57,16 @@ pub struct SyscallStats {
     pub nr_exited: AtomicUsize,
     /// number of processes spawned via `execve*` syscall
     pub nr_process_spawns: AtomicUsize,
+    /// number of ptraced syscalls with duration attribution
+    pub nr_syscalls_timed: AtomicUsize,
+    /// nanoseconds ptraced syscalls spent stopped in the tracer
+    pub syscall_tracer_nanos: AtomicUsize,
+    /// nanoseconds ptraced syscalls spent running in the kernel
+    pub syscall_kernel_nanos: AtomicUsize,
+    /// number of seccomp stops not from reverie's filter
+    pub nr_syscalls_unfiltered: AtomicUsize,
+    /// number of syscalls emulated by the tool
+    pub nr_syscalls_emulated: AtomicUsize,
 }
 
 impl SyscallStats {
@@ -0,0 +1,133 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! 1-in-N syscall sampling
+//!
+//! every syscall is counted exactly, but only one in every N calls of the
+//! same syscall number is sampled (recorded). the rates are given by env
+//! var `REVERIE_SAMPLING`: `<N>[,<syscall nr>:<N>]*`, the first `N` is the
+//! default rate, `1` samples everything.
+//!
+//! NB: sampling is done in the tool (guest fast path) and in the tracer,
+//! seccomp bpf filters have no state, hence cannot count.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::consts;
+
+/// syscalls numbered beyond this are sampled at the default rate, and
+/// counted together.
+pub const SAMPLING_MAX_SYSCALLS: usize = 512;
+
+#[derive(Debug)]
+pub struct SyscallSampler {
+    rates: Vec<usize>,
+    counts: Vec<AtomicUsize>,
+    sampled: AtomicUsize,
+}
+
+impl Default for SyscallSampler {
+    fn default() -> Self {
+        SyscallSampler::with_rate(1)
+    }
+}
+
+impl SyscallSampler {
+    /// sample one in every `rate` calls of each syscall
+    pub fn with_rate(rate: usize) -> Self {
+        SyscallSampler {
+            rates: vec![rate.max(1); SAMPLING_MAX_SYSCALLS + 1],
+            counts: (0..=SAMPLING_MAX_SYSCALLS)
+                .map(|_| AtomicUsize::new(0))
+                .collect(),
+            sampled: AtomicUsize::new(0),
+        }
+    }
+    /// parse from `<N>[,<syscall nr>:<N>]*`
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut iter = spec.split(',');
+        let rate = iter.next()?.trim().parse().ok()?;
+        let mut sampler = SyscallSampler::with_rate(rate);
+        for s in iter {
+            let mut kv = s.splitn(2, ':');
+            let no: usize = kv.next()?.trim().parse().ok()?;
+            let rate: usize = kv.next()?.trim().parse().ok()?;
+            if no >= SAMPLING_MAX_SYSCALLS {
+                return None;
+            }
+            sampler.rates[no] = rate.max(1);
+        }
+        Some(sampler)
+    }
+    /// sampler configured by env var `REVERIE_SAMPLING`, sample
+    /// everything if not set.
+    pub fn from_env() -> Self {
+        std::env::var(consts::REVERIE_SAMPLING)
+            .ok()
+            .and_then(|spec| SyscallSampler::parse(&spec))
+            .unwrap_or_default()
+    }
+    fn index(no: i32) -> usize {
+        if no < 0 {
+            SAMPLING_MAX_SYSCALLS
+        } else {
+            (no as usize).min(SAMPLING_MAX_SYSCALLS)
+        }
+    }
+    /// count syscall `no`, returns true if this call is sampled
+    pub fn sample(&self, no: i32) -> bool {
+        let i = SyscallSampler::index(no);
+        let n = self.counts[i].fetch_add(1, Ordering::Relaxed);
+        let sampled = n.is_multiple_of(self.rates[i]);
+        if sampled {
+            self.sampled.fetch_add(1, Ordering::Relaxed);
+        }
+        sampled
+    }
+    /// exact number of calls of syscall `no`
+    pub fn count(&self, no: i32) -> usize {
+        self.counts[SyscallSampler::index(no)].load(Ordering::Relaxed)
+    }
+    /// exact number of calls of all syscalls
+    pub fn total(&self) -> usize {
+        self.counts.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+    }
+    /// number of calls sampled
+    pub fn sampled(&self) -> usize {
+        self.sampled.load(Ordering::Relaxed)
+    }
+    /// syscalls called at least once, with their exact counts
+    pub fn counts(&self) -> Vec<(i32, usize)> {
+        self.counts
+            .iter()
+            .take(SAMPLING_MAX_SYSCALLS)
+            .enumerate()
+            .map(|(no, c)| (no as i32, c.load(Ordering::Relaxed)))
+            .filter(|(_, c)| *c != 0)
+            .collect()
+    }
+}
+
+#[test]
+fn sampler_sanity_check() {
+    let sampler = SyscallSampler::parse("4,1:1").unwrap();
+    let sampled = (0..8).filter(|_| sampler.sample(0)).count();
+    assert_eq!(sampled, 2);
+    let sampled = (0..8).filter(|_| sampler.sample(1)).count();
+    assert_eq!(sampled, 8);
+    assert_eq!(sampler.count(0), 8);
+    assert_eq!(sampler.total(), 16);
+    assert_eq!(sampler.sampled(), 10);
+    assert!(SyscallSampler::parse("4,1").is_none());
+    assert!(SyscallSampler::parse("x").is_none());
+}
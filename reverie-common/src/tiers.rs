@@ -0,0 +1,165 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! per syscall handling tiers, shared by tracer, preloader and tools
+//!
+//! each syscall is handled at one tier, cheapest first:
+//!
+//! - `count`: allowed by the seccomp filter, never stopped nor patched,
+//!   counted in-kernel (`--bpf-counters`), or by the guest if it reaches a
+//!   site patched for another syscall.
+//! - `guest`: patched on first stop, then handled by the tool's
+//!   `captured_syscall`, the default.
+//! - `tracer`: never patched, every call stops in the tracer, calls
+//!   reaching the guest are re-issued as traced syscalls.
+//!
+//! the table is given by env var `REVERIE_TIERS`: `<tier>[,<nr>:<tier>]*`,
+//! the first tier is the default one. it is compiled into the seccomp
+//! filter by the preloader, and into the guest dispatch table, on exec.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::consts;
+use crate::sampling::SAMPLING_MAX_SYSCALLS;
+
+/// how a syscall is handled, see module doc
+#[repr(u8)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HandlingTier {
+    Count,
+    #[default]
+    Guest,
+    Tracer,
+}
+
+impl FromStr for HandlingTier {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "count" => Ok(HandlingTier::Count),
+            "guest" => Ok(HandlingTier::Guest),
+            "tracer" => Ok(HandlingTier::Tracer),
+            _ => Err(format!("unknown handling tier: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for HandlingTier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandlingTier::Count => write!(f, "count"),
+            HandlingTier::Guest => write!(f, "guest"),
+            HandlingTier::Tracer => write!(f, "tracer"),
+        }
+    }
+}
+
+/// handling tier of each syscall
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TierTable {
+    tiers: Vec<HandlingTier>,
+}
+
+impl Default for TierTable {
+    fn default() -> Self {
+        TierTable::with_tier(HandlingTier::default())
+    }
+}
+
+impl TierTable {
+    /// all syscalls handled at `tier`
+    pub fn with_tier(tier: HandlingTier) -> Self {
+        TierTable {
+            tiers: vec![tier; SAMPLING_MAX_SYSCALLS + 1],
+        }
+    }
+    /// parse from `<tier>[,<nr>:<tier>]*`
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut iter = spec.split(',');
+        let tier = iter.next()?.trim().parse().ok()?;
+        let mut table = TierTable::with_tier(tier);
+        for s in iter {
+            let mut kv = s.splitn(2, ':');
+            let no: usize = kv.next()?.trim().parse().ok()?;
+            let tier = kv.next()?.trim().parse().ok()?;
+            if no >= SAMPLING_MAX_SYSCALLS {
+                return None;
+            }
+            table.tiers[no] = tier;
+        }
+        Some(table)
+    }
+    /// table configured by env var `REVERIE_TIERS`, everything at the
+    /// default tier if not set.
+    pub fn from_env() -> Self {
+        std::env::var(consts::REVERIE_TIERS)
+            .ok()
+            .and_then(|spec| TierTable::parse(&spec))
+            .unwrap_or_default()
+    }
+    fn index(no: i32) -> usize {
+        if no < 0 {
+            SAMPLING_MAX_SYSCALLS
+        } else {
+            (no as usize).min(SAMPLING_MAX_SYSCALLS)
+        }
+    }
+    /// tier of syscall `no`
+    pub fn tier(&self, no: i32) -> HandlingTier {
+        self.tiers[TierTable::index(no)]
+    }
+    /// handle syscall `no` at `tier`
+    pub fn set(&mut self, no: i32, tier: HandlingTier) {
+        self.tiers[TierTable::index(no)] = tier;
+    }
+    /// syscalls handled at `tier`, syscalls numbered beyond the table are
+    /// not listed
+    pub fn syscalls(&self, tier: HandlingTier) -> Vec<i32> {
+        self.tiers
+            .iter()
+            .take(SAMPLING_MAX_SYSCALLS)
+            .enumerate()
+            .filter(|(_, t)| **t == tier)
+            .map(|(no, _)| no as i32)
+            .collect()
+    }
+}
+
+impl fmt::Display for TierTable {
+    /// as parsed by `TierTable::parse`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let default = self.tiers[SAMPLING_MAX_SYSCALLS];
+        write!(f, "{}", default)?;
+        for (no, tier) in self.tiers.iter().enumerate() {
+            if no < SAMPLING_MAX_SYSCALLS && *tier != default {
+                write!(f, ",{}:{}", no, tier)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn tier_table_sanity_check() {
+    let table = TierTable::parse("count,0:guest,2:tracer").unwrap();
+    assert_eq!(table.tier(0), HandlingTier::Guest);
+    assert_eq!(table.tier(1), HandlingTier::Count);
+    assert_eq!(table.tier(2), HandlingTier::Tracer);
+    assert_eq!(table.tier(1000), HandlingTier::Count);
+    assert_eq!(table.syscalls(HandlingTier::Tracer), vec![2]);
+    assert_eq!(TierTable::parse(&table.to_string()), Some(table));
+    assert_eq!(TierTable::default().tier(0), HandlingTier::Guest);
+    assert!(TierTable::parse("guest,1").is_none());
+    assert!(TierTable::parse("guest,1:patched").is_none());
+}
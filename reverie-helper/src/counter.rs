@@ -19,6 +19,8 @@ use reverie_common::local_state::*;
 /// syscall events
 pub enum NoteInfo {
     SyscallEntry,
+    /// syscall of the `count` tier, not routed to `captured_syscall`
+    SyscallCounted,
 }
 
 /// note a syscall event
@@ -31,5 +33,17 @@ pub fn note_syscall(p: &mut ProcessState, no: i32, note: NoteInfo) {
             p.stats.nr_syscalls_captured.fetch_add(1, Ordering::SeqCst);
             unsafe { core::ptr::write(p.pstate_store.as_mut(), p.nr_syscalls) };
         }
+        NoteInfo::SyscallCounted => {
+            p.nr_syscalls += 1;
+            p.stats.nr_syscalls.fetch_add(1, Ordering::SeqCst);
+            p.sampler.sample(no);
+            unsafe { core::ptr::write(p.pstate_store.as_mut(), p.nr_syscalls) };
+        }
     }
 }
+
+/// count syscall `no`, returns true if it is sampled hence should be
+/// recorded, see `REVERIE_SAMPLING`.
+pub fn sample_syscall(p: &ProcessState, no: i32) -> bool {
+    p.sampler.sample(no)
+}
@@ -20,7 +20,11 @@
 /// cdylib is built correctly.
 use core::ffi::c_void;
 use reverie_common::consts;
+use reverie_common::dispatch::Dispatch;
 use reverie_common::local_state::*;
+use reverie_common::tiers::HandlingTier;
+
+use crate::counter::{note_syscall, NoteInfo};
 
 use syscalls::*;
 
@@ -52,6 +56,13 @@ extern "C" {
     fn _syscall_hook_trampoline_89_d0_87_07();
     fn _syscall_hook_trampoline_c3_nop();
     fn _syscall_hook_trampoline_85_c0_0f_94_c2();
+    fn _syscall_hook_trampoline_48_89_c7();
+    fn _syscall_hook_trampoline_go_48_3d_01_f0_ff_ff();
+    fn _syscall_hook_trampoline_go_89_44_24_20();
+    fn _syscall_hook_trampoline_go_89_44_24_30();
+    fn _syscall_hook_trampoline_go_89_44_24_08();
+    fn _syscall_hook_trampoline_go_c3_cc_cc();
+    fn _syscall_hook_trampoline_go_90_90_90();
     fn _remote_syscall_helper();
     fn _remote_funccall_helper();
     fn captured_syscall(
@@ -125,6 +136,41 @@ unsafe extern "C" fn syscall_hook_trampoline_85_c0_0f_94_c2() {
     _syscall_hook_trampoline_85_c0_0f_94_c2()
 }
 
+#[no_mangle]
+unsafe extern "C" fn syscall_hook_trampoline_48_89_c7() {
+    _syscall_hook_trampoline_48_89_c7()
+}
+
+#[no_mangle]
+unsafe extern "C" fn syscall_hook_trampoline_go_48_3d_01_f0_ff_ff() {
+    _syscall_hook_trampoline_go_48_3d_01_f0_ff_ff()
+}
+
+#[no_mangle]
+unsafe extern "C" fn syscall_hook_trampoline_go_89_44_24_20() {
+    _syscall_hook_trampoline_go_89_44_24_20()
+}
+
+#[no_mangle]
+unsafe extern "C" fn syscall_hook_trampoline_go_89_44_24_30() {
+    _syscall_hook_trampoline_go_89_44_24_30()
+}
+
+#[no_mangle]
+unsafe extern "C" fn syscall_hook_trampoline_go_89_44_24_08() {
+    _syscall_hook_trampoline_go_89_44_24_08()
+}
+
+#[no_mangle]
+unsafe extern "C" fn syscall_hook_trampoline_go_c3_cc_cc() {
+    _syscall_hook_trampoline_go_c3_cc_cc()
+}
+
+#[no_mangle]
+unsafe extern "C" fn syscall_hook_trampoline_go_90_90_90() {
+    _syscall_hook_trampoline_go_90_90_90()
+}
+
 #[no_mangle]
 unsafe extern "C" fn traced_syscall(
     syscallno: i32,
@@ -191,16 +237,30 @@ unsafe extern "C" fn syscall_hook(info: *const syscall_info) -> i64 {
         let sc = info.as_ref().unwrap();
         let _no = SyscallNo::from(sc.no as i32);
         let _tid = syscall!(SYS_gettid).unwrap() as i32;
-        let res = captured_syscall(
-            &mut pstate,
-            sc.no as i32,
-            sc.args[0] as i64,
-            sc.args[1] as i64,
-            sc.args[2] as i64,
-            sc.args[3] as i64,
-            sc.args[4] as i64,
-            sc.args[5] as i64,
-        );
+        let no = sc.no as i32;
+        let [a0, a1, a2, a3, a4, a5] = sc.args;
+        let (a0, a1, a2) = (a0 as i64, a1 as i64, a2 as i64);
+        let (a3, a4, a5) = (a3 as i64, a4 as i64, a5 as i64);
+        // guest dispatch table, see `REVERIE_TIERS` and `dispatch`: sites
+        // patched are shared by syscalls of all tiers, i.e.: libc's
+        // `syscall`.
+        let tier = match pstate.dispatch(no) {
+            Some(Dispatch::Disabled) => {
+                return untraced_syscall(no, a0, a1, a2, a3, a4, a5);
+            }
+            Some(Dispatch::Tier(tier)) => tier,
+            None => pstate.tiers.tier(no),
+        };
+        let res = match tier {
+            HandlingTier::Guest => {
+                captured_syscall(&mut pstate, no, a0, a1, a2, a3, a4, a5)
+            }
+            HandlingTier::Count => {
+                note_syscall(&mut pstate, no, NoteInfo::SyscallCounted);
+                untraced_syscall(no, a0, a1, a2, a3, a4, a5)
+            }
+            HandlingTier::Tracer => traced_syscall(no, a0, a1, a2, a3, a4, a5),
+        };
         return res;
     }
     return -38; // ENOSYS
@@ -35,6 +35,7 @@
 
  * hence we fixup `__get_nprocs`, by forcing it return 2.
  */
+#ifdef __GLIBC__
 static void __libc_get_nprocs_fixup(void* addr, int nprocs) {
   unsigned long start = ((unsigned long) addr) & ~0xfffull;
   size_t len = 0x1000;
@@ -65,23 +66,33 @@ static void __libc_get_nprocs_fixup(void* addr, int nprocs) {
     fprintf(stderr, "mprotect failed: %s\n", strerror(errno));
   }
 }
+#endif
 
 void* _early_preload_dso(const char* dso) {
   void* handle = NULL;
 
+#ifdef __GLIBC__
   Lmid_t id = LM_ID_NEWLM;
 
   handle = dlmopen(id, dso, RTLD_NOW | RTLD_LOCAL | RTLD_NODELETE);
   assert(handle);
   assert(dlinfo(handle, RTLD_DI_LMID, &id) == 0);
 
-  /* XXX: assuming we're using glibc */
   void* nl1_get_nprocs = dlsym(handle, "get_nprocs");
   assert(nl1_get_nprocs);
 
   int nprocs = get_nprocs();
   __libc_get_nprocs_fixup(nl1_get_nprocs, nprocs);
   __libc_get_nprocs_fixup(get_nprocs, nprocs);
+#else
+  /*
+   * `dlmopen` is glibc only: the tool shares the default namespace,
+   * hence the libc, of the tracee. musl's malloc has no arenas, nothing
+   * to fixup.
+   */
+  handle = dlopen(dso, RTLD_NOW | RTLD_LOCAL | RTLD_NODELETE);
+  assert(handle);
+#endif
 
   return handle;
 }
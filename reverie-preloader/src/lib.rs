@@ -16,7 +16,9 @@ use std::io::Result;
 
 pub mod relink;
 
+use reverie_common::bypass;
 use reverie_common::consts;
+use reverie_common::tiers::{HandlingTier, TierTable};
 use reverie_seccomp::seccomp_bpf;
 
 use syscalls::*;
@@ -60,15 +62,16 @@ fn preload_dl_ns() -> Result<()> {
                               system calls */
                        BPF_STMT(BPF_RET | BPF_K, SECCOMP_RET_ALLOW),
 
-                       /* [3] Destination of architecture mismatch: kill task */
-                       BPF_STMT(BPF_RET | BPF_K, SECCOMP_RET_KILL),
+                       /* [3] Destination of architecture mismatch: allow,
+                              the process is passed through by the tracer */
+                       BPF_STMT(BPF_RET | BPF_K, SECCOMP_RET_ALLOW),
                };
         */
         let filter = vec![
             0x0004_0000_0020u64,
             0xc000_003e_0100_0015u64,
             0x7fff_0000_0000_0006u64,
-            0x6u64,
+            0x7fff_0000_0000_0006u64,
         ];
         let prog = sock_fprog {
             len: 4,
@@ -88,8 +91,30 @@ fn preload_dl_ns() -> Result<()> {
                 }
             });
         });
+        // syscall sites bypassed by the tracer's adaptive mode
+        if let (Ok(path), Ok(exe)) = (
+            std::env::var(consts::REVERIE_ADAPTIVE_BYPASS_FILE),
+            std::fs::read_link("/proc/self/exe"),
+        ) {
+            bypass::read_bypass_file(path)
+                .into_iter()
+                .filter(|(e, _)| e == &exe)
+                .for_each(|(_, ip)| whitelist.push((ip, ip)));
+        }
         // println!("whitelist: {:#x?}", whitelist);
         let bytes = seccomp_bpf::bpf_whitelist_ips(whitelist.as_mut());
+        // syscalls of the `count` tier are never stopped
+        let counted = TierTable::from_env().syscalls(HandlingTier::Count);
+        let bytes = seccomp_bpf::bpf_allow_syscalls(&counted, &bytes);
+        // syscalls failed as of older kernels, see `--enosys`
+        let enosys: Vec<i32> = std::env::var(consts::REVERIE_ENOSYS)
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|nr| nr.parse().ok())
+            .collect();
+        let bytes = seccomp_bpf::bpf_enosys_syscalls(&enosys, &bytes);
+        // i386 and x32 syscalls, i.e.: of wine, are never stopped
+        let bytes = seccomp_bpf::bpf_allow_foreign_abis(&bytes);
         let prog = sock_fprog {
             len: bytes.len() as u32,
             filter: bytes.as_ptr() as *const sock_filter,
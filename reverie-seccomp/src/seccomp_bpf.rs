@@ -90,6 +90,91 @@ pub fn bpf_blacklist_ips(ips: &[(u64, u64)]) -> Vec<u64> {
     v
 }
 
+// struct sock_filter: code, jt, jf, k
+fn bpf_insn(code: u16, jt: u8, jf: u8, k: u32) -> u64 {
+    u64::from(code)
+        | u64::from(jt) << 16
+        | u64::from(jf) << 24
+        | u64::from(k) << 32
+}
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JSET_K: u16 = 0x45;
+const BPF_RET_K: u16 = 0x06;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// `filter`, with syscalls `nrs` allowed from anywhere before it, see
+/// `HandlingTier::Count`
+pub fn bpf_allow_syscalls(nrs: &[i32], filter: &[u64]) -> Vec<u64> {
+    if nrs.is_empty() {
+        return filter.to_vec();
+    }
+    // load seccomp_data.nr
+    let mut res = vec![bpf_insn(BPF_LD_W_ABS, 0, 0, 0)];
+    for nr in nrs {
+        res.push(bpf_insn(BPF_JMP_JEQ_K, 0, 1, *nr as u32));
+        res.push(bpf_insn(BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW));
+    }
+    res.extend_from_slice(filter);
+    res
+}
+
+/// `filter`, with syscalls `nrs` failed with `ENOSYS` from anywhere before
+/// it, as of older kernels
+pub fn bpf_enosys_syscalls(nrs: &[i32], filter: &[u64]) -> Vec<u64> {
+    if nrs.is_empty() {
+        return filter.to_vec();
+    }
+    let enosys = SECCOMP_RET_ERRNO | libc::ENOSYS as u32;
+    // load seccomp_data.nr
+    let mut res = vec![bpf_insn(BPF_LD_W_ABS, 0, 0, 0)];
+    for nr in nrs {
+        res.push(bpf_insn(BPF_JMP_JEQ_K, 0, 1, *nr as u32));
+        res.push(bpf_insn(BPF_RET_K, 0, 0, enosys));
+    }
+    res.extend_from_slice(filter);
+    res
+}
+
+/// `filter`, with syscalls made at `ips` allowed before it, i.e.: the
+/// untraced syscall of the tracer, whichever the filter.
+pub fn bpf_allow_ips(ips: &[u64], filter: &[u64]) -> Vec<u64> {
+    let mut res = Vec::new();
+    for ip in ips {
+        res.extend_from_slice(&[
+            // load seccomp_data.instruction_pointer, low then high half
+            bpf_insn(BPF_LD_W_ABS, 0, 0, 8),
+            bpf_insn(BPF_JMP_JEQ_K, 0, 3, *ip as u32),
+            bpf_insn(BPF_LD_W_ABS, 0, 0, 12),
+            bpf_insn(BPF_JMP_JEQ_K, 0, 1, (*ip >> 32) as u32),
+            bpf_insn(BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW),
+        ]);
+    }
+    res.extend_from_slice(filter);
+    res
+}
+
+/// `filter`, with syscalls of other abis than x86_64 (i386, x32) allowed
+/// before it: processes making them are passed through by the tracer.
+pub fn bpf_allow_foreign_abis(filter: &[u64]) -> Vec<u64> {
+    let mut res = vec![
+        // load seccomp_data.arch
+        bpf_insn(BPF_LD_W_ABS, 0, 0, 4),
+        bpf_insn(BPF_JMP_JEQ_K, 1, 0, AUDIT_ARCH_X86_64),
+        bpf_insn(BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW),
+        // load seccomp_data.nr
+        bpf_insn(BPF_LD_W_ABS, 0, 0, 0),
+        bpf_insn(BPF_JMP_JSET_K, 0, 1, X32_SYSCALL_BIT),
+        bpf_insn(BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW),
+    ];
+    res.extend_from_slice(filter);
+    res
+}
+
 pub fn seccomp(bytecode: &[u64]) -> io::Result<()> {
     let prog = sock_fprog {
         len: bytecode.len() as u32,
@@ -104,3 +189,42 @@ pub fn seccomp(bytecode: &[u64]) -> io::Result<()> {
         Err(io::Error::last_os_error())
     }
 }
+
+#[test]
+fn bpf_allow_syscalls_sanity_check() {
+    let filter = bpf_allow_syscalls(&[SYS_getpid as i32], &[0x6]);
+    assert_eq!(
+        filter,
+        vec![0x20, 0x27_0100_0015, 0x7fff_0000_0000_0006, 0x6]
+    );
+    assert_eq!(bpf_allow_syscalls(&[], &[0x6]), vec![0x6]);
+}
+
+#[test]
+fn bpf_enosys_syscalls_sanity_check() {
+    let filter = bpf_enosys_syscalls(&[435], &[0x6]);
+    assert_eq!(filter, vec![0x20, 0x1b3_0100_0015, 0x5_0026_0000_0006, 0x6]);
+    assert_eq!(bpf_enosys_syscalls(&[], &[0x6]), vec![0x6]);
+}
+
+#[test]
+fn bpf_allow_foreign_abis_sanity_check() {
+    let filter = bpf_allow_foreign_abis(&[0x6]);
+    assert_eq!(filter.len(), 7);
+    assert_eq!(filter[0], 0x4_0000_0020);
+    assert_eq!(filter[1], 0xc000_003e_0001_0015);
+    assert_eq!(filter[4], 0x4000_0000_0100_0045);
+    assert_eq!(filter[6], 0x6);
+}
+
+#[test]
+fn bpf_allow_ips_sanity_check() {
+    let filter = bpf_allow_ips(&[0x7000_0002], &[0x6]);
+    assert_eq!(filter.len(), 6);
+    assert_eq!(filter[0], 0x8_0000_0020);
+    assert_eq!(filter[1], 0x7000_0002_0300_0015);
+    assert_eq!(filter[2], 0xc_0000_0020);
+    assert_eq!(filter[3], 0x0100_0015);
+    assert_eq!(filter[5], 0x6);
+    assert_eq!(bpf_allow_ips(&[], &[0x6]), vec![0x6]);
+}
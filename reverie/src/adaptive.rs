@@ -0,0 +1,228 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! adaptive mitigation of hot unpatchable syscall sites
+//!
+//! a syscall site which cannot be patched traps into the tracer every time
+//! it is executed. once such a site has trapped `threshold` times it is
+//! mitigated according to `HotSitePolicy`: a warning with its symbolized
+//! location, or a seccomp bypass: the site is appended to the bypass file,
+//! the preloader then adds the site to the seccomp whitelist when the
+//! filter is installed, i.e. on next `exec`.
+//!
+//! NB: bypassed syscalls are no longer visible to the tool.
+//!
+//! NB: switching a single task to `PTRACE_SYSEMU` is not offered, see
+//! `EmulationMode::Sysemu` to emulate syscalls of all tasks.
+
+use log::{info, warn};
+use nix::unistd::Pid;
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use reverie_common::bypass::*;
+use reverie_common::consts;
+
+use crate::debug;
+
+/// default number of traps before a syscall site is mitigated
+pub const ADAPTIVE_BYPASS_THRESHOLD: usize = 64;
+
+/// mitigation of hot unpatchable syscall sites
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotSitePolicy {
+    /// warn, with the symbolized location of the site
+    Warn,
+    /// bypass seccomp for the site, on next `exec`
+    Bypass,
+}
+
+impl Default for HotSitePolicy {
+    fn default() -> Self {
+        HotSitePolicy::Bypass
+    }
+}
+
+impl FromStr for HotSitePolicy {
+    type Err = Error;
+    /// parse from `warn` or `bypass`
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "warn" => Ok(HotSitePolicy::Warn),
+            "bypass" => Ok(HotSitePolicy::Bypass),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown hot site policy: {}", s),
+            )),
+        }
+    }
+}
+
+/// trap counters of unpatchable syscall sites
+pub struct AdaptiveBypass {
+    threshold: usize,
+    policy: HotSitePolicy,
+    path: PathBuf,
+    /// number of traps, and time of the first one
+    traps: HashMap<(PathBuf, u64), (usize, Instant)>,
+    bypassed: HashSet<(PathBuf, u64)>,
+}
+
+impl AdaptiveBypass {
+    pub fn new(path: PathBuf, threshold: usize, policy: HotSitePolicy) -> Self {
+        let bypassed = match policy {
+            HotSitePolicy::Bypass => {
+                read_bypass_file(&path).into_iter().collect()
+            }
+            HotSitePolicy::Warn => HashSet::new(),
+        };
+        AdaptiveBypass {
+            threshold,
+            policy,
+            path,
+            traps: HashMap::new(),
+            bypassed,
+        }
+    }
+    /// record a trap of syscall site `ip` in `exe`, returns time taken
+    /// to reach `threshold` traps if the site is newly mitigated.
+    pub fn record_trap(
+        &mut self,
+        exe: PathBuf,
+        ip: u64,
+    ) -> Result<Option<Duration>> {
+        let key = (exe, ip);
+        if self.bypassed.contains(&key) {
+            return Ok(None);
+        }
+        let (count, since) =
+            self.traps.entry(key.clone()).or_insert((0, Instant::now()));
+        *count += 1;
+        if *count < self.threshold {
+            return Ok(None);
+        }
+        let elapsed = since.elapsed();
+        if self.policy == HotSitePolicy::Bypass {
+            let mut f = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            writeln!(f, "{}", format_bypass_entry(&key.0, key.1))?;
+        }
+        self.traps.remove(&key);
+        self.bypassed.insert(key);
+        Ok(Some(elapsed))
+    }
+}
+
+// bypass file, known to the preloader
+fn bypass_file() -> PathBuf {
+    std::env::var(consts::REVERIE_ADAPTIVE_BYPASS_FILE)
+        .unwrap_or_default()
+        .into()
+}
+
+lazy_static! {
+    static ref ADAPTIVE_BYPASS: Mutex<Option<AdaptiveBypass>> = {
+        let threshold =
+            match std::env::var(consts::REVERIE_ADAPTIVE_BYPASS_THRESHOLD) {
+                Ok(threshold) => {
+                    threshold.parse().unwrap_or(ADAPTIVE_BYPASS_THRESHOLD)
+                }
+                Err(_) => return Mutex::new(None),
+            };
+        let policy = std::env::var(consts::REVERIE_HOT_SITE_POLICY)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or_default();
+        let bypass = AdaptiveBypass::new(bypass_file(), threshold, policy);
+        Mutex::new(Some(bypass))
+    };
+}
+
+/// bypass hot unpatchable sites from now on, i.e.: when over an overhead
+/// budget. false if bypassed already, or unless the preloader knows a
+/// bypass file.
+pub fn force_bypass() -> bool {
+    let path = bypass_file();
+    if path.as_os_str().is_empty() {
+        return false;
+    }
+    let mut bypass = ADAPTIVE_BYPASS.lock().unwrap_or_else(|e| e.into_inner());
+    match bypass.as_ref() {
+        Some(bypass) if bypass.policy == HotSitePolicy::Bypass => false,
+        Some(warn) => {
+            let threshold = warn.threshold;
+            let policy = HotSitePolicy::Bypass;
+            *bypass = Some(AdaptiveBypass::new(path, threshold, policy));
+            true
+        }
+        None => {
+            let threshold = ADAPTIVE_BYPASS_THRESHOLD;
+            let policy = HotSitePolicy::Bypass;
+            *bypass = Some(AdaptiveBypass::new(path, threshold, policy));
+            true
+        }
+    }
+}
+
+/// syscall site `ip` of task `tid` trapped because it cannot be patched
+pub fn unpatchable_syscall_trapped(tid: Pid, ip: u64) {
+    let mut bypass = ADAPTIVE_BYPASS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(bypass) = bypass.as_mut() {
+        let exe = match std::fs::read_link(format!("/proc/{}/exe", tid)) {
+            Ok(exe) => exe,
+            Err(_) => return,
+        };
+        match bypass.record_trap(exe, ip) {
+            Ok(Some(elapsed)) => {
+                let site = debug::symbolize(tid, ip);
+                match bypass.policy {
+                    HotSitePolicy::Warn => warn!(
+                        "[adaptive] {} hot unpatchable syscall @{:x} ({}), \
+                         {} traps in {:?}",
+                        tid, ip, site, bypass.threshold, elapsed
+                    ),
+                    HotSitePolicy::Bypass => info!(
+                        "[adaptive] {} syscall @{:x} ({}) bypassed on next exec",
+                        tid, ip, site
+                    ),
+                }
+            }
+            Ok(None) => (),
+            Err(err) => {
+                warn!("[adaptive] failed to record bypass @{:x}: {:?}", ip, err)
+            }
+        }
+    }
+}
+
+#[test]
+fn hot_site_warn_sanity_check() {
+    let path = PathBuf::from("/nonexistent/reverie-bypass.txt");
+    let mut bypass = AdaptiveBypass::new(path, 2, HotSitePolicy::Warn);
+    let exe = PathBuf::from("/bin/true");
+    assert_eq!(bypass.record_trap(exe.clone(), 0x1000).unwrap(), None);
+    assert!(bypass.record_trap(exe.clone(), 0x1000).unwrap().is_some());
+    assert_eq!(bypass.record_trap(exe, 0x1000).unwrap(), None);
+    assert_eq!(
+        "warn".parse::<HotSitePolicy>().ok(),
+        Some(HotSitePolicy::Warn)
+    );
+    assert!("sysemu".parse::<HotSitePolicy>().is_err());
+}
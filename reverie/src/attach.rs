@@ -0,0 +1,148 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! attaching to running processes, and the global state tracees share,
+//! for the `reverie` and `strace` binaries
+//!
+//! a process attached while running has its private page mapped lazily,
+//! at its first syscall, see `traced_task::inject_private_page`: the
+//! attach fails if the process has anything mapped there already.
+
+use nix::fcntl::OFlag;
+use nix::sys::memfd;
+use nix::sys::ptrace;
+use nix::sys::stat::Mode;
+use nix::unistd::{self, Pid};
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+
+use reverie_api::task::Task;
+use reverie_common::consts;
+
+use crate::nesting;
+use crate::sched_wait;
+use crate::traced_task::TracedTask;
+
+/// raise the soft limit of open files to `nofile`, if below, so that
+/// fixed fds of reverie are valid
+pub fn raise_nofile(nofile: u64) {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe {
+        if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) == 0
+            && limit.rlim_cur < nofile
+            && limit.rlim_max >= nofile
+        {
+            limit.rlim_cur = nofile;
+            libc::setrlimit(libc::RLIMIT_NOFILE, &limit);
+        }
+    }
+}
+
+/// global state shared with tracees, at `REVERIE_GLOBAL_STATE_FD`: a
+/// memfd, or a temporary file where memfd_create is denied, i.e.: in
+/// containers.
+pub fn init_global_state() {
+    let memfd_name = std::ffi::CStr::from_bytes_with_nul(&[
+        b'r', b'e', b'v', b'e', b'r', b'i', b'e', 0,
+    ])
+    .unwrap();
+    let fd_ = memfd::memfd_create(memfd_name, memfd::MemFdCreateFlag::empty())
+        .or_else(|_| {
+            nix::fcntl::open(
+                &env::temp_dir(),
+                OFlag::O_TMPFILE | OFlag::O_RDWR,
+                Mode::S_IRUSR | Mode::S_IWUSR,
+            )
+        })
+        .expect("neither memfd_create nor O_TMPFILE");
+    raise_nofile(consts::REVERIE_GLOBAL_STATE_FD as u64 + 1);
+    let memfd = unistd::dup2(fd_, consts::REVERIE_GLOBAL_STATE_FD)
+        .expect("dup2 to REVERIE_GLOBAL_STATE_FD failed");
+    let _ = unistd::close(fd_);
+    let glob_size = 32768 * 4096;
+    unistd::ftruncate(memfd, glob_size).unwrap_or_else(|_| {
+        panic!("memfd, unable to alloc {} bytes.", glob_size)
+    });
+}
+
+// mapping of `maps` (of `/proc/<pid>/maps`) overlapping the private page
+fn private_page_overlap(maps: &str) -> Option<&str> {
+    let page = consts::REVERIE_PRIVATE_PAGE_OFFSET;
+    let end = page + consts::REVERIE_PRIVATE_PAGE_SIZE;
+    maps.lines().find(|line| {
+        let range = line.split_whitespace().next().unwrap_or_default();
+        let mut bounds = range
+            .split('-')
+            .map(|bound| u64::from_str_radix(bound, 16).unwrap_or(0));
+        match (bounds.next(), bounds.next()) {
+            (Some(start), Some(stop)) => start < end && page < stop,
+            _ => false,
+        }
+    })
+}
+
+/// seize the threads of running process `pid`, its leader last. the
+/// private page is mapped lazily with `private_page`, see module doc.
+/// tasks are not killed when the tracer exits, as launched programs are.
+pub fn seize_process(pid: Pid, private_page: bool) -> Result<Vec<TracedTask>> {
+    let mut tids = Vec::new();
+    for entry in fs::read_dir(format!("/proc/{}/task", pid))? {
+        let tid = entry?.file_name().to_string_lossy().parse();
+        tids.push(Pid::from_raw(tid.map_err(|_| {
+            Error::new(ErrorKind::InvalidData, "invalid /proc/pid/task")
+        })?));
+    }
+    nesting::check_attachable(pid)?;
+    if private_page {
+        let maps = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+        if let Some(mapping) = private_page_overlap(&maps) {
+            let reason = format!(
+                "{} has {:?} mapped at the private page of reverie ({:#x})",
+                pid,
+                mapping,
+                consts::REVERIE_PRIVATE_PAGE_OFFSET
+            );
+            return Err(Error::new(ErrorKind::AddrInUse, reason));
+        }
+    }
+    init_global_state();
+    let options =
+        sched_wait::ptrace_options() - ptrace::Options::PTRACE_O_EXITKILL;
+    let leader: TracedTask = Task::new(pid);
+    leader.process.borrow_mut().lazy_private_page = private_page;
+    let mut tasks = Vec::new();
+    for tid in tids {
+        sched_wait::seize_running(tid, options)
+            .map_err(|err| Error::new(ErrorKind::Other, err))?;
+        if tid != pid {
+            tasks.push(leader.cloned(tid));
+        }
+    }
+    tasks.push(leader);
+    Ok(tasks)
+}
+
+#[test]
+fn attach_sanity_check() {
+    let maps = "00400000-00452000 r-xp 00000000 08:02 173521 /usr/bin/dbus\n\
+                6fff0000-70001000 rw-p 00000000 00:00 0 [heap]\n";
+    assert!(private_page_overlap(maps).unwrap().ends_with("[heap]"));
+    let first = maps.lines().next().unwrap();
+    assert_eq!(private_page_overlap(first), None);
+    let maps = "70004000-70005000 rw-p 00000000 00:00 0\n";
+    assert_eq!(private_page_overlap(maps), None);
+}
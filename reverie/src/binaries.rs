@@ -0,0 +1,159 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! binary provenance, see `reverie_api::binaries`
+//!
+//! at exec, the program (`/proc/<pid>/exe`) and the files mapped
+//! executable by the kernel (i.e.: the dynamic loader) are hashed. shared
+//! libraries are hashed when mapped executable, by the fd mapped: `mmap`s
+//! are stopped at exit when hashing, as for `MappingChange`s.
+//!
+//! files are read by procfs, deleted files as well. hashes are cached by
+//! file identity (device, inode, size and mtime), each image is reported
+//! once per process image.
+
+use nix::unistd::Pid;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Result;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use reverie_api::binaries::*;
+use reverie_api::fileless::sha256;
+use reverie_api::mapping::MappingChange;
+use reverie_api::search::read_regions;
+
+/// identity of a file: `st_dev`, `st_ino`, `st_size`, and `st_mtime` (in
+/// nanoseconds)
+type FileId = (u64, u64, u64, i64);
+
+lazy_static! {
+    static ref HASHES: Mutex<HashMap<FileId, String>> =
+        Mutex::new(HashMap::new());
+    static ref LOADED: Mutex<HashMap<Pid, HashSet<PathBuf>>> =
+        Mutex::new(HashMap::new());
+}
+
+// size and sha256 of the file at `file`
+fn hash_file(file: &Path) -> Result<(u64, String)> {
+    let meta = fs::metadata(file)?;
+    let mtime = meta.mtime() * 1_000_000_000 + meta.mtime_nsec();
+    let id = (meta.dev(), meta.ino(), meta.size(), mtime);
+    if let Some(sha256) =
+        HASHES.lock().unwrap_or_else(|e| e.into_inner()).get(&id)
+    {
+        return Ok((meta.size(), sha256.clone()));
+    }
+    let bytes = fs::read(file)?;
+    let sha256 = sha256(&bytes);
+    HASHES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(id, sha256.clone());
+    Ok((bytes.len() as u64, sha256))
+}
+
+// `path` loaded by `pid`, read by `file`. `None` if loaded before.
+fn loaded(
+    pid: Pid,
+    path: PathBuf,
+    file: &Path,
+    main: bool,
+) -> Option<BinaryImage> {
+    let mut loaded = LOADED.lock().unwrap_or_else(|e| e.into_inner());
+    if !loaded.entry(pid).or_default().insert(path.clone()) {
+        return None;
+    }
+    drop(loaded);
+    match hash_file(file) {
+        Ok((size, sha256)) => Some(BinaryImage {
+            path,
+            main,
+            size,
+            sha256,
+        }),
+        Err(err) => {
+            log::warn!("[pid {}] cannot hash {:?}: {}", pid, path, err);
+            None
+        }
+    }
+}
+
+/// images of process `pid`, which just exec'ed: its program first, then
+/// other files mapped executable, i.e.: the dynamic loader
+pub fn exec_images(pid: Pid) -> Vec<BinaryImage> {
+    LOADED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&pid);
+    let exe = PathBuf::from(format!("/proc/{}/exe", pid));
+    let path = match fs::read_link(&exe) {
+        Ok(path) => path,
+        Err(_) => return Vec::new(),
+    };
+    let mut images: Vec<_> =
+        loaded(pid, path, &exe, true).into_iter().collect();
+    for region in read_regions(pid).unwrap_or_default() {
+        if region.perms.contains('x') && region.path.starts_with('/') {
+            let path = PathBuf::from(&region.path);
+            images.extend(loaded(pid, path.clone(), &path, false));
+        }
+    }
+    images
+}
+
+/// image mapped by `change` of process `pid`, with `regs` of the `mmap`
+/// exit stop, `None` unless a file newly mapped
+pub fn mapped_image(
+    pid: Pid,
+    change: &MappingChange,
+    regs: &libc::user_regs_struct,
+) -> Option<BinaryImage> {
+    match change {
+        MappingChange::NewExecutableMapping {
+            path: Some(path), ..
+        } => {
+            let fd = regs.r8 as i32;
+            let file = PathBuf::from(format!("/proc/{}/fd/{}", pid, fd));
+            loaded(pid, path.clone(), &file, false)
+        }
+        _ => None,
+    }
+}
+
+/// process `pid` exited
+pub fn exited(pid: Pid) {
+    LOADED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&pid);
+}
+
+#[test]
+fn binaries_sanity_check() {
+    let pid = nix::unistd::getpid();
+    let images = exec_images(pid);
+    assert!(images[0].main);
+    assert_eq!(images[0].path, fs::read_link("/proc/self/exe").unwrap());
+    assert_eq!(images[0].sha256.len(), 64);
+    assert!(images[1..].iter().all(|image| !image.main));
+    // once per process image
+    let exe = PathBuf::from("/proc/self/exe");
+    assert_eq!(loaded(pid, images[0].path.clone(), &exe, true), None);
+    exited(pid);
+    let image = loaded(pid, images[0].path.clone(), &exe, true).unwrap();
+    assert_eq!(image, images[0]);
+    exited(pid);
+}
@@ -0,0 +1,244 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! overhead budget, with adaptive degradation
+//!
+//! with a `LatencyBudget`, the tracer overhead of ptraced syscalls (the
+//! time tasks are stopped by the tracer) is sampled in windows of `WINDOW`
+//! syscalls. when the percentile of a window is over budget, the next
+//! `Degradation` which applies is applied, in order: syscall data capture
+//! disabled, hot unpatchable sites bypassed (on next exec, see
+//! `adaptive`), logging reduced to warnings. degradations are logged, and
+//! reported, see `degradations`.
+
+use serde::Serialize;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// syscalls sampled per window
+pub const WINDOW: usize = 256;
+
+/// max tracer overhead of ptraced syscalls, at a percentile
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyBudget {
+    pub max: Duration,
+    /// percentile, 1 to 100
+    pub percentile: u8,
+}
+
+impl FromStr for LatencyBudget {
+    type Err = Error;
+    /// `MAX[@pPERCENTILE]`, `MAX` in `ns`, `us` or `ms`, i.e.: `20us@p99`,
+    /// the 99th percentile by default
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid latency budget: {}", s),
+            )
+        };
+        let mut split = s.splitn(2, '@');
+        let max = split.next().unwrap_or("");
+        let percentile = match split.next() {
+            Some(p) => p
+                .strip_prefix('p')
+                .and_then(|p| p.parse().ok())
+                .filter(|p| (1..=100).contains(p))
+                .ok_or_else(invalid)?,
+            None => 99,
+        };
+        let units = [("ns", 1), ("us", 1_000), ("ms", 1_000_000)];
+        let max = units
+            .iter()
+            .find_map(|(unit, nanos)| {
+                let n: u64 = max.strip_suffix(unit)?.parse().ok()?;
+                Some(Duration::from_nanos(n * nanos))
+            })
+            .ok_or_else(invalid)?;
+        Ok(LatencyBudget { max, percentile })
+    }
+}
+
+/// a degradation, of overhead for features
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Degradation {
+    DataCapture,
+    SiteBypass,
+    Logging,
+}
+
+/// degradations, in the order they are applied
+pub const DEGRADATIONS: [Degradation; 3] = [
+    Degradation::DataCapture,
+    Degradation::SiteBypass,
+    Degradation::Logging,
+];
+
+impl fmt::Display for Degradation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Degradation::DataCapture => {
+                write!(f, "syscall data capture disabled")
+            }
+            Degradation::SiteBypass => write!(f, "hot sites bypassed"),
+            Degradation::Logging => write!(f, "logging reduced to warnings"),
+        }
+    }
+}
+
+/// a degradation applied, for the report
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DegradationRecord {
+    /// as of `Degradation`
+    pub degradation: String,
+    /// overhead at the percentile of the budget, of the window over it
+    pub observed_nanos: u64,
+    /// syscalls sampled before
+    pub syscalls: usize,
+}
+
+/// overhead samples against a budget
+#[derive(Debug)]
+pub struct BudgetMonitor {
+    budget: LatencyBudget,
+    window: Vec<Duration>,
+    sampled: usize,
+    degraded: Vec<(Degradation, DegradationRecord)>,
+}
+
+impl BudgetMonitor {
+    pub fn new(budget: LatencyBudget) -> Self {
+        BudgetMonitor {
+            budget,
+            window: Vec::with_capacity(WINDOW),
+            sampled: 0,
+            degraded: Vec::new(),
+        }
+    }
+
+    /// sample tracer `overhead` of a syscall, returns the overhead at the
+    /// percentile of the budget when a window is over budget
+    pub fn sample(&mut self, overhead: Duration) -> Option<Duration> {
+        self.sampled += 1;
+        self.window.push(overhead);
+        if self.window.len() < WINDOW {
+            return None;
+        }
+        let mut window = std::mem::take(&mut self.window);
+        window.sort();
+        let percentile = usize::from(self.budget.percentile);
+        let k = (window.len() * percentile).div_ceil(100);
+        Some(window[k.max(1) - 1])
+            .filter(|observed| *observed > self.budget.max)
+    }
+
+    /// whether `degradation` was applied
+    pub fn is_degraded(&self, degradation: Degradation) -> bool {
+        self.degraded.iter().any(|(d, _)| *d == degradation)
+    }
+
+    /// `degradation` applied, with `observed` overhead
+    pub fn degraded(&mut self, degradation: Degradation, observed: Duration) {
+        let record = DegradationRecord {
+            degradation: degradation.to_string(),
+            observed_nanos: observed.as_nanos() as u64,
+            syscalls: self.sampled,
+        };
+        self.degraded.push((degradation, record));
+    }
+}
+
+lazy_static! {
+    static ref MONITOR: Mutex<Option<BudgetMonitor>> = Mutex::new(None);
+}
+
+/// set the overhead budget of the tracer
+pub fn set_budget(budget: LatencyBudget) {
+    *MONITOR.lock().unwrap_or_else(|e| e.into_inner()) =
+        Some(BudgetMonitor::new(budget));
+}
+
+/// sample tracer `overhead` of a syscall, returns what to degrade, if over
+/// budget. `applies` applies a degradation, telling whether it had any
+/// effect, i.e.: false with data capture not enabled.
+pub fn sample<F>(overhead: Duration, mut applies: F) -> Option<Degradation>
+where
+    F: FnMut(Degradation) -> bool,
+{
+    let mut monitor = MONITOR.lock().unwrap_or_else(|e| e.into_inner());
+    let monitor = monitor.as_mut()?;
+    let observed = monitor.sample(overhead)?;
+    let budget = monitor.budget;
+    let next = DEGRADATIONS
+        .iter()
+        .cloned()
+        .filter(|degradation| !monitor.is_degraded(*degradation))
+        .find(|degradation| applies(*degradation));
+    match next {
+        Some(degradation) => {
+            monitor.degraded(degradation, observed);
+            log::warn!(
+                "[budget] overhead {:?} over budget {:?} at p{}: {}",
+                observed,
+                budget.max,
+                budget.percentile,
+                degradation
+            );
+        }
+        None => log::info!(
+            "[budget] overhead {:?} over budget {:?}, nothing left to degrade",
+            observed,
+            budget.max
+        ),
+    }
+    next
+}
+
+/// degradations applied so far
+pub fn degradations() -> Vec<DegradationRecord> {
+    match MONITOR.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(monitor) => {
+            monitor.degraded.iter().map(|(_, r)| r.clone()).collect()
+        }
+        None => Vec::new(),
+    }
+}
+
+#[test]
+fn budget_sanity_check() {
+    let budget: LatencyBudget = "20us@p90".parse().unwrap();
+    assert_eq!(budget.max, Duration::from_micros(20));
+    assert_eq!(budget.percentile, 90);
+    assert_eq!("1ms".parse::<LatencyBudget>().unwrap().percentile, 99);
+    assert!("20".parse::<LatencyBudget>().is_err());
+    assert!("20us@p0".parse::<LatencyBudget>().is_err());
+
+    let mut monitor = BudgetMonitor::new(budget);
+    // 5% of syscalls over budget: the 90th percentile is not
+    for k in 0..WINDOW - 1 {
+        let micros = if k % 20 == 0 { 50 } else { 5 };
+        assert_eq!(monitor.sample(Duration::from_micros(micros)), None);
+    }
+    assert_eq!(monitor.sample(Duration::from_micros(5)), None);
+    for _ in 0..WINDOW - 1 {
+        assert_eq!(monitor.sample(Duration::from_micros(30)), None);
+    }
+    let observed = monitor.sample(Duration::from_micros(30));
+    assert_eq!(observed, Some(Duration::from_micros(30)));
+    monitor.degraded(Degradation::DataCapture, Duration::from_micros(30));
+    assert!(monitor.is_degraded(Degradation::DataCapture));
+    assert_eq!(monitor.degraded[0].1.syscalls, 2 * WINDOW);
+}
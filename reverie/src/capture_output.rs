@@ -0,0 +1,206 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! output capture, of `--capture-output`
+//!
+//! writes of tracees to their fds 1 and 2 are kept unpatched (by an
+//! emulator declining them), and what was written is teed at syscall exit
+//! into `<pid>.stdout` and `<pid>.stderr` of the capture directory, while
+//! written through as usual. each line is prefixed by the raw timestamp of
+//! the write it started with, see `clock::Timestamp`, the same as of
+//! events, so that logs can be correlated with the syscall trace.
+
+use nix::unistd::Pid;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Result, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use syscalls::SyscallNo;
+
+use reverie_api::clock::Timestamp;
+use reverie_api::emulate::*;
+use reverie_api::event::*;
+
+use crate::ordered_output::{written_bytes, Stream};
+
+/// a captured stream of a process
+struct Captured {
+    file: File,
+    /// last line, not complete yet, and when it started
+    partial: Option<(Timestamp, Vec<u8>)>,
+}
+
+impl Captured {
+    // `bytes` written at `at`, complete lines to `file`
+    fn written(&mut self, at: Timestamp, bytes: &[u8]) -> Result<()> {
+        let mut res = Vec::new();
+        for line in bytes.split_inclusive(|&b| b == b'\n') {
+            let (start, mut partial) =
+                self.partial.take().unwrap_or_else(|| (at, Vec::new()));
+            partial.extend_from_slice(line);
+            if line.ends_with(b"\n") {
+                res.extend(format!("{} ", start.as_nanos()).bytes());
+                res.extend(partial);
+            } else {
+                self.partial = Some((start, partial));
+            }
+        }
+        self.file.write_all(&res)
+    }
+
+    // write the last line, if not complete
+    fn finish(&mut self) -> Result<()> {
+        match self.partial.take() {
+            Some((start, partial)) => {
+                write!(self.file, "{} ", start.as_nanos())?;
+                self.file.write_all(&partial)?;
+                self.file.write_all(b"\n")
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// streams of processes captured to `dir`, see module doc
+pub struct OutputCapture {
+    dir: PathBuf,
+    captured: HashMap<(Pid, Stream), Captured>,
+}
+
+impl OutputCapture {
+    /// capture to `dir`, created if missing
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(OutputCapture {
+            dir,
+            captured: HashMap::new(),
+        })
+    }
+
+    /// file of `stream` of process `pid`
+    pub fn path(&self, pid: Pid, stream: Stream) -> PathBuf {
+        let name = match stream {
+            Stream::Stdout => format!("{}.stdout", pid),
+            Stream::Stderr => format!("{}.stderr", pid),
+        };
+        self.dir.join(name)
+    }
+
+    /// `bytes` written to `stream` by process `pid`, at `at`
+    pub fn written(
+        &mut self,
+        pid: Pid,
+        stream: Stream,
+        at: Timestamp,
+        bytes: &[u8],
+    ) -> Result<()> {
+        let key = (pid, stream);
+        if !self.captured.contains_key(&key) {
+            // appended to, by threads left after exit
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.path(pid, stream))?;
+            let partial = None;
+            self.captured.insert(key, Captured { file, partial });
+        }
+        self.captured.get_mut(&key).unwrap().written(at, bytes)
+    }
+
+    /// process `pid` exited, its last lines are written
+    pub fn exited(&mut self, pid: Pid) -> Result<()> {
+        for stream in &[Stream::Stdout, Stream::Stderr] {
+            if let Some(mut captured) = self.captured.remove(&(pid, *stream)) {
+                captured.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// write last lines of all processes, at the end of the run
+    pub fn finish(&mut self) -> Result<()> {
+        for captured in self.captured.values_mut() {
+            captured.finish()?;
+        }
+        self.captured.clear();
+        Ok(())
+    }
+
+    /// `event` observed by the tracer
+    pub fn record_event(&mut self, event: &TimedEvent) {
+        if let Event::Exited(_) = event.event {
+            if let Err(err) = self.exited(event.tid) {
+                log::warn!("[capture] output of {} lost: {}", event.tid, err);
+            }
+        }
+    }
+}
+
+/// syscall emulation (declining writes to fds 1 and 2, for them to be
+/// seen) and event sink capturing with `capture`
+pub fn output_capture(
+    capture: Rc<RefCell<OutputCapture>>,
+) -> (SyscallEmulation, EventSink) {
+    let mut emulation = SyscallEmulation::new(
+        EmulationMode::Seccomp,
+        vec![SyscallNo::SYS_write, SyscallNo::SYS_writev],
+        Box::new(|_task, _memory, _syscall, _args| None),
+    );
+    let exits = capture.clone();
+    emulation.set_exit_handler(Box::new(
+        move |task, memory, syscall, args, retval| {
+            let stream = match args.arg0 {
+                1 => Stream::Stdout,
+                2 => Stream::Stderr,
+                _ => return,
+            };
+            let at = Timestamp::now();
+            let mut bytes = match written_bytes(memory, syscall, args) {
+                Some(bytes) if retval > 0 => bytes,
+                _ => return,
+            };
+            bytes.truncate(retval as usize);
+            let pid = task.getpid();
+            let res = exits.borrow_mut().written(pid, stream, at, &bytes);
+            if let Err(err) = res {
+                log::warn!("[capture] output of {} lost: {}", pid, err);
+            }
+        },
+    ));
+    let sink: EventSink =
+        Box::new(move |event| capture.borrow_mut().record_event(event));
+    (emulation, sink)
+}
+
+#[test]
+fn capture_output_sanity_check() {
+    let dir =
+        std::env::temp_dir().join(format!("capture-{}", std::process::id()));
+    let mut capture = OutputCapture::new(&dir).unwrap();
+    let pid = Pid::from_raw(i32::MAX);
+    let at = Timestamp::from_nanos;
+    capture.written(pid, Stream::Stdout, at(1), b"a").unwrap();
+    capture
+        .written(pid, Stream::Stdout, at(2), b"b\nc\nd")
+        .unwrap();
+    capture.written(pid, Stream::Stderr, at(3), b"e\n").unwrap();
+    capture.exited(pid).unwrap();
+    let stdout = fs::read_to_string(capture.path(pid, Stream::Stdout));
+    assert_eq!(stdout.unwrap(), "1 ab\n2 c\n2 d\n");
+    let stderr = fs::read_to_string(capture.path(pid, Stream::Stderr));
+    assert_eq!(stderr.unwrap(), "3 e\n");
+    let _ = fs::remove_dir_all(&dir);
+}
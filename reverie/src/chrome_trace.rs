@@ -0,0 +1,287 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! export of events to the chrome trace-event format
+//!
+//! events are written as the json object format of `trace_event`, which
+//! chrome://tracing and the perfetto ui open, a lane per thread, grouped
+//! by process:
+//!
+//! - syscalls are spans (`X`), from their `SyscallExit`.
+//! - idle periods of the tree (`Event::Idle`) are spans of the lane of the
+//!   tracer.
+//! - signals, preemptions (`Event::Preempted`), and task events (forks,
+//!   execs, exits..) are instants (`i`).
+//! - threads and processes are named after their `comm` at exec.
+//!
+//! processes of threads are told by `Clone` and `Fork` events, a task not
+//! seen created (i.e.: the first) being a process of its own. timestamps
+//! are in microseconds since the first event.
+
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{Result, Write};
+use std::time::Duration;
+
+use reverie_api::event::*;
+
+/// an event of the trace-event format
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TraceEvent {
+    pub name: String,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    pub cat: &'static str,
+    pub ph: &'static str,
+    /// in microseconds
+    pub ts: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dur: Option<f64>,
+    pub pid: i32,
+    pub tid: i32,
+    /// scope of instants, thread
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub s: Option<&'static str>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub args: BTreeMap<&'static str, Value>,
+}
+
+// `comm` of `tid`, if still alive
+fn comm(tid: i32) -> Option<String> {
+    let comm = fs::read_to_string(format!("/proc/{}/comm", tid)).ok()?;
+    Some(comm.trim().to_string())
+}
+
+/// converts events to `TraceEvent`s, see module doc
+#[derive(Debug, Default)]
+pub struct TraceEvents {
+    /// of the first event, in nanoseconds
+    start: Option<u64>,
+    /// process of threads, by tid
+    processes: HashMap<i32, i32>,
+}
+
+impl TraceEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn micros(&mut self, nanos: u64) -> f64 {
+        let start = *self.start.get_or_insert(nanos);
+        nanos.saturating_sub(start) as f64 / 1e3
+    }
+
+    /// `TraceEvent`s of `event`, if any
+    pub fn convert(&mut self, event: &TimedEvent) -> Vec<TraceEvent> {
+        let tid = event.tid.as_raw();
+        let pid = *self.processes.entry(tid).or_insert(tid);
+        let at = event.at.as_nanos();
+        let ts = self.micros(at);
+        let instant = |name: String, cat, args| TraceEvent {
+            name,
+            cat,
+            ph: "i",
+            ts,
+            dur: None,
+            pid,
+            tid,
+            s: Some("t"),
+            args,
+        };
+        let mut args = BTreeMap::new();
+        let span = |this: &mut Self, name: String, cat, elapsed: Duration| {
+            let begin = at.saturating_sub(elapsed.as_nanos() as u64);
+            TraceEvent {
+                name,
+                cat,
+                ph: "X",
+                ts: this.micros(begin),
+                dur: Some(elapsed.as_nanos() as f64 / 1e3),
+                pid,
+                tid,
+                s: None,
+                args: BTreeMap::new(),
+            }
+        };
+        match &event.event {
+            Event::SyscallExit(syscall, retval, elapsed) => {
+                let name = format!("{:?}", syscall);
+                let name = name.trim_start_matches("SYS_").to_string();
+                let mut span = span(self, name, "syscall", *elapsed);
+                span.args.insert("retval", Value::from(*retval));
+                vec![span]
+            }
+            Event::Idle(idle) => {
+                vec![span(self, String::from("idle"), "sched", *idle)]
+            }
+            Event::Preempted(ticks) => {
+                args.insert("ticks", Value::from(*ticks));
+                vec![instant(String::from("preempted"), "sched", args)]
+            }
+            Event::Signal(sig) | Event::AsyncSignal(sig, _) => {
+                vec![instant(format!("{}", sig), "signal", args)]
+            }
+            Event::Clone(child) => {
+                self.processes.insert(child.as_raw(), pid);
+                args.insert("child", Value::from(child.as_raw()));
+                vec![instant(String::from("clone"), "task", args)]
+            }
+            Event::Fork(child) | Event::Spawn(child) => {
+                let child = child.as_raw();
+                self.processes.insert(child, child);
+                args.insert("child", Value::from(child));
+                let name = format!("{:?}", event.event.kind()).to_lowercase();
+                vec![instant(name, "task", args)]
+            }
+            Event::Exec => {
+                let mut events =
+                    vec![instant(String::from("exec"), "task", args)];
+                if let Some(comm) = comm(tid) {
+                    for name in &["thread_name", "process_name"] {
+                        let mut args = BTreeMap::new();
+                        args.insert("name", Value::from(comm.as_str()));
+                        events.push(TraceEvent {
+                            name: name.to_string(),
+                            cat: "",
+                            ph: "M",
+                            ts: 0.0,
+                            dur: None,
+                            pid,
+                            tid,
+                            s: None,
+                            args,
+                        });
+                    }
+                }
+                events
+            }
+            Event::Exited(code) => {
+                args.insert("code", Value::from(*code));
+                vec![instant(String::from("exited"), "task", args)]
+            }
+            Event::Zombie(_)
+            | Event::Reaped(_)
+            | Event::Orphaned(_)
+            | Event::Quarantined(_) => {
+                let name = format!("{:?}", event.event);
+                vec![instant(name, "task", args)]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// writes events as a trace-event json object
+pub struct ChromeTrace {
+    out: Box<dyn Write>,
+    events: TraceEvents,
+    written: usize,
+}
+
+impl ChromeTrace {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        ChromeTrace {
+            out,
+            events: TraceEvents::new(),
+            written: 0,
+        }
+    }
+
+    fn write(&mut self, event: &TraceEvent) -> Result<()> {
+        let sep = if self.written == 0 {
+            "{\"traceEvents\":[\n"
+        } else {
+            ",\n"
+        };
+        self.out.write_all(sep.as_bytes())?;
+        serde_json::to_writer(&mut self.out, event)?;
+        self.written += 1;
+        Ok(())
+    }
+
+    pub fn record_event(&mut self, event: &TimedEvent) {
+        for event in self.events.convert(event) {
+            if let Err(err) = self.write(&event) {
+                log::error!("[chrome_trace] cannot write event: {}", err);
+            }
+        }
+    }
+
+    /// close the json object, after the last event
+    pub fn finish(&mut self) -> Result<()> {
+        if self.written == 0 {
+            self.out.write_all(b"{\"traceEvents\":[")?;
+        }
+        self.out.write_all(b"\n],\"displayTimeUnit\":\"ns\"}\n")?;
+        self.out.flush()
+    }
+}
+
+#[test]
+fn chrome_trace_sanity_check() {
+    use nix::sys::signal::Signal;
+    use nix::unistd::Pid;
+    use reverie_api::clock::Timestamp;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use syscalls::SyscallNo;
+
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    let (parent, thread) = (Pid::from_raw(i32::MAX), Pid::from_raw(7));
+    let timed = |tid, micros: u64, event| TimedEvent {
+        tid,
+        at: Timestamp::from_nanos(1_000_000 + micros * 1000),
+        ticks: None,
+        event,
+    };
+    let out = Shared::default();
+    let mut trace = ChromeTrace::new(Box::new(out.clone()));
+    trace.record_event(&timed(parent, 0, Event::Exec));
+    trace.record_event(&timed(parent, 10, Event::Clone(thread)));
+    let elapsed = Duration::from_micros(5);
+    let exit = Event::SyscallExit(SyscallNo::SYS_read, 3, elapsed);
+    trace.record_event(&timed(thread, 30, exit));
+    trace.record_event(&timed(thread, 40, Event::Signal(Signal::SIGUSR1)));
+    trace.record_event(&timed(
+        parent,
+        50,
+        Event::SyscallEnter(
+            SyscallNo::SYS_exit_group,
+            reverie_api::remote::SyscallArgs::from(0, 0, 0, 0, 0, 0),
+        ),
+    ));
+    trace.finish().unwrap();
+
+    let json: Value = serde_json::from_slice(&out.0.borrow()).unwrap();
+    let events = json["traceEvents"].as_array().unwrap();
+    assert_eq!(events.len(), 4);
+    assert_eq!(events[2]["name"], "read");
+    assert_eq!(events[2]["ph"], "X");
+    assert_eq!(events[2]["ts"], 25.0);
+    assert_eq!(events[2]["dur"], 5.0);
+    assert_eq!(events[2]["pid"], i32::MAX);
+    assert_eq!(events[2]["tid"], 7);
+    assert_eq!(events[3]["name"], "SIGUSR1");
+    assert_eq!(events[3]["s"], "t");
+}
@@ -0,0 +1,86 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! clone flags decoding
+//!
+//! ptrace reports `PTRACE_EVENT_CLONE` for clones with exit signal other
+//! than `SIGCHLD`, and `PTRACE_EVENT_FORK` otherwise, which says nothing
+//! about what the new task shares with its creator. the actual `CLONE_*`
+//! flags are decoded from the creating syscall at the event stop instead.
+
+use nix::sched::CloneFlags;
+use syscalls::*;
+
+/// `clone3`, not known by `syscalls`
+pub const SYS_CLONE3: u64 = 435;
+
+/// low byte of clone flags is the exit signal
+const CSIGNAL: u64 = 0xff;
+
+/// decode clone flags of syscall `nr` with first argument `arg0`, `clone3`
+/// flags must be read from `struct clone_args` (at `arg0`) by `read_flags`.
+/// `None` if `nr` doesn't create a task.
+pub fn decode_clone_flags<F>(
+    nr: u64,
+    arg0: u64,
+    read_flags: F,
+) -> Option<CloneFlags>
+where
+    F: FnOnce(u64) -> Option<u64>,
+{
+    let raw = match nr {
+        nr if nr == SYS_fork as u64 => 0,
+        nr if nr == SYS_vfork as u64 => {
+            (libc::CLONE_VM | libc::CLONE_VFORK) as u64
+        }
+        nr if nr == SYS_clone as u64 => arg0,
+        SYS_CLONE3 => read_flags(arg0)?,
+        _ => return None,
+    };
+    Some(CloneFlags::from_bits_truncate(
+        (raw & !CSIGNAL) as libc::c_int,
+    ))
+}
+
+/// clone flags of a new thread, as `pthread_create`
+pub fn thread_clone_flags() -> CloneFlags {
+    CloneFlags::CLONE_VM
+        | CloneFlags::CLONE_FS
+        | CloneFlags::CLONE_FILES
+        | CloneFlags::CLONE_SIGHAND
+        | CloneFlags::CLONE_THREAD
+}
+
+#[test]
+fn clone_flags_sanity_check() {
+    let none = |_| None;
+    assert_eq!(
+        decode_clone_flags(SYS_fork as u64, 0, none),
+        Some(CloneFlags::empty())
+    );
+    let vfork = decode_clone_flags(SYS_vfork as u64, 0, none).unwrap();
+    assert!(vfork.contains(CloneFlags::CLONE_VM | CloneFlags::CLONE_VFORK));
+    // clone(CLONE_VM | SIGCHLD): shares VM, but not a thread
+    let flags = (libc::CLONE_VM | libc::SIGCHLD) as u64;
+    assert_eq!(
+        decode_clone_flags(SYS_clone as u64, flags, none),
+        Some(CloneFlags::CLONE_VM)
+    );
+    let threads = thread_clone_flags().bits() as u64;
+    assert_eq!(
+        decode_clone_flags(SYS_CLONE3, 0x1000, |_| Some(threads)),
+        Some(thread_clone_flags())
+    );
+    assert_eq!(decode_clone_flags(SYS_CLONE3, 0x1000, none), None);
+    assert_eq!(decode_clone_flags(SYS_getpid as u64, 0, none), None);
+}
@@ -0,0 +1,303 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! thread creation and lock contention profiling, of `--contention`
+//!
+//! threads are counted as `clone`d, and `pthread_create` calls by call
+//! site, by return probes (of glibc's libc, or libpthread before 2.34).
+//! futex waits are kept unpatched (by an emulator declining them) and
+//! timed, see `Event::SyscallExit`, by futex word and by the pc of the
+//! waiting `syscall`: the words waited for the longest are reported
+//! first, with call sites symbolized, see `debug::symbolize`. threads are
+//! reported with the time they waited, and the ticks they retired if
+//! counted, see `ticks`: the progress they made while others waited.
+
+use nix::unistd::Pid;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{Result, Write};
+use std::rc::Rc;
+use std::time::Duration;
+use syscalls::SyscallNo;
+
+use reverie_api::emulate::*;
+use reverie_api::event::*;
+use reverie_api::probe::ProbeSpec;
+use reverie_api::remote::SyscallArgs;
+
+use crate::debug;
+
+/// probes of `pthread_create`, of glibc's libc or libpthread
+pub const CONTENTION_PROBES: &[&str] = &[
+    "libc.so.6:pthread_create%return,sp",
+    "libpthread.so.0:pthread_create%return,sp",
+];
+
+const FUTEX_WAIT: u64 = 0;
+const FUTEX_LOCK_PI: u64 = 6;
+const FUTEX_WAIT_BITSET: u64 = 9;
+const FUTEX_WAIT_REQUEUE_PI: u64 = 11;
+const FUTEX_LOCK_PI2: u64 = 13;
+const FUTEX_CMD_MASK: u64 = !(128 | 256);
+
+/// probes of `CONTENTION_PROBES`, to add to `TaskEventCB::probes`
+pub fn contention_probes() -> Vec<ProbeSpec> {
+    CONTENTION_PROBES
+        .iter()
+        .map(|probe| probe.parse().unwrap())
+        .collect()
+}
+
+// whether futex `op` waits
+fn is_futex_wait(op: u64) -> bool {
+    matches!(
+        op & FUTEX_CMD_MASK,
+        FUTEX_WAIT
+            | FUTEX_LOCK_PI
+            | FUTEX_WAIT_BITSET
+            | FUTEX_WAIT_REQUEUE_PI
+            | FUTEX_LOCK_PI2
+    )
+}
+
+// pc of task `tid` of process `pid` in a syscall, of its
+// `/proc/<pid>/task/<tid>/syscall`: `nr args.. sp pc`
+fn syscall_pc(pid: Pid, tid: Pid) -> Option<u64> {
+    let path = format!("/proc/{}/task/{}/syscall", pid, tid);
+    let contents = fs::read_to_string(path).ok()?;
+    let pc = contents.split_whitespace().last()?;
+    u64::from_str_radix(pc.strip_prefix("0x")?, 16).ok()
+}
+
+/// waits for a futex word
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FutexWaits {
+    /// futex word, symbolized
+    pub word: String,
+    pub waits: u64,
+    pub total: Duration,
+    pub longest: Duration,
+    /// waits by symbolized call site
+    pub sites: BTreeMap<String, u64>,
+}
+
+/// a thread, and its waits
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThreadWaits {
+    pub pid: i32,
+    pub waits: u64,
+    pub waited: Duration,
+    /// ticks retired at exit, if counted
+    pub ticks: Option<u64>,
+}
+
+/// profiles thread creation and futex waits, see module doc
+#[derive(Default)]
+pub struct ContentionProfiler {
+    /// futex waits entered, by tid: word, pc
+    waiting: HashMap<Pid, (u64, Option<u64>)>,
+    /// waits by process and futex word
+    words: HashMap<(Pid, u64), FutexWaits>,
+    /// by tid
+    threads: BTreeMap<i32, ThreadWaits>,
+    /// threads `clone`d, by pid
+    clones: BTreeMap<i32, u64>,
+    /// `pthread_create` calls, by symbolized call site
+    creates: BTreeMap<String, u64>,
+}
+
+impl ContentionProfiler {
+    pub fn new() -> Self {
+        ContentionProfiler::default()
+    }
+
+    /// `syscall` entered by task `tid` of process `pid`
+    pub fn entered(
+        &mut self,
+        pid: Pid,
+        tid: Pid,
+        syscall: SyscallNo,
+        args: &SyscallArgs,
+    ) {
+        let thread = self.threads.entry(tid.as_raw()).or_default();
+        thread.pid = pid.as_raw();
+        if syscall == SyscallNo::SYS_futex && is_futex_wait(args.arg1) {
+            let pc = syscall_pc(pid, tid);
+            self.waiting.insert(tid, (args.arg0, pc));
+        }
+    }
+
+    // futex wait of `tid` on `word` at `pc` returned after `waited`
+    fn waited(
+        &mut self,
+        tid: Pid,
+        word: u64,
+        pc: Option<u64>,
+        waited: Duration,
+    ) {
+        let thread = self.threads.entry(tid.as_raw()).or_default();
+        thread.waits += 1;
+        thread.waited += waited;
+        let pid = Pid::from_raw(thread.pid);
+        let waits =
+            self.words.entry((pid, word)).or_insert_with(|| FutexWaits {
+                word: debug::symbolize(tid, word),
+                ..Default::default()
+            });
+        waits.waits += 1;
+        waits.total += waited;
+        waits.longest = waits.longest.max(waited);
+        let site = match pc {
+            Some(pc) => debug::symbolize(tid, pc),
+            None => String::from("?"),
+        };
+        *waits.sites.entry(site).or_default() += 1;
+    }
+
+    /// `event` observed by the tracer
+    pub fn record_event(&mut self, event: &TimedEvent) {
+        let tid = event.tid;
+        match &event.event {
+            Event::SyscallExit(SyscallNo::SYS_futex, _, waited) => {
+                if let Some((word, pc)) = self.waiting.remove(&tid) {
+                    self.waited(tid, word, pc, *waited);
+                }
+            }
+            Event::Clone(_) => {
+                let pid = self.threads.get(&tid.as_raw()).map(|t| t.pid);
+                let pid = pid.unwrap_or_else(|| tid.as_raw());
+                *self.clones.entry(pid).or_default() += 1;
+            }
+            Event::Probe(probe, values)
+                if CONTENTION_PROBES.contains(&&**probe) =>
+            {
+                if let [Some(site), Some(0)] = values.as_slice() {
+                    let site = debug::symbolize(tid, *site);
+                    *self.creates.entry(site).or_default() += 1;
+                }
+            }
+            Event::Exited(_) => {
+                self.waiting.remove(&tid);
+                if let Some(thread) = self.threads.get_mut(&tid.as_raw()) {
+                    thread.ticks = event.ticks;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// futex words, waited for the longest first
+    pub fn contended(&self) -> Vec<(Pid, &FutexWaits)> {
+        let mut words: Vec<_> = self
+            .words
+            .iter()
+            .map(|((pid, _), waits)| (*pid, waits))
+            .collect();
+        words.sort_by_key(|(_, waits)| std::cmp::Reverse(waits.total));
+        words
+    }
+
+    /// write the contention report to `out`
+    pub fn write<W: Write>(&self, mut out: W) -> Result<()> {
+        for (pid, clones) in &self.clones {
+            writeln!(out, "pid {}: {} threads cloned", pid, clones)?;
+        }
+        if !self.creates.is_empty() {
+            writeln!(out, "pthread_create, by call site:")?;
+        }
+        for (site, creates) in &self.creates {
+            writeln!(out, "  {} {}", creates, site)?;
+        }
+        if !self.words.is_empty() {
+            writeln!(out, "futex waits, by futex word:")?;
+        }
+        for (pid, waits) in self.contended() {
+            writeln!(
+                out,
+                "  pid {} {}: {} waits, {:?} total, {:?} longest",
+                pid, waits.word, waits.waits, waits.total, waits.longest
+            )?;
+            for (site, count) in &waits.sites {
+                writeln!(out, "    {} {}", count, site)?;
+            }
+        }
+        if !self.threads.is_empty() {
+            writeln!(out, "threads:")?;
+        }
+        for (tid, thread) in &self.threads {
+            write!(
+                out,
+                "  tid {} (pid {}): {} waits, {:?} waiting",
+                tid, thread.pid, thread.waits, thread.waited
+            )?;
+            match thread.ticks {
+                Some(ticks) => writeln!(out, ", {} ticks", ticks)?,
+                None => writeln!(out)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// syscall emulation (declining futexes and clones, for them to be seen
+/// with their pid) and event sink profiling with `profiler`
+pub fn contention_tracing(
+    profiler: Rc<RefCell<ContentionProfiler>>,
+) -> (SyscallEmulation, EventSink) {
+    let enter = profiler.clone();
+    let emulation = SyscallEmulation::new(
+        EmulationMode::Seccomp,
+        vec![SyscallNo::SYS_futex, SyscallNo::SYS_clone],
+        Box::new(move |task, _memory, syscall, args| {
+            let (pid, tid) = (task.getpid(), task.gettid());
+            enter.borrow_mut().entered(pid, tid, syscall, args);
+            None
+        }),
+    );
+    let sink: EventSink =
+        Box::new(move |event| profiler.borrow_mut().record_event(event));
+    (emulation, sink)
+}
+
+#[test]
+fn contention_sanity_check() {
+    assert_eq!(contention_probes().len(), 2);
+    assert!(is_futex_wait(FUTEX_WAIT | 128));
+    assert!(!is_futex_wait(1));
+    let (pid, tid) = (Pid::from_raw(i32::MAX - 1), Pid::from_raw(i32::MAX));
+    let event = |tid, event| TimedEvent {
+        tid,
+        at: Default::default(),
+        ticks: Some(42),
+        event,
+    };
+    let mut profiler = ContentionProfiler::new();
+    let args = SyscallArgs::from(0x1000, 128, 0, 0, 0, 0);
+    for millis in &[2, 5] {
+        profiler.entered(pid, tid, SyscallNo::SYS_futex, &args);
+        let waited = Duration::from_millis(*millis);
+        let exit = Event::SyscallExit(SyscallNo::SYS_futex, 0, waited);
+        profiler.record_event(&event(tid, exit));
+    }
+    profiler.record_event(&event(tid, Event::Exited(0)));
+    let contended = profiler.contended();
+    assert_eq!(contended.len(), 1);
+    assert_eq!(contended[0].1.waits, 2);
+    assert_eq!(contended[0].1.longest, Duration::from_millis(5));
+    assert_eq!(profiler.threads[&tid.as_raw()].ticks, Some(42));
+    let mut out = Vec::new();
+    profiler.write(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("2 waits, 7ms total, 5ms longest"));
+}
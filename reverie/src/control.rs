@@ -0,0 +1,463 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! control socket, of `--control-socket`
+//!
+//! out-of-band requests to traced tasks, i.e.: to make a supervised
+//! service reopen its log file. clients connect to a unix socket, and send
+//! requests as json, one per line, each answered by a `Reply` line:
+//!
+//! ```text
+//! {"command": "syscall", "pid": 1234, "syscall": "SYS_close", "args": [3]}
+//! {"command": "call", "pid": 1234, "function": "reopen_log", "args": [0]}
+//! ```
+//!
+//! syscall args are numbers, strings (copied in the task, nul terminated)
+//! or `{"buffer": SIZE}`, a zeroed buffer returned in the reply. functions
+//! are symbols of the tool preloaded, run when the task resumes, their
+//! return value is not reported.
+//!
+//! the guest dispatch of a syscall of a process is set by:
+//!
+//! ```text
+//! {"command": "dispatch", "pid": 1234, "syscall": "SYS_read",
+//!  "dispatch": "disabled"}
+//! ```
+//!
+//! i.e.: `count`, `guest`, `tracer`, `disabled`, or `exec` to dispatch by
+//! the tier of exec again, see `reverie_common::dispatch`.
+//!
+//! the tracer saves its session and detaches from all tasks by:
+//!
+//! ```text
+//! {"command": "detach", "session": "/run/reverie.session"}
+//! ```
+//!
+//! to be re-attached by `reverie attach --session`, see `session`.
+//!
+//! connections are served by their own threads, requests are served by
+//! the scheduler loop, at the next syscall stop of a task of `pid` (or of
+//! `tid`, if given), see `serve`: requests to idle tasks wait. dispatch
+//! and detach requests are served at the next stop of any task.
+
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+
+use reverie_api::inject::{remote_syscall, RemoteArg};
+use reverie_api::remote::{Injector, SyscallArgs};
+use reverie_api::task::{Task, TaskState};
+use reverie_common::dispatch::{self, Dispatch};
+use syscalls::SyscallNo;
+
+use crate::traced_task::TracedTask;
+
+/// a syscall, by name (i.e.: `SYS_close`) or number
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum SyscallName {
+    Nr(i32),
+    Name(String),
+}
+
+impl SyscallName {
+    fn syscall(&self) -> Result<SyscallNo> {
+        let max = SyscallNo::SYS_statx as i32;
+        let nr = match self {
+            SyscallName::Nr(nr) => {
+                Some(*nr).filter(|nr| (0..=max).contains(nr))
+            }
+            SyscallName::Name(name) => (0..=max)
+                .find(|nr| format!("{:?}", SyscallNo::from(*nr)) == *name),
+        };
+        nr.map(SyscallNo::from).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown syscall: {:?}", self),
+            )
+        })
+    }
+}
+
+/// an argument of a syscall request
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Arg {
+    Value(i64),
+    Str(String),
+    Buffer { buffer: usize },
+}
+
+/// a request, of a line
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// inject a syscall
+    Syscall {
+        pid: i32,
+        tid: Option<i32>,
+        syscall: SyscallName,
+        #[serde(default)]
+        args: Vec<Arg>,
+    },
+    /// call a function of the tool preloaded
+    Call {
+        pid: i32,
+        tid: Option<i32>,
+        function: String,
+        #[serde(default)]
+        args: Vec<u64>,
+    },
+    /// set the guest dispatch of a syscall
+    Dispatch {
+        pid: i32,
+        syscall: SyscallName,
+        dispatch: String,
+    },
+    /// save the session and detach all tasks
+    Detach { session: PathBuf },
+}
+
+impl Request {
+    /// process and thread targeted, `None` for requests to the tracer
+    fn target(&self) -> Option<(Pid, Option<Pid>)> {
+        let (pid, tid) = match self {
+            Request::Syscall { pid, tid, .. } => (pid, tid),
+            Request::Call { pid, tid, .. } => (pid, tid),
+            Request::Dispatch { pid, .. } => (pid, &None),
+            Request::Detach { .. } => return None,
+        };
+        Some((Pid::from_raw(*pid), tid.map(Pid::from_raw)))
+    }
+}
+
+/// a reply, of a line
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Reply {
+    /// whether the request was served
+    pub ok: bool,
+    /// what the syscall returned, negated errno if it failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retval: Option<i64>,
+    /// contents of buffer args after the syscall, in order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub buffers: Vec<Vec<u8>>,
+    /// why the request was not served
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Reply {
+    fn error(err: &dyn std::fmt::Display) -> Self {
+        Reply {
+            error: Some(err.to_string()),
+            ..Reply::default()
+        }
+    }
+}
+
+/// a detach request, served by the scheduler
+pub struct DetachRequest {
+    /// where the session is saved
+    pub session: PathBuf,
+    sender: SyncSender<Reply>,
+}
+
+impl DetachRequest {
+    /// reply whether the session is saved, returns once the reply is
+    /// received by the connection, before the tracer exits
+    pub fn reply(self, saved: Result<()>) {
+        let reply = match saved {
+            Ok(()) => Reply {
+                ok: true,
+                ..Reply::default()
+            },
+            Err(err) => Reply::error(&err),
+        };
+        let _ = self.sender.send(reply);
+    }
+}
+
+lazy_static! {
+    static ref PENDING: Mutex<Vec<(Request, SyncSender<Reply>)>> =
+        Mutex::new(Vec::new());
+}
+
+/// listen on unix socket `path`, replacing a stale socket
+pub fn listen(path: &Path) -> Result<()> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    thread::Builder::new()
+        .name(String::from("reverie-control"))
+        .spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = thread::Builder::new()
+                    .name(String::from("reverie-control-client"))
+                    .spawn(move || connection(stream));
+            }
+        })?;
+    Ok(())
+}
+
+fn connection(stream: UnixStream) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                log::info!("[control] request {:?}", request);
+                let (sender, receiver) = sync_channel(0);
+                PENDING
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push((request, sender));
+                receiver.recv().unwrap_or_else(|_| {
+                    Reply::error(&"tracer exited before serving request")
+                })
+            }
+            Err(err) => Reply::error(&err),
+        };
+        serde_json::to_writer(&mut writer, &reply)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// serve requests pending to `task`, stopped at a syscall, and fail
+/// requests to processes not `traced`
+pub fn serve<F>(task: &TracedTask, traced: F)
+where
+    F: Fn(Pid) -> bool,
+{
+    let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+    if pending.is_empty() {
+        return;
+    }
+    let at_syscall = match task.state {
+        TaskState::Seccomp(_) | TaskState::Syscall(_) => true,
+        _ => false,
+    };
+    let (pid, tid) = (task.getpid(), task.gettid());
+    let mut k = 0;
+    while k < pending.len() {
+        let (target_pid, target_tid) = match pending[k].0.target() {
+            Some(target) => target,
+            None => {
+                k += 1;
+                continue;
+            }
+        };
+        let thread_exited = target_tid.map_or(false, |target| {
+            let task = format!("/proc/{}/task/{}", target_pid, target);
+            !Path::new(&task).exists()
+        });
+        let reply = if !traced(target_pid) || thread_exited {
+            let target = target_tid.unwrap_or(target_pid);
+            Some(Reply::error(&format!("{} is not traced", target)))
+        } else if let Request::Dispatch { .. } = pending[k].0 {
+            Some(handle(task, &pending[k].0))
+        } else if at_syscall
+            && target_pid == pid
+            && target_tid.map_or(true, |target| target == tid)
+        {
+            Some(handle(task, &pending[k].0))
+        } else {
+            None
+        };
+        match reply {
+            Some(reply) => {
+                let (_, sender) = pending.remove(k);
+                let _ = sender.send(reply);
+            }
+            None => k += 1,
+        }
+    }
+}
+
+/// take the detach request pending, if any
+pub fn take_detach() -> Option<DetachRequest> {
+    let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+    let k = pending
+        .iter()
+        .position(|(request, _)| request.target().is_none())?;
+    match pending.remove(k) {
+        (Request::Detach { session }, sender) => {
+            Some(DetachRequest { session, sender })
+        }
+        _ => None,
+    }
+}
+
+fn handle(task: &TracedTask, request: &Request) -> Reply {
+    let served = match request {
+        Request::Syscall { syscall, args, .. } => {
+            // strings nul terminated
+            let strings: Vec<Vec<u8>> = args
+                .iter()
+                .map(|arg| match arg {
+                    Arg::Str(s) => s.bytes().chain(Some(0)).collect(),
+                    _ => Vec::new(),
+                })
+                .collect();
+            let args: Vec<_> = args
+                .iter()
+                .zip(&strings)
+                .map(|(arg, string)| match arg {
+                    Arg::Value(value) => RemoteArg::Value(*value as u64),
+                    Arg::Str(_) => RemoteArg::Bytes(string),
+                    Arg::Buffer { buffer } => RemoteArg::Buffer(*buffer),
+                })
+                .collect();
+            syscall
+                .syscall()
+                .and_then(|syscall| syscall_reply(task, syscall, &args))
+        }
+        Request::Call { function, args, .. } => {
+            call_reply(task, function, args)
+        }
+        Request::Dispatch {
+            pid,
+            syscall,
+            dispatch,
+        } => syscall
+            .syscall()
+            .and_then(|syscall| dispatch_reply(*pid, syscall, dispatch)),
+        Request::Detach { .. } => {
+            Err(Error::new(ErrorKind::InvalidInput, "served by scheduler"))
+        }
+    };
+    served.unwrap_or_else(|err| Reply::error(&err))
+}
+
+fn syscall_reply(
+    task: &TracedTask,
+    syscall: SyscallNo,
+    args: &[RemoteArg],
+) -> Result<Reply> {
+    match remote_syscall(task, syscall, args) {
+        Ok((retval, buffers)) => Ok(Reply {
+            ok: true,
+            retval: Some(retval),
+            buffers,
+            error: None,
+        }),
+        Err(err) => match err.raw_os_error() {
+            Some(errno) => Ok(Reply {
+                ok: true,
+                retval: Some(-i64::from(errno)),
+                ..Reply::default()
+            }),
+            None => Err(err),
+        },
+    }
+}
+
+fn call_reply(
+    task: &TracedTask,
+    function: &str,
+    args: &[u64],
+) -> Result<Reply> {
+    if args.len() > 6 {
+        return Err(Error::from_raw_os_error(libc::E2BIG));
+    }
+    let func = task.resolve_symbol_address(function).ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("unknown function: {}", function),
+        )
+    })?;
+    let mut values = [0u64; 6];
+    values[..args.len()].copy_from_slice(args);
+    let [a0, a1, a2, a3, a4, a5] = values;
+    task.inject_funcall(func, &SyscallArgs::from(a0, a1, a2, a3, a4, a5));
+    Ok(Reply {
+        ok: true,
+        ..Reply::default()
+    })
+}
+
+fn dispatch_reply(
+    pid: i32,
+    syscall: SyscallNo,
+    dispatch: &str,
+) -> Result<Reply> {
+    let dispatch = match dispatch {
+        "exec" => None,
+        "disabled" => Some(Dispatch::Disabled),
+        tier => Some(Dispatch::Tier(
+            tier.parse()
+                .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?,
+        )),
+    };
+    dispatch::set_dispatch(pid, syscall as i32, dispatch)?;
+    Ok(Reply {
+        ok: true,
+        ..Reply::default()
+    })
+}
+
+#[test]
+fn control_request_sanity_check() {
+    let request: Request = serde_json::from_str(
+        r#"{"command": "syscall", "pid": 1, "syscall": "SYS_write",
+            "args": [1, "hello", {"buffer": 8}]}"#,
+    )
+    .unwrap();
+    let expected = Request::Syscall {
+        pid: 1,
+        tid: None,
+        syscall: SyscallName::Name(String::from("SYS_write")),
+        args: vec![
+            Arg::Value(1),
+            Arg::Str(String::from("hello")),
+            Arg::Buffer { buffer: 8 },
+        ],
+    };
+    assert_eq!(request, expected);
+    let write = SyscallName::Name(String::from("SYS_write"));
+    assert_eq!(write.syscall().unwrap(), SyscallNo::SYS_write);
+    assert!(SyscallName::Nr(-1).syscall().is_err());
+    let request: Request = serde_json::from_str(
+        r#"{"command": "call", "pid": 1, "tid": 2, "function": "f"}"#,
+    )
+    .unwrap();
+    let target = (Pid::from_raw(1), Some(Pid::from_raw(2)));
+    assert_eq!(request.target(), Some(target));
+    let request: Request = serde_json::from_str(
+        r#"{"command": "dispatch", "pid": 1, "syscall": 0,
+            "dispatch": "disabled"}"#,
+    )
+    .unwrap();
+    assert_eq!(request.target(), Some((Pid::from_raw(1), None)));
+    let request: Request = serde_json::from_str(
+        r#"{"command": "detach", "session": "/tmp/reverie.session"}"#,
+    )
+    .unwrap();
+    assert_eq!(request.target(), None);
+    let reply = serde_json::to_string(&Reply::error(&"gone")).unwrap();
+    assert_eq!(reply, r#"{"ok":false,"error":"gone"}"#);
+}
@@ -0,0 +1,254 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! coverage collection by one-shot breakpoints, see `CoverageSpec`
+//!
+//! blocks of the modules covered get an `int3` when mapped executable: at
+//! exec for the program (and the modules mapped by the kernel), or when
+//! `mmap`ed for shared libraries, `mmap`s are stopped at exit then. the
+//! first hit of a block records it, and restores its original byte, so
+//! that each block traps once per process at most.
+//!
+//! blocks are function entries (from the ELF symbols) unless listed, i.e.:
+//! by a disassembler. breakpoints are inherited by forked children, which
+//! are covered as well.
+
+use goblin::elf::program_header::{PF_X, PT_LOAD};
+use goblin::elf::Elf;
+use nix::unistd::Pid;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use reverie_api::coverage::*;
+use reverie_api::emulate::TaskMemory;
+use reverie_api::mapping::MappingChange;
+use reverie_api::search::read_regions;
+
+const INT3: u8 = 0xcc;
+
+/// a block with a breakpoint
+#[derive(Debug, Clone, Copy)]
+struct Planted {
+    module: u16,
+    /// offset from the base of `module`
+    offset: u32,
+    size: u16,
+    /// original byte
+    saved: u8,
+}
+
+lazy_static! {
+    static ref COVERAGE: Mutex<Coverage> = Mutex::new(Coverage::new());
+    static ref PLANTED: Mutex<HashMap<Pid, HashMap<u64, Planted>>> =
+        Mutex::new(HashMap::new());
+    static ref FUNCTIONS: Mutex<HashMap<PathBuf, Vec<Block>>> =
+        Mutex::new(HashMap::new());
+}
+
+// entries of the functions of ELF `path`, by file offset
+fn function_blocks(path: &Path) -> Vec<Block> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(_) => return Vec::new(),
+    };
+    let elf = match Elf::parse(&bytes) {
+        Ok(elf) => elf,
+        Err(_) => return Vec::new(),
+    };
+    let mut offsets = BTreeSet::new();
+    for sym in elf.syms.iter().chain(elf.dynsyms.iter()) {
+        if !sym.is_function() || sym.st_value == 0 {
+            continue;
+        }
+        let vaddr = sym.st_value;
+        let segment = elf.program_headers.iter().find(|ph| {
+            ph.p_type == PT_LOAD
+                && ph.p_flags & PF_X != 0
+                && vaddr >= ph.p_vaddr
+                && vaddr < ph.p_vaddr + ph.p_filesz
+        });
+        if let Some(ph) = segment {
+            offsets.insert(vaddr - ph.p_vaddr + ph.p_offset);
+        }
+    }
+    offsets
+        .into_iter()
+        .map(|offset| Block { offset, size: 1 })
+        .collect()
+}
+
+// blocks of module `path`, listed or function entries
+fn blocks_of(spec: &CoverageSpec, path: &Path) -> Vec<Block> {
+    if let Some(blocks) = spec.blocks_of(path) {
+        return blocks.to_vec();
+    }
+    FUNCTIONS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .entry(path.to_path_buf())
+        .or_insert_with(|| function_blocks(path))
+        .clone()
+}
+
+// plant breakpoints in `start..end` of process `pid`, mapping module
+// `path` from file `offset`
+fn plant(
+    pid: Pid,
+    memory: &dyn TaskMemory,
+    spec: &CoverageSpec,
+    path: &Path,
+    (start, end, offset): (u64, u64, u64),
+) {
+    // the module is based where its file is mapped first
+    let regions = read_regions(pid).unwrap_or_default();
+    let module_regions = regions
+        .iter()
+        .filter(|region| Path::new(&region.path) == path);
+    let base = module_regions.clone().map(|region| region.start).min();
+    let module_end = module_regions.map(|region| region.end).max();
+    let (base, module_end) = match (base, module_end) {
+        (Some(base), Some(module_end)) => (base, module_end),
+        _ => return,
+    };
+    let module = COVERAGE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .module(path, base, module_end);
+    let mut planted = PLANTED.lock().unwrap_or_else(|e| e.into_inner());
+    let planted = planted.entry(pid).or_default();
+    let mut count = 0;
+    for block in blocks_of(spec, path) {
+        if block.offset < offset || block.offset - offset >= end - start {
+            continue;
+        }
+        let at = start + block.offset - offset;
+        // i.e.: a breakpoint of the tracer already
+        let saved = match memory.read_bytes(at, 1) {
+            Ok(bytes) if bytes[0] != INT3 => bytes[0],
+            _ => continue,
+        };
+        if memory.write_bytes(at, &[INT3]).is_err() {
+            continue;
+        }
+        let planted_block = Planted {
+            module,
+            offset: (at - base) as u32,
+            size: block.size,
+            saved,
+        };
+        planted.insert(at, planted_block);
+        count += 1;
+    }
+    log::debug!("[pid {}] coverage: {} blocks of {:?}", pid, count, path);
+}
+
+/// plant breakpoints in process `pid`, which just exec'ed
+pub fn exec_planted(pid: Pid, memory: &dyn TaskMemory, spec: &CoverageSpec) {
+    PLANTED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&pid);
+    for region in read_regions(pid).unwrap_or_default() {
+        let path = Path::new(&region.path);
+        if region.perms.contains('x') && spec.is_selected(path) {
+            let range = (region.start, region.end, region.offset);
+            plant(pid, memory, spec, path, range);
+        }
+    }
+}
+
+/// plant breakpoints in the mapping of `change`, with `regs` of the `mmap`
+/// exit stop
+pub fn mapped(
+    pid: Pid,
+    memory: &dyn TaskMemory,
+    spec: &CoverageSpec,
+    change: &MappingChange,
+    regs: &libc::user_regs_struct,
+) {
+    if let MappingChange::NewExecutableMapping {
+        addr,
+        size,
+        path: Some(path),
+        ..
+    } = change
+    {
+        if spec.is_selected(path) {
+            let range = (*addr, addr + size, regs.r9);
+            plant(pid, memory, spec, path, range);
+        }
+    }
+}
+
+/// whether the `int3` at `at` process `pid` trapped by is a block's, the
+/// block is recorded, and its original byte restored.
+pub fn hit(pid: Pid, memory: &dyn TaskMemory, at: u64) -> bool {
+    let planted =
+        match PLANTED.lock().unwrap_or_else(|e| e.into_inner()).get(&pid) {
+            Some(planted) => planted.get(&at).cloned(),
+            None => None,
+        };
+    let block = match planted {
+        Some(block) => block,
+        None => return false,
+    };
+    // planted blocks are kept once hit: a breakpoint of the tracer saved
+    // with ours may restore the `int3`.
+    match memory.read_bytes(at, 1) {
+        Ok(bytes) if bytes[0] == INT3 => (),
+        _ => return false,
+    }
+    if memory.write_bytes(at, &[block.saved]).is_err() {
+        return false;
+    }
+    let mut coverage = COVERAGE.lock().unwrap_or_else(|e| e.into_inner());
+    coverage.hit(block.module, block.offset, block.size);
+    true
+}
+
+/// `child` forked by `parent`, inheriting its breakpoints
+pub fn forked(parent: Pid, child: Pid) {
+    let mut planted = PLANTED.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(blocks) = planted.get(&parent).cloned() {
+        planted.insert(child, blocks);
+    }
+}
+
+/// process `pid` exited
+pub fn exited(pid: Pid) {
+    PLANTED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&pid);
+}
+
+/// write the coverage collected to `path`, as a drcov log, returns the
+/// number of blocks executed
+pub fn write_drcov(path: &Path) -> Result<usize> {
+    let coverage = COVERAGE.lock().unwrap_or_else(|e| e.into_inner());
+    coverage.write_drcov(File::create(path)?)?;
+    Ok(coverage.len())
+}
+
+#[test]
+fn function_blocks_sanity_check() {
+    let exe = std::fs::read_link("/proc/self/exe").unwrap();
+    let blocks = function_blocks(&exe);
+    assert!(!blocks.is_empty());
+    let size = std::fs::metadata(&exe).unwrap().len();
+    assert!(blocks.iter().all(|block| block.offset < size));
+    assert!(function_blocks(Path::new("/proc/self/status")).is_empty());
+}
@@ -16,10 +16,14 @@ use reverie_api::remote::*;
 use reverie_api::task::Task;
 
 use crate::traced_task::TracedTask;
+use goblin::elf::{program_header::PT_LOAD, Elf, Symtab};
+use goblin::strtab::Strtab;
 use log::debug;
 use nix::sys::ptrace;
 use nix::sys::signal;
 use nix::unistd::Pid;
+use procfs::process::MMapPath;
+use std::path::Path;
 
 // TODO: could check whether or not stack is valid
 fn show_stackframe(
@@ -80,7 +84,6 @@ fn show_user_regs(regs: &libc::user_regs_struct) -> String {
 }
 
 fn show_proc_maps(maps: &procfs::process::MemoryMap) -> String {
-    use procfs::process::MMapPath;
     let mut res = String::new();
     let fp = match &maps.pathname {
         MMapPath::Path(path) => String::from(path.to_str().unwrap_or("")),
@@ -124,6 +127,57 @@ fn task_rip_is_valid(task: &TracedTask, rip: u64) -> bool {
     has_valid_rip.is_some()
 }
 
+/// function symbol of ELF `path` covering file `offset`, and the offset
+/// into the symbol
+pub fn elf_symbol_at(path: &Path, offset: u64) -> Option<(String, u64)> {
+    let bytes = std::fs::read(path).ok()?;
+    let elf = Elf::parse(&bytes).ok()?;
+    let vaddr = elf
+        .program_headers
+        .iter()
+        .find(|ph| {
+            ph.p_type == PT_LOAD
+                && offset >= ph.p_offset
+                && offset < ph.p_offset + ph.p_filesz
+        })
+        .map(|ph| offset - ph.p_offset + ph.p_vaddr)?;
+    let lookup = |syms: &Symtab, strtab: &Strtab| {
+        let sym = syms.iter().find(|sym| {
+            sym.is_function()
+                && vaddr >= sym.st_value
+                && vaddr < sym.st_value + sym.st_size
+        })?;
+        let name = strtab.get(sym.st_name)?.ok()?;
+        Some((String::from(name), vaddr - sym.st_value))
+    };
+    lookup(&elf.syms, &elf.strtab)
+        .or_else(|| lookup(&elf.dynsyms, &elf.dynstrtab))
+}
+
+/// symbolize address `ip` of task `tid`, as `<file>!<symbol>+<offset>`, or
+/// `<file>+<offset>` when no symbol covers `ip`.
+pub fn symbolize(tid: Pid, ip: u64) -> String {
+    let maps = procfs::process::Process::new(tid.as_raw())
+        .and_then(|p| p.maps())
+        .unwrap_or_else(|_| Vec::new());
+    let entry =
+        match maps.iter().find(|e| ip >= e.address.0 && ip < e.address.1) {
+            Some(entry) => entry,
+            None => return format!("{:#x}", ip),
+        };
+    let path = match &entry.pathname {
+        MMapPath::Path(path) => path,
+        other => return format!("{:?}+{:#x}", other, ip - entry.address.0),
+    };
+    let offset = ip - entry.address.0 + entry.offset;
+    match elf_symbol_at(path, offset) {
+        Some((sym, delta)) => {
+            format!("{}!{}+{:#x}", path.display(), sym, delta)
+        }
+        None => format!("{}+{:#x}", path.display(), offset),
+    }
+}
+
 pub fn show_fault_context(task: &TracedTask, sig: signal::Signal) {
     let regs = task.getregs().unwrap();
     let siginfo = task.getsiginfo().unwrap();
@@ -0,0 +1,391 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! dependency extraction, for compilers and build systems
+//!
+//! `DepsRecorder` records the files processes exec, open, stat or rename:
+//! paths are resolved on syscall entry (as an emulator declining all
+//! syscalls, so that they are never patched), and recorded once the
+//! syscall succeeds (as an event sink).
+//!
+//! the manifest lists, for each program image (a process, from its fork up
+//! to its first exec, or from one exec to the next), the deduplicated files
+//! it read (`inputs`), wrote (`outputs`) and stat'ed (`probed`). files
+//! renamed are tracked: a temporary output renamed is only listed under
+//! its final name. paths are relative to the root directory, when under it.
+
+use nix::unistd::Pid;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{Result, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use syscalls::SyscallNo;
+
+use reverie_api::device::read_cstring;
+use reverie_api::emulate::*;
+use reverie_api::event::*;
+use reverie_api::remote::SyscallArgs;
+use reverie_api::task::Task;
+
+use crate::exec;
+use crate::paths::*;
+
+/// how a file is accessed by a syscall
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Exec,
+    Read,
+    Write,
+    Stat,
+    RenameFrom,
+    RenameTo,
+}
+
+// `(syscall, dirfd argument, path argument, access)`, `Read` for `open`s
+// whose flags decide. `Exec` paths are resolved by `exec::exec_path`.
+const ACCESSES: &[(SyscallNo, Option<usize>, usize, Access)] = &[
+    (SyscallNo::SYS_execve, None, 0, Access::Exec),
+    (SyscallNo::SYS_execveat, Some(0), 1, Access::Exec),
+    (SyscallNo::SYS_open, None, 0, Access::Read),
+    (SyscallNo::SYS_openat, Some(0), 1, Access::Read),
+    (SyscallNo::SYS_creat, None, 0, Access::Write),
+    (SyscallNo::SYS_stat, None, 0, Access::Stat),
+    (SyscallNo::SYS_lstat, None, 0, Access::Stat),
+    (SyscallNo::SYS_newfstatat, Some(0), 1, Access::Stat),
+    (SyscallNo::SYS_statx, Some(0), 1, Access::Stat),
+    (SyscallNo::SYS_access, None, 0, Access::Stat),
+    (SyscallNo::SYS_faccessat, Some(0), 1, Access::Stat),
+    (SyscallNo::SYS_rename, None, 0, Access::RenameFrom),
+    (SyscallNo::SYS_rename, None, 1, Access::RenameTo),
+    (SyscallNo::SYS_renameat, Some(0), 1, Access::RenameFrom),
+    (SyscallNo::SYS_renameat, Some(2), 3, Access::RenameTo),
+    (SyscallNo::SYS_renameat2, Some(0), 1, Access::RenameFrom),
+    (SyscallNo::SYS_renameat2, Some(2), 3, Access::RenameTo),
+];
+
+fn arg(args: &SyscallArgs, k: usize) -> u64 {
+    match k {
+        0 => args.arg0,
+        1 => args.arg1,
+        2 => args.arg2,
+        3 => args.arg3,
+        4 => args.arg4,
+        _ => args.arg5,
+    }
+}
+
+// access of `open` flags
+fn open_accesses(flags: i32) -> &'static [Access] {
+    let writes = flags & (libc::O_CREAT | libc::O_TRUNC) != 0;
+    match flags & libc::O_ACCMODE {
+        libc::O_RDONLY if !writes => &[Access::Read],
+        libc::O_RDWR => &[Access::Read, Access::Write],
+        _ => &[Access::Write],
+    }
+}
+
+/// files accessed by a program image
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProcessDeps {
+    pub pid: i32,
+    pub ppid: i32,
+    /// program exec'ed, `None` until the process execs
+    pub exe: Option<PathBuf>,
+    pub inputs: BTreeSet<PathBuf>,
+    pub outputs: BTreeSet<PathBuf>,
+    pub probed: BTreeSet<PathBuf>,
+}
+
+/// dependency manifest, see module doc
+#[derive(Debug, Clone, Serialize)]
+pub struct Manifest {
+    pub root: PathBuf,
+    pub processes: Vec<ProcessDeps>,
+}
+
+/// records files accessed by traced processes, see module doc
+pub struct DepsRecorder {
+    root: PathBuf,
+    processes: Vec<ProcessDeps>,
+    /// current program image of processes, in `processes`
+    current: HashMap<Pid, usize>,
+    /// process of tasks
+    pids: HashMap<Pid, Pid>,
+    /// accesses of syscalls entered, by tid
+    pending: HashMap<Pid, Vec<(Access, PathBuf)>>,
+}
+
+impl DepsRecorder {
+    /// recorder of paths relative to `root`
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        DepsRecorder {
+            root: normalize(root.as_ref()),
+            processes: Vec::new(),
+            current: HashMap::new(),
+            pids: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    // current program image of `pid`, child of `ppid`
+    fn process(&mut self, pid: Pid, ppid: Pid) -> usize {
+        let processes = &mut self.processes;
+        *self.current.entry(pid).or_insert_with(|| {
+            processes.push(ProcessDeps {
+                pid: pid.as_raw(),
+                ppid: ppid.as_raw(),
+                ..ProcessDeps::default()
+            });
+            processes.len() - 1
+        })
+    }
+
+    /// `syscall` entered by `task`, its paths are resolved
+    pub fn enter(
+        &mut self,
+        task: &dyn Task,
+        memory: &dyn TaskMemory,
+        syscall: SyscallNo,
+        args: &SyscallArgs,
+    ) {
+        let (pid, tid) = (task.getpid(), task.gettid());
+        self.pids.insert(tid, pid);
+        self.process(pid, task.getppid());
+        let mut accesses = Vec::new();
+        // exec by fd as well, i.e.: `fexecve`
+        if exec::is_exec_syscall(syscall) {
+            let path = exec::exec_path(pid, memory, syscall, args);
+            accesses.extend(path.map(|path| (Access::Exec, path)));
+        }
+        for (_, dirfd, path, access) in
+            ACCESSES.iter().filter(|(nr, _, _, access)| {
+                *nr == syscall && *access != Access::Exec
+            })
+        {
+            let path = match read_cstring(memory, arg(args, *path)) {
+                Ok(path) if !path.is_empty() => path,
+                _ => continue,
+            };
+            let dirfd = dirfd.map(|k| arg(args, k) as i32);
+            let path = absolute_path(pid, dirfd, &path);
+            let flags = match syscall {
+                SyscallNo::SYS_open => Some(args.arg1),
+                SyscallNo::SYS_openat => Some(args.arg2),
+                _ => None,
+            };
+            match flags {
+                Some(flags) => {
+                    for access in open_accesses(flags as i32) {
+                        accesses.push((*access, path.clone()));
+                    }
+                }
+                None => accesses.push((*access, path)),
+            }
+        }
+        if !accesses.is_empty() {
+            self.pending.insert(tid, accesses);
+        }
+    }
+
+    // syscall of `tid` succeeded
+    fn commit(&mut self, tid: Pid) {
+        let accesses = match self.pending.remove(&tid) {
+            Some(accesses) => accesses,
+            None => return,
+        };
+        let pid = self.pids.get(&tid).cloned().unwrap_or(tid);
+        let mut k = self.process(pid, pid);
+        for (access, path) in accesses {
+            if access == Access::Exec && self.processes[k].exe.is_some() {
+                // new program image
+                let ppid = Pid::from_raw(self.processes[k].ppid);
+                self.current.remove(&pid);
+                k = self.process(pid, ppid);
+            }
+            let process = &mut self.processes[k];
+            match access {
+                Access::Exec => {
+                    process.exe = Some(path.clone());
+                    process.inputs.insert(path);
+                }
+                Access::Read => {
+                    process.inputs.insert(path);
+                }
+                Access::Write | Access::RenameTo => {
+                    process.outputs.insert(path);
+                }
+                Access::Stat => {
+                    process.probed.insert(path);
+                }
+                Access::RenameFrom => {
+                    process.outputs.remove(&path);
+                }
+            }
+        }
+    }
+
+    /// record `event`: syscalls entered are recorded once succeeded
+    pub fn record_event(&mut self, event: &TimedEvent) {
+        match &event.event {
+            Event::SyscallExit(_, retval, _) if *retval >= 0 => {
+                self.commit(event.tid)
+            }
+            Event::SyscallExit(_, _, _) => {
+                self.pending.remove(&event.tid);
+            }
+            Event::Exec => self.commit(event.tid),
+            Event::Fork(child) | Event::Spawn(child) => {
+                let pid = self.pids.get(&event.tid).cloned();
+                let ppid = pid.unwrap_or(event.tid);
+                self.pids.insert(*child, *child);
+                self.process(*child, ppid);
+            }
+            Event::Clone(child) => {
+                let pid = self.pids.get(&event.tid).cloned();
+                self.pids.insert(*child, pid.unwrap_or(event.tid));
+            }
+            _ => (),
+        }
+    }
+
+    fn relative(&self, paths: &BTreeSet<PathBuf>) -> BTreeSet<PathBuf> {
+        paths
+            .iter()
+            .map(|path| {
+                path.strip_prefix(&self.root)
+                    .map(Path::to_path_buf)
+                    .unwrap_or_else(|_| path.clone())
+            })
+            .collect()
+    }
+
+    /// files accessed so far, relative to the root
+    pub fn manifest(&self) -> Manifest {
+        let processes = self
+            .processes
+            .iter()
+            .map(|process| ProcessDeps {
+                inputs: self.relative(&process.inputs),
+                outputs: self.relative(&process.outputs),
+                probed: self.relative(&process.probed),
+                ..process.clone()
+            })
+            .collect();
+        Manifest {
+            root: self.root.clone(),
+            processes,
+        }
+    }
+
+    /// write the manifest to `out`, as json
+    pub fn write_manifest<W: Write>(&self, out: W) -> Result<()> {
+        serde_json::to_writer_pretty(out, &self.manifest())?;
+        Ok(())
+    }
+}
+
+/// syscall emulation (declining all syscalls) and event sink recording
+/// into `recorder`
+pub fn deps_tracing(
+    recorder: Rc<RefCell<DepsRecorder>>,
+) -> (SyscallEmulation, EventSink) {
+    let mut syscalls: Vec<SyscallNo> = Vec::new();
+    for (syscall, _, _, _) in ACCESSES {
+        if !syscalls.contains(syscall) {
+            syscalls.push(*syscall);
+        }
+    }
+    let enter = recorder.clone();
+    let emulation = SyscallEmulation::new(
+        EmulationMode::Seccomp,
+        syscalls,
+        Box::new(move |task, memory, syscall, args| {
+            enter.borrow_mut().enter(task, memory, syscall, args);
+            None
+        }),
+    );
+    let sink: EventSink =
+        Box::new(move |event| recorder.borrow_mut().record_event(event));
+    (emulation, sink)
+}
+
+#[test]
+fn deps_recorder_sanity_check() {
+    let mut recorder = DepsRecorder::new("/src");
+    let (pid, child) = (Pid::from_raw(10), Pid::from_raw(11));
+    let event = |tid, event| TimedEvent {
+        tid,
+        at: Default::default(),
+        ticks: None,
+        event,
+    };
+    let exit = |tid, retval| {
+        let exit = Event::SyscallExit(
+            SyscallNo::SYS_openat,
+            retval,
+            Default::default(),
+        );
+        event(tid, exit)
+    };
+    recorder.record_event(&event(pid, Event::Fork(child)));
+    recorder
+        .pending
+        .insert(child, vec![(Access::Write, PathBuf::from("/src/a.o.tmp"))]);
+    recorder.record_event(&exit(child, 3));
+    recorder.pending.insert(
+        child,
+        vec![
+            (Access::RenameFrom, PathBuf::from("/src/a.o.tmp")),
+            (Access::RenameTo, PathBuf::from("/src/a.o")),
+        ],
+    );
+    recorder.record_event(&exit(child, 0));
+    recorder
+        .pending
+        .insert(child, vec![(Access::Read, PathBuf::from("/src/missing.h"))]);
+    recorder.record_event(&exit(child, -libc::ENOENT as i64));
+    recorder
+        .pending
+        .insert(child, vec![(Access::Exec, PathBuf::from("/usr/bin/cc"))]);
+    recorder.record_event(&event(child, Event::Exec));
+    recorder
+        .pending
+        .insert(child, vec![(Access::Read, PathBuf::from("/src/a.c"))]);
+    recorder.record_event(&exit(child, 3));
+    recorder.record_event(&exit(child, 3));
+
+    let manifest = recorder.manifest();
+    assert_eq!(manifest.processes.len(), 1);
+    let process = &manifest.processes[0];
+    assert_eq!((process.pid, process.ppid), (11, 10));
+    assert_eq!(process.exe, Some(PathBuf::from("/usr/bin/cc")));
+    let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect();
+    assert_eq!(process.outputs, paths(&["a.o"]));
+    assert_eq!(process.inputs, paths(&["/usr/bin/cc", "a.c"]));
+    assert_eq!(
+        open_accesses(libc::O_WRONLY | libc::O_CREAT),
+        &[Access::Write]
+    );
+    assert_eq!(open_accesses(libc::O_RDONLY), &[Access::Read]);
+
+    let mut exec = DepsRecorder::new("/");
+    exec.pending
+        .insert(pid, vec![(Access::Exec, PathBuf::from("/bin/sh"))]);
+    exec.record_event(&event(pid, Event::Exec));
+    exec.pending
+        .insert(pid, vec![(Access::Exec, PathBuf::from("/bin/make"))]);
+    exec.record_event(&event(pid, Event::Exec));
+    assert_eq!(exec.manifest().processes.len(), 2);
+}
@@ -0,0 +1,89 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! clean detach, on a signal of the tracer
+//!
+//! once the tracer receives the signal (`--detach-on`, i.e.: `SIGUSR2`),
+//! the scheduler detaches the tasks at its next stop, which keep running
+//! untraced, see `SchedWait::detach_all_running`:
+//!
+//! - breakpoints are removed, their instructions restored.
+//! - a syscall skipped at its sysemu entry is rewound, to be run.
+//! - a signal the task is stopped at is delivered.
+//!
+//! syscall sites patched stay patched: they call the preloaded hooks,
+//! whose untraced syscalls run as is. seccomp filters however can't be
+//! removed, and syscalls they trap would fail with `ENOSYS` without a
+//! tracer: processes with a filter (those launched, unlike attached by
+//! `reverie attach` or `strace -p`) are kept traced, but passed through
+//! (see `passthrough`), exec included: their syscalls are resumed as is,
+//! neither emulated nor reported. the tracer exits once they exit.
+
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::Pid;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// why processes kept traced are passed through, see `Process::passthrough`
+pub const DETACHED: &str = "seccomp filter, detached";
+
+static DETACH: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn detach_requested(_sig: libc::c_int) {
+    DETACH.store(true, Ordering::SeqCst);
+}
+
+/// detach tasks once the tracer receives `sig`
+pub fn detach_on(sig: Signal) {
+    let action = SigAction::new(
+        SigHandler::Handler(detach_requested),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    if let Err(err) = unsafe { signal::sigaction(sig, &action) } {
+        log::warn!("[detach] cannot handle {}: {:?}", sig, err);
+    }
+}
+
+/// whether tasks are to be detached, once
+pub fn take_detach() -> bool {
+    DETACH.swap(false, Ordering::SeqCst)
+}
+
+// seccomp mode of a `/proc/PID/status`, 2 being filtered
+fn seccomp_mode(status: &str) -> Option<u32> {
+    status
+        .lines()
+        .find(|line| line.starts_with("Seccomp:"))
+        .and_then(|line| line["Seccomp:".len()..].trim().parse().ok())
+}
+
+/// whether process `pid` has a seccomp filter, which can't be detached
+pub fn is_filtered(pid: Pid) -> bool {
+    fs::read_to_string(format!("/proc/{}/status", pid))
+        .ok()
+        .and_then(|status| seccomp_mode(&status))
+        .map_or(false, |mode| mode == libc::SECCOMP_MODE_FILTER as u32)
+}
+
+#[test]
+fn detach_sanity_check() {
+    let status = "Name:\tcat\nNoNewPrivs:\t1\nSeccomp:\t2\nSpeculation:\t\n";
+    assert_eq!(seccomp_mode(status), Some(2));
+    assert_eq!(seccomp_mode("Name:\tcat\n"), None);
+    assert!(!is_filtered(Pid::from_raw(1 << 30)));
+    assert!(!take_detach());
+    detach_requested(0);
+    assert!(take_detach());
+    assert!(!take_detach());
+}
@@ -0,0 +1,157 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! directory listing virtualization
+//!
+//! when a `DirentFilterFn` is set, `getdents64` is never patched, so that
+//! it always stops at syscall exit, where the entries returned are passed
+//! to the filter, written back and the size returned rewritten. entries
+//! which don't fit the buffer of the tracee are kept by the tracer, and
+//! returned first by the next `getdents64` of the same fd. a `getdents64`
+//! whose entries are all hidden, short of the end, is restarted: 0 bytes
+//! returned are the end of the directory.
+
+use lazy_static::lazy_static;
+use nix::unistd::Pid;
+use std::collections::{HashMap, HashSet};
+use std::io::Result;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use syscalls::*;
+
+use reverie_api::dirents::*;
+use reverie_api::emulate::TaskMemory;
+use reverie_api::remote::Ptracer;
+use reverie_api::task::Task;
+use reverie_common::consts;
+
+use crate::traced_task::TracedTask;
+
+lazy_static! {
+    /// entries of (pid, fd) not returned yet
+    static ref PENDING: Mutex<HashMap<(Pid, i32), Vec<Dirent>>> =
+        Mutex::new(HashMap::new());
+    /// (pid, fd) read to their end, seen by the filter
+    static ref ENDED: Mutex<HashSet<(Pid, i32)>> = Mutex::new(HashSet::new());
+}
+
+/// syscalls to be stopped (and never patched) when a filter is set
+pub fn is_getdents_syscall(syscall: SyscallNo) -> bool {
+    syscall == SYS_getdents64
+}
+
+// `entries` fitting in `count` bytes, the others are left
+fn take_fitting(entries: &mut Vec<Dirent>, count: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let fits = entries
+        .iter()
+        .take_while(|e| {
+            let fit = bytes.len() + e.reclen() <= count;
+            if fit {
+                bytes.extend(e.encode());
+            }
+            fit
+        })
+        .count();
+    entries.drain(..fits);
+    bytes
+}
+
+/// pass entries returned by a (successful) `getdents64` to `filter`, write
+/// back those left, and return their size. `regs` are from the syscall
+/// exit stop.
+pub fn filter_dirents(
+    task: &TracedTask,
+    regs: &libc::user_regs_struct,
+    filter: &mut DirentFilterFn,
+) -> Result<()> {
+    let retval = regs.rax as i64;
+    if retval < 0 {
+        return Ok(());
+    }
+    // getdents64(fd, *dirp, count), returns bytes read
+    let fd = regs.rdi as i32;
+    let (dirp, count) = (regs.rsi, regs.rdx as usize);
+    let key = (task.getpid(), fd);
+    let bytes = task.read_bytes(dirp, retval as usize)?;
+    let mut batch = Dirent::decode(&bytes);
+    let end = batch.is_empty();
+    // the end of the directory is seen by the filter once, until read again
+    let ended = if end {
+        !ENDED.lock().unwrap_or_else(|e| e.into_inner()).insert(key)
+    } else {
+        ENDED.lock().unwrap_or_else(|e| e.into_inner()).remove(&key);
+        false
+    };
+    if !ended {
+        let dir = std::fs::read_link(format!("/proc/{}/fd/{}", key.0, fd))
+            .unwrap_or_else(|_| PathBuf::from("?"));
+        let last_off = batch.last().map_or(0, |e| e.off);
+        filter(task, &dir, &mut batch, end);
+        let mut off = last_off;
+        for e in batch.iter_mut() {
+            if e.off == 0 {
+                e.off = off;
+            }
+            off = e.off;
+        }
+    }
+    let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+    let mut entries = pending.remove(&key).unwrap_or_default();
+    entries.append(&mut batch);
+    let out = take_fitting(&mut entries, count);
+    let mut new_regs = *regs;
+    if out.is_empty() && !entries.is_empty() {
+        new_regs.rax = -i64::from(libc::EINVAL) as u64;
+    } else if out.is_empty() && !end {
+        new_regs.rax = regs.orig_rax;
+        new_regs.rip -= consts::SYSCALL_INSN_SIZE as u64;
+    } else {
+        task.write_bytes(dirp, &out)?;
+        new_regs.rax = out.len() as u64;
+    }
+    if !entries.is_empty() {
+        pending.insert(key, entries);
+    }
+    if new_regs.rax != regs.rax {
+        task.setregs(new_regs)?;
+    }
+    Ok(())
+}
+
+/// process `pid` exited, its pending entries are dropped
+pub fn exited(pid: Pid) {
+    PENDING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|&(p, _), _| p != pid);
+    ENDED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|&(p, _)| p != pid);
+}
+
+#[test]
+fn dirent_filter_sanity_check() {
+    let mut entries = vec![
+        Dirent::new(1, DT_DIR, "."),
+        Dirent::new(2, DT_DIR, ".."),
+        Dirent::new(3, DT_REG, "a-long-file-name"),
+    ];
+    let bytes = take_fitting(&mut entries, 60);
+    assert_eq!(bytes.len(), 48);
+    assert_eq!(entries.len(), 1);
+    assert!(take_fitting(&mut entries, 16).is_empty());
+    assert_eq!(take_fitting(&mut entries, 40).len(), 40);
+    assert!(entries.is_empty());
+}
@@ -0,0 +1,523 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! host checks, of `reverie doctor`
+//!
+//! whether the kernel and its configuration support tracing: ptrace and
+//! seccomp (required), user, pid and time namespaces (required by some
+//! options only).
+//!
+//! in containers, the runtime restricts reverie further: the default
+//! seccomp profiles of docker and kubernetes deny `unshare` (hence
+//! namespaces) without `CAP_SYS_ADMIN`, and may deny `memfd_create`,
+//! `CAP_SYS_PTRACE` is dropped, parts of `/proc` are masked and the limit
+//! of open files may be low. `probe` tells what is available, features
+//! not available are downgraded at startup (see `Probe::downgrades`) and
+//! reported, rather than failing midway.
+
+use std::fmt;
+use std::path::Path;
+
+use reverie_common::consts;
+
+use crate::nesting;
+
+/// minimal kernel version: seccomp stops before syscall-enter stops
+pub const MIN_KERNEL_VERSION: (u32, u32) = (4, 8);
+
+/// outcome of a check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    /// some options won't work
+    Warn,
+    /// reverie won't work
+    Fail,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn new<S: Into<String>>(
+        name: &'static str,
+        status: Status,
+        detail: S,
+    ) -> Self {
+        Check {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self.status {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "FAIL",
+        };
+        write!(f, "[{:>4}] {}: {}", status, self.name, self.detail)
+    }
+}
+
+/// `(major, minor)` of kernel `release`, i.e.: `5.4.0-42-generic`
+pub fn kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut iter = release.split(|c: char| !c.is_ascii_digit());
+    let major = iter.next()?.parse().ok()?;
+    let minor = iter.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+fn read_trimmed<P: AsRef<Path>>(path: P) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+// field `key` of /proc/self/status
+fn self_status(key: &str) -> Option<String> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    nesting::status_field(&status, key)
+}
+
+fn check_kernel() -> Check {
+    let release = nix::sys::utsname::uname().release().to_string();
+    match kernel_version(&release) {
+        Some(version) if version >= MIN_KERNEL_VERSION => {
+            Check::new("kernel", Status::Ok, release)
+        }
+        _ => Check::new(
+            "kernel",
+            Status::Fail,
+            format!(
+                "{}, linux {}.{}+ is required",
+                release, MIN_KERNEL_VERSION.0, MIN_KERNEL_VERSION.1
+            ),
+        ),
+    }
+}
+
+/// check of yama `ptrace_scope`, `None` if yama is not enabled
+pub fn check_ptrace_scope(scope: Option<&str>) -> Check {
+    match scope {
+        None | Some("0") | Some("1") => {
+            Check::new("ptrace", Status::Ok, "children can be traced")
+        }
+        Some("2") => Check::new(
+            "ptrace",
+            Status::Warn,
+            "kernel.yama.ptrace_scope is 2, CAP_SYS_PTRACE is required",
+        ),
+        Some(scope) => Check::new(
+            "ptrace",
+            Status::Fail,
+            format!("kernel.yama.ptrace_scope is {}, ptrace disabled", scope),
+        ),
+    }
+}
+
+fn check_tracer() -> Check {
+    match nesting::outer_tracer() {
+        None => Check::new("tracer", Status::Ok, "not traced"),
+        Some(tracer) => Check::new(
+            "tracer",
+            Status::Warn,
+            format!("traced by {}, which must not follow forks", tracer),
+        ),
+    }
+}
+
+fn check_seccomp() -> Check {
+    match self_status("Seccomp").as_deref() {
+        None => Check::new("seccomp", Status::Fail, "not supported"),
+        Some("0") => Check::new("seccomp", Status::Ok, "supported"),
+        Some(_) => Check::new(
+            "seccomp",
+            Status::Warn,
+            "already filtered, filters of reverie are stacked",
+        ),
+    }
+}
+
+// whether user namespaces can be created, by a child
+fn user_ns_available() -> bool {
+    let cloneable = read_trimmed("/proc/sys/kernel/unprivileged_userns_clone");
+    let max = read_trimmed("/proc/sys/user/max_user_namespaces");
+    if cloneable.as_deref() == Some("0") || max.as_deref() == Some("0") {
+        return false;
+    }
+    // denied by seccomp profiles of container runtimes
+    match unsafe { libc::fork() } {
+        0 => unsafe {
+            libc::_exit((libc::unshare(libc::CLONE_NEWUSER) != 0) as i32)
+        },
+        -1 => false,
+        child => {
+            let mut status = 0;
+            unsafe { libc::waitpid(child, &mut status, 0) };
+            libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+        }
+    }
+}
+
+fn check_user_ns(probe: &Probe) -> Check {
+    if !probe.user_ns {
+        Check::new(
+            "user namespaces",
+            Status::Warn,
+            "disabled, --with-namespace and --hermetic are unavailable",
+        )
+    } else {
+        Check::new("user namespaces", Status::Ok, "supported")
+    }
+}
+
+fn check_time_ns(probe: &Probe) -> Check {
+    if probe.time_ns {
+        Check::new("time namespaces", Status::Ok, "supported")
+    } else {
+        Check::new(
+            "time namespaces",
+            Status::Warn,
+            "not supported (linux 5.6+), --ns time is unavailable",
+        )
+    }
+}
+
+/// container runtime reverie runs in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Container {
+    Docker,
+    Kubernetes,
+    Podman,
+    /// as of env var `container`, i.e.: `lxc` or `systemd-nspawn`
+    Other(String),
+}
+
+impl fmt::Display for Container {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Container::Docker => write!(f, "docker"),
+            Container::Kubernetes => write!(f, "kubernetes"),
+            Container::Podman => write!(f, "podman"),
+            Container::Other(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// container runtime reverie runs in, if any
+pub fn container() -> Option<Container> {
+    if std::env::var_os("KUBERNETES_SERVICE_HOST").is_some() {
+        Some(Container::Kubernetes)
+    } else if Path::new("/.dockerenv").exists() {
+        Some(Container::Docker)
+    } else if Path::new("/run/.containerenv").exists() {
+        Some(Container::Podman)
+    } else {
+        std::env::var("container").ok().map(Container::Other)
+    }
+}
+
+/// `CAP_SYS_PTRACE`, to trace processes but children
+const CAP_SYS_PTRACE: u32 = 19;
+
+fn has_cap_sys_ptrace() -> bool {
+    self_status("CapEff")
+        .and_then(|caps| u64::from_str_radix(&caps, 16).ok())
+        .map_or(false, |caps| caps & (1 << CAP_SYS_PTRACE) != 0)
+}
+
+// whether memory of tasks can be accessed by `/proc/<pid>/mem`
+fn proc_mem_available() -> bool {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/proc/self/mem")
+        .is_ok()
+}
+
+fn memfd_available() -> bool {
+    let name = std::ffi::CStr::from_bytes_with_nul(b"reverie-probe\0").unwrap();
+    match nix::sys::memfd::memfd_create(
+        name,
+        nix::sys::memfd::MemFdCreateFlag::empty(),
+    ) {
+        Ok(fd) => {
+            let _ = nix::unistd::close(fd);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// hard limit of open files, `None` if unlimited
+fn nofile_limit() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0
+        || limit.rlim_max == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+    Some(limit.rlim_max)
+}
+
+/// features of the host, as probed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Probe {
+    pub container: Option<Container>,
+    pub cap_sys_ptrace: bool,
+    pub user_ns: bool,
+    pub time_ns: bool,
+    pub memfd: bool,
+    pub proc_mem: bool,
+    /// whether fixed fds of reverie (up to `REVERIE_GLOBAL_STATE_FD`) are
+    /// below the hard limit of open files
+    pub fixed_fds: bool,
+}
+
+/// probe features of the host
+pub fn probe() -> Probe {
+    let fixed_fd = consts::REVERIE_GLOBAL_STATE_FD as u64;
+    Probe {
+        container: container(),
+        cap_sys_ptrace: has_cap_sys_ptrace(),
+        user_ns: user_ns_available(),
+        time_ns: Path::new("/proc/self/ns/time").exists(),
+        memfd: memfd_available(),
+        proc_mem: proc_mem_available(),
+        fixed_fds: nofile_limit().map_or(true, |limit| limit > fixed_fd),
+    }
+}
+
+/// a feature downgraded for the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Downgrade {
+    pub feature: &'static str,
+    pub reason: String,
+}
+
+impl fmt::Display for Downgrade {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.feature, self.reason)
+    }
+}
+
+impl Probe {
+    // why a feature is not available, `what` being missing
+    fn reason(&self, what: &str) -> String {
+        match &self.container {
+            Some(container) => format!("{} (in {})", what, container),
+            None => String::from(what),
+        }
+    }
+
+    /// downgrades of features not available, of namespaces (`--with-
+    /// namespace`) and time namespaces (`--ns time`) if wanted. features
+    /// downgraded are turned off by the caller.
+    pub fn downgrades(
+        &self,
+        namespaces: bool,
+        time_ns: bool,
+    ) -> Vec<Downgrade> {
+        let mut res = Vec::new();
+        if namespaces && !self.user_ns {
+            res.push(Downgrade {
+                feature: "namespaces",
+                reason: self.reason("user namespaces can't be created"),
+            });
+        }
+        if time_ns && !self.time_ns {
+            res.push(Downgrade {
+                feature: "time namespace",
+                reason: self.reason("time namespaces not supported"),
+            });
+        }
+        if !self.memfd {
+            res.push(Downgrade {
+                feature: "global state memfd",
+                reason: self.reason("memfd_create denied, a temporary file"),
+            });
+        }
+        res
+    }
+}
+
+fn check_container(probe: &Probe) -> Check {
+    match &probe.container {
+        None => Check::new("container", Status::Ok, "none"),
+        Some(container) => Check::new(
+            "container",
+            Status::Warn,
+            format!(
+                "{}, its seccomp profile and capabilities restrict reverie",
+                container
+            ),
+        ),
+    }
+}
+
+fn check_cap_sys_ptrace(probe: &Probe) -> Check {
+    if probe.cap_sys_ptrace {
+        Check::new("CAP_SYS_PTRACE", Status::Ok, "any process can be traced")
+    } else {
+        Check::new(
+            "CAP_SYS_PTRACE",
+            Status::Warn,
+            "dropped, reverie attach may be denied",
+        )
+    }
+}
+
+fn check_proc(probe: &Probe) -> Check {
+    if probe.proc_mem {
+        Check::new("/proc", Status::Ok, "/proc/pid/mem accessible")
+    } else {
+        Check::new("/proc", Status::Fail, "/proc/pid/mem is masked or denied")
+    }
+}
+
+fn check_memfd(probe: &Probe) -> Check {
+    if probe.memfd {
+        Check::new("memfd", Status::Ok, "supported")
+    } else {
+        Check::new(
+            "memfd",
+            Status::Warn,
+            "memfd_create denied, global state in a temporary file",
+        )
+    }
+}
+
+fn check_fixed_fds(probe: &Probe) -> Check {
+    if probe.fixed_fds {
+        Check::new("open files", Status::Ok, "fixed fds of reverie allowed")
+    } else {
+        Check::new(
+            "open files",
+            Status::Fail,
+            format!(
+                "hard limit below fd {}, raise ulimit -n",
+                consts::REVERIE_GLOBAL_STATE_FD
+            ),
+        )
+    }
+}
+
+/// run all checks
+pub fn run_checks() -> Vec<Check> {
+    let scope = read_trimmed("/proc/sys/kernel/yama/ptrace_scope");
+    let probe = probe();
+    vec![
+        check_kernel(),
+        check_ptrace_scope(scope.as_deref()),
+        check_tracer(),
+        check_seccomp(),
+        check_user_ns(&probe),
+        check_time_ns(&probe),
+        check_container(&probe),
+        check_cap_sys_ptrace(&probe),
+        check_proc(&probe),
+        check_memfd(&probe),
+        check_fixed_fds(&probe),
+    ]
+}
+
+#[test]
+fn doctor_sanity_check() {
+    assert_eq!(kernel_version("5.4.0-42-generic"), Some((5, 4)));
+    assert_eq!(kernel_version("4.19.112+"), Some((4, 19)));
+    assert_eq!(kernel_version("foo"), None);
+    assert_eq!(check_ptrace_scope(None).status, Status::Ok);
+    assert_eq!(check_ptrace_scope(Some("2")).status, Status::Warn);
+    assert_eq!(check_ptrace_scope(Some("3")).status, Status::Fail);
+    let checks = run_checks();
+    assert_eq!(checks.len(), 11);
+    assert_eq!(checks[0].name, "kernel");
+    assert!(checks[0].to_string().starts_with("["));
+    let probe = Probe {
+        container: Some(Container::Docker),
+        cap_sys_ptrace: false,
+        user_ns: false,
+        time_ns: true,
+        memfd: true,
+        proc_mem: true,
+        fixed_fds: true,
+    };
+    let downgrades = probe.downgrades(true, true);
+    assert_eq!(downgrades.len(), 1);
+    assert_eq!(
+        downgrades[0].to_string(),
+        "namespaces: user namespaces can't be created (in docker)"
+    );
+    assert!(probe.downgrades(false, true).is_empty());
+}
+
+#[test]
+fn doctor_checks_sanity_check() {
+    let probe = Probe {
+        container: None,
+        cap_sys_ptrace: true,
+        user_ns: true,
+        time_ns: true,
+        memfd: true,
+        proc_mem: true,
+        fixed_fds: true,
+    };
+    let checks = |probe: &Probe| {
+        vec![
+            check_user_ns(probe),
+            check_time_ns(probe),
+            check_container(probe),
+            check_cap_sys_ptrace(probe),
+            check_proc(probe),
+            check_memfd(probe),
+            check_fixed_fds(probe),
+        ]
+    };
+    assert!(checks(&probe)
+        .iter()
+        .all(|check| check.status == Status::Ok));
+    assert!(probe.downgrades(true, true).is_empty());
+    let restricted = Probe {
+        container: Some(Container::Podman),
+        cap_sys_ptrace: false,
+        user_ns: false,
+        time_ns: false,
+        memfd: false,
+        proc_mem: false,
+        fixed_fds: false,
+    };
+    let status: Vec<_> = checks(&restricted)
+        .iter()
+        .map(|check| check.status)
+        .collect();
+    use Status::{Fail, Warn};
+    assert_eq!(status, vec![Warn, Warn, Warn, Warn, Fail, Warn, Fail]);
+    assert!(check_container(&restricted).detail.starts_with("podman"));
+    let features: Vec<_> = restricted
+        .downgrades(true, false)
+        .iter()
+        .map(|downgrade| downgrade.feature)
+        .collect();
+    assert_eq!(features, vec!["namespaces", "global state memfd"]);
+}
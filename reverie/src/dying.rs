@@ -0,0 +1,124 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! ptrace races with task death
+//!
+//! a tracee can be killed (i.e.: `SIGKILL` sent by the kernel when a sibling
+//! thread called `exit_group`) between a ptrace stop and our next ptrace
+//! request, which then fails with `ESRCH`, or our next `waitpid` returns
+//! the task's exit instead of the expected stop. such errors are ignored for
+//! tasks known to be dying. for other tasks `ESRCH`/`EPERM` is retried a
+//! few times, i.e.: a new child not yet in its initial ptrace stop.
+
+use nix::errno::Errno;
+use nix::sys::wait::WaitStatus;
+use nix::sys::{ptrace, signal};
+use nix::unistd::Pid;
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind};
+use std::sync::Mutex;
+
+/// max retries of a failed ptrace request against a live task
+const PTRACE_RETRIES: usize = 16;
+
+lazy_static! {
+    static ref DYING: Mutex<HashSet<Pid>> = Mutex::new(HashSet::new());
+}
+
+/// task (or thread group) `pid` is about to exit
+pub fn mark_dying(pid: Pid) {
+    DYING.lock().unwrap_or_else(|e| e.into_inner()).insert(pid);
+}
+
+/// task `pid` has been reaped, its pid can be reused
+pub fn reaped(pid: Pid) {
+    DYING.lock().unwrap_or_else(|e| e.into_inner()).remove(&pid);
+}
+
+/// task `tid` of thread group `tgid` is known to be dying (or gone)
+pub fn is_dying(tgid: Pid, tid: Pid) -> bool {
+    {
+        let dying = DYING.lock().unwrap_or_else(|e| e.into_inner());
+        if dying.contains(&tid) || dying.contains(&tgid) {
+            return true;
+        }
+    }
+    // killed without us noticing, i.e.: `exit_group` from patched syscall.
+    match procfs::process::Process::new(tid.as_raw()) {
+        Ok(p) => p.stat.state == 'Z' || p.stat.state == 'X',
+        Err(_) => true,
+    }
+}
+
+/// run ptrace request `f` against task `tid` of thread group `tgid`,
+/// retrying on `ESRCH`/`EPERM`. returns `Ok(None)` if the task is dying.
+pub fn retry_ptrace<T, F>(
+    tgid: Pid,
+    tid: Pid,
+    mut f: F,
+) -> nix::Result<Option<T>>
+where
+    F: FnMut() -> nix::Result<T>,
+{
+    let mut retries = 0;
+    loop {
+        match f() {
+            Ok(res) => return Ok(Some(res)),
+            Err(nix::Error::Sys(errno))
+                if errno == Errno::ESRCH || errno == Errno::EPERM =>
+            {
+                if is_dying(tgid, tid) {
+                    log::debug!("[dying] {} ignored ptrace {:?}", tid, errno);
+                    return Ok(None);
+                }
+                if retries >= PTRACE_RETRIES {
+                    return Err(nix::Error::Sys(errno));
+                }
+                retries += 1;
+                std::thread::yield_now();
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// `waitpid` of task `tid` returned `status` rather than the expected stop.
+/// if `status` says the task is dying, it is marked as such, and resumed if
+/// it is in `PTRACE_EVENT_EXIT` stop, so that it can be reaped later.
+///
+/// statuses unexpected for other reasons are `InvalidData` errors: as any
+/// error handling a task, the task is then assumed killed, see
+/// `sched_wait`.
+pub fn unexpected_status(tid: Pid, status: nix::Result<WaitStatus>) -> Error {
+    match status {
+        Ok(WaitStatus::PtraceEvent(_, signal::SIGTRAP, 6)) => {
+            mark_dying(tid);
+            let _ = ptrace::cont(tid, None);
+        }
+        Ok(WaitStatus::Exited(..)) | Ok(WaitStatus::Signaled(..)) => {
+            reaped(tid);
+        }
+        Err(nix::Error::Sys(Errno::ECHILD)) => (),
+        otherwise => {
+            log::warn!("[dying] waitpid({}): unexpected {:?}", tid, otherwise);
+            return Error::new(
+                ErrorKind::InvalidData,
+                format!("waitpid({}): unexpected status {:?}", tid, otherwise),
+            );
+        }
+    }
+    Error::new(
+        ErrorKind::Other,
+        format!("task {} exited unexpectedly: {:?}", tid, status),
+    )
+}
@@ -0,0 +1,733 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! ebpf co-processor, of `--bpf-counters`
+//!
+//! tracees are moved to a cgroup (v2) of their own, and two raw
+//! tracepoint programs on `sys_enter` and `sys_exit`, filtered to that
+//! cgroup, count syscalls (and their failures) in-kernel, in array maps,
+//! without any ptrace stop. one in `sample_every` syscalls has its
+//! arguments sampled, and sent to the tracer by a ring buffer, drained by
+//! the scheduler loop (`poll`).
+//!
+//! programs are assembled here, no compiler needed, but they need linux
+//! 5.8 (ring buffers), cgroup v2, and `CAP_BPF`/`CAP_PERFMON` (or root).
+//! the tracer runs without them otherwise.
+
+use nix::unistd::{self, Pid};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fs;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+use syscalls::SyscallNo;
+
+use reverie_api::lies::syscall_name;
+
+/// syscalls counted, by number
+pub const MAX_SYSCALLS: u32 = 512;
+
+/// size of the ring buffer of samples
+pub const RING_SIZE: usize = 0x4_0000;
+
+/// samples kept for the report, the most recent
+pub const SAMPLES_KEPT: usize = 64;
+
+/// syscalls sampled, one in
+pub const DEFAULT_SAMPLE_EVERY: u32 = 1024;
+
+// `bpf` commands
+const BPF_MAP_CREATE: i32 = 0;
+const BPF_MAP_LOOKUP_ELEM: i32 = 1;
+const BPF_PROG_LOAD: i32 = 5;
+const BPF_RAW_TRACEPOINT_OPEN: i32 = 17;
+
+const BPF_MAP_TYPE_ARRAY: u32 = 2;
+const BPF_MAP_TYPE_RINGBUF: u32 = 27;
+const BPF_PROG_TYPE_RAW_TRACEPOINT: u32 = 17;
+
+// helpers
+const MAP_LOOKUP_ELEM: i32 = 1;
+const PROBE_READ: i32 = 4;
+const GET_PRANDOM_U32: i32 = 7;
+const GET_CURRENT_PID_TGID: i32 = 14;
+const GET_CURRENT_CGROUP_ID: i32 = 80;
+const RINGBUF_OUTPUT: i32 = 130;
+
+// offsets in `struct pt_regs` of syscall arguments, and `orig_ax`
+const PT_REGS_ARGS: [i16; 6] = [112, 104, 96, 56, 72, 64];
+const PT_REGS_ORIG_AX: i16 = 120;
+
+// ring buffer record header bits
+const RINGBUF_BUSY: u32 = 1 << 31;
+const RINGBUF_DISCARD: u32 = 1 << 30;
+const RINGBUF_HEADER: usize = 8;
+
+/// size of a sample: tid, syscall, 6 arguments
+const SAMPLE_SIZE: usize = 56;
+
+/// an ebpf instruction, as of `struct bpf_insn`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Insn {
+    pub code: u8,
+    pub dst: u8,
+    pub src: u8,
+    pub off: i16,
+    pub imm: i32,
+}
+
+impl Insn {
+    fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+        Insn {
+            code,
+            dst,
+            src,
+            off,
+            imm,
+        }
+    }
+
+    /// encoded, as loaded by the kernel
+    pub fn encode(&self) -> u64 {
+        u64::from(self.code)
+            | u64::from(self.dst & 0xf | self.src << 4) << 8
+            | u64::from(self.off as u16) << 16
+            | u64::from(self.imm as u32) << 32
+    }
+}
+
+// registers
+const R0: u8 = 0;
+const R1: u8 = 1;
+const R2: u8 = 2;
+const R3: u8 = 3;
+const R4: u8 = 4;
+const R6: u8 = 6;
+const R7: u8 = 7;
+const R8: u8 = 8;
+const R10: u8 = 10;
+
+/// assembler of programs, jumps are to their exit
+#[derive(Debug, Default)]
+pub struct Program {
+    insns: Vec<Insn>,
+    /// jumps to exit, to patch
+    exits: Vec<usize>,
+}
+
+impl Program {
+    fn new() -> Self {
+        Program::default()
+    }
+    fn push(&mut self, insn: Insn) -> &mut Self {
+        self.insns.push(insn);
+        self
+    }
+    fn mov(&mut self, dst: u8, src: u8) -> &mut Self {
+        self.push(Insn::new(0xbf, dst, src, 0, 0))
+    }
+    fn mov_imm(&mut self, dst: u8, imm: i32) -> &mut Self {
+        self.push(Insn::new(0xb7, dst, 0, 0, imm))
+    }
+    fn add_imm(&mut self, dst: u8, imm: i32) -> &mut Self {
+        self.push(Insn::new(0x07, dst, 0, 0, imm))
+    }
+    fn and_imm(&mut self, dst: u8, imm: i32) -> &mut Self {
+        self.push(Insn::new(0x57, dst, 0, 0, imm))
+    }
+    /// `dst = imm`, 64 bits, `src` 1 for a map fd
+    fn ld_imm64(&mut self, dst: u8, src: u8, imm: u64) -> &mut Self {
+        self.push(Insn::new(0x18, dst, src, 0, imm as i32));
+        self.push(Insn::new(0, 0, 0, 0, (imm >> 32) as i32))
+    }
+    fn ld_map(&mut self, dst: u8, fd: RawFd) -> &mut Self {
+        self.ld_imm64(dst, 1, fd as u64)
+    }
+    /// `dst = *(u64 *)(src + off)`
+    fn ldx64(&mut self, dst: u8, src: u8, off: i16) -> &mut Self {
+        self.push(Insn::new(0x79, dst, src, off, 0))
+    }
+    /// `*(u32 *)(dst + off) = src`
+    fn stx32(&mut self, dst: u8, src: u8, off: i16) -> &mut Self {
+        self.push(Insn::new(0x63, dst, src, off, 0))
+    }
+    /// `lock *(u64 *)(dst + off) += src`
+    fn xadd64(&mut self, dst: u8, src: u8, off: i16) -> &mut Self {
+        self.push(Insn::new(0xdb, dst, src, off, 0))
+    }
+    fn call(&mut self, helper: i32) -> &mut Self {
+        self.push(Insn::new(0x85, 0, 0, 0, helper))
+    }
+    /// exit if `dst <op> imm`, `op` of a `BPF_JMP | BPF_K` code
+    fn exit_if(&mut self, op: u8, dst: u8, imm: i32) -> &mut Self {
+        self.exits.push(self.insns.len());
+        self.push(Insn::new(op, dst, 0, 0, imm))
+    }
+    /// exit if `dst <op> src`, `op` of a `BPF_JMP | BPF_X` code
+    fn exit_if_reg(&mut self, op: u8, dst: u8, src: u8) -> &mut Self {
+        self.exits.push(self.insns.len());
+        self.push(Insn::new(op, dst, src, 0, 0))
+    }
+    /// `r0 = 0; exit`, jumps patched
+    fn finish(&mut self) -> Vec<Insn> {
+        let exit = self.insns.len();
+        self.mov_imm(R0, 0);
+        self.push(Insn::new(0x95, 0, 0, 0, 0));
+        for k in self.exits.drain(..) {
+            self.insns[k].off = (exit - k - 1) as i16;
+        }
+        std::mem::take(&mut self.insns)
+    }
+}
+
+const JEQ_IMM: u8 = 0x15;
+const JGT_IMM: u8 = 0x25;
+const JNE_IMM: u8 = 0x55;
+const JSGE_IMM: u8 = 0x75;
+const JNE_REG: u8 = 0x5d;
+
+// exit unless in cgroup `cgroup`, with the context saved in r6
+fn cgroup_filter(program: &mut Program, cgroup: u64) {
+    program
+        .mov(R6, R1)
+        .call(GET_CURRENT_CGROUP_ID)
+        .ld_imm64(R1, 0, cgroup)
+        .exit_if_reg(JNE_REG, R0, R1);
+}
+
+// `map[*(u32 *)(r10 + key)] += 1`, exit if not found
+fn count(program: &mut Program, map: RawFd, key: i16) {
+    program
+        .mov(R2, R10)
+        .add_imm(R2, i32::from(key))
+        .ld_map(R1, map)
+        .call(MAP_LOOKUP_ELEM)
+        .exit_if(JEQ_IMM, R0, 0)
+        .mov_imm(R1, 1)
+        .xadd64(R0, R1, 0);
+}
+
+/// `sys_enter` program: counts syscalls of `cgroup` in `counts`, samples
+/// one in `sample_every` (a power of 2) to `ring`
+pub fn sys_enter_program(
+    cgroup: u64,
+    counts: RawFd,
+    ring: RawFd,
+    sample_every: u32,
+) -> Vec<Insn> {
+    let mut program = Program::new();
+    cgroup_filter(&mut program, cgroup);
+    // r7: syscall number, args[1] of the context
+    program
+        .ldx64(R7, R6, 8)
+        .exit_if(JGT_IMM, R7, MAX_SYSCALLS as i32 - 1)
+        .stx32(R10, R7, -4);
+    count(&mut program, counts, -4);
+    if sample_every == 0 {
+        return program.finish();
+    }
+    // sample at r10 - 64: tid, syscall, arguments
+    let sample = -(SAMPLE_SIZE as i16) - 8;
+    program
+        .call(GET_PRANDOM_U32)
+        .and_imm(R0, sample_every as i32 - 1)
+        .exit_if(JNE_IMM, R0, 0)
+        .call(GET_CURRENT_PID_TGID)
+        .stx32(R10, R0, sample)
+        .stx32(R10, R7, sample + 4)
+        // r8: `struct pt_regs *`, args[0] of the context
+        .ldx64(R8, R6, 0);
+    for (k, off) in PT_REGS_ARGS.iter().enumerate() {
+        program
+            .mov(R1, R10)
+            .add_imm(R1, i32::from(sample) + 8 + 8 * k as i32)
+            .mov_imm(R2, 8)
+            .mov(R3, R8)
+            .add_imm(R3, i32::from(*off))
+            .call(PROBE_READ);
+    }
+    program
+        .ld_map(R1, ring)
+        .mov(R2, R10)
+        .add_imm(R2, i32::from(sample))
+        .mov_imm(R3, SAMPLE_SIZE as i32)
+        .mov_imm(R4, 0)
+        .call(RINGBUF_OUTPUT);
+    program.finish()
+}
+
+/// `sys_exit` program: counts syscalls of `cgroup` failing in `errors`
+pub fn sys_exit_program(cgroup: u64, errors: RawFd) -> Vec<Insn> {
+    let mut program = Program::new();
+    cgroup_filter(&mut program, cgroup);
+    // args[1] of the context: the return value
+    program
+        .ldx64(R7, R6, 8)
+        .exit_if(JSGE_IMM, R7, 0)
+        .ldx64(R8, R6, 0)
+        .mov(R1, R10)
+        .add_imm(R1, -16)
+        .mov_imm(R2, 8)
+        .mov(R3, R8)
+        .add_imm(R3, i32::from(PT_REGS_ORIG_AX))
+        .call(PROBE_READ)
+        .ldx64(R1, R10, -16)
+        .exit_if(JGT_IMM, R1, MAX_SYSCALLS as i32 - 1)
+        .stx32(R10, R1, -4);
+    count(&mut program, errors, -4);
+    program.finish()
+}
+
+fn bpf(cmd: i32, attr: &mut [u8; 128]) -> Result<RawFd> {
+    let ret = unsafe {
+        libc::syscall(libc::SYS_bpf, cmd, attr.as_mut_ptr(), attr.len())
+    };
+    if ret < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(ret as RawFd)
+    }
+}
+
+fn put_u32(attr: &mut [u8; 128], at: usize, value: u32) {
+    attr[at..at + 4].copy_from_slice(&value.to_ne_bytes());
+}
+
+fn put_u64(attr: &mut [u8; 128], at: usize, value: u64) {
+    attr[at..at + 8].copy_from_slice(&value.to_ne_bytes());
+}
+
+fn map_create(map_type: u32, key: u32, value: u32, entries: u32) -> Result<Fd> {
+    let mut attr = [0u8; 128];
+    put_u32(&mut attr, 0, map_type);
+    put_u32(&mut attr, 4, key);
+    put_u32(&mut attr, 8, value);
+    put_u32(&mut attr, 12, entries);
+    bpf(BPF_MAP_CREATE, &mut attr).map(Fd)
+}
+
+fn map_lookup_u64(map: RawFd, key: u32) -> Result<u64> {
+    let mut value = 0u64;
+    let mut attr = [0u8; 128];
+    put_u32(&mut attr, 0, map as u32);
+    put_u64(&mut attr, 8, &key as *const u32 as u64);
+    put_u64(&mut attr, 16, &mut value as *mut u64 as u64);
+    bpf(BPF_MAP_LOOKUP_ELEM, &mut attr)?;
+    Ok(value)
+}
+
+fn prog_load(insns: &[Insn], name: &str) -> Result<Fd> {
+    let insns: Vec<u64> = insns.iter().map(Insn::encode).collect();
+    let license = b"GPL\0";
+    let mut log = vec![0u8; 0x1_0000];
+    let mut attr = [0u8; 128];
+    put_u32(&mut attr, 0, BPF_PROG_TYPE_RAW_TRACEPOINT);
+    put_u32(&mut attr, 4, insns.len() as u32);
+    put_u64(&mut attr, 8, insns.as_ptr() as u64);
+    put_u64(&mut attr, 16, license.as_ptr() as u64);
+    put_u32(&mut attr, 24, 1);
+    put_u32(&mut attr, 28, log.len() as u32);
+    put_u64(&mut attr, 32, log.as_mut_ptr() as u64);
+    // nul terminated, in 16 bytes
+    let len = name.len().min(15);
+    attr[48..48 + len].copy_from_slice(&name.as_bytes()[..len]);
+    bpf(BPF_PROG_LOAD, &mut attr).map(Fd).map_err(|err| {
+        let end = log.iter().position(|c| *c == 0).unwrap_or(log.len());
+        let log = String::from_utf8_lossy(&log[..end]);
+        log::debug!("[ebpf] {} rejected by the verifier:\n{}", name, log);
+        err
+    })
+}
+
+fn raw_tracepoint_open(name: &str, prog: RawFd) -> Result<Fd> {
+    let name = CString::new(name)
+        .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    let mut attr = [0u8; 128];
+    put_u64(&mut attr, 0, name.as_ptr() as u64);
+    put_u32(&mut attr, 8, prog as u32);
+    bpf(BPF_RAW_TRACEPOINT_OPEN, &mut attr).map(Fd)
+}
+
+// an fd, closed when dropped
+#[derive(Debug)]
+struct Fd(RawFd);
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        let _ = unistd::close(self.0);
+    }
+}
+
+/// cgroup (v2) of tracees, removed when dropped
+#[derive(Debug)]
+pub struct TraceeCgroup {
+    pub path: PathBuf,
+    /// cgroup id, as of `bpf_get_current_cgroup_id`
+    pub id: u64,
+}
+
+impl TraceeCgroup {
+    /// cgroup `reverie.PID` under the cgroup of the tracer
+    pub fn create() -> Result<Self> {
+        let not_mounted =
+            || Error::new(ErrorKind::NotFound, "cgroup v2 not mounted");
+        let cgroups = fs::read_to_string("/proc/self/cgroup")?;
+        let own = cgroups
+            .lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .ok_or_else(not_mounted)?;
+        let root = cgroup2_mount()?.ok_or_else(not_mounted)?;
+        let path = root
+            .join(own.trim_start_matches('/'))
+            .join(format!("reverie.{}", unistd::getpid()));
+        fs::create_dir(&path)?;
+        // the inode number of a cgroup v2 directory is its id
+        let id = fs::metadata(&path)?.ino();
+        Ok(TraceeCgroup { path, id })
+    }
+
+    /// move process `pid` (and its children to come) to the cgroup
+    pub fn enter(&self, pid: Pid) -> Result<()> {
+        let mut procs = fs::OpenOptions::new()
+            .write(true)
+            .open(self.path.join("cgroup.procs"))?;
+        procs.write_all(pid.to_string().as_bytes())
+    }
+}
+
+// mount point of the cgroup v2 hierarchy, if mounted
+fn cgroup2_mount() -> Result<Option<PathBuf>> {
+    let mounts = fs::read_to_string("/proc/self/mountinfo")?;
+    // i.e.: `30 24 0:26 / /sys/fs/cgroup rw - cgroup2 cgroup2 rw`
+    let mount = mounts.lines().find_map(|line| {
+        let mut split = line.splitn(2, " - ");
+        let (mount, fstype) = (split.next()?, split.next()?);
+        if fstype.split(' ').next() != Some("cgroup2") {
+            return None;
+        }
+        mount.split(' ').nth(4).map(PathBuf::from)
+    });
+    Ok(mount)
+}
+
+impl Drop for TraceeCgroup {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir(&self.path) {
+            log::debug!("[ebpf] cannot remove {:?}: {}", self.path, err);
+        }
+    }
+}
+
+/// arguments of a syscall, sampled in-kernel
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SyscallSample {
+    pub tid: i32,
+    pub syscall: String,
+    pub args: [u64; 6],
+}
+
+impl SyscallSample {
+    /// sample of a ring buffer record
+    pub fn parse(record: &[u8]) -> Option<Self> {
+        if record.len() < SAMPLE_SIZE {
+            return None;
+        }
+        let u32_at = |at: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&record[at..at + 4]);
+            u32::from_ne_bytes(bytes)
+        };
+        let mut args = [0u64; 6];
+        for (k, arg) in args.iter_mut().enumerate() {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&record[8 + 8 * k..16 + 8 * k]);
+            *arg = u64::from_ne_bytes(bytes);
+        }
+        Some(SyscallSample {
+            tid: u32_at(0) as i32,
+            syscall: syscall_name(u32_at(4) as i32),
+            args,
+        })
+    }
+}
+
+/// syscalls counted in-kernel, of a syscall
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BpfCounter {
+    pub syscall: String,
+    pub calls: u64,
+    pub errors: u64,
+}
+
+/// what the co-processor saw, for the report
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BpfStats {
+    /// syscalls called at least once
+    pub counters: Vec<BpfCounter>,
+    /// samples received
+    pub samples: usize,
+    /// most recent samples, up to `SAMPLES_KEPT`
+    pub recent_samples: Vec<SyscallSample>,
+}
+
+// ring buffer mapped, of the consumer page, and the producer page followed
+// by the data (mapped twice, so that records are contiguous)
+struct RingBuffer {
+    consumer: usize,
+    producer: usize,
+    page: usize,
+}
+
+impl RingBuffer {
+    fn map(fd: RawFd) -> Result<Self> {
+        let page = unistd::sysconf(unistd::SysconfVar::PAGE_SIZE)
+            .ok()
+            .and_then(|page| page)
+            .unwrap_or(4096) as usize;
+        let map = |len, prot, offset| {
+            let addr = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    len,
+                    prot,
+                    libc::MAP_SHARED,
+                    fd,
+                    offset as libc::off_t,
+                )
+            };
+            if addr == libc::MAP_FAILED {
+                Err(Error::last_os_error())
+            } else {
+                Ok(addr as usize)
+            }
+        };
+        let consumer = map(page, libc::PROT_READ | libc::PROT_WRITE, 0)?;
+        let producer = map(page + 2 * RING_SIZE, libc::PROT_READ, page)
+            .map_err(|err| {
+                unsafe { libc::munmap(consumer as *mut _, page) };
+                err
+            })?;
+        Ok(RingBuffer {
+            consumer,
+            producer,
+            page,
+        })
+    }
+
+    /// records produced since last drained
+    fn drain(&self) -> Vec<Vec<u8>> {
+        let consumer_pos = unsafe { &*(self.consumer as *const AtomicU64) };
+        let producer_pos = unsafe { &*(self.producer as *const AtomicU64) };
+        let data = self.producer + self.page;
+        let mut records = Vec::new();
+        let mut pos = consumer_pos.load(Ordering::Acquire);
+        while pos < producer_pos.load(Ordering::Acquire) {
+            let header = data + (pos as usize & (RING_SIZE - 1));
+            let len = unsafe { &*(header as *const AtomicU32) };
+            let len = len.load(Ordering::Acquire);
+            if len & RINGBUF_BUSY != 0 {
+                break;
+            }
+            let size = (len & !RINGBUF_DISCARD) as usize;
+            if len & RINGBUF_DISCARD == 0 {
+                let record = unsafe {
+                    std::slice::from_raw_parts(
+                        (header + RINGBUF_HEADER) as *const u8,
+                        size,
+                    )
+                };
+                records.push(record.to_vec());
+            }
+            pos += ((RINGBUF_HEADER + size + 7) & !7) as u64;
+            consumer_pos.store(pos, Ordering::Release);
+        }
+        records
+    }
+}
+
+impl Drop for RingBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.consumer as *mut _, self.page);
+            libc::munmap(self.producer as *mut _, self.page + 2 * RING_SIZE);
+        }
+    }
+}
+
+/// programs loaded and attached, see module doc
+pub struct Coprocessor {
+    // NB: dropped in order, programs detached first
+    links: Vec<Fd>,
+    programs: Vec<Fd>,
+    ring: RingBuffer,
+    counts: Fd,
+    errors: Fd,
+    ring_fd: Fd,
+    cgroup: TraceeCgroup,
+    samples: usize,
+    recent_samples: VecDeque<SyscallSample>,
+}
+
+impl Coprocessor {
+    /// move process `tracee` to a cgroup of its own, and count its
+    /// syscalls, sampling one in `sample_every` (rounded up to a power of
+    /// 2, 0 for none)
+    pub fn start(tracee: Pid, sample_every: u32) -> Result<Self> {
+        let sample_every = match sample_every {
+            0 => 0,
+            n => n.next_power_of_two(),
+        };
+        let cgroup = TraceeCgroup::create()?;
+        let counts = map_create(BPF_MAP_TYPE_ARRAY, 4, 8, MAX_SYSCALLS)?;
+        let errors = map_create(BPF_MAP_TYPE_ARRAY, 4, 8, MAX_SYSCALLS)?;
+        let ring_fd = map_create(BPF_MAP_TYPE_RINGBUF, 0, 0, RING_SIZE as u32)?;
+        let enter =
+            sys_enter_program(cgroup.id, counts.0, ring_fd.0, sample_every);
+        let exit = sys_exit_program(cgroup.id, errors.0);
+        let programs = vec![
+            prog_load(&enter, "reverie_enter")?,
+            prog_load(&exit, "reverie_exit")?,
+        ];
+        let links = vec![
+            raw_tracepoint_open("sys_enter", programs[0].0)?,
+            raw_tracepoint_open("sys_exit", programs[1].0)?,
+        ];
+        let ring = RingBuffer::map(ring_fd.0)?;
+        cgroup.enter(tracee)?;
+        log::info!(
+            "[ebpf] counting syscalls of cgroup {:?}, sampling 1 in {}",
+            cgroup.path,
+            sample_every
+        );
+        Ok(Coprocessor {
+            links,
+            programs,
+            ring,
+            counts,
+            errors,
+            ring_fd,
+            cgroup,
+            samples: 0,
+            recent_samples: VecDeque::new(),
+        })
+    }
+
+    /// drain samples sent by the programs
+    pub fn poll(&mut self) {
+        for record in self.ring.drain() {
+            if let Some(sample) = SyscallSample::parse(&record) {
+                log::trace!("[ebpf] sampled {:?}", sample);
+                self.samples += 1;
+                if self.recent_samples.len() == SAMPLES_KEPT {
+                    self.recent_samples.pop_front();
+                }
+                self.recent_samples.push_back(sample);
+            }
+        }
+    }
+
+    /// syscalls counted so far, and samples received
+    pub fn stats(&mut self) -> BpfStats {
+        self.poll();
+        let counters = (0..MAX_SYSCALLS)
+            .filter_map(|nr| {
+                let calls = map_lookup_u64(self.counts.0, nr).ok()?;
+                let errors = map_lookup_u64(self.errors.0, nr).unwrap_or(0);
+                Some(BpfCounter {
+                    syscall: syscall_name(nr as i32),
+                    calls,
+                    errors,
+                })
+            })
+            .filter(|counter| counter.calls > 0)
+            .collect();
+        BpfStats {
+            counters,
+            samples: self.samples,
+            recent_samples: self.recent_samples.iter().cloned().collect(),
+        }
+    }
+}
+
+lazy_static! {
+    static ref COPROCESSOR: Mutex<Option<Coprocessor>> = Mutex::new(None);
+}
+
+/// start the co-processor, see `Coprocessor::start`
+pub fn start(tracee: Pid, sample_every: u32) -> Result<()> {
+    let coprocessor = Coprocessor::start(tracee, sample_every)?;
+    *COPROCESSOR.lock().unwrap_or_else(|e| e.into_inner()) = Some(coprocessor);
+    Ok(())
+}
+
+/// move process `tracee` to the cgroup of the co-processor, if started, so
+/// that its syscalls are counted too
+pub fn enter(tracee: Pid) -> Result<()> {
+    match COPROCESSOR
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+    {
+        Some(coprocessor) => coprocessor.cgroup.enter(tracee),
+        None => Ok(()),
+    }
+}
+
+/// drain samples, if started, without blocking
+pub fn poll() {
+    if let Some(coprocessor) = COPROCESSOR
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_mut()
+    {
+        coprocessor.poll();
+    }
+}
+
+/// stop the co-processor (once tracees exited), returns what it saw
+pub fn finish() -> Option<BpfStats> {
+    let mut coprocessor = COPROCESSOR
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take()?;
+    Some(coprocessor.stats())
+}
+
+#[test]
+fn ebpf_sanity_check() {
+    let insn = Insn::new(0xb7, R1, 0, 0, -1);
+    assert_eq!(insn.encode(), 0xffff_ffff_0000_01b7);
+    let program = sys_exit_program(7, 3);
+    // jumps land on `r0 = 0; exit`
+    let exit = program.len() - 2;
+    for (k, insn) in program.iter().enumerate() {
+        if insn.code & 0x07 == 0x05 && insn.code != 0x85 && insn.code != 0x95 {
+            assert_eq!(k + 1 + insn.off as usize, exit);
+        }
+    }
+    assert_eq!(program[exit + 1].code, 0x95);
+    let mut record = vec![0u8; SAMPLE_SIZE];
+    record[0..4].copy_from_slice(&42u32.to_ne_bytes());
+    record[4..8].copy_from_slice(&1u32.to_ne_bytes());
+    record[8..16].copy_from_slice(&3u64.to_ne_bytes());
+    let sample = SyscallSample::parse(&record).unwrap();
+    assert_eq!(sample.tid, 42);
+    assert_eq!(sample.syscall, "write");
+    assert_eq!(sample.args[0], 3);
+    assert!(SyscallSample::parse(&record[1..]).is_none());
+}
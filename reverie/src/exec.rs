@@ -0,0 +1,348 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! exec syscalls: `execve` and `execveat`
+//!
+//! programs can be exec'ed by fd as well: `fexecve` is `execveat(fd, "",
+//! .., AT_EMPTY_PATH)`, or `execve` of `/proc/self/fd/<fd>` by older libcs,
+//! i.e.: to exec memfds. `exec_path` resolves the program either way, for
+//! policies.
+//!
+//! exec syscalls are never patched, so that they stop at seccomp, where
+//! `LD_PRELOAD` is re-established if the tracee dropped it from the new
+//! environment, and pinned variables are set, see `preload_envp`.
+//!
+//! the new environment is written below the stack pointer, within its
+//! mapping: children of `vfork` or `posix_spawn` run on the memory of
+//! their parent, i.e.: on a small stack mapped next to other mappings of
+//! the parent, see `stack_floor`.
+
+use nix::unistd::Pid;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use syscalls::*;
+
+use reverie_api::device::read_cstring;
+use reverie_api::emulate::TaskMemory;
+use reverie_api::guest::GuestStruct;
+use reverie_api::remote::SyscallArgs;
+
+use reverie_common::consts;
+
+use crate::paths::*;
+
+const LD_PRELOAD: &str = "LD_PRELOAD=";
+
+/// environment variables read at most
+const MAX_ENVS: usize = 0x10000;
+
+/// bytes below the stack pointer left alone, as of the x86_64 abi
+const RED_ZONE: u64 = 128;
+
+lazy_static! {
+    static ref PRELOADER: Option<String> =
+        std::env::var(consts::REVERIE_PRELOADER).ok();
+}
+
+/// preloader of the tracees, see `REVERIE_PRELOADER`
+pub fn preloader() -> Option<&'static str> {
+    PRELOADER.as_deref()
+}
+
+/// syscalls to be stopped (and never patched)
+pub fn is_exec_syscall(syscall: SyscallNo) -> bool {
+    syscall == SYS_execve || syscall == SYS_execveat
+}
+
+// `/proc/self/fd/<fd>` (or of `pid`) as `fd`
+fn proc_fd(pid: Pid, path: &str) -> Option<i32> {
+    let fd = path
+        .strip_prefix("/proc/self/fd/")
+        .or_else(|| path.strip_prefix(&format!("/proc/{}/fd/", pid)))?;
+    fd.parse().ok()
+}
+
+/// program `syscall` (with `args`) of process `pid` execs. for exec by
+/// fd, the path the fd was opened at, `/memfd:<name> (deleted)` for memfds.
+pub fn exec_path(
+    pid: Pid,
+    memory: &dyn TaskMemory,
+    syscall: SyscallNo,
+    args: &SyscallArgs,
+) -> Option<PathBuf> {
+    let (dirfd, path, flags) = match syscall {
+        SYS_execve => (None, args.arg0, 0),
+        SYS_execveat => (Some(args.arg0 as i32), args.arg1, args.arg4 as i32),
+        _ => return None,
+    };
+    let path = read_cstring(memory, path).ok()?;
+    let fd = match dirfd {
+        Some(fd) if path.is_empty() && flags & libc::AT_EMPTY_PATH != 0 => {
+            Some(fd)
+        }
+        _ => proc_fd(pid, &path),
+    };
+    match fd {
+        Some(fd) => std::fs::read_link(format!("/proc/{}/fd/{}", pid, fd)).ok(),
+        None if path.is_empty() => None,
+        None => Some(resolve(pid, dirfd, &path)),
+    }
+}
+
+/// whether `path` (of `/proc/<pid>/fd`) is a memfd
+pub fn is_memfd(path: &Path) -> bool {
+    path.to_string_lossy().starts_with("/memfd:")
+}
+
+/// `envp` argument of exec `syscall`
+fn envp_of(syscall: SyscallNo, regs: &libc::user_regs_struct) -> u64 {
+    if syscall == SYS_execveat {
+        regs.r10
+    } else {
+        regs.rdx
+    }
+}
+
+// NULL terminated array of pointers at `addr`
+fn read_ptrs(memory: &dyn TaskMemory, addr: u64) -> Result<Vec<u64>> {
+    let mut ptrs = Vec::new();
+    while ptrs.len() < MAX_ENVS {
+        let ptr = u64::read(memory, addr + 8 * ptrs.len() as u64)?;
+        if ptr == 0 {
+            break;
+        }
+        ptrs.push(ptr);
+    }
+    Ok(ptrs)
+}
+
+/// `LD_PRELOAD` entry with `preload` loaded first, given the current entry
+/// (if any). `None` if `preload` is loaded already.
+pub fn preload_env(current: Option<&str>, preload: &str) -> Option<String> {
+    let libs = current.map_or("", |env| &env[LD_PRELOAD.len()..]);
+    if libs
+        .split(|c| c == ':' || c == ' ')
+        .any(|lib| lib == preload)
+    {
+        return None;
+    }
+    if libs.is_empty() {
+        Some(format!("{}{}", LD_PRELOAD, preload))
+    } else {
+        Some(format!("{}{}:{}", LD_PRELOAD, preload, libs))
+    }
+}
+
+/// lowest address to write below stack pointer `sp` of process `pid`: the
+/// start of the mapping of `sp`, but for the main stack, which grows.
+pub fn stack_floor(pid: Pid, sp: u64) -> u64 {
+    let maps = procfs::process::Process::new(pid.as_raw())
+        .and_then(|p| p.maps())
+        .unwrap_or_else(|_| Vec::new());
+    maps.iter()
+        .find(|map| map.address.0 <= sp && sp <= map.address.1)
+        .filter(|map| map.pathname != procfs::process::MMapPath::Stack)
+        .map_or(0, |map| map.address.0)
+}
+
+/// re-establish `LD_PRELOAD` of `preload` (if any) in the environment of
+/// exec `syscall` with `regs`, and `pinned` variables, as `KEY=VALUE`. the
+/// new environment is written below the stack, not below `floor` (see
+/// `stack_floor`), returns the registers pointing to it, `None` if
+/// preloaded and pinned already.
+pub fn preload_envp(
+    memory: &dyn TaskMemory,
+    syscall: SyscallNo,
+    regs: &libc::user_regs_struct,
+    preload: Option<&str>,
+    pinned: &[&str],
+    floor: u64,
+) -> Result<Option<libc::user_regs_struct>> {
+    let envp = envp_of(syscall, regs);
+    let mut envs = if envp == 0 {
+        Vec::new()
+    } else {
+        read_ptrs(memory, envp)?
+    };
+    let starts_with = |ptr: u64, prefix: &str| {
+        memory
+            .read_bytes(ptr, prefix.len())
+            .map_or(false, |bytes| bytes == prefix.as_bytes())
+    };
+    // entries to set, and the index of the entry they replace, if any
+    let mut changes: Vec<(Option<usize>, String)> = Vec::new();
+    if let Some(preload) = preload {
+        let current = envs.iter().position(|ptr| starts_with(*ptr, LD_PRELOAD));
+        let env = match current {
+            Some(k) => Some(read_cstring(memory, envs[k])?),
+            None => None,
+        };
+        if let Some(env) = preload_env(env.as_deref(), preload) {
+            changes.push((current, env));
+        }
+    }
+    for env in pinned {
+        let key = env.find('=').map_or(*env, |k| &env[..=k]);
+        let current = envs.iter().position(|ptr| starts_with(*ptr, key));
+        match current {
+            Some(k) if read_cstring(memory, envs[k])? == *env => (),
+            _ => changes.push((current, env.to_string())),
+        }
+    }
+    if changes.is_empty() {
+        return Ok(None);
+    }
+    // the new entries, then the new array.
+    let mut bytes = Vec::new();
+    let mut offsets = Vec::new();
+    for (_, env) in &changes {
+        offsets.push(bytes.len() as u64);
+        bytes.extend_from_slice(env.as_bytes());
+        bytes.push(0);
+    }
+    bytes.resize((bytes.len() + 7) & !7, 0);
+    let added = changes.iter().filter(|(k, _)| k.is_none()).count();
+    let size = bytes.len() + 8 * (envs.len() + added + 1);
+    let addr = regs
+        .rsp
+        .checked_sub(RED_ZONE + size as u64)
+        .map(|addr| addr & !0xf)
+        .filter(|addr| *addr >= floor)
+        .ok_or_else(|| {
+            Error::new(ErrorKind::Other, "environment doesn't fit the stack")
+        })?;
+    for ((current, _), offset) in changes.iter().zip(offsets) {
+        match current {
+            Some(k) => envs[*k] = addr + offset,
+            None => envs.push(addr + offset),
+        }
+    }
+    envs.push(0);
+    let array = addr + bytes.len() as u64;
+    for ptr in envs {
+        bytes.extend_from_slice(&ptr.to_le_bytes());
+    }
+    memory.write_bytes(addr, &bytes)?;
+    let mut new_regs = *regs;
+    if syscall == SYS_execveat {
+        new_regs.r10 = array;
+    } else {
+        new_regs.rdx = array;
+    }
+    Ok(Some(new_regs))
+}
+
+#[test]
+fn exec_sanity_check() {
+    use std::cell::RefCell;
+
+    struct Memory(RefCell<Vec<u8>>);
+    impl TaskMemory for Memory {
+        fn read_bytes(&self, addr: u64, size: usize) -> Result<Vec<u8>> {
+            let mem = self.0.borrow();
+            let end = std::cmp::min(addr as usize + size, mem.len());
+            Ok(Vec::from(&mem[addr as usize..end]))
+        }
+        fn write_bytes(&self, addr: u64, bytes: &[u8]) -> Result<()> {
+            let addr = addr as usize;
+            self.0.borrow_mut()[addr..addr + bytes.len()]
+                .copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+    let preload = "/lib/libpreloader.so";
+    assert_eq!(
+        preload_env(Some("LD_PRELOAD=/lib/a.so"), preload).unwrap(),
+        "LD_PRELOAD=/lib/libpreloader.so:/lib/a.so"
+    );
+    let current = "LD_PRELOAD=/lib/a.so /lib/libpreloader.so";
+    assert_eq!(preload_env(Some(current), preload), None);
+
+    // envp at 0x100: ["HOME=/", NULL], strings at 0x200, stack at 0x1000.
+    let memory = Memory(RefCell::new(vec![0; 0x1000]));
+    memory.write_bytes(0x200, b"HOME=/\0").unwrap();
+    memory.write_bytes(0x100, &0x200u64.to_le_bytes()).unwrap();
+    let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+    regs.orig_rax = SYS_execve as u64;
+    regs.rdx = 0x100;
+    regs.rsp = 0x1000;
+    let preload = Some(preload);
+    let new_regs = preload_envp(&memory, SYS_execve, &regs, preload, &[], 0)
+        .unwrap()
+        .unwrap();
+    let envs = read_ptrs(&memory, new_regs.rdx).unwrap();
+    assert_eq!(envs.len(), 2);
+    assert_eq!(read_cstring(&memory, envs[0]).unwrap(), "HOME=/");
+    let env = read_cstring(&memory, envs[1]).unwrap();
+    assert_eq!(env, "LD_PRELOAD=/lib/libpreloader.so");
+    assert!(new_regs.rdx < 0x1000 - RED_ZONE);
+    let again = preload_envp(&memory, SYS_execve, &new_regs, preload, &[], 0);
+    assert_eq!(again.unwrap(), None);
+    let pinned = ["HOME=/root", "TZ=UTC0"];
+    // below the environment written
+    let mut lower_regs = new_regs;
+    lower_regs.rsp = 0x800;
+    // a spawned child's stack, too small
+    let spawned =
+        preload_envp(&memory, SYS_execve, &lower_regs, preload, &pinned, 0x7c0);
+    assert!(spawned.is_err());
+    let pinned_regs =
+        preload_envp(&memory, SYS_execve, &lower_regs, preload, &pinned, 0)
+            .unwrap()
+            .unwrap();
+    let envs = read_ptrs(&memory, pinned_regs.rdx).unwrap();
+    assert_eq!(envs.len(), 3);
+    assert_eq!(read_cstring(&memory, envs[0]).unwrap(), "HOME=/root");
+    assert_eq!(read_cstring(&memory, envs[2]).unwrap(), "TZ=UTC0");
+    let again =
+        preload_envp(&memory, SYS_execve, &pinned_regs, None, &pinned, 0);
+    assert_eq!(again.unwrap(), None);
+
+    let pid = nix::unistd::getpid();
+    memory.write_bytes(0x300, b"\0").unwrap();
+    let args = SyscallArgs::from(0, 0x300, 0, 0, 0, 0);
+    let args_empty = SyscallArgs::from(0, 0x300, 0, 0, 0x1000, 0);
+    assert_eq!(exec_path(pid, &memory, SYS_execveat, &args), None);
+    assert!(is_memfd(Path::new("/memfd:payload (deleted)")));
+    assert_eq!(
+        exec_path(pid, &memory, SYS_execveat, &args_empty),
+        std::fs::read_link("/proc/self/fd/0").ok()
+    );
+}
+
+#[test]
+fn stack_floor_sanity_check() {
+    let pid = nix::unistd::getpid();
+    // a stack mapped by `posix_spawn` for its child
+    let stack = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            0x4000,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_STACK,
+            -1,
+            0,
+        )
+    };
+    assert_ne!(stack, libc::MAP_FAILED);
+    let stack = stack as u64;
+    assert_eq!(stack_floor(pid, stack + 0x3000), stack);
+    unsafe { libc::munmap(stack as *mut libc::c_void, 0x4000) };
+    // the main stack grows, unmapped addresses have no floor
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    let main = maps.lines().find(|line| line.ends_with("[stack]")).unwrap();
+    let end = main.split(&['-', ' '][..]).nth(1).unwrap();
+    let end = u64::from_str_radix(end, 16).unwrap();
+    assert_eq!(stack_floor(pid, end - 0x100), 0);
+    assert_eq!(stack_floor(pid, stack + 0x3000), 0);
+}
@@ -0,0 +1,74 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! capture of fileless execs, see `reverie_api::fileless`
+//!
+//! once a process exec'ed, its image is read back by `/proc/<pid>/exe`,
+//! which stays readable after the memfd is closed.
+
+use nix::unistd::Pid;
+use std::fs;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+use reverie_api::fileless::*;
+
+use crate::workers;
+
+// save `image` to `dir`, named by its `sha256`, unless saved already. the
+// image is written by a worker, failures are logged then.
+fn save_image(dir: &Path, sha256: &str, image: Vec<u8>) -> PathBuf {
+    let path = dir.join(sha256);
+    let (dir, saved) = (dir.to_path_buf(), path.clone());
+    workers::spawn(
+        &format!("save of {}", path.display()),
+        Box::new(move || -> Result<()> {
+            if !saved.exists() {
+                fs::create_dir_all(dir)?;
+                fs::write(&saved, image)?;
+            }
+            Ok(())
+        }),
+    );
+    path
+}
+
+/// `FilelessExec` of process `pid`, which just exec'ed, `None` unless its
+/// program is a memfd. the image is saved to `dir`, if any.
+pub fn capture(pid: Pid, dir: Option<&Path>) -> Option<FilelessExec> {
+    let exe = PathBuf::from(format!("/proc/{}/exe", pid));
+    let path = fs::read_link(&exe).ok()?;
+    let memfd = memfd_name(&path)?.to_string();
+    let image = match fs::read(&exe) {
+        Ok(image) => image,
+        Err(err) => {
+            log::warn!("[pid {}] cannot read memfd {}: {}", pid, memfd, err);
+            return None;
+        }
+    };
+    let sha256 = sha256(&image);
+    let size = image.len() as u64;
+    let saved = dir.map(|dir| save_image(dir, &sha256, image));
+    log::info!(
+        "[pid {}] fileless exec of memfd {}, sha256 {}",
+        pid,
+        memfd,
+        sha256
+    );
+    Some(FilelessExec {
+        memfd,
+        size,
+        sha256,
+        saved,
+    })
+}
@@ -0,0 +1,386 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! flakiness analysis, for tests
+//!
+//! `FlakyRecorder` records scheduling relevant events only: threads (and
+//! processes) spawned, futex wakes, timeouts expired and signals
+//! delivered. the syscalls involved are kept unpatched (by an emulator
+//! declining them), all others are patched as usual, for overhead to stay
+//! low. tasks are named by logical ids, in order of creation, so that
+//! traces of two runs compare.
+//!
+//! `FlakyReport` compares the traces of two runs of the same test, and
+//! highlights where they diverge: the first event which differs, and for
+//! each kind of event, differences of order or count.
+
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, Error, ErrorKind, Result, Write};
+use std::rc::Rc;
+use syscalls::SyscallNo;
+
+use reverie_api::emulate::*;
+use reverie_api::event::*;
+use reverie_api::remote::SyscallArgs;
+
+/// syscalls whose completion is recorded
+const FLAKY_SYSCALLS: &[SyscallNo] = &[
+    SyscallNo::SYS_futex,
+    SyscallNo::SYS_poll,
+    SyscallNo::SYS_ppoll,
+    SyscallNo::SYS_select,
+    SyscallNo::SYS_pselect6,
+    SyscallNo::SYS_epoll_wait,
+    SyscallNo::SYS_epoll_pwait,
+    SyscallNo::SYS_nanosleep,
+    SyscallNo::SYS_clock_nanosleep,
+];
+
+const FUTEX_WAIT: u64 = 0;
+const FUTEX_WAKE: u64 = 1;
+const FUTEX_REQUEUE: u64 = 3;
+const FUTEX_CMP_REQUEUE: u64 = 4;
+const FUTEX_WAKE_OP: u64 = 5;
+const FUTEX_WAIT_BITSET: u64 = 9;
+const FUTEX_WAKE_BITSET: u64 = 10;
+/// without `FUTEX_PRIVATE_FLAG` and `FUTEX_CLOCK_REALTIME`
+const FUTEX_CMD_MASK: u64 = !(128 | 256);
+
+/// scheduling relevant event, tasks are logical ids
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SchedEvent {
+    /// `child` forked or cloned by `parent`
+    Spawn { parent: usize, child: usize },
+    /// futex wake (or requeue) of `thread` woke `woken` waiters
+    FutexWake { thread: usize, woken: i64 },
+    /// futex wait of `thread` returned, woken up
+    FutexWoken { thread: usize },
+    /// `syscall` of `thread` returned, its timeout expired
+    Timeout { thread: usize, syscall: String },
+    /// `signal` delivered to `thread`
+    Signal { thread: usize, signal: i32 },
+}
+
+impl SchedEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SchedEvent::Spawn { .. } => "spawn",
+            SchedEvent::FutexWake { .. } => "futex-wake",
+            SchedEvent::FutexWoken { .. } => "futex-woken",
+            SchedEvent::Timeout { .. } => "timeout",
+            SchedEvent::Signal { .. } => "signal",
+        }
+    }
+}
+
+impl fmt::Display for SchedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchedEvent::Spawn { parent, child } => {
+                write!(f, "t{} spawned t{}", parent, child)
+            }
+            SchedEvent::FutexWake { thread, woken } => {
+                write!(f, "t{} woke {} futex waiter(s)", thread, woken)
+            }
+            SchedEvent::FutexWoken { thread } => {
+                write!(f, "t{} woken from futex", thread)
+            }
+            SchedEvent::Timeout { thread, syscall } => {
+                write!(f, "t{} {} timed out", thread, syscall)
+            }
+            SchedEvent::Signal { thread, signal } => {
+                match Signal::from_c_int(*signal) {
+                    Ok(sig) => write!(f, "t{} got {:?}", thread, sig),
+                    Err(_) => write!(f, "t{} got signal {}", thread, signal),
+                }
+            }
+        }
+    }
+}
+
+/// records `SchedEvent`s, see module doc
+#[derive(Default)]
+pub struct FlakyRecorder {
+    /// logical ids of tasks
+    ids: HashMap<Pid, usize>,
+    /// syscalls entered, by tid
+    pending: HashMap<Pid, (SyscallNo, SyscallArgs)>,
+    events: Vec<SchedEvent>,
+}
+
+impl FlakyRecorder {
+    pub fn new() -> Self {
+        FlakyRecorder::default()
+    }
+
+    fn id(&mut self, tid: Pid) -> usize {
+        let next = self.ids.len();
+        *self.ids.entry(tid).or_insert(next)
+    }
+
+    // `syscall` of `thread` returned `retval`
+    fn exited(
+        &mut self,
+        thread: usize,
+        syscall: SyscallNo,
+        args: &SyscallArgs,
+        retval: i64,
+    ) {
+        let timed_out = match syscall {
+            SyscallNo::SYS_futex => {
+                match args.arg1 & FUTEX_CMD_MASK {
+                    FUTEX_WAIT | FUTEX_WAIT_BITSET if retval == 0 => {
+                        self.events.push(SchedEvent::FutexWoken { thread });
+                    }
+                    FUTEX_WAKE | FUTEX_REQUEUE | FUTEX_CMP_REQUEUE
+                    | FUTEX_WAKE_OP | FUTEX_WAKE_BITSET
+                        if retval > 0 =>
+                    {
+                        let woken = retval;
+                        self.events
+                            .push(SchedEvent::FutexWake { thread, woken });
+                    }
+                    _ => (),
+                }
+                retval == -(libc::ETIMEDOUT as i64)
+            }
+            // sleeps always expire, unless interrupted
+            SyscallNo::SYS_nanosleep | SyscallNo::SYS_clock_nanosleep => {
+                retval == 0
+            }
+            // no fds ready
+            _ => retval == 0,
+        };
+        if timed_out {
+            let syscall = format!("{:?}", syscall);
+            self.events.push(SchedEvent::Timeout { thread, syscall });
+        }
+    }
+
+    /// record `event`
+    pub fn record_event(&mut self, event: &TimedEvent) {
+        let thread = self.id(event.tid);
+        match &event.event {
+            Event::SyscallEnter(syscall, args)
+                if FLAKY_SYSCALLS.contains(syscall) =>
+            {
+                self.pending.insert(event.tid, (*syscall, *args));
+            }
+            Event::SyscallExit(_, retval, _) => {
+                if let Some((syscall, args)) = self.pending.remove(&event.tid) {
+                    self.exited(thread, syscall, &args, *retval);
+                }
+            }
+            Event::Fork(child) | Event::Spawn(child) | Event::Clone(child) => {
+                let child = self.id(*child);
+                self.events.push(SchedEvent::Spawn {
+                    parent: thread,
+                    child,
+                });
+            }
+            Event::Signal(signal) => {
+                let signal = *signal as i32;
+                self.events.push(SchedEvent::Signal { thread, signal });
+            }
+            _ => (),
+        }
+    }
+
+    /// events recorded so far, in order
+    pub fn events(&self) -> &[SchedEvent] {
+        &self.events
+    }
+
+    /// write the trace to `out`, as json lines
+    pub fn write_trace<W: Write>(&self, mut out: W) -> Result<()> {
+        for event in &self.events {
+            serde_json::to_writer(&mut out, event)?;
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+/// read a trace written by `FlakyRecorder::write_trace`
+pub fn read_trace<R: BufRead>(trace: R) -> Result<Vec<SchedEvent>> {
+    let mut events = Vec::new();
+    for line in trace.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = serde_json::from_str(&line)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+/// syscall emulation (declining all syscalls, for them to be seen) and
+/// event sink recording into `recorder`.
+pub fn flaky_tracing(
+    recorder: Rc<RefCell<FlakyRecorder>>,
+) -> (SyscallEmulation, EventSink) {
+    let emulation = SyscallEmulation::new(
+        EmulationMode::Seccomp,
+        FLAKY_SYSCALLS.to_vec(),
+        Box::new(|_, _, _, _| None),
+    );
+    let sink: EventSink =
+        Box::new(move |event| recorder.borrow_mut().record_event(event));
+    (emulation, sink)
+}
+
+/// events of a kind, in two runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KindDiff {
+    pub kind: &'static str,
+    pub a: Vec<SchedEvent>,
+    pub b: Vec<SchedEvent>,
+}
+
+/// differences between two runs of the same test, see module doc
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlakyReport {
+    /// index of the first event which differs, with the events of both
+    /// runs there (`None` past the end of a run)
+    pub divergence: Option<(usize, Option<SchedEvent>, Option<SchedEvent>)>,
+    /// kinds of events which differ, in order or count
+    pub kinds: Vec<KindDiff>,
+}
+
+const KINDS: &[&str] =
+    &["spawn", "futex-wake", "futex-woken", "timeout", "signal"];
+
+impl FlakyReport {
+    pub fn new(a: &[SchedEvent], b: &[SchedEvent]) -> Self {
+        let divergence = (0..a.len().max(b.len()))
+            .find(|&k| a.get(k) != b.get(k))
+            .map(|k| (k, a.get(k).cloned(), b.get(k).cloned()));
+        let of_kind = |events: &[SchedEvent], kind: &str| -> Vec<SchedEvent> {
+            events
+                .iter()
+                .filter(|e| e.kind() == kind)
+                .cloned()
+                .collect()
+        };
+        let kinds = KINDS
+            .iter()
+            .map(|&kind| KindDiff {
+                kind,
+                a: of_kind(a, kind),
+                b: of_kind(b, kind),
+            })
+            .filter(|diff| diff.a != diff.b)
+            .collect();
+        FlakyReport { divergence, kinds }
+    }
+
+    /// whether both runs are the same
+    pub fn is_deterministic(&self) -> bool {
+        self.divergence.is_none()
+    }
+}
+
+fn show(event: &Option<SchedEvent>) -> String {
+    match event {
+        Some(event) => event.to_string(),
+        None => String::from("<end of run>"),
+    }
+}
+
+impl fmt::Display for FlakyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (k, a, b) = match &self.divergence {
+            None => return writeln!(f, "no scheduling differences observed"),
+            Some(divergence) => divergence,
+        };
+        writeln!(f, "runs diverge at event #{}:", k)?;
+        writeln!(f, "  a: {}", show(a))?;
+        writeln!(f, "  b: {}", show(b))?;
+        for diff in &self.kinds {
+            writeln!(
+                f,
+                "{}: {} event(s) in a, {} in b",
+                diff.kind,
+                diff.a.len(),
+                diff.b.len()
+            )?;
+            let first = (0..diff.a.len().max(diff.b.len()))
+                .find(|&k| diff.a.get(k) != diff.b.get(k));
+            if let Some(k) = first {
+                let (a, b) = (diff.a.get(k).cloned(), diff.b.get(k).cloned());
+                writeln!(f, "  first differs at #{}:", k)?;
+                writeln!(f, "    a: {}", show(&a))?;
+                writeln!(f, "    b: {}", show(&b))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn flaky_sanity_check() {
+    let event = |tid, event| TimedEvent {
+        tid: Pid::from_raw(tid),
+        at: Default::default(),
+        ticks: None,
+        event,
+    };
+    let futex = |op| SyscallArgs::from(0, op, 0, 0, 0, 0);
+    let run = |woken_first: i32| {
+        let mut recorder = FlakyRecorder::new();
+        recorder.record_event(&event(100, Event::Clone(Pid::from_raw(101))));
+        recorder.record_event(&event(100, Event::Clone(Pid::from_raw(102))));
+        for tid in &[woken_first, 203 - woken_first] {
+            let enter = Event::SyscallEnter(SyscallNo::SYS_futex, futex(128));
+            recorder.record_event(&event(*tid, enter));
+            let exit =
+                Event::SyscallExit(SyscallNo::SYS_futex, 0, Default::default());
+            recorder.record_event(&event(*tid, exit));
+        }
+        let enter = Event::SyscallEnter(SyscallNo::SYS_poll, futex(0));
+        recorder.record_event(&event(100, enter));
+        let exit =
+            Event::SyscallExit(SyscallNo::SYS_poll, 0, Default::default());
+        recorder.record_event(&event(100, exit));
+        recorder
+    };
+    let a = run(101);
+    assert_eq!(a.events().len(), 5);
+    assert_eq!(a.events()[2], SchedEvent::FutexWoken { thread: 1 });
+    assert_eq!(
+        a.events()[4],
+        SchedEvent::Timeout {
+            thread: 0,
+            syscall: String::from("SYS_poll")
+        }
+    );
+
+    let mut trace = Vec::new();
+    a.write_trace(&mut trace).unwrap();
+    let a = read_trace(&trace[..]).unwrap();
+    assert!(FlakyReport::new(&a, &a).is_deterministic());
+
+    let b = run(102);
+    let report = FlakyReport::new(&a, b.events());
+    assert_eq!(report.divergence.as_ref().map(|d| d.0), Some(2));
+    assert_eq!(report.kinds.len(), 1);
+    assert_eq!(report.kinds[0].kind, "futex-woken");
+    assert!(report.to_string().contains("runs diverge at event #2"));
+}
@@ -0,0 +1,54 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! compares traces of two runs of a test, recorded by `strace --mode flaky`
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+use reverie::flaky::{read_trace, FlakyReport, SchedEvent};
+
+#[derive(Debug, StructOpt)]
+#[structopt(about)]
+struct Arguments {
+    /// Trace of the first run.
+    #[structopt(value_name = "TRACE_A")]
+    a: PathBuf,
+
+    /// Trace of the second run.
+    #[structopt(value_name = "TRACE_B")]
+    b: PathBuf,
+}
+
+fn read(path: &PathBuf) -> io::Result<Vec<SchedEvent>> {
+    read_trace(BufReader::new(File::open(path)?))
+}
+
+/// exits with 1 if the runs differ, 2 on errors.
+#[paw::main]
+fn main(args: Arguments) {
+    let traces = read(&args.a).and_then(|a| Ok((a, read(&args.b)?)));
+    match traces {
+        Ok((a, b)) => {
+            let report = FlakyReport::new(&a, &b);
+            print!("{}", report);
+            std::process::exit(if report.is_deterministic() { 0 } else { 1 });
+        }
+        Err(err) => {
+            eprintln!("cannot read traces: {}", err);
+            std::process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! syscall time by call stack, of `--flamegraph`
+//!
+//! the user-space stack of each syscall is captured at its entry, see
+//! `Event::SyscallStack`, all syscalls being declined by an emulator, so
+//! that they are never patched and timed, see `Event::SyscallExit`. frames
+//! are named after their function, or `<file>+<offset>` when no symbol
+//! covers them, see `debug::elf_symbol_at`.
+//!
+//! syscall time is aggregated by stack, the syscall being the leaf frame
+//! (annotated `_[k]`, as kernel frames), and written as folded stacks in
+//! microseconds, for `flamegraph.pl`:
+//!
+//! ```text
+//! _start;__libc_start_main;main;load_config;read_file;read_[k] 1834
+//! ```
+//!
+//! NB: code with neither frame pointers nor call frame information yields
+//! truncated stacks, see `unwind`.
+
+use nix::unistd::Pid;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Result, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+use syscalls::SyscallNo;
+
+use reverie_api::emulate::*;
+use reverie_api::event::*;
+use reverie_api::violation::Frame;
+
+use crate::debug;
+
+// name of `frame`, without the separators of folded stacks
+fn frame_name(frame: &Frame) -> String {
+    let name = match &frame.location {
+        Some((path, offset)) => {
+            match debug::elf_symbol_at(Path::new(path), *offset) {
+                Some((symbol, _)) => symbol,
+                None => {
+                    let file = Path::new(path).file_name();
+                    let file = file.map(|f| f.to_string_lossy());
+                    format!("{}+{:#x}", file.unwrap_or_default(), offset)
+                }
+            }
+        }
+        None => format!("{:#x}", frame.ip),
+    };
+    name.replace(|c: char| c == ';' || c.is_whitespace(), "_")
+}
+
+/// aggregates syscall time by call stack, see module doc
+#[derive(Debug, Default)]
+pub struct FlameGraph {
+    /// stacks of syscalls entered, by tid, folded
+    entered: HashMap<Pid, String>,
+    /// syscall time, by folded stack
+    folded: BTreeMap<String, Duration>,
+    /// frame names, by file and offset
+    names: HashMap<(String, u64), String>,
+}
+
+impl FlameGraph {
+    pub fn new() -> Self {
+        FlameGraph::default()
+    }
+
+    /// syscall of task `tid` entered with `frames`, innermost first
+    pub fn entered(&mut self, tid: Pid, frames: &[String]) {
+        let stack: Vec<&str> =
+            frames.iter().rev().map(|f| f.as_str()).collect();
+        self.entered.insert(tid, stack.join(";"));
+    }
+
+    // syscall of task `tid` entered with a stack of `frames`, located
+    fn captured(&mut self, tid: Pid, frames: &[Frame]) {
+        let names = &mut self.names;
+        let frames: Vec<String> = frames
+            .iter()
+            .map(|frame| match &frame.location {
+                Some(location) => names
+                    .entry(location.clone())
+                    .or_insert_with(|| frame_name(frame))
+                    .clone(),
+                None => frame_name(frame),
+            })
+            .collect();
+        self.entered(tid, &frames);
+    }
+
+    /// `event` observed by the tracer
+    pub fn record_event(&mut self, event: &TimedEvent) {
+        let tid = event.tid;
+        match &event.event {
+            Event::SyscallExit(syscall, _, elapsed) => {
+                let stack = match self.entered.remove(&tid) {
+                    Some(stack) => stack,
+                    None => return,
+                };
+                let name = format!("{:?}", syscall);
+                let leaf = format!("{}_[k]", name.trim_start_matches("SYS_"));
+                let folded = if stack.is_empty() {
+                    leaf
+                } else {
+                    format!("{};{}", stack, leaf)
+                };
+                *self.folded.entry(folded).or_default() += *elapsed;
+            }
+            Event::SyscallStack(_, frames) => self.captured(tid, frames),
+            Event::Exited(_) => {
+                self.entered.remove(&tid);
+            }
+            _ => (),
+        }
+    }
+
+    /// syscall time, by folded stack
+    pub fn folded(&self) -> &BTreeMap<String, Duration> {
+        &self.folded
+    }
+
+    /// write folded stacks to `out`, in microseconds
+    pub fn write<W: Write>(&self, mut out: W) -> Result<()> {
+        for (stack, elapsed) in &self.folded {
+            writeln!(out, "{} {}", stack, elapsed.as_micros())?;
+        }
+        Ok(())
+    }
+}
+
+/// syscall emulation (declining all syscalls) and event sink aggregating
+/// with `flamegraph`, stacks are to be captured, see
+/// `TaskEventCB::stack_frames`
+pub fn flamegraph_tracing(
+    flamegraph: Rc<RefCell<FlameGraph>>,
+) -> (SyscallEmulation, EventSink) {
+    let syscalls = (0..=SyscallNo::SYS_statx as i32)
+        .map(SyscallNo::from)
+        .collect();
+    let emulation = SyscallEmulation::new(
+        EmulationMode::Seccomp,
+        syscalls,
+        Box::new(|_task, _memory, _syscall, _args| None),
+    );
+    let sink: EventSink =
+        Box::new(move |event| flamegraph.borrow_mut().record_event(event));
+    (emulation, sink)
+}
+
+#[test]
+fn flamegraph_sanity_check() {
+    let tid = Pid::from_raw(i32::MAX);
+    let exit = |syscall, micros| TimedEvent {
+        tid,
+        at: Default::default(),
+        ticks: None,
+        event: Event::SyscallExit(syscall, 0, Duration::from_micros(micros)),
+    };
+    let frames = |names: &[&str]| -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    };
+    let frame = Frame {
+        ip: 0x1234,
+        location: Some((String::from("/usr/lib/app"), 0x234)),
+    };
+    assert_eq!(frame_name(&frame), "app+0x234");
+    let mut flamegraph = FlameGraph::new();
+    for micros in &[10, 5] {
+        flamegraph.entered(tid, &frames(&["read_file", "main", "_start"]));
+        flamegraph.record_event(&exit(SyscallNo::SYS_read, *micros));
+    }
+    flamegraph.entered(tid, &frames(&["main", "_start"]));
+    flamegraph.record_event(&exit(SyscallNo::SYS_write, 3));
+    // not entered
+    flamegraph.record_event(&exit(SyscallNo::SYS_write, 1));
+    assert_eq!(flamegraph.folded().len(), 2);
+    let mut out = Vec::new();
+    flamegraph.write(&mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "_start;main;read_file;read_[k] 15\n_start;main;write_[k] 3\n"
+    );
+}
@@ -0,0 +1,228 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! GOT hooking of library functions
+//!
+//! syscall hooks see the `write`s, not the `fwrite`s buffered into them.
+//! GOT hooks intercept calls of functions instead: the GOT slots (of
+//! `R_X86_64_JUMP_SLOT` and `R_X86_64_GLOB_DAT` relocations) of selected
+//! functions, i.e.: `open`, `read` or `connect`, are redirected to
+//! functions of libraries loaded in the task, see `loader`, in all other
+//! objects mapped. ptrace writes ignore page protections, so that slots
+//! made read-only by RELRO are patched as well.
+//!
+//! replacements get the original by `dlsym(RTLD_NEXT, ..)`, their own
+//! slots are not patched. calls within the object defining the function
+//! (libc calling its own `open`), by pointers from `dlsym`, or from
+//! objects loaded after the patching are not intercepted.
+
+use goblin::elf::header::ET_EXEC;
+use goblin::elf::program_header::PT_LOAD;
+use goblin::elf::Elf;
+use nix::sys::ptrace;
+use nix::unistd::Pid;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use reverie_api::remote::from_nix_error;
+use reverie_api::task::Task;
+
+use crate::loader::{self, R_X86_64_GLOB_DAT, R_X86_64_JUMP_SLOT};
+
+/// calls of `symbol` redirected to `replacement`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GotHook {
+    pub symbol: String,
+    pub replacement: String,
+}
+
+impl FromStr for GotHook {
+    type Err = String;
+    /// `SYMBOL=REPLACEMENT`
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut kv = s.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some(symbol), Some(replacement))
+                if !symbol.is_empty() && !replacement.is_empty() =>
+            {
+                Ok(GotHook {
+                    symbol: symbol.to_string(),
+                    replacement: replacement.to_string(),
+                })
+            }
+            _ => Err(format!("invalid GOT hook: {}, expected SYMBOL=FUNC", s)),
+        }
+    }
+}
+
+impl fmt::Display for GotHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.symbol, self.replacement)
+    }
+}
+
+/// a patched GOT slot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GotPatch {
+    /// object of the slot
+    pub object: PathBuf,
+    pub symbol: String,
+    /// address of the slot
+    pub slot: u64,
+    pub original: u64,
+    pub replacement: u64,
+}
+
+// load bias of `elf` mapped at `start`
+fn load_bias(elf: &Elf, start: u64) -> u64 {
+    if elf.header.e_type == ET_EXEC {
+        return 0;
+    }
+    let first = elf
+        .program_headers
+        .iter()
+        .filter(|ph| ph.p_type == PT_LOAD)
+        .map(|ph| loader::page_down(ph.p_vaddr))
+        .min()
+        .unwrap_or(0);
+    start - first
+}
+
+// address of dynamic symbol `name` defined by `elf` loaded at `bias`
+fn defined_symbol(elf: &Elf, bias: u64, name: &str) -> Option<u64> {
+    elf.dynsyms
+        .iter()
+        .find(|sym| {
+            sym.st_shndx != 0 && sym.st_value != 0 && {
+                &elf.dynstrtab[sym.st_name] == name
+            }
+        })
+        .map(|sym| bias + sym.st_value)
+}
+
+// objects mapped by process `pid`, and where, by their first mapping
+fn mapped_objects(pid: Pid) -> Result<Vec<(PathBuf, u64)>> {
+    let maps = procfs::process::Process::new(pid.as_raw())
+        .and_then(|p| p.maps())
+        .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?;
+    let mut objects: Vec<(PathBuf, u64)> = Vec::new();
+    for map in maps {
+        if let procfs::process::MMapPath::Path(path) = map.pathname {
+            if map.offset == 0 && objects.iter().all(|(p, _)| *p != path) {
+                objects.push((path, map.address.0));
+            }
+        }
+    }
+    Ok(objects)
+}
+
+fn poke_word(tid: Pid, addr: u64, word: u64) -> Result<()> {
+    let at = addr as ptrace::AddressType;
+    ptrace::write(tid, at, word as *mut libc::c_void).map_err(from_nix_error)
+}
+
+/// redirect GOT slots of `hooks` in the objects of stopped `task`, but
+/// `libraries` (loaded at their load bias, see `loader::load_library`)
+/// which define the replacements. returns the patched slots.
+pub fn patch_got(
+    task: &dyn Task,
+    libraries: &[(PathBuf, u64)],
+    hooks: &[GotHook],
+) -> Result<Vec<GotPatch>> {
+    let mut replacements = Vec::new();
+    for hook in hooks {
+        let replacement = libraries.iter().find_map(|(path, bias)| {
+            let bytes = fs::read(path).ok()?;
+            let elf = Elf::parse(&bytes).ok()?;
+            defined_symbol(&elf, *bias, &hook.replacement)
+        });
+        let replacement = replacement.ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("{}: no such function loaded", hook.replacement),
+            )
+        })?;
+        replacements.push((hook.symbol.as_str(), replacement));
+    }
+    let own: Vec<PathBuf> = libraries
+        .iter()
+        .filter_map(|(path, _)| fs::canonicalize(path).ok())
+        .collect();
+
+    let tid = task.gettid();
+    let mut patches = Vec::new();
+    for (object, start) in mapped_objects(task.getpid())? {
+        if own.contains(&object) {
+            continue;
+        }
+        // not an ELF object, i.e.: a locale archive
+        let bytes = match fs::read(&object) {
+            Ok(bytes) => bytes,
+            Err(_) => continue,
+        };
+        let elf = match Elf::parse(&bytes) {
+            Ok(elf) => elf,
+            Err(_) => continue,
+        };
+        let bias = load_bias(&elf, start);
+        for rela in loader::relas_of(&elf, &bytes) {
+            if rela.kind != R_X86_64_GLOB_DAT && rela.kind != R_X86_64_JUMP_SLOT
+            {
+                continue;
+            }
+            let name = match elf.dynsyms.get(rela.sym as usize) {
+                Some(sym) => &elf.dynstrtab[sym.st_name],
+                None => continue,
+            };
+            let replacement = match replacements.iter().find(|r| r.0 == name) {
+                Some((_, replacement)) => *replacement,
+                None => continue,
+            };
+            let slot = bias + rela.offset;
+            let original = ptrace::read(tid, slot as ptrace::AddressType)
+                .map_err(from_nix_error)?;
+            poke_word(tid, slot, replacement)?;
+            patches.push(GotPatch {
+                object: object.clone(),
+                symbol: name.to_string(),
+                slot,
+                original: original as u64,
+                replacement,
+            });
+        }
+    }
+    Ok(patches)
+}
+
+/// restore GOT slots of `patches` in stopped `task`
+pub fn unpatch_got(task: &dyn Task, patches: &[GotPatch]) -> Result<()> {
+    for patch in patches.iter().rev() {
+        poke_word(task.gettid(), patch.slot, patch.original)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn got_sanity_check() {
+    let hook: GotHook = "open=tool_open".parse().unwrap();
+    assert_eq!(hook.symbol, "open");
+    assert_eq!(hook.to_string(), "open=tool_open");
+    assert!("open".parse::<GotHook>().is_err());
+    assert!("=tool_open".parse::<GotHook>().is_err());
+    let exe = fs::read("/proc/self/exe").unwrap();
+    let elf = Elf::parse(&exe).unwrap();
+    assert_eq!(defined_symbol(&elf, 0, "no_such_symbol_at_all"), None);
+}
@@ -17,12 +17,23 @@ use std::path::PathBuf;
 
 use goblin::elf::Elf;
 
+use crate::libc_flavor::*;
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct SyscallHook {
     pub name: String,
     pub offset: u64,
     pub instructions: Vec<u8>,
     pub is_multi: bool,
+    /// libc flavors of programs the hook applies to
+    pub libcs: &'static [LibcFlavor],
+}
+
+impl SyscallHook {
+    /// whether the hook applies to programs of libc `flavor`
+    pub fn applies_to(&self, flavor: LibcFlavor) -> bool {
+        self.libcs.contains(&flavor)
+    }
 }
 
 /// resolve syscall hooks from (LD) preload library
@@ -49,6 +60,7 @@ pub fn resolve_syscall_hooks_from(
                     offset: sym.st_value,
                     instructions: Vec::from(hook.instructions),
                     is_multi: hook.is_multi,
+                    libcs: hook.libcs,
                 });
             }
         }
@@ -69,6 +81,8 @@ struct SyscallPatchHook<'a> {
     is_multi: bool,
     instructions: &'a [u8],
     symbol: &'a str,
+    /// see `libc_flavor`
+    libcs: &'static [LibcFlavor],
 }
 
 const SYSCALL_HOOKS: &[SyscallPatchHook] = &[
@@ -78,6 +92,7 @@ const SYSCALL_HOOKS: &[SyscallPatchHook] = &[
         is_multi: false,
         instructions: &[0x48, 0x3d, 0x01, 0xf0, 0xff, 0xff],
         symbol: "_syscall_hook_trampoline_48_3d_01_f0_ff_ff",
+        libcs: ANY_LIBC,
     },
     /* Many glibc syscall wrappers (e.g. __libc_recv) have 'syscall'
      * followed by
@@ -86,6 +101,7 @@ const SYSCALL_HOOKS: &[SyscallPatchHook] = &[
         is_multi: false,
         instructions: &[0x48, 0x3d, 0x00, 0xf0, 0xff, 0xff],
         symbol: "_syscall_hook_trampoline_48_3d_00_f0_ff_ff",
+        libcs: ANY_LIBC,
     },
     /* Many glibc syscall wrappers (e.g. read) have 'syscall' followed by
      * mov (%rsp),%rdi */
@@ -93,6 +109,7 @@ const SYSCALL_HOOKS: &[SyscallPatchHook] = &[
         is_multi: false,
         instructions: &[0x48, 0x8b, 0x3c, 0x24],
         symbol: "_syscall_hook_trampoline_48_8b_3c_24",
+        libcs: ANY_LIBC,
     },
     /* __lll_unlock_wake has 'syscall' followed by
      * pop %rdx; pop %rsi; ret */
@@ -100,6 +117,7 @@ const SYSCALL_HOOKS: &[SyscallPatchHook] = &[
         is_multi: true,
         instructions: &[0x5a, 0x5e, 0xc3],
         symbol: "_syscall_hook_trampoline_5a_5e_c3",
+        libcs: GLIBC,
     },
     /* posix_fadvise64 has 'syscall' followed by
      * mov %eax,%edx;
@@ -108,6 +126,7 @@ const SYSCALL_HOOKS: &[SyscallPatchHook] = &[
         is_multi: true,
         instructions: &[0x89, 0xc2, 0xf7, 0xda],
         symbol: "_syscall_hook_trampoline_89_c2_f7_da",
+        libcs: GLIBC,
     },
     /* Our VDSO vsyscall patches have 'syscall' followed by
      * nop; nop; nop */
@@ -115,6 +134,7 @@ const SYSCALL_HOOKS: &[SyscallPatchHook] = &[
         is_multi: true,
         instructions: &[0x90, 0x90, 0x90],
         symbol: "_syscall_hook_trampoline_90_90_90",
+        libcs: ANY_LIBC,
     },
     /* glibc-2.22-17.fc23.x86_64 has 'syscall' followed by
      * 'mov $1,%rdx' in pthread_barrier_wait.
@@ -123,6 +143,7 @@ const SYSCALL_HOOKS: &[SyscallPatchHook] = &[
         is_multi: false,
         instructions: &[0xba, 0x01, 0x00, 0x00, 0x00],
         symbol: "_syscall_hook_trampoline_ba_01_00_00_00",
+        libcs: GLIBC,
     },
     /* pthread_sigmask has 'syscall' followed by
      * 'mov %eax,%ecx;
@@ -131,6 +152,7 @@ const SYSCALL_HOOKS: &[SyscallPatchHook] = &[
         is_multi: true,
         instructions: &[0x89, 0xc1, 0x31, 0xd2],
         symbol: "_syscall_hook_trampoline_89_c1_31_d2",
+        libcs: GLIBC,
     },
     /* getpid has 'syscall' followed by
      * 'retq;
@@ -139,6 +161,7 @@ const SYSCALL_HOOKS: &[SyscallPatchHook] = &[
         is_multi: true,
         instructions: &[0xc3, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
         symbol: "_syscall_hook_trampoline_c3_nop",
+        libcs: GLIBC,
     },
     /* liblsan internal_close has 'syscall' followed by
      * 'retq;
@@ -147,6 +170,7 @@ const SYSCALL_HOOKS: &[SyscallPatchHook] = &[
         is_multi: true,
         instructions: &[0xc3, 0x0f, 0x1f, 0x44, 0x00, 0x00],
         symbol: "_syscall_hook_trampoline_c3_nop",
+        libcs: GLIBC,
     },
     /* liblsan internal_open has 'syscall' followed by
      * 'retq;
@@ -155,6 +179,7 @@ const SYSCALL_HOOKS: &[SyscallPatchHook] = &[
         is_multi: true,
         instructions: &[0xc3, 0x0f, 0x1f, 0x00],
         symbol: "_syscall_hook_trampoline_c3_nop",
+        libcs: GLIBC,
     },
     /* liblsan internal_dup2 has 'syscall' followed by
      * 'retq;
@@ -163,6 +188,7 @@ const SYSCALL_HOOKS: &[SyscallPatchHook] = &[
         is_multi: true,
         instructions: &[0xc3, 0x66, 0x90],
         symbol: "_syscall_hook_trampoline_c3_nop",
+        libcs: GLIBC,
     },
     /* ld-linux.so SYS_access has 'syscall' followed by
      * 'test %eax, %eax
@@ -171,6 +197,7 @@ const SYSCALL_HOOKS: &[SyscallPatchHook] = &[
         is_multi: true,
         instructions: &[0x85, 0xc0, 0x0f, 0x94, 0xc2],
         symbol: "_syscall_hook_trampoline_85_c0_0f_94_c2",
+        libcs: GLIBC,
     },
     /* ubuntu 18.04 libc-2.27.so, `syscall` followed by
      * nopl   0x0(%rax)
@@ -179,6 +206,67 @@ const SYSCALL_HOOKS: &[SyscallPatchHook] = &[
         is_multi: false,
         instructions: &[0x0f, 0x1f, 0x80, 0x00, 0x00, 0x00, 0x00],
         symbol: "_syscall_hook_trampoline_90_90_90",
+        libcs: GLIBC,
+    },
+    /* musl syscall wrappers returning by `__syscall_ret` have 'syscall'
+     * followed by
+     * mov %rax,%rdi
+     * jmp __syscall_ret */
+    SyscallPatchHook {
+        is_multi: false,
+        instructions: &[0x48, 0x89, 0xc7],
+        symbol: "_syscall_hook_trampoline_48_89_c7",
+        libcs: MUSL,
+    },
+    /* go `Syscall6` and many go runtime functions (e.g. `sysMmap`) have
+     * 'syscall' followed by
+     * cmp $-4095,%rax */
+    SyscallPatchHook {
+        is_multi: false,
+        instructions: &[0x48, 0x3d, 0x01, 0xf0, 0xff, 0xff],
+        symbol: "_syscall_hook_trampoline_go_48_3d_01_f0_ff_ff",
+        libcs: GO,
+    },
+    /* go runtime `read`, `write1` and `madvise` have 'syscall' followed by
+     * movl %eax,ret+24(FP), i.e.: mov %eax,0x20(%rsp) */
+    SyscallPatchHook {
+        is_multi: false,
+        instructions: &[0x89, 0x44, 0x24, 0x20],
+        symbol: "_syscall_hook_trampoline_go_89_44_24_20",
+        libcs: GO,
+    },
+    /* go runtime `futex` has 'syscall' followed by
+     * movl %eax,ret+40(FP), i.e.: mov %eax,0x30(%rsp) */
+    SyscallPatchHook {
+        is_multi: false,
+        instructions: &[0x89, 0x44, 0x24, 0x30],
+        symbol: "_syscall_hook_trampoline_go_89_44_24_30",
+        libcs: GO,
+    },
+    /* go runtime `gettid` has 'syscall' followed by
+     * movl %eax,ret+0(FP), i.e.: mov %eax,0x8(%rsp) */
+    SyscallPatchHook {
+        is_multi: false,
+        instructions: &[0x89, 0x44, 0x24, 0x08],
+        symbol: "_syscall_hook_trampoline_go_89_44_24_08",
+        libcs: GO,
+    },
+    /* go runtime `usleep`, `tgkill` and `osyield` have 'syscall'
+     * followed by
+     * 'retq;
+     *  int3; int3' (padding) */
+    SyscallPatchHook {
+        is_multi: true,
+        instructions: &[0xc3, 0xcc, 0xcc],
+        symbol: "_syscall_hook_trampoline_go_c3_cc_cc",
+        libcs: GO,
+    },
+    /* Our VDSO vsyscall patches in go programs, see above */
+    SyscallPatchHook {
+        is_multi: true,
+        instructions: &[0x90, 0x90, 0x90],
+        symbol: "_syscall_hook_trampoline_go_90_90_90",
+        libcs: GO,
     },
     /* ubuntu 18.04 pthread_setcanceltype@libc-2.27.so, `syscall` followed by
      * mov    %edx,%eax
@@ -189,6 +277,7 @@ const SYSCALL_HOOKS: &[SyscallPatchHook] = &[
         is_multi: true,
         instructions: &[0x89, 0xd0, 0x87, 0x07],
         symbol: "_syscall_hook_trampoline_89_d0_87_07",
+        libcs: GLIBC,
     },
     */
 ];
@@ -206,3 +295,34 @@ fn syscall_patch_hooks_sanity_check() {
         assert!(hook.instructions.len() <= 12);
     }
 }
+
+#[test]
+fn go_syscall_patch_hooks_sanity_check() {
+    // the first hook of `flavor` matching the bytes after a syscall site
+    let hook = |bytes: &[u8], flavor| {
+        SYSCALL_HOOKS
+            .iter()
+            .find(|hook| {
+                bytes.starts_with(hook.instructions)
+                    && hook.libcs.contains(&flavor)
+            })
+            .map(|hook| hook.symbol)
+    };
+    // `Syscall6`: cmp $-4095,%rax; jls; movq $-1,r1+56(FP)
+    let syscall6 = [0x48, 0x3d, 0x01, 0xf0, 0xff, 0xff, 0x76, 0x20];
+    assert_eq!(
+        hook(&syscall6, LibcFlavor::Go),
+        Some("_syscall_hook_trampoline_go_48_3d_01_f0_ff_ff")
+    );
+    assert_eq!(
+        hook(&syscall6, LibcFlavor::Glibc),
+        Some("_syscall_hook_trampoline_48_3d_01_f0_ff_ff")
+    );
+    // `futex`: movl %eax,ret+40(FP); ret
+    let futex = [0x89, 0x44, 0x24, 0x30, 0xc3];
+    assert!(hook(&futex, LibcFlavor::Go).is_some());
+    assert_eq!(hook(&futex, LibcFlavor::Glibc), None);
+    // sites of glibc with cgo, but not of musl
+    assert!(hook(&[0x5a, 0x5e, 0xc3], LibcFlavor::Go).is_some());
+    assert_eq!(hook(&[0x48, 0x89, 0xc7], LibcFlavor::Go), None);
+}
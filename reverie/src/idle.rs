@@ -0,0 +1,153 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! idle detection of the traced tree
+//!
+//! patched syscalls never stop the tracer, so that a tree without stops
+//! is not necessarily idle. instead, tasks are sampled from
+//! `/proc/<tid>/syscall` while the scheduler waits for stops: the tree is
+//! idle once all tasks are found blocked in the very same syscalls (number,
+//! arguments, stack and pc) for `IdleDetector::after`, often meaning a
+//! service is ready. `Event::Idle` is emitted once, until any task stops
+//! or is found elsewhere.
+//!
+//! NB: a task making the same syscall in a loop between samples looks
+//! blocked, i.e.: `poll` with a short timeout.
+
+use nix::unistd::Pid;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, Instant};
+
+/// samples taken per `IdleDetector::after`
+pub const IDLE_SAMPLES: u32 = 8;
+
+/// parse a duration of `ms` or `s`, i.e.: `500ms`
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let millis = |n: &str, unit| n.parse::<u64>().ok().map(|n| n * unit);
+    s.strip_suffix("ms")
+        .and_then(|n| millis(n, 1))
+        .or_else(|| s.strip_suffix('s').and_then(|n| millis(n, 1_000)))
+        .map(Duration::from_millis)
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid duration: {}, expected ms or s", s),
+            )
+        })
+}
+
+// `contents` of `/proc/<tid>/syscall` if blocked in a syscall, which is
+// `nr args.. sp pc`, rather than `-1 sp pc` or `running`
+fn blocked_syscall(contents: &str) -> Option<&str> {
+    let nr: i64 = contents.split_whitespace().next()?.parse().ok()?;
+    if nr >= 0 {
+        Some(contents.trim())
+    } else {
+        None
+    }
+}
+
+/// detects the traced tree is idle
+pub struct IdleDetector {
+    after: Duration,
+    /// since when tasks are blocked as of `blocked`
+    since: Instant,
+    sampled: Instant,
+    /// syscalls tasks are blocked in, by tid
+    blocked: Vec<(i32, String)>,
+    reported: bool,
+}
+
+impl IdleDetector {
+    /// idle once blocked in syscalls for `after`
+    pub fn new(after: Duration) -> Self {
+        let now = Instant::now();
+        IdleDetector {
+            after,
+            since: now,
+            sampled: now,
+            blocked: Vec::new(),
+            reported: false,
+        }
+    }
+
+    /// a task stopped, the tree is not idle
+    pub fn activity(&mut self) {
+        self.since = Instant::now();
+        self.blocked.clear();
+        self.reported = false;
+    }
+
+    // all `tids` blocked in a syscall, if they are
+    fn sample<I: Iterator<Item = Pid>>(tids: I) -> Option<Vec<(i32, String)>> {
+        let mut blocked = tids
+            .map(|tid| {
+                let path = format!("/proc/{}/syscall", tid);
+                let contents = fs::read_to_string(path).ok()?;
+                let syscall = blocked_syscall(&contents)?.to_string();
+                Some((tid.as_raw(), syscall))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        blocked.sort();
+        Some(blocked)
+    }
+
+    /// sample `tids`, all tasks waited for: time blocked in the same
+    /// syscalls, once over `after`
+    pub fn poll<I: Iterator<Item = Pid>>(
+        &mut self,
+        tids: I,
+    ) -> Option<Duration> {
+        let now = Instant::now();
+        if self.reported
+            || now.duration_since(self.sampled) < self.after / IDLE_SAMPLES
+        {
+            return None;
+        }
+        self.sampled = now;
+        match Self::sample(tids) {
+            Some(ref blocked) if *blocked == self.blocked => (),
+            Some(blocked) => {
+                self.blocked = blocked;
+                self.since = now;
+            }
+            None => self.activity(),
+        }
+        let idle = now.duration_since(self.since);
+        if !self.blocked.is_empty() && idle >= self.after {
+            self.reported = true;
+            Some(idle)
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn idle_sanity_check() {
+    assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+    assert_eq!(parse_duration("2s").unwrap(), Duration::from_secs(2));
+    assert!(parse_duration("2").is_err());
+    assert_eq!(
+        blocked_syscall("7 0x7ffd 0x1 0x2 0x0 0x0 0x0 0x7ffc 0x7f01\n"),
+        Some("7 0x7ffd 0x1 0x2 0x0 0x0 0x0 0x7ffc 0x7f01")
+    );
+    assert_eq!(blocked_syscall("-1 0x7ffc 0x7f01"), None);
+    assert_eq!(blocked_syscall("running"), None);
+    // no such task
+    let mut detector = IdleDetector::new(Duration::from_millis(0));
+    let tids = || std::iter::once(Pid::from_raw(i32::MAX));
+    assert_eq!(detector.poll(tids()), None);
+    assert!(detector.blocked.is_empty());
+}
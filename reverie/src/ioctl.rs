@@ -0,0 +1,336 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! `ioctl` requests and `fcntl` commands, of recordings
+//!
+//! the buffer an `ioctl` request (or `fcntl` command) is given is decoded
+//! as a `Command`: its size, and whether the kernel reads it, writes it or
+//! both. requests of the tty layer (i.e.: `TCGETS`, `TIOCGWINSZ`,
+//! `FIONREAD`) have no size encoded, they are known by number, others are
+//! decoded by their `_IOC` bits.
+//!
+//! buffers written are recorded as `Event::SyscallData`, see
+//! `recording::syscall_output`, and replayed: the recorded data is written
+//! instead of running the command again, see `command_replay`, so that
+//! programs recorded on a terminal replay the same without one.
+
+use nix::unistd::Pid;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use syscalls::SyscallNo;
+
+use reverie_api::emulate::*;
+
+use crate::recording::Recording;
+
+/// how the kernel uses the buffer of a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// read by the kernel
+    In,
+    /// written by the kernel
+    Out,
+    /// read, then written by the kernel
+    InOut,
+}
+
+/// an `ioctl` request, or `fcntl` command, decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Command {
+    /// name, if known
+    pub name: Option<&'static str>,
+    /// buffer the argument points to, `None` if a scalar (or unknown)
+    pub buffer: Option<(Direction, usize)>,
+}
+
+impl Command {
+    /// size of the buffer written by the kernel, if any
+    pub fn output(&self) -> Option<usize> {
+        match self.buffer {
+            Some((Direction::Out, size)) | Some((Direction::InOut, size)) => {
+                Some(size)
+            }
+            _ => None,
+        }
+    }
+}
+
+// size of the kernel `struct termios`, rather than glibc's
+const TERMIOS_SIZE: usize = 36;
+const WINSIZE_SIZE: usize = 8;
+const INT_SIZE: usize = 4;
+const FLOCK_SIZE: usize = 32;
+const F_OWNER_EX_SIZE: usize = 8;
+
+// tty requests, without size encoded, `asm-generic/ioctls.h`
+const TTY_REQUESTS: &[(u64, &str, Option<(Direction, usize)>)] = &[
+    (0x5401, "TCGETS", Some((Direction::Out, TERMIOS_SIZE))),
+    (0x5402, "TCSETS", Some((Direction::In, TERMIOS_SIZE))),
+    (0x5403, "TCSETSW", Some((Direction::In, TERMIOS_SIZE))),
+    (0x5404, "TCSETSF", Some((Direction::In, TERMIOS_SIZE))),
+    (0x540e, "TIOCSCTTY", None),
+    (0x540f, "TIOCGPGRP", Some((Direction::Out, INT_SIZE))),
+    (0x5410, "TIOCSPGRP", Some((Direction::In, INT_SIZE))),
+    (0x5411, "TIOCOUTQ", Some((Direction::Out, INT_SIZE))),
+    (0x5413, "TIOCGWINSZ", Some((Direction::Out, WINSIZE_SIZE))),
+    (0x5414, "TIOCSWINSZ", Some((Direction::In, WINSIZE_SIZE))),
+    (0x541b, "FIONREAD", Some((Direction::Out, INT_SIZE))),
+    (0x5421, "FIONBIO", Some((Direction::In, INT_SIZE))),
+    (0x5429, "TIOCGSID", Some((Direction::Out, INT_SIZE))),
+    (0x5450, "FIONCLEX", None),
+    (0x5451, "FIOCLEX", None),
+    (0x5452, "FIOASYNC", Some((Direction::In, INT_SIZE))),
+];
+
+// `fcntl` commands with a buffer, others take or return scalars
+const FCNTL_COMMANDS: &[(u64, &str, Option<(Direction, usize)>)] = &[
+    (0, "F_DUPFD", None),
+    (1, "F_GETFD", None),
+    (2, "F_SETFD", None),
+    (3, "F_GETFL", None),
+    (4, "F_SETFL", None),
+    (5, "F_GETLK", Some((Direction::InOut, FLOCK_SIZE))),
+    (6, "F_SETLK", Some((Direction::In, FLOCK_SIZE))),
+    (7, "F_SETLKW", Some((Direction::In, FLOCK_SIZE))),
+    (15, "F_SETOWN_EX", Some((Direction::In, F_OWNER_EX_SIZE))),
+    (16, "F_GETOWN_EX", Some((Direction::Out, F_OWNER_EX_SIZE))),
+    (36, "F_OFD_GETLK", Some((Direction::InOut, FLOCK_SIZE))),
+    (37, "F_OFD_SETLK", Some((Direction::In, FLOCK_SIZE))),
+    (38, "F_OFD_SETLKW", Some((Direction::In, FLOCK_SIZE))),
+    (1030, "F_DUPFD_CLOEXEC", None),
+    (1035, "F_GET_RW_HINT", Some((Direction::Out, 8))),
+    (1036, "F_SET_RW_HINT", Some((Direction::In, 8))),
+];
+
+fn lookup(
+    table: &[(u64, &'static str, Option<(Direction, usize)>)],
+    nr: u64,
+) -> Option<Command> {
+    table
+        .iter()
+        .find(|(known, _, _)| *known == nr)
+        .map(|&(_, name, buffer)| Command {
+            name: Some(name),
+            buffer,
+        })
+}
+
+/// decode `ioctl` request `request`
+pub fn decode_ioctl(request: u64) -> Command {
+    let request = request & 0xffff_ffff;
+    if let Some(command) = lookup(TTY_REQUESTS, request) {
+        return command;
+    }
+    // `_IOC(dir, type, nr, size)`: dir is of userspace, `_IOC_WRITE` for
+    // the kernel to read the buffer
+    let size = (request >> 16) as usize & 0x3fff;
+    let direction = match request >> 30 {
+        1 => Some(Direction::In),
+        2 => Some(Direction::Out),
+        3 => Some(Direction::InOut),
+        _ => None,
+    };
+    Command {
+        name: None,
+        buffer: direction.filter(|_| size > 0).map(|dir| (dir, size)),
+    }
+}
+
+/// decode `fcntl` command `cmd`
+pub fn decode_fcntl(cmd: u64) -> Command {
+    lookup(FCNTL_COMMANDS, cmd).unwrap_or(Command {
+        name: None,
+        buffer: None,
+    })
+}
+
+/// command `cmd` of `syscall`, `ioctl` or `fcntl`
+pub fn command_of(syscall: SyscallNo, cmd: u64) -> Option<Command> {
+    match syscall {
+        SyscallNo::SYS_ioctl => Some(decode_ioctl(cmd)),
+        SyscallNo::SYS_fcntl => Some(decode_fcntl(cmd)),
+        _ => None,
+    }
+}
+
+/// buffer written by a (successful) `ioctl` or `fcntl`, with `regs` at its
+/// exit: address and size
+pub fn command_output(
+    syscall: SyscallNo,
+    regs: &libc::user_regs_struct,
+) -> Option<(u64, usize)> {
+    let retval = regs.rax as i64;
+    if retval < 0 || regs.rdx == 0 {
+        return None;
+    }
+    let size = command_of(syscall, regs.rsi)?.output()?;
+    Some((regs.rdx, size))
+}
+
+/// buffers written by commands of a recording, by logical task, see
+/// `command_replay`
+#[derive(Debug, Default)]
+pub struct RecordedCommands {
+    outputs: HashMap<usize, VecDeque<(SyscallNo, Vec<u8>)>>,
+}
+
+impl RecordedCommands {
+    pub fn new(recording: &Recording) -> Self {
+        let mut outputs: HashMap<_, VecDeque<_>> = HashMap::new();
+        for event in &recording.events {
+            let syscall = match event.event.split(',').next() {
+                Some("SyscallData(SYS_ioctl") => SyscallNo::SYS_ioctl,
+                Some("SyscallData(SYS_fcntl") => SyscallNo::SYS_fcntl,
+                _ => continue,
+            };
+            if let Some(data) = event.syscall_data(&recording.blocks) {
+                let outputs = outputs.entry(event.thread).or_default();
+                outputs.push_back((syscall, data));
+            }
+        }
+        RecordedCommands { outputs }
+    }
+
+    /// next buffer of `size` bytes written by `syscall` of logical task
+    /// `thread`, `None` if the next one recorded differs
+    pub fn next(
+        &mut self,
+        thread: usize,
+        syscall: SyscallNo,
+        size: usize,
+    ) -> Option<Vec<u8>> {
+        let outputs = self.outputs.get_mut(&thread)?;
+        match outputs.front() {
+            Some((recorded, data))
+                if *recorded == syscall && data.len() == size =>
+            {
+                outputs.pop_front().map(|(_, data)| data)
+            }
+            _ => None,
+        }
+    }
+
+    /// number of buffers left
+    pub fn len(&self) -> usize {
+        self.outputs.values().map(|outputs| outputs.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// syscall emulation replaying the buffers written by `ioctl` and `fcntl`
+/// from `commands`, tasks are named by `thread`
+pub fn command_replay(
+    commands: Rc<RefCell<RecordedCommands>>,
+    thread: Box<dyn Fn(Pid) -> Option<usize>>,
+) -> SyscallEmulation {
+    SyscallEmulation::new(
+        EmulationMode::Seccomp,
+        vec![SyscallNo::SYS_ioctl, SyscallNo::SYS_fcntl],
+        Box::new(move |task, memory, syscall, args| {
+            let size = command_of(syscall, args.arg1)?.output()?;
+            if args.arg2 == 0 {
+                return None;
+            }
+            let thread = thread(task.gettid())?;
+            let data = commands.borrow_mut().next(thread, syscall, size)?;
+            memory.write_bytes(args.arg2, &data).ok()?;
+            Some(0)
+        }),
+    )
+}
+
+#[test]
+fn ioctl_sanity_check() {
+    let tcgets = decode_ioctl(0x5401);
+    assert_eq!(tcgets.name, Some("TCGETS"));
+    assert_eq!(tcgets.output(), Some(TERMIOS_SIZE));
+    assert_eq!(decode_ioctl(0x5414).output(), None);
+    assert_eq!(decode_ioctl(0x5451).buffer, None);
+    // TIOCGPTN, `_IOR('T', 0x30, unsigned int)`
+    let tiocgptn = decode_ioctl(0x8004_5430);
+    assert_eq!(tiocgptn.buffer, Some((Direction::Out, 4)));
+    // TIOCSPTLCK, `_IOW('T', 0x31, int)`
+    assert_eq!(decode_ioctl(0x4004_5431).buffer, Some((Direction::In, 4)));
+    assert_eq!(decode_fcntl(5).output(), Some(FLOCK_SIZE));
+    assert_eq!(decode_fcntl(4).buffer, None);
+    assert_eq!(decode_fcntl(99).name, None);
+
+    let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+    regs.rsi = 0x5413;
+    regs.rdx = 0x7000_0000;
+    let tiocgwinsz = command_output(SyscallNo::SYS_ioctl, &regs);
+    assert_eq!(tiocgwinsz, Some((0x7000_0000, WINSIZE_SIZE)));
+    regs.rax = -libc::ENOTTY as i64 as u64;
+    assert_eq!(command_output(SyscallNo::SYS_ioctl, &regs), None);
+}
+
+#[test]
+fn command_replay_sanity_check() {
+    use crate::recording::*;
+    use reverie_api::event::*;
+
+    let mut recorder = EventRecorder::new();
+    let tid = Pid::from_raw(100);
+    let event = |event| TimedEvent {
+        tid,
+        at: Default::default(),
+        ticks: None,
+        event,
+    };
+    let winsize = vec![24, 0, 80, 0, 0, 0, 0, 0];
+    let data = Event::SyscallData(SyscallNo::SYS_ioctl, winsize.clone());
+    let read = Event::SyscallData(SyscallNo::SYS_read, vec![1, 2]);
+    let events = vec![
+        recorder.record(&event(read)).unwrap(),
+        recorder.record(&event(data)).unwrap(),
+    ];
+    let blocks = recorder
+        .take_blocks()
+        .into_iter()
+        .map(|block| {
+            let data = (0..block.data.len())
+                .step_by(2)
+                .map(|k| u8::from_str_radix(&block.data[k..k + 2], 16))
+                .collect::<std::result::Result<_, _>>()
+                .unwrap();
+            (block.block, data)
+        })
+        .collect();
+    let recording = Recording {
+        header: RecordingHeader {
+            program: String::from("/bin/stty"),
+            args: Vec::new(),
+            cwd: std::path::PathBuf::from("/"),
+            data: true,
+            data_selectors: Vec::new(),
+            ticks: false,
+            timeslice: None,
+        },
+        events,
+        blocks,
+    };
+    let mut commands = RecordedCommands::new(&recording);
+    assert_eq!(commands.len(), 1);
+    // not the buffer recorded next
+    assert_eq!(commands.next(0, SyscallNo::SYS_ioctl, TERMIOS_SIZE), None);
+    assert_eq!(commands.next(0, SyscallNo::SYS_fcntl, WINSIZE_SIZE), None);
+    assert_eq!(
+        commands.next(0, SyscallNo::SYS_ioctl, WINSIZE_SIZE),
+        Some(winsize)
+    );
+    assert!(commands.is_empty());
+}
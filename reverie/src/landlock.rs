@@ -0,0 +1,201 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! landlock rulesets, complementing hermetic mode
+//!
+//! `Hermetic` denies paths of the syscalls the tracer sees only, patched
+//! syscalls bypass it. with `--landlock`, the allowlist is compiled into a
+//! `LandlockRuleset` the tracee installs before exec, so that the kernel
+//! enforces it for every syscall, and for the whole tree. denials seen by
+//! the tracer are still reported by `Hermetic`.
+//!
+//! NB: paths are resolved when the ruleset is installed, i.e.: `/proc/self`
+//! is of the initial process, and paths created later cannot be allowed.
+
+use std::fs::OpenOptions;
+use std::io::{Error, Result};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+pub const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+pub const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+pub const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+/// `MAKE_SYM` and the rights before it, of abi 1
+const ACCESS_FS_ABI_1: u64 = (1 << 13) - 1;
+pub const ACCESS_FS_REFER: u64 = 1 << 13;
+pub const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+/// rights which apply to files, others apply to directories only
+const ACCESS_FILE: u64 = ACCESS_FS_EXECUTE
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_READ_FILE
+    | ACCESS_FS_TRUNCATE;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// landlock abi version of the running kernel, `None` if unsupported
+pub fn abi_version() -> Option<u32> {
+    let version = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if version > 0 {
+        Some(version as u32)
+    } else {
+        None
+    }
+}
+
+/// filesystem rights known by landlock `abi`
+pub fn handled_access(abi: u32) -> u64 {
+    match abi {
+        0 => 0,
+        1 => ACCESS_FS_ABI_1,
+        2 => ACCESS_FS_ABI_1 | ACCESS_FS_REFER,
+        _ => ACCESS_FS_ABI_1 | ACCESS_FS_REFER | ACCESS_FS_TRUNCATE,
+    }
+}
+
+/// `path`, and everything under it, allowed `access`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LandlockRule {
+    pub path: PathBuf,
+    pub access: u64,
+}
+
+/// paths allowed, everything else is denied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LandlockRuleset {
+    pub abi: u32,
+    /// rights restricted by the ruleset
+    pub handled: u64,
+    pub rules: Vec<LandlockRule>,
+}
+
+impl LandlockRuleset {
+    /// ruleset of landlock `abi` allowing all of `allowlist`. paths which
+    /// don't exist are skipped.
+    pub fn new<P: AsRef<Path>>(abi: u32, allowlist: &[P]) -> Self {
+        let handled = handled_access(abi);
+        let rules = allowlist
+            .iter()
+            .filter_map(|path| {
+                let path = path.as_ref();
+                let meta = std::fs::metadata(path).ok()?;
+                let access = if meta.is_dir() {
+                    handled
+                } else {
+                    handled & ACCESS_FILE
+                };
+                Some(LandlockRule {
+                    path: path.to_path_buf(),
+                    access,
+                })
+            })
+            .collect();
+        LandlockRuleset {
+            abi,
+            handled,
+            rules,
+        }
+    }
+
+    /// restrict the calling process, and its children to be, to the
+    /// ruleset. `PR_SET_NO_NEW_PRIVS` must be set.
+    pub fn restrict_self(&self) -> Result<()> {
+        let attr = RulesetAttr {
+            handled_access_fs: self.handled,
+        };
+        let ruleset = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_CREATE_RULESET,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if ruleset < 0 {
+            return Err(Error::last_os_error());
+        }
+        let res = self.add_rules(ruleset as i32).and_then(|_| {
+            let res = unsafe {
+                libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset, 0)
+            };
+            if res < 0 {
+                Err(Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        });
+        unsafe { libc::close(ruleset as i32) };
+        res
+    }
+
+    fn add_rules(&self, ruleset: i32) -> Result<()> {
+        for rule in &self.rules {
+            let file = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_PATH)
+                .open(&rule.path)?;
+            let attr = PathBeneathAttr {
+                allowed_access: rule.access,
+                parent_fd: file.as_raw_fd(),
+            };
+            let res = unsafe {
+                libc::syscall(
+                    SYS_LANDLOCK_ADD_RULE,
+                    ruleset,
+                    LANDLOCK_RULE_PATH_BENEATH,
+                    &attr as *const PathBeneathAttr,
+                    0,
+                )
+            };
+            if res < 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn landlock_ruleset_sanity_check() {
+    assert_eq!(handled_access(1) & ACCESS_FS_REFER, 0);
+    assert_eq!(handled_access(3) & ACCESS_FS_TRUNCATE, ACCESS_FS_TRUNCATE);
+    let allowlist = ["/", "/proc/self/exe", "/reverie/no/such/path"];
+    let ruleset = LandlockRuleset::new(3, &allowlist);
+    assert_eq!(ruleset.rules.len(), 2);
+    assert_eq!(ruleset.rules[0].access, handled_access(3));
+    assert_eq!(ruleset.rules[1].access, ACCESS_FILE);
+}
@@ -27,17 +27,76 @@ extern crate lazy_static;
 pub use reverie_common;
 pub use syscalls;
 
+pub mod adaptive;
+pub mod attach;
 pub mod aux;
 pub mod auxv;
+pub mod binaries;
 pub mod block_events;
+pub mod budget;
+pub mod capture_output;
+pub mod chrome_trace;
+pub mod clone_flags;
 pub mod config;
+pub mod contention;
+pub mod control;
+pub mod coverage;
 pub mod debug;
+pub mod deps;
+pub mod detach;
+pub mod dirent_filter;
+pub mod doctor;
+pub mod dying;
+pub mod ebpf;
+pub mod exec;
+pub mod fileless;
+pub mod flaky;
+pub mod flamegraph;
+pub mod got;
+pub mod hermetic;
 pub mod hooks;
+pub mod idle;
+pub mod ioctl;
+pub mod landlock;
+pub mod libc_flavor;
+pub mod loader;
+pub mod malloc_stats;
+pub mod mapping;
+pub mod memory_limit;
+pub mod nesting;
 pub mod ns;
+pub mod ordered_output;
+pub mod overhead;
+pub mod passthrough;
 pub mod patcher;
+pub mod paths;
+pub mod pinning;
+pub mod probes;
+pub mod process;
+pub mod provenance;
+pub mod quiesce;
+pub mod recording;
+pub mod relocate;
 pub mod remote_rwlock;
+pub mod report;
 pub mod rpc_ptrace;
+pub mod runtimes;
 pub mod sched_wait;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod scrub;
+pub mod session;
+pub mod shm;
+pub mod signal_filter;
 pub mod stubs;
+pub mod sysemu;
+pub mod task_limits;
+pub mod ticks;
+pub mod tool;
 pub mod traced_task;
+pub mod unwind;
 pub mod vdso;
+pub mod violation;
+pub mod vsyscall;
+pub mod wait_filter;
+pub mod workers;
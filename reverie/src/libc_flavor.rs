@@ -0,0 +1,259 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! libc flavor of programs
+//!
+//! syscall sites are patched by patterns of the code following `syscall`
+//! (see `hooks`), which depend on the libc the program is linked against.
+//! the flavor is detected on exec, by the program interpreter, and selects
+//! patterns of the process: glibc patterns are of glibc programs only, and
+//! musl patterns of musl programs only. static programs have no
+//! interpreter, their flavor is unknown, and patched as glibc's.
+//!
+//! musl's `__syscall_cp_asm` has `syscall; ret`, the `ret` being
+//! `__cp_end`: thread cancellation compares the ip of interrupted syscalls
+//! against `__cp_begin`..`__cp_end`, so that syscalls followed by `ret` are
+//! never patched in musl programs.
+//!
+//! the trampoline keeps its state in the private page
+//! (`REVERIE_LOCAL_*`), never in TLS, and does not switch stacks: it works
+//! with musl threads as is, but tools' hooks run on stacks of musl threads,
+//! 128K by default, instead of 8M for glibc. the preloader loads the tool
+//! in its own namespace by `dlmopen` with glibc only, and in the default
+//! namespace with musl; both must be built against the libc of the tracee.
+//!
+//! go programs (with a go build id note) issue raw syscalls from the
+//! runtime, followed by `cmp $-4095,%rax` (`Syscall6`), by storing `%eax`
+//! to the result on the stack, or by `ret` and `int3` padding. goroutine
+//! stacks are a few KB, hence go hooks switch to the signal stack of the
+//! thread (`sigaltstack`, 32K, set by the runtime for each M), unless
+//! already on it, i.e.: syscalls of signal handlers. the runtime preempts
+//! goroutines by `SIGURG`, which is deferred while in hooks: their ip is
+//! not an async safe point. go programs with cgo are patched by glibc
+//! patterns too, for sites of libc.
+
+use std::fs::File;
+use std::io::{Read, Result, Seek, SeekFrom};
+use std::path::Path;
+
+/// libc a program is linked against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LibcFlavor {
+    Glibc,
+    Musl,
+    /// go runtime, with glibc if cgo is used
+    Go,
+    /// static programs, or any other libc
+    Unknown,
+}
+
+impl Default for LibcFlavor {
+    fn default() -> Self {
+        LibcFlavor::Unknown
+    }
+}
+
+/// flavors patterns of any c library apply to
+pub const ANY_LIBC: &[LibcFlavor] =
+    &[LibcFlavor::Glibc, LibcFlavor::Musl, LibcFlavor::Unknown];
+
+/// flavors glibc patterns apply to
+pub const GLIBC: &[LibcFlavor] =
+    &[LibcFlavor::Glibc, LibcFlavor::Go, LibcFlavor::Unknown];
+
+/// flavors musl patterns apply to
+pub const MUSL: &[LibcFlavor] = &[LibcFlavor::Musl];
+
+/// flavors go patterns apply to
+pub const GO: &[LibcFlavor] = &[LibcFlavor::Go];
+
+const PT_INTERP: u32 = 3;
+const PT_NOTE: u32 = 4;
+
+/// type of the go build id note, of name `Go`
+const NT_GO_BUILDID: u32 = 4;
+
+/// max size of notes read
+const MAX_NOTES: u64 = 0x1_0000;
+
+/// max size of a program interpreter path
+const MAX_INTERP: u64 = 4096;
+
+impl LibcFlavor {
+    /// flavor of programs with interpreter `interp`, i.e.:
+    /// `/lib/ld-musl-x86_64.so.1`
+    pub fn from_interp(interp: &str) -> Self {
+        let name = interp.rsplit('/').next().unwrap_or(interp);
+        if name.starts_with("ld-musl-") {
+            LibcFlavor::Musl
+        } else if name.starts_with("ld-linux") {
+            LibcFlavor::Glibc
+        } else {
+            LibcFlavor::Unknown
+        }
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, bytes: &mut [u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(bytes)
+}
+
+fn u32_at(bytes: &[u8], k: usize) -> u32 {
+    let mut word = [0u8; 4];
+    word.copy_from_slice(&bytes[k..k + 4]);
+    u32::from_le_bytes(word)
+}
+
+fn u64_at(bytes: &[u8], k: usize) -> u64 {
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&bytes[k..k + 8]);
+    u64::from_le_bytes(word)
+}
+
+/// (type, file offset, file size) of segments of 64-bit elf program `file`
+fn program_headers(file: &mut File) -> Result<Vec<(u32, u64, u64)>> {
+    let mut ehdr = [0u8; 64];
+    read_at(file, 0, &mut ehdr)?;
+    if &ehdr[..4] != b"\x7fELF" || ehdr[4] != 2 {
+        return Ok(Vec::new());
+    }
+    let u16_at = |k: usize| u16::from_le_bytes([ehdr[k], ehdr[k + 1]]);
+    let phoff = u64_at(&ehdr, 0x20);
+    let (phentsize, phnum) = (u16_at(0x36), u16_at(0x38));
+    let mut phdr = vec![0u8; usize::from(phentsize).max(56)];
+    let mut res = Vec::new();
+    for k in 0..u64::from(phnum) {
+        read_at(file, phoff + k * u64::from(phentsize), &mut phdr)?;
+        res.push((u32_at(&phdr, 0), u64_at(&phdr, 8), u64_at(&phdr, 32)));
+    }
+    Ok(res)
+}
+
+/// interpreter (`PT_INTERP`) of 64-bit elf program `path`, if any
+pub fn program_interp(path: &Path) -> Result<Option<String>> {
+    let mut file = File::open(path)?;
+    let phdrs = program_headers(&mut file)?;
+    let interp = phdrs.iter().find(|(ty, _, _)| *ty == PT_INTERP);
+    let (offset, size) = match interp {
+        Some((_, offset, size)) => (*offset, (*size).min(MAX_INTERP)),
+        None => return Ok(None),
+    };
+    let mut interp = vec![0u8; size as usize];
+    read_at(&mut file, offset, &mut interp)?;
+    let len = interp.iter().position(|c| *c == 0).unwrap_or(interp.len());
+    interp.truncate(len);
+    Ok(Some(String::from_utf8_lossy(&interp).into_owned()))
+}
+
+/// whether `notes` (of a `PT_NOTE` segment) has a go build id
+fn has_go_buildid(notes: &[u8]) -> bool {
+    let align4 = |n: usize| (n + 3) & !3;
+    let mut k = 0;
+    while k + 12 <= notes.len() {
+        let namesz = u32_at(notes, k) as usize;
+        let descsz = u32_at(notes, k + 4) as usize;
+        let ty = u32_at(notes, k + 8);
+        let name = notes.get(k + 12..k + 12 + namesz).unwrap_or(&[]);
+        if ty == NT_GO_BUILDID && name.starts_with(b"Go\0") {
+            return true;
+        }
+        k += 12 + align4(namesz) + align4(descsz);
+    }
+    false
+}
+
+/// whether 64-bit elf program `path` is built by go
+pub fn program_is_go(path: &Path) -> Result<bool> {
+    let mut file = File::open(path)?;
+    for (ty, offset, size) in program_headers(&mut file)? {
+        if ty != PT_NOTE {
+            continue;
+        }
+        let mut notes = vec![0u8; size.min(MAX_NOTES) as usize];
+        read_at(&mut file, offset, &mut notes)?;
+        if has_go_buildid(&notes) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// libc flavor of program `path`
+pub fn program_flavor(path: &Path) -> LibcFlavor {
+    if program_is_go(path).unwrap_or(false) {
+        return LibcFlavor::Go;
+    }
+    match program_interp(path) {
+        Ok(Some(interp)) => LibcFlavor::from_interp(&interp),
+        _ => LibcFlavor::Unknown,
+    }
+}
+
+#[test]
+fn libc_flavor_sanity_check() {
+    let musl = "/lib/ld-musl-x86_64.so.1";
+    assert_eq!(LibcFlavor::from_interp(musl), LibcFlavor::Musl);
+    let glibc = "/lib64/ld-linux-x86-64.so.2";
+    assert_eq!(LibcFlavor::from_interp(glibc), LibcFlavor::Glibc);
+    assert_eq!(LibcFlavor::from_interp(""), LibcFlavor::Unknown);
+    let exe = std::fs::read_link("/proc/self/exe").unwrap();
+    assert!(program_interp(&exe).is_ok());
+    let status = Path::new("/proc/self/status");
+    assert_eq!(program_interp(status).unwrap(), None);
+    assert!(!program_is_go(&exe).unwrap());
+    let note = b"\x04\0\0\0\x08\0\0\0\x04\0\0\0Go\0\0buildid!";
+    assert!(has_go_buildid(note));
+    assert!(!has_go_buildid(&note[..12]));
+}
+
+#[test]
+fn program_flavor_sanity_check() {
+    // elf header, program headers of `PT_INTERP` and `PT_NOTE`, then both
+    let interp = b"/lib64/ld-linux-x86-64.so.2\0";
+    let note = b"\x04\0\0\0\x08\0\0\0\x04\0\0\0Go\0\0buildid!";
+    let program = |phdrs: &[(u32, &[u8])]| {
+        let mut elf = vec![0u8; 64];
+        elf[..5].copy_from_slice(b"\x7fELF\x02");
+        elf[0x20..0x28].copy_from_slice(&64u64.to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+        elf[0x38..0x3a].copy_from_slice(&(phdrs.len() as u16).to_le_bytes());
+        let mut offset = 64 + 56 * phdrs.len() as u64;
+        for (ty, data) in phdrs {
+            let mut phdr = vec![0u8; 56];
+            phdr[..4].copy_from_slice(&ty.to_le_bytes());
+            phdr[8..16].copy_from_slice(&offset.to_le_bytes());
+            phdr[32..40].copy_from_slice(&(data.len() as u64).to_le_bytes());
+            elf.extend(phdr);
+            offset += data.len() as u64;
+        }
+        for (_, data) in phdrs {
+            elf.extend_from_slice(data);
+        }
+        let path = std::env::temp_dir().join(format!(
+            "flavor-{}-{}",
+            std::process::id(),
+            phdrs.len()
+        ));
+        std::fs::write(&path, elf).unwrap();
+        let flavor = program_flavor(&path);
+        std::fs::remove_file(&path).unwrap();
+        flavor
+    };
+    let glibc = program(&[(PT_INTERP, interp)]);
+    assert_eq!(glibc, LibcFlavor::Glibc);
+    // go with cgo
+    let go = program(&[(PT_INTERP, interp), (PT_NOTE, note)]);
+    assert_eq!(go, LibcFlavor::Go);
+    assert_eq!(program(&[(PT_NOTE, &note[..12])]), LibcFlavor::Unknown);
+}
@@ -0,0 +1,647 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! loading shared libraries in tasks
+//!
+//! `load_library` loads a shared library in a stopped task, i.e.: at its
+//! exec event, or just attached, and returns its load bias. it is loaded by
+//! the task's own `dlopen` (`__libc_dlopen_mode` before glibc 2.34) once
+//! its libc is loaded, which loads its dependencies and runs constructors.
+//! it is mapped raw otherwise: `PT_LOAD` segments are mapped from the
+//! library (opened by the task), and relocations applied, which must not
+//! refer to symbols of other objects. constructors of libraries mapped raw
+//! are not run.
+//!
+//! syscalls and calls are run from `syscall; int3` written at the pc of the
+//! task, and restored after, so that the private page of the tracer is not
+//! needed. the task must not be in a syscall-enter stop, nor hold locks
+//! `dlopen` takes, i.e.: stopped inside `malloc`. signals it receives
+//! meanwhile are dropped, but faults, which fail the loading.
+//!
+//! `dlopen` and `dlsym` call the task's own, to inject instrumentation
+//! late. other threads of the task are stopped while loading, see
+//! `quiesce::stop_others`, not to race them into the dynamic linker, nor
+//! into the `syscall; int3` at the pc, which is their code too.
+
+use goblin::elf::dynamic::{DT_JMPREL, DT_PLTRELSZ, DT_RELA, DT_RELASZ};
+use goblin::elf::header::ET_DYN;
+use goblin::elf::program_header::{PF_R, PF_W, PF_X, PT_LOAD};
+use goblin::elf::sym::STB_WEAK;
+use goblin::elf::Elf;
+use nix::sys::wait::{self, WaitPidFlag, WaitStatus};
+use nix::sys::{ptrace, signal};
+use nix::unistd::Pid;
+use std::ffi::CString;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::Duration;
+use syscalls::*;
+
+use reverie_api::remote::*;
+use reverie_api::task::Task;
+
+use crate::quiesce;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// bytes below the stack pointer a leaf function may use
+const RED_ZONE: u64 = 128;
+
+/// `RTLD_NOW` of `dlopen`
+const RTLD_NOW: u64 = 2;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+pub(crate) const R_X86_64_GLOB_DAT: u32 = 6;
+pub(crate) const R_X86_64_JUMP_SLOT: u32 = 7;
+const R_X86_64_RELATIVE: u32 = 8;
+
+pub(crate) fn page_down(addr: u64) -> u64 {
+    addr & !(PAGE_SIZE - 1)
+}
+
+fn page_up(addr: u64) -> u64 {
+    page_down(addr + PAGE_SIZE - 1)
+}
+
+fn invalid<E: ToString>(path: &Path, err: E) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), err.to_string()),
+    )
+}
+
+/// `Elf64_Rela`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rela {
+    pub offset: u64,
+    pub sym: u32,
+    pub kind: u32,
+    pub addend: i64,
+}
+
+/// decode `Elf64_Rela`s of `bytes`
+pub fn decode_relas(bytes: &[u8]) -> Vec<Rela> {
+    let word = |entry: &[u8], at: usize| {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(&entry[at..at + 8]);
+        u64::from_le_bytes(word)
+    };
+    bytes
+        .chunks_exact(24)
+        .map(|entry| {
+            let info = word(entry, 8);
+            Rela {
+                offset: word(entry, 0),
+                sym: (info >> 32) as u32,
+                kind: info as u32,
+                addend: word(entry, 16) as i64,
+            }
+        })
+        .collect()
+}
+
+// `syscall; int3` written at the pc of a stopped task, to run syscalls and
+// calls from, restored with the registers of the task when dropped
+struct RemoteCode {
+    tid: Pid,
+    regs: libc::user_regs_struct,
+    saved: i64,
+}
+
+impl RemoteCode {
+    fn new(tid: Pid) -> Result<Self> {
+        let regs = ptrace::getregs(tid).map_err(from_nix_error)?;
+        let pc = regs.rip as ptrace::AddressType;
+        let saved = ptrace::read(tid, pc).map_err(from_nix_error)?;
+        let code = (saved & !0xff_ffff) | 0xcc_050f;
+        ptrace::write(tid, pc, code as *mut libc::c_void)
+            .map_err(from_nix_error)?;
+        Ok(RemoteCode { tid, regs, saved })
+    }
+
+    // run from `regs` until the `int3`, registers then
+    fn run(
+        &self,
+        mut regs: libc::user_regs_struct,
+    ) -> Result<libc::user_regs_struct> {
+        // not restarting the syscall the task (if any) is stopped in
+        regs.orig_rax = -1i64 as u64;
+        ptrace::setregs(self.tid, regs).map_err(from_nix_error)?;
+        let mut sig = None;
+        loop {
+            ptrace::cont(self.tid, sig).map_err(from_nix_error)?;
+            let status = wait::waitpid(self.tid, Some(WaitPidFlag::__WALL))
+                .map_err(from_nix_error)?;
+            sig = match status {
+                // the `int3` after the syscall (or returned to), only
+                WaitStatus::Stopped(_, signal::SIGTRAP)
+                    if self.trapped()? =>
+                {
+                    break
+                }
+                WaitStatus::Stopped(_, sig @ signal::SIGTRAP) => {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        format!(
+                            "{} unexpected {:?} while loading",
+                            self.tid, sig
+                        ),
+                    ));
+                }
+                WaitStatus::Stopped(_, sig @ signal::SIGSEGV)
+                | WaitStatus::Stopped(_, sig @ signal::SIGBUS)
+                | WaitStatus::Stopped(_, sig @ signal::SIGILL)
+                | WaitStatus::Stopped(_, sig @ signal::SIGABRT) => {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        format!("{} {:?} while loading", self.tid, sig),
+                    ));
+                }
+                // dropped, see module doc
+                WaitStatus::Stopped(_, _) => None,
+                // syscalls of `dlopen`, seccomp stops included
+                WaitStatus::PtraceEvent(..) => None,
+                _ => return Err(Error::from_raw_os_error(libc::ESRCH)),
+            };
+        }
+        ptrace::getregs(self.tid).map_err(from_nix_error)
+    }
+
+    // whether the task stopped at the `int3`
+    fn trapped(&self) -> Result<bool> {
+        let regs = ptrace::getregs(self.tid).map_err(from_nix_error)?;
+        Ok(regs.rip == self.regs.rip + 3)
+    }
+
+    fn syscall(&self, nr: SyscallNo, args: [u64; 6]) -> Result<u64> {
+        let mut regs = self.regs;
+        regs.rax = nr as u64;
+        regs.rdi = args[0];
+        regs.rsi = args[1];
+        regs.rdx = args[2];
+        regs.r10 = args[3];
+        regs.r8 = args[4];
+        regs.r9 = args[5];
+        let regs = self.run(regs)?;
+        syscall_result(regs.rax as i64).map(|retval| retval as u64)
+    }
+
+    // call `func` with 2 arguments, returning to the `int3`
+    fn call(&self, func: u64, arg0: u64, arg1: u64) -> Result<u64> {
+        let mut regs = self.regs;
+        // aligned as of a `call`
+        let sp = ((self.regs.rsp - RED_ZONE - 0x100) & !0xf) - 8;
+        self.poke(sp, &(self.regs.rip + 2).to_le_bytes())?;
+        regs.rsp = sp;
+        regs.rip = func;
+        regs.rdi = arg0;
+        regs.rsi = arg1;
+        regs.rax = 0;
+        Ok(self.run(regs)?.rax)
+    }
+
+    fn poke(&self, addr: u64, bytes: &[u8]) -> Result<()> {
+        for (k, chunk) in bytes.chunks(8).enumerate() {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            let at = addr + 8 * k as u64;
+            if chunk.len() < 8 {
+                let old = ptrace::read(self.tid, at as ptrace::AddressType)
+                    .map_err(from_nix_error)?;
+                word[chunk.len()..]
+                    .copy_from_slice(&old.to_le_bytes()[chunk.len()..]);
+            }
+            let word = i64::from_le_bytes(word);
+            ptrace::write(
+                self.tid,
+                at as ptrace::AddressType,
+                word as *mut libc::c_void,
+            )
+            .map_err(from_nix_error)?;
+        }
+        Ok(())
+    }
+
+    // `bytes` copied in an anonymous page of the task
+    fn scratch(&self, bytes: &[u8]) -> Result<u64> {
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+        let len = page_up(bytes.len() as u64);
+        let addr = self.syscall(
+            SYS_mmap,
+            [0, len, prot as u64, flags as u64, -1i64 as u64, 0],
+        )?;
+        self.poke(addr, bytes)?;
+        Ok(addr)
+    }
+
+    fn munmap(&self, addr: u64, len: u64) -> Result<()> {
+        self.syscall(SYS_munmap, [addr, page_up(len), 0, 0, 0, 0])
+            .map(drop)
+    }
+}
+
+impl Drop for RemoteCode {
+    fn drop(&mut self) {
+        let pc = self.regs.rip as ptrace::AddressType;
+        let _ = ptrace::write(self.tid, pc, self.saved as *mut libc::c_void);
+        let _ = ptrace::setregs(self.tid, self.regs);
+    }
+}
+
+// `dlopen`, `dlsym`, and `dlerror` of libc, before glibc 2.34 otherwise
+const DLOPEN: &[&str] = &["dlopen", "__libc_dlopen_mode"];
+const DLSYM: &[&str] = &["dlsym", "__libc_dlsym"];
+const DLERROR: &[&str] = &["dlerror"];
+
+// the first of `names` defined by the libc loaded by process `pid`, if any
+fn libc_symbol(pid: Pid, names: &[&str]) -> Option<u64> {
+    let maps = procfs::process::Process::new(pid.as_raw())
+        .and_then(|p| p.maps())
+        .ok()?;
+    maps.iter().find_map(|map| {
+        let path = match &map.pathname {
+            procfs::process::MMapPath::Path(path) if map.offset == 0 => path,
+            _ => return None,
+        };
+        let name = path.file_name()?.to_str()?;
+        if !name.starts_with("libc.so") && !name.starts_with("libc-") {
+            return None;
+        }
+        let bytes = fs::read(path).ok()?;
+        let elf = Elf::parse(&bytes).ok()?;
+        let strtab = &elf.dynstrtab;
+        names.iter().find_map(|name| {
+            elf.dynsyms
+                .iter()
+                .find(|sym| sym.st_value != 0 && &strtab[sym.st_name] == *name)
+                .map(|sym| map.address.0 + sym.st_value)
+        })
+    })
+}
+
+// nul-terminated string at `addr` of `tid`, up to 256 bytes
+fn peek_cstring(tid: Pid, addr: u64) -> Result<String> {
+    let mut bytes = Vec::new();
+    for k in 0..32 {
+        let at = (addr + 8 * k) as ptrace::AddressType;
+        let word = ptrace::read(tid, at).map_err(from_nix_error)?;
+        let word = word.to_le_bytes();
+        match word.iter().position(|b| *b == 0) {
+            Some(nul) => {
+                bytes.extend_from_slice(&word[..nul]);
+                break;
+            }
+            None => bytes.extend_from_slice(&word),
+        }
+    }
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// error of a failed `what`, as told by `dlerror` if any
+fn dl_error(code: &RemoteCode, pid: Pid, what: &str) -> Error {
+    let message = libc_symbol(pid, DLERROR)
+        .and_then(|dlerror| code.call(dlerror, 0, 0).ok())
+        .filter(|message| *message != 0)
+        .and_then(|message| peek_cstring(code.tid, message).ok())
+        .unwrap_or_else(|| String::from("no error message"));
+    Error::new(ErrorKind::Other, format!("{} failed: {}", what, message))
+}
+
+// `f` run with the other threads of `task` stopped
+fn quiesced<R, F>(task: &dyn Task, f: F) -> Result<R>
+where
+    F: FnOnce() -> Result<R>,
+{
+    let (pid, tid) = (task.getpid(), task.gettid());
+    if !quiesce::stop_others(pid, tid, quiesce::QUIESCE_TIMEOUT) {
+        return Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("threads of {} not stopped", pid),
+        ));
+    }
+    f()
+}
+
+// handle of `path` loaded by `dlopen` at `dlopen`
+fn dlopen_handle(
+    code: &RemoteCode,
+    pid: Pid,
+    dlopen: u64,
+    path: &Path,
+) -> Result<u64> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let bytes = cpath.as_bytes_with_nul();
+    let scratch = code.scratch(bytes)?;
+    let handle = code.call(dlopen, scratch, RTLD_NOW);
+    code.munmap(scratch, bytes.len() as u64)?;
+    match handle? {
+        0 => Err(dl_error(code, pid, &format!("dlopen {}", path.display()))),
+        handle => Ok(handle),
+    }
+}
+
+// `path` loaded by `dlopen` at `dlopen`, its `link_map`'s `l_addr`
+fn dlopen_library(
+    code: &RemoteCode,
+    pid: Pid,
+    dlopen: u64,
+    path: &Path,
+) -> Result<u64> {
+    let handle = dlopen_handle(code, pid, dlopen, path)?;
+    let l_addr = ptrace::read(code.tid, handle as ptrace::AddressType)
+        .map_err(from_nix_error)?;
+    Ok(l_addr as u64)
+}
+
+// value of relocation `rela` of `elf` loaded at `bias`, `None` if none
+fn relocated(elf: &Elf, rela: &Rela, bias: u64) -> Result<Option<u64>> {
+    let addend = rela.addend as u64;
+    let symbol = || {
+        let sym = elf.dynsyms.get(rela.sym as usize)?;
+        if sym.st_shndx != 0 {
+            Some(Ok(bias + sym.st_value))
+        } else if sym.st_bind() == STB_WEAK {
+            Some(Ok(0))
+        } else {
+            let name = &elf.dynstrtab[sym.st_name];
+            Some(Err(format!("undefined symbol {}", name)))
+        }
+    };
+    let value = match rela.kind {
+        R_X86_64_NONE => return Ok(None),
+        R_X86_64_RELATIVE => Ok(bias.wrapping_add(addend)),
+        R_X86_64_64 => symbol()
+            .unwrap_or_else(|| Err(String::from("no such symbol")))
+            .map(|value| value.wrapping_add(addend)),
+        R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => {
+            symbol().unwrap_or_else(|| Err(String::from("no such symbol")))
+        }
+        kind => Err(format!("unsupported relocation type {}", kind)),
+    };
+    value
+        .map(Some)
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+// relocations of `elf`, of its `.rela.dyn` and `.rela.plt`
+pub(crate) fn relas_of(elf: &Elf, bytes: &[u8]) -> Vec<Rela> {
+    let dynamic = match &elf.dynamic {
+        Some(dynamic) => dynamic,
+        None => return Vec::new(),
+    };
+    let tag = |tag| dynamic.dyns.iter().find(|d| d.d_tag == tag);
+    // file offset of `vaddr`
+    let offset = |vaddr: u64| {
+        elf.program_headers
+            .iter()
+            .find(|ph| {
+                ph.p_type == PT_LOAD
+                    && ph.p_vaddr <= vaddr
+                    && vaddr < ph.p_vaddr + ph.p_filesz
+            })
+            .map(|ph| (vaddr - ph.p_vaddr + ph.p_offset) as usize)
+    };
+    let mut relas = Vec::new();
+    for (table, size) in &[(DT_RELA, DT_RELASZ), (DT_JMPREL, DT_PLTRELSZ)] {
+        if let (Some(table), Some(size)) = (tag(*table), tag(*size)) {
+            let start = offset(table.d_val).unwrap_or(bytes.len());
+            let end = (start + size.d_val as usize).min(bytes.len());
+            relas.extend(decode_relas(&bytes[start.min(end)..end]));
+        }
+    }
+    relas
+}
+
+// `path` mapped raw, and relocated: its load bias
+fn map_library(code: &RemoteCode, path: &Path) -> Result<u64> {
+    let bytes = fs::read(path)?;
+    let elf = Elf::parse(&bytes).map_err(|err| invalid(path, err))?;
+    if elf.header.e_type != ET_DYN {
+        return Err(invalid(path, "not a shared library"));
+    }
+    let loads: Vec<_> = elf
+        .program_headers
+        .iter()
+        .filter(|ph| ph.p_type == PT_LOAD)
+        .collect();
+    let start = loads.iter().map(|ph| page_down(ph.p_vaddr)).min();
+    let end = loads
+        .iter()
+        .map(|ph| page_up(ph.p_vaddr + ph.p_memsz))
+        .max();
+    let (start, end) = match (start, end) {
+        (Some(start), Some(end)) => (start, end),
+        _ => return Err(invalid(path, "nothing to load")),
+    };
+    // relocations first, not to map anything they fail
+    let relas = relas_of(&elf, &bytes);
+    for rela in &relas {
+        relocated(&elf, rela, 0).map_err(|err| invalid(path, err))?;
+    }
+
+    let (private, anonymous) = (libc::MAP_PRIVATE, libc::MAP_ANONYMOUS);
+    let reserve = (private | anonymous) as u64;
+    let base =
+        code.syscall(SYS_mmap, [0, end - start, 0, reserve, -1i64 as u64, 0])?;
+    let bias = base - start;
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let scratch = code.scratch(cpath.as_bytes_with_nul())?;
+    let flags = (libc::O_RDONLY | libc::O_CLOEXEC) as u64;
+    let fd = code.syscall(SYS_open, [scratch, flags, 0, 0, 0, 0]);
+    code.munmap(scratch, cpath.as_bytes_with_nul().len() as u64)?;
+    let fd = fd?;
+    let fixed = (private | libc::MAP_FIXED) as u64;
+    for ph in &loads {
+        let mut prot = 0;
+        for (flag, bit) in &[
+            (PF_R, libc::PROT_READ),
+            (PF_W, libc::PROT_WRITE),
+            (PF_X, libc::PROT_EXEC),
+        ] {
+            if ph.p_flags & flag != 0 {
+                prot |= bit;
+            }
+        }
+        let page = page_down(ph.p_vaddr);
+        let file_end = ph.p_vaddr + ph.p_filesz;
+        let mem_end = ph.p_vaddr + ph.p_memsz;
+        if ph.p_filesz > 0 {
+            let len = page_up(file_end) - page;
+            let offset = page_down(ph.p_offset);
+            let args = [bias + page, len, prot as u64, fixed, fd, offset];
+            code.syscall(SYS_mmap, args)?;
+        }
+        if mem_end > file_end {
+            // bss: the tail of the last page of the file, and pages after
+            let tail = page_up(file_end) - file_end;
+            if ph.p_filesz > 0 && tail > 0 {
+                code.poke(bias + file_end, &vec![0u8; tail as usize])?;
+            }
+            if page_up(mem_end) > page_up(file_end) {
+                let len = page_up(mem_end) - page_up(file_end);
+                let flags = fixed | anonymous as u64;
+                let args = [
+                    bias + page_up(file_end),
+                    len,
+                    prot as u64,
+                    flags,
+                    -1i64 as u64,
+                    0,
+                ];
+                code.syscall(SYS_mmap, args)?;
+            }
+        }
+    }
+    code.syscall(SYS_close, [fd, 0, 0, 0, 0, 0])?;
+    for rela in &relas {
+        if let Some(value) = relocated(&elf, rela, bias)? {
+            code.poke(bias + rela.offset, &value.to_le_bytes())?;
+        }
+    }
+    Ok(bias)
+}
+
+/// load shared library `path` in stopped `task`, see module doc: returns
+/// its load bias, which its symbols are relative to
+pub fn load_library(task: &dyn Task, path: &Path) -> Result<u64> {
+    let pid = task.getpid();
+    let dlopen = libc_symbol(pid, DLOPEN);
+    quiesced(task, || {
+        let code = RemoteCode::new(task.gettid())?;
+        match dlopen {
+            Some(dlopen) => dlopen_library(&code, pid, dlopen, path),
+            None => map_library(&code, path),
+        }
+    })
+}
+
+// the first of `names` defined by the libc of `task`, or an error
+fn libc_symbol_of(task: &dyn Task, names: &[&str]) -> Result<u64> {
+    libc_symbol(task.getpid(), names).ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("{}: no libc with {}", task.getpid(), names[0]),
+        )
+    })
+}
+
+/// `dlopen(path, RTLD_NOW)` in stopped `task`, with its libc loaded:
+/// returns the handle, see module doc
+pub fn dlopen(task: &dyn Task, path: &Path) -> Result<u64> {
+    let dlopen = libc_symbol_of(task, DLOPEN)?;
+    quiesced(task, || {
+        let code = RemoteCode::new(task.gettid())?;
+        dlopen_handle(&code, task.getpid(), dlopen, path)
+    })
+}
+
+/// `dlsym(handle, name)` in stopped `task`, with its libc loaded: returns
+/// the address of symbol `name`, see module doc
+pub fn dlsym(task: &dyn Task, handle: u64, name: &str) -> Result<u64> {
+    let dlsym = libc_symbol_of(task, DLSYM)?;
+    let cname = CString::new(name)?;
+    quiesced(task, || {
+        let code = RemoteCode::new(task.gettid())?;
+        let bytes = cname.as_bytes_with_nul();
+        let scratch = code.scratch(bytes)?;
+        let address = code.call(dlsym, handle, scratch);
+        code.munmap(scratch, bytes.len() as u64)?;
+        match address? {
+            0 => {
+                Err(dl_error(&code, task.getpid(), &format!("dlsym {}", name)))
+            }
+            address => Ok(address),
+        }
+    })
+}
+
+#[test]
+fn loader_sanity_check() {
+    assert_eq!(page_down(0x1fff), 0x1000);
+    assert_eq!(page_up(0x1001), 0x2000);
+    assert_eq!(page_up(0x2000), 0x2000);
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&0x3df0u64.to_le_bytes());
+    bytes.extend_from_slice(&(u64::from(R_X86_64_RELATIVE)).to_le_bytes());
+    bytes.extend_from_slice(&0x1130u64.to_le_bytes());
+    bytes.extend_from_slice(&0x4018u64.to_le_bytes());
+    bytes.extend_from_slice(&((3u64 << 32) | 7).to_le_bytes());
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    // truncated
+    bytes.extend_from_slice(&[0u8; 8]);
+    let relas = decode_relas(&bytes);
+    assert_eq!(relas.len(), 2);
+    assert_eq!(
+        relas[0],
+        Rela {
+            offset: 0x3df0,
+            sym: 0,
+            kind: R_X86_64_RELATIVE,
+            addend: 0x1130,
+        }
+    );
+    assert_eq!((relas[1].sym, relas[1].kind), (3, R_X86_64_JUMP_SLOT));
+}
+
+#[test]
+fn dlopen_sanity_check() {
+    use crate::traced_task::TracedTask;
+    use nix::unistd;
+
+    let child = match unistd::fork().expect("fork failed") {
+        unistd::ForkResult::Child => unsafe {
+            libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0);
+            libc::raise(libc::SIGSTOP);
+            libc::_exit(0)
+        },
+        unistd::ForkResult::Parent { child } => child,
+    };
+    let status = wait::waitpid(child, None);
+    assert_eq!(status, Ok(WaitStatus::Stopped(child, signal::SIGSTOP)));
+    let task: TracedTask = Task::new(child);
+    let regs = ptrace::getregs(child).unwrap();
+    // mapping of the child with `address`, by path
+    let mapping_of = |address: u64| {
+        let maps = procfs::process::Process::new(child.as_raw())
+            .and_then(|p| p.maps())
+            .unwrap();
+        maps.into_iter()
+            .find(|map| (map.address.0..map.address.1).contains(&address))
+            .and_then(|map| match map.pathname {
+                procfs::process::MMapPath::Path(path) => Some(path),
+                _ => None,
+            })
+    };
+
+    let path = Path::new("libz.so.1");
+    let handle = dlopen(&task, path).unwrap();
+    assert_ne!(handle, 0);
+    let version = dlsym(&task, handle, "zlibVersion").unwrap();
+    let library = mapping_of(version).unwrap();
+    assert!(library.to_string_lossy().contains("libz.so"));
+    // failures are told by `dlerror`
+    let missing = dlsym(&task, handle, "no_such_symbol").unwrap_err();
+    assert!(missing.to_string().contains("no_such_symbol"));
+    let missing = dlopen(&task, Path::new("libnonexistent.so")).unwrap_err();
+    assert!(missing.to_string().contains("libnonexistent.so"));
+    // the task is left as it was stopped
+    assert_eq!(ptrace::getregs(child).unwrap().rip, regs.rip);
+
+    ptrace::detach(child).unwrap();
+    let status = wait::waitpid(child, None);
+    assert_eq!(status, Ok(WaitStatus::Exited(child, 0)));
+}
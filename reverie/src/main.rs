@@ -26,20 +26,66 @@ use nix::sys::wait::WaitStatus;
 use nix::sys::{memfd, mman, ptrace, signal, wait};
 use nix::unistd;
 use nix::unistd::ForkResult;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::env;
 use std::ffi::CString;
-use std::io::{self, Error, ErrorKind};
-use std::path::PathBuf;
+use std::io::{self, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use structopt::{clap::AppSettings, StructOpt};
 
+use reverie_api::coverage::CoverageSpec;
+use reverie_api::emulate::SyscallEmulation;
 use reverie_api::event::*;
+use reverie_api::lies::{lies_emulation, Lie};
+use reverie_api::mapping::WxPolicy;
+use reverie_api::probe::ProbeSpec;
+use reverie_api::ready::{ready_tracing, ReadyCondition, ReadyMatcher};
+use reverie_api::record_data::*;
 use reverie_api::remote::*;
+use reverie_api::shm::SharedMemoryPolicy;
 use reverie_api::task::*;
+use reverie_api::trace_output::TraceOutput;
+use reverie_api::violation::ViolationAction;
 
+use reverie::adaptive::HotSitePolicy;
+use reverie::attach;
+use reverie::budget::{self, LatencyBudget};
+use reverie::capture_output::{output_capture, OutputCapture};
+use reverie::chrome_trace::ChromeTrace;
+use reverie::clone_flags::SYS_CLONE3;
+use reverie::contention::{self, contention_tracing, ContentionProfiler};
+use reverie::control;
+use reverie::coverage;
+use reverie::detach;
+use reverie::doctor;
+use reverie::ebpf;
+use reverie::flamegraph::{flamegraph_tracing, FlameGraph};
+use reverie::got::GotHook;
+use reverie::hermetic::Hermetic;
+use reverie::idle;
+use reverie::ioctl::{command_replay, RecordedCommands};
+use reverie::landlock::{self, LandlockRuleset};
+use reverie::malloc_stats::{self, malloc_tracing, MallocTracker};
+use reverie::memory_limit::{self, memory_limiting, MemoryLimit};
+use reverie::nesting;
+use reverie::ordered_output::{ordered_output, OrderedOutput};
+use reverie::overhead::{self, NativeEstimate, Timeline};
+use reverie::pinning::{self, LocalePinning};
+use reverie::process::ProcessRef;
+use reverie::recording::*;
+use reverie::report::{self, ExitRecorder};
 use reverie::reverie_common::{consts, state::*};
-use reverie::sched_wait::SchedWait;
+use reverie::sched_wait::{self, SchedPolicy, SchedWait, UnknownEventPolicy};
+use reverie::scrub::{ScrubData, Scrubber};
+use reverie::session::SessionState;
+use reverie::syscalls::SyscallNo;
+use reverie::task_limits::{task_limiting, LimitAction, TaskLimits};
+use reverie::traced_task::{self, TracedTask};
+use reverie::unwind;
 use reverie::{hooks, ns};
 
 #[test]
@@ -50,17 +96,28 @@ fn can_resolve_syscall_hooks() -> io::Result<()> {
     Ok(())
 }
 
+// options of all subcommands. NB: flattened structs are not documented
+// by doc comments, which would override the about of subcommands.
 #[derive(Debug, StructOpt)]
-#[structopt(about)]
-struct Arguments {
-    /// Set debug level [0...5].
-    #[structopt(
-        long = "debug",
-        value_name = "DEBUG_LEVEL",
-        default_value = "0"
-    )]
-    log_level: u32,
+struct GlobalOptions {
+    /// Set debug level [0...5], default is 0.
+    #[structopt(long = "debug", value_name = "DEBUG_LEVEL", global = true)]
+    log_level: Option<u32>,
 
+    /// Configures how to do logging.
+    #[structopt(long = "with-log", value_name = "OUTPUT", global = true)]
+    log_output: Option<String>,
+
+    /// Detaches from tasks once the tracer receives SIGNAL (i.e.:
+    /// SIGUSR2), to keep running untraced, but processes with a seccomp
+    /// filter, passed through, see reverie::detach.
+    #[structopt(long, value_name = "SIGNAL", global = true)]
+    detach_on: Option<signal::Signal>,
+}
+
+// options of subcommands running a program under the tracer
+#[derive(Debug, Clone, StructOpt)]
+struct TracerOptions {
     /// Preloader tool.
     #[structopt(
         long,
@@ -95,45 +152,603 @@ struct Arguments {
     #[structopt(long = "with-namespace")]
     namespaces: bool,
 
-    /// Configures how to do logging.
-    #[structopt(long = "with-log", value_name = "OUTPUT")]
-    log_output: Option<String>,
+    /// Creates namespaces for the program: time (linux 5.6+), which
+    /// offsets the monotonic and boot time clocks by --monotonic-offset
+    /// and --boottime-offset. Can be used multiple times.
+    #[structopt(long = "ns", value_name = "NS", number_of_values = 1)]
+    ns: Vec<ns::Namespace>,
+
+    /// CLOCK_MONOTONIC offset in the time namespace, in seconds.
+    #[structopt(
+        long,
+        value_name = "SECS",
+        default_value = "0",
+        allow_hyphen_values = true
+    )]
+    monotonic_offset: i64,
+
+    /// CLOCK_BOOTTIME offset in the time namespace, in seconds.
+    #[structopt(
+        long,
+        value_name = "SECS",
+        default_value = "0",
+        allow_hyphen_values = true
+    )]
+    boottime_offset: i64,
+
+    /// Runs the program hermetically: no network (loopback aside), files
+    /// limited to an allowlist (see --allow), fixed clocks and random
+    /// bytes, and deterministic pids (implies --with-namespace).
+    #[structopt(long)]
+    hermetic: bool,
+
+    /// Pins the timezone (UTC) and locale (C) of the program, so that
+    /// timestamps are formatted alike on every machine: TZ, LC_ALL and
+    /// LANG are set at exec, and /etc/localtime, /etc/timezone and locale
+    /// archives read are pinned ones, see reverie::pinning.
+    #[structopt(long)]
+    pin_locale: bool,
+
+    /// Allows PATH (and everything under it) in --hermetic mode, besides
+    /// system directories, the current directory and the program. Can be
+    /// used multiple times.
+    #[structopt(long = "allow", value_name = "PATH", number_of_values = 1)]
+    allow: Vec<PathBuf>,
+
+    /// Enforces the --hermetic allowlist by a landlock ruleset as well, so
+    /// that syscalls bypassing the tracer are restricted too. Ignored when
+    /// the kernel does not support landlock.
+    #[structopt(long, requires = "hermetic")]
+    landlock: bool,
+
+    /// Decides syscalls by policy script FILE, in rhai: functions named
+    /// after syscalls allow, deny or emulate them, see reverie::script.
+    /// Needs reverie built with feature scripting.
+    #[structopt(long, value_name = "FILE", conflicts_with = "hermetic")]
+    policy_script: Option<PathBuf>,
+
+    /// Tells the program a common sandbox lie, by syscall emulation: root
+    /// (uids and gids are 0), cpus=N (N cpus are available), memory=BYTES
+    /// (free memory), disk=FREE[:TOTAL] (free and total bytes of
+    /// filesystems), setrlimit (setting limits succeeds), enosys=SYSCALL,..
+    /// (syscalls fail with ENOSYS) or kernel=VERSION (linux VERSION). Can be
+    /// used multiple times, see reverie_api::lies.
+    #[structopt(long = "lie", value_name = "NAME[=ARG]", number_of_values = 1)]
+    lie: Vec<Lie>,
+
+    /// Fails SYSCALLS (i.e.: io_uring_setup,clone3,statx) with ENOSYS, to
+    /// test fallback paths of programs for older kernels, by the seccomp
+    /// filter of the preloader. Same as --lie enosys=SYSCALLS, can be used
+    /// multiple times.
+    #[structopt(
+        long,
+        value_name = "SYSCALLS",
+        number_of_values = 1,
+        parse(try_from_str = Lie::enosys)
+    )]
+    enosys: Vec<Lie>,
+
+    /// Pretends linux VERSION (i.e.: 4.19): uname reports VERSION, and
+    /// syscalls added since fail with ENOSYS. Same as --lie kernel=VERSION.
+    #[structopt(
+        long,
+        value_name = "VERSION",
+        parse(try_from_str = Lie::kernel)
+    )]
+    pretend_kernel: Option<Lie>,
 
     /// Do not match any syscalls. Handle all syscalls by seccomp.
     #[structopt(long)]
     disable_monkey_patcher: bool,
 
+    /// Mitigate unpatchable syscall sites once they trapped THRESHOLD
+    /// times, see --hot-site-policy.
+    #[structopt(long, value_name = "THRESHOLD")]
+    adaptive_bypass: Option<usize>,
+
+    /// Mitigation of hot unpatchable syscall sites: warn (with symbolized
+    /// location), or bypass seccomp for the site (takes effect on next
+    /// exec).
+    #[structopt(long, value_name = "POLICY", default_value = "bypass")]
+    hot_site_policy: HotSitePolicy,
+
+    /// File recording syscall sites bypassed by --adaptive-bypass.
+    #[structopt(
+        long,
+        value_name = "FILE",
+        default_value = "reverie-bypass.txt"
+    )]
+    adaptive_bypass_file: PathBuf,
+
+    /// Max tracer overhead of ptraced syscalls, at a percentile: MAX in ns,
+    /// us or ms, then @pPERCENTILE (p99 by default), i.e.: 20us@p99. Over
+    /// budget, features are degraded in order, as reported: syscall data
+    /// capture disabled, hot sites bypassed (on next exec), logging
+    /// reduced to warnings.
+    #[structopt(long, value_name = "BUDGET")]
+    overhead_budget: Option<LatencyBudget>,
+
+    /// Listens on unix socket PATH for requests to traced tasks, as json
+    /// lines: to inject a syscall, i.e.: {"command": "syscall", "pid": PID,
+    /// "syscall": "SYS_close", "args": [3]}, or call a function of the
+    /// tool preloaded, i.e.: {"command": "call", "pid": PID, "function":
+    /// NAME, "args": []}. Requests are served at the next syscall of PID.
+    /// {"command": "detach", "session": SESSION} saves the session and
+    /// detaches, see `reverie attach --session`.
+    #[structopt(long, value_name = "PATH")]
+    control_socket: Option<PathBuf>,
+
+    /// Counts syscalls (and failures) of the program in-kernel, by ebpf
+    /// programs on a cgroup of its own, see --report. Needs linux 5.8,
+    /// cgroup v2 and CAP_BPF, ignored otherwise.
+    #[structopt(long)]
+    bpf_counters: bool,
+
+    /// Samples arguments of 1 in N syscalls (rounded up to a power of 2)
+    /// in-kernel, 0 for none, implies --bpf-counters. Defaults to 1024.
+    #[structopt(long, value_name = "N")]
+    bpf_sample_every: Option<u32>,
+
+    /// Single-steps through each syscall patch once written, rolling back
+    /// (and never patching again) sites which do not reach their hook.
+    #[structopt(long)]
+    verify_patches: bool,
+
+    /// Sample one in every N syscalls, per syscall number, with optional
+    /// per syscall rates, i.e.: 100,0:1000 (read is sampled 1 in 1000).
+    #[structopt(
+        long,
+        value_name = "N[,NR:N]*",
+        parse(try_from_str = util::parse_sampling)
+    )]
+    sample: Option<String>,
+
+    /// Handling tier of syscalls: count (never stopped, counted by
+    /// --bpf-counters or the guest), guest (patched, handled by the tool)
+    /// or tracer (always stopped), the default tier first, with optional
+    /// per syscall tiers, i.e.: guest,39:count,257:tracer.
+    #[structopt(
+        long,
+        value_name = "TIER[,NR:TIER]*",
+        parse(try_from_str = util::parse_tiers)
+    )]
+    syscall_tiers: Option<String>,
+
+    /// Scheduling policy of traced tasks: fifo, priority, rr or
+    /// rr:<QUANTUM>.
+    #[structopt(long, value_name = "POLICY", default_value = "rr")]
+    sched_policy: SchedPolicy,
+
+    /// What to do with ptrace events unknown to reverie (i.e.: from newer
+    /// kernels): ignore, stop-task or abort.
+    #[structopt(long, value_name = "POLICY", default_value = "ignore")]
+    unknown_event: UnknownEventPolicy,
+
+    /// Shared memory policy: ignore, annotate, deny or private.
+    #[structopt(long, value_name = "POLICY", default_value = "ignore")]
+    shared_memory: SharedMemoryPolicy,
+
+    /// Tracks where each fd of traced processes came from (open, dup,
+    /// fork, SCM_RIGHTS or pidfd_getfd), see --report.
+    #[structopt(long)]
+    fd_provenance: bool,
+
+    /// W^X policy, for mappings both writable and executable: ignore, flag
+    /// (warn) or deny.
+    #[structopt(long, value_name = "POLICY", default_value = "ignore")]
+    wx: WxPolicy,
+
+    /// Allows PROGRAM (path, or file name) writable and executable
+    /// mappings under --wx, besides known JITs.
+    #[structopt(
+        long = "wx-allow",
+        value_name = "PROGRAM",
+        number_of_values = 1
+    )]
+    wx_allow: Vec<String>,
+
+    /// Hashes (sha256) the program each process execs, and each shared
+    /// library it loads, see --report.
+    #[structopt(long)]
+    hash_binaries: bool,
+
+    /// Collects coverage of MODULE (the program or a shared library, by
+    /// path or file name), by breakpoints at function entries, or at the
+    /// blocks of --coverage-blocks.
+    #[structopt(long, value_name = "MODULE", number_of_values = 1)]
+    coverage: Vec<String>,
+
+    /// Blocks to cover, one per line: MODULE OFFSET [SIZE], offsets in hex,
+    /// in the file of MODULE.
+    #[structopt(long, value_name = "FILE")]
+    coverage_blocks: Option<PathBuf>,
+
+    /// Writes the coverage collected to FILE, as a drcov log.
+    #[structopt(
+        long,
+        value_name = "FILE",
+        default_value = "reverie.drcov.log"
+    )]
+    coverage_out: PathBuf,
+
+    /// Probes an instruction of a function, as uprobes do: PROBE is
+    /// MODULE:FUNCTION[+OFFSET][,ARG..], MODULE by path or file name, ARGs
+    /// registers (rdi, rsi, ..) or stack words (sp+8) captured on every
+    /// hit, see --probe-out.
+    #[structopt(long, value_name = "PROBE", number_of_values = 1)]
+    probe: Vec<ProbeSpec>,
+
+    /// Writes probe hits to FILE, one per line: TID PROBE VALUE.., values
+    /// not read are ?. hits are logged otherwise.
+    #[structopt(long, value_name = "FILE", requires = "probe")]
+    probe_out: Option<PathBuf>,
+
+    /// Writes allocation statistics of each process to FILE: allocations,
+    /// peak bytes in use and mapped, and blocks never freed by call site,
+    /// by probing malloc, calloc, realloc and free of glibc, see
+    /// reverie::malloc_stats.
+    #[structopt(long, value_name = "FILE")]
+    malloc_stats: Option<PathBuf>,
+
+    /// Writes a contention report to FILE: threads created and the call
+    /// sites of pthread_create, futex words waited for the longest, with
+    /// symbolized call sites, and time each thread waited, with its ticks,
+    /// see reverie::contention.
+    #[structopt(long, value_name = "FILE")]
+    contention: Option<PathBuf>,
+
+    /// Writes syscall time by user-space call stack to FILE, as folded
+    /// stacks for flamegraph.pl, capturing the stack of every syscall,
+    /// which are then never patched, see reverie::flamegraph.
+    #[structopt(long, value_name = "FILE")]
+    flamegraph: Option<PathBuf>,
+
+    /// Captures N frames of the user-space stack of traced syscalls at
+    /// entry, by frame pointers, or .eh_frame of the binaries mapped, see
+    /// reverie::unwind. 32 with --flamegraph.
+    #[structopt(long, value_name = "N")]
+    stack_frames: Option<usize>,
+
+    /// Buffers output of tracees to stdout and stderr, and writes it in a
+    /// deterministic order, each process after exit, after its parent and
+    /// processes forked before it, each line prefixed by its logical id,
+    /// see reverie::ordered_output.
+    #[structopt(long)]
+    serialize_output: bool,
+
+    /// Tees output of each process to its fds 1 and 2 into DIR, as
+    /// <pid>.stdout and <pid>.stderr, each line prefixed by the timestamp
+    /// of its events, see reverie::capture_output.
+    #[structopt(long, value_name = "DIR", conflicts_with = "serialize-output")]
+    capture_output: Option<PathBuf>,
+
+    /// Writes a timeline of events to FILE, with the time the tracer spent
+    /// handling stops of their task so far, and a summary of overhead per
+    /// class of stops, see `reverie native`.
+    #[structopt(long, value_name = "FILE")]
+    timeline: Option<PathBuf>,
+
+    /// Writes events to FILE in the chrome trace-event format, to be
+    /// opened by chrome://tracing or the perfetto ui: syscalls and idle
+    /// periods as spans, signals, preemptions and task events as instants,
+    /// a lane per thread, see reverie::chrome_trace.
+    #[structopt(long, value_name = "FILE")]
+    chrome_trace: Option<PathBuf>,
+
+    /// Records data returned by syscalls reading into memory (read,
+    /// pread64, getdents, recvfrom, readlink), each block of data once.
+    #[structopt(long)]
+    record_data: bool,
+
+    /// Records data selected by SELECTOR in full, see --record-data
+    /// (implied): fd:N, path:PATH (files under PATH), syscall:NAME, or
+    /// sockets. other data is recorded by size and sha256 only, enough to
+    /// tell a replay diverges, for far smaller recordings.
+    #[structopt(long, value_name = "SELECTOR", number_of_values = 1)]
+    record_data_of: Vec<DataSelector>,
+
+    /// Counts conditional branches retired by each task ("ticks"), a
+    /// deterministic measure of progress, recorded with its events. Needs
+    /// a PMU, ignored otherwise.
+    #[structopt(long)]
+    ticks: bool,
+
+    /// Preempts tasks every TICKS, at exact points, see --ticks (implied).
+    /// Replays preempt at the same points.
+    #[structopt(long, value_name = "TICKS")]
+    timeslice: Option<u64>,
+
+    /// Reports the program idle once all its tasks are blocked in the same
+    /// syscalls for DURATION, in ms or s, i.e.: 500ms: often meaning a
+    /// service is ready. Logged, and reported by --idle-file.
+    #[structopt(
+        long,
+        value_name = "DURATION",
+        parse(try_from_str = idle::parse_duration)
+    )]
+    idle_after: Option<Duration>,
+
+    /// Writes the time idle (in ms) to FILE when the program is found
+    /// idle, see --idle-after, so that harnesses can wait for FILE rather
+    /// than sleep. FILE is removed at startup.
+    #[structopt(long, value_name = "FILE", requires = "idle-after")]
+    idle_file: Option<PathBuf>,
+
+    /// Reports the program ready (started) once all CONDITIONs are met:
+    /// listen:PORT (a socket listens on PORT), open:PATH (PATH is opened),
+    /// write:STRING (STRING is written by a single write) or idle (see
+    /// --idle-after, 1s by default). Logged, and reported by --ready-file.
+    #[structopt(long, value_name = "CONDITION", number_of_values = 1)]
+    ready_when: Vec<ReadyCondition>,
+
+    /// Writes "ready" to FILE once the program is ready, see --ready-when.
+    /// FILE is removed at startup.
+    #[structopt(long, value_name = "FILE", requires = "ready-when")]
+    ready_file: Option<PathBuf>,
+
+    /// Saves images of fileless execs (programs exec'ed from a memfd) to
+    /// DIR, named by their sha256. fileless execs are reported regardless.
+    #[structopt(long, value_name = "DIR")]
+    save_fileless: Option<PathBuf>,
+
+    /// What to do with syscalls denied by a policy (--hermetic,
+    /// --shared-memory=deny, --wx=deny): deny, failing the syscall, or
+    /// kill, killing the offending process with a violation report.
+    #[structopt(long, value_name = "ACTION", default_value = "deny")]
+    on_violation: ViolationAction,
+
+    /// Fails clone, fork and vfork with EAGAIN once N tasks (threads
+    /// included) are alive, reporting the offending process, see
+    /// reverie::task_limits. clone3 fails with ENOSYS.
+    #[structopt(long, value_name = "N")]
+    max_tasks: Option<usize>,
+
+    /// Fails fork, vfork and clone (but of threads) with EAGAIN once R
+    /// processes were created in the last second, as of --max-tasks.
+    #[structopt(long, value_name = "R")]
+    max_forks_per_second: Option<usize>,
+
+    /// What to do once over --max-tasks or --max-forks-per-second: deny,
+    /// failing the syscall, or freeze, stopping and detaching the tree,
+    /// left for inspection.
+    #[structopt(long, value_name = "ACTION", default_value = "deny")]
+    on_task_limit: LimitAction,
+
+    /// Fails mmap, mremap and brk growing anonymous memory of a process
+    /// over BYTES (or K, M, G), as out of memory, without cgroups, see
+    /// reverie::memory_limit.
+    #[structopt(
+        long,
+        value_name = "BYTES",
+        parse(try_from_str = memory_limit::parse_bytes)
+    )]
+    max_anon_memory: Option<u64>,
+
     /// Shows reverie software performance counter statistics (--debug must be
     /// >=3).
     #[structopt(long)]
     show_perf_stats: bool,
 
+    /// Writes a json report to FILE when the program exits: exit status of
+    /// each process, syscall statistics, patch coverage, warnings, fd
+    /// provenance (with --fd-provenance), policy violations, fileless
+    /// execs, binaries loaded (with --hash-binaries), degradations (with
+    /// --overhead-budget) and syscalls counted in-kernel (with
+    /// --bpf-counters).
+    #[structopt(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+}
+
+// program to run under the tracer
+#[derive(Debug, Clone, StructOpt)]
+struct Program {
     /// Name of the program to trace.
     #[structopt(value_name = "PROGRAM")]
     program: String,
 
-    /// Arguments to the program to trace.
+    /// Arguments to the program to trace. Several commands, separated by
+    /// `:::`, are traced by the same tracer, i.e.: `-- cmd1 ::: cmd2`. A
+    /// literal `:::` argument is given as `\:::` (one backslash is removed
+    /// from `\\:::` and so on).
     #[structopt(value_name = "ARGS")]
     program_args: Vec<String>,
 }
 
-fn run_tracer_main<G>(sched: &mut SchedWait<G>) -> i32 {
-    sched.run_all()
-}
+/// separates commands of a `Program`
+const COMMAND_SEPARATOR: &str = ":::";
 
-fn wait_sigstop(pid: unistd::Pid) -> io::Result<()> {
-    match wait::waitpid(Some(pid), None).expect("waitpid failed") {
-        WaitStatus::Stopped(new_pid, signal)
-            if signal == signal::SIGSTOP && new_pid == pid =>
-        {
-            Ok(())
+impl Program {
+    /// commands of the program, separated by `COMMAND_SEPARATOR`, escaped
+    /// separators are unescaped
+    fn commands(&self) -> Vec<Program> {
+        let words = std::iter::once(&self.program).chain(&self.program_args);
+        let mut commands = vec![Vec::new()];
+        for word in words {
+            let escaped = word.trim_start_matches('\\') == COMMAND_SEPARATOR;
+            if word == COMMAND_SEPARATOR {
+                commands.push(Vec::new());
+            } else if escaped {
+                commands.last_mut().unwrap().push(word[1..].to_string());
+            } else {
+                commands.last_mut().unwrap().push(word.clone());
+            }
         }
-        _ => Err(Error::new(ErrorKind::Other, "expect SIGSTOP")),
+        commands
+            .into_iter()
+            .filter(|command| !command.is_empty())
+            .map(|mut command| Program {
+                program: command.remove(0),
+                program_args: command,
+            })
+            .collect()
     }
 }
 
-fn from_nix_error(err: nix::Error) -> Error {
-    Error::new(ErrorKind::Other, err)
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Runs a program under the tracer, or several commands separated by
+    /// `:::`, each a process tree of its own.
+    Run {
+        #[structopt(flatten)]
+        tracer: TracerOptions,
+        #[structopt(flatten)]
+        program: Program,
+    },
+    /// Runs a program under the tracer, recording its events.
+    Record {
+        /// Writes the recording to TRACE.
+        #[structopt(
+            short = "o",
+            long,
+            value_name = "TRACE",
+            default_value = "reverie.trace"
+        )]
+        trace: PathBuf,
+        #[structopt(flatten)]
+        tracer: TracerOptions,
+        #[structopt(flatten)]
+        program: Program,
+    },
+    /// Runs the program of a recording again (from the same directory),
+    /// and reports where its events diverge from the recording. With
+    /// ticks recorded, asynchronous signals are replayed at the same
+    /// points. Syscall results are not replayed yet, but for the buffers
+    /// of ioctl and fcntl commands, see reverie::ioctl.
+    Replay {
+        #[structopt(flatten)]
+        tracer: TracerOptions,
+        /// Recording of `reverie record`.
+        #[structopt(value_name = "TRACE")]
+        trace: PathBuf,
+    },
+    /// Traces an already running process, and its threads. Only its
+    /// forks, execs, signals and exits are traced, syscalls are not
+    /// intercepted. The process is not killed when reverie exits. With
+    /// --session, re-attaches to the tasks of a session detached by the
+    /// control socket, intercepting syscalls as before.
+    Attach {
+        /// Scheduling policy of traced tasks: fifo, priority, rr or
+        /// rr:<QUANTUM>.
+        #[structopt(long, value_name = "POLICY", default_value = "rr")]
+        sched_policy: SchedPolicy,
+        /// Session saved by a {"command": "detach", "session": SESSION}
+        /// request of the control socket.
+        #[structopt(long, value_name = "SESSION", conflicts_with = "pid")]
+        session: Option<PathBuf>,
+        /// Loads shared library LIBRARY in the process once attached, by
+        /// its own dlopen if its libc has one, mapped raw otherwise, see
+        /// reverie::loader.
+        #[structopt(
+            long,
+            value_name = "LIBRARY",
+            number_of_values = 1,
+            conflicts_with = "session"
+        )]
+        load: Vec<PathBuf>,
+        /// Redirects calls of library function SYMBOL to FUNCTION of the
+        /// libraries loaded by --load, by patching GOT slots of all other
+        /// objects of the process, see reverie::got.
+        #[structopt(
+            long,
+            value_name = "SYMBOL=FUNCTION",
+            number_of_values = 1,
+            requires = "load"
+        )]
+        got_hook: Vec<GotHook>,
+        /// Process to trace.
+        #[structopt(value_name = "PID", required_unless = "session")]
+        pid: Option<i32>,
+    },
+    /// Checks the host supports reverie: kernel, ptrace, seccomp,
+    /// namespaces, and restrictions of container runtimes.
+    Doctor,
+    /// Compares two recordings of `reverie record`, exits with 1 if they
+    /// differ.
+    Diff {
+        #[structopt(value_name = "TRACE_A")]
+        a: PathBuf,
+        #[structopt(value_name = "TRACE_B")]
+        b: PathBuf,
+    },
+    /// Scrubs a recording of `reverie record` of secrets, so that it can be
+    /// shared: syscall data, path components and environment variables
+    /// (given in arguments) matching patterns, `*` and `?` wildcards.
+    Scrub {
+        /// What to do with syscall data: keep, redact (zeroed) or hash
+        /// (replays compare by hash).
+        #[structopt(long, value_name = "ACTION", default_value = "hash")]
+        data: ScrubData,
+        /// Redacts path components matching PATTERN, i.e.: `*.pem`.
+        #[structopt(long, value_name = "PATTERN", number_of_values = 1)]
+        path: Vec<String>,
+        /// Redacts values of environment variables matching PATTERN, i.e.:
+        /// `*TOKEN*`.
+        #[structopt(long, value_name = "PATTERN", number_of_values = 1)]
+        env: Vec<String>,
+        /// Hashes path components and values matched, rather than
+        /// redacting them, so that equal values stay equal.
+        #[structopt(long)]
+        hash: bool,
+        /// Writes the scrubbed recording to OUT.
+        #[structopt(
+            short = "o",
+            long,
+            value_name = "OUT",
+            default_value = "reverie.scrubbed.trace"
+        )]
+        out: PathBuf,
+        /// Recording of `reverie record`.
+        #[structopt(value_name = "TRACE")]
+        trace: PathBuf,
+    },
+    /// Estimates how a run would go untraced, from its `--timeline`: the
+    /// overhead of the tracer is subtracted from times of events, per task.
+    /// Prints traced and native durations, overall and per task.
+    Native {
+        /// Writes the corrected timeline to OUT.
+        #[structopt(
+            short = "o",
+            long,
+            value_name = "OUT",
+            default_value = "reverie.native.timeline"
+        )]
+        out: PathBuf,
+        /// Timeline of `--timeline`.
+        #[structopt(value_name = "TIMELINE")]
+        timeline: PathBuf,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about)]
+struct Arguments {
+    #[structopt(flatten)]
+    global: GlobalOptions,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+/// what the tracer does with events of the program
+enum LaunchMode {
+    Run,
+    /// record to the trace
+    Record(PathBuf),
+    /// compare to the recorded events
+    Replay(Recording),
+}
+
+/// program run under the tracer, by `run`, `record` or `replay`
+struct Launch<'a> {
+    opts: &'a TracerOptions,
+    program: Program,
+    mode: LaunchMode,
+    /// features turned off for the host
+    downgrades: Vec<doctor::Downgrade>,
+}
+
+fn run_tracer_main<G>(sched: &mut SchedWait<G>) -> i32 {
+    sched.run_all()
 }
 
 // hardcoded because `libc` does not export
@@ -161,29 +776,31 @@ fn tracee_init_signals() {
     };
 }
 
-fn run_tracee(argv: &Arguments) -> io::Result<i32> {
+fn run_tracee(launch: &Launch, cmd: &Program) -> io::Result<i32> {
+    let argv = launch.opts;
     let libs: Vec<_> = vec![&argv.preloader];
-    let ldpreload = String::from("LD_PRELOAD=")
-        + &libs
-            .iter()
-            .map(|p| p.to_str().unwrap())
-            .collect::<Vec<_>>()
-            .join(":");
+    let libs: Vec<_> = libs.iter().map(|p| p.to_str().unwrap()).collect();
+    let ldpreload = format!("LD_PRELOAD={}", libs.join(":"));
 
     unsafe {
         assert!(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == 0);
         assert!(libc::personality(PER_LINUX | ADDR_NO_RANDOMIZE) != -1);
     };
 
-    ptrace::traceme()
-        .and_then(|_| signal::raise(signal::SIGSTOP))
-        .map_err(from_nix_error)?;
+    // to be seized by the tracer.
+    signal::raise(signal::SIGSTOP).map_err(from_nix_error)?;
+
+    if argv.landlock {
+        if let Some(ruleset) = landlock_ruleset(launch)? {
+            ruleset.restrict_self()?;
+        }
+    }
 
     tracee_init_signals();
 
     let mut envs: Vec<String> = Vec::new();
 
-    if argv.host_envs {
+    if argv.host_envs && !argv.hermetic {
         std::env::vars().for_each(|(k, v)| {
             envs.push(format!("{}={}", k, v));
         });
@@ -199,11 +816,16 @@ fn run_tracee(argv: &Arguments) -> io::Result<i32> {
         }
     });
 
+    if argv.pin_locale {
+        envs.retain(|env| !pinning::is_pinned_env(env));
+        envs.extend(pinning::PINNED_ENVS.iter().map(|env| env.to_string()));
+    }
+
     envs.push(ldpreload);
-    let program = CString::new(argv.program.as_str())?;
+    let program = CString::new(cmd.program.as_str())?;
     let mut args: Vec<CString> = Vec::new();
     args.push(program.clone());
-    for v in argv.program_args.clone() {
+    for v in cmd.program_args.clone() {
         CString::new(v).map(|s| args.push(s))?;
     }
     let envp: Vec<CString> = envs
@@ -211,14 +833,10 @@ fn run_tracee(argv: &Arguments) -> io::Result<i32> {
         .map(|s| CString::new(s.as_bytes()).unwrap())
         .collect();
 
-    log::info!(
-        "[main] launching: {} {:?}",
-        &argv.program,
-        &argv.program_args
-    );
+    log::info!("[main] launching: {} {:?}", &cmd.program, &cmd.program_args);
     unistd::execvpe(&program, args.as_slice(), envp.as_slice())
         .map_err(from_nix_error)?;
-    panic!("exec failed: {} {:?}", &argv.program, &argv.program_args);
+    panic!("exec failed: {} {:?}", &cmd.program, &cmd.program_args);
 }
 
 fn show_perf_stats(state: &ReverieState) {
@@ -249,6 +867,35 @@ fn show_perf_stats(state: &ReverieState) {
         "syscalls captured(wo/ patching): {:.2}%",
         100.0 * (syscalls_captured - syscalls_patched) as f64 / syscalls as f64
     );
+
+    let syscalls_timed = state.stats.nr_syscalls_timed.load(Ordering::SeqCst);
+    let tracer_nanos = state.stats.syscall_tracer_nanos.load(Ordering::SeqCst);
+    let kernel_nanos = state.stats.syscall_kernel_nanos.load(Ordering::SeqCst);
+    let sampler = traced_task::syscall_sampler();
+    if sampler.sampled() < sampler.total() {
+        log::info!(
+            "seccomp syscalls sampled: {} of {}",
+            sampler.sampled(),
+            sampler.total()
+        );
+        for (no, count) in sampler.counts() {
+            log::info!("  {:?}: {}", SyscallNo::from(no), count);
+        }
+    }
+    if syscalls_timed > 0 {
+        log::info!(
+            "ptraced syscalls time in tracer: {:?} (avg {:?}), {:.2}%",
+            Duration::from_nanos(tracer_nanos as u64),
+            Duration::from_nanos((tracer_nanos / syscalls_timed) as u64),
+            100.0 * tracer_nanos as f64 / (tracer_nanos + kernel_nanos) as f64
+        );
+        log::info!(
+            "ptraced syscalls time in kernel: {:?} (avg {:?}), {:.2}%",
+            Duration::from_nanos(kernel_nanos as u64),
+            Duration::from_nanos((kernel_nanos / syscalls_timed) as u64),
+            100.0 * kernel_nanos as f64 / (tracer_nanos + kernel_nanos) as f64
+        );
+    }
 }
 
 fn task_exec_cb(task: &mut dyn Task) -> io::Result<()> {
@@ -279,77 +926,482 @@ fn task_exit_cb(_exit_code: i32) -> io::Result<()> {
     Ok(())
 }
 
+// hermetic execution of the program, as of `launch`
+fn hermetic(launch: &Launch) -> io::Result<Hermetic> {
+    let argv = launch.opts;
+    let cwd = env::current_dir()?;
+    let mut hermetic = Hermetic::new(0)?;
+    hermetic.allow(&cwd);
+    for lib in &[&argv.preloader, &argv.tool] {
+        hermetic.allow(lib.parent().unwrap_or(lib));
+    }
+    for cmd in launch.program.commands() {
+        if cmd.program.contains('/') {
+            hermetic.allow(cwd.join(&cmd.program));
+        }
+    }
+    for path in &argv.allow {
+        hermetic.allow(cwd.join(path));
+    }
+    if argv.pin_locale {
+        hermetic.allow(pinning_dir());
+    }
+    Ok(hermetic)
+}
+
+// pinned timezone files of --pin-locale, the same for every run
+fn pinning_dir() -> PathBuf {
+    env::temp_dir().join("reverie-pinned")
+}
+
+// syscall emulation of --policy-script
+#[cfg(feature = "scripting")]
+fn policy_script(path: &Path) -> io::Result<SyscallEmulation> {
+    Ok(reverie::script::ScriptPolicy::load(path)?.into_emulation())
+}
+
+#[cfg(not(feature = "scripting"))]
+fn policy_script(_path: &Path) -> io::Result<SyscallEmulation> {
+    Err(Error::new(
+        ErrorKind::InvalidInput,
+        "policy scripts need reverie built with feature scripting",
+    ))
+}
+
+// landlock ruleset of the --hermetic allowlist, `None` if not supported
+fn landlock_ruleset(launch: &Launch) -> io::Result<Option<LandlockRuleset>> {
+    let allowlist = hermetic(launch)?.allowlist().to_vec();
+    Ok(
+        landlock::abi_version()
+            .map(|abi| LandlockRuleset::new(abi, &allowlist)),
+    )
+}
+
+// callbacks of tasks traced, as of the options of `launch`
+fn tracer_callbacks(launch: &Launch) -> io::Result<TaskEventCB> {
+    let argv = launch.opts;
+    let mut cbs = TaskEventCB::new(
+        Box::new(task_exec_cb),
+        Box::new(task_fork_cb),
+        Box::new(task_clone_cb),
+        Box::new(task_exit_cb),
+    );
+    cbs.shared_memory = argv.shared_memory;
+    cbs.fd_provenance = argv.fd_provenance;
+    cbs.violation = argv.on_violation;
+    cbs.wx_policy = argv.wx;
+    cbs.wx_allowlist.extend(argv.wx_allow.iter().cloned());
+    cbs.fileless_dir = argv.save_fileless.clone();
+    cbs.hash_binaries = argv.hash_binaries;
+    cbs.record_data = argv.record_data || !argv.record_data_of.is_empty();
+    cbs.data_selection = DataSelection::new(argv.record_data_of.clone());
+    cbs.ticks =
+        argv.ticks || argv.timeslice.is_some() || argv.contention.is_some();
+    if let Some(overhead_budget) = argv.overhead_budget {
+        budget::set_budget(overhead_budget);
+    }
+    if let Some(path) = &argv.control_socket {
+        control::listen(path)?;
+    }
+    cbs.timeslice = argv.timeslice;
+    cbs.idle_after = argv.idle_after;
+    cbs.stack_frames = argv.stack_frames.or_else(|| {
+        argv.flamegraph
+            .as_ref()
+            .map(|_| unwind::DEFAULT_STACK_FRAMES)
+    });
+    if !argv.coverage.is_empty() {
+        let mut spec = CoverageSpec::new(argv.coverage.clone());
+        if let Some(blocks) = &argv.coverage_blocks {
+            spec.add_blocks(&std::fs::read_to_string(blocks)?)?;
+        }
+        cbs.coverage = Some(spec);
+    }
+    cbs.probes = argv.probe.clone();
+    if argv.malloc_stats.is_some() {
+        cbs.probes.extend(malloc_stats::malloc_probes());
+    }
+    if argv.contention.is_some() {
+        cbs.probes.extend(contention::contention_probes());
+    }
+    if argv.hermetic {
+        cbs.set_syscall_emulation(hermetic(launch)?.into_emulation());
+    }
+    if let Some(script) = &argv.policy_script {
+        cbs.set_syscall_emulation(policy_script(script)?);
+    }
+    if argv.pin_locale {
+        pinning::pin();
+        let pinned = LocalePinning::new(pinning_dir())?.into_emulation();
+        // before --hermetic, which denies the pinned files
+        let emulation = match cbs.on_syscall_emulation.take() {
+            Some(emulation) => pinned.chain(emulation),
+            None => pinned,
+        };
+        cbs.set_syscall_emulation(emulation);
+    }
+    // `enosys` lies are told by the preloader's filter, see
+    // `set_tracer_envs`
+    let lies: Vec<Lie> = argv
+        .lie
+        .iter()
+        .chain(&argv.pretend_kernel)
+        .filter(|lie| !lie.syscalls().is_empty())
+        .cloned()
+        .collect();
+    if !lies.is_empty() {
+        let lies = lies_emulation(lies);
+        cbs.chain_syscall_emulation(lies);
+    }
+    Ok(cbs)
+}
+
+// probe hits, written to `out` if any, logged otherwise
+fn probe_sink(out: Option<&PathBuf>) -> io::Result<EventSink> {
+    let mut out = match out {
+        Some(path) => Some(std::fs::File::create(path)?),
+        None => None,
+    };
+    Ok(Box::new(move |event| {
+        if let Event::Probe(probe, values) = &event.event {
+            let mut line = format!("{} {}", event.tid, probe);
+            for value in values {
+                match value {
+                    Some(value) => line.push_str(&format!(" {:#x}", value)),
+                    None => line.push_str(" ?"),
+                }
+            }
+            match out.as_mut() {
+                Some(out) => {
+                    if let Err(err) = writeln!(out, "{}", line) {
+                        log::warn!("[main] failed to write probe: {}", err);
+                    }
+                }
+                None => log::info!("[probe] {}", line),
+            }
+        }
+    }))
+}
+
+// match --ready-when conditions, reported by --ready-file
+fn ready_when(cbs: &mut TaskEventCB, argv: &TracerOptions) {
+    if argv.ready_when.contains(&ReadyCondition::Idle) {
+        cbs.idle_after.get_or_insert(Duration::from_secs(1));
+    }
+    let mut matcher = ReadyMatcher::new(argv.ready_when.clone());
+    let file = argv.ready_file.clone();
+    if let Some(file) = &file {
+        let _ = std::fs::remove_file(file);
+    }
+    matcher.set_ready_handler(Box::new(move || {
+        log::info!("[main] program ready");
+        if let Some(file) = &file {
+            if let Err(err) = std::fs::write(file, "ready\n") {
+                log::warn!("[main] failed to write {:?}: {}", file, err);
+            }
+        }
+    }));
+    let (ready, sink) = ready_tracing(Rc::new(RefCell::new(matcher)));
+    cbs.add_syscall_emulation(ready, sink);
+}
+
 fn run_tracer(
     starting_pid: unistd::Pid,
     starting_uid: unistd::Uid,
     starting_gid: unistd::Gid,
-    argv: &Arguments,
+    launch: &Launch,
 ) -> io::Result<i32> {
+    let argv = launch.opts;
     // tracer is the 1st process in the new namespace.
-    if argv.namespaces {
+    if argv.namespaces || argv.hermetic {
         ns::init_ns(starting_pid, starting_uid, starting_gid)?;
         debug_assert!(unistd::getpid() == unistd::Pid::from_raw(1));
     }
+    if argv.hermetic {
+        unistd::sethostname("reverie").map_err(from_nix_error)?;
+    }
 
-    let memfd_name = std::ffi::CStr::from_bytes_with_nul(&[
-        b'r', b'e', b'v', b'e', b'r', b'i', b'e', 0,
-    ])
-    .unwrap();
-    let fd_ = memfd::memfd_create(&memfd_name, memfd::MemFdCreateFlag::empty())
-        .expect("memfd_create failed");
-    let memfd = unistd::dup2(fd_, consts::REVERIE_GLOBAL_STATE_FD)
-        .expect("dup2 to REVERIE_GLOBAL_STATE_FD failed");
-    let _ = unistd::close(fd_);
-    let glob_size = 32768 * 4096;
-    let _ = unistd::ftruncate(memfd, 32768 * 4096)
-        .expect(&format!("memfd, unable to alloc {} bytes.", glob_size));
-
-    match unistd::fork().expect("fork failed") {
-        ForkResult::Child => run_tracee(argv),
-        ForkResult::Parent { child } => {
-            // wait for sigstop
-            wait_sigstop(child)?;
-            ptrace::setoptions(
-                child,
-                ptrace::Options::PTRACE_O_TRACEEXEC
-                    | ptrace::Options::PTRACE_O_EXITKILL
-                    | ptrace::Options::PTRACE_O_TRACECLONE
-                    | ptrace::Options::PTRACE_O_TRACEFORK
-                    | ptrace::Options::PTRACE_O_TRACEVFORK
-                    | ptrace::Options::PTRACE_O_TRACEVFORKDONE
-                    | ptrace::Options::PTRACE_O_TRACEEXIT
-                    | ptrace::Options::PTRACE_O_TRACESECCOMP
-                    | ptrace::Options::PTRACE_O_TRACESYSGOOD,
-            )
+    // entered by the tracee, which is forked below.
+    if argv.ns.contains(&ns::Namespace::Time) {
+        let offsets = ns::TimeOffsets {
+            monotonic: argv.monotonic_offset,
+            boottime: argv.boottime_offset,
+        };
+        if let Err(err) = ns::init_time_ns(&offsets) {
+            log::warn!(
+                "[main] cannot create time namespace, clocks not offset: {}",
+                err
+            );
+        }
+    }
+
+    attach::init_global_state();
+
+    // one tracee per command, sharing the tracer
+    let mut tracees = Vec::new();
+    for cmd in launch.program.commands() {
+        let child = sched_wait::spawn_stopped(|| run_tracee(launch, &cmd))?;
+        tracees.push(child);
+    }
+    let bpf_counters = argv.bpf_counters || argv.bpf_sample_every.is_some();
+    if let (true, Some((first, rest))) = (bpf_counters, tracees.split_first()) {
+        let sample_every =
+            argv.bpf_sample_every.unwrap_or(ebpf::DEFAULT_SAMPLE_EVERY);
+        let counted = ebpf::start(*first, sample_every)
+            .and_then(|()| rest.iter().try_for_each(|t| ebpf::enter(*t)));
+        if let Err(err) = counted {
+            log::warn!("[main] cannot count syscalls in-kernel: {}", err);
+        }
+    }
+    for tracee in &tracees {
+        ptrace::cont(*tracee, None)
             .map_err(|e| Error::new(ErrorKind::Other, e))?;
-            ptrace::cont(child, None)
-                .map_err(|e| Error::new(ErrorKind::Other, e))?;
-            let tracee = Task::new(child);
-            let cbs = TaskEventCB::new(
-                Box::new(task_exec_cb),
-                Box::new(task_fork_cb),
-                Box::new(task_clone_cb),
-                Box::new(task_exit_cb),
+    }
+    let mut cbs = tracer_callbacks(launch)?;
+    let landlock = if argv.landlock {
+        landlock_ruleset(launch)?
+    } else {
+        None
+    };
+    match &landlock {
+        Some(ruleset) => {
+            let paths: Vec<_> =
+                ruleset.rules.iter().map(|rule| &rule.path).collect();
+            log::info!(
+                "[main] landlock abi {}, paths allowed: {:?}",
+                ruleset.abi,
+                paths
             );
-            let mut sched: SchedWait<i32> = SchedWait::new(cbs, 0);
-            sched.add(tracee);
-            let res = run_tracer_main(&mut sched);
-            if argv.show_perf_stats {
-                let _ = reverie_global_state().lock().as_ref().and_then(|st| {
-                    show_perf_stats(st);
-                    Ok(())
-                });
+        }
+        None if argv.landlock => {
+            log::warn!("[main] landlock not supported, ignored")
+        }
+        None => (),
+    }
+    let replayed = Rc::new(RefCell::new(Vec::new()));
+    match &launch.mode {
+        LaunchMode::Run => (),
+        LaunchMode::Record(trace) => {
+            let header = RecordingHeader {
+                program: launch.program.program.clone(),
+                args: launch.program.program_args.clone(),
+                cwd: env::current_dir()?,
+                data: cbs.record_data,
+                data_selectors: argv
+                    .record_data_of
+                    .iter()
+                    .map(|selector| selector.to_string())
+                    .collect(),
+                ticks: cbs.ticks,
+                timeslice: cbs.timeslice,
+            };
+            let out = TraceOutput::create(trace, Duration::from_secs(1))?;
+            let sink = EventRecorder::new().into_sink(&header, out)?;
+            cbs.set_event_sink(sink);
+        }
+        LaunchMode::Replay(recording) => {
+            let replayed = replayed.clone();
+            let recorder = Rc::new(RefCell::new(EventRecorder::new()));
+            let sink_recorder = recorder.clone();
+            cbs.set_event_sink(Box::new(move |event| {
+                let mut recorder = sink_recorder.borrow_mut();
+                let event = recorder.record(event);
+                // blocks are compared by id, not kept
+                recorder.take_blocks();
+                if let Some(event) = event {
+                    replayed.borrow_mut().push(event);
+                }
+            }));
+            let commands = RecordedCommands::new(recording);
+            if !commands.is_empty() {
+                log::info!(
+                    "[main] {} ioctl and fcntl buffers to replay",
+                    commands.len()
+                );
+                let threads = recorder.clone();
+                cbs.chain_syscall_emulation(command_replay(
+                    Rc::new(RefCell::new(commands)),
+                    Box::new(move |tid| threads.borrow().thread(tid)),
+                ));
+            }
+            if cbs.ticks {
+                let mut signals = RecordedSignals::new(&recording.events);
+                log::info!(
+                    "[main] {} asynchronous signals to replay",
+                    signals.len()
+                );
+                cbs.set_replay_signals(Box::new(move |tid| {
+                    let thread = recorder.borrow().thread(tid)?;
+                    signals.next(thread)
+                }));
+            }
+        }
+    }
+    if let Some(file) = argv.idle_file.clone() {
+        let _ = std::fs::remove_file(&file);
+        cbs.add_event_sink(Box::new(move |event| {
+            if let Event::Idle(idle) = event.event {
+                let millis = format!("{}\n", idle.as_millis());
+                if let Err(err) = std::fs::write(&file, millis) {
+                    log::warn!("[main] failed to write {:?}: {}", file, err);
+                }
             }
-            Ok(res)
+        }));
+    }
+    if !argv.probe.is_empty() {
+        cbs.add_event_sink(probe_sink(argv.probe_out.as_ref())?);
+    }
+    if !argv.ready_when.is_empty() {
+        ready_when(&mut cbs, argv);
+    }
+    let malloc = Rc::new(RefCell::new(MallocTracker::new()));
+    if argv.malloc_stats.is_some() {
+        let (emulation, sink) = malloc_tracing(malloc.clone());
+        cbs.add_syscall_emulation(emulation, sink);
+    }
+    let contended = Rc::new(RefCell::new(ContentionProfiler::new()));
+    if argv.contention.is_some() {
+        let (emulation, sink) = contention_tracing(contended.clone());
+        cbs.add_syscall_emulation(emulation, sink);
+    }
+    let flamegraph = Rc::new(RefCell::new(FlameGraph::new()));
+    if argv.flamegraph.is_some() {
+        let (emulation, sink) = flamegraph_tracing(flamegraph.clone());
+        cbs.add_syscall_emulation(emulation, sink);
+    }
+    if argv.max_tasks.is_some() || argv.max_forks_per_second.is_some() {
+        let limits = TaskLimits::new(
+            argv.max_tasks,
+            argv.max_forks_per_second,
+            argv.on_task_limit,
+        );
+        let (emulation, sink) = task_limiting(Rc::new(RefCell::new(limits)));
+        cbs.add_syscall_emulation(emulation, sink);
+    }
+    if let Some(max) = argv.max_anon_memory {
+        let limit = Rc::new(RefCell::new(MemoryLimit::new(max)));
+        let (emulation, sink) = memory_limiting(limit);
+        cbs.add_syscall_emulation(emulation, sink);
+    }
+    let output = Rc::new(RefCell::new(OrderedOutput::new(
+        Box::new(std::io::stdout()),
+        Box::new(std::io::stderr()),
+    )));
+    if argv.serialize_output {
+        let (emulation, sink) = ordered_output(output.clone());
+        cbs.add_syscall_emulation(emulation, sink);
+    }
+    let capture = match &argv.capture_output {
+        Some(dir) => Some(Rc::new(RefCell::new(OutputCapture::new(dir)?))),
+        None => None,
+    };
+    if let Some(capture) = &capture {
+        let (emulation, sink) = output_capture(capture.clone());
+        cbs.add_syscall_emulation(emulation, sink);
+    }
+    let timeline = match &argv.timeline {
+        Some(path) => {
+            overhead::enable();
+            let out = TraceOutput::create(path, Duration::from_secs(1))?;
+            Some(Rc::new(RefCell::new(Timeline::new(Box::new(out)))))
+        }
+        None => None,
+    };
+    if let Some(timeline) = &timeline {
+        let timeline = timeline.clone();
+        cbs.add_event_sink(Box::new(move |event| {
+            timeline.borrow_mut().record_event(event)
+        }));
+    }
+    let chrome_trace = match &argv.chrome_trace {
+        Some(path) => {
+            let out = TraceOutput::create(path, Duration::from_secs(1))?;
+            Some(Rc::new(RefCell::new(ChromeTrace::new(Box::new(out)))))
         }
+        None => None,
+    };
+    if let Some(chrome_trace) = &chrome_trace {
+        let chrome_trace = chrome_trace.clone();
+        cbs.add_event_sink(Box::new(move |event| {
+            chrome_trace.borrow_mut().record_event(event)
+        }));
+    }
+    let exits = Rc::new(RefCell::new(ExitRecorder::new()));
+    if argv.report.is_some() {
+        let exits = exits.clone();
+        cbs.add_event_sink(Box::new(move |event| {
+            exits.borrow_mut().record_event(event)
+        }));
+    }
+    let mut sched: SchedWait<i32> = SchedWait::new(cbs, 0);
+    sched.set_policy(argv.sched_policy);
+    sched.set_unknown_event_policy(argv.unknown_event);
+    for tracee in tracees {
+        sched.add(Task::new(tracee));
+    }
+    let res = run_tracer_main(&mut sched);
+    drop(sched);
+    output.borrow_mut().finish();
+    if let Some(capture) = &capture {
+        capture.borrow_mut().finish()?;
+    }
+    if let Some(timeline) = &timeline {
+        timeline.borrow_mut().finish()?;
+    }
+    if let Some(chrome_trace) = &chrome_trace {
+        chrome_trace.borrow_mut().finish()?;
+    }
+    let bpf = ebpf::finish();
+    if let LaunchMode::Replay(recording) = &launch.mode {
+        let diff = RecordingDiff::new(&recording.events, &replayed.borrow());
+        eprint!("{}", diff);
+    }
+    if !argv.coverage.is_empty() {
+        let blocks = coverage::write_drcov(&argv.coverage_out)?;
+        log::info!(
+            "[main] {} blocks covered, written to {:?}",
+            blocks,
+            argv.coverage_out
+        );
+    }
+    if let Some(path) = &argv.malloc_stats {
+        malloc.borrow_mut().write(std::fs::File::create(path)?)?;
+        log::info!("[main] allocation statistics written to {:?}", path);
+    }
+    if let Some(path) = &argv.contention {
+        contended.borrow().write(std::fs::File::create(path)?)?;
+        log::info!("[main] contention report written to {:?}", path);
     }
+    if let Some(path) = &argv.flamegraph {
+        flamegraph.borrow().write(std::fs::File::create(path)?)?;
+        log::info!("[main] folded stacks written to {:?}", path);
+    }
+    if let Some(path) = &argv.report {
+        let state = reverie_global_state()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut report = exits.borrow().report(res, &state.stats);
+        report.landlock_abi = landlock.map(|ruleset| ruleset.abi);
+        report.bpf = bpf;
+        report.downgrades =
+            launch.downgrades.iter().map(|d| d.to_string()).collect();
+        report.write(std::fs::File::create(path)?)?;
+    }
+    if argv.show_perf_stats {
+        let _ = reverie_global_state().lock().as_ref().and_then(|st| {
+            show_perf_stats(st);
+            Ok(())
+        });
+    }
+    Ok(res)
 }
 
-fn run_app(argv: &Arguments) -> io::Result<i32> {
+fn run_app(launch: &Launch) -> io::Result<i32> {
+    let argv = launch.opts;
     let (starting_pid, starting_uid, starting_gid) =
         (unistd::getpid(), unistd::getuid(), unistd::getgid());
 
-    if argv.namespaces {
+    if argv.namespaces || argv.hermetic {
         unsafe {
             assert!(
                 libc::unshare(
@@ -363,7 +1415,7 @@ fn run_app(argv: &Arguments) -> io::Result<i32> {
 
         match unistd::fork().expect("fork failed") {
             ForkResult::Child => {
-                run_tracer(starting_pid, starting_uid, starting_gid, argv)
+                run_tracer(starting_pid, starting_uid, starting_gid, launch)
             }
             ForkResult::Parent { child } => {
                 match wait::waitpid(Some(child), None) {
@@ -379,7 +1431,7 @@ fn run_app(argv: &Arguments) -> io::Result<i32> {
             }
         }
     } else {
-        run_tracer(starting_pid, starting_uid, starting_gid, argv)
+        run_tracer(starting_pid, starting_uid, starting_gid, launch)
     }
 }
 
@@ -404,15 +1456,360 @@ fn populate_rpath(hint: Option<&str>, so: &str) -> io::Result<PathBuf> {
     })
 }
 
+// environment of the tracer (and tracees), as of `opts`
+fn set_tracer_envs(opts: &TracerOptions) {
+    if let Some(spec) = &opts.sample {
+        std::env::set_var(consts::REVERIE_SAMPLING, spec);
+    }
+    if let Some(spec) = &opts.syscall_tiers {
+        std::env::set_var(consts::REVERIE_TIERS, spec);
+    }
+    let mut enosys: Vec<String> = opts
+        .lie
+        .iter()
+        .chain(&opts.enosys)
+        .chain(&opts.pretend_kernel)
+        .flat_map(Lie::enosys_syscalls)
+        .map(|nr| nr.to_string())
+        .collect();
+    // task limits are enforced on clone, which programs fall back to
+    if opts.max_tasks.is_some() || opts.max_forks_per_second.is_some() {
+        enosys.push(SYS_CLONE3.to_string());
+    }
+    if !enosys.is_empty() {
+        std::env::set_var(consts::REVERIE_ENOSYS, enosys.join(","));
+    }
+    std::env::set_var(consts::REVERIE_TRACEE_PRELOAD, opts.tool.as_os_str());
+    std::env::set_var(consts::REVERIE_PRELOADER, opts.preloader.as_os_str());
+    // sites may be bypassed when over budget
+    let bypass = opts.adaptive_bypass.is_some()
+        && opts.hot_site_policy == HotSitePolicy::Bypass;
+    if bypass || opts.overhead_budget.is_some() {
+        let path = env::current_dir()
+            .expect("current dir")
+            .join(&opts.adaptive_bypass_file);
+        std::env::set_var(consts::REVERIE_ADAPTIVE_BYPASS_FILE, path);
+    }
+    if let Some(threshold) = opts.adaptive_bypass {
+        let policy = match opts.hot_site_policy {
+            HotSitePolicy::Warn => "warn",
+            HotSitePolicy::Bypass => "bypass",
+        };
+        std::env::set_var(consts::REVERIE_HOT_SITE_POLICY, policy);
+        std::env::set_var(
+            consts::REVERIE_ADAPTIVE_BYPASS_THRESHOLD,
+            threshold.to_string(),
+        );
+    }
+    if opts.verify_patches {
+        std::env::set_var(consts::REVERIE_VERIFY_PATCHES, "1");
+    }
+}
+
+fn run_program(
+    opts: &TracerOptions,
+    program: &Program,
+    mode: LaunchMode,
+) -> io::Result<i32> {
+    nesting::report_outer_layers();
+    let mut opts = opts.clone();
+    let downgrades = downgrade(&mut opts, &doctor::probe())?;
+    set_tracer_envs(&opts);
+    let launch = Launch {
+        opts: &opts,
+        program: program.clone(),
+        mode,
+        downgrades,
+    };
+    run_app(&launch)
+}
+
+// turn off features the host does not support, see `doctor::Probe`
+fn downgrade(
+    opts: &mut TracerOptions,
+    probe: &doctor::Probe,
+) -> io::Result<Vec<doctor::Downgrade>> {
+    if opts.hermetic && !probe.user_ns {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "--hermetic needs user namespaces, which can't be created",
+        ));
+    }
+    if !probe.fixed_fds {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "limit of open files is too low, raise ulimit -n",
+        ));
+    }
+    let time_ns = opts.ns.contains(&ns::Namespace::Time);
+    let downgrades = probe.downgrades(opts.namespaces, time_ns);
+    for downgrade in &downgrades {
+        log::warn!("[main] downgraded {}", downgrade);
+        match downgrade.feature {
+            "namespaces" => opts.namespaces = false,
+            "time namespace" => opts.ns.retain(|ns| *ns != ns::Namespace::Time),
+            _ => (),
+        }
+    }
+    Ok(downgrades)
+}
+
+fn replay(opts: &TracerOptions, trace: &PathBuf) -> io::Result<i32> {
+    let file = std::fs::File::open(trace)?;
+    let recording = read_recording(io::BufReader::new(file))?;
+    let header = recording.header.clone();
+    env::set_current_dir(&header.cwd)?;
+    // data and ticks as recorded, so that they compare
+    let mut opts = opts.clone();
+    opts.record_data = header.data;
+    opts.record_data_of = header
+        .data_selectors
+        .iter()
+        .map(|selector| selector.parse())
+        .collect::<io::Result<_>>()?;
+    opts.ticks = header.ticks;
+    opts.timeslice = header.timeslice;
+    let program = Program {
+        program: header.program,
+        program_args: header.args,
+    };
+    run_program(&opts, &program, LaunchMode::Replay(recording))
+}
+
+fn attach(
+    pid: unistd::Pid,
+    sched_policy: SchedPolicy,
+    libraries: &[PathBuf],
+    got_hooks: &[GotHook],
+) -> io::Result<i32> {
+    let mut tasks = attach::seize_process(pid, false)?;
+    let leader = tasks.pop().expect("leader seized last");
+    let cbs = TaskEventCB::new(
+        Box::new(task_exec_cb),
+        Box::new(task_fork_cb),
+        Box::new(task_clone_cb),
+        Box::new(task_exit_cb),
+    );
+    let mut sched: SchedWait<i32> = SchedWait::new(cbs, 0);
+    sched.set_policy(sched_policy);
+    for task in tasks {
+        sched.add(task);
+    }
+    if !libraries.is_empty() {
+        let load = || -> io::Result<()> {
+            let mut loaded = Vec::new();
+            for library in libraries {
+                let bias = leader.load_library(library)?;
+                log::info!("[main] loaded {:?} at {:#x}", library, bias);
+                loaded.push((library.clone(), bias));
+            }
+            if !got_hooks.is_empty() {
+                let patches = leader.patch_got(&loaded, got_hooks)?;
+                for patch in &patches {
+                    log::info!(
+                        "[main] {} of {:?} hooked at {:#x}",
+                        patch.symbol,
+                        patch.object,
+                        patch.slot
+                    );
+                }
+            }
+            Ok(())
+        };
+        sched_wait::with_interrupted(pid, load).map_err(from_nix_error)??;
+    }
+    sched.add(leader);
+    log::info!("[main] attached to {}, syscalls are not intercepted", pid);
+    Ok(run_tracer_main(&mut sched))
+}
+
+// global state memfd of tracees of a session, held by process `pid`
+fn join_global_state(pid: unistd::Pid) -> io::Result<()> {
+    let fd = consts::REVERIE_GLOBAL_STATE_FD;
+    let path = format!("/proc/{}/fd/{}", pid, fd);
+    let fd_ = nix::fcntl::open(path.as_str(), OFlag::O_RDWR, Mode::empty())
+        .map_err(from_nix_error)?;
+    unistd::dup2(fd_, fd).map_err(from_nix_error)?;
+    let _ = unistd::close(fd_);
+    Ok(())
+}
+
+fn attach_session(path: &Path, sched_policy: SchedPolicy) -> io::Result<i32> {
+    let state = SessionState::load(path)?;
+    env::set_current_dir(&state.cwd)?;
+    let args = Arguments::from_iter_safe(&state.tracer_args)
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err.message))?;
+    let (opts, program) = match &args.command {
+        Command::Run { tracer, program } => (tracer, program),
+        Command::Record {
+            tracer, program, ..
+        } => {
+            log::warn!("[main] recording of the session is not resumed");
+            (tracer, program)
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "session of neither reverie run nor record",
+            ))
+        }
+    };
+    set_tracer_envs(opts);
+    let launch = Launch {
+        opts,
+        program: program.clone(),
+        mode: LaunchMode::Run,
+        downgrades: Vec::new(),
+    };
+    let first = state.tasks.first().ok_or_else(|| {
+        Error::new(ErrorKind::InvalidData, "session without tasks")
+    })?;
+    if let Err(err) = join_global_state(unistd::Pid::from_raw(first.pid)) {
+        log::warn!("[main] global state of the session is lost: {}", err);
+        attach::init_global_state();
+    }
+    let cbs = tracer_callbacks(&launch)?;
+    let mut sched: SchedWait<i32> = SchedWait::new(cbs, 0);
+    sched.set_policy(sched_policy);
+    sched.set_unknown_event_policy(opts.unknown_event);
+    // not killed when the tracer exits, as attached processes.
+    let options =
+        sched_wait::ptrace_options() - ptrace::Options::PTRACE_O_EXITKILL;
+    let mut processes: HashMap<i32, ProcessRef> = HashMap::new();
+    for task in &state.tasks {
+        let tid = unistd::Pid::from_raw(task.tid);
+        if let Err(err) = sched_wait::seize_running(tid, options) {
+            log::warn!("[main] cannot attach to {}: {}", tid, err);
+            continue;
+        }
+        let process = processes
+            .entry(task.pid)
+            .or_insert_with(|| state.restore_process(task.pid));
+        sched.add(TracedTask::restored(task, process.clone()));
+    }
+    // stopped when detached
+    for pid in processes.keys() {
+        let _ = signal::kill(unistd::Pid::from_raw(*pid), signal::SIGCONT);
+    }
+    log::info!(
+        "[main] attached to {} processes of session {:?}",
+        processes.len(),
+        path
+    );
+    Ok(run_tracer_main(&mut sched))
+}
+
+fn doctor() -> i32 {
+    let checks = doctor::run_checks();
+    for check in &checks {
+        println!("{}", check);
+    }
+    let failed = checks.iter().any(|c| c.status == doctor::Status::Fail);
+    if failed {
+        1
+    } else {
+        0
+    }
+}
+
+fn diff(a: &PathBuf, b: &PathBuf) -> io::Result<i32> {
+    let read = |path| -> io::Result<Vec<RecordedEvent>> {
+        let file = std::fs::File::open(path)?;
+        Ok(read_recording(io::BufReader::new(file))?.events)
+    };
+    let diff = RecordingDiff::new(&read(a)?, &read(b)?);
+    print!("{}", diff);
+    Ok(if diff.is_empty() { 0 } else { 1 })
+}
+
+fn native(timeline: &PathBuf, out: &PathBuf) -> io::Result<i32> {
+    let file = std::fs::File::open(timeline)?;
+    let (events, summary) = overhead::read_timeline(io::BufReader::new(file))?;
+    let estimate = NativeEstimate::new(&events, summary);
+    let file = std::fs::File::create(out)?;
+    estimate.write_events(io::BufWriter::new(file))?;
+    print!("{}", estimate);
+    Ok(0)
+}
+
+fn scrub(
+    scrubber: &Scrubber,
+    trace: &PathBuf,
+    out: &PathBuf,
+) -> io::Result<i32> {
+    let file = std::fs::File::open(trace)?;
+    let mut recording = read_recording(io::BufReader::new(file))?;
+    scrubber.scrub(&mut recording);
+    let file = std::fs::File::create(out)?;
+    let mut out = io::BufWriter::new(file);
+    write_recording(&recording, &mut out)?;
+    out.flush()?;
+    Ok(0)
+}
+
 #[paw::main]
 fn main(args: Arguments) {
-    setup_logger(args.log_level, args.log_output.as_ref().map(|s| s.as_ref()))
-        .expect("set log level");
+    let global = &args.global;
+    setup_logger(
+        global.log_level.unwrap_or(0),
+        global.log_output.as_ref().map(|s| s.as_ref()),
+    )
+    .expect("set log level");
+    if let Some(sig) = global.detach_on {
+        detach::detach_on(sig);
+    }
 
-    std::env::set_var(consts::REVERIE_TRACEE_PRELOAD, args.tool.as_os_str());
-    match run_app(&args) {
+    let res = match &args.command {
+        Command::Run { tracer, program } => {
+            run_program(tracer, program, LaunchMode::Run)
+        }
+        Command::Record {
+            trace,
+            tracer,
+            program,
+        } => run_program(tracer, program, LaunchMode::Record(trace.clone())),
+        Command::Replay { tracer, trace } => replay(tracer, trace),
+        Command::Attach {
+            sched_policy,
+            session,
+            load,
+            got_hook,
+            pid,
+        } => match (session, pid) {
+            (Some(session), _) => attach_session(session, *sched_policy),
+            (None, Some(pid)) => attach(
+                unistd::Pid::from_raw(*pid),
+                *sched_policy,
+                load,
+                got_hook,
+            ),
+            (None, None) => {
+                Err(Error::new(ErrorKind::InvalidInput, "no PID to attach"))
+            }
+        },
+        Command::Doctor => Ok(doctor()),
+        Command::Diff { a, b } => diff(a, b),
+        Command::Scrub {
+            data,
+            path,
+            env,
+            hash,
+            out,
+            trace,
+        } => {
+            let scrubber = Scrubber {
+                data: *data,
+                paths: path.clone(),
+                env: env.clone(),
+                hash: *hash,
+            };
+            scrub(&scrubber, trace, out)
+        }
+        Command::Native { out, timeline } => native(timeline, out),
+    };
+    match res {
         Ok(exit_code) => std::process::exit(exit_code),
-        err => panic!("run app failed with error: {:?}", err),
+        err => panic!("{:?} failed with error: {:?}", args.command, err),
     }
 }
 
@@ -445,9 +1842,67 @@ fn setup_logger(level: u32, output: Option<&str>) -> io::Result<()> {
         _ => log::LevelFilter::Trace,
     };
 
-    fern_with_output(output)?
-        .level(log_level)
-        .format(|out, message, _record| out.finish(format_args!("{}", message)))
+    let output = fern_with_output(output)?.level(log_level).format(
+        |out, message, _record| out.finish(format_args!("{}", message)),
+    );
+    // warnings are kept for --report, whatever the log level.
+    let warnings = fern::Dispatch::new().level(log::LevelFilter::Warn).chain(
+        fern::Output::call(|record| {
+            report::record_warning(record.args().to_string())
+        }),
+    );
+    fern::Dispatch::new()
+        .chain(output)
+        .chain(warnings)
         .apply()
         .map_err(|e| Error::new(ErrorKind::Other, e))
 }
+
+#[test]
+fn program_commands_sanity_check() {
+    let words = |words: &[&str]| -> Vec<String> {
+        words.iter().map(|word| String::from(*word)).collect()
+    };
+    let program = Program {
+        program: String::from("echo"),
+        program_args: words(&["a", ":::", "true", ":::", ":::", "ls", "-l"]),
+    };
+    let commands: Vec<_> = program
+        .commands()
+        .into_iter()
+        .map(|cmd| (cmd.program, cmd.program_args))
+        .collect();
+    let expected = vec![
+        (String::from("echo"), words(&["a"])),
+        (String::from("true"), Vec::new()),
+        (String::from("ls"), words(&["-l"])),
+    ];
+    assert_eq!(commands, expected);
+    let program = Program {
+        program: String::from("parallel"),
+        program_args: words(&["echo", "\\:::", "a", "\\\\:::", ":::", "ls"]),
+    };
+    let commands = program.commands();
+    assert_eq!(commands.len(), 2);
+    assert_eq!(
+        commands[0].program_args,
+        words(&["echo", ":::", "a", "\\:::"])
+    );
+    assert_eq!(commands[1].program, "ls");
+}
+
+#[test]
+fn arguments_sanity_check() {
+    let run = |extra: &[&str]| {
+        let mut args = vec!["reverie", "run", "--preloader", "/bin/true"];
+        args.extend_from_slice(&["--tool", "/bin/true"]);
+        args.extend_from_slice(extra);
+        args.push("/bin/true");
+        Arguments::from_iter_safe(args)
+    };
+    assert!(run(&[]).is_ok());
+    assert!(run(&["--bpf-counters"]).is_ok());
+    assert!(run(&["--bpf-counters", "--bpf-sample-every", "8"]).is_ok());
+    assert!(run(&["--bpf-sample-every", "8"]).is_ok());
+    assert!(run(&["--bpf-sample-every", "eight"]).is_err());
+}
@@ -0,0 +1,359 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! allocation statistics of traced processes, of `--malloc-stats`
+//!
+//! `malloc`, `calloc`, `realloc` (by return probes) and `free` of glibc
+//! are probed, see `malloc_probes`, as well as anonymous `mmap`s and
+//! `munmap`s, at exit. each process image, until exec or exit, gets its
+//! allocation counts, peak bytes in use and mapped, and its leak
+//! candidates: blocks never freed, by call site (the return address of
+//! the allocation).
+//!
+//! NB: glibc's `realloc` calls `malloc` or `free` itself for a null
+//! pointer or a zero size, which are counted once. allocators other than
+//! glibc's, or linked statically, are not probed.
+
+use nix::unistd::Pid;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::{Result, Write};
+use std::rc::Rc;
+use syscalls::SyscallNo;
+
+use reverie_api::emulate::*;
+use reverie_api::event::*;
+use reverie_api::probe::ProbeSpec;
+use reverie_api::remote::SyscallArgs;
+
+/// probes of the allocator: `malloc`, `calloc`, `realloc`, `free`
+pub const MALLOC_PROBES: &[&str] = &[
+    "libc.so.6:malloc%return,rdi,sp",
+    "libc.so.6:calloc%return,rdi,rsi,sp",
+    "libc.so.6:realloc%return,rdi,rsi,sp",
+    "libc.so.6:free,rdi",
+];
+
+/// probes of `MALLOC_PROBES`, to add to `TaskEventCB::probes`
+pub fn malloc_probes() -> Vec<ProbeSpec> {
+    MALLOC_PROBES
+        .iter()
+        .map(|probe| probe.parse().unwrap())
+        .collect()
+}
+
+/// allocation statistics of a process image
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MallocStats {
+    pub pid: i32,
+    pub allocations: u64,
+    pub bytes_allocated: u64,
+    pub frees: u64,
+    /// bytes of the blocks in use
+    pub in_use: u64,
+    pub peak_in_use: u64,
+    /// anonymous mappings
+    pub mappings: u64,
+    pub mapped: u64,
+    pub peak_mapped: u64,
+    /// blocks in use, by address: size and call site
+    blocks: HashMap<u64, (u64, u64)>,
+    /// anonymous mappings, by address: size
+    maps: BTreeMap<u64, u64>,
+}
+
+impl MallocStats {
+    fn new(pid: Pid) -> Self {
+        MallocStats {
+            pid: pid.as_raw(),
+            ..Default::default()
+        }
+    }
+
+    fn allocated(&mut self, addr: u64, size: u64, site: u64) {
+        self.allocations += 1;
+        self.bytes_allocated += size;
+        self.in_use += size;
+        self.peak_in_use = self.peak_in_use.max(self.in_use);
+        if let Some((former, _)) = self.blocks.insert(addr, (size, site)) {
+            // freed unseen, i.e.: by an allocator function not probed
+            self.in_use -= former;
+        }
+    }
+
+    fn freed(&mut self, addr: u64) {
+        self.frees += 1;
+        if let Some((size, _)) = self.blocks.remove(&addr) {
+            self.in_use -= size;
+        }
+    }
+
+    fn mapped(&mut self, addr: u64, size: u64) {
+        self.unmapped(addr, size);
+        self.mappings += 1;
+        self.maps.insert(addr, size);
+        self.mapped += size;
+        self.peak_mapped = self.peak_mapped.max(self.mapped);
+    }
+
+    fn unmapped(&mut self, addr: u64, size: u64) {
+        let end = addr + size;
+        let overlapping: Vec<(u64, u64)> = self
+            .maps
+            .range(..end)
+            .filter(|(start, len)| **start + **len > addr)
+            .map(|(start, len)| (*start, *len))
+            .collect();
+        for (start, len) in overlapping {
+            self.maps.remove(&start);
+            self.mapped -= len;
+            // what is left below and above the unmapped range
+            if start < addr {
+                self.maps.insert(start, addr - start);
+                self.mapped += addr - start;
+            }
+            if start + len > end {
+                self.maps.insert(end, start + len - end);
+                self.mapped += start + len - end;
+            }
+        }
+    }
+
+    /// leak candidates: call site, blocks and bytes never freed, by bytes
+    pub fn leaks(&self) -> Vec<(u64, u64, u64)> {
+        let mut sites: HashMap<u64, (u64, u64)> = HashMap::new();
+        for (size, site) in self.blocks.values() {
+            let leaked = sites.entry(*site).or_default();
+            leaked.0 += 1;
+            leaked.1 += size;
+        }
+        let mut leaks: Vec<_> = sites
+            .into_iter()
+            .map(|(site, (blocks, bytes))| (site, blocks, bytes))
+            .collect();
+        leaks.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+        leaks
+    }
+}
+
+impl fmt::Display for MallocStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "pid {}: {} allocations ({} bytes), {} frees, peak {} bytes in use",
+            self.pid,
+            self.allocations,
+            self.bytes_allocated,
+            self.frees,
+            self.peak_in_use
+        )?;
+        writeln!(
+            f,
+            "  {} anonymous mappings, peak {} bytes mapped",
+            self.mappings, self.peak_mapped
+        )?;
+        if !self.blocks.is_empty() {
+            writeln!(
+                f,
+                "  {} blocks ({} bytes) never freed:",
+                self.blocks.len(),
+                self.in_use
+            )?;
+        }
+        for (site, blocks, bytes) in self.leaks() {
+            writeln!(
+                f,
+                "    {} blocks ({} bytes) allocated at {:#x}",
+                blocks, bytes, site
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// process of thread `tid`, `tid` itself if gone
+fn pid_of(tid: Pid) -> Pid {
+    let status = fs::read_to_string(format!("/proc/{}/status", tid));
+    status
+        .ok()
+        .and_then(|status| {
+            let tgid = status.lines().find(|l| l.starts_with("Tgid:"))?;
+            tgid["Tgid:".len()..].trim().parse().ok()
+        })
+        .map(Pid::from_raw)
+        .unwrap_or(tid)
+}
+
+/// collects `MallocStats` of process images
+#[derive(Default)]
+pub struct MallocTracker {
+    /// processes, by tid
+    pids: HashMap<Pid, Pid>,
+    images: HashMap<Pid, MallocStats>,
+    /// images exec'ed over, or exited
+    ended: Vec<MallocStats>,
+}
+
+impl MallocTracker {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn stats_of(&mut self, tid: Pid) -> &mut MallocStats {
+        let pid = *self.pids.entry(tid).or_insert_with(|| pid_of(tid));
+        self.images
+            .entry(pid)
+            .or_insert_with(|| MallocStats::new(pid))
+    }
+
+    fn end(&mut self, pid: Pid) {
+        if let Some(stats) = self.images.remove(&pid) {
+            if stats.allocations > 0 || stats.mappings > 0 {
+                self.ended.push(stats);
+            }
+        }
+    }
+
+    /// `mmap` or `munmap` of process `pid` returned `retval`
+    pub fn exited(
+        &mut self,
+        pid: Pid,
+        syscall: SyscallNo,
+        args: &SyscallArgs,
+        retval: i64,
+    ) {
+        if retval < 0 {
+            return;
+        }
+        self.pids.insert(pid, pid);
+        match syscall {
+            SyscallNo::SYS_mmap
+                if args.arg3 as i32 & libc::MAP_ANONYMOUS != 0 =>
+            {
+                self.stats_of(pid).mapped(retval as u64, args.arg1)
+            }
+            SyscallNo::SYS_munmap => {
+                self.stats_of(pid).unmapped(args.arg0, args.arg1)
+            }
+            _ => (),
+        }
+    }
+
+    /// `event` observed by the tracer
+    pub fn record_event(&mut self, event: &TimedEvent) {
+        let tid = event.tid;
+        match &event.event {
+            Event::Probe(probe, values) => {
+                let kind = MALLOC_PROBES.iter().position(|p| p == probe);
+                let v: Vec<u64> =
+                    values.iter().map(|v| v.unwrap_or(0)).collect();
+                let stats = self.stats_of(tid);
+                match (kind, v.as_slice()) {
+                    (Some(0), &[size, site, addr]) if addr != 0 => {
+                        stats.allocated(addr, size, site)
+                    }
+                    (Some(1), &[n, size, site, addr]) if addr != 0 => {
+                        stats.allocated(addr, n.saturating_mul(size), site)
+                    }
+                    // by `malloc` or `free` otherwise
+                    (Some(2), &[old, size, site, addr])
+                        if old != 0 && size != 0 && addr != 0 =>
+                    {
+                        stats.freed(old);
+                        stats.allocated(addr, size, site);
+                    }
+                    (Some(3), &[addr]) if addr != 0 => stats.freed(addr),
+                    _ => (),
+                }
+            }
+            Event::Exec => {
+                self.pids.insert(tid, tid);
+                self.end(tid);
+            }
+            Event::Exited(_) if self.pids.remove(&tid) == Some(tid) => {
+                self.end(tid);
+            }
+            _ => (),
+        }
+    }
+
+    /// write statistics of all process images to `out`
+    pub fn write<W: Write>(&mut self, mut out: W) -> Result<()> {
+        let pids: Vec<Pid> = self.images.keys().cloned().collect();
+        for pid in pids {
+            self.end(pid);
+        }
+        for stats in &self.ended {
+            write!(out, "{}", stats)?;
+        }
+        Ok(())
+    }
+}
+
+/// syscall emulation (declining anonymous `mmap`s and `munmap`s) and event
+/// sink recording with `tracker`
+pub fn malloc_tracing(
+    tracker: Rc<RefCell<MallocTracker>>,
+) -> (SyscallEmulation, EventSink) {
+    let syscalls = vec![SyscallNo::SYS_mmap, SyscallNo::SYS_munmap];
+    let mut emulation = SyscallEmulation::new(
+        EmulationMode::Seccomp,
+        syscalls,
+        Box::new(|_task, _memory, _syscall, _args| None),
+    );
+    let exits = tracker.clone();
+    emulation.set_exit_handler(Box::new(
+        move |task, _memory, syscall, args, retval| {
+            let pid = task.getpid();
+            exits.borrow_mut().exited(pid, syscall, args, retval)
+        },
+    ));
+    let sink: EventSink =
+        Box::new(move |event| tracker.borrow_mut().record_event(event));
+    (emulation, sink)
+}
+
+#[test]
+fn malloc_tracker_sanity_check() {
+    assert_eq!(malloc_probes().len(), MALLOC_PROBES.len());
+    let pid = Pid::from_raw(i32::MAX);
+    let probe = |k: usize, values: &[u64]| TimedEvent {
+        tid: pid,
+        at: Default::default(),
+        ticks: None,
+        event: Event::Probe(
+            MALLOC_PROBES[k].to_string(),
+            values.iter().map(|v| Some(*v)).collect(),
+        ),
+    };
+    let mut tracker = MallocTracker::new();
+    tracker.record_event(&probe(0, &[16, 0x401000, 0x1000]));
+    tracker.record_event(&probe(1, &[4, 8, 0x401100, 0x2000]));
+    tracker.record_event(&probe(2, &[0x1000, 64, 0x401200, 0x3000]));
+    tracker.record_event(&probe(3, &[0x2000]));
+    let args = SyscallArgs::from(0, 0x4000, 3, 0x22, 0, 0);
+    tracker.exited(pid, SyscallNo::SYS_mmap, &args, 0x7000_0000);
+    let args = SyscallArgs::from(0x7000_1000, 0x1000, 0, 0, 0, 0);
+    tracker.exited(pid, SyscallNo::SYS_munmap, &args, 0);
+    let stats = tracker.images[&pid].clone();
+    assert_eq!((stats.allocations, stats.frees), (3, 2));
+    assert_eq!((stats.in_use, stats.peak_in_use), (64, 96));
+    assert_eq!((stats.mapped, stats.peak_mapped), (0x3000, 0x4000));
+    assert_eq!(stats.leaks(), vec![(0x401200, 1, 64)]);
+    let mut out = Vec::new();
+    tracker.write(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("1 blocks (64 bytes) allocated at 0x401200"));
+}
@@ -0,0 +1,202 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! memory mapping change detection
+//!
+//! when a `MappingChangeFn` is set, `mmap`, `mremap`, `mprotect`,
+//! `pkey_mprotect` and `munmap` are never patched, so that they always
+//! stop at syscall exit, where the `MappingChange`s are decoded. likewise
+//! when a `WxPolicy` is set, so that W^X is checked at seccomp stop, see
+//! `is_wx_request`.
+
+use nix::sys::mman::ProtFlags;
+use nix::unistd::Pid;
+use std::path::PathBuf;
+use syscalls::*;
+
+use reverie_api::mapping::*;
+
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+/// syscalls to be stopped (and never patched) when mappings are tracked
+pub fn is_mapping_syscall(syscall: SyscallNo) -> bool {
+    match syscall {
+        SYS_mmap | SYS_mremap | SYS_mprotect | SYS_pkey_mprotect
+        | SYS_munmap => true,
+        _ => false,
+    }
+}
+
+/// whether `syscall` with arguments in `regs` asks for a mapping both
+/// writable and executable
+pub fn is_wx_request(
+    syscall: SyscallNo,
+    regs: &libc::user_regs_struct,
+) -> bool {
+    let wx = ProtFlags::PROT_WRITE | ProtFlags::PROT_EXEC;
+    match syscall {
+        SYS_mmap | SYS_mprotect | SYS_pkey_mprotect => {
+            ProtFlags::from_bits_truncate(regs.rdx as i32).contains(wx)
+        }
+        _ => false,
+    }
+}
+
+/// whether the program process `pid` runs is in `allowlist`, see
+/// `is_wx_allowed`
+pub fn is_wx_allowed_process(pid: Pid, allowlist: &[String]) -> bool {
+    std::fs::read_link(format!("/proc/{}/exe", pid))
+        .map(|exe| is_wx_allowed(&exe, allowlist))
+        .unwrap_or(false)
+}
+
+fn fd_path(pid: Pid, fd: i32) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/{}/fd/{}", pid, fd)).ok()
+}
+
+/// decode mappings changed by a (successful) syscall of process `pid`,
+/// `regs` are from the syscall exit stop.
+pub fn mapping_changes_at_exit(
+    pid: Pid,
+    regs: &libc::user_regs_struct,
+) -> Vec<MappingChange> {
+    let retval = regs.rax as i64;
+    if retval < 0 && retval > -4096 {
+        return Vec::new();
+    }
+    let prot = ProtFlags::from_bits_truncate(regs.rdx as i32);
+    let mut changes = Vec::new();
+    match SyscallNo::from(regs.orig_rax as i32) {
+        SYS_mmap => {
+            // the mappings previously at `addr` are unmapped
+            if regs.r10 & MAP_FIXED != 0 {
+                changes.push(MappingChange::MappingRemoved {
+                    addr: retval as u64,
+                    size: regs.rsi,
+                });
+            }
+            if prot.contains(ProtFlags::PROT_EXEC) {
+                let path = if regs.r10 & MAP_ANONYMOUS != 0 {
+                    None
+                } else {
+                    fd_path(pid, regs.r8 as i32)
+                };
+                changes.push(MappingChange::NewExecutableMapping {
+                    addr: retval as u64,
+                    size: regs.rsi,
+                    prot,
+                    path,
+                });
+            }
+        }
+        SYS_mremap => changes.push(MappingChange::MappingMoved {
+            from: regs.rdi,
+            from_size: regs.rsi,
+            addr: retval as u64,
+            size: regs.rdx,
+        }),
+        SYS_mprotect | SYS_pkey_mprotect => {
+            changes.push(MappingChange::PermissionsChanged {
+                addr: regs.rdi,
+                size: regs.rsi,
+                prot,
+            })
+        }
+        SYS_munmap => changes.push(MappingChange::MappingRemoved {
+            addr: regs.rdi,
+            size: regs.rsi,
+        }),
+        _ => (),
+    }
+    changes
+}
+
+#[test]
+fn mapping_changes_at_exit_sanity_check() {
+    let pid = nix::unistd::getpid();
+    let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+    regs.orig_rax = SYS_mmap as u64;
+    regs.rax = 0x7000_0000;
+    regs.rsi = 0x1000;
+    regs.rdx = (libc::PROT_READ | libc::PROT_EXEC) as u64;
+    regs.r10 = (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as u64;
+    let mapped = MappingChange::NewExecutableMapping {
+        addr: 0x7000_0000,
+        size: 0x1000,
+        prot: ProtFlags::PROT_READ | ProtFlags::PROT_EXEC,
+        path: None,
+    };
+    assert_eq!(mapping_changes_at_exit(pid, &regs), vec![mapped.clone()]);
+    // replacing the mappings at `addr`
+    regs.r10 |= libc::MAP_FIXED as u64;
+    let replaced = MappingChange::MappingRemoved {
+        addr: 0x7000_0000,
+        size: 0x1000,
+    };
+    assert_eq!(
+        mapping_changes_at_exit(pid, &regs),
+        vec![replaced.clone(), mapped]
+    );
+    // not executable
+    regs.rdx = libc::PROT_READ as u64;
+    assert_eq!(mapping_changes_at_exit(pid, &regs), vec![replaced]);
+    regs.r10 = (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as u64;
+    assert_eq!(mapping_changes_at_exit(pid, &regs), vec![]);
+
+    // grown and moved
+    assert!(is_mapping_syscall(SYS_mremap));
+    regs.orig_rax = SYS_mremap as u64;
+    regs.rax = 0x7100_0000;
+    regs.rdi = 0x7000_0000;
+    regs.rsi = 0x1000;
+    regs.rdx = 0x3000;
+    regs.r10 = libc::MREMAP_MAYMOVE as u64;
+    assert_eq!(
+        mapping_changes_at_exit(pid, &regs),
+        vec![MappingChange::MappingMoved {
+            from: 0x7000_0000,
+            from_size: 0x1000,
+            addr: 0x7100_0000,
+            size: 0x3000,
+        }]
+    );
+
+    // failed
+    regs.orig_rax = SYS_munmap as u64;
+    regs.rax = -libc::EINVAL as i64 as u64;
+    assert_eq!(mapping_changes_at_exit(pid, &regs), vec![]);
+
+    regs.orig_rax = SYS_mprotect as u64;
+    regs.rdx = (libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC) as u64;
+    assert!(is_wx_request(SYS_mprotect, &regs));
+    regs.rdx = (libc::PROT_READ | libc::PROT_EXEC) as u64;
+    assert!(!is_wx_request(SYS_mprotect, &regs));
+
+    // `pkey_mprotect` takes the same first three arguments
+    assert!(is_mapping_syscall(SYS_pkey_mprotect));
+    regs.orig_rax = SYS_pkey_mprotect as u64;
+    regs.rax = 0;
+    regs.rdi = 0x7000_0000;
+    regs.rdx = (libc::PROT_WRITE | libc::PROT_EXEC) as u64;
+    regs.r10 = 1;
+    assert!(is_wx_request(SYS_pkey_mprotect, &regs));
+    assert_eq!(
+        mapping_changes_at_exit(pid, &regs),
+        vec![MappingChange::PermissionsChanged {
+            addr: 0x7000_0000,
+            size: 0x1000,
+            prot: ProtFlags::PROT_WRITE | ProtFlags::PROT_EXEC,
+        }]
+    );
+}
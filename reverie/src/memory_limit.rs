@@ -0,0 +1,325 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! anonymous memory limits, of `--max-anon-memory`
+//!
+//! cgroups may not be available (unprivileged, or nested containers), so
+//! anonymous memory of each process is tracked from syscalls instead: its
+//! `MAP_ANONYMOUS` mappings, and its heap, above the first break seen. at
+//! entry, `mmap`, `mremap` and `brk` growing a process over the limit fail
+//! as the kernel does when out of memory: `ENOMEM`, or the current break
+//! for `brk`, so that allocators return `NULL`. memory is counted as
+//! mapped, not as resident. forks start with the memory of their parent,
+//! execs with none.
+
+use nix::unistd::Pid;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Error, ErrorKind, Result};
+use std::rc::Rc;
+use syscalls::SyscallNo;
+
+use reverie_api::emulate::*;
+use reverie_api::event::*;
+use reverie_api::remote::SyscallArgs;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// parse bytes, with an optional `K`, `M` or `G` suffix, i.e.: `512M`
+pub fn parse_bytes(s: &str) -> Result<u64> {
+    let (digits, unit) = match s.chars().last() {
+        Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid size: {}, expected bytes, K, M or G", s),
+            )
+        })
+}
+
+fn page_up(size: u64) -> u64 {
+    size.saturating_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// anonymous memory of a process
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AnonMemory {
+    /// anonymous mappings, size by address
+    maps: BTreeMap<u64, u64>,
+    mapped: u64,
+    /// first break seen, and the current one
+    brk: Option<(u64, u64)>,
+}
+
+impl AnonMemory {
+    /// bytes mapped anonymously, heap included
+    pub fn total(&self) -> u64 {
+        let heap = self.brk.map_or(0, |(start, brk)| brk.saturating_sub(start));
+        self.mapped + heap
+    }
+
+    // bytes of anonymous mappings within `size` bytes at `addr`
+    fn overlap(&self, addr: u64, size: u64) -> u64 {
+        let end = addr.saturating_add(size);
+        self.maps
+            .range(..end)
+            .map(|(start, len)| (*start.max(&addr), (start + len).min(end)))
+            .filter(|(start, end)| start < end)
+            .map(|(start, end)| end - start)
+            .sum()
+    }
+
+    fn mapped(&mut self, addr: u64, size: u64) {
+        self.unmapped(addr, size);
+        self.maps.insert(addr, size);
+        self.mapped += size;
+    }
+
+    fn unmapped(&mut self, addr: u64, size: u64) {
+        let end = addr.saturating_add(size);
+        let overlapping: Vec<(u64, u64)> = self
+            .maps
+            .range(..end)
+            .filter(|(start, len)| **start + **len > addr)
+            .map(|(start, len)| (*start, *len))
+            .collect();
+        for (start, len) in overlapping {
+            self.maps.remove(&start);
+            self.mapped -= len;
+            // what is left below and above the unmapped range
+            if start < addr {
+                self.maps.insert(start, addr - start);
+                self.mapped += addr - start;
+            }
+            if start + len > end {
+                self.maps.insert(end, start + len - end);
+                self.mapped += start + len - end;
+            }
+        }
+    }
+
+    // bytes `syscall` grows the memory by, if it succeeds
+    fn growth(&self, syscall: SyscallNo, args: &SyscallArgs) -> u64 {
+        match syscall {
+            SyscallNo::SYS_mmap
+                if args.arg3 as i32 & libc::MAP_ANONYMOUS != 0 =>
+            {
+                let size = page_up(args.arg1);
+                if args.arg3 as i32 & libc::MAP_FIXED != 0 {
+                    size - self.overlap(args.arg0, size)
+                } else {
+                    size
+                }
+            }
+            SyscallNo::SYS_mremap if self.maps.contains_key(&args.arg0) => {
+                page_up(args.arg2).saturating_sub(page_up(args.arg1))
+            }
+            SyscallNo::SYS_brk => match self.brk {
+                Some((_, brk)) => args.arg0.saturating_sub(brk),
+                None => 0,
+            },
+            _ => 0,
+        }
+    }
+
+    // `syscall` returned `retval`
+    fn exited(&mut self, syscall: SyscallNo, args: &SyscallArgs, retval: i64) {
+        if retval < 0 {
+            return;
+        }
+        let retval = retval as u64;
+        match syscall {
+            SyscallNo::SYS_mmap
+                if args.arg3 as i32 & libc::MAP_ANONYMOUS != 0 =>
+            {
+                self.mapped(retval, page_up(args.arg1))
+            }
+            SyscallNo::SYS_munmap => {
+                self.unmapped(args.arg0, page_up(args.arg1))
+            }
+            SyscallNo::SYS_mremap if self.maps.contains_key(&args.arg0) => {
+                self.unmapped(args.arg0, page_up(args.arg1));
+                self.mapped(retval, page_up(args.arg2));
+            }
+            SyscallNo::SYS_brk => {
+                let start = self.brk.map_or(retval, |(start, _)| start);
+                self.brk = Some((start, retval.max(start)));
+            }
+            _ => (),
+        }
+    }
+}
+
+/// anonymous memory of processes, limited, see module doc
+pub struct MemoryLimit {
+    max: u64,
+    processes: HashMap<Pid, AnonMemory>,
+    /// processes of tasks
+    pids: HashMap<Pid, Pid>,
+    /// processes denied memory, reported once
+    denied: HashSet<Pid>,
+}
+
+impl MemoryLimit {
+    /// processes limited to `max` bytes of anonymous memory
+    pub fn new(max: u64) -> Self {
+        MemoryLimit {
+            max,
+            processes: HashMap::new(),
+            pids: HashMap::new(),
+            denied: HashSet::new(),
+        }
+    }
+
+    /// anonymous memory of process `pid`, if tracked
+    pub fn memory(&self, pid: Pid) -> Option<&AnonMemory> {
+        self.processes.get(&pid)
+    }
+
+    /// `syscall` entered by task `tid` of process `pid`: its result if
+    /// denied, see module doc
+    pub fn entered(
+        &mut self,
+        pid: Pid,
+        tid: Pid,
+        syscall: SyscallNo,
+        args: &SyscallArgs,
+    ) -> Option<i64> {
+        self.pids.insert(tid, pid);
+        let memory = self.processes.entry(pid).or_default();
+        let total = memory.total();
+        if total.saturating_add(memory.growth(syscall, args)) <= self.max {
+            return None;
+        }
+        if self.denied.insert(pid) {
+            log::warn!(
+                "[memory] process {} over {} bytes of anonymous memory, \
+                 {:?} denied, with {} bytes",
+                pid,
+                self.max,
+                syscall,
+                total
+            );
+        }
+        match (syscall, memory.brk) {
+            (SyscallNo::SYS_brk, Some((_, brk))) => Some(brk as i64),
+            _ => Some(-(libc::ENOMEM as i64)),
+        }
+    }
+
+    /// `syscall` of process `pid` returned `retval`
+    pub fn exited(
+        &mut self,
+        pid: Pid,
+        syscall: SyscallNo,
+        args: &SyscallArgs,
+        retval: i64,
+    ) {
+        let memory = self.processes.entry(pid).or_default();
+        memory.exited(syscall, args, retval);
+    }
+
+    /// `event` observed by the tracer
+    pub fn record_event(&mut self, event: &TimedEvent) {
+        let tid = event.tid;
+        let pid = self.pids.get(&tid).cloned().unwrap_or(tid);
+        match &event.event {
+            Event::Fork(child) => {
+                if let Some(memory) = self.processes.get(&pid).cloned() {
+                    self.processes.insert(*child, memory);
+                }
+            }
+            Event::Exec => {
+                self.processes.remove(&pid);
+                self.denied.remove(&pid);
+            }
+            Event::Exited(_) => {
+                self.pids.remove(&tid);
+                if pid == tid {
+                    self.processes.remove(&pid);
+                    self.denied.remove(&pid);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// syscall emulation (denying allocations over the limit) and event sink
+/// tracking memory with `limit`
+pub fn memory_limiting(
+    limit: Rc<RefCell<MemoryLimit>>,
+) -> (SyscallEmulation, EventSink) {
+    let syscalls = vec![
+        SyscallNo::SYS_mmap,
+        SyscallNo::SYS_munmap,
+        SyscallNo::SYS_mremap,
+        SyscallNo::SYS_brk,
+    ];
+    let enters = limit.clone();
+    let mut emulation = SyscallEmulation::new(
+        EmulationMode::Seccomp,
+        syscalls,
+        Box::new(move |task, _memory, syscall, args| {
+            let (pid, tid) = (task.getpid(), task.gettid());
+            enters.borrow_mut().entered(pid, tid, syscall, args)
+        }),
+    );
+    let exits = limit.clone();
+    emulation.set_exit_handler(Box::new(
+        move |task, _memory, syscall, args, retval| {
+            let pid = task.getpid();
+            exits.borrow_mut().exited(pid, syscall, args, retval)
+        },
+    ));
+    let sink: EventSink =
+        Box::new(move |event| limit.borrow_mut().record_event(event));
+    (emulation, sink)
+}
+
+#[test]
+fn memory_limit_sanity_check() {
+    assert_eq!(parse_bytes("512M").unwrap(), 512 << 20);
+    assert_eq!(parse_bytes("4096").unwrap(), 4096);
+    assert!(parse_bytes("1T").is_err());
+    let anon = (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as u64;
+    let mmap = |size| SyscallArgs::from(0, size, 3, anon, u64::MAX, 0);
+    let brk = |addr| SyscallArgs::from(addr, 0, 0, 0, 0, 0);
+    let pid = Pid::from_raw(i32::MAX);
+    let mut limit = MemoryLimit::new(0x10000);
+    let mmap_ = SyscallNo::SYS_mmap;
+    assert_eq!(limit.entered(pid, pid, mmap_, &mmap(0x8000)), None);
+    limit.exited(pid, mmap_, &mmap(0x8000), 0x100000);
+    let brk_ = SyscallNo::SYS_brk;
+    assert_eq!(limit.entered(pid, pid, brk_, &brk(0)), None);
+    limit.exited(pid, brk_, &brk(0), 0x200000);
+    assert_eq!(limit.entered(pid, pid, brk_, &brk(0x204000)), None);
+    limit.exited(pid, brk_, &brk(0x204000), 0x204000);
+    assert_eq!(limit.memory(pid).unwrap().total(), 0xc000);
+    let denied = limit.entered(pid, pid, mmap_, &mmap(0x5000));
+    assert_eq!(denied, Some(-(libc::ENOMEM as i64)));
+    let denied = limit.entered(pid, pid, brk_, &brk(0x209000));
+    assert_eq!(denied, Some(0x204000));
+    let munmap = SyscallArgs::from(0x102000, 0x2000, 0, 0, 0, 0);
+    limit.exited(pid, SyscallNo::SYS_munmap, &munmap, 0);
+    assert_eq!(limit.memory(pid).unwrap().total(), 0xa000);
+    assert_eq!(limit.entered(pid, pid, mmap_, &mmap(0x5000)), None);
+}
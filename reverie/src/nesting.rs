@@ -0,0 +1,180 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! reverie under an outer tracer, or seccomp filter
+//!
+//! reverie may itself be traced, i.e.: by `strace`, `gdb` or reverie, and
+//! filtered, i.e.: by a container runtime. outer layers are detected at
+//! startup, then:
+//!
+//! - a tracer not following forks (`strace` without `-f`, `gdb` by
+//!   default) sees reverie only, which traces its tracees as usual.
+//! - a tracer following forks (`strace -f`, reverie) attaches tracees of
+//!   reverie as soon as they are forked. a task has one tracer only: their
+//!   stops are never reported to reverie, which fails with a diagnostic
+//!   rather than waiting for them.
+//! - seccomp filters are stacked with reverie's, the strictest action of
+//!   all filters wins. stops of outer `SECCOMP_RET_TRACE` filters are
+//!   reported to reverie, and traced as unfiltered syscalls.
+//!
+//! an outer reverie is told by its private page, mapped in its tracees.
+
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+
+use nix::unistd::Pid;
+use reverie_common::consts;
+
+/// tracer of a process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OuterTracer {
+    pub pid: i32,
+    /// command name, `?` if unknown
+    pub name: String,
+    /// whether the tracer is reverie
+    pub reverie: bool,
+}
+
+impl fmt::Display for OuterTracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.reverie {
+            write!(f, "reverie (pid {})", self.pid)
+        } else {
+            write!(f, "{} (pid {})", self.name, self.pid)
+        }
+    }
+}
+
+/// field `key` of `status`, of `/proc/<pid>/status`
+pub fn status_field(status: &str, key: &str) -> Option<String> {
+    status.lines().find_map(|line| {
+        let mut kv = line.splitn(2, ':');
+        if kv.next()? == key {
+            Some(kv.next()?.trim().to_string())
+        } else {
+            None
+        }
+    })
+}
+
+// whether the private page of reverie is mapped in `/proc/<proc>`
+fn private_page_mapped(proc: &str) -> bool {
+    let start = format!("{:x}-", consts::REVERIE_PRIVATE_PAGE_OFFSET);
+    fs::read_to_string(format!("/proc/{}/maps", proc))
+        .map(|maps| maps.lines().any(|line| line.starts_with(&start)))
+        .unwrap_or(false)
+}
+
+// tracer of `/proc/<proc>`, if traced
+fn tracer(proc: &str) -> Option<OuterTracer> {
+    let status = fs::read_to_string(format!("/proc/{}/status", proc)).ok()?;
+    let pid: i32 = status_field(&status, "TracerPid")?.parse().ok()?;
+    if pid == 0 {
+        return None;
+    }
+    let name = fs::read_to_string(format!("/proc/{}/comm", pid))
+        .map(|comm| comm.trim().to_string())
+        .unwrap_or_else(|_| String::from("?"));
+    Some(OuterTracer {
+        pid,
+        name,
+        reverie: private_page_mapped(proc),
+    })
+}
+
+/// tracer of reverie, if traced
+pub fn outer_tracer() -> Option<OuterTracer> {
+    tracer("self")
+}
+
+/// seccomp filters of reverie, at least 1 if filtered, as
+/// `Seccomp_filters` needs linux 5.9
+pub fn seccomp_filters() -> u32 {
+    let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+    let filters = status_field(&status, "Seccomp_filters")
+        .and_then(|filters| filters.parse().ok());
+    match status_field(&status, "Seccomp")
+        .as_ref()
+        .map(String::as_str)
+    {
+        Some("2") => filters.unwrap_or(1).max(1),
+        _ => 0,
+    }
+}
+
+/// log outer layers of reverie, at startup
+pub fn report_outer_layers() {
+    if let Some(tracer) = outer_tracer() {
+        log::warn!(
+            "[nesting] traced by {}, tracees fail to start if it follows \
+             forks",
+            tracer
+        );
+    }
+    let filters = seccomp_filters();
+    if filters > 0 {
+        log::info!(
+            "[nesting] {} outer seccomp filters, stacked with reverie's",
+            filters
+        );
+    }
+}
+
+/// check tracee `child`, just forked, is not attached by an outer tracer
+/// following forks
+pub fn check_forked(child: Pid) -> Result<()> {
+    let tracer = match tracer(&child.to_string()) {
+        Some(tracer) => tracer,
+        None => return Ok(()),
+    };
+    let hint = if tracer.reverie {
+        "run the program under the outer reverie only"
+    } else {
+        "trace reverie without following forks, i.e.: strace without -f"
+    };
+    Err(Error::new(
+        ErrorKind::Other,
+        format!(
+            "tracee {} is attached by {}, which follows forks of reverie, \
+             a task has one tracer only: {}",
+            child, tracer, hint
+        ),
+    ))
+}
+
+/// check running process `pid` can be attached, i.e.: is not traced
+pub fn check_attachable(pid: Pid) -> Result<()> {
+    match tracer(&pid.to_string()) {
+        Some(tracer) => Err(Error::new(
+            ErrorKind::Other,
+            format!("{} is already traced by {}", pid, tracer),
+        )),
+        None => Ok(()),
+    }
+}
+
+#[test]
+fn nesting_sanity_check() {
+    let status = "Name:\tcat\nTracerPid:\t42\nSeccomp:\t2\n";
+    assert_eq!(status_field(status, "TracerPid"), Some(String::from("42")));
+    assert_eq!(status_field(status, "Seccomp_filters"), None);
+    let tracer = OuterTracer {
+        pid: 42,
+        name: String::from("strace"),
+        reverie: false,
+    };
+    assert_eq!(tracer.to_string(), "strace (pid 42)");
+    let traced = outer_tracer().is_some();
+    assert_eq!(check_forked(nix::unistd::getpid()).is_err(), traced);
+}
@@ -12,8 +12,49 @@
 
 use nix::{mount, unistd};
 use std::fs::File;
-use std::io::{Result, Write};
+use std::io::{Error, ErrorKind, Result, Write};
 use std::path::PathBuf;
+use std::str::FromStr;
+
+/// `CLONE_NEWTIME`, since linux 5.6, not exported by `libc`
+const CLONE_NEWTIME: libc::c_int = 0x80;
+
+/// namespaces which can be created for tracees
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Namespace {
+    /// time namespace, see `init_time_ns`
+    Time,
+}
+
+impl FromStr for Namespace {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "time" => Ok(Namespace::Time),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown namespace: {}", s),
+            )),
+        }
+    }
+}
+
+/// clock offsets (in seconds) of a time namespace
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimeOffsets {
+    pub monotonic: i64,
+    pub boottime: i64,
+}
+
+impl TimeOffsets {
+    // as written to `/proc/self/timens_offsets`
+    fn to_timens_offsets(&self) -> String {
+        format!(
+            "monotonic {} 0\nboottime {} 0\n",
+            self.monotonic, self.boottime
+        )
+    }
+}
 
 fn proc_setpgroups_write(child_pid: unistd::Pid) -> Result<()> {
     let setgroups = PathBuf::from("/proc")
@@ -62,3 +103,33 @@ pub fn init_ns(
         .expect("mount proc failed");
     Ok(())
 }
+
+/// create a time namespace with clock `offsets`, entered by children
+/// created afterwards, hence must be called before the tracee is forked.
+///
+/// `CLOCK_MONOTONIC` and `CLOCK_BOOTTIME` are offset by the kernel, their
+/// syscalls included: vdso time functions are still patched by
+/// `vdso::vdso_patch`, other clocks (i.e.: `CLOCK_REALTIME`) being
+/// intercepted as without a time namespace.
+pub fn init_time_ns(offsets: &TimeOffsets) -> Result<()> {
+    if unsafe { libc::unshare(CLONE_NEWTIME) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    let mut file = File::create("/proc/self/timens_offsets")?;
+    file.write_all(offsets.to_timens_offsets().as_bytes())?;
+    Ok(())
+}
+
+#[test]
+fn namespace_from_str() {
+    assert_eq!("time".parse::<Namespace>().ok(), Some(Namespace::Time));
+    assert!("net".parse::<Namespace>().is_err());
+    let offsets = TimeOffsets {
+        monotonic: -10,
+        boottime: 3600,
+    };
+    assert_eq!(
+        offsets.to_timens_offsets(),
+        "monotonic -10 0\nboottime 3600 0\n"
+    );
+}
@@ -0,0 +1,453 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! deterministic ordering of output, of `--serialize-output`
+//!
+//! writes of tracees to the stdout or stderr of the tracer (by any fd
+//! referring to them, i.e.: dups) are emulated: they are buffered by
+//! process, rather than written. such fds are tracked by process, from
+//! `open`, `dup` and `close`, and read again after `fork` and `exec`:
+//! `write` and `writev` are trapped only while a process has any, and
+//! patched otherwise. processes are named by logical ids, the
+//! id of their parent and the number of processes it forked before, i.e.:
+//! `0.2.1`. these do not depend on how processes are scheduled.
+//!
+//! the output of a process is written once it exited, each line prefixed
+//! by its id, in order of ids (a process before its children), so that
+//! parallel builds or tests print the same output every run. output of
+//! processes is written as soon as all processes before them exited, the
+//! rest at the end of the run.
+//!
+//! NB: fds received by `recvmsg` or `pidfd_getfd` are not tracked, nor
+//! are fds of `close_range`.
+
+use nix::unistd::Pid;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{Result, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use syscalls::SyscallNo;
+
+use reverie_api::emulate::*;
+use reverie_api::event::*;
+use reverie_api::remote::SyscallArgs;
+
+/// stdout or stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// bytes written by `write` or `writev`, read from `memory`
+pub fn written_bytes(
+    memory: &dyn TaskMemory,
+    syscall: SyscallNo,
+    args: &SyscallArgs,
+) -> Option<Vec<u8>> {
+    match syscall {
+        SyscallNo::SYS_write => {
+            memory.read_bytes(args.arg1, args.arg2 as usize).ok()
+        }
+        SyscallNo::SYS_writev => {
+            let iovs = memory.read_bytes(args.arg1, 16 * args.arg2 as usize);
+            let mut bytes = Vec::new();
+            for iov in iovs.ok()?.chunks(16) {
+                let word = |at: usize| {
+                    let mut word = [0u8; 8];
+                    word.copy_from_slice(&iov[at..at + 8]);
+                    u64::from_ne_bytes(word)
+                };
+                let (base, len) = (word(0), word(8));
+                if len > 0 {
+                    bytes.extend(memory.read_bytes(base, len as usize).ok()?);
+                }
+            }
+            Some(bytes)
+        }
+        _ => None,
+    }
+}
+
+// parent of process `pid`, of its `/proc/<pid>/stat`
+fn parent_of(pid: Pid) -> Option<Pid> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // after the command, which may contain spaces: `state ppid ..`
+    let fields = &stat[stat.rfind(')')? + 1..];
+    let ppid = fields.split_whitespace().nth(1)?.parse().ok()?;
+    Some(Pid::from_raw(ppid))
+}
+
+// `bytes` with each line prefixed by `prefix`, ending with a newline
+fn prefixed(prefix: &str, bytes: &[u8]) -> Vec<u8> {
+    let mut res = Vec::new();
+    let bytes = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+    for line in bytes.split(|&b| b == b'\n') {
+        res.extend_from_slice(prefix.as_bytes());
+        res.extend_from_slice(line);
+        res.push(b'\n');
+    }
+    res
+}
+
+/// what an fd changed to, see `OrderedOutput::fd_changed`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FdLink {
+    Opened(Option<PathBuf>),
+    Duplicated(i32),
+    Closed,
+}
+
+/// output of a process, not written yet
+#[derive(Debug, Default)]
+struct Pending {
+    exited: bool,
+    /// writes, in order, consecutive ones to the same stream joined
+    writes: Vec<(Stream, Vec<u8>)>,
+}
+
+/// output of tracees, written in order, see module doc
+pub struct OrderedOutput {
+    /// logical ids, by pid
+    ids: HashMap<Pid, Vec<usize>>,
+    /// processes forked, by logical id
+    forks: HashMap<Vec<usize>, usize>,
+    roots: usize,
+    pending: BTreeMap<Vec<usize>, Pending>,
+    stdout: Box<dyn Write>,
+    stderr: Box<dyn Write>,
+    /// what the stdout and stderr of the tracer link to
+    links: (Option<PathBuf>, Option<PathBuf>),
+    /// fds referring to the stdout or stderr of the tracer, by pid
+    aliases: HashMap<Pid, HashMap<i32, Stream>>,
+}
+
+impl OrderedOutput {
+    /// output written to `stdout` and `stderr`
+    pub fn new(stdout: Box<dyn Write>, stderr: Box<dyn Write>) -> Self {
+        OrderedOutput {
+            ids: HashMap::new(),
+            forks: HashMap::new(),
+            roots: 0,
+            pending: BTreeMap::new(),
+            stdout,
+            stderr,
+            links: (fd_link("self", 1), fd_link("self", 2)),
+            aliases: HashMap::new(),
+        }
+    }
+
+    // stream fd `fd` linking to `link` refers to, if any
+    fn stream_of(&self, fd: i32, link: Option<&Path>) -> Option<Stream> {
+        let (stdout, stderr) =
+            (self.links.0.as_deref(), self.links.1.as_deref());
+        match link {
+            None => None,
+            Some(_) if link == stderr && (fd == 2 || link != stdout) => {
+                Some(Stream::Stderr)
+            }
+            Some(_) if link == stdout => Some(Stream::Stdout),
+            Some(_) => None,
+        }
+    }
+
+    // fds of process `pid` referring to the stdout or stderr of the tracer,
+    // of its `/proc/<pid>/fd` unless known
+    fn aliases(&mut self, pid: Pid) -> &mut HashMap<i32, Stream> {
+        if !self.aliases.contains_key(&pid) {
+            let mut aliases = HashMap::new();
+            let dir = fs::read_dir(format!("/proc/{}/fd", pid));
+            for entry in dir.into_iter().flatten().flatten() {
+                let fd = match entry.file_name().to_string_lossy().parse() {
+                    Ok(fd) => fd,
+                    Err(_) => continue,
+                };
+                let link = fs::read_link(entry.path()).ok();
+                if let Some(stream) = self.stream_of(fd, link.as_deref()) {
+                    aliases.insert(fd, stream);
+                }
+            }
+            self.aliases.insert(pid, aliases);
+        }
+        self.aliases.get_mut(&pid).unwrap()
+    }
+
+    /// stream fd `fd` of process `pid` refers to, if any
+    pub fn stream(&mut self, pid: Pid, fd: i32) -> Option<Stream> {
+        self.aliases(pid).get(&fd).cloned()
+    }
+
+    /// whether process `pid` has fds referring to the stdout or stderr of
+    /// the tracer, whose writes are to be trapped
+    pub fn has_aliases(&mut self, pid: Pid) -> bool {
+        !self.aliases(pid).is_empty()
+    }
+
+    /// fd `fd` of process `pid` changed to `link`
+    pub fn fd_changed(&mut self, pid: Pid, fd: i32, link: FdLink) {
+        let stream = match link {
+            FdLink::Closed => None,
+            FdLink::Opened(link) => self.stream_of(fd, link.as_deref()),
+            FdLink::Duplicated(from) => self.stream(pid, from),
+        };
+        let aliases = self.aliases(pid);
+        match stream {
+            Some(stream) => aliases.insert(fd, stream),
+            None => aliases.remove(&fd),
+        };
+    }
+
+    // logical id of process `pid`, named after its parent if not yet
+    fn id(&mut self, pid: Pid) -> Vec<usize> {
+        if let Some(id) = self.ids.get(&pid) {
+            return id.clone();
+        }
+        let parent = parent_of(pid).filter(|ppid| self.ids.contains_key(ppid));
+        let id = match parent {
+            Some(parent) => {
+                let mut id = self.ids[&parent].clone();
+                let forks = self.forks.entry(id.clone()).or_default();
+                id.push(*forks);
+                *forks += 1;
+                id
+            }
+            None => {
+                self.roots += 1;
+                vec![self.roots - 1]
+            }
+        };
+        self.ids.insert(pid, id.clone());
+        self.pending.insert(id.clone(), Pending::default());
+        id
+    }
+
+    /// process `child` forked, named in order of forks of its parent
+    pub fn forked(&mut self, child: Pid) {
+        self.id(child);
+    }
+
+    fn emit(&mut self, id: &[usize], stream: Stream, bytes: &[u8]) {
+        let id: Vec<_> = id.iter().map(|n| n.to_string()).collect();
+        let bytes = prefixed(&format!("[{}] ", id.join(".")), bytes);
+        let out = match stream {
+            Stream::Stdout => &mut self.stdout,
+            Stream::Stderr => &mut self.stderr,
+        };
+        let _ = out.write_all(&bytes).and_then(|_| out.flush());
+    }
+
+    /// `bytes` written to `stream` by process `pid`
+    pub fn written(&mut self, pid: Pid, stream: Stream, bytes: &[u8]) {
+        let id = self.id(pid);
+        match self.pending.get_mut(&id) {
+            Some(pending) => match pending.writes.last_mut() {
+                Some((last, written)) if *last == stream => {
+                    written.extend_from_slice(bytes)
+                }
+                _ => pending.writes.push((stream, bytes.to_vec())),
+            },
+            // threads left after the process exited, written as is
+            None => self.emit(&id, stream, bytes),
+        }
+    }
+
+    // write output of the first processes, while exited
+    fn flush(&mut self) {
+        while let Some((id, pending)) = self.pending.iter().next() {
+            if !pending.exited {
+                break;
+            }
+            let id = id.clone();
+            let pending = self.pending.remove(&id).unwrap();
+            for (stream, bytes) in pending.writes {
+                self.emit(&id, stream, &bytes);
+            }
+        }
+    }
+
+    /// process `pid` exited
+    pub fn exited(&mut self, pid: Pid) {
+        self.aliases.remove(&pid);
+        let id = match self.ids.get(&pid) {
+            Some(id) => id,
+            None => return,
+        };
+        if let Some(pending) = self.pending.get_mut(id) {
+            pending.exited = true;
+        }
+        self.flush();
+    }
+
+    /// write all output left, at the end of the run
+    pub fn finish(&mut self) {
+        for pending in self.pending.values_mut() {
+            pending.exited = true;
+        }
+        self.flush();
+    }
+
+    /// `event` observed by the tracer
+    pub fn record_event(&mut self, event: &TimedEvent) {
+        match &event.event {
+            Event::Fork(child) | Event::Spawn(child) => {
+                // fds inherited are read again, as after `exec`
+                self.aliases.remove(child);
+                self.forked(*child)
+            }
+            Event::Exec => {
+                self.aliases.remove(&event.tid);
+            }
+            Event::Exited(_) => self.exited(event.tid),
+            _ => (),
+        }
+    }
+}
+
+// what `/proc/<pid>/fd/<fd>` links to
+fn fd_link(pid: &str, fd: i32) -> Option<PathBuf> {
+    fs::read_link(format!("/proc/{}/fd/{}", pid, fd)).ok()
+}
+
+// syscalls changing the fds referring to the stdout or stderr of the
+// tracer, declined
+const FD_SYSCALLS: &[SyscallNo] = &[
+    SyscallNo::SYS_open,
+    SyscallNo::SYS_openat,
+    SyscallNo::SYS_creat,
+    SyscallNo::SYS_dup,
+    SyscallNo::SYS_dup2,
+    SyscallNo::SYS_dup3,
+    SyscallNo::SYS_fcntl,
+    SyscallNo::SYS_close,
+];
+
+/// syscall emulation (buffering writes to the stdout and stderr of the
+/// tracer) and event sink, ordering with `output`
+pub fn ordered_output(
+    output: Rc<RefCell<OrderedOutput>>,
+) -> (SyscallEmulation, EventSink) {
+    let mut syscalls = vec![SyscallNo::SYS_write, SyscallNo::SYS_writev];
+    syscalls.extend_from_slice(FD_SYSCALLS);
+    let writes = output.clone();
+    let mut emulation = SyscallEmulation::new(
+        EmulationMode::Seccomp,
+        syscalls,
+        Box::new(move |task, memory, syscall, args| {
+            if FD_SYSCALLS.contains(&syscall) {
+                return None;
+            }
+            let pid = task.getpid();
+            let stream = writes.borrow_mut().stream(pid, args.arg0 as i32)?;
+            let bytes = written_bytes(memory, syscall, args)?;
+            writes.borrow_mut().written(pid, stream, &bytes);
+            Some(bytes.len() as i64)
+        }),
+    );
+    let fds = output.clone();
+    emulation.set_exit_handler(Box::new(
+        move |task, _memory, syscall, args, retval| {
+            if retval < 0 {
+                return;
+            }
+            let pid = task.getpid();
+            let (fd, from) = (retval as i32, args.arg0 as i32);
+            let (fd, link) = match syscall {
+                SyscallNo::SYS_open
+                | SyscallNo::SYS_openat
+                | SyscallNo::SYS_creat => {
+                    (fd, FdLink::Opened(fd_link(&pid.to_string(), fd)))
+                }
+                SyscallNo::SYS_dup => (fd, FdLink::Duplicated(from)),
+                SyscallNo::SYS_dup2 | SyscallNo::SYS_dup3 => {
+                    (args.arg1 as i32, FdLink::Duplicated(from))
+                }
+                SyscallNo::SYS_fcntl
+                    if args.arg1 as i32 == libc::F_DUPFD
+                        || args.arg1 as i32 == libc::F_DUPFD_CLOEXEC =>
+                {
+                    (fd, FdLink::Duplicated(from))
+                }
+                SyscallNo::SYS_close => (from, FdLink::Closed),
+                _ => return,
+            };
+            fds.borrow_mut().fd_changed(pid, fd, link);
+        },
+    ));
+    let traps = output.clone();
+    emulation.set_trap_filter(Box::new(move |task, syscall| {
+        match syscall {
+            // may open the stdout or stderr of the tracer again
+            SyscallNo::SYS_open
+            | SyscallNo::SYS_openat
+            | SyscallNo::SYS_creat => true,
+            _ => traps.borrow_mut().has_aliases(task.getpid()),
+        }
+    }));
+    let sink: EventSink =
+        Box::new(move |event| output.borrow_mut().record_event(event));
+    (emulation, sink)
+}
+
+#[test]
+fn ordered_output_sanity_check() {
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+    assert_eq!(prefixed("[0] ", b"a\nb"), b"[0] a\n[0] b\n");
+    assert_eq!(prefixed("[0] ", b"a\n"), b"[0] a\n");
+    let stdout = Rc::new(RefCell::new(Vec::new()));
+    let stderr = Rc::new(RefCell::new(Vec::new()));
+    let mut output = OrderedOutput::new(
+        Box::new(Shared(stdout.clone())),
+        Box::new(Shared(stderr.clone())),
+    );
+    // no such processes: roots, in order of first output
+    let (a, b) = (Pid::from_raw(i32::MAX - 1), Pid::from_raw(i32::MAX));
+    output.written(a, Stream::Stdout, b"a1\n");
+    output.written(b, Stream::Stdout, b"b1\n");
+    output.written(a, Stream::Stderr, b"a2\n");
+    output.exited(b);
+    assert!(stdout.borrow().is_empty());
+    output.written(a, Stream::Stdout, b"a3");
+    output.exited(a);
+    assert_eq!(&stdout.borrow()[..], b"[0] a1\n[0] a3\n[1] b1\n");
+    assert_eq!(&stderr.borrow()[..], b"[0] a2\n");
+    output.written(b, Stream::Stdout, b"b2\n");
+    output.finish();
+    assert!(stdout.borrow().ends_with(b"[1] b2\n"));
+
+    // no fds, until the stderr of the tracer is opened and dup'ed
+    assert!(!output.has_aliases(a));
+    let stderr = output.links.1.clone();
+    output.fd_changed(a, 3, FdLink::Opened(stderr));
+    output.fd_changed(a, 4, FdLink::Duplicated(3));
+    output.fd_changed(a, 5, FdLink::Opened(Some(PathBuf::from("/a"))));
+    if output.links.1.is_some() {
+        assert!(output.stream(a, 3).is_some());
+        assert_eq!(output.stream(a, 4), output.stream(a, 3));
+        output.fd_changed(a, 3, FdLink::Closed);
+        assert!(output.has_aliases(a));
+        output.fd_changed(a, 4, FdLink::Closed);
+    }
+    assert_eq!(output.stream(a, 5), None);
+    assert!(!output.has_aliases(a));
+}
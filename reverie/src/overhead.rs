@@ -0,0 +1,412 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! tracer overhead per event class, and native timeline estimates
+//!
+//! once enabled, the time the tracer spends handling each stop of a task
+//! is measured by the scheduler, classified (`StopClass`) and accumulated
+//! per task: a forked task starts with the overhead of its parent. events
+//! are written to a timeline with the overhead of their task so far (see
+//! `Timeline`), followed by a summary of overhead per class, so that
+//! `NativeEstimate` estimates when they would have happened untraced, by
+//! subtracting it.
+//!
+//! NB: only time handling stops of a task itself is subtracted, not time
+//! stopped waiting for the tracer to handle others, nor the cost of stops
+//! in the kernel: estimates of native time are upper bounds.
+
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{BufRead, Error, ErrorKind, Result, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use reverie_api::event::*;
+use reverie_api::task::TaskState;
+
+/// class of a stop handled by the tracer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum StopClass {
+    /// seccomp stop, or syscall exit
+    Syscall,
+    Exec,
+    /// clone, fork or vfork
+    Fork,
+    Signal,
+    Exit,
+    Other,
+}
+
+impl StopClass {
+    pub fn of(state: &TaskState) -> Self {
+        match state {
+            TaskState::Seccomp(_) | TaskState::Syscall(_) => StopClass::Syscall,
+            TaskState::Exec => StopClass::Exec,
+            TaskState::Clone(_) | TaskState::Fork(_) | TaskState::VforkDone => {
+                StopClass::Fork
+            }
+            TaskState::Stopped(_) | TaskState::Signaled(_) => StopClass::Signal,
+            TaskState::Exited(..) => StopClass::Exit,
+            _ => StopClass::Other,
+        }
+    }
+}
+
+impl fmt::Display for StopClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            StopClass::Syscall => "syscall",
+            StopClass::Exec => "exec",
+            StopClass::Fork => "fork",
+            StopClass::Signal => "signal",
+            StopClass::Exit => "exit",
+            StopClass::Other => "other",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// overhead of a class of stops
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClassOverhead {
+    /// as of `StopClass`
+    pub class: String,
+    pub stops: u64,
+    pub nanos: u64,
+}
+
+/// tracer overhead, per task and per class
+#[derive(Debug, Default)]
+pub struct OverheadMeter {
+    /// cumulative overhead, by tid
+    tasks: HashMap<i32, Duration>,
+    classes: BTreeMap<StopClass, (u64, Duration)>,
+}
+
+impl OverheadMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the tracer handled a stop of `tid` in `state` for `elapsed`
+    pub fn stopped(&mut self, tid: Pid, state: &TaskState, elapsed: Duration) {
+        let class = StopClass::of(state);
+        let (stops, total) = self.classes.entry(class).or_default();
+        *stops += 1;
+        *total += elapsed;
+        let overhead = self.tasks.entry(tid.as_raw()).or_default();
+        *overhead += elapsed;
+        let overhead = *overhead;
+        match state {
+            // the child may have stopped first
+            TaskState::Clone(child) | TaskState::Fork(child) => {
+                *self.tasks.entry(child.as_raw()).or_default() += overhead;
+            }
+            TaskState::Exited(..) => {
+                self.tasks.remove(&tid.as_raw());
+            }
+            _ => (),
+        }
+    }
+
+    /// cumulative overhead of `tid`
+    pub fn overhead_of(&self, tid: Pid) -> Duration {
+        self.tasks.get(&tid.as_raw()).cloned().unwrap_or_default()
+    }
+
+    /// overhead per class, of classes with any stop
+    pub fn classes(&self) -> Vec<ClassOverhead> {
+        self.classes
+            .iter()
+            .map(|(class, (stops, total))| ClassOverhead {
+                class: class.to_string(),
+                stops: *stops,
+                nanos: total.as_nanos() as u64,
+            })
+            .collect()
+    }
+}
+
+lazy_static! {
+    static ref METER: Mutex<Option<OverheadMeter>> = Mutex::new(None);
+}
+
+/// measure overhead of stops from now on
+pub fn enable() {
+    *METER.lock().unwrap_or_else(|e| e.into_inner()) =
+        Some(OverheadMeter::new());
+}
+
+/// the tracer handled a stop of `tid` in `state` for `elapsed`, if enabled
+pub fn stopped(tid: Pid, state: &TaskState, elapsed: Duration) {
+    if let Some(meter) =
+        METER.lock().unwrap_or_else(|e| e.into_inner()).as_mut()
+    {
+        meter.stopped(tid, state, elapsed);
+    }
+}
+
+/// cumulative overhead of `tid` so far
+pub fn overhead_of(tid: Pid) -> Duration {
+    METER
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map(|meter| meter.overhead_of(tid))
+        .unwrap_or_default()
+}
+
+/// overhead per class so far
+pub fn class_overheads() -> Vec<ClassOverhead> {
+    METER
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map(|meter| meter.classes())
+        .unwrap_or_default()
+}
+
+/// an event of a timeline
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimelineEvent {
+    pub tid: i32,
+    /// see `Timestamp::as_nanos`
+    pub at_nanos: u64,
+    /// cumulative overhead of the task when observed
+    pub overhead_nanos: u64,
+    pub event: String,
+}
+
+/// last line of a timeline
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimelineSummary {
+    pub classes: Vec<ClassOverhead>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TimelineLine {
+    Event(TimelineEvent),
+    Summary(TimelineSummary),
+}
+
+/// writes events, with overhead of their tasks, as json lines
+pub struct Timeline {
+    out: Box<dyn Write>,
+}
+
+impl Timeline {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Timeline { out }
+    }
+
+    pub fn record_event(&mut self, event: &TimedEvent) {
+        let line = TimelineEvent {
+            tid: event.tid.as_raw(),
+            at_nanos: event.at.as_nanos(),
+            overhead_nanos: overhead_of(event.tid).as_nanos() as u64,
+            event: format!("{:?}", event.event),
+        };
+        if let Err(err) = write_line(&mut self.out, &line) {
+            log::error!("[overhead] cannot write timeline: {}", err);
+        }
+    }
+
+    /// write the summary, after the last event
+    pub fn finish(&mut self) -> Result<()> {
+        let summary = TimelineSummary {
+            classes: class_overheads(),
+        };
+        write_line(&mut self.out, &summary)?;
+        self.out.flush()
+    }
+}
+
+fn write_line<W: Write, T: Serialize>(out: &mut W, value: &T) -> Result<()> {
+    serde_json::to_writer(&mut *out, value)?;
+    writeln!(out)
+}
+
+/// read a timeline written by `Timeline`, the summary is empty if missing
+pub fn read_timeline<R: BufRead>(
+    timeline: R,
+) -> Result<(Vec<TimelineEvent>, TimelineSummary)> {
+    let mut events = Vec::new();
+    let mut summary = TimelineSummary {
+        classes: Vec::new(),
+    };
+    for line in timeline.lines() {
+        match serde_json::from_str(&line?) {
+            Ok(TimelineLine::Event(event)) => events.push(event),
+            Ok(TimelineLine::Summary(last)) => summary = last,
+            Err(err) => return Err(Error::new(ErrorKind::InvalidData, err)),
+        }
+    }
+    Ok((events, summary))
+}
+
+/// traced and estimated native duration, of a task or the whole run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Durations {
+    pub traced_nanos: u64,
+    pub native_nanos: u64,
+}
+
+impl Durations {
+    // of events, as (traced, native) times
+    fn of<I: Iterator<Item = (u64, u64)>>(times: I) -> Self {
+        let (mut first, mut last) = ((u64::MAX, u64::MAX), (0, 0));
+        for (traced, native) in times {
+            first = (first.0.min(traced), first.1.min(native));
+            last = (last.0.max(traced), last.1.max(native));
+        }
+        Durations {
+            traced_nanos: last.0.saturating_sub(first.0),
+            native_nanos: last.1.saturating_sub(first.1),
+        }
+    }
+}
+
+/// an event of a corrected timeline, times relative to the first event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CorrectedEvent {
+    pub tid: i32,
+    pub traced_nanos: u64,
+    /// estimated time of the event untraced
+    pub native_nanos: u64,
+    pub event: String,
+}
+
+/// native run estimated from a timeline
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NativeEstimate {
+    /// in order of native time
+    pub events: Vec<CorrectedEvent>,
+    pub total: Durations,
+    /// by tid
+    pub tasks: BTreeMap<i32, Durations>,
+    pub classes: Vec<ClassOverhead>,
+}
+
+impl NativeEstimate {
+    pub fn new(events: &[TimelineEvent], summary: TimelineSummary) -> Self {
+        let start = events.iter().map(|e| e.at_nanos).min().unwrap_or(0);
+        let mut corrected: Vec<CorrectedEvent> = events
+            .iter()
+            .map(|e| {
+                let traced = e.at_nanos - start;
+                CorrectedEvent {
+                    tid: e.tid,
+                    traced_nanos: traced,
+                    native_nanos: traced.saturating_sub(e.overhead_nanos),
+                    event: e.event.clone(),
+                }
+            })
+            .collect();
+        corrected.sort_by_key(|e| (e.native_nanos, e.traced_nanos));
+        let times = |tid: Option<i32>| {
+            let events = corrected.iter();
+            Durations::of(
+                events
+                    .filter(|e| tid.map_or(true, |tid| e.tid == tid))
+                    .map(|e| (e.traced_nanos, e.native_nanos)),
+            )
+        };
+        let tasks = corrected
+            .iter()
+            .map(|e| (e.tid, times(Some(e.tid))))
+            .collect();
+        NativeEstimate {
+            total: times(None),
+            tasks,
+            events: corrected,
+            classes: summary.classes,
+        }
+    }
+
+    /// write the corrected timeline, as json lines
+    pub fn write_events<W: Write>(&self, mut out: W) -> Result<()> {
+        for event in &self.events {
+            write_line(&mut out, event)?;
+        }
+        out.flush()
+    }
+}
+
+impl fmt::Display for NativeEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |nanos: u64| nanos as f64 / 1e6;
+        let total = &self.total;
+        writeln!(
+            f,
+            "traced {:.3}ms, native {:.3}ms (estimated)",
+            ms(total.traced_nanos),
+            ms(total.native_nanos)
+        )?;
+        for class in &self.classes {
+            writeln!(
+                f,
+                "  {:8} {:>8} stops {:>12.3}ms",
+                class.class,
+                class.stops,
+                ms(class.nanos)
+            )?;
+        }
+        for (tid, task) in &self.tasks {
+            writeln!(
+                f,
+                "  task {:>8}: traced {:.3}ms, native {:.3}ms",
+                tid,
+                ms(task.traced_nanos),
+                ms(task.native_nanos)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn overhead_sanity_check() {
+    let (parent, child) = (Pid::from_raw(100), Pid::from_raw(101));
+    let ms = Duration::from_millis;
+    let mut meter = OverheadMeter::new();
+    let seccomp = TaskState::Seccomp(syscalls::SyscallNo::SYS_read);
+    meter.stopped(parent, &seccomp, ms(2));
+    meter.stopped(parent, &TaskState::Fork(child), ms(1));
+    meter.stopped(child, &TaskState::Exec, ms(4));
+    assert_eq!(meter.overhead_of(parent), ms(3));
+    assert_eq!(meter.overhead_of(child), ms(7));
+    meter.stopped(child, &TaskState::Exited(child, 0), ms(1));
+    assert_eq!(meter.overhead_of(child), ms(0));
+    let classes = meter.classes();
+    assert_eq!(classes[0].class, "syscall");
+    assert_eq!(classes.len(), 4);
+
+    let lines = [
+        r#"{"tid":100,"at_nanos":1000,"overhead_nanos":0,"event":"Exec"}"#,
+        r#"{"tid":101,"at_nanos":9000,"overhead_nanos":5000,"event":"Exec"}"#,
+        r#"{"tid":100,"at_nanos":7000,"overhead_nanos":3000,"event":"Exited"}"#,
+        r#"{"classes":[{"class":"exec","stops":2,"nanos":4000}]}"#,
+    ];
+    let (events, summary) = read_timeline(lines.join("\n").as_bytes()).unwrap();
+    assert_eq!(events.len(), 3);
+    let estimate = NativeEstimate::new(&events, summary);
+    assert_eq!(estimate.total.traced_nanos, 8000);
+    assert_eq!(estimate.total.native_nanos, 3000);
+    assert_eq!(estimate.tasks[&100].native_nanos, 3000);
+    assert_eq!(estimate.events[1].event, "Exited");
+    assert!(estimate.to_string().starts_with("traced 0.008ms"));
+    assert!(read_timeline(&b"{}"[..]).is_err());
+}
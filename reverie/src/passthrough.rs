@@ -0,0 +1,83 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! pass-through of processes which can't be instrumented
+//!
+//! 32-bit programs (i386, i.e.: wine) and x32 programs make syscalls of
+//! another abi: numbers, arguments and registers are not what the tracer
+//! and tools decode, and the trampoline, the private page and the tool
+//! preloaded are x86_64 only. such processes are passed through, with a
+//! warning, rather than crashing the session: their syscalls are neither
+//! patched, decoded nor reported, but run as is.
+//!
+//! a process is passed through when it execs an unsupported program, or
+//! is stopped running code of an unsupported abi (i.e.: wine switching to
+//! 32-bit code); until its next exec. the preloader's filter allows all
+//! syscalls of foreign abis, see `bpf_allow_foreign_abis`, syscalls still
+//! stopped, by filters inherited, are resumed.
+
+use std::fs::File;
+use std::io::{Read, Result};
+use std::path::Path;
+
+/// code segment of 32-bit user code
+pub const USER32_CS: u64 = 0x23;
+
+/// set in syscall numbers of the x32 abi
+pub const X32_SYSCALL_BIT: u64 = 0x4000_0000;
+
+const EM_X86_64: u16 = 62;
+
+/// unsupported abi of a task stopped with `regs`, if any
+pub fn unsupported_abi(regs: &libc::user_regs_struct) -> Option<&'static str> {
+    if regs.cs == USER32_CS {
+        Some("32-bit code")
+    } else if (regs.orig_rax as i64) >= 0
+        && regs.orig_rax & X32_SYSCALL_BIT != 0
+    {
+        Some("x32 syscalls")
+    } else {
+        None
+    }
+}
+
+/// unsupported abi of elf program `path`, if any
+pub fn unsupported_program(path: &Path) -> Result<Option<&'static str>> {
+    let mut ehdr = [0u8; 20];
+    File::open(path)?.read_exact(&mut ehdr)?;
+    if &ehdr[..4] != b"\x7fELF" {
+        return Ok(None);
+    }
+    let machine = u16::from_le_bytes([ehdr[18], ehdr[19]]);
+    if ehdr[4] != 2 {
+        Ok(Some("32-bit program"))
+    } else if machine != EM_X86_64 {
+        Ok(Some("program of another machine"))
+    } else {
+        Ok(None)
+    }
+}
+
+#[test]
+fn passthrough_sanity_check() {
+    let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+    regs.cs = 0x33;
+    regs.orig_rax = -1i64 as u64;
+    assert_eq!(unsupported_abi(&regs), None);
+    regs.orig_rax = X32_SYSCALL_BIT | 39;
+    assert_eq!(unsupported_abi(&regs), Some("x32 syscalls"));
+    regs.cs = USER32_CS;
+    assert_eq!(unsupported_abi(&regs), Some("32-bit code"));
+    let exe = std::fs::read_link("/proc/self/exe").unwrap();
+    assert_eq!(unsupported_program(&exe).unwrap(), None);
+}
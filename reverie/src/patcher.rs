@@ -13,7 +13,7 @@
 //! `patcher` implements APIs so that tracer can control tracees by ptrace interface
 use libc;
 use log::debug;
-use nix::sys::wait::WaitStatus;
+use nix::sys::wait::{waitpid, WaitStatus};
 use nix::sys::{ptrace, signal};
 use nix::unistd;
 use nix::unistd::Pid;
@@ -23,6 +23,7 @@ use std::io::{Error, ErrorKind, Result};
 use std::path::PathBuf;
 use std::ptr::NonNull;
 
+use reverie_api::arch::{Arch, Host};
 use reverie_api::remote::*;
 use reverie_api::task::{RunTask, Task};
 use reverie_common::consts;
@@ -169,10 +170,20 @@ pub fn remote_do_syscall(
     task.setregs(regs)
 }
 
+lazy_static! {
+    static ref VERIFY_PATCHES: bool =
+        std::env::var(consts::REVERIE_VERIFY_PATCHES).is_ok();
+}
+
 /// patch a given syscall sequence at `rip` with provided `hook`
 /// target is a indirect interim jump, who then jump to the final
 /// trampoline.
 ///
+/// the task is rewound to the syscall site, which is patched unless
+/// `Err` is returned, with the original bytes restored. when
+/// `REVERIE_VERIFY_PATCHES` is set, the task is single-stepped through
+/// the patch, see `verify_patch_traversal`.
+///
 /// NB: this function calls `synchronized_from`
 ///
 pub fn patch_syscall_at(
@@ -180,11 +191,170 @@ pub fn patch_syscall_at(
     syscall: SyscallNo,
     hook: &hooks::SyscallHook,
     target: u64,
-) {
+) -> Result<()> {
+    let regs = task.getregs()?;
+    let ip = regs.rip - SYSCALL_INSN_SIZE as u64;
+    let written = write_syscall_patch(task, syscall, hook, ip, target);
+    let mut new_regs = regs;
+    new_regs.rax = regs.orig_rax; // for our patch, we use rax as syscall no.
+    new_regs.rip = ip; // rewind pc back (-2).
+    task.setregs(new_regs)?;
+    let original_bytes = written?;
+    let mut resume_from = ip;
+    if *VERIFY_PATCHES {
+        let trampoline =
+            task.ldpreload_address.map(|(base, _)| base).unwrap_or(0)
+                + hook.offset;
+        if let Err(err) = verify_patch_traversal(task, ip, target, trampoline) {
+            task.setregs(new_regs)?;
+            rollback_syscall_patch(task, ip, &original_bytes)?;
+            return Err(err);
+        }
+        // continue from the trampoline, where the step stopped.
+        resume_from = task.getregs()?.rip;
+    }
+    // because we modified tracee's code
+    // we need some kind of synchronization to make sure
+    // the CPU (especially i-cache) noticed the change
+    // hence we set a breakponit at ip (original rip - 2)
+    // to force synchronization.
+    synchronize_from(task, resume_from);
+    Ok(())
+}
+
+// write `bytes` at syscall site `ip`: the part after the `syscall`
+// instruction first, then the `syscall` instruction itself, so that the
+// site is never entered half written.
+//
+// split into chunks so that ptrace::write is called
+// explicitly avoid process_vm_writev because the later
+// requires memory map permission change
+// since bytes to write is small, we can save the permission
+// change and restore, which requires two mprotect
+fn write_syscall_site(task: &TracedTask, ip: u64, bytes: &[u8]) -> Result<()> {
+    let (head, tail) = bytes.split_at(SYSCALL_INSN_SIZE);
+    for (k, chunk) in tail.chunks(std::mem::size_of::<u64>()).enumerate() {
+        let rptr = Remoteable::remote(
+            (ip as usize + k * std::mem::size_of::<u64>() + SYSCALL_INSN_SIZE)
+                as *mut u8,
+        )
+        .unwrap();
+        task.poke_bytes(rptr, chunk)?;
+    }
+    let remote_ip = Remoteable::remote(ip as *mut u8).unwrap();
+    task.poke_bytes(remote_ip, head)
+}
+
+/// restore the `original` bytes of the syscall site patched at `ip`
+pub fn rollback_syscall_patch(
+    task: &TracedTask,
+    ip: u64,
+    original: &[u8],
+) -> Result<()> {
+    // restore `syscall` first, so that no thread enters the patch any more.
+    let (head, tail) = original.split_at(SYSCALL_INSN_SIZE);
+    let remote_ip = Remoteable::remote(ip as *mut u8).unwrap();
+    task.poke_bytes(remote_ip, head)?;
+    let remote_tail =
+        Remoteable::remote((ip as usize + SYSCALL_INSN_SIZE) as *mut u8)
+            .unwrap();
+    task.poke_bytes(remote_tail, tail)?;
+    debug!("{} rolled back patch @{:x}", task.gettid(), ip);
+    Ok(())
+}
+
+// faults of the instruction stepped, which fail the step
+const STEP_FAULTS: &[signal::Signal] = &[
+    signal::SIGSEGV,
+    signal::SIGBUS,
+    signal::SIGILL,
+    signal::SIGFPE,
+];
+
+// step one instruction. signals arriving meanwhile are not lost: the
+// first is delivered once the task is resumed, see `signal_to_deliver`,
+// others are sent again.
+fn step_one(task: &mut TracedTask) -> Result<()> {
+    let tid = task.gettid();
+    let mut pending = Vec::new();
+    let stepped = loop {
+        task.step(None)?;
+        match waitpid(Some(tid), None) {
+            Ok(WaitStatus::Stopped(_, signal::SIGTRAP)) => break Ok(()),
+            Ok(WaitStatus::Stopped(_, sig)) if !STEP_FAULTS.contains(&sig) => {
+                if task.signal_to_deliver.is_none() {
+                    task.signal_to_deliver = Some(sig);
+                } else {
+                    pending.push(sig);
+                }
+            }
+            otherwise => {
+                break Err(Error::new(
+                    ErrorKind::Other,
+                    format!("{} single step failed: {:?}", tid, otherwise),
+                ))
+            }
+        }
+    };
+    for sig in pending {
+        let _ = unsafe {
+            libc::syscall(
+                libc::SYS_tgkill,
+                task.getpid().as_raw(),
+                tid.as_raw(),
+                sig as i32,
+            )
+        };
+    }
+    stepped
+}
+
+/// single-step `task`, stopped at the syscall site `ip` just patched,
+/// through the `callq` to `stub` and its jump to `trampoline`, which is
+/// to return right after the `callq`.
+pub fn verify_patch_traversal(
+    task: &mut TracedTask,
+    ip: u64,
+    stub: u64,
+    trampoline: u64,
+) -> Result<()> {
+    let expect = |what: &str, actual: u64, expected: u64| {
+        if actual == expected {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "patch @{:x}: {} {:x}, expected {:x}",
+                    ip, what, actual, expected
+                ),
+            ))
+        }
+    };
+    step_one(task)?;
+    let regs = task.getregs()?;
+    expect("callq landed at", regs.rip, stub)?;
+    let ret = task.peek(Remoteable::remote(regs.rsp as *mut u64).unwrap())?;
+    expect("returns to", ret, ip + 5)?;
+    step_one(task)?;
+    expect("stub jumped to", task.getregs()?.rip, trampoline)
+}
+
+/// write the `callq target` sequence replacing syscall (and `hook`
+/// instructions) at `ip`, without touching the task's registers. returns
+/// the original bytes, or `Err` (with the original bytes restored) if
+/// the patch does not read back as written.
+///
+/// NB: the tail is written before the `syscall` instruction, but threads
+/// already past `ip` could still be executing the tail, see `quiesce`.
+pub fn write_syscall_patch(
+    task: &TracedTask,
+    syscall: SyscallNo,
+    hook: &hooks::SyscallHook,
+    ip: u64,
+    target: u64,
+) -> Result<Vec<u8>> {
     let jmp_insn_size = 5i64;
-    let regs = task.getregs().unwrap();
-    let resume_from = regs.rip - SYSCALL_INSN_SIZE as u64;
-    let ip = resume_from;
     let rela: i64 = target as i64 - ip as i64 - jmp_insn_size;
     assert!(rela >= -(1i64.wrapping_shl(31)) && rela < 1i64.wrapping_shl(31));
 
@@ -271,33 +441,19 @@ pub fn patch_syscall_at(
         patch_bytes.len(),
         hook.instructions.len() + consts::SYSCALL_INSN_SIZE
     );
-    let patch_head: Vec<_> = patch_bytes
-        .iter()
-        .cloned()
-        .take(SYSCALL_INSN_SIZE)
-        .collect();
-    let patch_tail: Vec<_> = patch_bytes
-        .iter()
-        .cloned()
-        .skip(SYSCALL_INSN_SIZE)
-        .collect();
-    let original_bytes =
-        task.peek_bytes(remote_rip, patch_bytes.len()).unwrap();
-    // split into chunks so that ptrace::write is called
-    // explicitly avoid process_vm_writev because the later
-    // requires memory map permission change
-    // since bytes to write is small, we can save the permission
-    // change and restore, which requires two mprotect
-    for (k, chunk) in patch_tail.chunks(std::mem::size_of::<u64>()).enumerate()
-    {
-        let rptr = Remoteable::remote(
-            (ip as usize + k * std::mem::size_of::<u64>() + SYSCALL_INSN_SIZE)
-                as *mut u8,
-        )
-        .unwrap();
-        task.poke_bytes(rptr, chunk).unwrap();
+    let original_bytes = task.peek_bytes(remote_rip, patch_bytes.len())?;
+    write_syscall_site(task, ip, &patch_bytes)?;
+    let written = task.peek_bytes(remote_rip, patch_bytes.len())?;
+    if written != patch_bytes {
+        rollback_syscall_patch(task, ip, &original_bytes)?;
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "patch @{:x} reads back {:02x?}, expected {:02x?}",
+                ip, written, patch_bytes
+            ),
+        ));
     }
-    task.poke_bytes(remote_rip, patch_head.as_slice()).unwrap();
     debug!(
         "{} patched {:?}@{:x} {:02x?} => {:02x?} (callq {:x})",
         task.gettid(),
@@ -307,16 +463,7 @@ pub fn patch_syscall_at(
         patch_bytes,
         target
     );
-    let mut new_regs = regs;
-    new_regs.rax = regs.orig_rax; // for our patch, we use rax as syscall no.
-    new_regs.rip = ip; // rewind pc back (-2).
-    task.setregs(new_regs).unwrap();
-    // because we modified tracee's code
-    // we need some kind of synchronization to make sure
-    // the CPU (especially i-cache) noticed the change
-    // hence we set a breakponit at ip (original rip - 2)
-    // to force synchronization.
-    synchronize_from(task, ip)
+    Ok(original_bytes)
 }
 
 /// search for spare page(s) which can be allocated (mmap) within the
@@ -410,9 +557,60 @@ fn can_find_stub_page() {
     }
 }
 
+#[test]
+fn verify_patch_sanity_check() {
+    // code of the traced child, a copy of the test's
+    #[inline(never)]
+    extern "C" fn site() -> i32 {
+        unsafe { libc::getpid() }
+    }
+    #[inline(never)]
+    extern "C" fn stub() -> i32 {
+        unsafe { libc::getppid() }
+    }
+    let child = match unistd::fork().expect("fork failed") {
+        unistd::ForkResult::Child => unsafe {
+            libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0);
+            libc::raise(libc::SIGSTOP);
+            libc::_exit(0)
+        },
+        unistd::ForkResult::Parent { child } => child,
+    };
+    let status = waitpid(Some(child), None);
+    assert_eq!(status, Ok(WaitStatus::Stopped(child, signal::SIGSTOP)));
+    let mut task: TracedTask = Task::new(child);
+    let code = |f: extern "C" fn() -> i32| f as u64;
+    let (ip, target) = (code(site), code(stub));
+    // syscall; cmp $-4095,%rax
+    let original = vec![0x0f, 0x05, 0x48, 0x3d, 0x01, 0xf0, 0xff, 0xff];
+    let rptr = Remoteable::remote(ip as *mut u8).unwrap();
+    task.poke_bytes(rptr, &original).unwrap();
+    let hook = hooks::SyscallHook {
+        name: String::from("_syscall_hook_trampoline_48_3d_01_f0_ff_ff"),
+        offset: 0,
+        instructions: original[SYSCALL_INSN_SIZE..].to_vec(),
+        is_multi: false,
+        libcs: crate::libc_flavor::ANY_LIBC,
+    };
+    let written = write_syscall_patch(&task, SYS_getpid, &hook, ip, target);
+    assert_eq!(written.as_ref().ok(), Some(&original));
+    assert_eq!(task.peek_bytes(rptr, 1).unwrap(), vec![0xe8]);
+    let mut regs = task.getregs().unwrap();
+    regs.rip = ip;
+    task.setregs(regs).unwrap();
+    // the stub does not jump to the trampoline
+    let err = verify_patch_traversal(&mut task, ip, target, 0).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(task.signal_to_deliver, None);
+    rollback_syscall_patch(&task, ip, &original).unwrap();
+    assert_eq!(task.peek_bytes(rptr, original.len()).unwrap(), original);
+    let _ = signal::kill(child, signal::SIGKILL);
+    let _ = waitpid(Some(child), None);
+}
+
 /// generate syscall instructions at injected page
-/// the page address should be 0x7000_0000
-/// the byte code can be confirmed by running objcopy
+/// the page address should be 0x7000_0000, see `Arch::syscall_sequences`.
+/// on x86_64 the byte code can be confirmed by running objcopy
 /// x86_64-linux-gnu-objcopy -I binary /tmp/1.bin -O elf64-x86-64 -B i386:x86-64 /tmp/1.elf
 /// then objdump -d 1.elf must match the instructions listed below.
 pub fn gen_syscall_sequences_at(
@@ -455,25 +653,30 @@ pub fn gen_syscall_sequences_at(
      * 36:  58                   	pop    %rax
      * 37:  cc                   	int3
      */
-    let syscall_stub: &[u64] = &[
-        0x90c3050f90c3050f,
-        0x9066ccfffffff3e8,
-        0x9066ccffffffefe8,
-        0x000000b852515350,
-        0xc3585b595aa20f00,
-        0x000000b852515350,
-        0xcc585b595aa20f00,
-    ];
+    let mut syscall_stub = Host::syscall_sequences();
+    if cfg!(target_arch = "x86_64") {
+        let cpuid_stub: &[u64] = &[
+            0x000000b852515350,
+            0xc3585b595aa20f00,
+            0x000000b852515350,
+            0xcc585b595aa20f00,
+        ];
+        for s in cpuid_stub {
+            syscall_stub.extend_from_slice(&s.to_le_bytes());
+        }
+    }
     // please note we force each `ptrace::write` to be exactly ptrace_poke (8 bytes a time)
     // instead of using `process_vm_writev`, because this function can be called in
     // PTRACE_EXEC_EVENT, the process seems not fully loaded by ld-linux.so
     // call process_vm_{readv, writev} would 100% fail.
-    for (k, s) in syscall_stub.iter().enumerate() {
+    for (k, s) in syscall_stub.chunks(8).enumerate() {
+        let mut word = [0u8; 8];
+        word[..s.len()].copy_from_slice(s);
         let offset = k * std::mem::size_of::<u64>() + page_address as usize;
         ptrace::write(
             pid,
             offset as ptrace::AddressType,
-            *s as *mut libc::c_void,
+            u64::from_le_bytes(word) as *mut libc::c_void,
         )?;
     }
     Ok(())
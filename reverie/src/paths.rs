@@ -0,0 +1,139 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! paths of syscall arguments, as seen by the tracee
+
+use nix::unistd::Pid;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// symlinks followed by a lookup, at most, as of linux
+const MAX_SYMLINKS: usize = 40;
+
+/// `path` without `.` and `..` components, not resolving symlinks
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut res = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                res.pop();
+            }
+            component => res.push(component),
+        }
+    }
+    res
+}
+
+/// absolute (normalized) path of `path` of process `pid`, relative to
+/// `dirfd` (as of `openat`) or the process's working directory
+pub fn absolute_path(pid: Pid, dirfd: Option<i32>, path: &str) -> PathBuf {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        return normalize(path);
+    }
+    let dir = match dirfd {
+        Some(fd) if fd != libc::AT_FDCWD => format!("/proc/{}/fd/{}", pid, fd),
+        _ => format!("/proc/{}/cwd", pid),
+    };
+    normalize(&std::fs::read_link(dir).unwrap_or_default().join(path))
+}
+
+// pid of process `pid` in its own pid namespace, i.e.: its `/proc/self`
+fn ns_pid(pid: Pid) -> String {
+    fs::read_to_string(format!("/proc/{}/status", pid))
+        .ok()
+        .and_then(|status| {
+            let nspid = status.lines().find(|l| l.starts_with("NSpid:"))?;
+            nspid.split_whitespace().last().map(String::from)
+        })
+        .unwrap_or_else(|| pid.to_string())
+}
+
+/// absolute path of `path` of process `pid` (relative to `dirfd`, as of
+/// `absolute_path`), with symlinks resolved as by the process: from its
+/// root, `/proc/self` being the process. `..` applies to symlinks
+/// resolved, missing components are kept as is. paths under the process's
+/// `/proc/<pid>` are given under `/proc/self`.
+pub fn resolve(pid: Pid, dirfd: Option<i32>, path: &str) -> PathBuf {
+    let path = Path::new(path);
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        let dir = match dirfd {
+            Some(fd) if fd != libc::AT_FDCWD => {
+                format!("/proc/{}/fd/{}", pid, fd)
+            }
+            _ => format!("/proc/{}/cwd", pid),
+        };
+        fs::read_link(dir).unwrap_or_default().join(path)
+    };
+    let root = PathBuf::from(format!("/proc/{}/root", pid));
+    let proc_self = Path::new("/proc").join(ns_pid(pid));
+    let components = |path: &Path| -> Vec<OsString> {
+        let components = path.components().rev();
+        components.map(|c| c.as_os_str().to_os_string()).collect()
+    };
+    let mut pending = components(&path);
+    let mut resolved = PathBuf::from("/");
+    let mut links = 0;
+    while let Some(name) = pending.pop() {
+        if name == "/" {
+            resolved = PathBuf::from("/");
+        } else if name == ".." {
+            resolved.pop();
+        } else if name != "." {
+            let next = if resolved == Path::new("/proc")
+                && (name == "self" || name == "thread-self")
+            {
+                proc_self.clone()
+            } else {
+                resolved.join(&name)
+            };
+            let relative = next.strip_prefix("/").unwrap_or(&next);
+            match fs::read_link(root.join(relative)) {
+                Ok(target) if links < MAX_SYMLINKS => {
+                    links += 1;
+                    pending.extend(components(&target));
+                }
+                _ => resolved = next,
+            }
+        }
+    }
+    match resolved.strip_prefix(&proc_self) {
+        Ok(rest) if rest == Path::new("") => PathBuf::from("/proc/self"),
+        Ok(rest) => Path::new("/proc/self").join(rest),
+        Err(_) => resolved,
+    }
+}
+
+#[test]
+fn paths_sanity_check() {
+    assert_eq!(normalize(Path::new("/a/./b/../c")), PathBuf::from("/a/c"));
+    assert_eq!(normalize(Path::new("/..")), PathBuf::from("/"));
+    let pid = nix::unistd::getpid();
+    let cwd = std::env::current_dir().unwrap();
+    assert_eq!(absolute_path(pid, None, "x/../y"), cwd.join("y"));
+    assert_eq!(absolute_path(pid, Some(3), "/etc"), PathBuf::from("/etc"));
+    assert_eq!(resolve(pid, None, "/proc/self/root/etc"), Path::new("/etc"));
+    assert_eq!(
+        resolve(pid, None, "/proc/self/cwd/.."),
+        cwd.parent().unwrap()
+    );
+    assert_eq!(resolve(pid, None, "x/../y"), cwd.join("y"));
+    let maps = Path::new("/proc/self/maps");
+    assert_eq!(resolve(pid, None, "/proc/self/maps"), maps);
+    let maps = format!("/proc/{}/maps", pid);
+    assert_eq!(resolve(pid, None, &maps), Path::new("/proc/self/maps"));
+}
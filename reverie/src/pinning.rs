@@ -0,0 +1,137 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! timezone and locale pinning
+//!
+//! with `--pin-locale`, timestamps (and messages) are formatted alike on
+//! every machine:
+//!
+//! - `PINNED_ENVS` are set in the environment of every exec, see
+//!   `exec::preload_envp`: glibc resolves `UTC0` and the `C` locale
+//!   without reading any file.
+//! - programs reading timezone files themselves (`/etc/localtime`,
+//!   `/etc/timezone`) read the pinned ones instead, and locale archives
+//!   are not found: their opens are redirected, see
+//!   `VirtualDevices::redirect`.
+//!
+//! symlinks are not: `readlink("/etc/localtime")` still tells the zone of
+//! the host.
+
+use std::fs;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use reverie_api::device::*;
+use reverie_api::emulate::*;
+
+/// environment of tracees when pinned
+pub const PINNED_ENVS: &[&str] = &["TZ=UTC0", "LC_ALL=C", "LANG=C"];
+
+/// locale archives of glibc, not found when pinned
+pub const LOCALE_ARCHIVES: &[&str] = &[
+    "/usr/lib/locale/locale-archive",
+    "/usr/lib64/locale/locale-archive",
+];
+
+static PINNED: AtomicBool = AtomicBool::new(false);
+
+/// pin the environment of execs from now on
+pub fn pin() {
+    PINNED.store(true, Ordering::SeqCst);
+}
+
+/// variables pinned in the environment of execs, if any
+pub fn pinned_envs() -> &'static [&'static str] {
+    if PINNED.load(Ordering::SeqCst) {
+        PINNED_ENVS
+    } else {
+        &[]
+    }
+}
+
+/// whether variable `env` (`KEY=VALUE`) is overridden when pinned
+pub fn is_pinned_env(env: &str) -> bool {
+    env.starts_with("LC_")
+        || env.starts_with("LANGUAGE=")
+        || PINNED_ENVS.iter().any(|pinned| {
+            let key = &pinned[..=pinned.find('=').unwrap_or(0)];
+            env.starts_with(key)
+        })
+}
+
+/// `/etc/localtime` of UTC: a TZif file with a single time type
+pub fn utc_tzif() -> Vec<u8> {
+    let mut bytes = Vec::from(&b"TZif"[..]);
+    // version 1, reserved
+    bytes.resize(20, 0);
+    // isutcnt, isstdcnt, leapcnt, timecnt, typecnt, charcnt
+    for count in &[0u32, 0, 0, 0, 1, 4] {
+        bytes.extend_from_slice(&count.to_be_bytes());
+    }
+    // ttinfo: utoff, isdst, desigidx
+    bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    bytes.extend_from_slice(b"UTC\0");
+    bytes
+}
+
+/// pinned timezone files, in `dir`
+pub struct LocalePinning {
+    dir: PathBuf,
+}
+
+impl LocalePinning {
+    /// pinned files written to `dir`, i.e.: of the system temp dir
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("localtime"), utc_tzif())?;
+        fs::write(dir.join("timezone"), "Etc/UTC\n")?;
+        Ok(LocalePinning { dir })
+    }
+
+    /// directory of the pinned files
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// emulation redirecting opens of timezone files and locale archives
+    pub fn into_emulation(self) -> SyscallEmulation {
+        let mut devices = VirtualDevices::new(Box::new(|_| None));
+        devices.redirect("/etc/localtime", self.dir.join("localtime"));
+        devices.redirect("/etc/timezone", self.dir.join("timezone"));
+        // no such device: `ENOENT`
+        let missing = Path::new(VIRTUAL_DEVICE_DIR).join("locale-archive");
+        for archive in LOCALE_ARCHIVES {
+            devices.redirect(archive, &missing);
+        }
+        devices.into_emulation(EmulationMode::Seccomp)
+    }
+}
+
+#[test]
+fn pinning_sanity_check() {
+    let tzif = utc_tzif();
+    assert_eq!(tzif.len(), 54);
+    assert_eq!(&tzif[..4], b"TZif");
+    assert_eq!(&tzif[tzif.len() - 4..], b"UTC\0");
+    assert!(is_pinned_env("LC_TIME=de_DE.UTF-8"));
+    assert!(is_pinned_env("TZ=Europe/Paris"));
+    assert!(!is_pinned_env("TZDIR=/usr/share/zoneinfo"));
+    assert!(pinned_envs().is_empty());
+    let dir = std::env::temp_dir().join(format!("pin-{}", std::process::id()));
+    let pinning = LocalePinning::new(&dir).unwrap();
+    assert_eq!(fs::read(pinning.dir().join("localtime")).unwrap(), tzif);
+    assert!(pinning.into_emulation().is_emulated(syscalls::SYS_openat));
+    let _ = fs::remove_dir_all(&dir);
+}
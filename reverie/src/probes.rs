@@ -0,0 +1,365 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! probes planted by breakpoints, see `ProbeSpec`
+//!
+//! probes get an `int3` as coverage blocks do, see `coverage`: when their
+//! module is mapped executable, at exec or `mmap`. unlike blocks, probes
+//! trap on every hit: the original byte is restored to single-step the
+//! probed instruction, and the `int3` planted again. threads running
+//! meanwhile may miss the probe. probes of `syscall` instructions are not
+//! supported.
+//!
+//! return probes plant an `int3` at the return address of each call as
+//! well, until all calls returning there returned, as told by the stack
+//! pointer. calls in progress when forking are not reported by the child.
+
+use goblin::elf::program_header::{PF_X, PT_LOAD};
+use goblin::elf::Elf;
+use nix::sys::ptrace;
+use nix::sys::signal::{self, Signal};
+use nix::sys::wait::{self, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use reverie_api::emulate::TaskMemory;
+use reverie_api::event::Event;
+use reverie_api::mapping::MappingChange;
+use reverie_api::probe::ProbeSpec;
+use reverie_api::search::read_regions;
+
+use crate::dying;
+
+const INT3: u8 = 0xcc;
+
+/// a probe with a breakpoint
+#[derive(Debug, Clone, Copy)]
+struct Planted {
+    /// index of the probe in `TaskEventCB::probes`
+    probe: usize,
+    /// original byte
+    saved: u8,
+}
+
+lazy_static! {
+    static ref PLANTED: Mutex<HashMap<Pid, HashMap<u64, Planted>>> =
+        Mutex::new(HashMap::new());
+    static ref OFFSETS: Mutex<HashMap<(PathBuf, String), Option<u64>>> =
+        Mutex::new(HashMap::new());
+}
+
+// file offset of `function` of ELF `path`, if in an executable segment
+fn function_offset(path: &Path, function: &str) -> Option<u64> {
+    let bytes = std::fs::read(path).ok()?;
+    let elf = Elf::parse(&bytes).ok()?;
+    let vaddr = elf
+        .syms
+        .iter()
+        .map(|sym| (sym, &elf.strtab))
+        .chain(elf.dynsyms.iter().map(|sym| (sym, &elf.dynstrtab)))
+        .find(|(sym, strtab)| {
+            sym.st_value != 0 && &strtab[sym.st_name] == function
+        })?
+        .0
+        .st_value;
+    let ph = elf.program_headers.iter().find(|ph| {
+        ph.p_type == PT_LOAD
+            && ph.p_flags & PF_X != 0
+            && vaddr >= ph.p_vaddr
+            && vaddr < ph.p_vaddr + ph.p_filesz
+    })?;
+    Some(vaddr - ph.p_vaddr + ph.p_offset)
+}
+
+// file offset of `probe` in module `path`
+fn probe_offset(probe: &ProbeSpec, path: &Path) -> Option<u64> {
+    let key = (path.to_path_buf(), probe.function.clone());
+    let offset = *OFFSETS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .entry(key)
+        .or_insert_with(|| function_offset(path, &probe.function));
+    if offset.is_none() {
+        log::warn!("[probes] no function {} in {:?}", probe.function, path);
+    }
+    Some(offset? + probe.offset)
+}
+
+// plant `probes` of module `path` in `start..end` of process `pid`,
+// mapping it from file `offset`
+fn plant(
+    pid: Pid,
+    memory: &dyn TaskMemory,
+    probes: &[ProbeSpec],
+    path: &Path,
+    (start, end, offset): (u64, u64, u64),
+) {
+    let mut planted = PLANTED.lock().unwrap_or_else(|e| e.into_inner());
+    let planted = planted.entry(pid).or_default();
+    for (k, probe) in probes.iter().enumerate() {
+        if !probe.is_selected(path) {
+            continue;
+        }
+        let at = match probe_offset(probe, path) {
+            Some(at) if at >= offset && at - offset < end - start => {
+                start + at - offset
+            }
+            _ => continue,
+        };
+        // i.e.: a breakpoint of the tracer already
+        let saved = match memory.read_bytes(at, 1) {
+            Ok(bytes) if bytes[0] != INT3 => bytes[0],
+            _ => continue,
+        };
+        if memory.write_bytes(at, &[INT3]).is_ok() {
+            planted.insert(at, Planted { probe: k, saved });
+            log::debug!("[pid {}] probe {} at {:#x}", pid, probe, at);
+        }
+    }
+}
+
+/// plant `probes` in process `pid`, which just exec'ed
+pub fn exec_planted(pid: Pid, memory: &dyn TaskMemory, probes: &[ProbeSpec]) {
+    PLANTED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&pid);
+    RETURNS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&pid);
+    for region in read_regions(pid).unwrap_or_default() {
+        let path = Path::new(&region.path);
+        if region.perms.contains('x') {
+            let range = (region.start, region.end, region.offset);
+            plant(pid, memory, probes, path, range);
+        }
+    }
+}
+
+/// plant `probes` in the mapping of `change`, with `regs` of the `mmap`
+/// exit stop
+pub fn mapped(
+    pid: Pid,
+    memory: &dyn TaskMemory,
+    probes: &[ProbeSpec],
+    change: &MappingChange,
+    regs: &libc::user_regs_struct,
+) {
+    if let MappingChange::NewExecutableMapping {
+        addr,
+        size,
+        path: Some(path),
+        ..
+    } = change
+    {
+        let range = (*addr, addr + size, regs.r9);
+        plant(pid, memory, probes, path, range);
+    }
+}
+
+/// a call of a return probe, until it returns
+#[derive(Debug, Clone)]
+struct Call {
+    tid: Pid,
+    /// stack pointer once returned
+    sp: u64,
+    probe: usize,
+    /// values of the arguments at entry
+    args: Vec<Option<u64>>,
+}
+
+/// a return address with a breakpoint, while calls are to return to it
+#[derive(Debug, Clone)]
+struct ReturnSite {
+    /// original byte
+    saved: u8,
+    calls: Vec<Call>,
+}
+
+lazy_static! {
+    static ref RETURNS: Mutex<HashMap<Pid, HashMap<u64, ReturnSite>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// a probe hit, see `hit`
+#[derive(Debug)]
+pub struct Probed {
+    /// `Event::Probe` of the hit, `None` for the entry of a return probe
+    pub event: Option<Event>,
+    /// signal received while stepping, to deliver
+    pub signal: Option<Signal>,
+}
+
+/// whether the `int3` at `at` task `tid` of process `pid` trapped by is of
+/// one of `probes`, or of the return of a return probe: its arguments are
+/// captured, and the task is stepped over the probed instruction, with the
+/// `int3` planted again while needed.
+pub fn hit(
+    tid: Pid,
+    pid: Pid,
+    memory: &dyn TaskMemory,
+    probes: &[ProbeSpec],
+    at: u64,
+) -> Result<Option<Probed>> {
+    let all_planted = PLANTED.lock().unwrap_or_else(|e| e.into_inner());
+    let mut returns = RETURNS.lock().unwrap_or_else(|e| e.into_inner());
+    let planted = all_planted.get(&pid);
+    let probe = planted.and_then(|planted| planted.get(&at)).cloned();
+    let sites = returns.entry(pid).or_default();
+    if probe.is_none() && !sites.contains_key(&at) {
+        return Ok(None);
+    }
+    match memory.read_bytes(at, 1) {
+        Ok(bytes) if bytes[0] == INT3 => (),
+        _ => return Ok(None),
+    }
+    let from_nix_error = |err| Error::new(ErrorKind::Other, err);
+    let mut regs = ptrace::getregs(tid).map_err(from_nix_error)?;
+    regs.rip = at;
+    ptrace::setregs(tid, regs).map_err(from_nix_error)?;
+
+    let mut event = None;
+    let mut saved = probe.map(|probe| probe.saved);
+    if let Some(site) = sites.get_mut(&at) {
+        saved = Some(site.saved);
+        // calls of `tid` deeper than returned, left by a `longjmp`, dropped
+        site.calls
+            .retain(|call| call.tid != tid || call.sp >= regs.rsp);
+        let returned = site
+            .calls
+            .iter()
+            .position(|call| call.tid == tid && call.sp == regs.rsp);
+        if let Some(k) = returned {
+            let call = site.calls.remove(k);
+            let mut values = call.args;
+            values.push(Some(regs.rax));
+            event = probes
+                .get(call.probe)
+                .map(|probe| Event::Probe(probe.to_string(), values));
+        }
+    }
+    if let Some(planted_probe) = probe {
+        if let Some(probe) = probes.get(planted_probe.probe) {
+            let args = probe.capture(&regs, memory);
+            if !probe.ret {
+                event = Some(Event::Probe(probe.to_string(), args));
+            } else if let Ok(bytes) = memory.read_bytes(regs.rsp, 8) {
+                let mut word = [0u8; 8];
+                word.copy_from_slice(&bytes);
+                let call = Call {
+                    tid,
+                    sp: regs.rsp + 8,
+                    probe: planted_probe.probe,
+                    args,
+                };
+                let ret = u64::from_le_bytes(word);
+                if let Some(site) = sites.get_mut(&ret) {
+                    site.calls.push(call);
+                } else {
+                    // i.e.: a breakpoint of the tracer, but a probe's
+                    let saved = match planted.and_then(|p| p.get(&ret)) {
+                        Some(planted) => Ok(planted.saved),
+                        None => memory.read_bytes(ret, 1).map(|b| b[0]),
+                    };
+                    if let Ok(saved) = saved {
+                        if saved != INT3
+                            && memory.write_bytes(ret, &[INT3]).is_ok()
+                        {
+                            let calls = vec![call];
+                            sites.insert(ret, ReturnSite { saved, calls });
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(saved) = saved {
+        memory.write_bytes(at, &[saved])?;
+    }
+    let mut received = None;
+    loop {
+        ptrace::step(tid, None).map_err(from_nix_error)?;
+        let status = wait::waitpid(Some(tid), Some(WaitPidFlag::__WALL));
+        match status {
+            Ok(WaitStatus::Stopped(_, signal::SIGTRAP)) => break,
+            // stepped again, the signal is delivered once resumed
+            Ok(WaitStatus::Stopped(_, signal)) => {
+                received = received.or(Some(signal));
+            }
+            otherwise => return Err(dying::unexpected_status(tid, otherwise)),
+        }
+    }
+    let returning = sites.get(&at).map_or(false, |site| !site.calls.is_empty());
+    if probe.is_some() || returning {
+        memory.write_bytes(at, &[INT3])?;
+    } else {
+        sites.remove(&at);
+    }
+    Ok(Some(Probed {
+        event,
+        signal: received,
+    }))
+}
+
+/// `child` forked by `parent`, inheriting its probes, and the breakpoints
+/// of its return sites, for calls of the parent only
+pub fn forked(parent: Pid, child: Pid) {
+    let mut planted = PLANTED.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(probes) = planted.get(&parent).cloned() {
+        planted.insert(child, probes);
+    }
+    let mut returns = RETURNS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(sites) = returns.get(&parent) {
+        let sites = sites
+            .iter()
+            .map(|(at, site)| {
+                let saved = site.saved;
+                (
+                    *at,
+                    ReturnSite {
+                        saved,
+                        calls: Vec::new(),
+                    },
+                )
+            })
+            .collect();
+        returns.insert(child, sites);
+    }
+}
+
+/// process `pid` exited
+pub fn exited(pid: Pid) {
+    PLANTED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&pid);
+    RETURNS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&pid);
+}
+
+#[test]
+fn function_offset_sanity_check() {
+    let exe = std::fs::read_link("/proc/self/exe").unwrap();
+    let offset = function_offset(&exe, "main").unwrap();
+    assert!(offset < std::fs::metadata(&exe).unwrap().len());
+    assert_eq!(function_offset(&exe, "no_such_function_at_all"), None);
+    let probe: ProbeSpec = "x:main+4".parse().unwrap();
+    assert_eq!(probe_offset(&probe, &exe), Some(offset + 4));
+}
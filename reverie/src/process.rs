@@ -0,0 +1,194 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! per-process tracer state
+//!
+//! threads of a process share the same address space, hence the tracer's
+//! state about it: memory map, patched syscalls, stub pages, breakpoints.
+//! a `Process` is owned by the scheduler, and each `TracedTask` holds a
+//! handle (`ProcessRef`) to the process it belongs to. tasks created by
+//! `clone` with `CLONE_VM` (threads, `vfork`) share the handle, `fork`
+//! copies the state into a new `Process`, and `exec` (from any thread)
+//! resets it in place.
+
+use nix::unistd::Pid;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::c_void;
+use std::io::Result;
+use std::rc::Rc;
+
+use reverie_api::remote::*;
+use reverie_api::search::Region;
+use reverie_api::task::RunTask;
+
+use crate::libc_flavor::LibcFlavor;
+use crate::patcher::SyscallStubPage;
+use crate::remote_rwlock::RemoteRWLock;
+use crate::runtimes::ManagedRuntime;
+use crate::traced_task::TracedTask;
+
+/// breakpoint handler, called when the breakpoint is hit
+pub type FnBreakpoint = Box<
+    dyn FnOnce(TracedTask, Remoteable<c_void>) -> Result<RunTask<TracedTask>>
+        + 'static,
+>;
+
+/// handle to a `Process`, shared by all its threads
+pub type ProcessRef = Rc<RefCell<Process>>;
+
+/// tracer state shared by threads of a process
+pub struct Process {
+    pid: Pid,
+    pub memory_map: Vec<procfs::process::MemoryMap>,
+    pub stub_pages: Vec<SyscallStubPage>,
+    pub unpatchable_syscalls: HashSet<u64>,
+    pub patched_syscalls: HashSet<u64>,
+    pub syscall_patch_lockset: RemoteRWLock,
+    /// breakpoint address => (saved instruction, handler)
+    pub breakpoints: HashMap<u64, (u64, FnBreakpoint)>,
+    /// libc of the program, detected on exec
+    pub libc: LibcFlavor,
+    /// managed runtime, detected by libraries mapped
+    pub runtime: Option<ManagedRuntime>,
+    /// unsupported abi the process is passed through for, see
+    /// `passthrough`, or `detach::DETACHED`
+    pub passthrough: Option<&'static str>,
+    /// attached while running, its private page is still to be mapped,
+    /// at the first syscall entry, see `traced_task::inject_private_page`
+    pub lazy_private_page: bool,
+    /// rpc areas of tasks gone, which shared the address space, for the
+    /// next tasks to reuse, see `traced_task::init_rpc_stack_data`
+    pub rpc_areas: Vec<u64>,
+    /// regions of `/proc/<pid>/maps`, read when stacks are captured, until
+    /// mappings change, see `unwind::changes_regions`
+    pub regions: Option<Rc<Vec<Region>>>,
+}
+
+impl std::fmt::Debug for Process {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Process {{ pid: {}, patched: {}, unpatchable: {}, \
+             stub pages: {}, breakpoints: {}, libc: {:?} }}",
+            self.pid,
+            self.patched_syscalls.len(),
+            self.unpatchable_syscalls.len(),
+            self.stub_pages.len(),
+            self.breakpoints.len(),
+            self.libc
+        )
+    }
+}
+
+impl Process {
+    /// new process `pid`, with empty state
+    pub fn new(pid: Pid) -> ProcessRef {
+        Rc::new(RefCell::new(Process {
+            pid,
+            memory_map: Vec::new(),
+            stub_pages: Vec::new(),
+            unpatchable_syscalls: HashSet::new(),
+            patched_syscalls: HashSet::new(),
+            syscall_patch_lockset: RemoteRWLock::new(),
+            breakpoints: HashMap::new(),
+            libc: LibcFlavor::default(),
+            runtime: None,
+            passthrough: None,
+            lazy_private_page: false,
+            rpc_areas: Vec::new(),
+            regions: None,
+        }))
+    }
+
+    /// process id
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// state of process `child` forked from `self`: the address space is
+    /// copied, but not breakpoints, which belong to the parent.
+    pub fn forked(&self, child: Pid) -> ProcessRef {
+        Rc::new(RefCell::new(Process {
+            pid: child,
+            memory_map: self.memory_map.clone(),
+            stub_pages: self.stub_pages.clone(),
+            unpatchable_syscalls: self.unpatchable_syscalls.clone(),
+            patched_syscalls: self.patched_syscalls.clone(),
+            syscall_patch_lockset: RemoteRWLock::new(),
+            breakpoints: HashMap::new(),
+            libc: self.libc,
+            runtime: self.runtime,
+            passthrough: self.passthrough,
+            lazy_private_page: self.lazy_private_page,
+            rpc_areas: self.rpc_areas.clone(),
+            regions: self.regions.clone(),
+        }))
+    }
+
+    /// the process exec'ed, its address space is gone
+    pub fn exec_reset(&mut self) {
+        self.memory_map = Vec::new();
+        self.stub_pages = Vec::new();
+        self.unpatchable_syscalls = HashSet::new();
+        self.patched_syscalls = HashSet::new();
+        self.syscall_patch_lockset = RemoteRWLock::new();
+        self.breakpoints = HashMap::new();
+        self.libc = LibcFlavor::default();
+        self.runtime = None;
+        self.passthrough = None;
+        self.lazy_private_page = false;
+        self.rpc_areas = Vec::new();
+        self.regions = None;
+    }
+}
+
+#[test]
+fn process_sanity_check() {
+    use nix::sched::CloneFlags;
+    use reverie_api::task::Task;
+
+    let leader: TracedTask = Task::new(nix::unistd::getpid());
+    {
+        let mut process = leader.process.borrow_mut();
+        process.patched_syscalls.insert(0x1000);
+        let bkpt: FnBreakpoint =
+            Box::new(|task, _| Ok(RunTask::Runnable(task)));
+        process.breakpoints.insert(0x2000, (0x90, bkpt));
+    }
+    // threads and vfork children share the process
+    let thread = leader.cloned(Pid::from_raw(2));
+    let vforked = leader.spawned(
+        Pid::from_raw(3),
+        CloneFlags::CLONE_VM | CloneFlags::CLONE_VFORK,
+    );
+    assert!(Rc::ptr_eq(&leader.process, &thread.process));
+    assert!(Rc::ptr_eq(&leader.process, &vforked.process));
+    thread.process.borrow_mut().patched_syscalls.insert(0x3000);
+    assert!(leader.is_patched_syscall(0x3000));
+
+    // forks copy the address space state, but breakpoints
+    let forked = leader.forked(Pid::from_raw(4));
+    assert!(!Rc::ptr_eq(&leader.process, &forked.process));
+    assert_eq!(forked.process.borrow().pid(), Pid::from_raw(4));
+    assert!(forked.is_patched_syscall(0x1000));
+    assert!(forked.process.borrow().breakpoints.is_empty());
+    forked.process.borrow_mut().patched_syscalls.insert(0x4000);
+    assert!(!leader.is_patched_syscall(0x4000));
+
+    // exec from any thread resets the process of all of them
+    thread.process.borrow_mut().exec_reset();
+    assert!(!leader.is_patched_syscall(0x1000));
+    assert!(leader.process.borrow().breakpoints.is_empty());
+    assert!(forked.is_patched_syscall(0x1000));
+}
@@ -0,0 +1,304 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! fd provenance tracking
+//!
+//! when enabled (see `TaskEventCB::fd_provenance`), syscalls creating,
+//! closing or passing fds are never patched, so that they always stop at
+//! syscall exit, where `FdChange`s are decoded. fds sent by `sendmsg` are
+//! decoded at seccomp stop instead, before they can be received.
+//!
+//! the tracer keeps an `FdProvenance` graph of all traced processes, see
+//! `fd_provenance`.
+
+use nix::unistd::Pid;
+use std::os::linux::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use syscalls::*;
+
+use reverie_api::emulate::TaskMemory;
+use reverie_api::guest::*;
+use reverie_api::lies::syscall_name;
+use reverie_api::provenance::*;
+
+/// `pidfd_open`, not known by `syscalls`
+pub const SYS_PIDFD_OPEN: u64 = 434;
+
+/// `pidfd_getfd`, not known by `syscalls`
+pub const SYS_PIDFD_GETFD: u64 = 438;
+
+// syscalls returning a new fd
+const OPENING_SYSCALLS: &[SyscallNo] = &[
+    SYS_open,
+    SYS_openat,
+    SYS_creat,
+    SYS_socket,
+    SYS_accept,
+    SYS_accept4,
+    SYS_memfd_create,
+    SYS_eventfd,
+    SYS_eventfd2,
+    SYS_epoll_create,
+    SYS_epoll_create1,
+    SYS_timerfd_create,
+    SYS_signalfd,
+    SYS_signalfd4,
+    SYS_inotify_init,
+    SYS_inotify_init1,
+    SYS_fanotify_init,
+    SYS_userfaultfd,
+    SYS_perf_event_open,
+    SYS_open_by_handle_at,
+];
+
+const OTHER_FD_SYSCALLS: &[SyscallNo] = &[
+    SYS_pipe,
+    SYS_pipe2,
+    SYS_socketpair,
+    SYS_dup,
+    SYS_dup2,
+    SYS_dup3,
+    SYS_fcntl,
+    SYS_close,
+    SYS_sendmsg,
+    SYS_recvmsg,
+];
+
+/// a change of the fds of a process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FdChange {
+    /// fd created by syscall, with its path
+    Opened(i32, String, Option<PathBuf>),
+    Duplicated {
+        from: i32,
+        to: i32,
+    },
+    Closed(i32),
+    /// fds sent by `sendmsg`
+    Sent(Vec<i32>),
+    /// fds received by `recvmsg`
+    Received(Vec<i32>),
+    /// fd taken by `pidfd_getfd`
+    Taken {
+        fd: i32,
+        from: Pid,
+        from_fd: i32,
+    },
+}
+
+/// syscall `nr` (which may be unknown to `syscalls`) changes fds
+pub fn is_fd_syscall(nr: u64) -> bool {
+    nr == SYS_PIDFD_OPEN
+        || nr == SYS_PIDFD_GETFD
+        || OPENING_SYSCALLS
+            .iter()
+            .chain(OTHER_FD_SYSCALLS)
+            .any(|syscall| *syscall as u64 == nr)
+}
+
+fn fd_path(pid: Pid, fd: i32) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/{}/fd/{}", pid, fd)).ok()
+}
+
+fn fd_object(pid: Pid, fd: i32) -> Option<FileObject> {
+    let meta = std::fs::metadata(format!("/proc/{}/fd/{}", pid, fd)).ok()?;
+    Some((meta.st_dev(), meta.st_ino()))
+}
+
+/// process of a pidfd, from its `/proc/<pid>/fdinfo`
+pub fn fdinfo_pid(fdinfo: &str) -> Option<Pid> {
+    let line = fdinfo.lines().find(|line| line.starts_with("Pid:"))?;
+    let pid = line["Pid:".len()..].trim().parse().ok()?;
+    Some(Pid::from_raw(pid))
+}
+
+// fds of `SCM_RIGHTS` of `struct msghdr` at `addr`
+fn rights(memory: &dyn TaskMemory, addr: u64) -> Vec<i32> {
+    read_msghdr(memory, addr)
+        .map(|msg| {
+            msg.control
+                .iter()
+                .filter_map(ControlMessage::rights)
+                .flatten()
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// decode fds changed at seccomp stop of syscall `orig_rax` of `regs`
+pub fn fd_changes_at_entry(
+    memory: &dyn TaskMemory,
+    regs: &libc::user_regs_struct,
+) -> Vec<FdChange> {
+    if regs.orig_rax != SYS_sendmsg as u64 {
+        return Vec::new();
+    }
+    let fds = rights(memory, regs.rsi);
+    if fds.is_empty() {
+        Vec::new()
+    } else {
+        vec![FdChange::Sent(fds)]
+    }
+}
+
+/// decode fds changed by a (successful) syscall of process `pid`, `regs`
+/// are from the syscall exit stop.
+pub fn fd_changes_at_exit(
+    pid: Pid,
+    memory: &dyn TaskMemory,
+    regs: &libc::user_regs_struct,
+) -> Vec<FdChange> {
+    let retval = regs.rax as i64;
+    if (retval < 0 && retval > -4096) || !is_fd_syscall(regs.orig_rax) {
+        return Vec::new();
+    }
+    let fd = retval as i32;
+    let opened = |fd, name: &str| {
+        FdChange::Opened(fd, name.to_string(), fd_path(pid, fd))
+    };
+    if regs.orig_rax == SYS_PIDFD_OPEN {
+        return vec![opened(fd, "pidfd_open")];
+    }
+    if regs.orig_rax == SYS_PIDFD_GETFD {
+        let fdinfo = format!("/proc/{}/fdinfo/{}", pid, regs.rdi as i32);
+        let from = std::fs::read_to_string(fdinfo)
+            .ok()
+            .and_then(|fdinfo| fdinfo_pid(&fdinfo));
+        return from
+            .map(|from| FdChange::Taken {
+                fd,
+                from,
+                from_fd: regs.rsi as i32,
+            })
+            .into_iter()
+            .collect();
+    }
+    let syscall = SyscallNo::from(regs.orig_rax as i32);
+    let (from, to) = (regs.rdi as i32, regs.rsi as i32);
+    match syscall {
+        _ if OPENING_SYSCALLS.contains(&syscall) => {
+            vec![opened(fd, &syscall_name(syscall as i32))]
+        }
+        SYS_pipe | SYS_pipe2 | SYS_socketpair => {
+            let fds = if syscall == SYS_socketpair {
+                regs.r10
+            } else {
+                regs.rdi
+            };
+            i32::read_array(memory, fds, 2)
+                .map(|fds| {
+                    let name = syscall_name(syscall as i32);
+                    fds.into_iter().map(|fd| opened(fd, &name)).collect()
+                })
+                .unwrap_or_default()
+        }
+        SYS_dup => vec![FdChange::Duplicated { from, to: fd }],
+        SYS_dup2 | SYS_dup3 if from != to => {
+            vec![FdChange::Duplicated { from, to }]
+        }
+        SYS_fcntl if to == libc::F_DUPFD || to == libc::F_DUPFD_CLOEXEC => {
+            vec![FdChange::Duplicated { from, to: fd }]
+        }
+        SYS_close => vec![FdChange::Closed(from)],
+        SYS_recvmsg => {
+            let fds = rights(memory, regs.rsi);
+            if fds.is_empty() {
+                Vec::new()
+            } else {
+                vec![FdChange::Received(fds)]
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+lazy_static! {
+    static ref FD_PROVENANCE: Mutex<FdProvenance> =
+        Mutex::new(FdProvenance::new());
+}
+
+/// fd provenance graph, across the traced tree
+pub fn fd_provenance() -> &'static Mutex<FdProvenance> {
+    &FD_PROVENANCE
+}
+
+/// track `changes` of the fds of process `pid`, in the fd table of process
+/// `files`, see `Task::files`
+pub fn track_fd_changes(pid: Pid, files: Pid, changes: &[FdChange]) {
+    let mut graph = FD_PROVENANCE.lock().unwrap_or_else(|e| e.into_inner());
+    for change in changes {
+        match change {
+            FdChange::Opened(fd, syscall, path) => {
+                graph.opened(files, *fd, syscall, path.clone())
+            }
+            FdChange::Duplicated { from, to } => {
+                graph.duplicated(files, *from, *to)
+            }
+            FdChange::Closed(fd) => graph.closed(files, *fd),
+            FdChange::Sent(fds) => {
+                for fd in fds {
+                    if let Some(object) = fd_object(pid, *fd) {
+                        graph.sent(files, *fd, object);
+                    }
+                }
+            }
+            FdChange::Received(fds) => {
+                for fd in fds {
+                    if let Some(object) = fd_object(pid, *fd) {
+                        graph.received(files, *fd, object);
+                    }
+                }
+            }
+            FdChange::Taken { fd, from, from_fd } => {
+                graph.taken(files, *fd, *from, *from_fd)
+            }
+        }
+    }
+}
+
+#[test]
+fn fd_changes_sanity_check() {
+    struct NoMemory;
+    impl TaskMemory for NoMemory {
+        fn read_bytes(&self, _: u64, _: usize) -> std::io::Result<Vec<u8>> {
+            Err(std::io::Error::from_raw_os_error(libc::EFAULT))
+        }
+        fn write_bytes(&self, _: u64, _: &[u8]) -> std::io::Result<()> {
+            Err(std::io::Error::from_raw_os_error(libc::EFAULT))
+        }
+    }
+    let fdinfo = "pos:\t0\nflags:\t02000002\nmnt_id:\t15\nPid:\t42\n";
+    assert_eq!(fdinfo_pid(fdinfo), Some(Pid::from_raw(42)));
+    assert!(is_fd_syscall(SYS_PIDFD_GETFD));
+    assert!(!is_fd_syscall(SYS_read as u64));
+
+    let pid = nix::unistd::getpid();
+    let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+    regs.orig_rax = SYS_dup2 as u64;
+    regs.rdi = 0;
+    regs.rsi = 9;
+    regs.rax = 9;
+    let changes = fd_changes_at_exit(pid, &NoMemory, &regs);
+    assert_eq!(changes, vec![FdChange::Duplicated { from: 0, to: 9 }]);
+    regs.orig_rax = SYS_openat as u64;
+    regs.rax = 0;
+    match &fd_changes_at_exit(pid, &NoMemory, &regs)[..] {
+        [FdChange::Opened(0, syscall, _)] => assert_eq!(syscall, "openat"),
+        otherwise => panic!("unexpected changes {:?}", otherwise),
+    }
+    regs.rax = -libc::EBADF as i64 as u64;
+    assert!(fd_changes_at_exit(pid, &NoMemory, &regs).is_empty());
+    regs.orig_rax = SYS_sendmsg as u64;
+    assert!(fd_changes_at_entry(&NoMemory, &regs).is_empty());
+}
@@ -0,0 +1,150 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! two-phase patching of multi-instruction hook windows
+//!
+//! when the hook spans several instructions, another thread can be inside
+//! the window while it is rewritten, i.e.: blocked in the very syscall,
+//! about to return to the instructions following it. such sites are
+//! patched as rr does: an `int3` is written at the `syscall` first, so
+//! that no thread enters the window any more, and the full patch is only
+//! installed once no thread is left inside it, running threads being
+//! interrupted to tell, see `quiesce`. threads trapping on the `int3`
+//! meanwhile run the syscall by the hook.
+//!
+//! sites never quiescent, and sites no hook applies to (i.e.: the window
+//! is shorter than the 5 bytes of a jump), keep the `int3` as their patch:
+//! it leaves the instructions after the `syscall`, and branch targets
+//! among them, intact, at the cost of a stop per syscall.
+//!
+//! NB: this does not make branch targets inside the window of a full
+//! patch safe, which must be excluded by the hook definitions.
+//!
+//! calls injected in a task, which must not race other threads, stop them
+//! first instead, see `stop_others`.
+
+use nix::unistd::Pid;
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::sched_wait;
+
+/// breakpoint hits before a site keeps the `int3` as its patch
+pub const QUIESCE_ATTEMPTS: usize = 16;
+
+/// time for other threads to stop, see `stop_others`
+pub const QUIESCE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// pc of a (stopped) task, from `/proc/<pid>/task/<tid>/syscall`, which is
+// `nr args.. sp pc` when blocked in a syscall, `-1 sp pc` when blocked
+// otherwise, or `running`.
+fn syscall_pc(contents: &str) -> Option<u64> {
+    let pc = contents.split_whitespace().last()?;
+    if !pc.starts_with("0x") {
+        return None;
+    }
+    u64::from_str_radix(&pc[2..], 16).ok()
+}
+
+/// whether threads of process `pid` other than `tid` are all outside
+/// the `[start, end)` window, but for `start` itself, once stopped, see
+/// `stop_others`.
+pub fn quiesce(pid: Pid, tid: Pid, window: (u64, u64)) -> bool {
+    stop_others(pid, tid, QUIESCE_TIMEOUT) && is_quiescent(pid, tid, window)
+}
+
+/// whether threads of process `pid` other than `tid` are all outside
+/// the `[start, end)` window, but for `start` itself. running threads are
+/// assumed to be inside.
+pub fn is_quiescent(pid: Pid, tid: Pid, window: (u64, u64)) -> bool {
+    let entries = match fs::read_dir(format!("/proc/{}/task", pid)) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .map(Pid::from_raw)
+        .filter(|thread| *thread != tid)
+        .all(|thread| {
+            let path = format!("/proc/{}/task/{}/syscall", pid, thread);
+            match fs::read_to_string(path)
+                .ok()
+                .as_ref()
+                .map(|s| syscall_pc(s))
+            {
+                // exited meanwhile
+                None => true,
+                Some(Some(pc)) => pc <= window.0 || pc >= window.1,
+                Some(None) => false,
+            }
+        })
+}
+
+// state of a task from `/proc/<pid>/task/<tid>/stat`, after its `(comm)`
+fn stat_state(contents: &str) -> Option<char> {
+    let after = contents.rfind(')')?;
+    contents[after + 1..].trim_start().chars().next()
+}
+
+/// stop threads of process `pid` other than `tid` by `PTRACE_INTERRUPT`,
+/// within `timeout`, false if some did not. their stops are left to the
+/// scheduler, which resumes them once it waits for them.
+pub fn stop_others(pid: Pid, tid: Pid, timeout: Duration) -> bool {
+    let entries = match fs::read_dir(format!("/proc/{}/task", pid)) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    let threads: Vec<Pid> = entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .map(Pid::from_raw)
+        .filter(|thread| *thread != tid)
+        .collect();
+    // ptrace-stopped or group-stopped, or exited meanwhile
+    let stopped = |thread: &Pid| {
+        let path = format!("/proc/{}/task/{}/stat", pid, thread);
+        match fs::read_to_string(path)
+            .ok()
+            .as_ref()
+            .map(|s| stat_state(s))
+        {
+            None => true,
+            Some(state) => state == Some('t') || state == Some('T'),
+        }
+    };
+    for thread in threads.iter().filter(|thread| !stopped(thread)) {
+        let _ = sched_wait::interrupt(*thread);
+    }
+    let deadline = Instant::now() + timeout;
+    while !threads.iter().all(stopped) {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    true
+}
+
+#[test]
+fn syscall_pc_sanity_check() {
+    assert_eq!(
+        syscall_pc(
+            "0 0x3 0x7ffd2000 0x400 0x0 0x0 0x0 0x7ffd1f00 0x7f3a1c2d\n"
+        ),
+        Some(0x7f3a_1c2d)
+    );
+    assert_eq!(syscall_pc("-1 0x7ffd1f00 0x401000\n"), Some(0x401000));
+    assert_eq!(syscall_pc("running\n"), None);
+    assert_eq!(stat_state("42 (a) b) t 1 42 42 0"), Some('t'));
+    assert_eq!(stat_state("42 (cat"), None);
+}
@@ -0,0 +1,621 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! event recordings, of `reverie record`
+//!
+//! a recording is json lines: a `RecordingHeader` (the command recorded),
+//! then a `RecordedEvent` per event. tasks are logical ids, in order of
+//! creation, and timings are left out, so that recordings of two runs of
+//! the same command compare with `RecordingDiff`. ticks are recorded if
+//! counted, they are deterministic, and compared as well. asynchronous
+//! signals are replayed at the points recorded, see `RecordedSignals`.
+//!
+//! data returned by syscalls (see `TaskEventCB::record_data`) is split in
+//! `BLOCK_SIZE` blocks, recorded once by content: a `RecordedBlock` is
+//! written before the first event it is part of, and events refer to
+//! blocks by id. repeated reads of the same file, or the same directory
+//! listings, add a few bytes to the recording then, and so do reads of
+//! files changed in place but for the blocks changed. blocks are records
+//! of their own, so that recordings compress well still. data not selected
+//! by a `DataSelection` is recorded by digest only, without blocks.
+//! buffers written by `ioctl` and `fcntl` are recorded as well, and
+//! replayed, see `ioctl`.
+
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{BufRead, Error, ErrorKind, Result, Write};
+use std::path::PathBuf;
+
+use reverie_api::event::*;
+use reverie_api::fileless::sha256;
+use reverie_api::ticks::ExecPoint;
+use syscalls::SyscallNo;
+
+use crate::ioctl;
+
+/// size of recorded data blocks
+pub const BLOCK_SIZE: usize = 4096;
+
+/// command recorded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordingHeader {
+    pub program: String,
+    pub args: Vec<String>,
+    pub cwd: PathBuf,
+    /// whether syscall data is recorded, see `TaskEventCB::record_data`
+    #[serde(default)]
+    pub data: bool,
+    /// data recorded in full, all if none, see `DataSelection`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub data_selectors: Vec<String>,
+    /// whether ticks are recorded, see `TaskEventCB::ticks`
+    #[serde(default)]
+    pub ticks: bool,
+    /// timeslice of tasks, in ticks, see `TaskEventCB::timeslice`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeslice: Option<u64>,
+}
+
+/// event of logical task `thread`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    pub thread: usize,
+    pub event: String,
+    /// ticks of the task at the event, if counted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ticks: Option<u64>,
+}
+
+impl RecordedEvent {
+    /// `Event` variant
+    pub fn kind(&self) -> &str {
+        self.event.split('(').next().unwrap_or(&self.event)
+    }
+
+    /// signal, and point, of an `AsyncSignal`
+    pub fn async_signal(&self) -> Option<(Signal, ExecPoint)> {
+        let args =
+            self.event.strip_prefix("AsyncSignal(")?.strip_suffix(")")?;
+        let args: Vec<&str> = args.split(", ").collect();
+        let hex = |s: &str| u64::from_str_radix(s.strip_prefix("0x")?, 16).ok();
+        match args.as_slice() {
+            [sig, ticks, rip, regs] => Some((
+                sig.parse().ok()?,
+                ExecPoint {
+                    ticks: ticks.parse().ok()?,
+                    rip: hex(rip)?,
+                    regs: hex(regs)?,
+                },
+            )),
+            _ => None,
+        }
+    }
+
+    /// data of a `SyscallData`, by `blocks` of its recording
+    pub fn syscall_data(
+        &self,
+        blocks: &HashMap<usize, Vec<u8>>,
+    ) -> Option<Vec<u8>> {
+        let args = self
+            .event
+            .strip_prefix("SyscallData(")?
+            .strip_suffix("])")?;
+        let mut args = args.splitn(3, ", ");
+        let (_, size, ids) = (args.next()?, args.next()?, args.next()?);
+        let mut data = Vec::with_capacity(size.parse().ok()?);
+        let ids = ids.strip_prefix('[')?;
+        for id in ids.split(", ").filter(|id| !id.is_empty()) {
+            data.extend_from_slice(blocks.get(&id.parse().ok()?)?);
+        }
+        Some(data)
+    }
+}
+
+/// a data block, see module doc
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedBlock {
+    pub block: usize,
+    /// data, in hex
+    pub data: String,
+}
+
+/// a line of a recording, after its header
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Record {
+    Event(RecordedEvent),
+    Block(RecordedBlock),
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|k| u8::from_str_radix(hex.get(k..k + 2)?, 16).ok())
+        .collect()
+}
+
+impl fmt::Display for RecordedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "t{} {}", self.thread, self.event)?;
+        match self.ticks {
+            Some(ticks) => write!(f, " @{}", ticks),
+            None => Ok(()),
+        }
+    }
+}
+
+/// names tasks by logical ids, see module doc
+#[derive(Debug, Default)]
+pub struct EventRecorder {
+    ids: HashMap<Pid, usize>,
+    /// ids of blocks recorded, by sha256
+    blocks: HashMap<String, usize>,
+    /// blocks recorded, not written yet
+    new_blocks: Vec<RecordedBlock>,
+}
+
+impl EventRecorder {
+    pub fn new() -> Self {
+        EventRecorder::default()
+    }
+
+    fn id(&mut self, tid: Pid) -> usize {
+        let next = self.ids.len();
+        *self.ids.entry(tid).or_insert(next)
+    }
+
+    // ids of the blocks of `data`, recording new blocks
+    fn blocks_of(&mut self, data: &[u8]) -> Vec<usize> {
+        let mut ids = Vec::new();
+        for block in data.chunks(BLOCK_SIZE) {
+            let next = self.blocks.len();
+            let id = *self.blocks.entry(sha256(block)).or_insert(next);
+            if id == next {
+                self.new_blocks.push(RecordedBlock {
+                    block: id,
+                    data: to_hex(block),
+                });
+            }
+            ids.push(id);
+        }
+        ids
+    }
+
+    /// blocks recorded since last taken, to be written before the event
+    /// recorded last
+    pub fn take_blocks(&mut self) -> Vec<RecordedBlock> {
+        std::mem::take(&mut self.new_blocks)
+    }
+
+    /// logical id of task `tid`, if named yet
+    pub fn thread(&self, tid: Pid) -> Option<usize> {
+        self.ids.get(&tid).cloned()
+    }
+
+    /// `event` as recorded, `None` for calibration records and idle events,
+    /// which depend on timing
+    pub fn record(&mut self, event: &TimedEvent) -> Option<RecordedEvent> {
+        let (thread, ticks) = (self.id(event.tid), event.ticks);
+        let event = match &event.event {
+            Event::Calibration(_) | Event::Idle(_) => return None,
+            Event::SyscallExit(syscall, retval, _) => {
+                format!("SyscallExit({:?}, {})", syscall, retval)
+            }
+            Event::Fork(pid) => format!("Fork(t{})", self.id(*pid)),
+            Event::Spawn(pid) => format!("Spawn(t{})", self.id(*pid)),
+            Event::Clone(pid) => format!("Clone(t{})", self.id(*pid)),
+            Event::Zombie(pid) => format!("Zombie(t{})", self.id(*pid)),
+            Event::Reaped(pid) => format!("Reaped(t{})", self.id(*pid)),
+            Event::Orphaned(pid) => format!("Orphaned(t{})", self.id(*pid)),
+            Event::SyscallData(syscall, data) => format!(
+                "SyscallData({:?}, {}, {:?})",
+                syscall,
+                data.len(),
+                self.blocks_of(data)
+            ),
+            Event::AsyncSignal(sig, point) => format!(
+                "AsyncSignal({:?}, {}, {:#x}, {:#x})",
+                sig, point.ticks, point.rip, point.regs
+            ),
+            event => format!("{:?}", event),
+        };
+        Some(RecordedEvent {
+            thread,
+            event,
+            ticks,
+        })
+    }
+
+    /// sink writing the recording of `header` to `out`
+    pub fn into_sink<W: Write + 'static>(
+        mut self,
+        header: &RecordingHeader,
+        mut out: W,
+    ) -> Result<EventSink> {
+        write_line(&mut out, header)?;
+        Ok(Box::new(move |event| {
+            if let Some(event) = self.record(event) {
+                let blocks = self.take_blocks();
+                let written = blocks
+                    .iter()
+                    .try_for_each(|block| write_line(&mut out, block))
+                    .and_then(|_| write_line(&mut out, &event));
+                if let Err(err) = written {
+                    log::error!("[recording] cannot write event: {}", err);
+                }
+            }
+        }))
+    }
+}
+
+fn write_line<W: Write, T: Serialize>(out: &mut W, value: &T) -> Result<()> {
+    serde_json::to_writer(&mut *out, value)?;
+    writeln!(out)
+}
+
+fn invalid<E>(err: E) -> Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    Error::new(ErrorKind::InvalidData, err)
+}
+
+/// a recording, read by `read_recording`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recording {
+    pub header: RecordingHeader,
+    pub events: Vec<RecordedEvent>,
+    /// data blocks, by id
+    pub blocks: HashMap<usize, Vec<u8>>,
+}
+
+/// read a recording written by `EventRecorder::into_sink`
+pub fn read_recording<R: BufRead>(recording: R) -> Result<Recording> {
+    let mut lines = recording.lines();
+    let header = lines.next().ok_or_else(|| invalid("empty recording"))??;
+    let header = serde_json::from_str(&header).map_err(invalid)?;
+    let mut events = Vec::new();
+    let mut blocks = HashMap::new();
+    for line in lines {
+        let line = line?;
+        // partial last record, of a tracer crash
+        match serde_json::from_str(&line) {
+            Ok(Record::Event(event)) => events.push(event),
+            Ok(Record::Block(block)) => match from_hex(&block.data) {
+                Some(data) => {
+                    blocks.insert(block.block, data);
+                }
+                None => break,
+            },
+            Err(_) => break,
+        }
+    }
+    Ok(Recording {
+        header,
+        events,
+        blocks,
+    })
+}
+
+/// write `recording` as read by `read_recording`, its blocks first
+pub fn write_recording<W: Write>(
+    recording: &Recording,
+    mut out: W,
+) -> Result<()> {
+    write_line(&mut out, &recording.header)?;
+    let mut ids: Vec<&usize> = recording.blocks.keys().collect();
+    ids.sort();
+    for id in ids {
+        let block = RecordedBlock {
+            block: *id,
+            data: to_hex(&recording.blocks[id]),
+        };
+        write_line(&mut out, &block)?;
+    }
+    for event in &recording.events {
+        write_line(&mut out, event)?;
+    }
+    Ok(())
+}
+
+/// buffer `syscall` returned data in, with `regs` at its exit: address
+/// and size. syscalls with data in `iovec`s or `msghdr`s are not handled.
+pub fn syscall_output(
+    syscall: SyscallNo,
+    regs: &libc::user_regs_struct,
+) -> Option<(u64, usize)> {
+    let retval = regs.rax as i64;
+    match syscall {
+        SyscallNo::SYS_ioctl | SyscallNo::SYS_fcntl => {
+            ioctl::command_output(syscall, regs)
+        }
+        _ if retval <= 0 || !is_data_syscall(syscall) => None,
+        SyscallNo::SYS_readlinkat => Some((regs.rdx, retval as usize)),
+        _ => Some((regs.rsi, retval as usize)),
+    }
+}
+
+/// fd `syscall` returned data of, with `regs` at its exit, if any
+pub fn syscall_fd(
+    syscall: SyscallNo,
+    regs: &libc::user_regs_struct,
+) -> Option<i32> {
+    match syscall {
+        SyscallNo::SYS_readlink | SyscallNo::SYS_readlinkat => None,
+        _ if is_data_syscall(syscall) => Some(regs.rdi as i32),
+        _ => None,
+    }
+}
+
+/// whether `syscall` returns data in a buffer, see `syscall_output`
+pub fn is_data_syscall(syscall: SyscallNo) -> bool {
+    match syscall {
+        SyscallNo::SYS_read
+        | SyscallNo::SYS_pread64
+        | SyscallNo::SYS_getdents
+        | SyscallNo::SYS_getdents64
+        | SyscallNo::SYS_recvfrom
+        | SyscallNo::SYS_readlink
+        | SyscallNo::SYS_readlinkat
+        | SyscallNo::SYS_ioctl
+        | SyscallNo::SYS_fcntl => true,
+        _ => false,
+    }
+}
+
+/// asynchronous signals of a recording, by logical task, to replay, see
+/// `TaskEventCB::set_replay_signals`
+#[derive(Debug, Default)]
+pub struct RecordedSignals {
+    signals: HashMap<usize, VecDeque<(Signal, ExecPoint)>>,
+}
+
+impl RecordedSignals {
+    pub fn new(events: &[RecordedEvent]) -> Self {
+        let mut signals: HashMap<_, VecDeque<_>> = HashMap::new();
+        for event in events {
+            if let Some(signal) = event.async_signal() {
+                signals.entry(event.thread).or_default().push_back(signal);
+            }
+        }
+        RecordedSignals { signals }
+    }
+
+    /// next signal of logical task `thread`
+    pub fn next(&mut self, thread: usize) -> Option<(Signal, ExecPoint)> {
+        self.signals.get_mut(&thread)?.pop_front()
+    }
+
+    /// number of signals left
+    pub fn len(&self) -> usize {
+        self.signals.values().map(|signals| signals.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// differences between two recordings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordingDiff {
+    /// index of the first event which differs, with the events of both
+    /// recordings there (`None` past the end of a recording)
+    pub divergence:
+        Option<(usize, Option<RecordedEvent>, Option<RecordedEvent>)>,
+    /// kinds of events whose counts differ: `(kind, count a, count b)`
+    pub counts: Vec<(String, usize, usize)>,
+}
+
+impl RecordingDiff {
+    pub fn new(a: &[RecordedEvent], b: &[RecordedEvent]) -> Self {
+        let divergence = (0..a.len().max(b.len()))
+            .find(|&k| a.get(k) != b.get(k))
+            .map(|k| (k, a.get(k).cloned(), b.get(k).cloned()));
+        let mut counts: Vec<(String, usize, usize)> = Vec::new();
+        let mut count = |event: &RecordedEvent, in_a: bool| {
+            let kind = event.kind();
+            let k = match counts.iter().position(|c| c.0 == kind) {
+                Some(k) => k,
+                None => {
+                    counts.push((kind.to_string(), 0, 0));
+                    counts.len() - 1
+                }
+            };
+            if in_a {
+                counts[k].1 += 1;
+            } else {
+                counts[k].2 += 1;
+            }
+        };
+        a.iter().for_each(|event| count(event, true));
+        b.iter().for_each(|event| count(event, false));
+        counts.retain(|(_, a, b)| a != b);
+        RecordingDiff { divergence, counts }
+    }
+
+    /// whether both recordings are the same
+    pub fn is_empty(&self) -> bool {
+        self.divergence.is_none()
+    }
+}
+
+impl fmt::Display for RecordingDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let show = |event: &Option<RecordedEvent>| match event {
+            Some(event) => event.to_string(),
+            None => String::from("<end of recording>"),
+        };
+        let (k, a, b) = match &self.divergence {
+            None => return writeln!(f, "recordings are identical"),
+            Some(divergence) => divergence,
+        };
+        writeln!(f, "recordings diverge at event #{}:", k)?;
+        writeln!(f, "  a: {}", show(a))?;
+        writeln!(f, "  b: {}", show(b))?;
+        for (kind, a, b) in &self.counts {
+            writeln!(f, "{}: {} event(s) in a, {} in b", kind, a, b)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn recording_sanity_check() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    let header = RecordingHeader {
+        program: String::from("/bin/true"),
+        args: Vec::new(),
+        cwd: PathBuf::from("/"),
+        data: true,
+        data_selectors: vec![String::from("sockets")],
+        ticks: true,
+        timeslice: None,
+    };
+    let out = Shared::default();
+    let mut sink = EventRecorder::new()
+        .into_sink(&header, out.clone())
+        .unwrap();
+    let event = |tid, event| TimedEvent {
+        tid: Pid::from_raw(tid),
+        at: Default::default(),
+        ticks: None,
+        event,
+    };
+    let exit = |retval, micros| {
+        let elapsed = std::time::Duration::from_micros(micros);
+        Event::SyscallExit(SyscallNo::SYS_getpid, retval, elapsed)
+    };
+    sink(&event(100, exit(100, 3)));
+    sink(&event(100, Event::Fork(Pid::from_raw(101))));
+    sink(&event(101, exit(101, 5)));
+    sink(&event(101, Event::Exited(0)));
+    drop(sink);
+    let recording = out.0.borrow().clone();
+    let read = read_recording(&recording[..]).unwrap();
+    let a = read.events;
+    assert_eq!(read.header, header);
+    assert_eq!(a.len(), 4);
+    assert_eq!(a[1].event, "Fork(t1)");
+    assert_eq!(a[2].kind(), "SyscallExit");
+    assert!(RecordingDiff::new(&a, &a).is_empty());
+
+    let mut b = a.clone();
+    b.truncate(3);
+    let diff = RecordingDiff::new(&a, &b);
+    assert_eq!(diff.divergence, Some((3, Some(a[3].clone()), None)));
+    assert_eq!(diff.counts, vec![(String::from("Exited"), 1, 0)]);
+
+    let mut c = a.clone();
+    c[2].ticks = Some(42);
+    assert_eq!(c[2].to_string(), "t1 SyscallExit(SYS_getpid, 101) @42");
+    let diff = RecordingDiff::new(&a, &c);
+    assert_eq!(diff.divergence.map(|(k, _, _)| k), Some(2));
+    assert!(diff.counts.is_empty());
+
+    let mut recorder = EventRecorder::new();
+    let point = ExecPoint {
+        ticks: 42,
+        rip: 0x401000,
+        regs: 0xfeed,
+    };
+    let signal = Event::AsyncSignal(Signal::SIGALRM, point);
+    let d = vec![
+        recorder.record(&event(100, Event::Exec)).unwrap(),
+        recorder.record(&event(100, signal)).unwrap(),
+    ];
+    assert_eq!(recorder.thread(Pid::from_raw(100)), Some(0));
+    assert_eq!(d[1].async_signal(), Some((Signal::SIGALRM, point)));
+    let mut signals = RecordedSignals::new(&d);
+    assert_eq!(signals.len(), 1);
+    assert_eq!(signals.next(0), Some((Signal::SIGALRM, point)));
+    assert!(signals.is_empty());
+
+    // blocks are recorded once
+    let out = Shared::default();
+    let mut sink = EventRecorder::new()
+        .into_sink(&header, out.clone())
+        .unwrap();
+    let file: Vec<u8> = (0..BLOCK_SIZE + 100).map(|k| k as u8).collect();
+    let mut changed = file.clone();
+    changed[BLOCK_SIZE] = 0xff;
+    for data in &[&file, &file, &changed] {
+        let read = Event::SyscallData(SyscallNo::SYS_read, data.to_vec());
+        sink(&event(100, read));
+    }
+    drop(sink);
+    let recording = out.0.borrow().clone();
+    let read = read_recording(&recording[..]).unwrap();
+    assert_eq!(read.blocks.len(), 3);
+    assert_eq!(read.events[1].event, read.events[0].event);
+    assert_eq!(read.events[2].event, "SyscallData(SYS_read, 4196, [0, 2])");
+    assert_eq!(read.events[0].syscall_data(&read.blocks), Some(file));
+    assert_eq!(read.events[2].syscall_data(&read.blocks), Some(changed));
+    assert_eq!(a[0].syscall_data(&read.blocks), None);
+}
+
+#[test]
+fn record_data_sanity_check() {
+    let mut recorder = EventRecorder::new();
+    let data = |tid, data: Vec<u8>| TimedEvent {
+        tid: Pid::from_raw(tid),
+        at: Default::default(),
+        ticks: None,
+        event: Event::SyscallData(SyscallNo::SYS_getdents64, data),
+    };
+    // repeated blocks within a buffer are recorded once
+    let zeros = vec![0u8; 2 * BLOCK_SIZE];
+    let e = recorder.record(&data(100, zeros.clone())).unwrap();
+    assert_eq!(e.event, "SyscallData(SYS_getdents64, 8192, [0, 0])");
+    let blocks = recorder.take_blocks();
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(from_hex(&blocks[0].data), Some(vec![0u8; BLOCK_SIZE]));
+    assert!(recorder.take_blocks().is_empty());
+
+    // and so are blocks already recorded for other tasks
+    let e = recorder.record(&data(101, zeros[..10].to_vec())).unwrap();
+    assert_eq!(e.event, "SyscallData(SYS_getdents64, 10, [1])");
+    let e = recorder.record(&data(102, zeros)).unwrap();
+    assert_eq!(e.event, "SyscallData(SYS_getdents64, 8192, [0, 0])");
+    let blocks = recorder.take_blocks();
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0].block, 1);
+
+    let e = recorder.record(&data(100, Vec::new())).unwrap();
+    assert_eq!(e.event, "SyscallData(SYS_getdents64, 0, [])");
+    assert!(recorder.take_blocks().is_empty());
+    assert_eq!(e.syscall_data(&HashMap::new()), Some(Vec::new()));
+}
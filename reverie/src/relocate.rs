@@ -0,0 +1,144 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! relocation of x86_64 instructions
+//!
+//! instructions moved out of the syscall site (i.e.: into a stub) must
+//! still address the same memory: rip-relative displacements are fixed up
+//! for the new address. only the `modrm` (`[rip + disp32]`) form is
+//! decoded, relative branches are not relocatable.
+
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result};
+
+const REX_MASK: u8 = 0xf0;
+const REX: u8 = 0x40;
+const MODRM_RIP_MASK: u8 = 0xc7;
+const MODRM_RIP: u8 = 0x05;
+
+fn is_legacy_prefix(byte: u8) -> bool {
+    matches!(
+        byte,
+        0x66 | 0x67
+            | 0xf0
+            | 0xf2
+            | 0xf3
+            | 0x26
+            | 0x2e
+            | 0x36
+            | 0x3e
+            | 0x64
+            | 0x65
+    )
+}
+
+fn one_byte_has_modrm(op: u8) -> bool {
+    match op {
+        0x00..=0x3f => op & 0x7 < 4,
+        0x62 | 0x63 | 0x69 | 0x6b => true,
+        0x80..=0x8f => true,
+        0xc0 | 0xc1 | 0xc6 | 0xc7 => true,
+        0xd0..=0xd3 | 0xd8..=0xdf => true,
+        0xf6 | 0xf7 | 0xfe | 0xff => true,
+        _ => false,
+    }
+}
+
+fn two_byte_has_modrm(op: u8) -> bool {
+    match op {
+        0x05..=0x09 | 0x0b | 0x30..=0x37 | 0x77 => false,
+        // jcc rel32
+        0x80..=0x8f => false,
+        0xa0..=0xa2 | 0xa8..=0xaa | 0xc8..=0xcf => false,
+        _ => true,
+    }
+}
+
+/// offset of the `disp32` of a rip-relative instruction `insn`, `None` if
+/// not rip-relative.
+pub fn rip_relative_disp_offset(insn: &[u8]) -> Option<usize> {
+    let mut k = 0;
+    while is_legacy_prefix(*insn.get(k)?) {
+        k += 1;
+    }
+    if insn.get(k)? & REX_MASK == REX {
+        k += 1;
+    }
+    let has_modrm = match *insn.get(k)? {
+        0x0f => {
+            k += 1;
+            match *insn.get(k)? {
+                0x38 | 0x3a => {
+                    k += 1;
+                    true
+                }
+                op => two_byte_has_modrm(op),
+            }
+        }
+        op => one_byte_has_modrm(op),
+    };
+    let modrm = *insn.get(k + 1)?;
+    if has_modrm && modrm & MODRM_RIP_MASK == MODRM_RIP {
+        Some(k + 2).filter(|disp| disp + 4 <= insn.len())
+    } else {
+        None
+    }
+}
+
+/// relocate instruction `insn` from address `from` to `to`, fixing up its
+/// rip-relative displacement (if any).
+pub fn relocate_insn(insn: &[u8], from: u64, to: u64) -> Result<Vec<u8>> {
+    let mut res = Vec::from(insn);
+    let k = match rip_relative_disp_offset(insn) {
+        None => return Ok(res),
+        Some(k) => k,
+    };
+    let mut disp = [0u8; 4];
+    disp.copy_from_slice(&insn[k..k + 4]);
+    let next = insn.len() as i64;
+    let target = from as i64 + next + i32::from_le_bytes(disp) as i64;
+    let new_disp =
+        i32::try_from(target - (to as i64 + next)).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "cannot relocate {:02x?} from {:x} to {:x}: out of range",
+                    insn, from, to
+                ),
+            )
+        })?;
+    res[k..k + 4].copy_from_slice(&new_disp.to_le_bytes());
+    Ok(res)
+}
+
+#[test]
+fn relocate_sanity_check() {
+    // mov 0x10(%rip),%rax
+    let mov = [0x48, 0x8b, 0x05, 0x10, 0x00, 0x00, 0x00];
+    assert_eq!(rip_relative_disp_offset(&mov), Some(3));
+    let moved = relocate_insn(&mov, 0x1000, 0x2000).unwrap();
+    assert_eq!(moved, vec![0x48, 0x8b, 0x05, 0x10, 0xf0, 0xff, 0xff]);
+    assert_eq!(relocate_insn(&moved, 0x2000, 0x1000).unwrap(), mov.to_vec());
+    // cmp $-4095,%rax, no modrm
+    let cmp = [0x48, 0x3d, 0x01, 0xf0, 0xff, 0xff];
+    assert_eq!(rip_relative_disp_offset(&cmp), None);
+    assert_eq!(relocate_insn(&cmp, 0x1000, 0x2000).unwrap(), cmp.to_vec());
+    // movq (%rsp),%rdi
+    assert_eq!(rip_relative_disp_offset(&[0x48, 0x8b, 0x3c, 0x24]), None);
+    // jmp *0(%rip)
+    assert_eq!(
+        rip_relative_disp_offset(&[0xff, 0x25, 0x00, 0x00, 0x00, 0x00]),
+        Some(2)
+    );
+    assert!(relocate_insn(&mov, 0x1000, 0x1_0000_1000).is_err());
+}
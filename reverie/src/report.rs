@@ -0,0 +1,411 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! machine-readable exit report, of `--report`
+//!
+//! `ExitReport` is written as json when the session ends: exit status,
+//! how each traced process exited, syscall statistics, patch coverage,
+//! the warnings logged by the tracer (see `record_warning`), the fd
+//! provenance graph if tracked, policy violations, fileless execs,
+//! binaries loaded if hashed, degradations over the overhead budget,
+//! syscalls counted in-kernel, and features turned off for the host, so
+//! that CI systems can assert on tracer health.
+
+use nix::unistd::Pid;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{Result, Write};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use reverie_api::binaries::BinaryImage;
+use reverie_api::event::*;
+use reverie_api::fileless::FilelessExec;
+use reverie_api::provenance::FdProvenance;
+use reverie_api::violation::Violation;
+use reverie_api::wait::ChildStatus;
+use reverie_common::profiling::SyscallStats;
+
+use crate::budget::{self, DegradationRecord};
+use crate::ebpf::BpfStats;
+use crate::provenance::fd_provenance;
+use crate::violation::violations;
+
+/// warnings kept for the report, later ones are counted only
+pub const MAX_WARNINGS: usize = 1000;
+
+lazy_static! {
+    static ref WARNINGS: Mutex<(Vec<String>, usize)> =
+        Mutex::new((Vec::new(), 0));
+}
+
+/// keep warning `message` for the report, i.e.: from the logger
+pub fn record_warning(message: String) {
+    let mut warnings = WARNINGS.lock().unwrap_or_else(|e| e.into_inner());
+    if warnings.0.len() < MAX_WARNINGS {
+        warnings.0.push(message);
+    } else {
+        warnings.1 += 1;
+    }
+}
+
+/// how a traced process exited
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ProcessExit {
+    pub pid: i32,
+    /// `None` for the initial process
+    pub ppid: Option<i32>,
+    /// number of exec'ed programs
+    pub execs: usize,
+    /// `None` if killed by a signal, or if its exit was not seen
+    pub exit_code: Option<i32>,
+    pub signal: Option<String>,
+    pub core_dumped: bool,
+    /// handling the process panicked, see `Event::Quarantined`
+    pub quarantined: Option<String>,
+    /// programs exec'ed from memfds, see `Event::FilelessExec`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fileless_execs: Vec<FilelessRecord>,
+    /// programs and shared libraries loaded, see `Event::BinaryLoaded`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub binaries: Vec<BinaryRecord>,
+}
+
+/// a binary loaded, see `BinaryImage`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BinaryRecord {
+    pub path: PathBuf,
+    pub main: bool,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl From<&BinaryImage> for BinaryRecord {
+    fn from(image: &BinaryImage) -> Self {
+        BinaryRecord {
+            path: image.path.clone(),
+            main: image.main,
+            size: image.size,
+            sha256: image.sha256.clone(),
+        }
+    }
+}
+
+/// a fileless exec, see `FilelessExec`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FilelessRecord {
+    pub memfd: String,
+    pub size: u64,
+    pub sha256: String,
+    pub saved: Option<PathBuf>,
+}
+
+impl From<&FilelessExec> for FilelessRecord {
+    fn from(fileless: &FilelessExec) -> Self {
+        FilelessRecord {
+            memfd: fileless.memfd.clone(),
+            size: fileless.size,
+            sha256: fileless.sha256.clone(),
+            saved: fileless.saved.clone(),
+        }
+    }
+}
+
+/// syscall statistics, see `SyscallStats`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StatsSummary {
+    pub syscalls: usize,
+    pub syscalls_ptraced: usize,
+    pub syscalls_captured: usize,
+    pub syscalls_emulated: usize,
+    pub syscalls_unfiltered: usize,
+    pub tasks_forked: usize,
+    pub tasks_cloned: usize,
+    pub tracer_nanos: usize,
+    pub kernel_nanos: usize,
+}
+
+/// how syscalls were intercepted
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PatchCoverage {
+    /// syscall sites patched
+    pub sites_patched: usize,
+    /// syscalls through patched sites (the fast path), over all syscalls
+    pub captured_ratio: f64,
+    /// syscalls stopped by ptrace (the slow path), over all syscalls
+    pub ptraced_ratio: f64,
+}
+
+/// node of the fd provenance graph, see `FdNode`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FdRecord {
+    pub id: usize,
+    pub pid: i32,
+    pub fd: i32,
+    pub origin: String,
+    /// id of the fd it came from
+    pub source: Option<usize>,
+    pub closed: bool,
+}
+
+/// nodes of `graph`, as reported
+pub fn fd_records(graph: &FdProvenance) -> Vec<FdRecord> {
+    graph
+        .nodes()
+        .iter()
+        .enumerate()
+        .map(|(id, node)| FdRecord {
+            id,
+            pid: node.pid.as_raw(),
+            fd: node.fd,
+            origin: node.origin.to_string(),
+            source: node.source,
+            closed: node.closed,
+        })
+        .collect()
+}
+
+/// a process killed by a policy violation, see `Violation`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ViolationRecord {
+    pub pid: i32,
+    pub tid: i32,
+    pub syscall: String,
+    pub args: [u64; 6],
+    pub reason: String,
+    pub backtrace: Vec<String>,
+    pub recent_events: Vec<String>,
+}
+
+impl From<&Violation> for ViolationRecord {
+    fn from(violation: &Violation) -> Self {
+        let args = &violation.args;
+        ViolationRecord {
+            pid: violation.pid.as_raw(),
+            tid: violation.tid.as_raw(),
+            syscall: format!("{:?}", violation.syscall),
+            args: [
+                args.arg0, args.arg1, args.arg2, args.arg3, args.arg4,
+                args.arg5,
+            ],
+            reason: violation.reason.clone(),
+            backtrace: violation
+                .backtrace
+                .iter()
+                .map(|frame| frame.to_string())
+                .collect(),
+            recent_events: violation
+                .recent
+                .iter()
+                .map(|event| format!("{:?}", event.event))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ExitReport {
+    /// exit status of reverie, i.e.: of the traced program
+    pub exit_status: i32,
+    pub processes: Vec<ProcessExit>,
+    pub stats: StatsSummary,
+    pub patch_coverage: PatchCoverage,
+    pub warnings: Vec<String>,
+    /// warnings past `MAX_WARNINGS`, not kept
+    pub warnings_dropped: usize,
+    /// empty unless fd provenance is tracked
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fd_provenance: Vec<FdRecord>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<ViolationRecord>,
+    /// degradations applied, over the overhead budget, see `budget`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub degradations: Vec<DegradationRecord>,
+    /// landlock abi of the ruleset installed, see `--landlock`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub landlock_abi: Option<u32>,
+    /// syscalls counted in-kernel, see `--bpf-counters`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bpf: Option<BpfStats>,
+    /// features turned off for the host, see `doctor::Probe`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub downgrades: Vec<String>,
+}
+
+impl ExitReport {
+    /// write the report to `out`, as json
+    pub fn write<W: Write>(&self, out: W) -> Result<()> {
+        serde_json::to_writer_pretty(out, self)?;
+        Ok(())
+    }
+}
+
+/// builds `ExitReport`s from events
+#[derive(Debug, Default)]
+pub struct ExitRecorder {
+    processes: Vec<ProcessExit>,
+    /// processes, by pid
+    pids: HashMap<Pid, usize>,
+    /// process of threads (but process leaders), by tid
+    threads: HashMap<Pid, Pid>,
+}
+
+impl ExitRecorder {
+    pub fn new() -> Self {
+        ExitRecorder::default()
+    }
+
+    fn process(&mut self, pid: Pid, ppid: Option<Pid>) -> &mut ProcessExit {
+        let processes = &mut self.processes;
+        let k = *self.pids.entry(pid).or_insert_with(|| {
+            processes.push(ProcessExit {
+                pid: pid.as_raw(),
+                ppid: ppid.map(|ppid| ppid.as_raw()),
+                ..ProcessExit::default()
+            });
+            processes.len() - 1
+        });
+        &mut self.processes[k]
+    }
+
+    pub fn record_event(&mut self, event: &TimedEvent) {
+        let tid = event.tid;
+        let pid = self.threads.get(&tid).cloned().unwrap_or(tid);
+        match &event.event {
+            Event::Fork(child) | Event::Spawn(child) => {
+                self.process(*child, Some(pid));
+            }
+            Event::Clone(child) => {
+                self.threads.insert(*child, pid);
+            }
+            // NB: exits of threads are not reported
+            _ if pid != tid => (),
+            Event::Exec => self.process(pid, None).execs += 1,
+            Event::Exited(status) => {
+                let process = self.process(pid, None);
+                match ChildStatus::from_wstatus(*status) {
+                    Some(ChildStatus::Exited(code)) => {
+                        process.exit_code = Some(code)
+                    }
+                    Some(ChildStatus::Signaled(signal, core_dumped)) => {
+                        process.signal = Some(format!("{:?}", signal));
+                        process.core_dumped = core_dumped;
+                    }
+                    _ => (),
+                }
+            }
+            Event::Quarantined(reason) => {
+                self.process(pid, None).quarantined = Some(reason.clone());
+            }
+            Event::FilelessExec(fileless) => {
+                let record = FilelessRecord::from(fileless);
+                self.process(pid, None).fileless_execs.push(record);
+            }
+            Event::BinaryLoaded(image) => {
+                let record = BinaryRecord::from(image);
+                self.process(pid, None).binaries.push(record);
+            }
+            _ => (),
+        }
+    }
+
+    /// report of the session exiting with `exit_status`
+    pub fn report(&self, exit_status: i32, stats: &SyscallStats) -> ExitReport {
+        let load = |counter: &std::sync::atomic::AtomicUsize| {
+            counter.load(Ordering::SeqCst)
+        };
+        let sites_patched = load(&stats.nr_syscalls_patched);
+        let stats = StatsSummary {
+            syscalls: load(&stats.nr_syscalls),
+            syscalls_ptraced: load(&stats.nr_syscalls_ptraced),
+            syscalls_captured: load(&stats.nr_syscalls_captured),
+            syscalls_emulated: load(&stats.nr_syscalls_emulated),
+            syscalls_unfiltered: load(&stats.nr_syscalls_unfiltered),
+            tasks_forked: load(&stats.nr_forked),
+            tasks_cloned: load(&stats.nr_cloned),
+            tracer_nanos: load(&stats.syscall_tracer_nanos),
+            kernel_nanos: load(&stats.syscall_kernel_nanos),
+        };
+        let ratio = |n: usize| {
+            if stats.syscalls == 0 {
+                0.0
+            } else {
+                n as f64 / stats.syscalls as f64
+            }
+        };
+        let patch_coverage = PatchCoverage {
+            sites_patched,
+            captured_ratio: ratio(stats.syscalls_captured),
+            ptraced_ratio: ratio(stats.syscalls_ptraced),
+        };
+        let warnings = WARNINGS.lock().unwrap_or_else(|e| e.into_inner());
+        ExitReport {
+            exit_status,
+            processes: self.processes.clone(),
+            stats,
+            patch_coverage,
+            warnings: warnings.0.clone(),
+            warnings_dropped: warnings.1,
+            fd_provenance: fd_records(
+                &fd_provenance().lock().unwrap_or_else(|e| e.into_inner()),
+            ),
+            violations: violations()
+                .iter()
+                .map(ViolationRecord::from)
+                .collect(),
+            degradations: budget::degradations(),
+            landlock_abi: None,
+            bpf: None,
+            downgrades: Vec::new(),
+        }
+    }
+}
+
+#[test]
+fn exit_report_sanity_check() {
+    let event = |tid, event| TimedEvent {
+        tid: Pid::from_raw(tid),
+        at: Default::default(),
+        ticks: None,
+        event,
+    };
+    let mut recorder = ExitRecorder::new();
+    recorder.record_event(&event(10, Event::Exec));
+    recorder.record_event(&event(10, Event::Fork(Pid::from_raw(11))));
+    recorder.record_event(&event(10, Event::Clone(Pid::from_raw(12))));
+    recorder.record_event(&event(12, Event::Fork(Pid::from_raw(13))));
+    recorder.record_event(&event(12, Event::Exited(0)));
+    recorder.record_event(&event(11, Event::Exited(libc::SIGKILL)));
+    recorder.record_event(&event(10, Event::Exited(3 << 8)));
+    record_warning(String::from("[test] warned"));
+
+    let stats = SyscallStats::new();
+    stats.nr_syscalls.store(4, Ordering::SeqCst);
+    stats.nr_syscalls_captured.store(3, Ordering::SeqCst);
+    let report = recorder.report(3, &stats);
+    assert_eq!(report.processes.len(), 3);
+    assert_eq!(report.processes[2].ppid, Some(10));
+    let (parent, child) = (&report.processes[0], &report.processes[1]);
+    assert_eq!((parent.execs, parent.exit_code), (1, Some(3)));
+    assert_eq!(child.ppid, Some(10));
+    assert_eq!(child.signal.as_ref().map(String::as_str), Some("SIGKILL"));
+    assert_eq!(report.patch_coverage.captured_ratio, 0.75);
+    assert!(report.warnings.contains(&String::from("[test] warned")));
+
+    let mut json = Vec::new();
+    report.write(&mut json).unwrap();
+    assert!(String::from_utf8(json)
+        .unwrap()
+        .contains("\"exit_status\": 3"));
+}
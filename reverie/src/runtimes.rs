@@ -0,0 +1,148 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! quirks of managed runtimes: the jvm and node/v8
+//!
+//! a runtime is detected by the libraries mapped in the process
+//! (`libjvm.so`, `libnode.so` or the `node` program), when the memory map
+//! is refreshed for a syscall site not in it. then:
+//!
+//! - syscall sites of jit code (anonymous executable mappings) are never
+//!   patched: the runtime moves, frees and rewrites its code, nor are
+//!   sites of `libjsig.so`, which chains signal handlers of the jvm. they
+//!   are serviced by ptrace.
+//! - `SIGSEGV` and `SIGBUS` are part of normal execution: implicit null
+//!   checks and safepoint polls of the jvm, the wasm trap handler of v8.
+//!   they are forwarded as is, without dumping the fault context.
+//! - features which are not supported are warned about, once per process.
+
+use nix::sys::signal::Signal;
+use procfs::process::{MMapPath, MemoryMap};
+
+/// managed runtime of a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ManagedRuntime {
+    Jvm,
+    V8,
+}
+
+// file name of mapping `e`, if backed by a file
+fn file_name(e: &MemoryMap) -> Option<&str> {
+    match &e.pathname {
+        MMapPath::Path(path) => path.file_name()?.to_str(),
+        _ => None,
+    }
+}
+
+fn is_library(name: &str, lib: &str) -> bool {
+    name == lib || name.starts_with(&format!("{}.", lib))
+}
+
+impl ManagedRuntime {
+    /// runtime of a process with memory `maps`, if any
+    pub fn detect(maps: &[MemoryMap]) -> Option<Self> {
+        maps.iter().filter_map(file_name).find_map(|name| {
+            if is_library(name, "libjvm.so") {
+                Some(ManagedRuntime::Jvm)
+            } else if name == "node"
+                || is_library(name, "libnode.so")
+                || is_library(name, "libv8.so")
+            {
+                Some(ManagedRuntime::V8)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// whether `signal` is raised by the runtime as part of normal
+    /// execution
+    pub fn is_runtime_signal(self, signal: Signal) -> bool {
+        signal == Signal::SIGSEGV || signal == Signal::SIGBUS
+    }
+
+    /// features not supported with the runtime, `ticks` if counted, i.e.:
+    /// recording or replaying
+    pub fn unsupported(self, ticks: bool) -> Vec<&'static str> {
+        let mut res = vec!["syscalls of jit code are not patched"];
+        if ticks {
+            res.push("recording and replaying jit code is not deterministic");
+        }
+        if self == ManagedRuntime::Jvm {
+            res.push("signal handlers chained by libjsig.so are not patched");
+        }
+        res
+    }
+}
+
+/// whether syscall site `rip` of a process of `runtime`, with memory
+/// `maps`, is never patched
+pub fn is_unpatchable_site(
+    runtime: ManagedRuntime,
+    maps: &[MemoryMap],
+    rip: u64,
+) -> bool {
+    let e = match maps
+        .iter()
+        .find(|e| rip >= e.address.0 && rip < e.address.1)
+    {
+        Some(e) => e,
+        None => return true,
+    };
+    match &e.pathname {
+        MMapPath::Anonymous | MMapPath::Heap => true,
+        MMapPath::Path(path) => {
+            runtime == ManagedRuntime::Jvm
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .map_or(false, |name| is_library(name, "libjsig.so"))
+        }
+        _ => false,
+    }
+}
+
+/// whether `rip` is in a mapping of `maps`
+pub fn maps_cover(maps: &[MemoryMap], rip: u64) -> bool {
+    maps.iter().any(|e| rip >= e.address.0 && rip < e.address.1)
+}
+
+#[test]
+fn runtimes_sanity_check() {
+    use std::path::Path;
+    let map = |address: (u64, u64), pathname: MMapPath| MemoryMap {
+        address,
+        perms: String::from("r-xp"),
+        offset: 0,
+        dev: (0, 0),
+        inode: 0,
+        pathname,
+    };
+    let jvm = Path::new("/usr/lib/jvm/java-11/lib/server/libjvm.so");
+    let jsig = Path::new("/usr/lib/jvm/java-11/lib/libjsig.so");
+    let maps = [
+        map((0x1000, 0x2000), MMapPath::Path(jvm.to_path_buf())),
+        map((0x2000, 0x3000), MMapPath::Path(jsig.to_path_buf())),
+        map((0x3000, 0x4000), MMapPath::Anonymous),
+    ];
+    let runtime = ManagedRuntime::detect(&maps);
+    assert_eq!(runtime, Some(ManagedRuntime::Jvm));
+    assert_eq!(ManagedRuntime::detect(&maps[1..]), None);
+    let node = map((0x1000, 0x2000), MMapPath::Path("/usr/bin/node".into()));
+    assert_eq!(ManagedRuntime::detect(&[node]), Some(ManagedRuntime::V8));
+    assert!(!is_unpatchable_site(ManagedRuntime::Jvm, &maps, 0x1800));
+    assert!(is_unpatchable_site(ManagedRuntime::Jvm, &maps, 0x2800));
+    assert!(!is_unpatchable_site(ManagedRuntime::V8, &maps, 0x2800));
+    assert!(is_unpatchable_site(ManagedRuntime::V8, &maps, 0x3800));
+    assert!(ManagedRuntime::Jvm.is_runtime_signal(Signal::SIGSEGV));
+}
@@ -15,28 +15,129 @@
 use log::Level::Trace;
 use nix::sys::wait::{WaitPidFlag, WaitStatus};
 use nix::sys::{ptrace, signal, wait};
+use nix::unistd;
 use nix::unistd::Pid;
 use std::cell::RefCell;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::{Error, ErrorKind, Result};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use procfs;
 
+use reverie_api::clock::*;
 use reverie_api::event::*;
+use reverie_api::isolation::{catch_panic, panic_message};
+use reverie_api::lifecycle::*;
 use reverie_api::remote::*;
 use reverie_api::task::*;
 use reverie_common::consts;
-use reverie_common::state::ReverieState;
+use reverie_common::state::{reverie_global_state, ReverieState};
 
 use syscalls::*;
 
+use crate::control;
 use crate::debug;
+use crate::detach;
+use crate::dying;
+use crate::ebpf;
+use crate::idle::IdleDetector;
+use crate::nesting;
+use crate::overhead;
+use crate::process::ProcessRef;
+use crate::session::SessionState;
+use crate::task_limits;
+use crate::ticks;
 use crate::traced_task::TracedTask;
 use crate::traced_task::*;
+use crate::workers;
+
+/// default round-robin quantum, in number of events
+pub const SCHED_DEFAULT_QUANTUM: usize = 16;
+
+/// policies to pick the next runnable task
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedPolicy {
+    /// tasks are serviced in the order they became runnable
+    Fifo,
+    /// a task is serviced for at most `quantum` consecutive events before
+    /// other runnable tasks
+    RoundRobin { quantum: usize },
+    /// tasks with higher `TaskPriority` first, FIFO within the same priority
+    Priority,
+}
+
+impl Default for SchedPolicy {
+    fn default() -> Self {
+        SchedPolicy::RoundRobin {
+            quantum: SCHED_DEFAULT_QUANTUM,
+        }
+    }
+}
+
+impl FromStr for SchedPolicy {
+    type Err = Error;
+    /// parse from `fifo`, `priority`, `rr` or `rr:<quantum>`
+    fn from_str(s: &str) -> Result<Self> {
+        let mut iter = s.splitn(2, ':');
+        match (iter.next(), iter.next()) {
+            (Some("fifo"), None) => Ok(SchedPolicy::Fifo),
+            (Some("priority"), None) => Ok(SchedPolicy::Priority),
+            (Some("rr"), None) => Ok(SchedPolicy::default()),
+            (Some("rr"), Some(quantum)) => match quantum.parse() {
+                Ok(quantum) if quantum > 0 => {
+                    Ok(SchedPolicy::RoundRobin { quantum })
+                }
+                _ => Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid round-robin quantum: {}", quantum),
+                )),
+            },
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown sched policy: {}", s),
+            )),
+        }
+    }
+}
+
+/// what to do with ptrace events unknown to the tracer, i.e.: added by
+/// newer kernels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownEventPolicy {
+    /// resume the task, as if the event did not happen
+    Ignore,
+    /// quarantine the task, see `Event::Quarantined`
+    StopTask,
+    /// abort the tracer
+    Abort,
+}
+
+impl Default for UnknownEventPolicy {
+    fn default() -> Self {
+        UnknownEventPolicy::Ignore
+    }
+}
+
+impl FromStr for UnknownEventPolicy {
+    type Err = Error;
+    /// parse from `ignore`, `stop-task` or `abort`
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ignore" => Ok(UnknownEventPolicy::Ignore),
+            "stop-task" => Ok(UnknownEventPolicy::StopTask),
+            "abort" => Ok(UnknownEventPolicy::Abort),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown unknown-event policy: {}", s),
+            )),
+        }
+    }
+}
 
 /// the scheduler
 pub struct SchedWait<G> {
@@ -44,29 +145,246 @@ pub struct SchedWait<G> {
     run_queue: VecDeque<Pid>,
     blocked_queue: VecDeque<Pid>,
     task_tree: HashMap<Pid, Pid>,
+    /// processes of the tasks, tasks hold handles to their `Process`
+    processes: HashMap<Pid, ProcessRef>,
+    /// lifecycle of processes, for zombie/orphan accounting
+    lifecycles: ProcessTable,
     event_cbs: Rc<RefCell<TaskEventCB>>,
     global_state: Arc<Mutex<G>>,
+    policy: SchedPolicy,
+    unknown_event_policy: UnknownEventPolicy,
+    /// last serviced task, and number of consecutive events serviced
+    last_run: Option<(Pid, usize)>,
+    /// reports `Event::Idle`, if `TaskEventCB::idle_after` is set
+    idle: Option<IdleDetector>,
 }
 
 impl<G> SchedWait<G> {
     /// create a new `Sheduler`
     pub fn new(cb: TaskEventCB, gs: G) -> Self {
+        let idle = cb.idle_after.map(IdleDetector::new);
         SchedWait {
             tasks: HashMap::new(),
             run_queue: VecDeque::new(),
             blocked_queue: VecDeque::new(),
             task_tree: HashMap::new(),
+            processes: HashMap::new(),
+            lifecycles: ProcessTable::new(),
             event_cbs: Rc::new(RefCell::new(cb)),
             global_state: Arc::new(Mutex::new(gs)),
+            policy: SchedPolicy::default(),
+            unknown_event_policy: UnknownEventPolicy::default(),
+            last_run: None,
+            idle,
         }
     }
+    /// set scheduling policy
+    pub fn set_policy(&mut self, policy: SchedPolicy) {
+        self.policy = policy;
+    }
+    /// set policy for unknown ptrace events
+    pub fn set_unknown_event_policy(&mut self, policy: UnknownEventPolicy) {
+        self.unknown_event_policy = policy;
+    }
     /// add a new task into `Scheduler` run (ready) queue
     pub fn add(&mut self, task: TracedTask) {
         let tid = Task::gettid(&task);
         self.task_tree.insert(task.gettid(), task.getppid());
+        self.own_process(&task);
         self.tasks.insert(tid, task);
         self.run_queue.push_back(tid);
     }
+    /// trace another process tree, forked to run `tracee` (see
+    /// `spawn_stopped`), with the same callbacks and policies as the tasks
+    /// traced already. returns its pid.
+    pub fn spawn_another<F>(&mut self, tracee: F) -> Result<Pid>
+    where
+        F: FnOnce() -> Result<i32>,
+    {
+        let child = spawn_stopped(tracee)?;
+        ptrace::cont(child, None)
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        self.add(Task::new(child));
+        Ok(child)
+    }
+    /// `Process` of `pid`
+    pub fn process(&self, pid: Pid) -> Option<ProcessRef> {
+        self.processes.get(&pid).cloned()
+    }
+    /// lifecycle of process `pid`, `None` if never traced
+    pub fn lifecycle(&self, pid: Pid) -> Option<TaskLifecycle> {
+        self.lifecycles.lifecycle(pid)
+    }
+    /// process `pid` exited: it becomes a zombie of its traced parent (if
+    /// any), and its own children are reparented.
+    fn process_exited(&mut self, pid: Pid) {
+        self.processes.remove(&pid);
+        if let Some(parent) = self.lifecycles.exited(pid) {
+            self.emit(parent, Event::Zombie(pid));
+        }
+        self.check_reaped(pid);
+        let (zombies, orphans) = self.lifecycles.parent_exited(pid);
+        for child in zombies {
+            log::warn!(
+                "[sched] {} exited without reaping child {}",
+                pid,
+                child
+            );
+        }
+        for child in orphans {
+            self.emit(pid, Event::Orphaned(child));
+        }
+    }
+    /// check whether zombie children of `parent` has been reaped
+    fn check_reaped(&mut self, parent: Pid) {
+        for child in self.lifecycles.zombies(parent) {
+            // NB: pid of a reaped process can be reused, but not by a
+            // process we'd miss: a traced one is `spawned` again.
+            if !PathBuf::from(format!("/proc/{}", child)).exists() {
+                self.lifecycles.reaped(child);
+                self.emit(parent, Event::Reaped(child));
+            }
+        }
+    }
+    /// tasks not known to the scheduler which are exiting, i.e.: a thread
+    /// auto-attached by a clone which its process, killed, never reported.
+    /// they are let exit, and reaped: the zombie leader of their thread
+    /// group is not reaped (by its parent) until they are.
+    fn reap_unknown(&mut self) {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let flags = libc::WEXITED
+            | libc::WSTOPPED
+            | libc::WNOHANG
+            | libc::WNOWAIT
+            | libc::__WALL;
+        let ret = unsafe { libc::waitid(libc::P_ALL, 0, &mut info, flags) };
+        let tid = Pid::from_raw(unsafe { info.si_pid() });
+        if ret != 0 || tid.as_raw() == 0 || self.tasks.contains_key(&tid) {
+            return;
+        }
+        let exit_stop = libc::SIGTRAP | (libc::PTRACE_EVENT_EXIT << 8);
+        match info.si_code {
+            libc::CLD_EXITED | libc::CLD_KILLED | libc::CLD_DUMPED => (),
+            libc::CLD_TRAPPED if unsafe { info.si_status() } == exit_stop => (),
+            // i.e.: a new task, whose parent's event is not handled yet
+            _ => return,
+        }
+        let status = wait::waitpid(tid, Some(WaitPidFlag::__WALL));
+        log::debug!("[sched] {} unknown, reaped: {:?}", tid, status);
+        match status {
+            Ok(WaitStatus::PtraceEvent(..)) => {
+                let _ = ptrace::cont(tid, None);
+            }
+            _ => dying::reaped(tid),
+        }
+    }
+    /// all tasks waited for, report whether they are idle
+    fn poll_idle(&mut self) {
+        let idle = match self.idle.as_mut() {
+            Some(detector) => detector.poll(self.tasks.keys().cloned()),
+            None => None,
+        };
+        if let Some(idle) = idle {
+            log::info!("[sched] all tasks blocked in syscalls for {:?}", idle);
+            self.emit(unistd::getpid(), Event::Idle(idle));
+        }
+    }
+    fn emit(&self, tid: Pid, event: Event) {
+        self.event_cbs
+            .borrow_mut()
+            .emit(tid, Timestamp::now(), event);
+    }
+    /// thread `former` of process `pid` exec'ed: the kernel killed other
+    /// threads, and `former` took over `pid`. forget their tasks, and reap
+    /// any left as zombies.
+    fn reap_exec_siblings(&mut self, pid: Pid, former: Pid) {
+        log::debug!("[sched] {} exec'ed from thread {}", pid, former);
+        let siblings: Vec<Pid> = self
+            .tasks
+            .iter()
+            .filter(|(tid, task)| **tid != pid && task.getpid() == pid)
+            .map(|(tid, _)| *tid)
+            .chain(std::iter::once(former))
+            .collect();
+        for tid in siblings {
+            self.tasks.remove(&tid);
+            self.task_tree.remove(&tid);
+            self.run_queue.retain(|t| *t != tid);
+            self.blocked_queue.retain(|t| *t != tid);
+            let flags = WaitPidFlag::WNOHANG | WaitPidFlag::__WALL;
+            let _ = wait::waitpid(Some(tid), Some(flags));
+            dying::reaped(tid);
+        }
+    }
+    /// quarantine task `tid` of process `pid`, whose handling panicked with
+    /// `reason`: it is detached stopped, so that it can be inspected (i.e.:
+    /// by `gdb -p`), and so are the other threads of the process, whose
+    /// state is unknown then. the rest of the tree is still traced.
+    fn quarantine(&mut self, pid: Pid, tid: Pid, reason: &str) {
+        log::error!("[sched] {} panicked: {}, quarantined", tid, reason);
+        self.emit(tid, Event::Quarantined(String::from(reason)));
+        self.tasks.remove(&tid);
+        if let Some(process) = self.processes.get(&pid) {
+            for (at, (saved_insn, _)) in process.borrow().breakpoints.iter() {
+                let data = *saved_insn as *mut std::ffi::c_void;
+                let _ = ptrace::write(tid, *at as ptrace::AddressType, data);
+            }
+        }
+        if let Err(err) = detach_stopped(tid) {
+            log::warn!("[sched] failed to detach {}: {:?}", tid, err);
+        }
+        dying::reaped(tid);
+        ticks::exited(tid);
+        let kept: HashSet<Pid> = self
+            .tasks
+            .values()
+            .map(Task::getpid)
+            .chain(self.processes.keys().cloned())
+            .filter(|other| *other != pid)
+            .collect();
+        self.detach_tasks(None, kept, true);
+    }
+    /// `task` stopped by ptrace `event` unknown to the tracer, which is
+    /// handled according to `UnknownEventPolicy`.
+    fn unknown_event(&mut self, task: TracedTask, event: i32) {
+        let (pid, tid) = (task.getpid(), task.gettid());
+        let policy = self.unknown_event_policy;
+        log::warn!(
+            "[sched] {} unknown ptrace event {}, {:?}",
+            tid,
+            event,
+            policy
+        );
+        self.emit(tid, Event::UnknownPtraceEvent(event));
+        match policy {
+            UnknownEventPolicy::Ignore => {
+                self.tasks.insert(tid, task);
+                self.blocked_queue.push_back(tid);
+                let _ = ptrace::cont(tid, None);
+            }
+            UnknownEventPolicy::StopTask => {
+                let reason = format!("unknown ptrace event {}", event);
+                self.quarantine(pid, tid, &reason);
+            }
+            UnknownEventPolicy::Abort => {
+                panic!("{} unknown ptrace event `{}`", tid, event)
+            }
+        }
+    }
+    /// take ownership of `task`'s process, if it is a new one
+    fn own_process(&mut self, task: &TracedTask) {
+        let pid = task.getpid();
+        match self.processes.get(&pid) {
+            Some(process) if Rc::ptr_eq(process, &task.process) => (),
+            _ => {
+                self.processes.insert(pid, task.process.clone());
+                let ppid = task.getppid();
+                let parent =
+                    Some(ppid).filter(|p| self.processes.contains_key(p));
+                self.lifecycles.spawned(pid, parent);
+            }
+        }
+    }
     /// add a new task into `Scheduler` blocked queue
     fn add_blocked(&mut self, task: TracedTask) {
         let tid = Task::gettid(&task);
@@ -82,23 +400,60 @@ impl<G> SchedWait<G> {
         if !is_seccomp {
             // signal is to be delivered
             task.signal_to_deliver = None;
+        } else if task.syscall_entered_at.is_some() {
+            task.syscall_resumed_at = Some(Timestamp::now());
         }
 
         if let Some(signo) = sig {
-            if signo == signal::SIGSEGV || signo == signal::SIGILL {
+            // faults of managed runtimes are expected, see `runtimes`
+            let runtime = task.process.borrow().runtime;
+            let expected =
+                runtime.map_or(false, |rt| rt.is_runtime_signal(signo));
+            if (signo == signal::SIGSEGV || signo == signal::SIGILL)
+                && !expected
+            {
                 debug::show_fault_context(&task, signo);
             }
         }
 
-        self.task_tree.insert(tid, task.getppid());
-        self.tasks.insert(tid, task);
-        self.run_queue.push_front(tid);
-
         if is_seccomp {
             let _ = ptrace::syscall(tid);
         } else {
-            let _ = ptrace::cont(tid, sig);
+            let _ = resume_task(&mut task, sig);
         }
+
+        self.task_tree.insert(tid, task.getppid());
+        self.own_process(&task);
+        self.tasks.insert(tid, task);
+        self.enqueue(tid);
+    }
+    /// put a runnable task into run queue, according to `SchedPolicy`
+    fn enqueue(&mut self, tid: Pid) {
+        match self.policy {
+            SchedPolicy::Fifo => self.run_queue.push_back(tid),
+            SchedPolicy::RoundRobin { quantum } => match self.last_run {
+                Some((last, n)) if last == tid && n >= quantum => {
+                    self.last_run = None;
+                    self.run_queue.push_back(tid);
+                }
+                _ => self.run_queue.push_front(tid),
+            },
+            SchedPolicy::Priority => {
+                let priority = self.task_priority(tid);
+                let pos = self
+                    .run_queue
+                    .iter()
+                    .position(|t| self.task_priority(*t) < priority)
+                    .unwrap_or_else(|| self.run_queue.len());
+                self.run_queue.insert(pos, tid);
+            }
+        }
+    }
+    fn task_priority(&self, tid: Pid) -> TaskPriority {
+        self.tasks
+            .get(&tid)
+            .map(|task| task.task_priority())
+            .unwrap_or_default()
     }
     /// remove a task from `Scheduler`
     fn remove(&mut self, task: &mut TracedTask) {
@@ -109,7 +464,203 @@ impl<G> SchedWait<G> {
     ///
     /// NB: `SchedWait` find out next ready task based on `waitpid`
     fn next(&mut self) -> Option<TracedTask> {
-        ptracer_get_next(self)
+        let task = ptracer_get_next(self)?;
+        if let Some(detector) = self.idle.as_mut() {
+            detector.activity();
+        }
+        let tid = task.gettid();
+        self.last_run = match self.last_run {
+            Some((last, n)) if last == tid => Some((tid, n + 1)),
+            _ => Some((tid, 1)),
+        };
+        Some(task)
+    }
+    /// save the session to `path`, `task` being stopped out of the
+    /// scheduler, see `session`
+    fn save_session(&self, task: &TracedTask, path: &Path) -> Result<()> {
+        if unistd::getpid() == Pid::from_raw(1) {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "tracees of a pid namespace die with the tracer",
+            ));
+        }
+        SessionState::new(self.tasks.values().chain(Some(task)))?.save(path)
+    }
+    /// detach all tasks stopped, so that they are not run untraced, and
+    /// forget them. as when detached running, breakpoints are restored and
+    /// syscalls skipped at their sysemu entry rewound, see
+    /// `detach_tasks`: the session (or freeze) does not keep them.
+    fn detach_all(&mut self, task: TracedTask) {
+        let pids: HashSet<Pid> = self
+            .processes
+            .keys()
+            .cloned()
+            .chain(Some(task.getpid()))
+            .collect();
+        // pending, the group stops once detached, whatever the stop
+        for pid in pids {
+            let _ = signal::kill(pid, signal::SIGSTOP);
+        }
+        self.detach_tasks(Some(task), HashSet::new(), true);
+        // children auto-attached not reported yet
+        let detach = |tid: Pid| {
+            if let Err(err) = detach_stopped(tid) {
+                log::warn!("[sched] failed to detach {}: {:?}", tid, err);
+            }
+        };
+        let flags = WaitPidFlag::WNOHANG | WaitPidFlag::__WALL;
+        loop {
+            match wait::waitpid(None, Some(flags)) {
+                Ok(WaitStatus::StillAlive) | Err(_) => break,
+                Ok(WaitStatus::Exited(..)) | Ok(WaitStatus::Signaled(..)) => (),
+                Ok(status) => status.pid().into_iter().for_each(detach),
+            }
+        }
+        log::info!("[sched] detached all tasks");
+        self.tasks.clear();
+        self.task_tree.clear();
+        self.run_queue.clear();
+        self.blocked_queue.clear();
+        self.processes.clear();
+    }
+    /// detach all tasks to keep running untraced, and forget them, but
+    /// tasks of processes with a seccomp filter, passed through instead,
+    /// see `detach`. returns `task` if kept traced.
+    fn detach_all_running(&mut self, task: TracedTask) -> Option<TracedTask> {
+        let kept: HashSet<Pid> = self
+            .processes
+            .keys()
+            .cloned()
+            .chain(Some(task.getpid()))
+            .filter(|pid| detach::is_filtered(*pid))
+            .collect();
+        let current = self.detach_tasks(Some(task), kept.clone(), false);
+        let processes = self.processes.values();
+        for process in processes.chain(current.iter().map(|t| &t.process)) {
+            process.borrow_mut().passthrough = Some(detach::DETACHED);
+        }
+        if !kept.is_empty() {
+            log::warn!(
+                "[sched] processes {:?} are seccomp filtered, passed through",
+                kept
+            );
+        }
+        current
+    }
+    /// detach tasks (and `task`) but those of processes `kept`, their
+    /// breakpoints restored first, see `TracedTask::detach`. children of
+    /// forks and clones stopped at are detached too, stopped with `stop`.
+    /// returns `task` if kept traced.
+    fn detach_tasks(
+        &mut self,
+        task: Option<TracedTask>,
+        kept: HashSet<Pid>,
+        stop: bool,
+    ) -> Option<TracedTask> {
+        let tids: Vec<Pid> = self
+            .tasks
+            .iter()
+            .filter(|(_, task)| !kept.contains(&task.getpid()))
+            .map(|(tid, _)| *tid)
+            .collect();
+        for tid in &tids {
+            let _ = interrupt(*tid);
+        }
+        // auto-attached children not reported yet, and their parent
+        let mut children: Vec<(Pid, ProcessRef)> = Vec::new();
+        let mut stopped: Vec<TracedTask> = Vec::new();
+        let mut current = task;
+        if current
+            .as_ref()
+            .map_or(false, |t| !kept.contains(&t.getpid()))
+        {
+            stopped.extend(current.take());
+        }
+        for tid in tids {
+            let mut task = match self.tasks.remove(&tid) {
+                Some(task) => task,
+                None => continue,
+            };
+            match wait::waitpid(Some(tid), Some(WaitPidFlag::__WALL)) {
+                Ok(WaitStatus::PtraceSyscall(_)) => {
+                    let nr = ptrace::getevent(tid).unwrap_or(0) as i32;
+                    task.state = TaskState::Syscall(SyscallNo::from(nr));
+                }
+                Ok(WaitStatus::Stopped(_, sig)) => {
+                    task.state = TaskState::Stopped(sig);
+                }
+                Ok(WaitStatus::PtraceEvent(_, _, event)) => {
+                    // children of forks and clones are to be detached
+                    task.state = ptrace_event(event)
+                        .and_then(|event| {
+                            ptrace_event_state(&task, event).ok()?
+                        })
+                        .unwrap_or(TaskState::Running);
+                }
+                Ok(_) | Err(_) => {
+                    dying::reaped(tid);
+                    ticks::exited(tid);
+                    continue;
+                }
+            }
+            stopped.push(task);
+        }
+        for task in &stopped {
+            match task.state {
+                TaskState::Clone(child) | TaskState::Fork(child) => {
+                    children.push((child, task.process.clone()))
+                }
+                _ => (),
+            }
+        }
+
+        // breakpoints are restored before any task is detached
+        let mut restored: HashSet<Pid> = HashSet::new();
+        for task in &stopped {
+            if restored.insert(task.getpid()) {
+                let process = task.process.borrow();
+                for (at, (saved_insn, _)) in process.breakpoints.iter() {
+                    let rptr = Remoteable::remote(*at as *mut u64).unwrap();
+                    let _ = task.poke(rptr, saved_insn);
+                }
+            }
+        }
+        for (child, process) in &children {
+            // initial stop of the child
+            let _ = wait::waitpid(Some(*child), Some(WaitPidFlag::__WALL));
+            for (at, (saved_insn, _)) in process.borrow().breakpoints.iter() {
+                let data = *saved_insn as *mut std::ffi::c_void;
+                let _ = ptrace::write(*child, *at as ptrace::AddressType, data);
+            }
+        }
+        let mut detached = 0;
+        for mut task in stopped {
+            let tid = task.gettid();
+            match task.detach() {
+                Ok(()) => detached += 1,
+                Err(err) => {
+                    log::warn!("[sched] failed to detach {}: {:?}", tid, err)
+                }
+            }
+            task.process.borrow_mut().breakpoints.clear();
+        }
+        let sig = if stop { signal::SIGSTOP as u64 } else { 0 };
+        for (child, _) in children {
+            match ptrace_request(libc::PTRACE_DETACH, child, sig) {
+                Ok(()) => detached += 1,
+                Err(err) => {
+                    log::warn!("[sched] failed to detach {}: {:?}", child, err)
+                }
+            }
+        }
+
+        let tasks = &self.tasks;
+        self.run_queue.retain(|tid| tasks.contains_key(tid));
+        self.blocked_queue.retain(|tid| tasks.contains_key(tid));
+        self.task_tree.retain(|tid, _| tasks.contains_key(tid));
+        self.processes.retain(|pid, _| kept.contains(pid));
+        log::info!("[sched] detached {} tasks", detached);
+        current
     }
     /// return number of tasks in `Scheduler`
     fn size(&self) -> usize {
@@ -121,10 +672,153 @@ impl<G> SchedWait<G> {
     /// task and schedule/run it, unless there's no
     /// more task left, i.e.: when all tasks are exited.
     pub fn run_all(&mut self) -> i32 {
+        let calibration = ClockCalibration::now();
+        log::info!("[sched] clock calibration: {:?}", calibration);
+        self.event_cbs.borrow_mut().emit(
+            unistd::getpid(),
+            calibration.raw,
+            Event::Calibration(calibration),
+        );
         sched_wait_event_loop(self)
     }
 }
 
+/// `SECCOMP_RET_DATA` of seccomp stops reverie's filter did not cause, i.e.:
+/// from a filter installed by the tracee, or with seccomp not supported.
+pub const SECCOMP_RET_DATA_UNFILTERED: i64 = 0x7fff;
+
+/// `PTRACE_EVENT_STOP`, reported instead of `SIGSTOP` for tasks attached
+/// by `PTRACE_SEIZE`, including auto-attached children.
+pub const PTRACE_EVENT_STOP: i32 = 128;
+
+const PTRACE_SEIZE: libc::c_uint = 0x4206;
+const PTRACE_INTERRUPT: libc::c_uint = 0x4207;
+const PTRACE_LISTEN: libc::c_uint = 0x4208;
+
+/// ptrace options set on all tracees
+pub fn ptrace_options() -> ptrace::Options {
+    ptrace::Options::PTRACE_O_TRACEEXEC
+        | ptrace::Options::PTRACE_O_EXITKILL
+        | ptrace::Options::PTRACE_O_TRACECLONE
+        | ptrace::Options::PTRACE_O_TRACEFORK
+        | ptrace::Options::PTRACE_O_TRACEVFORK
+        | ptrace::Options::PTRACE_O_TRACEVFORKDONE
+        | ptrace::Options::PTRACE_O_TRACEEXIT
+        | ptrace::Options::PTRACE_O_TRACESECCOMP
+        | ptrace::Options::PTRACE_O_TRACESYSGOOD
+}
+
+fn ptrace_request(req: libc::c_uint, pid: Pid, data: u64) -> nix::Result<()> {
+    let res = unsafe {
+        libc::ptrace(req, pid.as_raw(), std::ptr::null_mut::<u8>(), data)
+    };
+    nix::errno::Errno::result(res).map(drop)
+}
+
+/// attach `pid` with `PTRACE_SEIZE` and `ptrace_options`.
+///
+/// `pid` must be in group-stop, i.e.: stopped itself by `SIGSTOP`. the
+/// tracee is continued by `SIGCONT`, returns once it is in the `SIGCONT`
+/// signal-delivery-stop, which is to be suppressed.
+pub fn seize(pid: Pid) -> nix::Result<()> {
+    ptrace_request(PTRACE_SEIZE, pid, ptrace_options().bits() as u64)?;
+    signal::kill(pid, signal::SIGCONT)?;
+    loop {
+        match wait::waitpid(Some(pid), None)? {
+            WaitStatus::Stopped(_, signal::SIGCONT) => return Ok(()),
+            // re-trapped group-stop, or woken up by `SIGCONT`
+            WaitStatus::PtraceEvent(_, _, PTRACE_EVENT_STOP) => {
+                ptrace::cont(pid, None)?
+            }
+            // signal received before `SIGCONT`
+            WaitStatus::Stopped(_, sig) => ptrace::cont(pid, Some(sig))?,
+            // gone before `SIGCONT`
+            otherwise => {
+                log::debug!("seize {}: unexpected {:?}", pid, otherwise);
+                return Err(nix::Error::Sys(nix::errno::Errno::ESRCH));
+            }
+        }
+    }
+}
+
+/// fork a tracee running `tracee`, which stops itself by `SIGSTOP` before
+/// exec'ing its program, and `seize` it. the tracee is left stopped, to be
+/// continued by the caller. `tracee` returning (i.e.: if exec failed) is
+/// the exit of the child.
+pub fn spawn_stopped<F>(tracee: F) -> Result<Pid>
+where
+    F: FnOnce() -> Result<i32>,
+{
+    let child = match unistd::fork() {
+        Ok(unistd::ForkResult::Child) => match tracee() {
+            Ok(code) => std::process::exit(code),
+            Err(err) => panic!("tracee failed with error: {:?}", err),
+        },
+        Ok(unistd::ForkResult::Parent { child }) => child,
+        Err(err) => return Err(Error::new(ErrorKind::Other, err)),
+    };
+    // its stops would be reported to the outer tracer only
+    if let Err(err) = nesting::check_forked(child) {
+        let _ = signal::kill(child, signal::SIGKILL);
+        return Err(err);
+    }
+    match wait::waitpid(Some(child), Some(WaitPidFlag::WUNTRACED)) {
+        Ok(WaitStatus::Stopped(_, signal::SIGSTOP)) => (),
+        otherwise => {
+            let reason = format!(
+                "tracee {}: expect SIGSTOP, got {:?}",
+                child, otherwise
+            );
+            return Err(Error::new(ErrorKind::Other, reason));
+        }
+    }
+    // seize, so that auto-attached children start with `PTRACE_EVENT_STOP`
+    // rather than a (racy) `SIGSTOP`.
+    seize(child).map_err(|e| Error::new(ErrorKind::Other, e))?;
+    Ok(child)
+}
+
+/// attach running `tid` with `PTRACE_SEIZE` and `options`, and interrupt
+/// it. its `PTRACE_EVENT_STOP` is handled by the scheduler, as the initial
+/// stop of a new task.
+pub fn seize_running(tid: Pid, options: ptrace::Options) -> nix::Result<()> {
+    ptrace_request(PTRACE_SEIZE, tid, options.bits() as u64)?;
+    ptrace_request(PTRACE_INTERRUPT, tid, 0)
+}
+
+/// interrupt running `tid`, whose `PTRACE_EVENT_STOP` is handled by the
+/// scheduler as any spurious stop: it is continued.
+pub fn interrupt(tid: Pid) -> nix::Result<()> {
+    ptrace_request(PTRACE_INTERRUPT, tid, 0)
+}
+
+/// run `f` on `tid` once in the `PTRACE_EVENT_STOP` of `seize_running`,
+/// and interrupt it again, for the scheduler
+pub fn with_interrupted<R, F: FnOnce() -> R>(tid: Pid, f: F) -> nix::Result<R> {
+    loop {
+        match wait::waitpid(Some(tid), Some(WaitPidFlag::__WALL))? {
+            WaitStatus::PtraceEvent(_, _, PTRACE_EVENT_STOP) => break,
+            // signal received before the interrupt
+            WaitStatus::Stopped(_, sig) => ptrace::cont(tid, Some(sig))?,
+            _ => return Err(nix::Error::Sys(nix::errno::Errno::ESRCH)),
+        }
+    }
+    let res = f();
+    ptrace::cont(tid, None)?;
+    ptrace_request(PTRACE_INTERRUPT, tid, 0)?;
+    Ok(res)
+}
+
+// detach `tid` in stopped state, stopping it first if it is running.
+fn detach_stopped(tid: Pid) -> nix::Result<()> {
+    let sigstop = signal::SIGSTOP as u64;
+    ptrace_request(libc::PTRACE_DETACH, tid, sigstop).or_else(|_| {
+        signal::kill(tid, signal::SIGSTOP)?;
+        wait::waitpid(Some(tid), Some(WaitPidFlag::__WALL))?;
+        ptrace_request(libc::PTRACE_DETACH, tid, sigstop)
+    })
+}
+
 // tracee received group stop
 // NB: must be call after waitpid returned STOPPED status.
 // see `man ptrace`, `Group-stop` for more details.
@@ -140,150 +834,310 @@ fn is_ptrace_group_stop(pid: Pid, sig: signal::Signal) -> bool {
     }
 }
 
-fn ptrace_event(event: i32) -> ptrace::Event {
+// `None` if `event` is unknown
+fn ptrace_event(event: i32) -> Option<ptrace::Event> {
     match event {
-        1 => ptrace::Event::PTRACE_EVENT_FORK,
-        2 => ptrace::Event::PTRACE_EVENT_VFORK,
-        3 => ptrace::Event::PTRACE_EVENT_CLONE,
-        4 => ptrace::Event::PTRACE_EVENT_EXEC,
-        5 => ptrace::Event::PTRACE_EVENT_VFORK_DONE,
-        6 => ptrace::Event::PTRACE_EVENT_EXIT,
-        7 => ptrace::Event::PTRACE_EVENT_SECCOMP,
-        _ => panic!("unknown ptrace event `{}`", event),
+        1 => Some(ptrace::Event::PTRACE_EVENT_FORK),
+        2 => Some(ptrace::Event::PTRACE_EVENT_VFORK),
+        3 => Some(ptrace::Event::PTRACE_EVENT_CLONE),
+        4 => Some(ptrace::Event::PTRACE_EVENT_EXEC),
+        5 => Some(ptrace::Event::PTRACE_EVENT_VFORK_DONE),
+        6 => Some(ptrace::Event::PTRACE_EVENT_EXIT),
+        7 => Some(ptrace::Event::PTRACE_EVENT_SECCOMP),
+        _ => None,
     }
 }
 
+// seccomp stop not caused by reverie's filter: the syscall is traced as any
+// other, so that a filter mismatch degrades rather than kills the session.
+fn unfiltered_syscall(tid: Pid, syscall: SyscallNo) {
+    log::warn!("[sched] {} unfiltered syscall {:?}", tid, syscall);
+    let state = reverie_global_state()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    state
+        .stats
+        .nr_syscalls_unfiltered
+        .fetch_add(1, Ordering::SeqCst);
+}
+
+// decode `TaskState` of ptrace `event` stop, `None` if the task is dying.
+fn ptrace_event_state(
+    task: &TracedTask,
+    event: ptrace::Event,
+) -> nix::Result<Option<TaskState>> {
+    let (pid, tid) = (task.getpid(), task.gettid());
+    let getevent = || dying::retry_ptrace(pid, tid, || ptrace::getevent(tid));
+    let state = match event {
+        ptrace::Event::PTRACE_EVENT_EXEC => Some(TaskState::Exec),
+        ptrace::Event::PTRACE_EVENT_CLONE => getevent()?
+            .map(|new_pid| TaskState::Clone(Pid::from_raw(new_pid as i32))),
+        ptrace::Event::PTRACE_EVENT_FORK
+        | ptrace::Event::PTRACE_EVENT_VFORK => getevent()?
+            .map(|new_pid| TaskState::Fork(Pid::from_raw(new_pid as i32))),
+        ptrace::Event::PTRACE_EVENT_VFORK_DONE => Some(TaskState::VforkDone),
+        ptrace::Event::PTRACE_EVENT_SECCOMP => match getevent()? {
+            Some(data) => {
+                dying::retry_ptrace(pid, tid, || ptrace::getregs(tid))?.map(
+                    |regs| {
+                        let nr = regs.orig_rax as i32;
+                        if data == SECCOMP_RET_DATA_UNFILTERED {
+                            unfiltered_syscall(tid, SyscallNo::from(nr));
+                        }
+                        if nr == SYS_exit_group as i32 {
+                            dying::mark_dying(pid);
+                        }
+                        TaskState::Seccomp(SyscallNo::from(nr))
+                    },
+                )
+            }
+            None => None,
+        },
+        ptrace::Event::PTRACE_EVENT_EXIT => {
+            dying::mark_dying(tid);
+            let exit_code = getevent()?.unwrap_or(0);
+            Some(TaskState::Exited(tid, exit_code as i32))
+        }
+    };
+    Ok(state)
+}
+
 fn ptracer_get_next<G>(tasks: &mut SchedWait<G>) -> Option<TracedTask> {
-    let mut retry = true;
-    while retry {
+    loop {
         let tid = tasks
             .run_queue
             .pop_front()
             .or_else(|| tasks.blocked_queue.pop_front())?;
-        loop {
-            let status = wait::waitpid(Some(tid), Some(WaitPidFlag::WNOHANG));
-            retry = status == Ok(WaitStatus::StillAlive);
-            if !retry {
-                log::trace!("[sched] {} {:?}", tid, status);
+        // a panic waiting for one task must not kill the whole tree.
+        match catch_panic(|| ptracer_wait(tasks, tid)) {
+            Ok(Some(task)) => return Some(task),
+            Ok(None) => (),
+            Err(panic) => {
+                let pid = tasks.tasks.get(&tid).map_or(tid, |t| t.getpid());
+                tasks.quarantine(pid, tid, &panic_message(&*panic));
             }
-            match status {
-                Ok(WaitStatus::StillAlive) => {
-                    tasks.blocked_queue.push_back(tid);
-                    break;
+        }
+    }
+}
+
+// task `tid` waited for, `None` if it is unknown (i.e.: reaped already by
+// a racing wait): it is forgotten, detached if still in a ptrace stop.
+fn take_waited<G>(
+    tasks: &mut SchedWait<G>,
+    tid: Pid,
+    stopped: bool,
+) -> Option<TracedTask> {
+    let task = tasks.tasks.remove(&tid);
+    if task.is_none() {
+        log::warn!("[sched] {} unknown, dropped", tid);
+        if stopped {
+            let _ = ptrace::detach(tid);
+        }
+        dying::reaped(tid);
+    }
+    task
+}
+
+// wait for `tid` (once, or until it isn't in group-stop), `None` if it is
+// not to be run, i.e.: still running, gone, or quarantined.
+fn ptracer_wait<G>(tasks: &mut SchedWait<G>, tid: Pid) -> Option<TracedTask> {
+    loop {
+        let status = wait::waitpid(Some(tid), Some(WaitPidFlag::WNOHANG));
+        if status != Ok(WaitStatus::StillAlive) {
+            log::trace!("[sched] {} {:?}", tid, status);
+        }
+        match status {
+            Ok(WaitStatus::StillAlive) => {
+                tasks.blocked_queue.push_back(tid);
+                if tasks.run_queue.is_empty() {
+                    tasks.reap_unknown();
+                    tasks.poll_idle();
                 }
-                Ok(WaitStatus::Signaled(_pid, signal, _core)) => {
-                    let mut task = tasks
-                        .tasks
-                        .remove(&tid)
-                        .unwrap_or_else(|| panic!("unknown pid {:}", tid));
-                    task.state = TaskState::Signaled(signal);
-                    return Some(task);
+                return None;
+            }
+            Ok(WaitStatus::Signaled(_pid, signal, _core)) => {
+                let mut task = take_waited(tasks, tid, false)?;
+                dying::reaped(tid);
+                task.state = TaskState::Signaled(signal);
+                return Some(task);
+            }
+            Ok(WaitStatus::Continued(_)) => {
+                return take_waited(tasks, tid, false);
+            }
+            Ok(WaitStatus::PtraceEvent(_, sig, PTRACE_EVENT_STOP)) => {
+                if sig != signal::SIGTRAP {
+                    // group-stop: keep stopped until `SIGCONT`, which is
+                    // reported by another `PTRACE_EVENT_STOP`.
+                    let _ = ptrace_request(PTRACE_LISTEN, tid, 0);
+                    if let Some(task) = tasks.tasks.get(&tid) {
+                        tasks.lifecycles.stopped(task.getpid(), true);
+                    }
+                    tasks.blocked_queue.push_back(tid);
+                    return None;
                 }
-                Ok(WaitStatus::Continued(_)) => {
-                    let task = tasks
-                        .tasks
-                        .remove(&tid)
-                        .unwrap_or_else(|| panic!("unknown pid {:}", tid));
-                    return Some(task);
+                // initial stop of a new task, `PTRACE_INTERRUPT`, or
+                // end of group-stop.
+                let mut task = take_waited(tasks, tid, true)?;
+                if task.state != TaskState::Ready {
+                    task.state = TaskState::Running;
                 }
-                Ok(WaitStatus::PtraceEvent(_, sig, event))
-                    if sig == signal::SIGTRAP =>
-                {
-                    let mut task = tasks
-                        .tasks
-                        .remove(&tid)
-                        .unwrap_or_else(|| panic!("unknown pid {:}", tid));
-
-                    match ptrace_event(event) {
-                        ptrace::Event::PTRACE_EVENT_EXEC => {
+                tasks.lifecycles.stopped(task.getpid(), false);
+                task.signal_to_deliver = None;
+                return Some(task);
+            }
+            Ok(WaitStatus::PtraceEvent(_, sig, event))
+                if sig == signal::SIGTRAP =>
+            {
+                let mut task = take_waited(tasks, tid, true)?;
+
+                let event = match ptrace_event(event) {
+                    Some(event) => event,
+                    None => {
+                        tasks.unknown_event(task, event);
+                        return None;
+                    }
+                };
+                let state = catch_panic(|| ptrace_event_state(&task, event))
+                    .map_err(|panic| panic_message(&*panic));
+                match state {
+                    Ok(Ok(Some(state))) => {
+                        if state == TaskState::Exec {
                             task.event_cbs = Some(tasks.event_cbs.clone());
-                            task.state = TaskState::Exec;
-                        }
-                        ptrace::Event::PTRACE_EVENT_CLONE => {
-                            let new_pid = ptrace::getevent(tid).unwrap();
-                            task.state =
-                                TaskState::Clone(Pid::from_raw(new_pid as i32));
-                        }
-                        ptrace::Event::PTRACE_EVENT_FORK
-                        | ptrace::Event::PTRACE_EVENT_VFORK => {
-                            let new_pid = ptrace::getevent(tid).unwrap();
-                            task.state =
-                                TaskState::Fork(Pid::from_raw(new_pid as i32));
-                        }
-                        ptrace::Event::PTRACE_EVENT_VFORK_DONE => {
-                            task.state = TaskState::VforkDone;
-                        }
-                        ptrace::Event::PTRACE_EVENT_SECCOMP => {
-                            let nr = ptrace::getevent(tid).unwrap() as i32;
-                            if nr == 0x7fff {
-                                panic!("unfiltered syscall: {:?}", nr);
+                            // former tid of the thread which exec'ed
+                            if let Ok(former) = ptrace::getevent(tid) {
+                                let former = Pid::from_raw(former as i32);
+                                if former != tid {
+                                    task.exec_from_thread(former);
+                                }
                             }
-                            let regs = ptrace::getregs(tid).unwrap();
-                            let nr = regs.orig_rax as i32;
-                            task.state =
-                                TaskState::Seccomp(SyscallNo::from(nr));
-                        }
-                        ptrace::Event::PTRACE_EVENT_EXIT => {
-                            let exit_code = ptrace::getevent(tid).unwrap();
-                            task.state =
-                                TaskState::Exited(tid, exit_code as i32);
                         }
-                    }
-
-                    return Some(task);
-                }
-                Ok(WaitStatus::PtraceSyscall(pid)) => {
-                    assert!(pid == tid);
-                    let mut task = tasks.tasks.remove(&tid).unwrap();
-                    let nr = ptrace::getevent(tid).unwrap() as i32;
-                    // println!("[pid = {}] got ptrace syscall posthook", pid);
-                    task.state = TaskState::Syscall(SyscallNo::from(nr));
-                    return Some(task);
-                }
-                Ok(WaitStatus::Stopped(pid, sig)) => {
-                    // ignore group-stop
-                    if !is_ptrace_group_stop(pid, sig) {
-                        // NB: we use TaskState::Ready for the initial SIGSTOP
-                        let mut task = tasks
-                            .tasks
-                            .remove(&tid)
-                            .unwrap_or_else(|| panic!("unknown pid {:}", tid));
-                        if task.state != TaskState::Ready {
-                            task.state = TaskState::Stopped(sig);
+                        task.state = state;
+                        if let Some(former) = task.take_exec_tid() {
+                            tasks.reap_exec_siblings(tid, former);
                         }
-                        task.signal_to_deliver = Some(sig);
                         return Some(task);
                     }
+                    Ok(Ok(None)) => {
+                        // killed while in ptrace stop, `waitpid` shall
+                        // report its exit.
+                        log::debug!("[sched] {} is dying", tid);
+                        tasks.tasks.insert(tid, task);
+                        tasks.run_queue.push_back(tid);
+                        return None;
+                    }
+                    Ok(Err(err)) => {
+                        let reason = format!("{:?}: {:?}", event, err);
+                        tasks.quarantine(task.getpid(), tid, &reason);
+                        return None;
+                    }
+                    Err(reason) => {
+                        tasks.quarantine(task.getpid(), tid, &reason);
+                        return None;
+                    }
                 }
-                Ok(WaitStatus::Exited(pid, _retval)) => {
-                    tasks.tasks.remove(&pid);
-                    retry = true;
-                    break;
-                }
-                Err(nix::Error::Sys(nix::errno::Errno::ECHILD)) => {
-                    // a non-awaited child
-                    log::debug!("[sched] waitpid {} => ECHILD", tid);
-                    retry = true;
-                    break;
+            }
+            Ok(WaitStatus::PtraceSyscall(_)) => {
+                let mut task = take_waited(tasks, tid, true)?;
+                let nr = ptrace::getevent(tid).unwrap_or(0) as i32;
+                task.state = TaskState::Syscall(SyscallNo::from(nr));
+                return Some(task);
+            }
+            Ok(WaitStatus::Stopped(pid, sig)) => {
+                // ignore group-stop
+                if !is_ptrace_group_stop(pid, sig) {
+                    // NB: we use TaskState::Ready for the initial SIGSTOP
+                    let mut task = take_waited(tasks, tid, true)?;
+                    if task.state != TaskState::Ready {
+                        task.state = TaskState::Stopped(sig);
+                    }
+                    task.signal_to_deliver = Some(sig);
+                    return Some(task);
                 }
-                otherwise => panic!("unknown status: {:?}", otherwise),
+            }
+            Ok(WaitStatus::Exited(pid, _retval)) => {
+                tasks.tasks.remove(&pid);
+                dying::reaped(pid);
+                return None;
+            }
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => (),
+            Err(nix::Error::Sys(nix::errno::Errno::ECHILD)) => {
+                // a non-awaited child, or already reaped.
+                log::debug!("[sched] waitpid {} => ECHILD", tid);
+                tasks.tasks.remove(&tid);
+                dying::reaped(tid);
+                return None;
+            }
+            otherwise => {
+                // i.e.: an event stop without `SIGTRAP`, the task is let go
+                log::warn!(
+                    "[sched] {} unexpected {:?}, dropped",
+                    tid,
+                    otherwise
+                );
+                tasks.tasks.remove(&tid);
+                let _ = ptrace::detach(tid);
+                dying::reaped(tid);
+                return None;
             }
         }
     }
-    None
 }
 
 pub fn sched_wait_event_loop<G>(sched: &mut SchedWait<G>) -> i32 {
     let mut exit_code = 0i32;
     while let Some(task) = sched.next() {
-        let tid = task.gettid();
-        let run_result = run_task(Arc::clone(&sched.global_state), task);
+        workers::reap();
+        ebpf::poll();
+        control::serve(&task, |pid| sched.process(pid).is_some());
+        if let Some(detach) = control::take_detach() {
+            let saved = sched.save_session(&task, &detach.session);
+            let detached = saved.is_ok();
+            detach.reply(saved);
+            if detached {
+                sched.detach_all(task);
+                break;
+            }
+        }
+        if task_limits::take_freeze() {
+            sched.detach_all(task);
+            break;
+        }
+        let task = if detach::take_detach() {
+            match sched.detach_all_running(task) {
+                Some(task) => task,
+                None if sched.size() == 0 => break,
+                None => continue,
+            }
+        } else {
+            task
+        };
+        let (pid, tid) = (task.getpid(), task.gettid());
+        let (state, handled) = (task.state, Instant::now());
+        // a panic handling one task must not kill the whole tree.
+        let global_state = Arc::clone(&sched.global_state);
+        let run_result = match catch_panic(|| run_task(global_state, task)) {
+            Ok(run_result) => run_result,
+            Err(panic) => {
+                sched.quarantine(pid, tid, &panic_message(&*panic));
+                continue;
+            }
+        };
+        overhead::stopped(tid, &state, handled.elapsed());
+        if tid == pid {
+            if let Ok(RunTask::Exited(_)) | Err(_) = run_result {
+                sched.process_exited(pid);
+            }
+        }
+        sched.check_reaped(pid);
         match run_result {
             Ok(RunTask::Exited(_code)) => exit_code = _code,
             Ok(RunTask::Blocked(task1)) => {
                 sched.add_blocked(task1);
             }
-            Ok(RunTask::Runnable(task1)) => {
+            Ok(RunTask::Runnable(mut task1)) => {
+                if let Some(former) = task1.take_exec_tid() {
+                    sched.reap_exec_siblings(task1.getpid(), former);
+                }
                 sched.add_and_schedule(task1);
             }
             Ok(RunTask::Forked(parent, child)) => {
@@ -338,19 +1192,299 @@ pub fn sched_wait_event_loop<G>(sched: &mut SchedWait<G>) -> i32 {
                 //
                 // Apparently this applies to kernel 4.15 as well
                 //
+                // the exit could have been consumed already by the failed
+                // task, in which case the task is reaped, or resumed.
                 let status = wait::waitpid(Some(tid), None);
                 log::trace!("[sched] {} {:?}", tid, status);
+                match status {
+                    Ok(WaitStatus::PtraceEvent(_, signal::SIGTRAP, 6)) => {
+                        //
+                        // NB: we *MUST* let the task to run
+                        // this is WHY this ptrace BUG matters, after all.
+                        //
+                        let _ = ptrace::detach(tid);
+                    }
+                    Ok(WaitStatus::Exited(..))
+                    | Ok(WaitStatus::Signaled(..))
+                    | Err(nix::Error::Sys(nix::errno::Errno::ECHILD)) => {
+                        dying::reaped(tid);
+                    }
+                    // still traced, though it can't be run: let go
+                    otherwise => {
+                        log::warn!(
+                            "[sched] {} expect exit, got: {:?}, dropped",
+                            tid,
+                            otherwise
+                        );
+                        let _ = ptrace::detach(tid);
+                        dying::reaped(tid);
+                    }
+                }
+            }
+        }
+    }
+    workers::finish();
+    exit_code
+}
+
+#[test]
+fn sched_policy_from_str() {
+    assert_eq!("fifo".parse::<SchedPolicy>().ok(), Some(SchedPolicy::Fifo));
+    assert_eq!(
+        "rr:4".parse::<SchedPolicy>().ok(),
+        Some(SchedPolicy::RoundRobin { quantum: 4 })
+    );
+    assert!("rr:0".parse::<SchedPolicy>().is_err());
+    assert!("lifo".parse::<SchedPolicy>().is_err());
+    assert_eq!(
+        "stop-task".parse::<UnknownEventPolicy>().ok(),
+        Some(UnknownEventPolicy::StopTask)
+    );
+    assert!("stop".parse::<UnknownEventPolicy>().is_err());
+}
+
+#[test]
+fn poisoned_state_sanity_check() {
+    // a handler panicking with the state locked poisons it
+    let panic = catch_panic(|| {
+        let _state = reverie_global_state().lock();
+        panic!("handler panicked");
+    });
+    assert!(panic.is_err());
+    assert!(reverie_global_state().is_poisoned());
+    // later handlers recover it
+    let unfiltered = || {
+        let state = reverie_global_state()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        state.stats.nr_syscalls_unfiltered.load(Ordering::SeqCst)
+    };
+    let before = unfiltered();
+    unfiltered_syscall(nix::unistd::getpid(), SyscallNo::SYS_getpid);
+    assert!(unfiltered() > before);
+}
+
+#[test]
+fn fork_storm_sanity_check() {
+    // children racing the scheduler: reaped (or never added) before their
+    // stops are waited for, they are unknown, and must not abort it.
+    let cbs = TaskEventCB::new(
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+    );
+    let mut sched: SchedWait<i32> = SchedWait::new(cbs, 0);
+    let mut traced = Vec::new();
+    for k in 0..96 {
+        let child = match unistd::fork().unwrap() {
+            unistd::ForkResult::Child => unsafe {
+                if k % 3 == 2 {
+                    libc::raise(libc::SIGSTOP);
+                }
+                libc::_exit(0)
+            },
+            unistd::ForkResult::Parent { child } => child,
+        };
+        match k % 3 {
+            0 => signal::kill(child, signal::SIGKILL).unwrap(),
+            // stopped in `PTRACE_EVENT_EXIT`
+            2 => {
+                let status = wait::waitpid(child, Some(WaitPidFlag::WUNTRACED));
                 assert_eq!(
                     status,
-                    Ok(WaitStatus::PtraceEvent(tid, signal::SIGTRAP, 6))
+                    Ok(WaitStatus::Stopped(child, signal::SIGSTOP))
                 );
-                //
-                // NB: we *MUST* let the task to run
-                // this is WHY this ptrace BUG matters, after all.
-                //
-                let _ = ptrace::detach(tid);
+                seize(child).unwrap();
+                ptrace::cont(child, None).unwrap();
+                traced.push(child);
             }
+            _ => (),
         }
+        sched.run_queue.push_back(child);
     }
-    exit_code
+    assert!(ptracer_get_next(&mut sched).is_none());
+    assert!(sched.tasks.is_empty());
+    // detached, the traced children exit
+    for child in traced {
+        let status = wait::waitpid(child, None);
+        assert_eq!(status, Ok(WaitStatus::Exited(child, 0)));
+    }
+}
+
+#[test]
+fn seize_sanity_check() {
+    // children of a seized tracee start in `PTRACE_EVENT_STOP`
+    let tracee = spawn_stopped(|| unsafe {
+        libc::raise(libc::SIGSTOP);
+        let child = libc::fork();
+        if child == 0 {
+            libc::_exit(0);
+        }
+        libc::waitpid(child, std::ptr::null_mut(), 0);
+        libc::_exit(0)
+    })
+    .unwrap();
+    let all = Some(WaitPidFlag::__WALL);
+    ptrace::cont(tracee, None).unwrap();
+    let status = wait::waitpid(tracee, all);
+    assert_eq!(
+        status,
+        Ok(WaitStatus::PtraceEvent(tracee, signal::SIGTRAP, 1))
+    );
+    let child = Pid::from_raw(ptrace::getevent(tracee).unwrap() as i32);
+    let status = wait::waitpid(child, all);
+    assert_eq!(
+        status,
+        Ok(WaitStatus::PtraceEvent(
+            child,
+            signal::SIGTRAP,
+            PTRACE_EVENT_STOP
+        ))
+    );
+    let _ = signal::kill(tracee, signal::SIGKILL);
+    let _ = signal::kill(child, signal::SIGKILL);
+    let _ = wait::waitpid(child, all);
+    let _ = wait::waitpid(tracee, all);
+}
+
+#[test]
+fn exec_from_thread_sanity_check() {
+    // thread 4_000_003 of the process exec'ed, it took over the leader's
+    // tid and the other threads are gone, tasks of other processes stay.
+    let cbs = TaskEventCB::new(
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+    );
+    let mut sched: SchedWait<i32> = SchedWait::new(cbs, 0);
+    let pid = unistd::getpid();
+    let leader: TracedTask = Task::new(pid);
+    let tids: Vec<Pid> = (4_000_001..4_000_004).map(Pid::from_raw).collect();
+    for tid in &tids {
+        sched.add(leader.cloned(*tid));
+    }
+    let forked = Pid::from_raw(4_000_004);
+    sched.add(leader.forked(forked));
+    sched.blocked_queue.push_back(tids[0]);
+    sched.add(leader);
+
+    sched.reap_exec_siblings(pid, tids[2]);
+    assert_eq!(sched.tasks.len(), 2);
+    assert!(sched.tasks.contains_key(&pid));
+    assert!(sched.tasks.contains_key(&forked));
+    assert!(tids.iter().all(|tid| !sched.task_tree.contains_key(tid)));
+    assert_eq!(sched.run_queue, vec![forked, pid]);
+    assert!(sched.blocked_queue.is_empty());
+}
+
+#[test]
+fn unknown_event_sanity_check() {
+    // tasks stopped by an event unknown to the tracer, as of the policy
+    let traced = || match unistd::fork().unwrap() {
+        unistd::ForkResult::Child => unsafe {
+            libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0);
+            libc::raise(libc::SIGSTOP);
+            libc::_exit(0)
+        },
+        unistd::ForkResult::Parent { child } => {
+            let status = wait::waitpid(child, None);
+            assert_eq!(status, Ok(WaitStatus::Stopped(child, signal::SIGSTOP)));
+            child
+        }
+    };
+    let mut cbs = TaskEventCB::new(
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+    );
+    let kinds = Rc::new(RefCell::new(Vec::new()));
+    let sink = kinds.clone();
+    cbs.add_event_sink(Box::new(move |event| {
+        sink.borrow_mut().push(event.event.kind())
+    }));
+    let mut sched: SchedWait<i32> = SchedWait::new(cbs, 0);
+
+    // resumed
+    let child = traced();
+    sched.unknown_event(Task::new(child), 0x42);
+    assert!(sched.tasks.contains_key(&child));
+    assert_eq!(sched.blocked_queue, vec![child]);
+    assert_eq!(wait::waitpid(child, None), Ok(WaitStatus::Exited(child, 0)));
+    sched.tasks.clear();
+    sched.blocked_queue.clear();
+
+    // detached, stopped
+    sched.set_unknown_event_policy(UnknownEventPolicy::StopTask);
+    let child = traced();
+    sched.unknown_event(Task::new(child), 0x42);
+    assert!(sched.tasks.is_empty());
+    let _ = signal::kill(child, signal::SIGKILL);
+    let _ = wait::waitpid(child, None);
+    assert_eq!(
+        *kinds.borrow(),
+        vec![
+            EventKind::UnknownPtraceEvent,
+            EventKind::UnknownPtraceEvent,
+            EventKind::Quarantined
+        ]
+    );
+
+    sched.set_unknown_event_policy(UnknownEventPolicy::Abort);
+    let task = Task::new(unistd::getpid());
+    assert!(catch_panic(|| sched.unknown_event(task, 0x42)).is_err());
+}
+
+#[test]
+fn unfiltered_syscall_sanity_check() {
+    // the tracee's own filter traces `getppid`, with the data of no filter
+    // of reverie, it is traced as any syscall.
+    let trace_getppid = [
+        0x20,
+        (SYS_getppid as u64) << 32 | 0x0100_0015,
+        (0x7ff0_0000 | SECCOMP_RET_DATA_UNFILTERED as u64) << 32 | 0x6,
+        0x7fff_0000 << 32 | 0x6,
+    ];
+    let child = match unistd::fork().unwrap() {
+        unistd::ForkResult::Child => unsafe {
+            libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0);
+            libc::raise(libc::SIGSTOP);
+            libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0);
+            let _ = reverie_seccomp::seccomp_bpf::seccomp(&trace_getppid);
+            libc::getppid();
+            libc::_exit(0)
+        },
+        unistd::ForkResult::Parent { child } => child,
+    };
+    let status = wait::waitpid(child, None);
+    assert_eq!(status, Ok(WaitStatus::Stopped(child, signal::SIGSTOP)));
+    ptrace::setoptions(child, ptrace_options()).unwrap();
+    ptrace::cont(child, None).unwrap();
+    let status = wait::waitpid(child, None);
+    assert_eq!(
+        status,
+        Ok(WaitStatus::PtraceEvent(child, signal::SIGTRAP, 7))
+    );
+
+    let unfiltered = || {
+        let state = reverie_global_state()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        state.stats.nr_syscalls_unfiltered.load(Ordering::SeqCst)
+    };
+    let before = unfiltered();
+    let task: TracedTask = Task::new(child);
+    let state = ptrace_event_state(&task, ptrace::Event::PTRACE_EVENT_SECCOMP);
+    assert_eq!(state, Ok(Some(TaskState::Seccomp(SYS_getppid))));
+    assert!(unfiltered() > before);
+    ptrace::cont(child, None).unwrap();
+    let status = wait::waitpid(child, None);
+    assert_eq!(
+        status,
+        Ok(WaitStatus::PtraceEvent(child, signal::SIGTRAP, 6))
+    );
+    ptrace::cont(child, None).unwrap();
+    assert_eq!(wait::waitpid(child, None), Ok(WaitStatus::Exited(child, 0)));
 }
@@ -0,0 +1,289 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! policy scripts, of `--policy-script` (feature `scripting`)
+//!
+//! per syscall logic in rhai, without recompiling a tool: a script defines
+//! functions named after syscalls (without `SYS_`), i.e.:
+//!
+//! ```text
+//! fn write(event) {
+//!     if event.path.starts_with("/tmp") && event.size > 1048576 {
+//!         return "write over 1MB to /tmp";
+//!     }
+//! }
+//! ```
+//!
+//! called at syscall entry with the event, a map of `syscall`, `pid`,
+//! `tid`, `args` (6 integers), `path` (of the first path argument,
+//! absolute, or of the fd argument) and `size` (of read/write-like
+//! syscalls), both `()` if none. functions return `()` or `true` to allow
+//! the syscall, `false` or a reason to deny it (`EPERM`, flagged as a
+//! violation), or an integer, returned instead of running the syscall.
+//!
+//! scripts see nothing else, but memory of the task, by `read_string(addr)`
+//! and `read_bytes(addr, len)`. they can't do I/O, calls are limited to
+//! `MAX_OPERATIONS`, and calls failing deny the syscall.
+
+use nix::unistd::Pid;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use std::cell::Cell;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use syscalls::SyscallNo;
+
+use reverie_api::device::read_cstring;
+use reverie_api::emulate::*;
+use reverie_api::remote::{ptrace_peek_bytes, RemotePtr, SyscallArgs};
+use reverie_api::task::Task;
+
+use crate::hermetic::PATH_ARGS;
+use crate::paths::absolute_path;
+use crate::violation;
+
+/// operations a call of a script may do at most
+pub const MAX_OPERATIONS: u64 = 100_000;
+
+/// bytes `read_bytes` reads at most
+pub const MAX_READ: usize = 0x10_0000;
+
+// syscalls of an fd (first argument), with their size argument, if any
+const FD_ARGS: &[(SyscallNo, Option<usize>)] = &[
+    (SyscallNo::SYS_read, Some(2)),
+    (SyscallNo::SYS_write, Some(2)),
+    (SyscallNo::SYS_pread64, Some(2)),
+    (SyscallNo::SYS_pwrite64, Some(2)),
+    (SyscallNo::SYS_readv, None),
+    (SyscallNo::SYS_writev, None),
+    (SyscallNo::SYS_sendto, Some(2)),
+    (SyscallNo::SYS_recvfrom, Some(2)),
+    (SyscallNo::SYS_sendmsg, None),
+    (SyscallNo::SYS_recvmsg, None),
+    (SyscallNo::SYS_ftruncate, Some(1)),
+    (SyscallNo::SYS_fallocate, Some(3)),
+    (SyscallNo::SYS_fsync, None),
+    (SyscallNo::SYS_fdatasync, None),
+    (SyscallNo::SYS_fchmod, None),
+    (SyscallNo::SYS_fchown, None),
+    (SyscallNo::SYS_fstat, None),
+    (SyscallNo::SYS_lseek, None),
+    (SyscallNo::SYS_ioctl, None),
+    (SyscallNo::SYS_close, None),
+];
+
+// memory of a task, by its tid, for helpers of scripts
+struct TidMemory(Pid);
+
+impl TaskMemory for TidMemory {
+    fn read_bytes(&self, addr: u64, size: usize) -> Result<Vec<u8>> {
+        let ptr = RemotePtr::new(addr as *mut u8)
+            .ok_or_else(|| Error::from_raw_os_error(libc::EFAULT))?;
+        ptrace_peek_bytes(self.0, ptr, size)
+    }
+    fn write_bytes(&self, _addr: u64, _bytes: &[u8]) -> Result<()> {
+        Err(Error::from_raw_os_error(libc::EPERM))
+    }
+}
+
+type ScriptResult<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+fn script_error(err: Error) -> Box<EvalAltResult> {
+    err.to_string().into()
+}
+
+/// a policy script, see module doc
+pub struct ScriptPolicy {
+    engine: Engine,
+    ast: AST,
+    /// tid of the task whose syscall is decided, for helpers
+    current: Rc<Cell<Pid>>,
+}
+
+impl ScriptPolicy {
+    /// compile policy `script`
+    pub fn new(script: &str) -> Result<Self> {
+        let current = Rc::new(Cell::new(Pid::from_raw(0)));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let tid = current.clone();
+        engine.register_fn(
+            "read_string",
+            move |addr: i64| -> ScriptResult<String> {
+                read_cstring(&TidMemory(tid.get()), addr as u64)
+                    .map_err(script_error)
+            },
+        );
+        let tid = current.clone();
+        engine.register_fn(
+            "read_bytes",
+            move |addr: i64, len: i64| -> ScriptResult<Dynamic> {
+                let len = (len.max(0) as usize).min(MAX_READ);
+                TidMemory(tid.get())
+                    .read_bytes(addr as u64, len)
+                    .map(Dynamic::from_blob)
+                    .map_err(script_error)
+            },
+        );
+        let ast = engine
+            .compile(script)
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+        Ok(ScriptPolicy {
+            engine,
+            ast,
+            current,
+        })
+    }
+
+    /// compile policy script `path`
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::new(&std::fs::read_to_string(path)?)
+    }
+
+    /// syscalls the script has a function of
+    pub fn syscalls(&self) -> Vec<SyscallNo> {
+        let names: Vec<_> = self
+            .ast
+            .iter_functions()
+            .filter(|f| f.params.len() == 1)
+            .map(|f| format!("SYS_{}", f.name))
+            .collect();
+        (0..=SyscallNo::SYS_statx as i32)
+            .map(SyscallNo::from)
+            .filter(|syscall| names.contains(&format!("{:?}", syscall)))
+            .collect()
+    }
+
+    /// decide `syscall` of `task`, as a `SyscallEmulatorFn`
+    pub fn decide(
+        &self,
+        task: &dyn Task,
+        memory: &dyn TaskMemory,
+        syscall: SyscallNo,
+        args: &SyscallArgs,
+    ) -> Option<i64> {
+        let name = format!("{:?}", syscall);
+        let name = name.trim_start_matches("SYS_");
+        let event = event(task, memory, syscall, args);
+        self.current.set(task.gettid());
+        let decided = self.engine.call_fn::<Dynamic>(
+            &mut Scope::new(),
+            &self.ast,
+            name,
+            (event,),
+        );
+        let reason = match decided {
+            Ok(allowed) if allowed.is_unit() => return None,
+            Ok(allowed) if allowed.is_bool() => {
+                if allowed.as_bool().unwrap_or(false) {
+                    return None;
+                }
+                format!("{} denied", name)
+            }
+            Ok(retval) if retval.is_int() => return retval.as_int().ok(),
+            Ok(reason) if reason.is_string() => {
+                reason.into_string().unwrap_or_default()
+            }
+            Ok(other) => format!("{} returned {}", name, other.type_name()),
+            Err(err) => format!("{} failed: {}", name, err),
+        };
+        log::info!("[pid {}] script: {}", task.getpid(), reason);
+        violation::flag_violation(task.gettid(), format!("script: {}", reason));
+        Some(-libc::EPERM as i64)
+    }
+
+    /// syscall emulation deciding syscalls by the script
+    pub fn into_emulation(self) -> SyscallEmulation {
+        SyscallEmulation::new(
+            EmulationMode::Seccomp,
+            self.syscalls(),
+            Box::new(move |task, memory, syscall, args| {
+                self.decide(task, memory, syscall, args)
+            }),
+        )
+    }
+}
+
+// path `syscall` is of: of its first path argument, or of its fd
+fn syscall_path(
+    pid: Pid,
+    memory: &dyn TaskMemory,
+    syscall: SyscallNo,
+    args: &[u64; 6],
+) -> Option<PathBuf> {
+    if let Some((_, dirfd, path)) =
+        PATH_ARGS.iter().find(|(nr, _, _)| *nr == syscall)
+    {
+        let dirfd = dirfd.map(|k| args[k] as i32);
+        let path = read_cstring(memory, args[*path]).ok()?;
+        return Some(absolute_path(pid, dirfd, &path));
+    }
+    FD_ARGS.iter().find(|(nr, _)| *nr == syscall)?;
+    std::fs::read_link(format!("/proc/{}/fd/{}", pid, args[0] as i32)).ok()
+}
+
+// the event scripts see, see module doc
+fn event(
+    task: &dyn Task,
+    memory: &dyn TaskMemory,
+    syscall: SyscallNo,
+    args: &SyscallArgs,
+) -> Map {
+    let (pid, tid) = (task.getpid(), task.gettid());
+    let args = [
+        args.arg0, args.arg1, args.arg2, args.arg3, args.arg4, args.arg5,
+    ];
+    let path = syscall_path(pid, memory, syscall, &args)
+        .map_or(Dynamic::UNIT, |path| {
+            Dynamic::from(path.to_string_lossy().into_owned())
+        });
+    let size = match syscall {
+        SyscallNo::SYS_truncate => Some(1),
+        _ => FD_ARGS
+            .iter()
+            .find(|(nr, _)| *nr == syscall)
+            .and_then(|(_, size)| *size),
+    };
+    let size = size.map_or(Dynamic::UNIT, |k| Dynamic::from(args[k] as i64));
+    let name = format!("{:?}", syscall);
+    let name = name.trim_start_matches("SYS_").to_string();
+    let args: Array =
+        args.iter().map(|arg| Dynamic::from(*arg as i64)).collect();
+    let mut event = Map::new();
+    event.insert("syscall".into(), Dynamic::from(name));
+    event.insert("pid".into(), Dynamic::from(i64::from(pid.as_raw())));
+    event.insert("tid".into(), Dynamic::from(i64::from(tid.as_raw())));
+    event.insert("args".into(), Dynamic::from(args));
+    event.insert("path".into(), path);
+    event.insert("size".into(), size);
+    event
+}
+
+#[test]
+fn script_policy_sanity_check() {
+    let script = r#"
+        fn write(event) {
+            if event.path.starts_with("/tmp") && event.size > 1048576 {
+                return "write over 1MB to /tmp";
+            }
+        }
+        fn getpid(event) { 42 }
+        fn helper(a, b) { a + b }
+    "#;
+    let policy = ScriptPolicy::new(script).unwrap();
+    assert_eq!(
+        policy.syscalls(),
+        vec![SyscallNo::SYS_write, SyscallNo::SYS_getpid]
+    );
+    assert!(ScriptPolicy::new("fn write(event) {").is_err());
+}
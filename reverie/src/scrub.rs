@@ -0,0 +1,281 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! scrubbing of recordings, of `reverie scrub`
+//!
+//! so that recordings with secrets can be shared: data returned by
+//! syscalls (file contents, network payloads) is hashed, as if not
+//! selected (see `DataSelection`), or zeroed. path components, and values
+//! of environment variables matching patterns are redacted, or hashed, so
+//! that equal values stay equal. the environment is not recorded, only
+//! variables given in arguments are, i.e.: `env NAME=VALUE PROGRAM`.
+//!
+//! patterns are wildcards: `*` matches any characters, `?` any one.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::str::FromStr;
+
+use reverie_api::fileless::sha256;
+
+use crate::recording::{RecordedEvent, Recording};
+
+/// what is done with syscall data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScrubData {
+    Keep,
+    /// blocks zeroed, sizes are kept
+    Redact,
+    /// recorded by digest only, see `Event::SyscallDigest`
+    Hash,
+}
+
+impl FromStr for ScrubData {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "keep" => Ok(ScrubData::Keep),
+            "redact" => Ok(ScrubData::Redact),
+            "hash" => Ok(ScrubData::Hash),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown data scrubbing: {}", s),
+            )),
+        }
+    }
+}
+
+/// what a recording is scrubbed of
+#[derive(Debug, Clone)]
+pub struct Scrubber {
+    pub data: ScrubData,
+    /// patterns of path components
+    pub paths: Vec<String>,
+    /// patterns of names of environment variables
+    pub env: Vec<String>,
+    /// whether values matched are hashed rather than redacted
+    pub hash: bool,
+}
+
+// characters paths in events end at, as of `Debug`
+const DELIMITERS: &str = " \"'(),[]{}";
+
+// data selector of no data, see `DataSelection`
+const NO_DATA: &str = "fd:-1";
+
+/// whether `s` matches wildcard `pattern`
+pub fn wildcard(pattern: &str, s: &str) -> bool {
+    let (pattern, s): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), s.chars().collect());
+    // `*` matched up to, in `pattern` and `s`, to backtrack to
+    let mut star = None;
+    let (mut p, mut k) = (0, 0);
+    while k < s.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == s[k]) {
+            p += 1;
+            k += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, k));
+            p += 1;
+        } else if let Some((star_p, star_k)) = star {
+            star = Some((star_p, star_k + 1));
+            p = star_p + 1;
+            k = star_k + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+impl Scrubber {
+    /// scrub `recording` in place
+    pub fn scrub(&self, recording: &mut Recording) {
+        match self.data {
+            ScrubData::Keep => (),
+            ScrubData::Redact => {
+                for data in recording.blocks.values_mut() {
+                    data.iter_mut().for_each(|byte| *byte = 0);
+                }
+            }
+            ScrubData::Hash => {
+                for event in recording.events.iter_mut() {
+                    if let Some(digest) =
+                        digest(&event.event, &recording.blocks)
+                    {
+                        event.event = digest;
+                    }
+                }
+                recording.blocks.clear();
+                // for replays to record digests as well
+                if recording.header.data {
+                    let selectors = vec![String::from(NO_DATA)];
+                    recording.header.data_selectors = selectors;
+                }
+            }
+        }
+        let header = &mut recording.header;
+        header.program = self.path(&header.program);
+        if let Some(cwd) = header.cwd.to_str() {
+            header.cwd = self.path(cwd).into();
+        }
+        for arg in header.args.iter_mut() {
+            *arg = self.arg(arg);
+        }
+        for selector in header.data_selectors.iter_mut() {
+            *selector = self.text(selector);
+        }
+        for event in recording.events.iter_mut() {
+            event.event = self.text(&event.event);
+        }
+    }
+
+    // `value` redacted, or hashed
+    fn value(&self, value: &str) -> String {
+        if self.hash {
+            format!("sha256:{}", &sha256(value.as_bytes())[..16])
+        } else {
+            String::from("REDACTED")
+        }
+    }
+
+    // `path` with its components matching scrubbed
+    fn path(&self, path: &str) -> String {
+        let components = path.split('/').map(|component| {
+            let matched = self
+                .paths
+                .iter()
+                .any(|pattern| wildcard(pattern, component));
+            if !component.is_empty() && matched {
+                self.value(component)
+            } else {
+                component.to_string()
+            }
+        });
+        components.collect::<Vec<_>>().join("/")
+    }
+
+    // `text` with the paths in it scrubbed
+    fn text(&self, text: &str) -> String {
+        let mut scrubbed = String::with_capacity(text.len());
+        let mut token = String::new();
+        let flush = |token: &mut String, scrubbed: &mut String| {
+            if token.contains('/') {
+                scrubbed.push_str(&self.path(token));
+            } else {
+                scrubbed.push_str(token);
+            }
+            token.clear();
+        };
+        for c in text.chars() {
+            if DELIMITERS.contains(c) {
+                flush(&mut token, &mut scrubbed);
+                scrubbed.push(c);
+            } else {
+                token.push(c);
+            }
+        }
+        flush(&mut token, &mut scrubbed);
+        scrubbed
+    }
+
+    // `arg` with its value scrubbed if an environment variable matching
+    fn arg(&self, arg: &str) -> String {
+        let mut split = arg.splitn(2, '=');
+        if let (Some(name), Some(value)) = (split.next(), split.next()) {
+            let is_name = !name.is_empty()
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            let matched =
+                self.env.iter().any(|pattern| wildcard(pattern, name));
+            if is_name && matched {
+                return format!("{}={}", name, self.value(value));
+            }
+        }
+        self.text(arg)
+    }
+}
+
+// `SyscallDigest` of a `SyscallData` event, by the `blocks` recorded
+fn digest(event: &str, blocks: &HashMap<usize, Vec<u8>>) -> Option<String> {
+    let args = event.strip_prefix("SyscallData(")?;
+    let syscall = args.split(", ").next()?;
+    let recorded = RecordedEvent {
+        thread: 0,
+        event: event.to_string(),
+        ticks: None,
+    };
+    let data = recorded.syscall_data(blocks)?;
+    Some(format!(
+        "SyscallDigest({}, {}, {:?})",
+        syscall,
+        data.len(),
+        sha256(&data)
+    ))
+}
+
+#[test]
+fn scrub_sanity_check() {
+    use crate::recording::RecordingHeader;
+    use std::path::PathBuf;
+
+    assert!(wildcard("*.pem", "key.pem"));
+    assert!(wildcard("s?cret*", "secrets"));
+    assert!(!wildcard("*.pem", "key.pem.txt"));
+    assert!(wildcard("*", ""));
+
+    let event = |event: &str| RecordedEvent {
+        thread: 0,
+        event: event.to_string(),
+        ticks: None,
+    };
+    let mut blocks = HashMap::new();
+    blocks.insert(0, b"password".to_vec());
+    let mut recording = Recording {
+        header: RecordingHeader {
+            program: String::from("/usr/bin/env"),
+            args: vec![
+                String::from("API_TOKEN=hunter2"),
+                String::from("/home/alice/key.pem"),
+            ],
+            cwd: PathBuf::from("/home/alice"),
+            data: true,
+            data_selectors: Vec::new(),
+            ticks: false,
+            timeslice: None,
+        },
+        events: vec![
+            event("SyscallData(SYS_read, 8, [0])"),
+            event("Quarantined(\"open /home/alice/key.pem\")"),
+        ],
+        blocks,
+    };
+    let scrubber = Scrubber {
+        data: ScrubData::Hash,
+        paths: vec![String::from("alice"), String::from("*.pem")],
+        env: vec![String::from("*TOKEN*")],
+        hash: false,
+    };
+    scrubber.scrub(&mut recording);
+    assert!(recording.blocks.is_empty());
+    assert_eq!(recording.header.data_selectors, vec![NO_DATA]);
+    assert_eq!(recording.header.args[0], "API_TOKEN=REDACTED");
+    assert_eq!(recording.header.args[1], "/home/REDACTED/REDACTED");
+    assert_eq!(recording.header.cwd, PathBuf::from("/home/REDACTED"));
+    let digest = sha256(b"password");
+    let expected = format!("SyscallDigest(SYS_read, 8, {:?})", digest);
+    assert_eq!(recording.events[0].event, expected);
+    assert_eq!(
+        recording.events[1].event,
+        "Quarantined(\"open /home/REDACTED/REDACTED\")"
+    );
+}
@@ -0,0 +1,249 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! session state, to re-attach a restarted tracer to its process tree
+//!
+//! a `detach` request of the control socket saves the session to a file,
+//! detaches all tasks, stopped, and the tracer exits. `reverie attach
+//! --session FILE` (i.e.: of an upgraded reverie) seizes the tasks again,
+//! restores their state and resumes them. the session has:
+//!
+//! - arguments and working directory of the tracer: the tool, the
+//!   preloader and the policy (tiers, sampling, emulation) are options of,
+//!   applied again. recording is not resumed.
+//! - the task table: tasks, their processes and rpc areas.
+//! - patch state of processes: syscall sites patched, or never to be,
+//!   and syscall stub pages. the tool and trampoline stay loaded in
+//!   tracees, and guest dispatch tables stay in the global state memfd,
+//!   which tracees hold and the new tracer maps again.
+//!
+//! libc flavor, managed runtime and unsupported abis of processes are
+//! detected again. tasks stay stopped while no tracer is attached: their
+//! syscalls stopped by the seccomp filter would fail with `ENOSYS`.
+//! tracers of their own pid namespace (`--namespaces`) can't be detached,
+//! their tracees die with them.
+
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io::{BufReader, BufWriter, Result, Write};
+use std::path::{Path, PathBuf};
+
+use nix::unistd::Pid;
+use reverie_api::task::Task;
+
+use crate::libc_flavor;
+use crate::passthrough;
+use crate::patcher::SyscallStubPage;
+use crate::process::{Process, ProcessRef};
+use crate::traced_task::TracedTask;
+
+/// a task of the session
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionTask {
+    pub tid: i32,
+    pub pid: i32,
+    pub ppid: i32,
+    /// top of the rpc stack, see `init_rpc_stack_data`
+    pub rpc_stack: Option<u64>,
+}
+
+/// patch state of a process of the session
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionProcess {
+    pub pid: i32,
+    pub patched_syscalls: Vec<u64>,
+    pub unpatchable_syscalls: Vec<u64>,
+    /// (address, size, allocated) of syscall stub pages
+    pub stub_pages: Vec<(u64, usize, usize)>,
+}
+
+/// state of a tracer session
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionState {
+    /// arguments of the tracer, program name first
+    pub tracer_args: Vec<String>,
+    /// working directory of the tracer
+    pub cwd: PathBuf,
+    pub tasks: Vec<SessionTask>,
+    pub processes: Vec<SessionProcess>,
+}
+
+impl SessionTask {
+    /// session state of `task`
+    pub fn of(task: &TracedTask) -> Self {
+        SessionTask {
+            tid: task.gettid().as_raw(),
+            pid: task.getpid().as_raw(),
+            ppid: task.getppid().as_raw(),
+            rpc_stack: task.rpc_stack.map(|(top, _)| top.as_ptr() as u64),
+        }
+    }
+}
+
+impl SessionProcess {
+    /// session state of `process`
+    pub fn of(process: &Process) -> Self {
+        let mut patched: Vec<u64> =
+            process.patched_syscalls.iter().cloned().collect();
+        let mut unpatchable: Vec<u64> =
+            process.unpatchable_syscalls.iter().cloned().collect();
+        patched.sort();
+        unpatchable.sort();
+        SessionProcess {
+            pid: process.pid().as_raw(),
+            patched_syscalls: patched,
+            unpatchable_syscalls: unpatchable,
+            stub_pages: process
+                .stub_pages
+                .iter()
+                .map(|page| (page.address, page.size, page.allocated))
+                .collect(),
+        }
+    }
+
+    /// restore patch state of `process`
+    pub fn restore(&self, process: &mut Process) {
+        process.patched_syscalls.extend(&self.patched_syscalls);
+        process
+            .unpatchable_syscalls
+            .extend(&self.unpatchable_syscalls);
+        process.stub_pages = self
+            .stub_pages
+            .iter()
+            .map(|&(address, size, allocated)| SyscallStubPage {
+                address,
+                size,
+                allocated,
+            })
+            .collect();
+    }
+}
+
+impl SessionState {
+    /// session of the tracer, tracing `tasks`
+    pub fn new<'a, I>(tasks: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = &'a TracedTask>,
+    {
+        let mut state = SessionState {
+            tracer_args: env::args().collect(),
+            cwd: env::current_dir()?,
+            ..SessionState::default()
+        };
+        for task in tasks {
+            state.tasks.push(SessionTask::of(task));
+            let process = task.process.borrow();
+            let pid = process.pid().as_raw();
+            if state.processes.iter().all(|p| p.pid != pid) {
+                state.processes.push(SessionProcess::of(&process));
+            }
+        }
+        state.tasks.sort_by_key(|task| task.tid);
+        Ok(state)
+    }
+
+    /// process `pid` of the session, if any
+    pub fn process(&self, pid: i32) -> Option<&SessionProcess> {
+        self.processes.iter().find(|process| process.pid == pid)
+    }
+
+    /// state of process `pid` re-attached, restored from the session
+    pub fn restore_process(&self, pid: i32) -> ProcessRef {
+        let process = Process::new(Pid::from_raw(pid));
+        let exe = PathBuf::from(format!("/proc/{}/exe", pid));
+        {
+            let mut process = process.borrow_mut();
+            if let Some(saved) = self.process(pid) {
+                saved.restore(&mut process);
+            }
+            process.libc = libc_flavor::program_flavor(&exe);
+            process.passthrough =
+                passthrough::unsupported_program(&exe).unwrap_or(None);
+        }
+        process
+    }
+
+    /// save to `path`, replaced atomically
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut out = BufWriter::new(fs::File::create(&tmp)?);
+        serde_json::to_writer_pretty(&mut out, self)?;
+        out.flush()?;
+        drop(out);
+        fs::rename(&tmp, path)
+    }
+
+    /// load from `path`, of `save`
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = BufReader::new(fs::File::open(path)?);
+        Ok(serde_json::from_reader(file)?)
+    }
+}
+
+#[test]
+fn session_sanity_check() {
+    let state = SessionState {
+        tracer_args: vec![String::from("reverie"), String::from("run")],
+        cwd: PathBuf::from("/"),
+        tasks: vec![SessionTask {
+            tid: 2,
+            pid: 2,
+            ppid: 1,
+            rpc_stack: Some(0x4000),
+        }],
+        processes: vec![SessionProcess {
+            pid: 2,
+            patched_syscalls: vec![0x1000],
+            unpatchable_syscalls: Vec::new(),
+            stub_pages: vec![(0x2000, 0x1000, 0x20)],
+        }],
+    };
+    let path = env::temp_dir().join(format!("session-{}", std::process::id()));
+    state.save(&path).unwrap();
+    assert_eq!(SessionState::load(&path).unwrap(), state);
+    let _ = fs::remove_file(&path);
+    assert!(state.process(2).is_some());
+    assert!(state.process(3).is_none());
+}
+
+#[test]
+fn restore_sanity_check() {
+    use reverie_api::remote::Remoteable;
+    let pid = nix::unistd::getpid();
+    let mut task: TracedTask = Task::new(pid);
+    let rpc_stack = Remoteable::remote(0x7000_8000 as *mut u64);
+    task.rpc_stack = rpc_stack.map(|top| (top, 0x4000));
+    task.process.borrow_mut().patched_syscalls.insert(0x1000);
+    let ppid = task.getppid();
+    let state = SessionState::new(&[task]).unwrap();
+    let path = env::temp_dir().join(format!("restore-{}", std::process::id()));
+    state.save(&path).unwrap();
+    let state = SessionState::load(&path).unwrap();
+    let _ = fs::remove_file(&path);
+
+    let saved = &state.tasks[0];
+    let restored =
+        TracedTask::restored(saved, state.restore_process(saved.pid));
+    assert_eq!(restored.gettid(), pid);
+    assert_eq!(restored.getpid(), pid);
+    assert_eq!(restored.getppid(), ppid);
+    let top = |area: Option<(Remoteable<u64>, usize)>| {
+        area.map(|(top, size)| (top.as_ptr() as u64, size))
+    };
+    assert_eq!(top(restored.rpc_stack), Some((0x7000_8000, 0x4000)));
+    assert_eq!(top(restored.rpc_data), Some((0x7000_8000, 0x4000)));
+    assert!(restored.is_patched_syscall(0x1000));
+    assert_eq!(SessionTask::of(&restored), *saved);
+}
@@ -0,0 +1,290 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! shared memory detection
+//!
+//! when enabled by `SharedMemoryPolicy`, shared memory syscalls (`mmap`s
+//! with `MAP_SHARED` only) are never patched, so that they always stop at
+//! seccomp, where the policy applies, and at syscall exit, where
+//! `SharedMemory` is decoded.
+//!
+//! the tracer also keeps a `SharedMemoryMap` of which processes share which
+//! memory objects, see `shared_memory_map`.
+
+use nix::unistd::Pid;
+use std::os::linux::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use syscalls::*;
+
+use reverie_api::shm::*;
+
+const MAP_TYPE: u64 = 0x0f;
+const MAP_SHARED: u64 = 0x01;
+const MAP_PRIVATE: u64 = 0x02;
+const MAP_SHARED_VALIDATE: u64 = 0x03;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+/// what to do with a shared memory syscall at seccomp stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShmVerdict {
+    Allow,
+    /// skip the syscall, fail with errno
+    Deny(i32),
+    /// rewrite mmap flags
+    Private(u64),
+}
+
+fn is_map_shared(flags: u64) -> bool {
+    let ty = flags & MAP_TYPE;
+    ty == MAP_SHARED || ty == MAP_SHARED_VALIDATE
+}
+
+/// whether `syscall` with arguments in `regs` is to be stopped (and never
+/// patched) under `policy`
+pub fn is_shared_memory_syscall(
+    policy: SharedMemoryPolicy,
+    syscall: SyscallNo,
+    regs: &libc::user_regs_struct,
+) -> bool {
+    if policy == SharedMemoryPolicy::Ignore {
+        return false;
+    }
+    match syscall {
+        SYS_shmget | SYS_shmat | SYS_memfd_create => true,
+        SYS_mmap => is_map_shared(regs.r10),
+        _ => false,
+    }
+}
+
+/// apply `policy` to `syscall` with arguments in `regs`
+pub fn shared_memory_verdict(
+    policy: SharedMemoryPolicy,
+    syscall: SyscallNo,
+    regs: &libc::user_regs_struct,
+) -> ShmVerdict {
+    let shared = match syscall {
+        SYS_mmap => is_map_shared(regs.r10),
+        SYS_shmget | SYS_shmat | SYS_memfd_create => true,
+        _ => false,
+    };
+    if !shared {
+        return ShmVerdict::Allow;
+    }
+    match (policy, syscall) {
+        (SharedMemoryPolicy::Deny, _) => ShmVerdict::Deny(libc::EPERM),
+        (SharedMemoryPolicy::Private, SYS_mmap) => {
+            ShmVerdict::Private((regs.r10 & !MAP_TYPE) | MAP_PRIVATE)
+        }
+        (SharedMemoryPolicy::Private, SYS_memfd_create) => ShmVerdict::Allow,
+        (SharedMemoryPolicy::Private, _) => ShmVerdict::Deny(libc::EPERM),
+        _ => ShmVerdict::Allow,
+    }
+}
+
+fn fd_path(pid: Pid, fd: i32) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/{}/fd/{}", pid, fd)).ok()
+}
+
+/// decode shared memory created/mapped by a (successful) syscall, `regs`
+/// are from the syscall exit stop.
+pub fn shared_memory_at_exit(
+    pid: Pid,
+    regs: &libc::user_regs_struct,
+) -> Option<SharedMemory> {
+    let retval = regs.rax as i64;
+    if retval < 0 && retval > -4096 {
+        return None;
+    }
+    match SyscallNo::from(regs.orig_rax as i32) {
+        SYS_shmget => Some(SharedMemory::SysvCreate {
+            key: regs.rdi as i32,
+            size: regs.rsi,
+            shmid: retval as i32,
+        }),
+        SYS_shmat => Some(SharedMemory::SysvAttach {
+            shmid: regs.rdi as i32,
+            addr: retval as u64,
+        }),
+        SYS_memfd_create => Some(SharedMemory::Memfd {
+            fd: retval as i32,
+            path: fd_path(pid, retval as i32),
+        }),
+        SYS_mmap if is_map_shared(regs.r10) => {
+            let fd = if regs.r10 & MAP_ANONYMOUS != 0 {
+                -1
+            } else {
+                regs.r8 as i32
+            };
+            Some(SharedMemory::Mapping {
+                fd,
+                addr: retval as u64,
+                size: regs.rsi,
+                path: if fd < 0 { None } else { fd_path(pid, fd) },
+            })
+        }
+        _ => None,
+    }
+}
+
+lazy_static! {
+    static ref SHARED_MEMORY_MAP: Mutex<SharedMemoryMap> =
+        Mutex::new(SharedMemoryMap::new());
+}
+
+/// processes sharing memory objects, across the traced tree
+pub fn shared_memory_map() -> &'static Mutex<SharedMemoryMap> {
+    &SHARED_MEMORY_MAP
+}
+
+fn fd_object(pid: Pid, fd: i32) -> Option<MemoryObject> {
+    let meta = std::fs::metadata(format!("/proc/{}/fd/{}", pid, fd)).ok()?;
+    Some(MemoryObject::File {
+        dev: meta.st_dev(),
+        ino: meta.st_ino(),
+    })
+}
+
+/// track `shm` mapped by process `pid`
+pub fn track_shared_memory(pid: Pid, shm: &SharedMemory) {
+    let object = match shm {
+        SharedMemory::SysvAttach { shmid, .. } => MemoryObject::Sysv(*shmid),
+        SharedMemory::Mapping { fd, addr, .. } if *fd < 0 => {
+            MemoryObject::Anonymous {
+                owner: pid,
+                addr: *addr,
+            }
+        }
+        SharedMemory::Mapping { fd, .. } => match fd_object(pid, *fd) {
+            Some(object) => object,
+            None => return,
+        },
+        _ => return,
+    };
+    SHARED_MEMORY_MAP
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .attach(object, pid);
+}
+
+#[test]
+fn shm_sanity_check() {
+    use SharedMemoryPolicy::*;
+
+    let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+    // mmap(NULL, 0x1000, .., MAP_PRIVATE | MAP_ANONYMOUS, -1, 0)
+    regs.rsi = 0x1000;
+    regs.r10 = MAP_PRIVATE | MAP_ANONYMOUS;
+    regs.r8 = -1i64 as u64;
+    assert!(!is_shared_memory_syscall(Annotate, SYS_mmap, &regs));
+    assert_eq!(
+        shared_memory_verdict(Deny, SYS_mmap, &regs),
+        ShmVerdict::Allow
+    );
+    regs.r10 = MAP_SHARED | MAP_ANONYMOUS;
+    assert!(!is_shared_memory_syscall(Ignore, SYS_mmap, &regs));
+    assert!(is_shared_memory_syscall(Annotate, SYS_mmap, &regs));
+    assert!(is_shared_memory_syscall(Deny, SYS_shmat, &regs));
+    assert!(!is_shared_memory_syscall(Deny, SYS_munmap, &regs));
+    let verdicts = [
+        (Annotate, SYS_mmap, ShmVerdict::Allow),
+        (Deny, SYS_mmap, ShmVerdict::Deny(libc::EPERM)),
+        (
+            Private,
+            SYS_mmap,
+            ShmVerdict::Private(MAP_PRIVATE | MAP_ANONYMOUS),
+        ),
+        (Private, SYS_memfd_create, ShmVerdict::Allow),
+        (Private, SYS_shmget, ShmVerdict::Deny(libc::EPERM)),
+    ];
+    for (policy, syscall, verdict) in verdicts.iter() {
+        assert_eq!(shared_memory_verdict(*policy, *syscall, &regs), *verdict);
+    }
+
+    let pid = nix::unistd::getpid();
+    regs.orig_rax = SYS_mmap as u64;
+    regs.rax = 0x7f00_0000;
+    assert_eq!(
+        shared_memory_at_exit(pid, &regs),
+        Some(SharedMemory::Mapping {
+            fd: -1,
+            addr: 0x7f00_0000,
+            size: 0x1000,
+            path: None,
+        })
+    );
+    regs.r10 = MAP_PRIVATE;
+    assert_eq!(shared_memory_at_exit(pid, &regs), None);
+    // shmat(7, ..) failed
+    regs.orig_rax = SYS_shmat as u64;
+    regs.rdi = 7;
+    regs.rax = -libc::EINVAL as i64 as u64;
+    assert_eq!(shared_memory_at_exit(pid, &regs), None);
+    regs.rax = 0x7f10_0000;
+    assert_eq!(
+        shared_memory_at_exit(pid, &regs),
+        Some(SharedMemory::SysvAttach {
+            shmid: 7,
+            addr: 0x7f10_0000,
+        })
+    );
+}
+
+#[test]
+fn track_shared_memory_sanity_check() {
+    use nix::sys::{signal, wait};
+    use nix::unistd::{self, ForkResult};
+
+    let name = std::ffi::CString::new("shm").unwrap();
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), 0) };
+    assert!(fd >= 0);
+    assert_eq!(unsafe { libc::ftruncate(fd, 0x1000) }, 0);
+    // the child shares the memfd, until killed
+    let child = match unistd::fork().expect("fork failed") {
+        ForkResult::Child => loop {
+            unistd::pause();
+        },
+        ForkResult::Parent { child } => child,
+    };
+    let pid = unistd::getpid();
+    let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+    // mmap(NULL, 0x1000, .., MAP_SHARED, fd, 0)
+    regs.orig_rax = SYS_mmap as u64;
+    regs.rax = 0x7f20_0000;
+    regs.rsi = 0x1000;
+    regs.r10 = MAP_SHARED;
+    regs.r8 = fd as u64;
+    let shm = shared_memory_at_exit(pid, &regs).unwrap();
+    match &shm {
+        SharedMemory::Mapping {
+            path: Some(path), ..
+        } => {
+            assert!(path.to_string_lossy().starts_with("/memfd:shm"))
+        }
+        shm => panic!("memfd mapping not decoded: {:?}", shm),
+    }
+    track_shared_memory(pid, &shm);
+    track_shared_memory(child, &shm);
+    let object = fd_object(pid, fd).unwrap();
+    let sharers = shared_memory_map().lock().unwrap().sharers(&object);
+    assert_eq!(sharers.len(), 2);
+    assert!(sharers.contains(&pid) && sharers.contains(&child));
+    shared_memory_map().lock().unwrap().detach_all(child);
+    let sharers = shared_memory_map().lock().unwrap().sharers(&object);
+    assert_eq!(sharers, vec![pid]);
+    shared_memory_map().lock().unwrap().detach_all(pid);
+
+    let _ = signal::kill(child, signal::SIGKILL);
+    let _ = wait::waitpid(child, None);
+    let _ = unistd::close(fd);
+}
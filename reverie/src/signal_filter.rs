@@ -0,0 +1,133 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! signal routing through tool policy
+//!
+//! when a `SignalFilterFn` is set, signal sending syscalls are never
+//! patched, so that they always stop at seccomp, where the filter decides
+//! whether the signal is to be sent.
+
+use nix::sys::signal::Signal;
+use nix::unistd;
+use nix::unistd::Pid;
+use std::fs;
+use syscalls::*;
+
+use reverie_api::kill::*;
+
+/// syscalls to be stopped (and never patched) when a filter is set
+pub fn is_signal_syscall(syscall: SyscallNo) -> bool {
+    match syscall {
+        SYS_kill
+        | SYS_tkill
+        | SYS_tgkill
+        | SYS_rt_sigqueueinfo
+        | SYS_rt_tgsigqueueinfo => true,
+        _ => false,
+    }
+}
+
+/// decode signal sent by process `pid` with `syscall`, with arguments in
+/// `regs`.
+pub fn decode_signal_send(
+    pid: Pid,
+    syscall: SyscallNo,
+    regs: &libc::user_regs_struct,
+) -> Option<SignalSend> {
+    let raw = |arg: u64| Pid::from_raw(arg as i32);
+    let (target, signo) = match syscall {
+        SYS_kill => {
+            let pgid = unistd::getpgid(Some(pid)).ok()?;
+            (SignalTarget::from_kill(regs.rdi as i32, pgid), regs.rsi)
+        }
+        SYS_tkill => {
+            let tid = raw(regs.rdi);
+            (SignalTarget::Thread { tgid: None, tid }, regs.rsi)
+        }
+        SYS_tgkill | SYS_rt_tgsigqueueinfo => {
+            let (tgid, tid) = (Some(raw(regs.rdi)), raw(regs.rsi));
+            (SignalTarget::Thread { tgid, tid }, regs.rdx)
+        }
+        SYS_rt_sigqueueinfo => (SignalTarget::Process(raw(regs.rdi)), regs.rsi),
+        _ => return None,
+    };
+    Some(SignalSend {
+        target,
+        signal: Signal::from_c_int(signo as i32).ok(),
+        traced: is_traced(target),
+    })
+}
+
+// `TracerPid` of task `tid` is us
+fn traced_by_us(tid: Pid) -> bool {
+    let status = match fs::read_to_string(format!("/proc/{}/status", tid)) {
+        Ok(status) => status,
+        Err(_) => return false,
+    };
+    status
+        .lines()
+        .find(|line| line.starts_with("TracerPid:"))
+        .and_then(|line| line["TracerPid:".len()..].trim().parse().ok())
+        .map(|tracer| Pid::from_raw(tracer) == unistd::gettid())
+        .unwrap_or(false)
+}
+
+// processes in process group `pgid`
+fn process_group(pgid: Pid) -> Vec<Pid> {
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .map(Pid::from_raw)
+        .filter(|pid| {
+            // pgrp is the 3rd field after `comm`, which can have spaces.
+            fs::read_to_string(format!("/proc/{}/stat", pid))
+                .ok()
+                .and_then(|stat| {
+                    let fields = &stat[stat.rfind(')')? + 1..];
+                    fields.split_whitespace().nth(2)?.parse().ok()
+                })
+                .map(Pid::from_raw)
+                == Some(pgid)
+        })
+        .collect()
+}
+
+fn is_traced(target: SignalTarget) -> bool {
+    match target {
+        SignalTarget::Process(pid) => traced_by_us(pid),
+        SignalTarget::Thread { tid, .. } => traced_by_us(tid),
+        SignalTarget::ProcessGroup(pgid) => {
+            let pids = process_group(pgid);
+            !pids.is_empty() && pids.into_iter().all(traced_by_us)
+        }
+        SignalTarget::All => false,
+    }
+}
+
+#[test]
+fn signal_filter_sanity_check() {
+    // not traced by ourselves
+    let pid = unistd::getpid();
+    assert!(!is_traced(SignalTarget::Process(pid)));
+    assert!(process_group(unistd::getpgrp()).contains(&pid));
+    let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+    regs.rdi = 0;
+    regs.rsi = libc::SIGTERM as u64;
+    let send = decode_signal_send(pid, SYS_kill, &regs).unwrap();
+    assert_eq!(send.target, SignalTarget::ProcessGroup(unistd::getpgrp()));
+    assert_eq!(send.signal, Some(Signal::SIGTERM));
+    assert!(!send.traced);
+}
@@ -26,21 +26,34 @@ use nix::sys::wait::WaitStatus;
 use nix::sys::{memfd, mman, ptrace, signal, wait};
 use nix::unistd;
 use nix::unistd::ForkResult;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::env;
 use std::ffi::CString;
-use std::io::{self, Error, ErrorKind};
+use std::io::{self, Error, ErrorKind, Write};
 use std::path::PathBuf;
+use std::rc::Rc;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use structopt::{clap::AppSettings, StructOpt};
 
+use reverie_api::arch::{Arch, Host};
+use reverie_api::emulate::{EmulationMode, SyscallEmulation};
 use reverie_api::event::*;
 use reverie_api::remote::*;
+use reverie_api::shm::SharedMemoryPolicy;
+use reverie_api::strace::*;
 use reverie_api::task::*;
+use reverie_api::trace_output::TraceOutput;
 
+use reverie::deps::{self, DepsRecorder};
+use reverie::flaky::{self, FlakyRecorder};
 use reverie::reverie_common::{consts, state::*};
-use reverie::sched_wait::SchedWait;
-use reverie::{hooks, ns};
+use reverie::sched_wait::{self, SchedPolicy, SchedWait, UnknownEventPolicy};
+use reverie::syscalls::SyscallNo;
+use reverie::traced_task::TracedTask;
+use reverie::{attach, hooks, ns, traced_task};
 
 use reverie_seccomp::seccomp_bpf;
 
@@ -52,6 +65,41 @@ fn can_resolve_syscall_hooks() -> io::Result<()> {
     Ok(())
 }
 
+/// what is traced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TraceMode {
+    /// syscalls, in strace format
+    Strace,
+    /// files accessed, as a dependency manifest, see `reverie::deps`
+    Deps,
+    /// scheduling events, to compare runs of a test, see `reverie::flaky`
+    Flaky,
+}
+
+impl FromStr for TraceMode {
+    type Err = Error;
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "strace" => Ok(TraceMode::Strace),
+            "deps" => Ok(TraceMode::Deps),
+            "flaky" => Ok(TraceMode::Flaky),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown mode: {}", s),
+            )),
+        }
+    }
+}
+
+const DEFAULT_DEPS_OUTPUT: &str = "reverie-deps.json";
+const DEFAULT_FLAKY_OUTPUT: &str = "reverie-flaky.jsonl";
+
+/// recorder of --mode deps or flaky, written out when all processes exited
+enum Recorder {
+    Deps(Rc<RefCell<DepsRecorder>>),
+    Flaky(Rc<RefCell<FlakyRecorder>>),
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(about)]
 struct Arguments {
@@ -81,6 +129,30 @@ struct Arguments {
     #[structopt(long = "with-namespace")]
     namespaces: bool,
 
+    /// Creates namespaces for the program: time (linux 5.6+), which
+    /// offsets the monotonic and boot time clocks by --monotonic-offset
+    /// and --boottime-offset. Can be used multiple times.
+    #[structopt(long = "ns", value_name = "NS", number_of_values = 1)]
+    ns: Vec<ns::Namespace>,
+
+    /// CLOCK_MONOTONIC offset in the time namespace, in seconds.
+    #[structopt(
+        long,
+        value_name = "SECS",
+        default_value = "0",
+        allow_hyphen_values = true
+    )]
+    monotonic_offset: i64,
+
+    /// CLOCK_BOOTTIME offset in the time namespace, in seconds.
+    #[structopt(
+        long,
+        value_name = "SECS",
+        default_value = "0",
+        allow_hyphen_values = true
+    )]
+    boottime_offset: i64,
+
     /// Configures how to do logging.
     #[structopt(long = "with-log", value_name = "OUTPUT")]
     log_output: Option<String>,
@@ -89,14 +161,87 @@ struct Arguments {
     #[structopt(long)]
     disable_monkey_patcher: bool,
 
+    /// Sample one in every N syscalls, per syscall number, with optional
+    /// per syscall rates, i.e.: 100,0:1000 (read is sampled 1 in 1000).
+    #[structopt(
+        long,
+        value_name = "N[,NR:N]*",
+        parse(try_from_str = util::parse_sampling)
+    )]
+    sample: Option<String>,
+
+    /// Scheduling policy of traced tasks: fifo, priority, rr or
+    /// rr:<QUANTUM>.
+    #[structopt(long, value_name = "POLICY", default_value = "rr")]
+    sched_policy: SchedPolicy,
+
+    /// What to do with ptrace events unknown to reverie (i.e.: from newer
+    /// kernels): ignore, stop-task or abort.
+    #[structopt(long, value_name = "POLICY", default_value = "ignore")]
+    unknown_event: UnknownEventPolicy,
+
+    /// Shared memory policy: ignore, annotate, deny or private.
+    #[structopt(long, value_name = "POLICY", default_value = "ignore")]
+    shared_memory: SharedMemoryPolicy,
+
+    /// What to trace: strace, deps (files exec'ed, read, written,
+    /// stat'ed or renamed by each process, written to --output as a json
+    /// dependency manifest when all processes exited) or flaky (threads
+    /// spawned, futex wakes, timeouts and signals, written to --output as
+    /// json lines, to compare runs with flaky-report).
+    #[structopt(long, value_name = "MODE", default_value = "strace")]
+    mode: TraceMode,
+
+    /// Root directory of --mode deps, paths under it are made relative.
+    /// Defaults to the current directory.
+    #[structopt(long, value_name = "DIR")]
+    deps_root: Option<PathBuf>,
+
+    /// Writes syscalls to OUTPUT (`-` for stderr), in strace format, as
+    /// `strace -e raw=all` does. With --mode deps or flaky, writes the
+    /// manifest or trace instead, to reverie-deps.json or
+    /// reverie-flaky.jsonl by default.
+    #[structopt(short = "o", long, value_name = "OUTPUT")]
+    output: Option<PathBuf>,
+
+    /// Prefixes each line of --output with the thread id, as strace -f
+    /// does. Forks are always traced.
+    #[structopt(short = "f")]
+    follow_forks: bool,
+
+    /// Prefixes each line of --output with the time of day (-tt: with
+    /// microseconds, -ttt: as seconds since epoch).
+    #[structopt(short = "t", parse(from_occurrences))]
+    timestamps: u8,
+
+    /// Shows time spent in syscalls in --output.
+    #[structopt(short = "T")]
+    syscall_times: bool,
+
+    /// Maximum bytes of buffers shown in --output, paths are shown in full.
+    #[structopt(short = "s", value_name = "STRSIZE", default_value = "32")]
+    string_limit: usize,
+
     /// Shows reverie software performance counter statistics (--debug must be
     /// >=3).
     #[structopt(long)]
     show_perf_stats: bool,
 
+    /// Attaches to running process PID and all its threads, rather than
+    /// launching PROGRAM. Its syscalls are stopped by ptrace alone, without
+    /// a seccomp filter, and the private page is mapped at its first
+    /// syscall. Not killed when the tracer exits.
+    #[structopt(
+        short = "p",
+        long = "attach",
+        value_name = "PID",
+        conflicts_with = "namespaces"
+    )]
+    pid: Option<i32>,
+
     /// Name of the program to trace.
-    #[structopt(value_name = "PROGRAM")]
-    program: String,
+    #[structopt(value_name = "PROGRAM", required_unless = "pid")]
+    program: Option<String>,
 
     /// Arguments to the program to trace.
     #[structopt(value_name = "ARGS")]
@@ -107,21 +252,6 @@ fn run_tracer_main<G>(sched: &mut SchedWait<G>) -> i32 {
     sched.run_all()
 }
 
-fn wait_sigstop(pid: unistd::Pid) -> io::Result<()> {
-    match wait::waitpid(Some(pid), None).expect("waitpid failed") {
-        WaitStatus::Stopped(new_pid, signal)
-            if signal == signal::SIGSTOP && new_pid == pid =>
-        {
-            Ok(())
-        }
-        _ => Err(Error::new(ErrorKind::Other, "expect SIGSTOP")),
-    }
-}
-
-fn from_nix_error(err: nix::Error) -> Error {
-    Error::new(ErrorKind::Other, err)
-}
-
 // hardcoded because `libc` does not export
 const PER_LINUX: u64 = 0x0;
 const ADDR_NO_RANDOMIZE: u64 = 0x0004_0000;
@@ -153,9 +283,8 @@ fn run_tracee(argv: &Arguments) -> io::Result<i32> {
         assert!(libc::personality(PER_LINUX | ADDR_NO_RANDOMIZE) != -1);
     };
 
-    ptrace::traceme()
-        .and_then(|_| signal::raise(signal::SIGSTOP))
-        .map_err(from_nix_error)?;
+    // to be seized by the tracer.
+    signal::raise(signal::SIGSTOP).map_err(from_nix_error)?;
 
     tracee_init_signals();
 
@@ -177,7 +306,8 @@ fn run_tracee(argv: &Arguments) -> io::Result<i32> {
         }
     });
 
-    let program = CString::new(argv.program.as_str())?;
+    let name = argv.program.clone().unwrap_or_default();
+    let program = CString::new(name.as_str())?;
     let mut args: Vec<CString> = Vec::new();
     args.push(program.clone());
     for v in argv.program_args.clone() {
@@ -188,20 +318,17 @@ fn run_tracee(argv: &Arguments) -> io::Result<i32> {
         .map(|s| CString::new(s.as_bytes()).unwrap())
         .collect();
 
-    log::info!(
-        "[main] launching: {} {:?}",
-        &argv.program,
-        &argv.program_args
-    );
+    log::info!("[main] launching: {} {:?}", name, &argv.program_args);
 
-    let mut whitelist: Vec<_> = vec![(0x7000_0002, 0x7000_0002)];
+    let ip = Host::untraced_syscall_ip(consts::REVERIE_PRIVATE_PAGE_OFFSET);
+    let mut whitelist: Vec<_> = vec![(ip, ip)];
     let bytes = seccomp_bpf::bpf_whitelist_ips(whitelist.as_mut());
     let rr = seccomp_bpf::seccomp(&bytes);
     println!("seccomp returned: {:?}", rr);
 
     unistd::execvpe(&program, args.as_slice(), envp.as_slice())
         .map_err(from_nix_error)?;
-    panic!("exec failed: {} {:?}", &argv.program, &argv.program_args);
+    panic!("exec failed: {} {:?}", name, &argv.program_args);
 }
 
 fn show_perf_stats(state: &ReverieState) {
@@ -232,6 +359,35 @@ fn show_perf_stats(state: &ReverieState) {
         "syscalls captured(wo/ patching): {:.2}%",
         100.0 * (syscalls_captured - syscalls_patched) as f64 / syscalls as f64
     );
+
+    let syscalls_timed = state.stats.nr_syscalls_timed.load(Ordering::SeqCst);
+    let tracer_nanos = state.stats.syscall_tracer_nanos.load(Ordering::SeqCst);
+    let kernel_nanos = state.stats.syscall_kernel_nanos.load(Ordering::SeqCst);
+    let sampler = traced_task::syscall_sampler();
+    if sampler.sampled() < sampler.total() {
+        log::info!(
+            "seccomp syscalls sampled: {} of {}",
+            sampler.sampled(),
+            sampler.total()
+        );
+        for (no, count) in sampler.counts() {
+            log::info!("  {:?}: {}", SyscallNo::from(no), count);
+        }
+    }
+    if syscalls_timed > 0 {
+        log::info!(
+            "ptraced syscalls time in tracer: {:?} (avg {:?}), {:.2}%",
+            Duration::from_nanos(tracer_nanos as u64),
+            Duration::from_nanos((tracer_nanos / syscalls_timed) as u64),
+            100.0 * tracer_nanos as f64 / (tracer_nanos + kernel_nanos) as f64
+        );
+        log::info!(
+            "ptraced syscalls time in kernel: {:?} (avg {:?}), {:.2}%",
+            Duration::from_nanos(kernel_nanos as u64),
+            Duration::from_nanos((kernel_nanos / syscalls_timed) as u64),
+            100.0 * kernel_nanos as f64 / (tracer_nanos + kernel_nanos) as f64
+        );
+    }
 }
 
 fn task_exec_cb(task: &mut dyn Task) -> io::Result<()> {
@@ -262,6 +418,24 @@ fn task_exit_cb(_exit_code: i32) -> io::Result<()> {
     Ok(())
 }
 
+fn strace_sink(output: &PathBuf, argv: &Arguments) -> io::Result<EventSink> {
+    let options = StraceOptions {
+        follow_forks: argv.follow_forks,
+        timestamps: argv.timestamps,
+        syscall_times: argv.syscall_times,
+    };
+    let out: Box<dyn Write> = if output.as_os_str() == "-" {
+        Box::new(io::stderr())
+    } else {
+        let mut out = TraceOutput::create(output, Duration::from_secs(1))?;
+        out.set_crash_marker(Box::new(|reason| {
+            format!("+++ tracer crashed: {} +++\n", reason)
+        }));
+        Box::new(out)
+    };
+    Ok(StraceWriter::new(out, options).into_sink())
+}
+
 fn run_tracer(
     starting_pid: unistd::Pid,
     starting_uid: unistd::Uid,
@@ -274,61 +448,126 @@ fn run_tracer(
         debug_assert!(unistd::getpid() == unistd::Pid::from_raw(1));
     }
 
-    let memfd_name = std::ffi::CStr::from_bytes_with_nul(&[
-        b'r', b'e', b'v', b'e', b'r', b'i', b'e', 0,
-    ])
-    .unwrap();
-    let fd_ = memfd::memfd_create(&memfd_name, memfd::MemFdCreateFlag::empty())
-        .expect("memfd_create failed");
-    let memfd = unistd::dup2(fd_, consts::REVERIE_GLOBAL_STATE_FD)
-        .expect("dup2 to REVERIE_GLOBAL_STATE_FD failed");
-    let _ = unistd::close(fd_);
-    let glob_size = 32768 * 4096;
-    let _ = unistd::ftruncate(memfd, 32768 * 4096)
-        .expect(&format!("memfd, unable to alloc {} bytes.", glob_size));
-
-    match unistd::fork().expect("fork failed") {
-        ForkResult::Child => run_tracee(argv),
-        ForkResult::Parent { child } => {
-            // wait for sigstop
-            wait_sigstop(child)?;
-            ptrace::setoptions(
-                child,
-                ptrace::Options::PTRACE_O_TRACEEXEC
-                    | ptrace::Options::PTRACE_O_EXITKILL
-                    | ptrace::Options::PTRACE_O_TRACECLONE
-                    | ptrace::Options::PTRACE_O_TRACEFORK
-                    | ptrace::Options::PTRACE_O_TRACEVFORK
-                    | ptrace::Options::PTRACE_O_TRACEVFORKDONE
-                    | ptrace::Options::PTRACE_O_TRACEEXIT
-                    | ptrace::Options::PTRACE_O_TRACESECCOMP
-                    | ptrace::Options::PTRACE_O_TRACESYSGOOD,
-            )
-            .map_err(|e| Error::new(ErrorKind::Other, e))?;
-            ptrace::cont(child, None)
-                .map_err(|e| Error::new(ErrorKind::Other, e))?;
-            let tracee = Task::new(child);
-            let cbs = TaskEventCB::new(
-                Box::new(task_exec_cb),
-                Box::new(task_fork_cb),
-                Box::new(task_clone_cb),
-                Box::new(task_exit_cb),
+    // entered by the tracee, which is forked below.
+    if argv.ns.contains(&ns::Namespace::Time) {
+        let offsets = ns::TimeOffsets {
+            monotonic: argv.monotonic_offset,
+            boottime: argv.boottime_offset,
+        };
+        if let Err(err) = ns::init_time_ns(&offsets) {
+            log::warn!(
+                "[main] cannot create time namespace, clocks not offset: {}",
+                err
             );
-            let mut sched: SchedWait<i32> = SchedWait::new(cbs, 0);
-            sched.add(tracee);
-            let res = run_tracer_main(&mut sched);
-            if argv.show_perf_stats {
-                let _ = reverie_global_state().lock().as_ref().and_then(|st| {
-                    show_perf_stats(st);
-                    Ok(())
-                });
+        }
+    }
+
+    attach::init_global_state();
+
+    let child = sched_wait::spawn_stopped(|| run_tracee(argv))?;
+    ptrace::cont(child, None).map_err(|e| Error::new(ErrorKind::Other, e))?;
+    trace_tasks(argv, vec![Task::new(child)], false)
+}
+
+// trace seized `tasks` until all exited. with `ptrace_only`, syscalls are
+// stopped by `PTRACE_SYSEMU` rather than by a seccomp filter.
+fn trace_tasks(
+    argv: &Arguments,
+    tasks: Vec<TracedTask>,
+    ptrace_only: bool,
+) -> io::Result<i32> {
+    let mut cbs = TaskEventCB::new(
+        Box::new(task_exec_cb),
+        Box::new(task_fork_cb),
+        Box::new(task_clone_cb),
+        Box::new(task_exit_cb),
+    );
+    cbs.shared_memory = argv.shared_memory;
+    let mut recorder = None;
+    match argv.mode {
+        TraceMode::Strace => {
+            if let Some(output) = &argv.output {
+                cbs.set_event_sink(strace_sink(output, argv)?);
+                cbs.string_limit = Some(argv.string_limit);
             }
-            Ok(res)
         }
+        TraceMode::Deps => {
+            let root = match &argv.deps_root {
+                Some(root) => root.canonicalize()?,
+                None => env::current_dir()?,
+            };
+            let deps = Rc::new(RefCell::new(DepsRecorder::new(root)));
+            let (emulation, sink) = deps::deps_tracing(deps.clone());
+            cbs.set_syscall_emulation(emulation);
+            cbs.set_event_sink(sink);
+            recorder = Some(Recorder::Deps(deps));
+        }
+        TraceMode::Flaky => {
+            let flaky = Rc::new(RefCell::new(FlakyRecorder::new()));
+            let (emulation, sink) = flaky::flaky_tracing(flaky.clone());
+            cbs.set_syscall_emulation(emulation);
+            cbs.set_event_sink(sink);
+            recorder = Some(Recorder::Flaky(flaky));
+        }
+    }
+    if ptrace_only {
+        // declines all syscalls, see `EmulationMode::Sysemu`
+        let ptrace_only = SyscallEmulation::new(
+            EmulationMode::Sysemu,
+            Vec::new(),
+            Box::new(|_task, _memory, _syscall, _args| None),
+        );
+        cbs.chain_syscall_emulation(ptrace_only);
+    }
+    let mut sched: SchedWait<i32> = SchedWait::new(cbs, 0);
+    sched.set_policy(argv.sched_policy);
+    sched.set_unknown_event_policy(argv.unknown_event);
+    for task in tasks {
+        sched.add(task);
+    }
+    let res = run_tracer_main(&mut sched);
+    if let Some(recorder) = recorder {
+        write_recording(&recorder, argv)?;
+    }
+    if argv.show_perf_stats {
+        let _ = reverie_global_state().lock().as_ref().and_then(|st| {
+            show_perf_stats(st);
+            Ok(())
+        });
+    }
+    Ok(res)
+}
+
+fn attach(pid: unistd::Pid, argv: &Arguments) -> io::Result<i32> {
+    let tasks = attach::seize_process(pid, true)?;
+    log::info!("[main] attached to {}, {} threads", pid, tasks.len());
+    trace_tasks(argv, tasks, true)
+}
+
+fn write_recording(recorder: &Recorder, argv: &Arguments) -> io::Result<()> {
+    let default_output = match recorder {
+        Recorder::Deps(_) => DEFAULT_DEPS_OUTPUT,
+        Recorder::Flaky(_) => DEFAULT_FLAKY_OUTPUT,
+    };
+    let output = argv
+        .output
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(default_output));
+    let out: Box<dyn Write> = if output.as_os_str() == "-" {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::BufWriter::new(std::fs::File::create(output)?))
+    };
+    match recorder {
+        Recorder::Deps(deps) => deps.borrow().write_manifest(out),
+        Recorder::Flaky(flaky) => flaky.borrow().write_trace(out),
     }
 }
 
 fn run_app(argv: &Arguments) -> io::Result<i32> {
+    if let Some(pid) = argv.pid {
+        return attach(unistd::Pid::from_raw(pid), argv);
+    }
     let (starting_pid, starting_uid, starting_gid) =
         (unistd::getpid(), unistd::getuid(), unistd::getgid());
 
@@ -392,6 +631,17 @@ fn main(args: Arguments) {
     setup_logger(args.log_level, args.log_output.as_ref().map(|s| s.as_ref()))
         .expect("set log level");
 
+    // deps and flaky record from syscall events, none can be skipped.
+    if let Some(spec) = &args.sample {
+        if args.mode != TraceMode::Strace {
+            log::warn!("[main] --sample ignored by --mode {:?}", args.mode);
+        } else {
+            std::env::set_var(consts::REVERIE_SAMPLING, spec);
+        }
+    }
+    if args.mode != TraceMode::Strace {
+        std::env::remove_var(consts::REVERIE_SAMPLING);
+    }
     match run_app(&args) {
         Ok(exit_code) => std::process::exit(exit_code),
         err => panic!("run app failed with error: {:?}", err),
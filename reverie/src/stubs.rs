@@ -17,36 +17,22 @@ use std::fs::File;
 use std::io::{Error, ErrorKind, Result, Write};
 use std::path::PathBuf;
 
+use reverie_api::arch::{Arch, Host};
 use reverie_common::consts;
 
 use crate::hooks;
+use crate::relocate;
 
-// jmp *0x0(pc)
-// .qword offset_64bit.
-const X64_JUMP_ABS_PC_RELA: &[u8] = &[0xff, 0x25, 0x00, 0x00, 0x00, 0x00];
-
+// jmp *0x0(pc) on x86_64, see `Arch::extended_jump`
 fn gen_extended_jump(jump_address: u64) -> Vec<u8> {
-    let mut res: Vec<u8> = Vec::new();
-
-    X64_JUMP_ABS_PC_RELA.iter().for_each(|c| res.push(*c));
-
-    res.push((jump_address.wrapping_shr(0) & 0xff) as u8);
-    res.push((jump_address.wrapping_shr(8) & 0xff) as u8);
-    res.push((jump_address.wrapping_shr(16) & 0xff) as u8);
-    res.push((jump_address.wrapping_shr(24) & 0xff) as u8);
-    res.push((jump_address.wrapping_shr(32) & 0xff) as u8);
-    res.push((jump_address.wrapping_shr(40) & 0xff) as u8);
-    res.push((jump_address.wrapping_shr(48) & 0xff) as u8);
-    res.push((jump_address.wrapping_shr(56) & 0xff) as u8);
-
-    debug_assert_eq!(res.len(), 14);
-
+    let res = Host::extended_jump(jump_address);
+    debug_assert_eq!(res.len(), Host::EXTENDED_JUMP_SIZE);
     res
 }
 
 #[test]
 fn extend_jump_sanity() {
-    let expected_size = X64_JUMP_ABS_PC_RELA.len() + std::mem::size_of::<u64>();
+    let expected_size = Host::EXTENDED_JUMP_SIZE;
     assert_eq!(gen_extended_jump(0x0).len(), expected_size);
     assert_eq!(gen_extended_jump(0x12345678).len(), expected_size);
     assert_eq!(
@@ -55,31 +41,115 @@ fn extend_jump_sanity() {
     );
 }
 
-pub fn extended_jump_size() -> usize {
-    0x80
+/// slot alignment within the stub page(s)
+const STUB_ALIGN: usize = 16;
+
+/// layout of the stub slot of a hook, see `stub_layout`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StubLayout {
+    /// offset from the stub page(s)
+    pub offset: usize,
+    /// slot size: the extended jump, followed by room for the hook's
+    /// instructions (relocated), and padding
+    pub size: usize,
+}
+
+fn align_up(size: usize, align: usize) -> usize {
+    size.div_ceil(align) * align
 }
 
-// 2*4096 / 128 => 64
-pub fn extended_jump_pages() -> usize {
-    2
+/// stub slots of `hooks`, in order. slots are variable-sized, so that
+/// hooks can reserve room for relocated instructions.
+pub fn stub_layout(hooks: &[hooks::SyscallHook]) -> Vec<StubLayout> {
+    let mut offset = 0;
+    hooks
+        .iter()
+        .map(|hook| {
+            let size = gen_extended_jump(0).len() + hook.instructions.len();
+            let size = align_up(size, STUB_ALIGN);
+            let layout = StubLayout { offset, size };
+            offset += size;
+            layout
+        })
+        .collect()
+}
+
+/// pages to allocate for the stubs of `hooks`, the rest of which holds
+/// relocation thunks, see `gen_relocation_thunk`.
+pub fn extended_jump_pages(hooks: &[hooks::SyscallHook]) -> usize {
+    let size = stub_layout(hooks)
+        .last()
+        .map(|layout| layout.offset + layout.size)
+        .unwrap_or(0);
+    size.div_ceil(0x1000) + 1
 }
 
 /// generate indirect jump stubs at given target `addr`, for predefine
-/// `hooks`.
+/// `hooks`, laid out as `stub_layout`.
 pub fn gen_extended_jump_stubs(
     hooks: &[hooks::SyscallHook],
     addr: u64,
 ) -> Vec<u8> {
     let mut res: Vec<u8> = Vec::new();
-    hooks.iter().for_each(|hook| {
-        assert!(hook.instructions.len() <= extended_jump_size());
-        let mut stub = gen_extended_jump(hook.offset + addr);
-        let pad = extended_jump_size() - stub.len();
-        res.append(&mut stub);
-        for _i in 0..pad {
-            res.push(0);
-        }
-        debug_assert!(res.len() % extended_jump_size() == 0);
-    });
+    hooks
+        .iter()
+        .zip(stub_layout(hooks))
+        .for_each(|(hook, layout)| {
+            debug_assert_eq!(res.len(), layout.offset);
+            let mut stub = gen_extended_jump(hook.offset + addr);
+            assert!(stub.len() <= layout.size);
+            res.append(&mut stub);
+            res.resize(layout.offset + layout.size, 0);
+        });
     res
 }
+
+/// generate a thunk at `at`, running instructions `insns` (each with its
+/// original address) relocated, then jumping to `return_to`.
+pub fn gen_relocation_thunk(
+    insns: &[(u64, Vec<u8>)],
+    at: u64,
+    return_to: u64,
+) -> Result<Vec<u8>> {
+    let mut res: Vec<u8> = Vec::new();
+    for (from, insn) in insns {
+        let to = at + res.len() as u64;
+        res.append(&mut relocate::relocate_insn(insn, *from, to)?);
+    }
+    res.append(&mut gen_extended_jump(return_to));
+    Ok(res)
+}
+
+#[test]
+fn stub_layout_sanity_check() {
+    let hook = |offset| hooks::SyscallHook {
+        name: String::new(),
+        offset,
+        instructions: vec![0x48, 0x3d, 0x01, 0xf0, 0xff, 0xff],
+        is_multi: false,
+        libcs: crate::libc_flavor::ANY_LIBC,
+    };
+    let hooks = [hook(0x100), hook(0x200)];
+    let layout = stub_layout(&hooks);
+    assert_eq!(
+        layout[0],
+        StubLayout {
+            offset: 0,
+            size: 32
+        }
+    );
+    assert_eq!(
+        layout[1],
+        StubLayout {
+            offset: 32,
+            size: 32
+        }
+    );
+    assert_eq!(gen_extended_jump_stubs(&hooks, 0x1000).len(), 64);
+    assert_eq!(extended_jump_pages(&hooks), 2);
+    // mov 0x10(%rip),%rax
+    let mov = vec![0x48, 0x8b, 0x05, 0x10, 0x00, 0x00, 0x00];
+    let thunk = gen_relocation_thunk(&[(0x1000, mov)], 0x2000, 0x1007).unwrap();
+    assert_eq!(&thunk[..7], &[0x48, 0x8b, 0x05, 0x10, 0xf0, 0xff, 0xff]);
+    assert_eq!(&thunk[7..], gen_extended_jump(0x1007).as_slice());
+}
@@ -0,0 +1,106 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! `PTRACE_SYSEMU` execution mode
+//!
+//! tasks resumed by `PTRACE_SYSEMU` stop on entry of every syscall, which
+//! the kernel then skips. syscalls emulated by the tool are completed by
+//! setting the return value. others are rewound and resumed by
+//! `PTRACE_SYSCALL`, so that the kernel runs them (through the seccomp
+//! filter as usual) on the second try:
+//!
+//! `Entry` --(rewound)--> `Rerun` --(entry stop)--> `Running` --(exit
+//! stop)--> `Off`, and the task is resumed by `PTRACE_SYSEMU` again.
+
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+
+use crate::reverie_common::consts;
+
+pub const PTRACE_SYSEMU: libc::c_uint = 31;
+pub const PTRACE_SYSEMU_SINGLESTEP: libc::c_uint = 32;
+
+/// what the next `PTRACE_SYSCALL` stop of a task is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SysemuState {
+    /// not resumed by `PTRACE_SYSEMU`, a syscall exit
+    Off,
+    /// resumed by `PTRACE_SYSEMU`, a syscall entry
+    Entry,
+    /// syscall rewound to be run by the kernel, its entry
+    Rerun,
+    /// syscall run by the kernel, its exit
+    Running,
+}
+
+impl Default for SysemuState {
+    fn default() -> Self {
+        SysemuState::Off
+    }
+}
+
+impl SysemuState {
+    /// whether the task is to be resumed by `PTRACE_SYSCALL` rather than
+    /// `PTRACE_SYSEMU`
+    pub fn is_rerun(self) -> bool {
+        self == SysemuState::Rerun || self == SysemuState::Running
+    }
+}
+
+fn ptrace_resume(
+    req: libc::c_uint,
+    tid: Pid,
+    sig: Option<Signal>,
+) -> nix::Result<()> {
+    let data = sig.map(|sig| sig as u64).unwrap_or(0);
+    let res = unsafe {
+        libc::ptrace(req, tid.as_raw(), std::ptr::null_mut::<u8>(), data)
+    };
+    nix::errno::Errno::result(res).map(drop)
+}
+
+/// resume `tid` until the next syscall entry, which is not run
+pub fn sysemu(tid: Pid, sig: Option<Signal>) -> nix::Result<()> {
+    ptrace_resume(PTRACE_SYSEMU, tid, sig)
+}
+
+/// single step `tid`, a syscall instruction stops on entry and is not run
+pub fn sysemu_singlestep(tid: Pid, sig: Option<Signal>) -> nix::Result<()> {
+    ptrace_resume(PTRACE_SYSEMU_SINGLESTEP, tid, sig)
+}
+
+/// resume `tid` until the next syscall entry or exit
+pub fn syscall(tid: Pid, sig: Option<Signal>) -> nix::Result<()> {
+    ptrace_resume(libc::PTRACE_SYSCALL, tid, sig)
+}
+
+/// registers to run the syscall stopped on sysemu entry (`regs`) again
+pub fn rerun_regs(regs: libc::user_regs_struct) -> libc::user_regs_struct {
+    let mut new_regs = regs;
+    new_regs.rip -= consts::SYSCALL_INSN_SIZE as u64;
+    new_regs.rax = regs.orig_rax;
+    new_regs
+}
+
+#[test]
+fn sysemu_rerun_sanity_check() {
+    let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+    regs.rip = 0x401002;
+    regs.orig_rax = 39;
+    regs.rax = -38i64 as u64;
+    let new_regs = rerun_regs(regs);
+    assert_eq!(new_regs.rip, 0x401000);
+    assert_eq!(new_regs.rax, 39);
+    assert!(!SysemuState::Entry.is_rerun());
+    assert!(SysemuState::Running.is_rerun());
+}
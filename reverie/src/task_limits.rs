@@ -0,0 +1,232 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! fork bomb protection, of `--max-tasks` and `--max-forks-per-second`
+//!
+//! tasks are counted from their creation (`Event::Fork`, `Spawn` and
+//! `Clone`) to their exit, and processes created in the last second. the
+//! syscalls creating tasks are emulated: once over a limit, they fail with
+//! `EAGAIN` (as of `RLIMIT_NPROC`), and the offending process is logged as
+//! a warning, hence reported, see `report`. with `LimitAction::Freeze`,
+//! the whole tree is stopped and detached instead, left for inspection.
+//!
+//! `clone3` is not known by `syscalls`, it fails with `ENOSYS` instead
+//! (see `lies`), so that programs fall back to `clone`.
+
+use nix::sched::CloneFlags;
+use nix::unistd::Pid;
+use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use syscalls::SyscallNo;
+
+use reverie_api::emulate::*;
+use reverie_api::event::*;
+
+use crate::clone_flags::decode_clone_flags;
+
+/// what to do once over a limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitAction {
+    /// fail the syscall with `EAGAIN`
+    Deny,
+    /// stop and detach the tree, see module doc
+    Freeze,
+}
+
+impl FromStr for LimitAction {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "deny" => Ok(LimitAction::Deny),
+            "freeze" => Ok(LimitAction::Freeze),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown task limit action: {}", s),
+            )),
+        }
+    }
+}
+
+static FREEZE: AtomicBool = AtomicBool::new(false);
+
+/// whether the tree is to be frozen, once
+pub fn take_freeze() -> bool {
+    FREEZE.swap(false, Ordering::SeqCst)
+}
+
+// command line of process `pid`, space separated
+fn cmdline(pid: Pid) -> String {
+    let cmdline = fs::read(format!("/proc/{}/cmdline", pid));
+    let cmdline = cmdline.unwrap_or_default();
+    let args: Vec<_> = cmdline
+        .split(|&b| b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(String::from_utf8_lossy)
+        .collect();
+    args.join(" ")
+}
+
+/// limits of tasks, see module doc
+pub struct TaskLimits {
+    max_tasks: Option<usize>,
+    max_forks_per_second: Option<usize>,
+    action: LimitAction,
+    /// tasks alive
+    tasks: HashSet<Pid>,
+    /// processes created in the last second
+    forks: VecDeque<Instant>,
+}
+
+impl TaskLimits {
+    pub fn new(
+        max_tasks: Option<usize>,
+        max_forks_per_second: Option<usize>,
+        action: LimitAction,
+    ) -> Self {
+        TaskLimits {
+            max_tasks,
+            max_forks_per_second,
+            action,
+            tasks: HashSet::new(),
+            forks: VecDeque::new(),
+        }
+    }
+
+    /// whether task `tid` may create a task (a thread if `thread`) at
+    /// `now`, the limit exceeded otherwise
+    pub fn admit(
+        &mut self,
+        tid: Pid,
+        thread: bool,
+        now: Instant,
+    ) -> std::result::Result<(), String> {
+        self.tasks.insert(tid);
+        while let Some(&oldest) = self.forks.front() {
+            if now.duration_since(oldest) < Duration::from_secs(1) {
+                break;
+            }
+            self.forks.pop_front();
+        }
+        match (self.max_tasks, self.max_forks_per_second) {
+            (Some(max), _) if self.tasks.len() >= max => {
+                return Err(format!("{} tasks", max));
+            }
+            (_, Some(max)) if !thread && self.forks.len() >= max => {
+                return Err(format!("{} forks per second", max));
+            }
+            _ => (),
+        }
+        if !thread {
+            self.forks.push_back(now);
+        }
+        Ok(())
+    }
+
+    /// `event` observed by the tracer
+    pub fn record_event(&mut self, event: &TimedEvent) {
+        match &event.event {
+            Event::Fork(child) | Event::Spawn(child) | Event::Clone(child) => {
+                self.tasks.insert(event.tid);
+                self.tasks.insert(*child);
+            }
+            Event::Exited(_) => {
+                self.tasks.remove(&event.tid);
+            }
+            _ => (),
+        }
+    }
+
+    // task `tid` of process `pid` denied creating a task, over `limit`
+    fn exceeded(&self, pid: Pid, tid: Pid, limit: &str) -> i64 {
+        log::warn!(
+            "[limits] over {}: task {} of process {} ({}) denied",
+            limit,
+            tid,
+            pid,
+            cmdline(pid)
+        );
+        if self.action == LimitAction::Freeze {
+            log::warn!("[limits] freezing the tree");
+            FREEZE.store(true, Ordering::SeqCst);
+        }
+        -(libc::EAGAIN as i64)
+    }
+}
+
+/// syscall emulation (denying task creation over limits) and event sink
+/// counting tasks with `limits`
+pub fn task_limiting(
+    limits: Rc<RefCell<TaskLimits>>,
+) -> (SyscallEmulation, EventSink) {
+    let creates = limits.clone();
+    let emulation = SyscallEmulation::new(
+        EmulationMode::Seccomp,
+        vec![
+            SyscallNo::SYS_clone,
+            SyscallNo::SYS_fork,
+            SyscallNo::SYS_vfork,
+        ],
+        Box::new(move |task, _memory, syscall, args| {
+            let flags =
+                decode_clone_flags(syscall as u64, args.arg0, |_| None)?;
+            let thread = flags.contains(CloneFlags::CLONE_THREAD);
+            let mut limits = creates.borrow_mut();
+            let tid = task.gettid();
+            match limits.admit(tid, thread, Instant::now()) {
+                Ok(()) => None,
+                Err(limit) => Some(limits.exceeded(task.getpid(), tid, &limit)),
+            }
+        }),
+    );
+    let sink: EventSink =
+        Box::new(move |event| limits.borrow_mut().record_event(event));
+    (emulation, sink)
+}
+
+#[test]
+fn task_limits_sanity_check() {
+    assert_eq!(
+        "freeze".parse::<LimitAction>().unwrap(),
+        LimitAction::Freeze
+    );
+    assert!("kill".parse::<LimitAction>().is_err());
+    let now = Instant::now();
+    let mut limits = TaskLimits::new(Some(3), Some(2), LimitAction::Deny);
+    let parent = Pid::from_raw(i32::MAX - 2);
+    assert!(limits.admit(parent, false, now).is_ok());
+    assert!(limits.admit(parent, true, now).is_ok());
+    assert!(limits.admit(parent, false, now).is_ok());
+    assert!(limits.admit(parent, false, now).is_err());
+    let later = now + Duration::from_secs(1);
+    assert!(limits.admit(parent, false, later).is_ok());
+    let event = |tid, event| TimedEvent {
+        tid,
+        at: Default::default(),
+        ticks: None,
+        event,
+    };
+    let (a, b) = (Pid::from_raw(i32::MAX - 1), Pid::from_raw(i32::MAX));
+    limits.record_event(&event(parent, Event::Fork(a)));
+    limits.record_event(&event(parent, Event::Spawn(b)));
+    let err = limits.admit(parent, true, later).unwrap_err();
+    assert_eq!(err, "3 tasks");
+    limits.record_event(&event(b, Event::Exited(0)));
+    assert!(limits.admit(parent, true, later).is_ok());
+    assert!(!take_freeze());
+}
@@ -0,0 +1,518 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! ticks: conditional branches retired by a task, see `TaskEventCB::ticks`
+//!
+//! as in rr, the number of conditional branches a task retired in user mode
+//! is a deterministic measure of its progress. it's counted by a perf
+//! counter per task, opened at the first stop of the task, and read at
+//! each stop.
+//!
+//! with a timeslice, tasks are interrupted by `TICKS_SIGNAL` when a second
+//! (sampling) counter overflows, see `PERF_EVENT_IOC_REFRESH`. the
+//! interrupt is imprecise, so it's armed `SKID` ticks early, and the task
+//! single-stepped up to the exact tick: preemptions are at the same points
+//! across runs, and replays. stepping stops short of `syscall`s and
+//! `int3`s, which are deterministic points as well.
+//!
+//! when replaying, asynchronous signals are delivered at the `ExecPoint`
+//! they were received at: the task is interrupted at its ticks, then
+//! stepped up to the instruction and registers recorded. signals due at a
+//! syscall stop are sent by `inject`.
+
+use nix::sys::ptrace;
+use nix::sys::signal::Signal;
+use nix::sys::wait::{self, WaitStatus};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use reverie_api::remote::from_nix_error;
+use reverie_api::ticks::ExecPoint;
+
+use crate::dying;
+
+const PERF_TYPE_RAW: u32 = 4;
+/// `PERF_ATTR_SIZE_VER5`
+const PERF_ATTR_SIZE: u32 = 112;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 8;
+
+const ATTR_DISABLED: u64 = 1 << 0;
+const ATTR_EXCLUDE_KERNEL: u64 = 1 << 5;
+const ATTR_EXCLUDE_HV: u64 = 1 << 6;
+
+const PERF_EVENT_IOC_DISABLE: libc::c_ulong = 0x2401;
+const PERF_EVENT_IOC_REFRESH: libc::c_ulong = 0x2402;
+const PERF_EVENT_IOC_PERIOD: libc::c_ulong = 0x4008_2404;
+
+const F_SETSIG: libc::c_int = 10;
+const F_SETOWN_EX: libc::c_int = 15;
+const F_OWNER_TID: libc::c_int = 0;
+
+/// signal interrupting tasks at the end of their timeslice
+pub const TICKS_SIGNAL: Signal = Signal::SIGSTKFLT;
+
+/// ticks the overflow interrupt may be late by
+const SKID: u64 = 100;
+
+/// period of the sampling counter while not armed
+const NEVER: u64 = 1 << 62;
+
+const INT3: u64 = 0xcc;
+const SYSCALL_INSN: u64 = 0x050f;
+
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+    branch_sample_type: u64,
+    sample_regs_user: u64,
+    sample_stack_user: u32,
+    clockid: i32,
+    sample_regs_intr: u64,
+    aux_watermark: u32,
+    sample_max_stack: u16,
+    reserved: u16,
+}
+
+#[repr(C)]
+struct FOwnerEx {
+    type_: libc::c_int,
+    pid: libc::c_int,
+}
+
+/// set once counters failed to open, i.e.: no PMU in a VM
+static UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+// raw perf event of retired conditional branches, by cpu vendor
+fn conditional_branches() -> Option<u64> {
+    let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").ok()?;
+    let vendor = cpuinfo
+        .lines()
+        .find(|line| line.starts_with("vendor_id"))?
+        .split(':')
+        .nth(1)?
+        .trim();
+    match vendor {
+        // BR_INST_RETIRED.CONDITIONAL
+        "GenuineIntel" => Some(0x5101c4),
+        // ExRetCond
+        "AuthenticAMD" | "HygonGenuine" => Some(0x5100d1),
+        _ => None,
+    }
+}
+
+fn perf_event_open(
+    tid: Pid,
+    config: u64,
+    sample_period: u64,
+    flags: u64,
+) -> Result<File> {
+    let attr = PerfEventAttr {
+        type_: PERF_TYPE_RAW,
+        size: PERF_ATTR_SIZE,
+        config,
+        sample_period,
+        flags: flags | ATTR_EXCLUDE_KERNEL | ATTR_EXCLUDE_HV,
+        wakeup_events: 1,
+        ..Default::default()
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            &attr as *const PerfEventAttr,
+            tid.as_raw(),
+            -1,
+            -1,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd as i32) })
+}
+
+fn ioctl(
+    file: &File,
+    request: libc::c_ulong,
+    arg: libc::c_ulong,
+) -> Result<()> {
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), request, arg) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn fcntl(file: &File, cmd: libc::c_int, arg: libc::c_ulong) -> Result<()> {
+    let ret = unsafe { libc::fcntl(file.as_raw_fd(), cmd, arg) };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// conditional branches retired by a task in user mode
+pub struct TicksCounter {
+    tid: Pid,
+    counter: File,
+    /// sampling counter, interrupting the task on overflow
+    interrupt: File,
+    /// tick the task is to be interrupted at, if armed
+    target: Option<u64>,
+}
+
+impl TicksCounter {
+    /// count ticks of task `tid`, from now on
+    pub fn open(tid: Pid) -> Result<Self> {
+        let config = conditional_branches().ok_or_else(|| {
+            Error::new(ErrorKind::Other, "ticks not supported by the cpu")
+        })?;
+        let counter = perf_event_open(tid, config, 0, 0)?;
+        let interrupt = perf_event_open(tid, config, NEVER, ATTR_DISABLED)?;
+        let owner = FOwnerEx {
+            type_: F_OWNER_TID,
+            pid: tid.as_raw(),
+        };
+        let owner = &owner as *const FOwnerEx as libc::c_ulong;
+        fcntl(&interrupt, F_SETOWN_EX, owner)?;
+        fcntl(&interrupt, F_SETSIG, TICKS_SIGNAL as libc::c_ulong)?;
+        fcntl(&interrupt, libc::F_SETFL, libc::O_ASYNC as libc::c_ulong)?;
+        Ok(TicksCounter {
+            tid,
+            counter,
+            interrupt,
+            target: None,
+        })
+    }
+
+    /// ticks so far
+    pub fn read(&self) -> Result<u64> {
+        let mut bytes = [0u8; 8];
+        (&self.counter).read_exact(&mut bytes)?;
+        Ok(u64::from_ne_bytes(bytes))
+    }
+
+    /// interrupt the task by `TICKS_SIGNAL`, `SKID` ticks (at most) before
+    /// it retired `target` ticks, see `step_to`
+    pub fn interrupt_at(&mut self, target: u64) -> Result<()> {
+        let ticks = target.saturating_sub(self.read()?);
+        let period: u64 = ticks.saturating_sub(SKID).max(1);
+        let period = &period as *const u64 as libc::c_ulong;
+        ioctl(&self.interrupt, PERF_EVENT_IOC_PERIOD, period)?;
+        ioctl(&self.interrupt, PERF_EVENT_IOC_REFRESH, 1)?;
+        self.target = Some(target);
+        Ok(())
+    }
+
+    /// tick the task is to be interrupted at, if armed
+    pub fn target(&self) -> Option<u64> {
+        self.target
+    }
+
+    pub fn disarm(&mut self) -> Result<()> {
+        self.target = None;
+        ioctl(&self.interrupt, PERF_EVENT_IOC_DISABLE, 0)
+    }
+
+    /// single-step the (stopped) task until it retired `target` ticks, or
+    /// is about to run a `syscall` or an `int3`. returns the ticks it
+    /// stopped at, and the signal it received meanwhile, if any.
+    pub fn step_to(&self, target: u64) -> Result<(u64, Option<Signal>)> {
+        self.step_until(|ticks, _| ticks >= target)
+    }
+
+    /// single-step the (stopped) task up to `point`, or past it, as of
+    /// `step_to`. returns whether at `point` as well.
+    pub fn step_to_point(
+        &self,
+        point: &ExecPoint,
+    ) -> Result<(u64, Option<Signal>, bool)> {
+        let (ticks, sig) = self.step_until(|ticks, regs| {
+            ticks > point.ticks || ExecPoint::new(ticks, regs) == *point
+        })?;
+        let regs = ptrace::getregs(self.tid).map_err(from_nix_error)?;
+        Ok((ticks, sig, ExecPoint::new(ticks, &regs) == *point))
+    }
+
+    fn step_until<F>(&self, done: F) -> Result<(u64, Option<Signal>)>
+    where
+        F: Fn(u64, &libc::user_regs_struct) -> bool,
+    {
+        let tid = self.tid;
+        loop {
+            let ticks = self.read()?;
+            let regs = ptrace::getregs(tid).map_err(from_nix_error)?;
+            if done(ticks, &regs) {
+                return Ok((ticks, None));
+            }
+            let insn = ptrace::read(tid, regs.rip as ptrace::AddressType)
+                .map_err(from_nix_error)? as u64;
+            if insn & 0xff == INT3 || insn & 0xffff == SYSCALL_INSN {
+                return Ok((ticks, None));
+            }
+            ptrace::step(tid, None).map_err(from_nix_error)?;
+            match wait::waitpid(tid, None) {
+                Ok(WaitStatus::Stopped(_, Signal::SIGTRAP)) => (),
+                Ok(WaitStatus::Stopped(_, sig)) => {
+                    return Ok((self.read()?, Some(sig)))
+                }
+                otherwise => {
+                    return Err(dying::unexpected_status(tid, otherwise))
+                }
+            }
+        }
+    }
+}
+
+/// ticks of a task, and what it's to be interrupted for
+struct TaskTicks {
+    counter: TicksCounter,
+    timeslice: Option<u64>,
+    /// tick the current timeslice ends at
+    slice_end: Option<u64>,
+    /// signal to deliver at a point, when replaying
+    signal: Option<(Signal, ExecPoint)>,
+    /// signal sent by `inject`, not to be suppressed
+    injected: Option<Signal>,
+}
+
+impl TaskTicks {
+    // interrupt the task at the end of its timeslice, or at the point of
+    // its signal, whichever first
+    fn rearm(&mut self) -> Result<()> {
+        let signal_at = self.signal.map(|(_, point)| point.ticks);
+        let target = match (self.slice_end, signal_at) {
+            (Some(end), Some(at)) => Some(end.min(at)),
+            (end, at) => end.or(at),
+        };
+        match target {
+            Some(target) => self.counter.interrupt_at(target),
+            None => self.counter.disarm(),
+        }
+    }
+}
+
+/// a task interrupted by its ticks counter, see `interrupted`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupt {
+    /// ticks it's stopped at
+    pub ticks: u64,
+    /// whether at the end of its timeslice
+    pub preempted: bool,
+    /// signal to deliver, replayed at its point
+    pub replayed: Option<(Signal, ExecPoint)>,
+    /// signal received while stepping, to deliver
+    pub received: Option<Signal>,
+}
+
+lazy_static! {
+    static ref TASKS: Mutex<HashMap<Pid, TaskTicks>> =
+        Mutex::new(HashMap::new());
+}
+
+/// count ticks of task `tid` if not yet, interrupting it every `timeslice`
+/// ticks if given. `false` if ticks can't be counted.
+pub fn start(tid: Pid, timeslice: Option<u64>) -> bool {
+    if UNSUPPORTED.load(Ordering::Relaxed) {
+        return false;
+    }
+    let mut tasks = TASKS.lock().unwrap_or_else(|e| e.into_inner());
+    if tasks.contains_key(&tid) {
+        return true;
+    }
+    let task = TicksCounter::open(tid).and_then(|counter| {
+        let slice_end = match timeslice {
+            Some(timeslice) => Some(counter.read()? + timeslice),
+            None => None,
+        };
+        let mut task = TaskTicks {
+            slice_end,
+            counter,
+            timeslice,
+            signal: None,
+            injected: None,
+        };
+        task.rearm()?;
+        Ok(task)
+    });
+    match task {
+        Ok(task) => {
+            tasks.insert(tid, task);
+            true
+        }
+        Err(err) => {
+            log::warn!("[ticks] cannot count ticks, not recorded: {}", err);
+            UNSUPPORTED.store(true, Ordering::Relaxed);
+            false
+        }
+    }
+}
+
+/// ticks of task `tid`, if counted
+pub fn ticks(tid: Pid) -> Option<u64> {
+    let tasks = TASKS.lock().unwrap_or_else(|e| e.into_inner());
+    tasks.get(&tid)?.counter.read().ok()
+}
+
+/// schedule the signal returned by `next` to be delivered to task `tid`,
+/// unless one is scheduled already
+pub fn schedule_signal<F>(tid: Pid, next: F) -> Result<()>
+where
+    F: FnOnce() -> Option<(Signal, ExecPoint)>,
+{
+    let mut tasks = TASKS.lock().unwrap_or_else(|e| e.into_inner());
+    match tasks.get_mut(&tid) {
+        Some(task) if task.signal.is_none() => {
+            task.signal = next();
+            match task.signal {
+                Some(_) => task.rearm(),
+                None => Ok(()),
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+/// signal scheduled for task `tid` which is due: at its point, or past
+/// it, with `regs` of the task
+pub fn due_signal(
+    tid: Pid,
+    regs: &libc::user_regs_struct,
+) -> Result<Option<Signal>> {
+    let mut tasks = TASKS.lock().unwrap_or_else(|e| e.into_inner());
+    let task = match tasks.get_mut(&tid) {
+        Some(task) => task,
+        None => return Ok(None),
+    };
+    let ticks = task.counter.read()?;
+    let (sig, point) = match task.signal {
+        Some((sig, point)) if ticks >= point.ticks => (sig, point),
+        _ => return Ok(None),
+    };
+    if ExecPoint::new(ticks, regs) != point {
+        log::debug!("[ticks] {} {:?} replayed at {} ticks", tid, sig, ticks);
+    }
+    task.signal = None;
+    task.rearm()?;
+    Ok(Some(sig))
+}
+
+/// send `sig` to task `tid` of process `pid`, see `take_injected`
+pub fn inject(pid: Pid, tid: Pid, sig: Signal) -> Result<()> {
+    let ret = unsafe {
+        libc::syscall(libc::SYS_tgkill, pid.as_raw(), tid.as_raw(), sig)
+    };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    if let Some(task) = TASKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_mut(&tid)
+    {
+        task.injected = Some(sig);
+    }
+    Ok(())
+}
+
+/// whether `sig` received by task `tid` was sent by `inject`
+pub fn take_injected(tid: Pid, sig: Signal) -> bool {
+    let mut tasks = TASKS.lock().unwrap_or_else(|e| e.into_inner());
+    match tasks.get_mut(&tid) {
+        Some(task) if task.injected == Some(sig) => {
+            task.injected = None;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// task `tid` stopped by `TICKS_SIGNAL`: if interrupted, it's stepped to
+/// the exact tick (and point, for a signal replayed), and interrupted
+/// again at the end of its next timeslice. `None` if the signal isn't an
+/// interrupt.
+pub fn interrupted(tid: Pid) -> Result<Option<Interrupt>> {
+    let mut tasks = TASKS.lock().unwrap_or_else(|e| e.into_inner());
+    let task = match tasks.get_mut(&tid) {
+        Some(task) => task,
+        None => return Ok(None),
+    };
+    let target = match task.counter.target() {
+        Some(target) if task.counter.read()? + SKID >= target => target,
+        _ => return Ok(None),
+    };
+    task.counter.disarm()?;
+    let (mut ticks, mut received) = task.counter.step_to(target)?;
+    let mut replayed = None;
+    match task.signal {
+        Some((sig, point)) if received.is_none() && ticks >= point.ticks => {
+            let (at, sig_received, exact) =
+                task.counter.step_to_point(&point)?;
+            if !exact {
+                log::warn!(
+                    "[ticks] {} {:?} replayed past {:?}",
+                    tid,
+                    sig,
+                    point
+                );
+            }
+            ticks = at;
+            received = sig_received;
+            if received.is_none() {
+                task.signal = None;
+                replayed = Some((sig, point));
+            }
+        }
+        _ => (),
+    }
+    let preempted = task.slice_end.map_or(false, |end| ticks >= end);
+    if preempted {
+        task.slice_end = task.timeslice.map(|timeslice| ticks + timeslice);
+    }
+    task.rearm()?;
+    Ok(Some(Interrupt {
+        ticks,
+        preempted,
+        replayed,
+        received,
+    }))
+}
+
+/// task `tid` exited
+pub fn exited(tid: Pid) {
+    TASKS.lock().unwrap_or_else(|e| e.into_inner()).remove(&tid);
+}
+
+#[test]
+fn perf_event_attr_size() {
+    assert_eq!(
+        std::mem::size_of::<PerfEventAttr>(),
+        PERF_ATTR_SIZE as usize
+    );
+}
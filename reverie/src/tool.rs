@@ -0,0 +1,140 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! tools in rust, run by the tracer
+//!
+//! a `Tool` is called on the stopped `TracedTask` (as `Task` and
+//! `TaskMemory`), in the tracer: unlike tools of the preloaded library, it
+//! needs no shared library of its own, at the cost of a ptrace stop per
+//! syscall it sees. syscalls it selects are seen at entry (and emulated if
+//! it returns a result), then at exit otherwise. `install` adds a tool to
+//! the callbacks of the tracer, after the emulation set (if any), see
+//! `SyscallEmulation::chain`. NB: tools are called with the callbacks
+//! borrowed, they may not set callbacks themselves.
+
+use nix::unistd::Pid;
+use std::cell::RefCell;
+use std::io::Result;
+use std::rc::Rc;
+use syscalls::SyscallNo;
+
+use reverie_api::emulate::*;
+use reverie_api::event::*;
+use reverie_api::remote::SyscallArgs;
+use reverie_api::task::Task;
+
+/// instrumentation run by the tracer, see module doc
+pub trait Tool {
+    /// syscalls seen by `on_syscall_enter` and `on_syscall_exit`, never
+    /// patched
+    fn syscalls(&self) -> Vec<SyscallNo> {
+        Vec::new()
+    }
+    /// `syscall` entered by `task`: its result (`-errno` on error) if
+    /// emulated, `None` for the kernel to run it
+    fn on_syscall_enter(
+        &mut self,
+        _task: &dyn Task,
+        _memory: &dyn TaskMemory,
+        _syscall: SyscallNo,
+        _args: &SyscallArgs,
+    ) -> Option<i64> {
+        None
+    }
+    /// `syscall` of `task`, not emulated, returned `retval`
+    fn on_syscall_exit(
+        &mut self,
+        _task: &dyn Task,
+        _memory: &dyn TaskMemory,
+        _syscall: SyscallNo,
+        _args: &SyscallArgs,
+        _retval: i64,
+    ) {
+    }
+    /// `task` exec'ed a new program, stopped at its entry point
+    fn on_exec(&mut self, _task: &mut dyn Task) -> Result<()> {
+        Ok(())
+    }
+    /// task `tid` exited with `exit_code`
+    fn on_exit(&mut self, _tid: Pid, _exit_code: i32) {}
+    /// any event, after the calls above
+    fn on_event(&mut self, _event: &TimedEvent) {}
+}
+
+/// add `tool` to the callbacks `cbs` of the tracer, see module doc
+pub fn install<T: Tool + 'static>(cbs: &mut TaskEventCB, tool: T) {
+    let tool = Rc::new(RefCell::new(tool));
+    let syscalls = tool.borrow().syscalls();
+    let enters = tool.clone();
+    let mut emulation = SyscallEmulation::new(
+        EmulationMode::Seccomp,
+        syscalls,
+        Box::new(move |task, memory, syscall, args| {
+            enters
+                .borrow_mut()
+                .on_syscall_enter(task, memory, syscall, args)
+        }),
+    );
+    let exits = tool.clone();
+    emulation.set_exit_handler(Box::new(
+        move |task, memory, syscall, args, retval| {
+            exits
+                .borrow_mut()
+                .on_syscall_exit(task, memory, syscall, args, retval)
+        },
+    ));
+    cbs.chain_syscall_emulation(emulation);
+    let execs = tool.clone();
+    let mut on_exec =
+        std::mem::replace(&mut cbs.on_task_exec, Box::new(|_| Ok(())));
+    cbs.on_task_exec = Box::new(move |task| {
+        on_exec(task)?;
+        execs.borrow_mut().on_exec(task)
+    });
+    cbs.add_event_sink(Box::new(move |event| {
+        let mut tool = tool.borrow_mut();
+        if let Event::Exited(exit_code) = event.event {
+            tool.on_exit(event.tid, exit_code);
+        }
+        tool.on_event(event);
+    }));
+}
+
+#[test]
+fn tool_sanity_check() {
+    #[derive(Default)]
+    struct Exits(Rc<RefCell<Vec<(Pid, i32)>>>);
+    impl Tool for Exits {
+        fn syscalls(&self) -> Vec<SyscallNo> {
+            vec![SyscallNo::SYS_getpid]
+        }
+        fn on_exit(&mut self, tid: Pid, exit_code: i32) {
+            self.0.borrow_mut().push((tid, exit_code));
+        }
+    }
+    let mut cbs = TaskEventCB::new(
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+    );
+    let exits = Exits::default();
+    let exited = exits.0.clone();
+    install(&mut cbs, exits);
+    let emulation = cbs.on_syscall_emulation.as_ref().unwrap();
+    assert!(emulation.is_emulated(SyscallNo::SYS_getpid));
+    let tid = Pid::from_raw(i32::MAX);
+    cbs.emit(tid, Default::default(), Event::Exec);
+    cbs.emit(tid, Default::default(), Event::Exited(3));
+    assert_eq!(&exited.borrow()[..], &[(tid, 3)]);
+}
@@ -31,6 +31,7 @@
 use goblin::elf::Elf;
 use libc;
 use log::{debug, info, trace, warn};
+use nix::sched::CloneFlags;
 use nix::sys::{mman, ptrace, signal, socket, uio, wait, wait::WaitStatus};
 use nix::unistd;
 use nix::unistd::Pid;
@@ -42,33 +43,80 @@ use std::fs::File;
 use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::mem::MaybeUninit;
 use std::ops::{Deref, DerefMut};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::ptr::NonNull;
 use std::rc::Rc;
 use std::sync::{atomic::Ordering, Arc, Mutex};
+use std::time::Duration;
 
 use reverie_common::consts;
 use reverie_common::consts::*;
+use reverie_common::dispatch::{self, Dispatch};
 use reverie_common::local_state::*;
+use reverie_common::sampling::*;
 use reverie_common::state::*;
+use reverie_common::tiers::{HandlingTier, TierTable};
 
+use reverie_api::arch::{self, Arch, Host, HostRegs};
+use reverie_api::clock::*;
+use reverie_api::emulate::*;
 use reverie_api::event::*;
+use reverie_api::fileless::{sha256, FilelessExec};
+use reverie_api::kill::*;
+use reverie_api::mapping::{MappingChange, WxPolicy};
 use reverie_api::remote::*;
+use reverie_api::search::{read_regions, Region};
+use reverie_api::shm::*;
+use reverie_api::strace;
 use reverie_api::task::*;
+use reverie_api::ticks::*;
+use reverie_api::violation::{Frame, ViolationAction};
 
+use reverie_seccomp::seccomp_bpf;
 use syscalls::*;
 
+use crate::adaptive;
 use crate::aux;
 use crate::auxv;
+use crate::binaries;
+use crate::budget::{self, Degradation};
+use crate::clone_flags::*;
+use crate::coverage;
 use crate::debug;
+use crate::detach;
+use crate::dirent_filter;
+use crate::dying;
+use crate::exec;
+use crate::fileless;
+use crate::got::{self, GotHook, GotPatch};
 use crate::hooks;
+use crate::libc_flavor;
+use crate::loader;
+use crate::mapping;
+use crate::passthrough;
 use crate::patcher::*;
+use crate::pinning;
+use crate::probes;
+use crate::process::*;
+use crate::provenance;
+use crate::quiesce;
+use crate::recording;
 use crate::remote_rwlock::*;
 use crate::rpc_ptrace::*;
+use crate::runtimes::{self, ManagedRuntime};
 use crate::sched_wait::*;
+use crate::session::SessionTask;
+use crate::shm;
+use crate::signal_filter;
 use crate::stubs;
+use crate::sysemu::{self, SysemuState};
+use crate::ticks::{self, Interrupt};
+use crate::unwind;
 
 use crate::vdso;
+use crate::violation;
+use crate::vsyscall::{self, SpecialMapping};
+use crate::wait_filter;
 
 lazy_static! {
 // get all symbols from tool dso
@@ -135,18 +183,55 @@ lazy_static! {
     };
 }
 
+lazy_static! {
+    static ref SYSCALL_SAMPLER: SyscallSampler = SyscallSampler::from_env();
+}
+
+/// sampler of syscalls stopped by seccomp, see `REVERIE_SAMPLING`
+pub fn syscall_sampler() -> &'static SyscallSampler {
+    &SYSCALL_SAMPLER
+}
+
+lazy_static! {
+    static ref SYSCALL_TIERS: TierTable = TierTable::from_env();
+}
+
+/// handling tiers of syscalls, see `REVERIE_TIERS`
+pub fn syscall_tiers() -> &'static TierTable {
+    &SYSCALL_TIERS
+}
+
+// tier of `syscall` of process `pid`, as dispatched by the tracer if set
+fn syscall_tier(pid: Pid, syscall: SyscallNo) -> HandlingTier {
+    match dispatch::dispatch_of(pid.as_raw(), syscall as i32) {
+        Some(Dispatch::Tier(tier)) => tier,
+        _ => SYSCALL_TIERS.tier(syscall as i32),
+    }
+}
+
 fn init_rpc_stack_data(task: &mut TracedTask) {
-    let _at = task.untraced_syscall(
-        SYS_mmap,
-        0,
-        0x8000,
-        u64::from((libc::PROT_READ | libc::PROT_WRITE) as u32),
-        u64::from((libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as u32),
-        -1i64 as u64,
-        0,
-    );
+    if task.process.borrow().passthrough.is_some() {
+        return;
+    }
+    let reused = task.process.borrow_mut().rpc_areas.pop();
+    let _at = match reused {
+        Some(at) => Ok(at as i64),
+        None => task.untraced_syscall(
+            SYS_mmap,
+            0,
+            0x8000,
+            u64::from((libc::PROT_READ | libc::PROT_WRITE) as u32),
+            u64::from((libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as u32),
+            -1i64 as u64,
+            0,
+        ),
+    };
 
     match _at {
+        // killed before it ever ran, i.e.: a new thread during `exit_group`
+        Err(_err) if dying::is_dying(task.getpid(), task.gettid()) => {
+            debug!("{} init_rpc_stack_data: task is dying", task.gettid());
+        }
         Err(_err) => panic!("init_rpc_stack_data failed: {:?}", _err),
         Ok(at) => {
             let stack_top = at + 0x4000;
@@ -159,6 +244,18 @@ fn init_rpc_stack_data(task: &mut TracedTask) {
     }
 }
 
+// the rpc area of `task` outlives it in the address space it shared, for
+// the next task to reuse. but the area of a `vfork` child, borrowed.
+fn release_rpc_area(task: &mut TracedTask) {
+    if let Some((stack, _)) = task.rpc_stack.take() {
+        task.rpc_data = None;
+        if !task.in_vfork && Rc::strong_count(&task.process) > 1 {
+            let at = stack.as_ptr() as u64 - 0x4000;
+            task.process.borrow_mut().rpc_areas.push(at);
+        }
+    }
+}
+
 /// ptraced task
 pub struct TracedTask {
     /// task id, same as `gettid()`
@@ -170,6 +267,8 @@ pub struct TracedTask {
     ppid: Pid,
     /// process group id as of `getpgid()`
     pgid: Pid,
+    /// process whose fd table the task uses, see `Task::files`
+    files: Pid,
 
     dpc_task: Option<Pid>,
 
@@ -190,38 +289,8 @@ pub struct TracedTask {
     pub injected_shared_page: Option<u64>,
     pub signal_to_deliver: Option<signal::Signal>,
     pub trampoline_hooks: &'static Vec<hooks::SyscallHook>,
-    ///
-    /// Even though the tracee can be multi-threaded
-    /// the tracer is not. hence no need for locking
-    ///
-    /// each process should have its own copy of below data
-    /// however, threads do resides in the same address space
-    /// as a result they should share below data as well
-    pub memory_map: Rc<RefCell<Vec<procfs::process::MemoryMap>>>,
-    pub stub_pages: Rc<RefCell<Vec<SyscallStubPage>>>,
-    pub unpatchable_syscalls: Rc<RefCell<HashSet<u64>>>,
-    pub patched_syscalls: Rc<RefCell<HashSet<u64>>>,
-    pub syscall_patch_lockset: Rc<RefCell<RemoteRWLock>>,
-
-    /// breakpoints
-    pub breakpoints: Rc<
-        RefCell<
-            HashMap<
-                u64,
-                (
-                    u64,
-                    Box<
-                        dyn FnOnce(
-                                TracedTask,
-                                Remoteable<c_void>,
-                            )
-                                -> Result<RunTask<TracedTask>>
-                            + 'static,
-                    >,
-                ),
-            >,
-        >,
-    >,
+    /// state shared by threads of the process, see `Process`
+    pub process: ProcessRef,
 
     /// ldso: ld.so loaded (range) by GNU linker
     /// NB: the linker itself is a static DSO with no dependencies
@@ -236,6 +305,18 @@ pub struct TracedTask {
     pub rpc_data: Option<(Remoteable<u64>, usize)>,
     /// task event call backs for `TaskEvent`
     pub event_cbs: Option<Rc<RefCell<TaskEventCB>>>,
+    /// when the pending (ptraced) syscall entered
+    pub syscall_entered_at: Option<Timestamp>,
+    /// when the pending (ptraced) syscall is resumed by the tracer
+    pub syscall_resumed_at: Option<Timestamp>,
+    /// scheduling priority, inherited by fork/clone
+    priority: TaskPriority,
+    /// whether the pending (ptraced) syscall is sampled
+    pub syscall_sampled: bool,
+    /// next `PTRACE_SYSCALL` stop, see `EmulationMode::Sysemu`
+    pub sysemu: SysemuState,
+    /// non-leader thread which exec'ed, and took over this task's tid
+    exec_tid: Option<Pid>,
 }
 
 impl std::fmt::Debug for TracedTask {
@@ -264,118 +345,42 @@ impl Task for TracedTask {
             pid,
             ppid: pid,
             pgid: unistd::getpgid(Some(pid)).unwrap(),
+            files: pid,
             dpc_task: None,
             state: TaskState::Ready,
             in_vfork: false,
             seccomp_hook_size: None,
-            memory_map: Rc::new(RefCell::new(Vec::new())),
-            stub_pages: Rc::new(RefCell::new(Vec::new())),
+            process: Process::new(pid),
             trampoline_hooks: &SYSCALL_HOOKS,
             ldpreload_address: libtrampoline_load_address(pid),
             ldpreload_symbols: &PRELOAD_TOOL_SYMS,
             injected_mmap_page: None,
             injected_shared_page: None,
             signal_to_deliver: None,
-            unpatchable_syscalls: Rc::new(RefCell::new(HashSet::new())),
-            patched_syscalls: Rc::new(RefCell::new(HashSet::new())),
-            syscall_patch_lockset: Rc::new(RefCell::new(RemoteRWLock::new())),
-            breakpoints: Rc::new(RefCell::new(HashMap::new())),
             ldso: None,
             ldso_symbols: Rc::new(HashMap::new()),
             rpc_stack: None,
             rpc_data: None,
             event_cbs: None,
+            syscall_entered_at: None,
+            syscall_resumed_at: None,
+            priority: TaskPriority::default(),
+            syscall_sampled: false,
+            sysemu: SysemuState::Off,
+            exec_tid: None,
         }
     }
 
     /// cloned a `TracedTask`
     /// called only when received ptrace clone event.
     fn cloned(&self, child: Pid) -> Self {
-        let new_task = TracedTask {
-            tid: child,
-            pid: self.pid,
-            ppid: self.pid,
-            pgid: self.pgid,
-            dpc_task: None,
-            state: TaskState::Ready,
-            in_vfork: false,
-            seccomp_hook_size: None,
-            memory_map: self.memory_map.clone(),
-            stub_pages: self.stub_pages.clone(),
-            trampoline_hooks: &SYSCALL_HOOKS,
-            ldpreload_address: self.ldpreload_address,
-            ldpreload_symbols: &PRELOAD_TOOL_SYMS,
-            injected_mmap_page: self.injected_mmap_page,
-            injected_shared_page: self.injected_shared_page,
-            signal_to_deliver: None,
-            unpatchable_syscalls: self.unpatchable_syscalls.clone(),
-            patched_syscalls: self.patched_syscalls.clone(),
-            syscall_patch_lockset: self.syscall_patch_lockset.clone(),
-            breakpoints: self.breakpoints.clone(),
-            ldso: self.ldso,
-            ldso_symbols: self.ldso_symbols.clone(),
-            rpc_stack: None,
-            rpc_data: None,
-            event_cbs: self.event_cbs.clone(),
-        };
-        new_task
+        self.spawned(child, thread_clone_flags())
     }
 
     /// fork a `TracedTask`
     /// called when received ptrace fork/vfork event
     fn forked(&self, child: Pid) -> Self {
-        TracedTask {
-            tid: child,
-            pid: child,
-            ppid: self.pid,
-            pgid: self.pgid,
-            dpc_task: None,
-            state: TaskState::Ready,
-            in_vfork: false,
-            seccomp_hook_size: None,
-            memory_map: {
-                let maps = self.memory_map.borrow().clone();
-                Rc::new(RefCell::new(maps))
-            },
-            stub_pages: {
-                let stubs = self.stub_pages.borrow().clone();
-                Rc::new(RefCell::new(stubs))
-            },
-            trampoline_hooks: &SYSCALL_HOOKS,
-            ldpreload_address: self.ldpreload_address,
-            ldpreload_symbols: &PRELOAD_TOOL_SYMS,
-            injected_mmap_page: self.injected_mmap_page,
-            injected_shared_page: self.injected_shared_page,
-            signal_to_deliver: None,
-            unpatchable_syscalls: {
-                let unpatchables = self.unpatchable_syscalls.borrow().clone();
-                Rc::new(RefCell::new(unpatchables))
-            },
-            patched_syscalls: {
-                let patched = self.patched_syscalls.borrow().clone();
-                Rc::new(RefCell::new(patched))
-            },
-            syscall_patch_lockset: Rc::new(RefCell::new(RemoteRWLock::new())),
-            breakpoints: Rc::new(RefCell::new(HashMap::new())),
-            ldso: self.ldso,
-            ldso_symbols: self.ldso_symbols.clone(),
-            rpc_stack: self.rpc_stack,
-            rpc_data: match &self.rpc_data {
-                None => None,
-                Some((rptr, size)) => {
-                    let new_rptr = match rptr {
-                        Remoteable::Remote(p) => {
-                            Remoteable::remote(p.as_ptr()).unwrap().cast()
-                        }
-                        Remoteable::Local(p) => {
-                            Remoteable::local(p.as_ptr()).unwrap().cast()
-                        }
-                    };
-                    Some((new_rptr, *size))
-                }
-            },
-            event_cbs: self.event_cbs.clone(),
-        }
+        self.spawned(child, CloneFlags::empty())
     }
 
     /// get task exit code
@@ -404,6 +409,21 @@ impl Task for TracedTask {
     fn getpgid(&self) -> Pid {
         self.pgid
     }
+
+    /// get process whose fd table the task uses
+    fn files(&self) -> Pid {
+        self.files
+    }
+
+    /// get task scheduling priority
+    fn task_priority(&self) -> TaskPriority {
+        self.priority
+    }
+
+    /// set task scheduling priority
+    fn set_task_priority(&mut self, priority: TaskPriority) {
+        self.priority = priority;
+    }
 }
 
 /// convenient ptrace interface for `TracedTask`
@@ -420,13 +440,12 @@ impl GuestMemoryAccess for TracedTask {
 }
 
 impl Ptracer for TracedTask {
-    fn getregs(&self) -> Result<libc::user_regs_struct> {
-        let regs = ptrace::getregs(self.tid).map_err(from_nix_error);
-        regs
+    fn getregs(&self) -> Result<HostRegs> {
+        arch::getregs::<Host>(self.tid).map_err(from_nix_error)
     }
 
-    fn setregs(&self, regs: libc::user_regs_struct) -> Result<()> {
-        ptrace::setregs(self.tid, regs).map_err(from_nix_error)
+    fn setregs(&self, regs: HostRegs) -> Result<()> {
+        arch::setregs::<Host>(self.tid, regs).map_err(from_nix_error)
     }
 
     fn resume(&self, sig: Option<signal::Signal>) -> Result<()> {
@@ -451,17 +470,8 @@ impl Ptracer for TracedTask {
 impl Injector for TracedTask {
     /// Inject a system call into the guest and register the callback.
     /// Note that the callback will be called twice in the case of a Fork.
-    fn inject_syscall(&self, sc: SyscallNo, args: &SyscallArgs) -> i64 {
-        reverie_api::remote::untraced_syscall(
-            self as &dyn Task,
-            sc,
-            args.arg0,
-            args.arg1,
-            args.arg2,
-            args.arg3,
-            args.arg4,
-            args.arg5,
-        )
+    fn inject_syscall(&self, sc: SyscallNo, args: &SyscallArgs) -> Result<i64> {
+        inject_untraced_syscall(self as &dyn Task, sc, args)
     }
 
     /// Look up the address of a function within the guest.
@@ -492,6 +502,9 @@ pub fn run_task<G>(
     gs: Arc<Mutex<G>>,
     mut task: TracedTask,
 ) -> Result<RunTask<TracedTask>> {
+    if has_ticks(&task) && ticks::start(task.gettid(), timeslice(&task)) {
+        replay_signals(&task)?;
+    }
     match task.state {
         TaskState::Running => Ok(RunTask::Runnable(task)),
         TaskState::Signaled(signal) => {
@@ -500,17 +513,25 @@ pub fn run_task<G>(
         }
         TaskState::Ready => Ok(RunTask::Runnable(task)),
         TaskState::Stopped(signal) => {
+            if signal == ticks::TICKS_SIGNAL && has_ticks(&task) {
+                if let Some(interrupt) = ticks::interrupted(task.gettid())? {
+                    ticks_interrupted(&mut task, interrupt)?;
+                    return Ok(RunTask::Runnable(task));
+                }
+            }
             if signal == signal::SIGTRAP {
                 let mut regs = task.getregs()?;
-                let rip_minus_1 = regs.rip - 1;
+                let rip_minus_1 = Host::breakpoint_site(Host::pc(&regs));
                 let mut maybe_f: Option<FnBreakpoint> = None;
-                match task.breakpoints.borrow_mut().remove(&rip_minus_1) {
+                let bkpt =
+                    task.process.borrow_mut().breakpoints.remove(&rip_minus_1);
+                match bkpt {
                     None => {} // not a breakpoint
                     Some((saved_insn, op)) => {
                         let rptr = Remoteable::remote(rip_minus_1 as *mut u64)
                             .unwrap();
                         task.poke(rptr, &saved_insn)?;
-                        regs.rip = rip_minus_1;
+                        Host::set_pc(&mut regs, rip_minus_1);
                         task.setregs(regs)?;
                         maybe_f = Some(op);
                     }
@@ -524,36 +545,221 @@ pub fn run_task<G>(
                         return f(task, rptr.cast());
                     }
                 }
+                if has_probes(&task) {
+                    if let Some(probed) = probe_hit(&task, rip_minus_1)? {
+                        if let Some(event) = probed.event {
+                            emit_event(&task, event);
+                        }
+                        task.signal_to_deliver = probed.signal;
+                        return Ok(RunTask::Runnable(task));
+                    }
+                }
+                if has_coverage(&task)
+                    && coverage::hit(task.getpid(), &task, rip_minus_1)
+                {
+                    Host::set_pc(&mut regs, rip_minus_1);
+                    task.setregs(regs)?;
+                    task.signal_to_deliver = None;
+                    return Ok(RunTask::Runnable(task));
+                }
             }
-            task.signal_to_deliver = Some(signal);
+            signal_received(&mut task, signal)?;
             Ok(RunTask::Runnable(task))
         }
         TaskState::Seccomp(syscall) => do_ptrace_seccomp(gs, task, syscall),
         TaskState::Exec => {
-            let _ = do_ptrace_exec(&mut task);
+            do_ptrace_exec(&mut task).map_err(from_nix_error)?;
             Ok(RunTask::Runnable(task))
         }
-        TaskState::Clone(child) => {
-            let new_task = do_ptrace_clone(gs, &mut task, child);
-            Ok(RunTask::Forked(task, new_task))
-        }
-        TaskState::Fork(child) => {
-            let new_task = do_ptrace_fork(gs, &mut task, child);
-            Ok(RunTask::Forked(task, new_task))
+        TaskState::Clone(child) | TaskState::Fork(child) => {
+            // the ptrace event doesn't tell what is shared with `child`
+            let flags = clone_flags(&task).unwrap_or_else(|| {
+                if task.state == TaskState::Clone(child) {
+                    thread_clone_flags()
+                } else {
+                    CloneFlags::empty()
+                }
+            });
+            let new_task = if flags.contains(CloneFlags::CLONE_THREAD) {
+                do_ptrace_clone(gs, &mut task, child, flags)
+            } else {
+                do_ptrace_fork(gs, &mut task, child, flags)
+            };
+            match new_task {
+                Ok(new_task) => Ok(RunTask::Forked(task, new_task)),
+                // the child never reached its initial stop, not the
+                // parent's fault: it goes on.
+                Err(err) => {
+                    warn!("{} lost child {}: {:?}", task.gettid(), child, err);
+                    Ok(RunTask::Runnable(task))
+                }
+            }
         }
         TaskState::VforkDone => Ok(RunTask::Runnable(task)),
-        TaskState::Syscall(_sc) => handle_syscall_exit(task),
+        TaskState::Syscall(_sc) => match task.sysemu {
+            SysemuState::Entry => handle_sysemu_entry(task),
+            SysemuState::Rerun => {
+                task.sysemu = SysemuState::Running;
+                Ok(RunTask::Runnable(task))
+            }
+            SysemuState::Running | SysemuState::Off => {
+                task.sysemu = SysemuState::Off;
+                handle_syscall_exit(task)
+            }
+        },
         TaskState::Exited(pid, exit_code) => {
-            do_ptrace_event_exit(gs, &mut task, pid, exit_code);
-            Ok(RunTask::Exited(exit_code))
+            // timed while the task is still there, not emitted for a
+            // leader surviving the exec of one of its threads
+            let exited = timed_event(&task, Event::Exited(exit_code));
+            match do_ptrace_event_exit(gs, &mut task, pid, exit_code) {
+                Some(tid) => {
+                    task.exec_from_thread(tid);
+                    do_ptrace_exec(&mut task).map_err(from_nix_error)?;
+                    Ok(RunTask::Runnable(task))
+                }
+                None => {
+                    if let Some(exited) = exited {
+                        emit_timed_event(&task, &exited);
+                    }
+                    release_rpc_area(&mut task);
+                    Ok(RunTask::Exited(exit_code))
+                }
+            }
         }
     }
 }
 
 impl TracedTask {
+    /// load shared library `path` in the (stopped) task, returns its load
+    /// bias, see `loader`
+    pub fn load_library(&self, path: &Path) -> Result<u64> {
+        loader::load_library(self, path)
+    }
+    /// `dlopen` shared library `path` in the (stopped) task, returns its
+    /// handle, see `loader`
+    pub fn dlopen(&self, path: &Path) -> Result<u64> {
+        loader::dlopen(self, path)
+    }
+    /// address of symbol `name` of `dlopen`ed `handle`, see `loader`
+    pub fn dlsym(&self, handle: u64, name: &str) -> Result<u64> {
+        loader::dlsym(self, handle, name)
+    }
+    /// redirect GOT slots of `hooks` to functions of `libraries` loaded
+    /// at their load bias, see `got`
+    pub fn patch_got(
+        &self,
+        libraries: &[(PathBuf, u64)],
+        hooks: &[GotHook],
+    ) -> Result<Vec<GotPatch>> {
+        got::patch_got(self, libraries, hooks)
+    }
+    /// new task `child` created by `clone` with `flags`. the process state
+    /// is shared with `CLONE_VM`, and copied otherwise, the fd table is
+    /// shared with `CLONE_FILES`.
+    pub fn spawned(&self, child: Pid, flags: CloneFlags) -> Self {
+        let is_thread = flags.contains(CloneFlags::CLONE_THREAD);
+        // a `vfork` child runs while `self` is suspended, and borrows
+        // its rpc area.
+        let new_rpc_area = flags.contains(CloneFlags::CLONE_VM)
+            && !flags.contains(CloneFlags::CLONE_VFORK);
+        TracedTask {
+            tid: child,
+            pid: if is_thread { self.pid } else { child },
+            ppid: if is_thread || flags.contains(CloneFlags::CLONE_PARENT) {
+                self.ppid
+            } else {
+                self.pid
+            },
+            pgid: self.pgid,
+            files: if flags.contains(CloneFlags::CLONE_FILES) {
+                self.files
+            } else {
+                child
+            },
+            dpc_task: None,
+            state: TaskState::Ready,
+            in_vfork: flags.contains(CloneFlags::CLONE_VFORK),
+            seccomp_hook_size: None,
+            process: if flags.contains(CloneFlags::CLONE_VM) {
+                self.process.clone()
+            } else {
+                self.process.borrow().forked(child)
+            },
+            trampoline_hooks: &SYSCALL_HOOKS,
+            ldpreload_address: self.ldpreload_address,
+            ldpreload_symbols: &PRELOAD_TOOL_SYMS,
+            injected_mmap_page: self.injected_mmap_page,
+            injected_shared_page: self.injected_shared_page,
+            signal_to_deliver: None,
+            ldso: self.ldso,
+            ldso_symbols: self.ldso_symbols.clone(),
+            // with `CLONE_VM` the rpc area would be shared with `self`,
+            // a new one is allocated instead, see `init_rpc_stack_data`.
+            rpc_stack: if new_rpc_area { None } else { self.rpc_stack },
+            rpc_data: match &self.rpc_data {
+                _ if new_rpc_area => None,
+                None => None,
+                Some((rptr, size)) => {
+                    let new_rptr = match rptr {
+                        Remoteable::Remote(p) => {
+                            Remoteable::remote(p.as_ptr()).unwrap().cast()
+                        }
+                        Remoteable::Local(p) => {
+                            Remoteable::local(p.as_ptr()).unwrap().cast()
+                        }
+                    };
+                    Some((new_rptr, *size))
+                }
+            },
+            event_cbs: self.event_cbs.clone(),
+            syscall_entered_at: None,
+            syscall_resumed_at: None,
+            priority: self.priority,
+            syscall_sampled: false,
+            sysemu: SysemuState::Off,
+            exec_tid: None,
+        }
+    }
+
+    /// task of a session re-attached, of `process`, see `session`
+    pub fn restored(task: &SessionTask, process: ProcessRef) -> Self {
+        let pid = Pid::from_raw(task.pid);
+        let rpc_area = |top: u64| {
+            Remoteable::remote(top as *mut u64).map(|s| (s, 0x4000 as usize))
+        };
+        TracedTask {
+            tid: Pid::from_raw(task.tid),
+            ppid: Pid::from_raw(task.ppid),
+            process,
+            injected_mmap_page: Some(0x7000_0000),
+            rpc_stack: task.rpc_stack.and_then(rpc_area),
+            rpc_data: task.rpc_stack.and_then(rpc_area),
+            ..Task::new(pid)
+        }
+    }
+
     /// return syscall instruction at `rip` is patched or not
     pub fn is_patched_syscall(&self, rip: u64) -> bool {
-        self.patched_syscalls.borrow().get(&rip).is_some()
+        self.process.borrow().patched_syscalls.contains(&rip)
+    }
+
+    /// thread `tid` (not the leader) called `execve`: the kernel killed
+    /// all other threads, and `tid` took over the leader's tid. `self`
+    /// (the leader) becomes the surviving thread, in exec stop.
+    pub fn exec_from_thread(&mut self, tid: Pid) {
+        self.exec_tid = Some(tid);
+        self.state = TaskState::Exec;
+        self.signal_to_deliver = None;
+        self.rpc_stack = None;
+        self.rpc_data = None;
+        self.syscall_entered_at = None;
+        self.syscall_resumed_at = None;
+        self.syscall_sampled = false;
+    }
+
+    /// take the (former) tid of the thread which exec'ed, if any
+    pub fn take_exec_tid(&mut self) -> Option<Pid> {
+        self.exec_tid.take()
     }
 
     /// return whether or net task state is seccomp stop
@@ -564,6 +770,48 @@ impl TracedTask {
         }
     }
 
+    /// detach the task stopped in `state`, to run untraced from then: a
+    /// syscall skipped at its sysemu entry is rewound to be run, a signal
+    /// stopped at is delivered, see `detach`. breakpoint instructions must
+    /// be restored already, the task is rewound if it hit one.
+    pub fn detach(&mut self) -> Result<()> {
+        let tid = self.gettid();
+        let mut sig = match self.state {
+            TaskState::Stopped(sig) if sig != ticks::TICKS_SIGNAL => Some(sig),
+            _ => None,
+        };
+        match self.state {
+            TaskState::Syscall(_) if self.sysemu == SysemuState::Entry => {
+                let regs = self.getregs()?;
+                self.setregs(sysemu::rerun_regs(regs))?;
+            }
+            TaskState::Stopped(signal::SIGTRAP) => {
+                let mut regs = self.getregs()?;
+                let at = Host::breakpoint_site(Host::pc(&regs));
+                if self.process.borrow().breakpoints.contains_key(&at) {
+                    Host::set_pc(&mut regs, at);
+                    self.setregs(regs)?;
+                    sig = None;
+                }
+            }
+            _ => (),
+        }
+        self.sysemu = SysemuState::Off;
+        ticks::exited(tid);
+        let data = sig.map_or(0, |sig| sig as u64);
+        let res = unsafe {
+            libc::ptrace(
+                libc::PTRACE_DETACH,
+                tid.as_raw(),
+                std::ptr::null_mut::<c_void>(),
+                data,
+            )
+        };
+        nix::errno::Errno::result(res)
+            .map(drop)
+            .map_err(from_nix_error)
+    }
+
     /// get ld preloaded tool symbol address
     pub fn get_preloaded_symbol_address(&self, sym: &str) -> Option<u64> {
         if let Some((la, _)) = self.ldpreload_address {
@@ -583,7 +831,7 @@ impl TracedTask {
         a4: u64,
         a5: u64,
     ) -> Result<i64> {
-        let ret = reverie_api::remote::untraced_syscall(
+        let ret = reverie_api::remote::try_untraced_syscall(
             self as &dyn Task,
             nr,
             a0,
@@ -592,12 +840,38 @@ impl TracedTask {
             a3,
             a4,
             a5,
-        );
-        if ret as u64 > (-4096i64) as u64 {
-            Err(Error::from_raw_os_error(-ret as i32))
-        } else {
-            Ok(ret)
+        )
+        .map_err(from_nix_error)?;
+        syscall_result(ret)
+    }
+
+    /// install seccomp `filter` (bpf bytecode) into the task by injecting
+    /// `seccomp(SECCOMP_SET_MODE_FILTER, ..)`, `filter` is stacked on top
+    /// of any existing filters.
+    pub fn install_seccomp_filter(&mut self, filter: &[u64]) -> Result<()> {
+        let (rpc_data, size) = self.rpc_data.ok_or_else(|| {
+            Error::new(ErrorKind::Other, "rpc data not initialized")
+        })?;
+        // struct sock_fprog, followed by the filter itself
+        let fprog_size = 2 * std::mem::size_of::<u64>();
+        if fprog_size + filter.len() * std::mem::size_of::<u64>() > size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("seccomp filter too large: {} insns", filter.len()),
+            ));
         }
+        let fprog = rpc_data.as_ptr() as u64;
+        let insns = fprog + fprog_size as u64;
+        let bytes: Vec<u8> = filter
+            .iter()
+            .flat_map(|insn| insn.to_ne_bytes().to_vec())
+            .collect();
+        let remote = |addr: u64| Remoteable::remote(addr as *mut u64).unwrap();
+        self.poke_bytes(remote(insns).cast(), &bytes)?;
+        self.poke(remote(fprog), &(filter.len() as u64))?;
+        self.poke(remote(fprog + 8), &insns)?;
+        self.untraced_syscall(SYS_seccomp, 1, 0, fprog, 0, 0, 0)
+            .map(|_| ())
     }
 
     fn setbp<F>(&mut self, _at: Remoteable<c_void>, op: F) -> Result<()>
@@ -611,37 +885,212 @@ impl TracedTask {
         let rptr = _at.cast();
         let at = rptr.as_ptr() as u64;
         let saved_insn: u64 = self.peek(rptr)?;
-        let insn = (saved_insn & !0xffu64) | 0xccu64;
-        self.poke(rptr, &insn)?;
-        self.breakpoints
+        let mut insn = saved_insn.to_le_bytes();
+        let bkpt = Host::BREAKPOINT_INSN;
+        insn[..bkpt.len()].copy_from_slice(bkpt);
+        self.poke(rptr, &u64::from_le_bytes(insn))?;
+        self.process
             .borrow_mut()
+            .breakpoints
             .insert(at, (saved_insn, Box::new(op)));
         Ok(())
     }
 }
 
-fn check_ref_counters(task: &TracedTask) {
-    let expected = 1;
-    let refcnt = Rc::strong_count(&task.unpatchable_syscalls);
-    if refcnt != expected {
-        warn!("{:?} Rc::strong_count(&task.unpatchable_syscalls) expected {} got {}", task, expected, refcnt);
+// pass `event` (observed now) to the task's event sink
+fn emit_event(task: &TracedTask, event: Event) {
+    if let Some(event) = timed_event(task, event) {
+        emit_timed_event(task, &event);
     }
-    let expected = 1;
-    let refcnt = Rc::strong_count(&task.memory_map);
-    if refcnt != expected {
-        warn!(
-            "{:?} Rc::strong_count(&task.memory_map) expected {} got {}",
-            task, expected, refcnt
-        );
+}
+
+// `event` of `task`, timed now, if events are observed
+fn timed_event(task: &TracedTask, event: Event) -> Option<TimedEvent> {
+    let cbs = task.event_cbs.as_ref()?.borrow();
+    let tid = task.gettid();
+    let ticks = if cbs.ticks { ticks::ticks(tid) } else { None };
+    Some(TimedEvent {
+        tid,
+        at: Timestamp::now(),
+        ticks,
+        event,
+    })
+}
+
+// report `event`, timed by `timed_event`
+fn emit_timed_event(task: &TracedTask, event: &TimedEvent) {
+    if let Some(cbs) = &task.event_cbs {
+        let mut cbs = cbs.borrow_mut();
+        if cbs.violation == ViolationAction::Kill {
+            violation::record_event(event);
+        }
+        cbs.emit_timed(event);
+    }
+}
+
+// report the user-space stack of the syscall `task` entered with `regs`,
+// if captured, see `TaskEventCB::stack_frames`
+fn emit_syscall_stack(task: &TracedTask, syscall: SyscallNo, regs: &HostRegs) {
+    let max_frames = task
+        .event_cbs
+        .as_ref()
+        .and_then(|cbs| cbs.borrow().stack_frames);
+    if let Some(max_frames) = max_frames {
+        let regions = process_regions(task);
+        let frames = unwind::capture(&regions, task, regs, max_frames)
+            .into_iter()
+            .map(|pc| Frame::new(pc, &regions))
+            .collect();
+        emit_event(task, Event::SyscallStack(syscall, frames));
     }
+}
 
-    let expected = 1;
-    let refcnt = Rc::strong_count(&task.stub_pages);
-    if refcnt != expected {
-        warn!(
-            "{:?} Rc::strong_count(&task.stub_pages) expected {} got {}",
-            task, expected, refcnt
-        );
+// regions of the process of `task`, read once until its mappings change,
+// see `unwind::changes_regions`
+fn process_regions(task: &TracedTask) -> Rc<Vec<Region>> {
+    let mut process = task.process.borrow_mut();
+    if let Some(regions) = &process.regions {
+        return regions.clone();
+    }
+    match read_regions(task.getpid()) {
+        Ok(regions) => process.regions.get_or_insert(Rc::new(regions)).clone(),
+        Err(_) => Rc::new(Vec::new()),
+    }
+}
+
+fn has_stack_frames(task: &TracedTask) -> bool {
+    task.event_cbs
+        .as_ref()
+        .map_or(false, |cbs| cbs.borrow().stack_frames.is_some())
+}
+
+// report the strings `task` gave to the syscall it entered with `args`, if
+// read, see `TaskEventCB::string_limit`
+fn emit_syscall_strings(
+    task: &TracedTask,
+    syscall: SyscallNo,
+    args: &SyscallArgs,
+) {
+    let limit = task
+        .event_cbs
+        .as_ref()
+        .and_then(|cbs| cbs.borrow().string_limit);
+    if let Some(limit) = limit {
+        let strings = strace::syscall_strings(task, syscall, args, limit);
+        if !strings.is_empty() {
+            emit_event(task, Event::SyscallStrings(syscall, strings));
+        }
+    }
+}
+
+// point `task` received asynchronous `signal` at, if ticks are counted
+fn async_signal_point(
+    task: &TracedTask,
+    signal: signal::Signal,
+) -> Result<Option<ExecPoint>> {
+    if !has_ticks(task) {
+        return Ok(None);
+    }
+    let ticks = match ticks::ticks(task.gettid()) {
+        Some(ticks) => ticks,
+        None => return Ok(None),
+    };
+    let siginfo = task.getsiginfo()?;
+    if !is_async_signal(signal, siginfo.si_code) {
+        return Ok(None);
+    }
+    Ok(Some(ExecPoint::new(ticks, &task.getregs()?)))
+}
+
+// `signal` to be delivered to `task` is reported, or suppressed when
+// replaying if asynchronous: recorded signals are replayed instead.
+fn signal_received(
+    task: &mut TracedTask,
+    signal: signal::Signal,
+) -> Result<()> {
+    let point = async_signal_point(task, signal)?;
+    if point.is_some()
+        && replaying(task)
+        && !ticks::take_injected(task.gettid(), signal)
+    {
+        log::debug!("[pid {}] {:?} suppressed", task.gettid(), signal);
+        task.signal_to_deliver = None;
+        return Ok(());
+    }
+    task.signal_to_deliver = Some(signal);
+    emit_event(task, Event::Signal(signal));
+    if let Some(point) = point {
+        emit_event(task, Event::AsyncSignal(signal, point));
+    }
+    Ok(())
+}
+
+// `task` stopped by its ticks counter, see `ticks::interrupted`
+fn ticks_interrupted(
+    task: &mut TracedTask,
+    interrupt: Interrupt,
+) -> Result<()> {
+    task.signal_to_deliver = None;
+    if interrupt.preempted {
+        emit_event(task, Event::Preempted(interrupt.ticks));
+    }
+    if let Some(signal) = interrupt.received {
+        signal_received(task, signal)?;
+    } else if let Some((signal, _)) = interrupt.replayed {
+        // in signal-delivery-stop, the signal is delivered by resuming
+        let point = ExecPoint::new(interrupt.ticks, &task.getregs()?);
+        task.signal_to_deliver = Some(signal);
+        emit_event(task, Event::Signal(signal));
+        emit_event(task, Event::AsyncSignal(signal, point));
+    }
+    Ok(())
+}
+
+// schedule the next signal recorded for `task` when replaying, and send
+// the signal due at a syscall stop, if any
+fn replay_signals(task: &TracedTask) -> Result<()> {
+    let cbs = match &task.event_cbs {
+        Some(cbs) => cbs,
+        None => return Ok(()),
+    };
+    let tid = task.gettid();
+    {
+        let mut cbs = cbs.borrow_mut();
+        let next = match cbs.on_replay_signal.as_mut() {
+            Some(next) => next,
+            None => return Ok(()),
+        };
+        ticks::schedule_signal(tid, || next(tid))?;
+    }
+    if let TaskState::Syscall(_) = task.state {
+        if let Some(signal) = ticks::due_signal(tid, &task.getregs()?)? {
+            ticks::inject(task.getpid(), tid, signal)?;
+        }
+    }
+    Ok(())
+}
+
+// emit the data `syscall` returned, with `regs` at its exit
+fn emit_syscall_data(task: &TracedTask, syscall: SyscallNo, regs: &HostRegs) {
+    if let Some((addr, size)) = recording::syscall_output(syscall, regs) {
+        match task.read_bytes(addr, size) {
+            Ok(data) if data_selected(task, syscall, regs) => {
+                emit_event(task, Event::SyscallData(syscall, data))
+            }
+            Ok(data) => {
+                let digest = sha256(&data);
+                let event = Event::SyscallDigest(syscall, data.len(), digest);
+                emit_event(task, event)
+            }
+            Err(err) => {
+                log::debug!(
+                    "[pid {}] {:?} data: {}",
+                    task.gettid(),
+                    syscall,
+                    err
+                )
+            }
+        }
     }
 }
 
@@ -650,25 +1099,39 @@ fn check_ref_counters(task: &TracedTask) {
 // see https://github.com/pgbovine/strace-plus/blob/master/README-linux-ptrace
 // section: 1.x execve under ptrace.
 fn task_exec_reset(task: &mut TracedTask) {
+    let pid = task.getpid();
+    if task.process.borrow().pid() != pid {
+        release_rpc_area(task);
+    }
+    // `execve` unshares the fd table, the task has a copy of it.
+    if task.files != pid {
+        provenance::fd_provenance()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .forked(task.files, pid);
+        task.files = pid;
+    }
     task.ldpreload_address = None;
     task.injected_mmap_page = Some(0x7000_0000);
     task.signal_to_deliver = None;
     task.state = TaskState::Exited(task.gettid(), 0);
     task.in_vfork = false;
     task.seccomp_hook_size = None;
-    check_ref_counters(task);
-    *(task.patched_syscalls.borrow_mut()) = HashSet::new();
-    *(task.unpatchable_syscalls.borrow_mut()) = HashSet::new();
-    *(task.memory_map.borrow_mut()) = Vec::new();
-    *(task.stub_pages.borrow_mut()) = Vec::new();
-    *(task.syscall_patch_lockset.borrow_mut()) = RemoteRWLock::new();
-    *(task.breakpoints.borrow_mut()) = HashMap::new();
+    if task.process.borrow().pid() != pid {
+        // spawned by `vfork` or `posix_spawn`, the process was the one of
+        // the parent, until now.
+        task.process = Process::new(pid);
+    } else {
+        // NB: other threads (if any) are gone, but they may still hold
+        // handles to the process, hence reset in place.
+        task.process.borrow_mut().exec_reset();
+    }
 }
 
 fn update_memory_map(task: &mut TracedTask) {
     // update memory mapping from /proc/[pid]/maps
     // NB: we must use `pid` here.
-    *(task.memory_map.borrow_mut()) =
+    task.process.borrow_mut().memory_map =
         procfs::process::Process::new(task.getpid().as_raw())
             .and_then(|p| p.maps())
             .unwrap_or_else(|_| Vec::new());
@@ -695,9 +1158,10 @@ fn find_syscall_hook(
         }
     }
 
+    let libc = task.process.borrow().libc;
     let mut it = task.trampoline_hooks.iter().filter(|hook| {
         let sequence: &[u8] = &bytes[0..hook.instructions.len()];
-        sequence == hook.instructions.as_slice()
+        sequence == hook.instructions.as_slice() && hook.applies_to(libc)
     });
     it.next()
 }
@@ -740,12 +1204,7 @@ pub fn patch_syscall_with(
     // keep this empty statement for documentation purpose.
     if task.is_patched_syscall(rip) {}
 
-    if task
-        .unpatchable_syscalls
-        .borrow()
-        .iter()
-        .any(|&pc| pc == rip)
-    {
+    if task.process.borrow().unpatchable_syscalls.contains(&rip) {
         return Err(Error::new(
             ErrorKind::Other,
             format!(
@@ -755,15 +1214,11 @@ pub fn patch_syscall_with(
             ),
         ));
     };
-    let old_regs = ptrace::getregs(task.gettid()).expect("ptrace getregs");
-    task.syscall_patch_lockset
-        .borrow_mut()
-        .try_read_unlock(task.gettid(), rip);
-    if !task
-        .syscall_patch_lockset
-        .borrow_mut()
-        .try_write_lock(task.gettid(), rip)
-    {
+    let old_regs = ptrace::getregs(task.gettid()).map_err(from_nix_error)?;
+    let tid = task.gettid();
+    let mut process = task.process.borrow_mut();
+    process.syscall_patch_lockset.try_read_unlock(tid, rip);
+    if !process.syscall_patch_lockset.try_write_lock(tid, rip) {
         return Err(Error::new(
             ErrorKind::Other,
             format!(
@@ -786,17 +1241,116 @@ pub fn patch_syscall_with(
     // PTRACE_EVENT_SECCOMP, as the kernel might allow previous syscall
     // to run through, this could cause chaotic issues if we rely ptrace
     // cont/breakpoint to control tracee's execution.
+    drop(process);
+
+    let ip = Host::syscall_site(rip);
+    let window = (ip, rip + hook.instructions.len() as u64);
+    if hook.is_multi && !quiesce::quiesce(task.getpid(), tid, window) {
+        arm_quiesce_bkpt(task, ip, hook.clone(), 0)?;
+        task.process
+            .borrow_mut()
+            .syscall_patch_lockset
+            .try_write_unlock(tid, rip);
+        return Err(Error::new(
+            ErrorKind::WouldBlock,
+            format!("syscall at {:x} deferred until quiescent", rip),
+        ));
+    }
+
     skip_seccomp_syscall(task, old_regs)?;
 
     let indirect_jump_address = extended_jump_from_to(task, hook, rip)?;
-    task.patched_syscalls.borrow_mut().insert(rip);
-    patch_syscall_at(task, syscall, hook, indirect_jump_address);
-    task.syscall_patch_lockset
-        .borrow_mut()
-        .try_write_unlock(task.gettid(), rip);
+    // NB: a failed patch is rolled back, and the task rewound to the
+    // `syscall`, which traps again, and is run unpatched.
+    let patched = patch_syscall_at(task, syscall, hook, indirect_jump_address);
+    let mut process = task.process.borrow_mut();
+    match patched {
+        Ok(()) => {
+            process.patched_syscalls.insert(rip);
+        }
+        Err(err) => {
+            warn!("{} {:?} not patched: {:?}", tid, syscall, err);
+            process.unpatchable_syscalls.insert(rip);
+        }
+    }
+    process.syscall_patch_lockset.try_write_unlock(tid, rip);
     Ok(())
 }
 
+// first phase of patching `hook` at `ip`: threads entering the window
+// trap at `ip`, see `handle_quiesce_bkpt`.
+fn arm_quiesce_bkpt(
+    task: &mut TracedTask,
+    ip: u64,
+    hook: hooks::SyscallHook,
+    attempts: usize,
+) -> Result<()> {
+    let rptr = Remoteable::remote(ip as *mut c_void).unwrap();
+    task.setbp(rptr, move |task, _| {
+        handle_quiesce_bkpt(task, ip, hook, attempts)
+    })
+}
+
+// second phase: install the full patch once no other thread is inside the
+// window, or run the syscall unpatched and try again on the next hit.
+fn handle_quiesce_bkpt(
+    mut task: TracedTask,
+    ip: u64,
+    hook: hooks::SyscallHook,
+    attempts: usize,
+) -> Result<RunTask<TracedTask>> {
+    let tid = task.gettid();
+    let rip = ip + Host::SYSCALL_INSN.len() as u64;
+    let window = (ip, rip + hook.instructions.len() as u64);
+    let mut regs = task.getregs()?;
+    let syscall = SyscallNo::from(Host::pending_syscall_nr(&regs) as i32);
+    if quiesce::quiesce(task.getpid(), tid, window) {
+        let target = extended_jump_from_to(&mut task, &hook, rip)?;
+        match write_syscall_patch(&task, syscall, &hook, ip, target) {
+            Ok(_) => {
+                task.process.borrow_mut().patched_syscalls.insert(rip);
+                synchronize_from(&task, ip);
+                return Ok(RunTask::Runnable(task));
+            }
+            Err(err) => {
+                warn!("{} {:?} not patched: {:?}", tid, syscall, err);
+                task.process.borrow_mut().unpatchable_syscalls.insert(rip);
+            }
+        }
+    } else if attempts + 1 < quiesce::QUIESCE_ATTEMPTS {
+        arm_quiesce_bkpt(&mut task, ip, hook, attempts + 1)?;
+    } else {
+        debug!("{} syscall at {:x} never quiescent, int3 patched", tid, rip);
+        arm_syscall_bkpt(&mut task, ip)?;
+    }
+    Host::enter_syscall(&mut regs);
+    Host::set_pc(&mut regs, rip);
+    task.setregs(regs)?;
+    inject_syscall_hook(&mut task, &regs)?;
+    Ok(RunTask::Runnable(task))
+}
+
+// `int3` patch of the syscall at `ip`, for sites the full patch can't be
+// installed at, see `quiesce`.
+fn arm_syscall_bkpt(task: &mut TracedTask, ip: u64) -> Result<()> {
+    let rptr = Remoteable::remote(ip as *mut c_void).unwrap();
+    task.setbp(rptr, move |task, _| handle_syscall_bkpt(task, ip))
+}
+
+// run the syscall trapped at `ip` by the hook, keeping the `int3`.
+fn handle_syscall_bkpt(
+    mut task: TracedTask,
+    ip: u64,
+) -> Result<RunTask<TracedTask>> {
+    arm_syscall_bkpt(&mut task, ip)?;
+    let mut regs = task.getregs()?;
+    Host::enter_syscall(&mut regs);
+    Host::set_pc(&mut regs, ip + Host::SYSCALL_INSN.len() as u64);
+    task.setregs(regs)?;
+    inject_syscall_hook(&mut task, &regs)?;
+    Ok(RunTask::Runnable(task))
+}
+
 fn hook_index(
     task: &mut TracedTask,
     curr: &hooks::SyscallHook,
@@ -817,7 +1371,7 @@ fn extended_jump_offset_from_stub_page(
     curr: &hooks::SyscallHook,
 ) -> Result<usize> {
     let k = hook_index(task, curr)?;
-    Ok(k * stubs::extended_jump_size())
+    Ok(stubs::stub_layout(task.trampoline_hooks)[k].offset)
 }
 
 // the extended (indirect) jump contains
@@ -835,16 +1389,16 @@ fn extended_jump_from_to(
 ) -> Result<u64> {
     let two_gb = 2u64.wrapping_shl(30);
     let stub_address = task
-        .stub_pages
+        .process
         .borrow()
+        .stub_pages
         .iter()
         .find(|page| {
             let (start, end) = (page.address, page.address + page.size as u64);
             if end <= rip {
                 rip - start <= two_gb
             } else if start >= rip {
-                start + stubs::extended_jump_pages() as u64 * 0x1000 - rip
-                    <= two_gb
+                end - rip <= two_gb
             } else {
                 false
             }
@@ -862,7 +1416,7 @@ fn extended_jump_from_to(
         task,
         rip,
         page_address,
-        task.stub_pages.borrow().clone()
+        task.process.borrow().stub_pages
     );
     let offset = extended_jump_offset_from_stub_page(task, hook)?;
     Ok(page_address + offset as u64)
@@ -873,7 +1427,8 @@ fn extended_jump_from_to(
 // `callq extended_jump_stub`, the `extended_jump_stub`
 // must be within +/- 2GB of IP.
 fn allocate_extended_jumps(task: &mut TracedTask, rip: u64) -> Result<u64> {
-    let size = (stubs::extended_jump_pages() * 0x1000) as u64;
+    let size =
+        (stubs::extended_jump_pages(task.trampoline_hooks) * 0x1000) as u64;
     let at = search_stub_page(task.gettid(), rip, size as usize)? as u64;
     let allocated_at = task.untraced_syscall(
         SYS_mmap,
@@ -899,7 +1454,7 @@ fn allocate_extended_jumps(task: &mut TracedTask, rip: u64) -> Result<u64> {
         task.trampoline_hooks,
         preload_address.0,
     );
-    task.stub_pages.borrow_mut().push(SyscallStubPage {
+    task.process.borrow_mut().stub_pages.push(SyscallStubPage {
         address: at as u64,
         size: size as usize,
         allocated: stubs.len(),
@@ -925,18 +1480,12 @@ fn allocate_extended_jumps(task: &mut TracedTask, rip: u64) -> Result<u64> {
 // wait either SIGTRAP (breakpoint) or SIGCHLD.
 fn wait_sigtrap_sigchld(task: &mut TracedTask) -> Result<()> {
     let tid = task.gettid();
-    let status = wait::waitpid(tid, None).expect("waitpid");
-    match status {
-        WaitStatus::Stopped(_pid, signal::SIGTRAP) => (),
-        WaitStatus::Stopped(_pid, signal::SIGCHLD) => {
+    match wait::waitpid(tid, None) {
+        Ok(WaitStatus::Stopped(_pid, signal::SIGTRAP)) => (),
+        Ok(WaitStatus::Stopped(_pid, signal::SIGCHLD)) => {
             task.signal_to_deliver = Some(signal::SIGCHLD)
         }
-        otherwise => {
-            panic!(
-                "task {} expecting SIGTRAP|SIGCHLD but got {:?}",
-                tid, otherwise
-            );
-        }
+        otherwise => return Err(dying::unexpected_status(tid, otherwise)),
     };
     Ok(())
 }
@@ -953,26 +1502,22 @@ fn remote_do_clone(
     let mut regs = task.getregs()?;
     let oldregs = regs;
 
-    let no = SYS_clone as u64;
-    regs.orig_rax = no;
-    regs.rax = no;
-    regs.rdi = flags as u64;
-    regs.rsi = child_stack as u64;
-    regs.rdx = 0;
-    regs.r10 = 0;
-    regs.r8 = 0;
-    regs.r9 = 0;
-
-    // instruction at 0x7000_0008 must be
-    // callq 0x70000000 (5-bytes)
-    // .byte 0xcc
-    regs.rip = 0x7000_0008;
+    let clone_args = [flags, child_stack, 0, 0, 0, 0];
+    Host::set_syscall(&mut regs, SYS_clone as u64, &clone_args);
+
+    // call the untraced syscall, then break, see `Arch::syscall_sequences`
+    let call =
+        consts::REVERIE_PRIVATE_PAGE_OFFSET + Host::CALL_UNTRACED_SYSCALL;
+    Host::set_pc(&mut regs, call);
     task.setregs(regs)?;
 
     task.resume(None)?;
-    let status = wait::waitpid(tid, None);
-    assert_eq!(status, Ok(WaitStatus::PtraceEvent(tid, signal::SIGTRAP, 1)));
-    let new_pid = ptrace::getevent(task.gettid()).unwrap() as i32;
+    match wait::waitpid(tid, None) {
+        Ok(WaitStatus::PtraceEvent(_, signal::SIGTRAP, 1)) => (),
+        otherwise => return Err(dying::unexpected_status(tid, otherwise)),
+    }
+    let new_pid =
+        ptrace::getevent(task.gettid()).map_err(from_nix_error)? as i32;
     let child = Pid::from_raw(new_pid);
     let new_task = task.cloned(child);
     wait_sigstop(&new_task)?;
@@ -985,18 +1530,18 @@ fn remote_do_clone(
     // call to the thread_routine, but we'll have to adjust
     // our stack accordingly..
     let mut new_regs = new_task.getregs()?;
-    let fake_ra = Remoteable::remote(new_regs.rsp as *mut u64).unwrap();
-    new_task.poke(fake_ra, &0xdeadbeef)?;
-    new_regs.rip = entry;
-    new_regs.rdi = args;
-    new_regs.rsp -= std::mem::size_of::<u64>() as u64;
+    let fake_ra = 0xdeadbeef;
+    if let Some(sp) = Host::set_call(&mut new_regs, entry, args, fake_ra) {
+        let sp = Remoteable::remote(sp as *mut u64).unwrap();
+        new_task.poke(sp, &fake_ra)?;
+    }
     new_task.setregs(new_regs)?;
     Ok(RunTask::Forked(task, new_task))
 }
 
-fn ptrace_get_stopsig(tid: Pid) -> libc::siginfo_t {
-    let si = ptrace::getsiginfo(tid).unwrap();
-    si
+fn ptrace_get_stopsig(tid: Pid) -> Option<libc::siginfo_t> {
+    // `None` if the task is gone
+    ptrace::getsiginfo(tid).ok()
 }
 
 const ERESTARTSYS: i32 = 512;
@@ -1006,17 +1551,14 @@ const ERESTARTBLOCK: i32 = 516;
 
 // PTRACE_SYSCALL may return restarted syscall
 // must restart them conditionally
-fn should_restart_syscall(
-    task: &mut TracedTask,
-    regs: libc::user_regs_struct,
-) -> bool {
+fn should_restart_syscall(task: &mut TracedTask, regs: HostRegs) -> bool {
     let tid = task.gettid();
 
-    if regs.rax < 0xfffffffffffff000u64 {
+    if (Host::retval(&regs) as u64) < 0xfffffffffffff000u64 {
         return false;
     }
 
-    let retval = -(regs.rax as i64) as i32;
+    let retval = -Host::retval(&regs) as i32;
 
     let res = match retval {
         ERESTARTSYS => true,
@@ -1025,46 +1567,151 @@ fn should_restart_syscall(
         _ => false,
     };
 
-    if res {
-        let si = ptrace_get_stopsig(tid);
+    if let Some(si) = ptrace_get_stopsig(tid).filter(|_| res) {
         let sig = signal::Signal::from_c_int(si.si_signo).unwrap();
         assert!(sig == signal::SIGTRAP || sig == signal::SIGCHLD);
     }
     res
 }
 
+// split the `total` duration of a ptraced syscall, entered until resumed
+// after its exit stop, into the time the tracee was stopped by the tracer,
+// `enter` and `exit` stop shares, and the time the syscall actually ran.
+fn split_syscall_time(
+    total: Duration,
+    enter: Duration,
+    exit: Duration,
+) -> (Duration, Duration) {
+    let tracer = enter + exit;
+    (tracer, total.saturating_sub(tracer))
+}
+
+// account `tracer` and `kernel` time of a ptraced syscall, see
+// `split_syscall_time`
+fn attribute_syscall_time(tracer: Duration, kernel: Duration) {
+    let state = reverie_global_state();
+    let state = state.lock().unwrap_or_else(|e| e.into_inner());
+    state.stats.nr_syscalls_timed.fetch_add(1, Ordering::SeqCst);
+    state
+        .stats
+        .syscall_tracer_nanos
+        .fetch_add(tracer.as_nanos() as usize, Ordering::SeqCst);
+    state
+        .stats
+        .syscall_kernel_nanos
+        .fetch_add(kernel.as_nanos() as usize, Ordering::SeqCst);
+}
+
+// apply `degradation`, the tracer being over its overhead budget, returns
+// whether it had any effect
+fn degrade(task: &TracedTask, degradation: Degradation) -> bool {
+    match degradation {
+        Degradation::DataCapture => match task.event_cbs.as_ref() {
+            Some(cbs) if cbs.borrow().record_data => {
+                cbs.borrow_mut().record_data = false;
+                true
+            }
+            _ => false,
+        },
+        Degradation::SiteBypass => adaptive::force_bypass(),
+        Degradation::Logging => {
+            let degraded = log::max_level() > log::LevelFilter::Warn;
+            log::set_max_level(log::LevelFilter::Warn.min(log::max_level()));
+            degraded
+        }
+    }
+}
+
 // PTRACE_SYSCALL stop. task was stopped because of syscall exit.
 // this is desired because some syscalls are blocking
 // we use it to do the read lock unlock
 fn handle_syscall_exit(mut task: TracedTask) -> Result<RunTask<TracedTask>> {
     let tid = task.gettid();
     let regs = task.getregs()?;
-    let rip = regs.rip;
+    let rip = Host::pc(&regs);
+    let syscall = SyscallNo::from(Host::syscall_nr(&regs) as i32);
+    let retval = Host::retval(&regs);
+    let now = Timestamp::now();
+    let elapsed = task
+        .syscall_entered_at
+        .map(|t| now.duration_since(t))
+        .unwrap_or_default();
 
     trace!(
-        "=== seccomp syscall {:?} @{:x}, return: {:x} ({})",
-        SyscallNo::from(regs.orig_rax as i32),
+        "=== seccomp syscall {:?} @{:x}, return: {:x} ({}), took {:?}",
+        syscall,
         rip,
-        regs.rax,
-        regs.rax as i64
+        retval,
+        retval,
+        elapsed
     );
 
     if should_restart_syscall(&mut task, regs) {
         debug!(
             "=== seccomp syscall {:?} @{:x} to be restarted",
-            SyscallNo::from(regs.orig_rax as i32),
-            rip
+            syscall, rip
         );
         // will re-enter syscall exit, state is TaskState::Syscall
         return Ok(RunTask::Runnable(task));
     }
 
+    // tracer share at the syscall enter stop, the exit's is measured once
+    // handled
+    let timed = task
+        .syscall_entered_at
+        .zip(task.syscall_resumed_at)
+        .map(|(entered, resumed)| (entered, resumed.duration_since(entered)));
+    task.syscall_entered_at = None;
+    task.syscall_resumed_at = None;
+    if task.syscall_sampled {
+        task.syscall_sampled = false;
+        emit_event(&task, Event::SyscallExit(syscall, retval, elapsed));
+        if record_data(&task) {
+            emit_syscall_data(&task, syscall, &regs);
+        }
+    }
+
+    if shared_memory_policy(&task) != SharedMemoryPolicy::Ignore {
+        if let Some(shm) = shm::shared_memory_at_exit(task.getpid(), &regs) {
+            shm::track_shared_memory(task.getpid(), &shm);
+            emit_event(&task, Event::SharedMemory(shm));
+        }
+    }
+
+    if wait_filter::is_wait_syscall(syscall) {
+        filter_wait_status(&task, &regs);
+    }
+
+    if dirent_filter::is_getdents_syscall(syscall) {
+        filter_dirents(&task, &regs);
+    }
+
+    if has_mapping_handler(&task)
+        || hash_binaries(&task)
+        || has_coverage(&task)
+        || has_probes(&task)
+    {
+        report_mapping_change(&task, &regs);
+    }
+
+    if unwind::changes_regions(syscall) {
+        task.process.borrow_mut().regions = None;
+    }
+
+    if has_fd_provenance(&task) {
+        let changes =
+            provenance::fd_changes_at_exit(task.getpid(), &task, &regs);
+        provenance::track_fd_changes(task.getpid(), task.files(), &changes);
+    }
+
+    emulation_exited(&task, &regs);
+
     let mut sig: Option<signal::Signal> = None;
     if let Some(hook_size) = task.seccomp_hook_size {
         task.seccomp_hook_size = None;
         let syscall_end = rip + hook_size as u64;
         loop {
-            ptrace::step(tid, sig).expect("ptrace single step");
+            ptrace::step(tid, sig).map_err(from_nix_error)?;
             match wait::waitpid(Some(tid), None) {
                 Ok(WaitStatus::Stopped(tid1, sig1)) if tid1 == tid => {
                     sig = if sig1 == signal::SIGTRAP {
@@ -1074,22 +1721,28 @@ fn handle_syscall_exit(mut task: TracedTask) -> Result<RunTask<TracedTask>> {
                     }
                 }
                 unexpected => {
-                    panic!(
-                        "waitpid({}): unexpected status {:?}, rip {:x}",
-                        tid, unexpected, rip
-                    );
+                    debug!("{} single step stopped at rip {:x}", tid, rip);
+                    return Err(dying::unexpected_status(tid, unexpected));
                 }
             }
-            let new_regs = ptrace::getregs(tid)
-                .unwrap_or_else(|_| panic!("tid {} ptrace getregs", tid));
-            if !(new_regs.rip > regs.rip && new_regs.rip < syscall_end) {
+            let new_rip = Host::pc(&task.getregs()?);
+            if !(new_rip > rip && new_rip < syscall_end) {
                 break;
             }
         }
     }
-    task.syscall_patch_lockset
+    task.process
         .borrow_mut()
+        .syscall_patch_lockset
         .try_read_unlock(tid, rip);
+    if let Some((entered, enter)) = timed {
+        let left = Timestamp::now();
+        let exit = left.duration_since(now);
+        let total = left.duration_since(entered);
+        let (tracer, kernel) = split_syscall_time(total, enter, exit);
+        attribute_syscall_time(tracer, kernel);
+        budget::sample(tracer, |degradation| degrade(&task, degradation));
+    }
     task.state = TaskState::Running;
     Ok(RunTask::Runnable(task))
 }
@@ -1111,6 +1764,20 @@ fn wait_sigstop(task: &TracedTask) -> Result<()> {
         {
             Ok(())
         }
+        // seized, options are inherited, but set them before first resume.
+        Ok(WaitStatus::PtraceEvent(new_pid, _, PTRACE_EVENT_STOP))
+            if new_pid == tid =>
+        {
+            ptrace::setoptions(tid, ptrace_options()).map_err(from_nix_error)
+        }
+        // killed before reaching its initial stop, the task is still
+        // scheduled, so that its exit can be reaped.
+        status @ Ok(WaitStatus::PtraceEvent(_, signal::SIGTRAP, 6))
+        | status @ Ok(WaitStatus::Exited(..))
+        | status @ Ok(WaitStatus::Signaled(..)) => {
+            let _ = dying::unexpected_status(tid, status);
+            Ok(())
+        }
         _st => Err(Error::new(
             ErrorKind::Other,
             format!("expect SIGSTOP, got: {:?}", _st),
@@ -1122,30 +1789,31 @@ fn do_ptrace_vfork_done(task: TracedTask) -> Result<TracedTask> {
     Ok(task)
 }
 
+// clone flags of the syscall which created a task, at clone/fork event stop
+fn clone_flags(task: &TracedTask) -> Option<CloneFlags> {
+    let regs = task.getregs().ok()?;
+    let flags = Host::syscall_args(&regs)[0];
+    decode_clone_flags(Host::syscall_nr(&regs), flags, |args| {
+        // `struct clone_args` starts with (u64) flags
+        let args = Remoteable::remote(args as *mut u64)?;
+        task.peek(args).ok()
+    })
+}
+
 fn do_ptrace_clone<G>(
     _gs: Arc<Mutex<G>>,
     task: &mut TracedTask,
     child: Pid,
-) -> TracedTask {
-    let mut new_task = task.cloned(child);
-    wait_sigstop(&new_task).unwrap();
+    flags: CloneFlags,
+) -> Result<TracedTask> {
+    let mut new_task = task.spawned(child, flags);
+    wait_sigstop(&new_task)?;
 
     let state = reverie_global_state();
+    count_ptraced_syscall();
     state
         .lock()
-        .unwrap()
-        .stats
-        .nr_syscalls
-        .fetch_add(1, Ordering::SeqCst);
-    state
-        .lock()
-        .unwrap()
-        .stats
-        .nr_syscalls_ptraced
-        .fetch_add(1, Ordering::SeqCst);
-    state
-        .lock()
-        .unwrap()
+        .unwrap_or_else(|e| e.into_inner())
         .stats
         .nr_cloned
         .fetch_add(1, Ordering::SeqCst);
@@ -1156,48 +1824,73 @@ fn do_ptrace_clone<G>(
         let clonefn = &mut cbs.borrow_mut().on_task_clone;
         let _ = clonefn(task);
     }
+    emit_event(task, Event::Clone(child));
 
-    new_task
+    Ok(new_task)
 }
 
 fn do_ptrace_fork<G>(
     _gs: Arc<Mutex<G>>,
     task: &mut TracedTask,
     child: Pid,
-) -> TracedTask {
-    let mut new_task = task.forked(child);
-    wait_sigstop(&new_task).unwrap();
+    flags: CloneFlags,
+) -> Result<TracedTask> {
+    let mut new_task = task.spawned(child, flags);
+    wait_sigstop(&new_task)?;
 
     let state = reverie_global_state();
+    count_ptraced_syscall();
     state
         .lock()
-        .unwrap()
-        .stats
-        .nr_syscalls
-        .fetch_add(1, Ordering::SeqCst);
-    state
-        .lock()
-        .unwrap()
-        .stats
-        .nr_syscalls_ptraced
-        .fetch_add(1, Ordering::SeqCst);
-    state
-        .lock()
-        .unwrap()
+        .unwrap_or_else(|e| e.into_inner())
         .stats
         .nr_forked
         .fetch_add(1, Ordering::SeqCst);
 
-    let regs = new_task.getregs().unwrap();
-    let _rptr = RemotePtr::new(regs.rip as *mut c_void);
-    // new_task.setbp(rptr, handle_fork_entry_bkpt)?;
+    shm::shared_memory_map()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .fork(task.getpid(), child);
+    if !flags.contains(CloneFlags::CLONE_FILES) {
+        provenance::fd_provenance()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .forked(task.getpid(), child);
+    }
+    if !flags.contains(CloneFlags::CLONE_THREAD) {
+        coverage::forked(task.getpid(), child);
+        probes::forked(task.getpid(), child);
+        dispatch::forked(task.getpid().as_raw(), child.as_raw());
+    }
+
+    if flags.contains(CloneFlags::CLONE_VM) {
+        if new_task.rpc_stack.is_none() {
+            init_rpc_stack_data(&mut new_task);
+        }
+    } else {
+        // breakpoints belong to the parent, restore the child's code.
+        for (at, (saved_insn, _)) in task.process.borrow().breakpoints.iter() {
+            let rptr = Remoteable::remote(*at as *mut u64).unwrap();
+            let _ = new_task.poke(rptr, saved_insn);
+        }
+    }
+
+    if let Ok(regs) = new_task.getregs() {
+        let _rptr = RemotePtr::new(Host::pc(&regs) as *mut c_void);
+        // new_task.setbp(rptr, handle_fork_entry_bkpt)?;
+    }
 
     if let Some(cbs) = &task.event_cbs.clone() {
         let forkfn = &mut cbs.borrow_mut().on_task_fork;
         let _ = forkfn(&mut new_task);
     }
+    if flags.contains(CloneFlags::CLONE_VFORK) {
+        emit_event(task, Event::Spawn(child));
+    } else {
+        emit_event(task, Event::Fork(child));
+    }
 
-    new_task
+    Ok(new_task)
 }
 
 fn do_ptrace_vfork(
@@ -1209,47 +1902,74 @@ fn do_ptrace_vfork(
     wait_sigstop(&new_task)?;
 
     let state = reverie_global_state();
+    count_ptraced_syscall();
     state
         .lock()
-        .unwrap()
+        .unwrap_or_else(|e| e.into_inner())
         .stats
-        .nr_syscalls
+        .nr_forked
         .fetch_add(1, Ordering::SeqCst);
-    state
+
+    shm::shared_memory_map()
         .lock()
-        .unwrap()
-        .stats
-        .nr_syscalls_ptraced
-        .fetch_add(1, Ordering::SeqCst);
-    state
+        .unwrap_or_else(|e| e.into_inner())
+        .fork(task.getpid(), child);
+    provenance::fd_provenance()
         .lock()
-        .unwrap()
-        .stats
-        .nr_forked
-        .fetch_add(1, Ordering::SeqCst);
+        .unwrap_or_else(|e| e.into_inner())
+        .forked(task.getpid(), child);
+    coverage::forked(task.getpid(), child);
+    probes::forked(task.getpid(), child);
+    dispatch::forked(task.getpid().as_raw(), child.as_raw());
 
     let regs = new_task.getregs()?;
-    let _rptr = RemotePtr::new(regs.rip as *mut c_void);
+    let _rptr = RemotePtr::new(Host::pc(&regs) as *mut c_void);
     //new_task.setbp(rptr, handle_fork_entry_bkpt)?;
     Ok((task, new_task))
 }
 
+// returns tid of the thread which exec'ed, if `pid` is a thread group
+// leader killed by `execve` from another thread.
 fn do_ptrace_event_exit<G>(
     _gs: Arc<Mutex<G>>,
-    _task: &mut TracedTask,
+    task: &mut TracedTask,
     pid: Pid,
     _retval: i32,
-) {
+) -> Option<Pid> {
     let state = reverie_global_state();
     state
         .lock()
-        .unwrap()
+        .unwrap_or_else(|e| e.into_inner())
         .stats
         .nr_exited
         .fetch_add(1, Ordering::SeqCst);
+    if pid == task.getpid() {
+        shm::shared_memory_map()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .detach_all(pid);
+        // the fd table lives on, shared by the task's creator
+        if task.files() == pid {
+            provenance::fd_provenance()
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .exited(pid);
+        }
+        binaries::exited(pid);
+        coverage::exited(pid);
+        probes::exited(pid);
+        dirent_filter::exited(pid);
+    }
+    ticks::exited(pid);
     let _ = ptrace::detach(pid);
     // XXX: this could be Exited, SIGCHLD, or ECHILD
     let _status = wait::waitpid(pid, None);
+    if let Ok(WaitStatus::PtraceEvent(_, signal::SIGTRAP, 4)) = _status {
+        // the leader is gone, the thread which called `execve` took over
+        // its tid, and is in `PTRACE_EVENT_EXEC` stop: not to be detached.
+        let tid = ptrace::getevent(pid).map(|tid| Pid::from_raw(tid as i32));
+        return Some(tid.unwrap_or(pid));
+    }
     let _ = ptrace::detach(pid);
 
     let offset = 4096 * (pid.as_raw() as i64 - 1);
@@ -1259,21 +1979,26 @@ fn do_ptrace_event_exit<G>(
     let nr_syscalls: usize = unsafe { std::mem::transmute(buf) };
     state
         .lock()
-        .unwrap()
+        .unwrap_or_else(|e| e.into_inner())
         .stats
         .nr_syscalls
         .fetch_add(nr_syscalls, Ordering::SeqCst);
     state
         .lock()
-        .unwrap()
+        .unwrap_or_else(|e| e.into_inner())
         .stats
         .nr_syscalls_captured
         .fetch_add(nr_syscalls, Ordering::SeqCst);
+    None
 }
 
 enum PatchStatus {
     NotTried,
     Failed,
+    /// waiting for other threads to leave the hook window, see `quiesce`
+    Deferred,
+    /// no hook applies, `int3` patched, see `quiesce`
+    Trapped,
     Successed,
 }
 
@@ -1284,20 +2009,141 @@ struct SyscallInfo {
     args: [u64; 6],
 }
 
+// run syscall of `regs` by calling `syscall_hook` in the tracee, which
+// returns to the pc of `regs`.
+fn inject_syscall_hook(task: &mut TracedTask, regs: &HostRegs) -> Result<()> {
+    let hook =
+        task.resolve_symbol_address("syscall_hook").ok_or_else(|| {
+            Error::new(ErrorKind::NotFound, "syscall_hook not found")
+        })?;
+    let rptr = match &task.rpc_data {
+        Some((rpc_data, _)) => rpc_data.clone().cast(),
+        None => {
+            return Err(Error::new(ErrorKind::NotFound, "no rpc data area"))
+        }
+    };
+    let info = SyscallInfo {
+        no: Host::syscall_nr(regs),
+        args: Host::syscall_args(regs),
+    };
+    task.poke(rptr, &info)?;
+    let args = SyscallArgs::from(rptr.as_ptr() as u64, 0, 0, 0, 0, 0);
+    task.inject_funcall(hook, &args);
+    Ok(())
+}
+
 fn do_ptrace_seccomp<G>(
     _gs: Arc<Mutex<G>>,
     mut task: TracedTask,
     syscall: SyscallNo,
 ) -> Result<RunTask<TracedTask>> {
     let regs = task.getregs()?;
-    let rip = regs.rip;
-    let rip_before_syscall = regs.rip - consts::SYSCALL_INSN_SIZE as u64;
+    let rip = Host::pc(&regs);
+    let rip_before_syscall = Host::syscall_site(rip);
     let tid = task.gettid();
 
+    if is_passed_through(&task, &regs) {
+        return do_passthrough_syscall(task);
+    }
+
+    // run again after `PTRACE_SYSEMU` entry, reported then
+    if task.sysemu == SysemuState::Running {
+        return do_unpatched_syscall(task);
+    }
+
+    task.syscall_entered_at = Some(Timestamp::now());
+    task.syscall_sampled = SYSCALL_SAMPLER.sample(syscall as i32);
+    if task.syscall_sampled {
+        let args = SyscallArgs::from_regs(&regs);
+        emit_event(&task, Event::SyscallEnter(syscall, args));
+        emit_syscall_stack(&task, syscall, &regs);
+        emit_syscall_strings(&task, syscall, &args);
+    }
+
+    let fd_syscall = has_fd_provenance(&task)
+        && provenance::is_fd_syscall(Host::syscall_nr(&regs));
+    if fd_syscall {
+        let changes = provenance::fd_changes_at_entry(&task, &regs);
+        provenance::track_fd_changes(task.getpid(), task.files(), &changes);
+    }
+
+    let wx_policy = wx_policy(&task);
+    if wx_policy != WxPolicy::Ignore
+        && mapping::is_wx_request(syscall, &regs)
+        && !is_wx_allowed_task(&task)
+    {
+        if wx_policy == WxPolicy::Deny {
+            return do_denied_wx_syscall(task, syscall, regs);
+        }
+        warn!("{} W^X: {:?} writable and executable", tid, syscall);
+    }
+
+    let exec_syscall = exec::is_exec_syscall(syscall);
+    let regs = if exec_syscall {
+        preload_exec(&task, syscall, regs)
+    } else {
+        regs
+    };
+
+    if is_emulated_syscall(&task, EmulationMode::Seccomp, syscall) {
+        return do_emulated_syscall(task, syscall, regs);
+    }
+
+    let shm_policy = shared_memory_policy(&task);
+    if shm::is_shared_memory_syscall(shm_policy, syscall, &regs) {
+        return do_shared_memory_syscall(task, shm_policy, syscall, regs);
+    }
+
+    if wait_filter::is_wait_syscall(syscall) && has_wait_filter(&task) {
+        return do_wait_syscall(task);
+    }
+
+    if dirent_filter::is_getdents_syscall(syscall) && has_dirent_filter(&task) {
+        return do_unpatched_syscall(task);
+    }
+
+    if signal_filter::is_signal_syscall(syscall) && has_signal_filter(&task) {
+        return do_signal_syscall(task, syscall, regs);
+    }
+
+    if mapping::is_mapping_syscall(syscall)
+        && (has_mapping_handler(&task)
+            || wx_policy != WxPolicy::Ignore
+            || hash_binaries(&task)
+            || has_coverage(&task)
+            || has_probes(&task))
+    {
+        return do_mapping_syscall(task);
+    }
+
+    // regions of stacks captured are read again after mapping changes
+    if unwind::changes_regions(syscall) && has_stack_frames(&task) {
+        return do_mapping_syscall(task);
+    }
+
+    // fd syscalls are never patched when tracked, see `handle_syscall_exit`.
+    // neither are exec syscalls, see `preload_exec`, syscalls returning
+    // data when recorded, nor syscalls of the `tracer` tier.
+    let data_syscall =
+        record_data(&task) && recording::is_data_syscall(syscall);
+    let tracer_tier =
+        syscall_tier(task.getpid(), syscall) == HandlingTier::Tracer;
+    if fd_syscall || exec_syscall || data_syscall || tracer_tier {
+        return do_unpatched_syscall(task);
+    }
+
+    if let Some(special) = special_syscall_site(&mut task, rip) {
+        return do_special_mapping_syscall(task, special, syscall);
+    }
+
+    if runtime_unpatchable_site(&mut task, rip) {
+        return do_unpatched_syscall(task);
+    }
+
     if task.ldpreload_address.is_none() {
         task.ldpreload_address = libtrampoline_load_address(tid);
     }
-    let hook = find_syscall_hook(&task, regs.rip);
+    let hook = find_syscall_hook(&task, rip);
     trace!(
         "{} seccomp syscall {:?}@{:x}, hook: {:x?}, preloaded: {}",
         tid,
@@ -1317,21 +2163,25 @@ fn do_ptrace_seccomp<G>(
     // it rerun from the beginning of the patched instruction.
     if !is_syscall_insn(tid, rip_before_syscall)? {
         let mut new_regs = regs;
-        new_regs.rax = regs.orig_rax;
-        debug!("{} seccomp syscall {:?}@{:x} restart because it is already patched, rax: {:x}", tid, syscall, rip, regs.rax);
-        skip_seccomp_syscall(&mut task, new_regs).unwrap();
+        Host::leave_syscall(&mut new_regs);
+        debug!(
+            "{} seccomp syscall {:?}@{:x} restart, already patched",
+            tid, syscall, rip
+        );
+        skip_seccomp_syscall(&mut task, new_regs)?;
         synchronize_from(&task, rip_before_syscall);
         return Ok(RunTask::Runnable(task));
     }
 
     // NB: another thread is patching this syscall, retry syscall
     if !task
-        .syscall_patch_lockset
+        .process
         .borrow_mut()
+        .syscall_patch_lockset
         .try_read_lock(tid, rip)
     {
         let mut new_regs = regs;
-        new_regs.rax = regs.orig_rax;
+        Host::leave_syscall(&mut new_regs);
         let _ = skip_seccomp_syscall(&mut task, new_regs);
         let _ = task.setregs(regs);
         task.state = TaskState::Ready;
@@ -1341,11 +2191,17 @@ fn do_ptrace_seccomp<G>(
     let patch_status = if task.ldpreload_address.is_some() {
         if let Some(hook) = hook {
             match patch_syscall_with(&mut task, hook, syscall, rip) {
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                    PatchStatus::Deferred
+                }
                 Err(_) => PatchStatus::Failed,
                 Ok(_) => PatchStatus::Successed,
             }
         } else {
-            PatchStatus::Failed
+            match arm_syscall_bkpt(&mut task, rip_before_syscall) {
+                Ok(()) => PatchStatus::Trapped,
+                Err(_) => PatchStatus::Failed,
+            }
         }
     } else {
         PatchStatus::NotTried
@@ -1354,44 +2210,23 @@ fn do_ptrace_seccomp<G>(
     let state = reverie_global_state();
     match patch_status {
         PatchStatus::NotTried => {
-            state
-                .lock()
-                .unwrap()
-                .stats
-                .nr_syscalls
-                .fetch_add(1, Ordering::SeqCst);
-            state
-                .lock()
-                .unwrap()
-                .stats
-                .nr_syscalls_ptraced
-                .fetch_add(1, Ordering::SeqCst);
+            count_ptraced_syscall();
         }
-        PatchStatus::Failed => {
-            let hook = task
-                .resolve_symbol_address("syscall_hook")
-                .expect("syscall_hook not found");
+        PatchStatus::Failed | PatchStatus::Deferred | PatchStatus::Trapped => {
+            if let PatchStatus::Failed = patch_status {
+                adaptive::unpatchable_syscall_trapped(tid, rip);
+            }
             let mut new_regs = regs;
-            new_regs.rax = regs.orig_rax;
-            skip_seccomp_syscall(&mut task, new_regs).unwrap();
+            Host::leave_syscall(&mut new_regs);
+            skip_seccomp_syscall(&mut task, new_regs)?;
             task.setregs(regs)?;
-
-            let rptr = task.rpc_data.unwrap().0.clone().cast();
-            let info = SyscallInfo {
-                no: regs.orig_rax,
-                args: [
-                    regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9,
-                ],
-            };
-            task.poke(rptr, &info).unwrap();
-            let args = SyscallArgs::from(rptr.as_ptr() as u64, 0, 0, 0, 0, 0);
-            task.inject_funcall(hook, &args);
+            inject_syscall_hook(&mut task, &regs)?;
         }
         PatchStatus::Successed => {
             // others fields are updated in tracee instead.
             state
                 .lock()
-                .unwrap()
+                .unwrap_or_else(|e| e.into_inner())
                 .stats
                 .nr_syscalls_patched
                 .fetch_add(1, Ordering::SeqCst);
@@ -1401,61 +2236,682 @@ fn do_ptrace_seccomp<G>(
     Ok(RunTask::Runnable(task))
 }
 
-fn from_nix_error(err: nix::Error) -> Error {
-    Error::new(ErrorKind::Other, err)
+fn shared_memory_policy(task: &TracedTask) -> SharedMemoryPolicy {
+    task.event_cbs
+        .as_ref()
+        .map(|cbs| cbs.borrow().shared_memory)
+        .unwrap_or_default()
 }
 
-fn from_nix_error_with(err: nix::Error, msg: &str) -> Error {
-    let my_error = format!("{}: {:?}", msg, err);
-    Error::new(ErrorKind::Other, my_error)
+// shared memory syscalls are never patched, the syscall is resumed by
+// `PTRACE_SYSCALL`, see `handle_syscall_exit`.
+fn do_shared_memory_syscall(
+    mut task: TracedTask,
+    policy: SharedMemoryPolicy,
+    syscall: SyscallNo,
+    regs: HostRegs,
+) -> Result<RunTask<TracedTask>> {
+    let tid = task.gettid();
+    count_ptraced_syscall();
+    task.seccomp_hook_size = None;
+    match shm::shared_memory_verdict(policy, syscall, &regs) {
+        shm::ShmVerdict::Allow => (),
+        shm::ShmVerdict::Deny(errno) => {
+            info!("{} shared memory {:?} denied", tid, syscall);
+            let mut new_regs = regs;
+            Host::set_retval(&mut new_regs, -errno as i64);
+            skip_seccomp_syscall(&mut task, new_regs)?;
+            task.syscall_entered_at = None;
+            task.syscall_sampled = false;
+            let reason = format!("shared memory {:?} denied", syscall);
+            violated(&task, &regs, &reason);
+        }
+        shm::ShmVerdict::Private(flags) => {
+            info!("{} shared memory {:?} made private", tid, syscall);
+            let mut new_regs = regs;
+            let mut args = Host::syscall_args(&regs);
+            args[3] = flags;
+            Host::set_syscall(&mut new_regs, Host::syscall_nr(&regs), &args);
+            task.setregs(new_regs)?;
+        }
+    }
+    Ok(RunTask::Runnable(task))
 }
 
-fn just_continue(pid: Pid, sig: Option<signal::Signal>) -> Result<()> {
-    ptrace::cont(pid, sig).map_err(from_nix_error)
+fn violation_action(task: &TracedTask) -> ViolationAction {
+    task.event_cbs
+        .as_ref()
+        .map(|cbs| cbs.borrow().violation)
+        .unwrap_or_default()
 }
 
-// set tool library log level
-fn systool_set_log_level(task: &TracedTask) {
-    let systool_log_ptr = consts::REVERIE_LOCAL_SYSTOOL_LOG_LEVEL as *mut i64;
-    let rptr = Remoteable::remote(systool_log_ptr).unwrap();
-    let lvl =
-        std::env::var(consts::REVERIE_ENV_TOOL_LOG_KEY).map(|s| match &s[..] {
-            "error" => 1,
-            "warn" => 2,
-            "info" => 3,
-            "debug" => 4,
-            "trace" => 5,
-            _ => 0,
-        });
-    match lvl {
-        Ok(x) if x >= 1 && x <= 5 => {
-            let _ = task.poke(rptr, &x);
-        }
-        _ => (),
+// the syscall of `regs` was denied by a policy for `reason`, and skipped.
+// the process is killed if so asked, see `ViolationAction`.
+fn violated(task: &TracedTask, regs: &HostRegs, reason: &str) {
+    if violation_action(task) == ViolationAction::Kill {
+        violation::kill_violating(task, task, regs, reason);
     }
 }
 
-fn tracee_preinit(task: &mut TracedTask) -> nix::Result<()> {
-    let tid = task.gettid();
-    let mut regs = ptrace::getregs(tid)?;
-    let mut saved_regs = regs;
-    let page_addr = consts::REVERIE_PRIVATE_PAGE_OFFSET;
-    let page_size = consts::REVERIE_PRIVATE_PAGE_SIZE;
+// re-establish `LD_PRELOAD` of the preloader if the exec'ed environment
+// dropped it, returns the registers of the syscall to run.
+fn preload_exec(
+    task: &TracedTask,
+    syscall: SyscallNo,
+    regs: HostRegs,
+) -> HostRegs {
+    let preloader = exec::preloader();
+    let pinned = pinning::pinned_envs();
+    if preloader.is_none() && pinned.is_empty() {
+        return regs;
+    }
+    let floor = exec::stack_floor(task.getpid(), Host::sp(&regs));
+    match exec::preload_envp(task, syscall, &regs, preloader, pinned, floor) {
+        Ok(Some(new_regs)) => match task.setregs(new_regs) {
+            Ok(()) => {
+                debug!(
+                    "{} {:?}: LD_PRELOAD restored, environment pinned",
+                    task.gettid(),
+                    syscall
+                );
+                new_regs
+            }
+            Err(err) => {
+                warn!(
+                    "{} failed to restore LD_PRELOAD: {:?}",
+                    task.gettid(),
+                    err
+                );
+                regs
+            }
+        },
+        Ok(None) => regs,
+        Err(err) => {
+            warn!("{} failed to read exec envp: {:?}", task.gettid(), err);
+            regs
+        }
+    }
+}
 
-    regs.orig_rax = SYS_mmap as u64;
-    regs.rax = regs.orig_rax;
-    regs.rdi = page_addr;
-    regs.rsi = page_size;
-    regs.rdx = (libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC) as u64;
-    regs.r10 =
-        (libc::MAP_PRIVATE | libc::MAP_FIXED | libc::MAP_ANONYMOUS) as u64;
-    regs.r8 = -1 as i64 as u64;
-    regs.r9 = 0 as u64;
-
-    ptrace::setregs(tid, regs)?;
-    ptrace::cont(tid, None)?;
+fn has_wait_filter(task: &TracedTask) -> bool {
+    task.event_cbs
+        .as_ref()
+        .map(|cbs| cbs.borrow().on_wait_filter.is_some())
+        .unwrap_or(false)
+}
 
-    // loop until second breakpoint hit after injected syscall
+// wait syscalls are never patched when filtered, the syscall is resumed by
+// `PTRACE_SYSCALL`, see `handle_syscall_exit`.
+fn do_wait_syscall(mut task: TracedTask) -> Result<RunTask<TracedTask>> {
+    count_ptraced_syscall();
+    task.seccomp_hook_size = None;
+    Ok(RunTask::Runnable(task))
+}
+
+// let `on_wait_filter` rewrite child status returned by `wait4`/`waitid`
+fn filter_wait_status(task: &TracedTask, regs: &HostRegs) {
+    let cbs = match &task.event_cbs {
+        Some(cbs) => cbs.clone(),
+        None => return,
+    };
+    let mut cbs = cbs.borrow_mut();
+    if let Some(filter) = cbs.on_wait_filter.as_mut() {
+        if let Err(err) = wait_filter::filter_wait_status(task, regs, filter) {
+            warn!("{} failed to filter wait status: {:?}", task.gettid(), err);
+        }
+    }
+}
+
+fn has_dirent_filter(task: &TracedTask) -> bool {
+    task.event_cbs
+        .as_ref()
+        .map(|cbs| cbs.borrow().on_dirent_filter.is_some())
+        .unwrap_or(false)
+}
+
+// let `on_dirent_filter` hide, or add entries returned by `getdents64`
+fn filter_dirents(task: &TracedTask, regs: &HostRegs) {
+    let cbs = match &task.event_cbs {
+        Some(cbs) => cbs.clone(),
+        None => return,
+    };
+    let mut cbs = cbs.borrow_mut();
+    if let Some(filter) = cbs.on_dirent_filter.as_mut() {
+        if let Err(err) = dirent_filter::filter_dirents(task, regs, filter) {
+            warn!("{} failed to filter dirents: {:?}", task.gettid(), err);
+        }
+    }
+}
+
+fn has_signal_filter(task: &TracedTask) -> bool {
+    task.event_cbs
+        .as_ref()
+        .map(|cbs| cbs.borrow().on_signal_filter.is_some())
+        .unwrap_or(false)
+}
+
+// signal syscalls are never patched when filtered, so that every signal
+// sent goes through `on_signal_filter`.
+fn do_signal_syscall(
+    mut task: TracedTask,
+    syscall: SyscallNo,
+    regs: HostRegs,
+) -> Result<RunTask<TracedTask>> {
+    let tid = task.gettid();
+    count_ptraced_syscall();
+    task.seccomp_hook_size = None;
+    let send = match signal_filter::decode_signal_send(
+        task.getpid(),
+        syscall,
+        &regs,
+    ) {
+        Some(send) => send,
+        None => return Ok(RunTask::Runnable(task)),
+    };
+    let verdict = match task.event_cbs.clone() {
+        Some(cbs) => match cbs.borrow_mut().on_signal_filter.as_mut() {
+            Some(filter) => filter(&task, &send),
+            None => SignalVerdict::Allow,
+        },
+        None => SignalVerdict::Allow,
+    };
+    if let SignalVerdict::Deny(errno) = verdict {
+        info!("{} {:?} {:?} denied", tid, syscall, send);
+        let mut new_regs = regs;
+        Host::set_retval(&mut new_regs, -errno as i64);
+        skip_seccomp_syscall(&mut task, new_regs)?;
+        task.syscall_entered_at = None;
+        task.syscall_sampled = false;
+        let reason = format!("signal filter: {:?} denied", send);
+        violated(&task, &regs, &reason);
+    }
+    Ok(RunTask::Runnable(task))
+}
+
+// special mapping the syscall at `rip` is made from, unless patchable
+fn special_syscall_site(
+    task: &mut TracedTask,
+    rip: u64,
+) -> Option<SpecialMapping> {
+    if task.process.borrow().memory_map.is_empty() {
+        update_memory_map(task);
+    }
+    let process = task.process.borrow();
+    match vsyscall::special_mapping_at(&process.memory_map, rip)? {
+        SpecialMapping::Vdso(offset)
+            if vdso::is_patched_vdso_offset(offset) =>
+        {
+            None
+        }
+        special => Some(special),
+    }
+}
+
+// syscall site `rip` of a managed runtime is never patched, see `runtimes`.
+// the runtime is detected when the memory map is refreshed, for sites of
+// code mapped since.
+fn runtime_unpatchable_site(task: &mut TracedTask, rip: u64) -> bool {
+    if !runtimes::maps_cover(&task.process.borrow().memory_map, rip) {
+        update_memory_map(task);
+        let ticks = has_ticks(task);
+        let mut process = task.process.borrow_mut();
+        if process.runtime.is_none() {
+            process.runtime = ManagedRuntime::detect(&process.memory_map);
+            if let Some(runtime) = process.runtime {
+                for what in runtime.unsupported(ticks) {
+                    warn!("{} {:?}: {}", task.getpid(), runtime, what);
+                }
+            }
+        }
+    }
+    let process = task.process.borrow();
+    process.runtime.map_or(false, |runtime| {
+        runtimes::is_unpatchable_site(runtime, &process.memory_map, rip)
+    })
+}
+
+// syscalls from special mappings are never patched: there is either no
+// `syscall` instruction (vsyscall emulation), or code we must not modify.
+fn do_special_mapping_syscall(
+    mut task: TracedTask,
+    special: SpecialMapping,
+    syscall: SyscallNo,
+) -> Result<RunTask<TracedTask>> {
+    debug!("{} {:?} from {:?}", task.gettid(), syscall, special);
+    count_ptraced_syscall();
+    task.seccomp_hook_size = None;
+    Ok(RunTask::Runnable(task))
+}
+
+fn has_fd_provenance(task: &TracedTask) -> bool {
+    task.event_cbs
+        .as_ref()
+        .map_or(false, |cbs| cbs.borrow().fd_provenance)
+}
+
+fn has_ticks(task: &TracedTask) -> bool {
+    task.event_cbs
+        .as_ref()
+        .map_or(false, |cbs| cbs.borrow().ticks)
+}
+
+fn record_data(task: &TracedTask) -> bool {
+    task.event_cbs
+        .as_ref()
+        .map_or(false, |cbs| cbs.borrow().record_data)
+}
+
+// whether data of `syscall` is recorded in full, see `DataSelection`
+fn data_selected(
+    task: &TracedTask,
+    syscall: SyscallNo,
+    regs: &HostRegs,
+) -> bool {
+    let cbs = match task.event_cbs.as_ref() {
+        Some(cbs) => cbs.borrow(),
+        None => return true,
+    };
+    let selection = &cbs.data_selection;
+    if selection.is_all() {
+        return true;
+    }
+    let fd = recording::syscall_fd(syscall, regs);
+    let file = fd.and_then(|fd| {
+        let link = format!("/proc/{}/fd/{}", task.gettid(), fd);
+        std::fs::read_link(link).ok()
+    });
+    selection.is_selected(syscall, fd, file.as_deref())
+}
+
+fn replaying(task: &TracedTask) -> bool {
+    task.event_cbs.as_ref().map_or(false, |cbs| {
+        let cbs = cbs.borrow();
+        cbs.ticks && cbs.on_replay_signal.is_some()
+    })
+}
+
+// timeslice of tasks, if ticks are counted
+fn timeslice(task: &TracedTask) -> Option<u64> {
+    let cbs = task.event_cbs.as_ref()?.borrow();
+    cbs.timeslice.filter(|_| cbs.ticks)
+}
+
+fn has_coverage(task: &TracedTask) -> bool {
+    task.event_cbs
+        .as_ref()
+        .map_or(false, |cbs| cbs.borrow().coverage.is_some())
+}
+
+fn has_probes(task: &TracedTask) -> bool {
+    task.event_cbs
+        .as_ref()
+        .map_or(false, |cbs| !cbs.borrow().probes.is_empty())
+}
+
+// `task` trapped by the `int3` at `at`, if a probe's, see `probes::hit`
+fn probe_hit(task: &TracedTask, at: u64) -> Result<Option<probes::Probed>> {
+    let cbs = match &task.event_cbs {
+        Some(cbs) => cbs,
+        None => return Ok(None),
+    };
+    let (tid, pid) = (task.gettid(), task.getpid());
+    probes::hit(tid, pid, task, &cbs.borrow().probes, at)
+}
+
+fn hash_binaries(task: &TracedTask) -> bool {
+    task.event_cbs
+        .as_ref()
+        .map_or(false, |cbs| cbs.borrow().hash_binaries)
+}
+
+fn wx_policy(task: &TracedTask) -> WxPolicy {
+    task.event_cbs
+        .as_ref()
+        .map(|cbs| cbs.borrow().wx_policy)
+        .unwrap_or_default()
+}
+
+fn is_wx_allowed_task(task: &TracedTask) -> bool {
+    task.event_cbs.as_ref().map_or(false, |cbs| {
+        let allowlist = &cbs.borrow().wx_allowlist;
+        mapping::is_wx_allowed_process(task.getpid(), allowlist)
+    })
+}
+
+// writable and executable mapping denied, see `WxPolicy`
+fn do_denied_wx_syscall(
+    mut task: TracedTask,
+    syscall: SyscallNo,
+    regs: HostRegs,
+) -> Result<RunTask<TracedTask>> {
+    info!("{} W^X: {:?} denied", task.gettid(), syscall);
+    count_ptraced_syscall();
+    task.seccomp_hook_size = None;
+    let mut new_regs = regs;
+    Host::set_retval(&mut new_regs, -libc::EACCES as i64);
+    skip_seccomp_syscall(&mut task, new_regs)?;
+    task.syscall_entered_at = None;
+    task.syscall_sampled = false;
+    let reason = format!("W^X: writable and executable {:?} denied", syscall);
+    violated(&task, &regs, &reason);
+    Ok(RunTask::Runnable(task))
+}
+
+fn has_mapping_handler(task: &TracedTask) -> bool {
+    task.event_cbs
+        .as_ref()
+        .map(|cbs| cbs.borrow().on_mapping_change.is_some())
+        .unwrap_or(false)
+}
+
+// mapping syscalls are never patched when tracked, the syscall is resumed
+// by `PTRACE_SYSCALL`, see `handle_syscall_exit`.
+fn do_mapping_syscall(mut task: TracedTask) -> Result<RunTask<TracedTask>> {
+    count_ptraced_syscall();
+    task.seccomp_hook_size = None;
+    Ok(RunTask::Runnable(task))
+}
+
+fn is_emulated_syscall(
+    task: &TracedTask,
+    mode: EmulationMode,
+    syscall: SyscallNo,
+) -> bool {
+    task.event_cbs
+        .as_ref()
+        .and_then(|cbs| {
+            let cbs = cbs.borrow();
+            let emulation = cbs.on_syscall_emulation.as_ref()?;
+            Some(emulation.mode == mode && emulation.is_trapped(task, syscall))
+        })
+        .unwrap_or(false)
+}
+
+fn emulate_syscall(
+    task: &TracedTask,
+    syscall: SyscallNo,
+    args: &SyscallArgs,
+) -> Option<i64> {
+    let cbs = task.event_cbs.as_ref()?.clone();
+    let mut cbs = cbs.borrow_mut();
+    cbs.on_syscall_emulation
+        .as_mut()?
+        .emulate(task, syscall, args)
+}
+
+// syscall emulated, returning as of `regs`, its data is recorded as if run
+fn syscall_emulated(task: &mut TracedTask, regs: &HostRegs) {
+    let syscall = SyscallNo::from(Host::syscall_nr(regs) as i32);
+    let retval = Host::retval(regs);
+    {
+        let state = reverie_global_state()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        state.stats.nr_syscalls.fetch_add(1, Ordering::SeqCst);
+        state
+            .stats
+            .nr_syscalls_emulated
+            .fetch_add(1, Ordering::SeqCst);
+    }
+    let elapsed = task
+        .syscall_entered_at
+        .take()
+        .map(|t| Timestamp::now().duration_since(t))
+        .unwrap_or_default();
+    if task.syscall_sampled {
+        task.syscall_sampled = false;
+        emit_event(task, Event::SyscallExit(syscall, retval, elapsed));
+        if record_data(task) {
+            emit_syscall_data(task, syscall, regs);
+        }
+    }
+}
+
+// exit of a syscall, which the emulator declined if offered to it
+fn emulation_exited(task: &TracedTask, regs: &HostRegs) {
+    let cbs = match task.event_cbs.as_ref() {
+        Some(cbs) => cbs.clone(),
+        None => return,
+    };
+    let mut cbs = cbs.borrow_mut();
+    if let Some(emulation) = cbs.on_syscall_emulation.as_mut() {
+        let args = SyscallArgs::from_regs(&regs);
+        let syscall = SyscallNo::from(Host::syscall_nr(regs) as i32);
+        emulation.exited(task, syscall, &args, Host::retval(regs));
+    }
+}
+
+// emulated syscalls are never patched, the kernel skips them. those the
+// emulator declines are ptraced.
+fn do_emulated_syscall(
+    mut task: TracedTask,
+    syscall: SyscallNo,
+    regs: HostRegs,
+) -> Result<RunTask<TracedTask>> {
+    let args = SyscallArgs::from_regs(&regs);
+    match emulate_syscall(&task, syscall, &args) {
+        Some(retval) => {
+            let mut new_regs = regs;
+            Host::set_retval(&mut new_regs, retval);
+            skip_seccomp_syscall(&mut task, new_regs)?;
+            syscall_emulated(&mut task, &new_regs);
+            if let Some(reason) = violation::take_flagged(task.gettid()) {
+                violated(&task, &regs, &reason);
+            }
+            Ok(RunTask::Runnable(task))
+        }
+        None => do_unpatched_syscall(task),
+    }
+}
+
+// `PTRACE_SYSEMU` entry stop, the syscall is either emulated, or to be run
+// again, see `sysemu`.
+fn handle_sysemu_entry(mut task: TracedTask) -> Result<RunTask<TracedTask>> {
+    if task.process.borrow().lazy_private_page {
+        task.process.borrow_mut().lazy_private_page = false;
+        if let Err(err) = inject_private_page(&mut task) {
+            warn!("{} cannot map the private page: {:?}", task.gettid(), err);
+        }
+    }
+    let regs = task.getregs()?;
+    let syscall = SyscallNo::from(Host::syscall_nr(&regs) as i32);
+    let args = SyscallArgs::from_regs(&regs);
+    task.syscall_entered_at = Some(Timestamp::now());
+    task.syscall_sampled = SYSCALL_SAMPLER.sample(syscall as i32);
+    if task.syscall_sampled {
+        emit_event(&task, Event::SyscallEnter(syscall, args.clone()));
+        emit_syscall_stack(&task, syscall, &regs);
+        emit_syscall_strings(&task, syscall, &args);
+    }
+    match emulate_syscall(&task, syscall, &args) {
+        Some(retval) => {
+            let mut new_regs = regs;
+            Host::set_retval(&mut new_regs, retval);
+            task.setregs(new_regs)?;
+            task.sysemu = SysemuState::Off;
+            syscall_emulated(&mut task, &new_regs);
+            if let Some(reason) = violation::take_flagged(task.gettid()) {
+                violated(&task, &regs, &reason);
+            }
+        }
+        None => {
+            task.setregs(sysemu::rerun_regs(regs))?;
+            task.sysemu = SysemuState::Rerun;
+        }
+    }
+    Ok(RunTask::Runnable(task))
+}
+
+// whether `task` is passed through, see `passthrough`: the process of a
+// task stopped in code of an unsupported abi is passed through from then.
+fn is_passed_through(task: &TracedTask, regs: &HostRegs) -> bool {
+    if task.process.borrow().passthrough.is_some() {
+        return true;
+    }
+    match passthrough::unsupported_abi(regs) {
+        Some(abi) => {
+            pass_through(task, abi);
+            true
+        }
+        None => false,
+    }
+}
+
+fn pass_through(task: &TracedTask, abi: &'static str) {
+    warn!(
+        "{} runs {}, passed through: syscalls are not intercepted",
+        task.getpid(),
+        abi
+    );
+    task.process.borrow_mut().passthrough = Some(abi);
+}
+
+// syscall of a process passed through, resumed as is, not reported.
+fn do_passthrough_syscall(mut task: TracedTask) -> Result<RunTask<TracedTask>> {
+    task.seccomp_hook_size = None;
+    task.state = TaskState::Running;
+    Ok(RunTask::Runnable(task))
+}
+
+// a syscall stopped by ptrace, rather than run by a patched site
+fn count_ptraced_syscall() {
+    let state = reverie_global_state()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    state.stats.nr_syscalls.fetch_add(1, Ordering::SeqCst);
+    state
+        .stats
+        .nr_syscalls_ptraced
+        .fetch_add(1, Ordering::SeqCst);
+}
+
+// unpatched syscalls are resumed by `PTRACE_SYSCALL`, see
+// `handle_syscall_exit`.
+fn do_unpatched_syscall(mut task: TracedTask) -> Result<RunTask<TracedTask>> {
+    count_ptraced_syscall();
+    task.seccomp_hook_size = None;
+    Ok(RunTask::Runnable(task))
+}
+
+/// resume `task`, which is not in seccomp stop: by `PTRACE_SYSEMU` with
+/// `EmulationMode::Sysemu`, `PTRACE_CONT` otherwise.
+pub fn resume_task(
+    task: &mut TracedTask,
+    sig: Option<signal::Signal>,
+) -> nix::Result<()> {
+    let tid = task.gettid();
+    if task.sysemu.is_rerun() {
+        sysemu::syscall(tid, sig)
+    } else if is_sysemu_mode(task) {
+        task.sysemu = SysemuState::Entry;
+        sysemu::sysemu(tid, sig)
+    } else {
+        ptrace::cont(tid, sig)
+    }
+}
+
+fn is_sysemu_mode(task: &TracedTask) -> bool {
+    if task.process.borrow().passthrough.is_some() {
+        return false;
+    }
+    task.event_cbs
+        .as_ref()
+        .and_then(|cbs| {
+            let cbs = cbs.borrow();
+            Some(cbs.on_syscall_emulation.as_ref()?.mode)
+        })
+        .map_or(false, |mode| mode == EmulationMode::Sysemu)
+}
+
+fn report_mapping_change(task: &TracedTask, regs: &HostRegs) {
+    for change in mapping::mapping_changes_at_exit(task.getpid(), regs) {
+        report_mapping(task, &change, regs);
+    }
+}
+
+fn report_mapping(task: &TracedTask, change: &MappingChange, regs: &HostRegs) {
+    emit_event(task, Event::Mapping(change.clone()));
+    if let Some(cbs) = &task.event_cbs {
+        if let Some(handler) = cbs.borrow_mut().on_mapping_change.as_mut() {
+            if let Err(err) = handler(task, change) {
+                warn!(
+                    "{} mapping change handler failed: {:?}",
+                    task.gettid(),
+                    err
+                );
+            }
+        }
+    }
+    if hash_binaries(task) {
+        if let Some(image) = binaries::mapped_image(task.getpid(), change, regs)
+        {
+            emit_event(task, Event::BinaryLoaded(image));
+        }
+    }
+    if let Some(cbs) = &task.event_cbs {
+        let cbs = cbs.borrow();
+        if let Some(spec) = &cbs.coverage {
+            coverage::mapped(task.getpid(), task, spec, change, regs);
+        }
+        if !cbs.probes.is_empty() {
+            probes::mapped(task.getpid(), task, &cbs.probes, change, regs);
+        }
+    }
+}
+
+fn from_nix_error_with(err: nix::Error, msg: &str) -> Error {
+    let my_error = format!("{}: {:?}", msg, err);
+    Error::new(ErrorKind::Other, my_error)
+}
+
+fn just_continue(pid: Pid, sig: Option<signal::Signal>) -> Result<()> {
+    ptrace::cont(pid, sig).map_err(from_nix_error)
+}
+
+// set tool library log level
+fn systool_set_log_level(task: &TracedTask) {
+    let systool_log_ptr = consts::REVERIE_LOCAL_SYSTOOL_LOG_LEVEL as *mut i64;
+    let rptr = Remoteable::remote(systool_log_ptr).unwrap();
+    let lvl =
+        std::env::var(consts::REVERIE_ENV_TOOL_LOG_KEY).map(|s| match &s[..] {
+            "error" => 1,
+            "warn" => 2,
+            "info" => 3,
+            "debug" => 4,
+            "trace" => 5,
+            _ => 0,
+        });
+    match lvl {
+        Ok(x) if x >= 1 && x <= 5 => {
+            let _ = task.poke(rptr, &x);
+        }
+        _ => (),
+    }
+}
+
+fn tracee_preinit(task: &mut TracedTask) -> nix::Result<()> {
+    let tid = task.gettid();
+    let mut regs = arch::getregs::<Host>(tid)?;
+    let mut saved_regs = regs;
+    let page_addr = consts::REVERIE_PRIVATE_PAGE_OFFSET;
+    let page_size = consts::REVERIE_PRIVATE_PAGE_SIZE;
+
+    let prot = libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC;
+    let flags = libc::MAP_PRIVATE | libc::MAP_FIXED | libc::MAP_ANONYMOUS;
+    let args = [
+        page_addr,
+        page_size,
+        prot as u64,
+        flags as u64,
+        -1i64 as u64,
+        0,
+    ];
+    Host::set_syscall(&mut regs, SYS_mmap as u64, &args);
+
+    arch::setregs::<Host>(tid, regs)?;
+    ptrace::cont(tid, None)?;
+
+    // loop until second breakpoint hit after injected syscall
     loop {
         let status = wait::waitpid(tid, None)?;
         match status {
@@ -1471,12 +2927,13 @@ fn tracee_preinit(task: &mut TracedTask) -> nix::Result<()> {
         }
     }
 
-    let ret = ptrace::getregs(tid).and_then(|r| {
-        if r.rax > (-4096i64 as u64) {
-            let errno = -(r.rax as i64) as i32;
+    let ret = arch::getregs::<Host>(tid).and_then(|r| {
+        let retval = Host::retval(&r);
+        if retval as u64 > (-4096i64 as u64) {
+            let errno = -retval as i32;
             Err(nix::Error::from_errno(nix::errno::from_i32(errno)))
         } else {
-            Ok(r.rax)
+            Ok(retval as u64)
         }
     })?;
     assert_eq!(ret, page_addr);
@@ -1487,8 +2944,61 @@ fn tracee_preinit(task: &mut TracedTask) -> nix::Result<()> {
 
     let _ = vdso::vdso_patch(task);
 
-    saved_regs.rip -= 1; // bp size
-    ptrace::setregs(tid, saved_regs)
+    let pc = Host::breakpoint_site(Host::pc(&saved_regs));
+    Host::set_pc(&mut saved_regs, pc);
+    arch::setregs::<Host>(tid, saved_regs)
+}
+
+// `MAP_FIXED`, but failing with `EEXIST` over a mapping (linux 4.17)
+const MAP_FIXED_NOREPLACE: i32 = 0x10_0000;
+
+// map the private page of a process attached while running, see
+// `Process::lazy_private_page`. at a `PTRACE_SYSEMU` entry stop, the
+// syscall (not run) is rewound and replaced by `mmap`, run until its exit
+// stop; registers of the entry stop are restored after, as if the task was
+// still stopped there.
+fn inject_private_page(task: &mut TracedTask) -> nix::Result<()> {
+    let tid = task.gettid();
+    let saved = arch::getregs::<Host>(tid)?;
+    let page_addr = consts::REVERIE_PRIVATE_PAGE_OFFSET;
+    let page_size = consts::REVERIE_PRIVATE_PAGE_SIZE;
+
+    let mut regs = sysemu::rerun_regs(saved);
+    let prot = libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC;
+    // never replacing a mapping of the process, see `attach`
+    let flags = libc::MAP_PRIVATE | MAP_FIXED_NOREPLACE | libc::MAP_ANONYMOUS;
+    let args = [
+        page_addr,
+        page_size,
+        prot as u64,
+        flags as u64,
+        -1i64 as u64,
+        0,
+    ];
+    Host::set_syscall(&mut regs, SYS_mmap as u64, &args);
+    arch::setregs::<Host>(tid, regs)?;
+
+    // entry, then exit stop of `mmap`
+    let mut stops = 0;
+    while stops < 2 {
+        sysemu::syscall(tid, None)?;
+        match wait::waitpid(tid, None)? {
+            WaitStatus::PtraceSyscall(_) => stops += 1,
+            // delivered once resumed by the scheduler
+            WaitStatus::Stopped(_, sig) => task.signal_to_deliver = Some(sig),
+            _ => return Err(nix::Error::Sys(nix::errno::Errno::ESRCH)),
+        }
+    }
+
+    let mapped = Host::retval(&arch::getregs::<Host>(tid)?) as u64;
+    arch::setregs::<Host>(tid, saved)?;
+    if mapped != page_addr {
+        let errno = nix::errno::from_i32(-(mapped as i64) as i32);
+        return Err(nix::Error::from_errno(errno));
+    }
+    gen_syscall_sequences_at(tid, page_addr)?;
+    task.injected_mmap_page = Some(page_addr);
+    Ok(())
 }
 
 // get ld.so load address (range) from pid.
@@ -1499,29 +3009,66 @@ fn get_proc_maps(pid: Pid) -> Option<Vec<procfs::process::MemoryMap>> {
 }
 
 fn do_ptrace_exec(mut task: &mut TracedTask) -> nix::Result<()> {
+    // processes detached with a seccomp filter stay passed through
+    if task.process.borrow().passthrough == Some(detach::DETACHED) {
+        task_exec_reset(task);
+        task.process.borrow_mut().passthrough = Some(detach::DETACHED);
+        return Ok(());
+    }
+    // unsupported programs are passed through, see `passthrough`
+    let exe = format!("/proc/{}/exe", task.getpid());
+    let unsupported = passthrough::unsupported_program(Path::new(&exe))
+        .ok()
+        .flatten()
+        .or_else(|| passthrough::unsupported_abi(&task.getregs().ok()?));
+    if let Some(abi) = unsupported {
+        task_exec_reset(task);
+        pass_through(task, abi);
+        return Ok(());
+    }
+
     let auxv = unsafe { aux::getauxval(task).unwrap() };
 
-    let bp_syscall_bp: i64 = 0xcc050fcc;
     let tid = task.gettid();
-    let regs = ptrace::getregs(tid)?;
-    let saved: i64 = ptrace::read(tid, regs.rip as ptrace::AddressType)?;
-    ptrace::write(
-        task.tid,
-        regs.rip as ptrace::AddressType,
-        ((saved & !(0xffffffff as i64)) | bp_syscall_bp) as *mut libc::c_void,
-    )?;
+    let pc = Host::pc(&arch::getregs::<Host>(tid)?);
+    let saved = read_code(tid, pc)?;
+    // breakpoint, syscall, breakpoint, see `tracee_preinit`
+    let mut bp_syscall_bp = Host::BREAKPOINT_INSN.to_vec();
+    bp_syscall_bp.extend_from_slice(Host::SYSCALL_INSN);
+    bp_syscall_bp.extend_from_slice(Host::BREAKPOINT_INSN);
+    let mut code = saved;
+    code[..bp_syscall_bp.len()].copy_from_slice(&bp_syscall_bp);
+    write_code(tid, pc, code)?;
     ptrace::cont(tid, None)?;
-    let wait_status = wait::waitpid(tid, None)?;
-    assert!(wait_status == wait::WaitStatus::Stopped(tid, signal::SIGTRAP));
+    loop {
+        match wait::waitpid(tid, None)? {
+            WaitStatus::Stopped(_, signal::SIGTRAP) => break,
+            // delivered once resumed by the scheduler
+            WaitStatus::Stopped(_, sig) => {
+                task.signal_to_deliver = Some(sig);
+                ptrace::cont(tid, None)?;
+            }
+            status => {
+                let _ = dying::unexpected_status(tid, Ok(status));
+                return Err(nix::Error::Sys(nix::errno::Errno::ESRCH));
+            }
+        }
+    }
     tracee_preinit(task)?;
-    ptrace::write(
-        tid,
-        regs.rip as ptrace::AddressType,
-        saved as *mut libc::c_void,
-    )?;
+    write_code(tid, pc, saved)?;
     task_exec_reset(task);
+    shm::shared_memory_map()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .detach_all(task.getpid());
 
     init_rpc_stack_data(&mut task);
+    dispatch::reset(task.getpid().as_raw());
+
+    // syscall sites are patched by the patterns of the program's libc
+    let libc = libc_flavor::program_flavor(Path::new(&exe));
+    debug!("{} exec'ed program of libc {:?}", task.getpid(), libc);
+    task.process.borrow_mut().libc = libc;
 
     // create per process local state.
     let local_state_addr = task
@@ -1545,7 +3092,7 @@ fn do_ptrace_exec(mut task: &mut TracedTask) -> nix::Result<()> {
 
     state
         .lock()
-        .unwrap()
+        .unwrap_or_else(|e| e.into_inner())
         .stats
         .nr_process_spawns
         .fetch_add(1, Ordering::SeqCst);
@@ -1581,9 +3128,78 @@ fn do_ptrace_exec(mut task: &mut TracedTask) -> nix::Result<()> {
         }
     }
 
+    let fileless =
+        fileless::capture(task.getpid(), fileless_dir(task).as_deref());
+    may_install_exec_filter(task, fileless.as_ref());
+
+    emit_event(task, Event::Exec);
+    if let Some(cbs) = &task.event_cbs.clone() {
+        let execfn = &mut cbs.borrow_mut().on_task_exec;
+        if let Err(err) = execfn(task) {
+            warn!("{} exec callback failed: {:?}", task.gettid(), err);
+        }
+    }
+    if let Some(fileless) = fileless {
+        emit_event(task, Event::FilelessExec(fileless));
+    }
+    if hash_binaries(task) {
+        for image in binaries::exec_images(task.getpid()) {
+            emit_event(task, Event::BinaryLoaded(image));
+        }
+    }
+    if let Some(cbs) = &task.event_cbs {
+        let cbs = cbs.borrow();
+        if let Some(spec) = &cbs.coverage {
+            coverage::exec_planted(task.getpid(), &*task, spec);
+        }
+        if !cbs.probes.is_empty() {
+            probes::exec_planted(task.getpid(), &*task, &cbs.probes);
+        }
+    }
     Ok(())
 }
 
+fn fileless_dir(task: &TracedTask) -> Option<PathBuf> {
+    task.event_cbs
+        .as_ref()
+        .and_then(|cbs| cbs.borrow().fileless_dir.clone())
+}
+
+// ask `on_exec_filter` for a seccomp filter for the newly exec'ed program,
+// a fileless program is given by its saved image, if saved. the untraced
+// syscall of the tracer stays allowed; a program whose filter can't be
+// installed is killed rather than run unfiltered.
+fn may_install_exec_filter(
+    task: &mut TracedTask,
+    fileless: Option<&FilelessExec>,
+) {
+    let cbs = match &task.event_cbs {
+        Some(cbs) => cbs.clone(),
+        None => return,
+    };
+    let saved = fileless.and_then(|fileless| fileless.saved.clone());
+    let filter = cbs.borrow_mut().on_exec_filter.as_mut().and_then(|f| {
+        let exe = match &saved {
+            Some(saved) => saved.clone(),
+            None => std::fs::read_link(format!("/proc/{}/exe", task.getpid()))
+                .ok()?,
+        };
+        f(task, &exe)
+    });
+    if let Some(filter) = filter {
+        let ip = Host::untraced_syscall_ip(consts::REVERIE_PRIVATE_PAGE_OFFSET);
+        let filter = seccomp_bpf::bpf_allow_ips(&[ip], &filter);
+        if let Err(err) = task.install_seccomp_filter(&filter) {
+            warn!(
+                "{} failed to install exec seccomp filter, killed: {:?}",
+                task.gettid(),
+                err
+            );
+            let _ = signal::kill(task.getpid(), signal::SIGKILL);
+        }
+    }
+}
+
 fn populate_ldpreload(task: &mut TracedTask) {
     let pid = task.getpid();
     task.ldpreload_address = libtrampoline_load_address(pid);
@@ -1610,11 +3226,6 @@ fn dump_bpf_filter(task: &TracedTask) {
     }
 }
 
-type FnBreakpoint = Box<
-    (dyn FnOnce(TracedTask, Remoteable<c_void>) -> Result<RunTask<TracedTask>>
-         + 'static),
->;
-
 // breakpoint at program's entry, likley `libc_start_main`for
 // for programs linked against glibc
 fn handle_program_entry_bkpt(
@@ -1698,28 +3309,46 @@ fn may_start_dpc_task(mut task: TracedTask) -> Result<RunTask<TracedTask>> {
 // kernel would simply skip the syscall, so that we can jump to our patched syscall
 // on the first run. please note after calling this function, the task state will
 // no longer in ptrace event seccomp.
-fn skip_seccomp_syscall(
-    task: &mut TracedTask,
-    regs: libc::user_regs_struct,
-) -> Result<()> {
+fn skip_seccomp_syscall(task: &mut TracedTask, regs: HostRegs) -> Result<()> {
     let tid = task.gettid();
     let mut new_regs = regs;
-    new_regs.orig_rax = -1i64 as u64;
-    task.setregs(new_regs)?;
+    Host::skip_syscall(&mut new_regs);
+    Host::setregs_at_syscall(tid, new_regs).map_err(from_nix_error)?;
     task.step(None)?;
-    assert!(
-        wait::waitpid(Some(tid), None)
-            == Ok(WaitStatus::Stopped(tid, signal::SIGTRAP))
-    );
+    match wait::waitpid(Some(tid), None) {
+        Ok(WaitStatus::Stopped(_, signal::SIGTRAP)) => (),
+        otherwise => return Err(dying::unexpected_status(tid, otherwise)),
+    }
     task.state = TaskState::Stopped(signal::SIGTRAP);
     task.setregs(regs)?;
     Ok(())
 }
 
 fn is_syscall_insn(tid: unistd::Pid, rip: u64) -> Result<bool> {
-    let insn = ptrace::read(tid, rip as ptrace::AddressType)
-        .map_err(from_nix_error)? as u64;
-    Ok(insn & SYSCALL_INSN_MASK as u64 == SYSCALL_INSN)
+    let insn = read_code(tid, rip).map_err(from_nix_error)?;
+    Ok(Host::is_syscall_insn(&insn))
+}
+
+// (two) words of code at `at` of `tid`
+fn read_code(tid: unistd::Pid, at: u64) -> nix::Result<[u8; 16]> {
+    let mut code = [0u8; 16];
+    for (k, word) in code.chunks_mut(8).enumerate() {
+        let addr = (at + 8 * k as u64) as ptrace::AddressType;
+        word.copy_from_slice(&ptrace::read(tid, addr)?.to_le_bytes());
+    }
+    Ok(code)
+}
+
+// write `code` read by `read_code` back to `at` of `tid`
+fn write_code(tid: unistd::Pid, at: u64, code: [u8; 16]) -> nix::Result<()> {
+    for (k, word) in code.chunks(8).enumerate() {
+        let addr = (at + 8 * k as u64) as ptrace::AddressType;
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(word);
+        let word = i64::from_le_bytes(bytes) as *mut libc::c_void;
+        ptrace::write(tid, addr, word)?;
+    }
+    Ok(())
 }
 
 fn handle_breakpoint_event(
@@ -1728,3 +3357,364 @@ fn handle_breakpoint_event(
 ) -> Result<RunTask<TracedTask>> {
     Ok(RunTask::Runnable(task))
 }
+
+#[test]
+fn exec_from_thread_sanity_check() {
+    // a thread of the traced child execs `/bin/true`, the leader is killed
+    extern "C" fn exec_true(argv: *mut c_void) -> libc::c_int {
+        let argv = argv as *const *const libc::c_char;
+        unsafe {
+            libc::execv(*argv, argv);
+            libc::_exit(1)
+        }
+    }
+    let path = std::ffi::CString::new("/bin/true").unwrap();
+    let argv = [path.as_ptr(), std::ptr::null()];
+    let mut stack = vec![0u8; 0x10000];
+    let flags = libc::CLONE_VM
+        | libc::CLONE_FS
+        | libc::CLONE_FILES
+        | libc::CLONE_SIGHAND
+        | libc::CLONE_THREAD
+        | libc::CLONE_SYSVSEM;
+    let child = match unistd::fork().expect("fork failed") {
+        unistd::ForkResult::Child => unsafe {
+            libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0);
+            libc::raise(libc::SIGSTOP);
+            let top = stack.as_mut_ptr().add(stack.len()) as *mut c_void;
+            let argv = argv.as_ptr() as *mut c_void;
+            libc::clone(exec_true, top, flags, argv);
+            loop {
+                libc::pause();
+            }
+        },
+        unistd::ForkResult::Parent { child } => child,
+    };
+    let all = Some(wait::WaitPidFlag::__WALL);
+    let status = wait::waitpid(child, all);
+    assert_eq!(status, Ok(WaitStatus::Stopped(child, signal::SIGSTOP)));
+    let options = ptrace::Options::PTRACE_O_TRACECLONE
+        | ptrace::Options::PTRACE_O_TRACEEXEC
+        | ptrace::Options::PTRACE_O_TRACEEXIT;
+    ptrace::setoptions(child, options).unwrap();
+    ptrace::cont(child, None).unwrap();
+    let status = wait::waitpid(child, all);
+    assert_eq!(
+        status,
+        Ok(WaitStatus::PtraceEvent(child, signal::SIGTRAP, 3))
+    );
+    let thread = Pid::from_raw(ptrace::getevent(child).unwrap() as i32);
+    ptrace::cont(child, None).unwrap();
+    let status = wait::waitpid(thread, all);
+    assert_eq!(status, Ok(WaitStatus::Stopped(thread, signal::SIGSTOP)));
+    ptrace::cont(thread, None).unwrap();
+    let status = wait::waitpid(child, all);
+    assert_eq!(
+        status,
+        Ok(WaitStatus::PtraceEvent(child, signal::SIGTRAP, 6))
+    );
+
+    let mut task: TracedTask = Task::new(child);
+    task.state = TaskState::Exited(child, 0);
+    // exec'ed programs are not to be patched
+    task.process.borrow_mut().passthrough = Some(detach::DETACHED);
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let mut cbs = TaskEventCB::new(
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+    );
+    let kinds = events.clone();
+    cbs.add_event_sink(Box::new(move |event| {
+        kinds.borrow_mut().push(event.event.kind())
+    }));
+    task.event_cbs = Some(Rc::new(RefCell::new(cbs)));
+    let mut task = match run_task(Arc::new(Mutex::new(())), task) {
+        Ok(RunTask::Runnable(task)) => task,
+        _ => panic!("leader gone with the exec of its thread"),
+    };
+    assert_eq!(task.gettid(), child);
+    assert_eq!(task.take_exec_tid(), Some(thread));
+    assert!(!events.borrow().contains(&EventKind::Exited));
+    let _ = signal::kill(child, signal::SIGKILL);
+    let _ = wait::waitpid(child, all);
+}
+
+#[test]
+fn exec_filter_sanity_check() {
+    // the filter of the child traps all syscalls, but the untraced one
+    const SECCOMP_RET_TRAP: u64 = 0x0003_0000;
+    let mut rpc = vec![0u64; 0x800];
+    let child = match unistd::fork().expect("fork failed") {
+        unistd::ForkResult::Child => unsafe {
+            let page = libc::mmap(
+                consts::REVERIE_PRIVATE_PAGE_OFFSET as *mut c_void,
+                0x1000,
+                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+                -1,
+                0,
+            );
+            let seq = Host::syscall_sequences();
+            std::ptr::copy(seq.as_ptr(), page as *mut u8, seq.len());
+            libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0);
+            libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0);
+            libc::raise(libc::SIGSTOP);
+            libc::getppid();
+            libc::_exit(0)
+        },
+        unistd::ForkResult::Parent { child } => child,
+    };
+    let status = wait::waitpid(child, None);
+    assert_eq!(status, Ok(WaitStatus::Stopped(child, signal::SIGSTOP)));
+
+    let mut task: TracedTask = Task::new(child);
+    let rpc_data = Remoteable::remote(rpc.as_mut_ptr());
+    task.rpc_data = rpc_data.map(|s| (s, rpc.len() * 8));
+    let mut cbs = TaskEventCB::new(
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+    );
+    cbs.set_exec_filter(Box::new(|_, exe| {
+        assert!(exe.is_absolute());
+        Some(vec![SECCOMP_RET_TRAP << 32 | 0x6])
+    }));
+    task.event_cbs = Some(Rc::new(RefCell::new(cbs)));
+    may_install_exec_filter(&mut task, None);
+    let ppid = task.untraced_syscall(SYS_getppid, 0, 0, 0, 0, 0, 0);
+    assert_eq!(ppid.ok(), Some(i64::from(unistd::getpid().as_raw())));
+
+    ptrace::detach(child).unwrap();
+    match wait::waitpid(child, None) {
+        Ok(WaitStatus::Signaled(pid, signal::SIGSYS, _)) => {
+            assert_eq!(pid, child)
+        }
+        status => panic!("denied syscall not trapped: {:?}", status),
+    }
+}
+
+#[test]
+fn split_syscall_time_sanity_check() {
+    let ms = Duration::from_millis;
+    assert_eq!(split_syscall_time(ms(10), ms(3), ms(2)), (ms(5), ms(5)));
+    assert_eq!(split_syscall_time(ms(10), ms(0), ms(0)), (ms(0), ms(10)));
+    // clock skew, the kernel never takes negative time
+    assert_eq!(split_syscall_time(ms(4), ms(3), ms(2)), (ms(5), ms(0)));
+}
+
+#[test]
+fn denied_wx_sanity_check() {
+    // `mprotect` of the child is traced by its own filter, the child exits
+    // with 0 if failed with `EACCES`.
+    let trace_mprotect = [
+        0x20,
+        (SYS_mprotect as u64) << 32 | 0x0100_0015,
+        0x7ff0_0000 << 32 | 0x6,
+        0x7fff_0000 << 32 | 0x6,
+    ];
+    let child = match unistd::fork().expect("fork failed") {
+        unistd::ForkResult::Child => unsafe {
+            let page = libc::mmap(
+                std::ptr::null_mut(),
+                0x1000,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0);
+            libc::raise(libc::SIGSTOP);
+            libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0);
+            let _ = seccomp_bpf::seccomp(&trace_mprotect);
+            let wx = libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC;
+            let denied = libc::mprotect(page, 0x1000, wx) == -1
+                && *libc::__errno_location() == libc::EACCES;
+            libc::_exit(if denied { 0 } else { 1 })
+        },
+        unistd::ForkResult::Parent { child } => child,
+    };
+    let status = wait::waitpid(child, None);
+    assert_eq!(status, Ok(WaitStatus::Stopped(child, signal::SIGSTOP)));
+    ptrace::setoptions(child, ptrace_options()).unwrap();
+    ptrace::cont(child, None).unwrap();
+    let status = wait::waitpid(child, None);
+    assert_eq!(
+        status,
+        Ok(WaitStatus::PtraceEvent(child, signal::SIGTRAP, 7))
+    );
+
+    let mut task: TracedTask = Task::new(child);
+    let mut cbs = TaskEventCB::new(
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+        Box::new(|_| Ok(())),
+    );
+    cbs.wx_policy = WxPolicy::Deny;
+    task.event_cbs = Some(Rc::new(RefCell::new(cbs)));
+    let regs = task.getregs().unwrap();
+    assert!(mapping::is_wx_request(SYS_mprotect, &regs));
+    assert!(!is_wx_allowed_task(&task));
+    match do_denied_wx_syscall(task, SYS_mprotect, regs) {
+        Ok(RunTask::Runnable(_)) => (),
+        _ => panic!("W^X mprotect not skipped"),
+    }
+    ptrace::cont(child, None).unwrap();
+    let status = wait::waitpid(child, None);
+    assert_eq!(
+        status,
+        Ok(WaitStatus::PtraceEvent(child, signal::SIGTRAP, 6))
+    );
+    ptrace::cont(child, None).unwrap();
+    assert_eq!(wait::waitpid(child, None), Ok(WaitStatus::Exited(child, 0)));
+}
+
+#[test]
+fn spawned_exec_sanity_check() {
+    let mut parent: TracedTask = Task::new(unistd::getpid());
+    let bkpt: FnBreakpoint = Box::new(|task, _| Ok(RunTask::Runnable(task)));
+    parent
+        .process
+        .borrow_mut()
+        .breakpoints
+        .insert(0x2000, (0x90, bkpt));
+    parent.process.borrow_mut().patched_syscalls.insert(0x1000);
+    let flags = CloneFlags::CLONE_VM | CloneFlags::CLONE_VFORK;
+    let mut spawned = parent.spawned(Pid::from_raw(4_000_101), flags);
+    assert!(Rc::ptr_eq(&parent.process, &spawned.process));
+
+    // the spawned child has a process of its own once it execs
+    task_exec_reset(&mut spawned);
+    assert!(!Rc::ptr_eq(&parent.process, &spawned.process));
+    assert_eq!(spawned.process.borrow().pid(), spawned.getpid());
+    assert!(!spawned.is_patched_syscall(0x1000));
+    assert!(parent.is_patched_syscall(0x1000));
+    assert_eq!(parent.process.borrow().breakpoints.len(), 1);
+
+    // the process of the parent is reset in place by its own exec
+    let process = parent.process.clone();
+    task_exec_reset(&mut parent);
+    assert!(Rc::ptr_eq(&parent.process, &process));
+    assert!(!parent.is_patched_syscall(0x1000));
+    assert!(parent.process.borrow().breakpoints.is_empty());
+}
+
+#[test]
+fn inject_sanity_check() {
+    use reverie_api::inject::*;
+
+    let child = match unistd::fork().expect("fork failed") {
+        unistd::ForkResult::Child => unsafe {
+            let page = libc::mmap(
+                consts::REVERIE_PRIVATE_PAGE_OFFSET as *mut c_void,
+                0x1000,
+                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+                -1,
+                0,
+            );
+            let seq = Host::syscall_sequences();
+            std::ptr::copy(seq.as_ptr(), page as *mut u8, seq.len());
+            libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0);
+            libc::raise(libc::SIGSTOP);
+            libc::_exit(0)
+        },
+        unistd::ForkResult::Parent { child } => child,
+    };
+    let status = wait::waitpid(child, None);
+    assert_eq!(status, Ok(WaitStatus::Stopped(child, signal::SIGSTOP)));
+    let task: TracedTask = Task::new(child);
+    let proc = PathBuf::from(format!("/proc/{}", child));
+
+    let args = SyscallArgs::from(0, 0, 0, 0, 0, 0);
+    let pid = task.inject_syscall(SYS_getpid, &args);
+    assert_eq!(pid.ok(), Some(i64::from(child.as_raw())));
+
+    let fd = remote_open(&task, Path::new("/dev/null"), libc::O_RDONLY, 0);
+    let fd = fd.unwrap();
+    let link = std::fs::read_link(proc.join("fd").join(fd.to_string()));
+    assert_eq!(link.ok(), Some(PathBuf::from("/dev/null")));
+    assert!(remote_close(&task, fd).is_ok());
+    assert!(!proc.join("fd").join(fd.to_string()).exists());
+    // failed syscalls are errors
+    let closed = remote_close(&task, fd).map_err(|err| err.raw_os_error());
+    assert_eq!(closed, Err(Some(libc::EBADF)));
+    let path = Path::new("/nonexistent/reverie");
+    let opened = remote_open(&task, path, libc::O_RDONLY, 0);
+    assert_eq!(opened.unwrap_err().raw_os_error(), Some(libc::ENOENT));
+
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+    let addr = remote_mmap(&task, 0, 0x2000, prot, flags, -1, 0).unwrap();
+    let mapped = |addr: u64| {
+        let maps = std::fs::read_to_string(proc.join("maps")).unwrap();
+        maps.lines()
+            .any(|line| line.starts_with(&format!("{:x}-", addr)))
+    };
+    assert!(mapped(addr));
+    assert!(remote_munmap(&task, addr, 0x2000).is_ok());
+    assert!(!mapped(addr));
+
+    ptrace::detach(child).unwrap();
+    let status = wait::waitpid(child, None);
+    assert_eq!(status, Ok(WaitStatus::Exited(child, 0)));
+}
+
+#[test]
+fn remote_file_sanity_check() {
+    use reverie_api::inject::*;
+
+    // the child works in `dir`, relative paths are relative to it
+    let dir = std::env::temp_dir()
+        .join(format!("remote-file-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let child = match unistd::fork().expect("fork failed") {
+        unistd::ForkResult::Child => unsafe {
+            let page = libc::mmap(
+                consts::REVERIE_PRIVATE_PAGE_OFFSET as *mut c_void,
+                0x1000,
+                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+                -1,
+                0,
+            );
+            let seq = Host::syscall_sequences();
+            std::ptr::copy(seq.as_ptr(), page as *mut u8, seq.len());
+            if unistd::chdir(&dir).is_err() {
+                libc::_exit(1)
+            }
+            libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0);
+            libc::raise(libc::SIGSTOP);
+            libc::_exit(0)
+        },
+        unistd::ForkResult::Parent { child } => child,
+    };
+    let status = wait::waitpid(child, None);
+    assert_eq!(status, Ok(WaitStatus::Stopped(child, signal::SIGSTOP)));
+    let task: TracedTask = Task::new(child);
+
+    // more than a buffer mapped in the child
+    let data: Vec<u8> = (0..0x18000u32).map(|k| (k % 251) as u8).collect();
+    let path = Path::new("data");
+    assert!(remote_write_file(&task, path, &data, 0o600).is_ok());
+    assert_eq!(std::fs::read(dir.join(path)).ok(), Some(data.clone()));
+    assert_eq!(remote_read_file(&task, path).ok(), Some(data));
+    // truncated when written again
+    assert!(remote_write_file(&task, path, b"short", 0o600).is_ok());
+    assert_eq!(remote_read_file(&task, path).ok(), Some(b"short".to_vec()));
+    let missing = remote_read_file(&task, Path::new("missing"));
+    assert_eq!(missing.unwrap_err().raw_os_error(), Some(libc::ENOENT));
+    // files opened in the child are closed after
+    let fds = std::fs::read_dir(format!("/proc/{}/fd", child)).unwrap();
+    assert!(fds
+        .filter_map(|fd| std::fs::read_link(fd.ok()?.path()).ok())
+        .all(|link| !link.starts_with(&dir)));
+
+    ptrace::detach(child).unwrap();
+    let status = wait::waitpid(child, None);
+    assert_eq!(status, Ok(WaitStatus::Exited(child, 0)));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
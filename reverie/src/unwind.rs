@@ -0,0 +1,442 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! user-space stack capture at syscall entry, of `--stack-frames`
+//!
+//! stacks are walked from the registers of a task stopped at a syscall by
+//! frame pointers (rbp), as `violation::backtrace`. a step the frame
+//! pointer fails (not pointing up the stack, or to a return address not
+//! executable, as of code built without frame pointers) is taken by the
+//! call frame information (`.eh_frame`) of the binary mapped at the pc
+//! instead: the canonical frame address (cfa), saved rbp and return
+//! address rules of the row of the pc are evaluated (by `gimli`), dwarf
+//! expressions are not supported and end the walk.
+//!
+//! NB: the first step is taken by call frame information if any: syscall
+//! wrappers of libc set no frame, walking rbp from them skips their caller.
+//!
+//! `.eh_frame` sections are parsed once per file mapped, by device, inode
+//! and modification time, see `EhFrame`. the regions of a process are read
+//! once until its mappings change, see `changes_regions`, and stacks are
+//! read by chunks. stacks are given by `Event::SyscallStack`, pc first.
+
+use gimli::{
+    BaseAddresses, CfaRule, CieOrFde, EndianArcSlice, FrameDescriptionEntry,
+    LittleEndian, Register, RegisterRule, UnwindContext, UnwindSection, X86_64,
+};
+use goblin::elf::{program_header::PT_LOAD, Elf};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use syscalls::SyscallNo;
+
+use reverie_api::emulate::TaskMemory;
+use reverie_api::search::Region;
+
+use crate::mapping;
+
+/// frames captured by default, see `TaskEventCB::stack_frames`
+pub const DEFAULT_STACK_FRAMES: usize = 32;
+
+// stacks are read by aligned chunks of this size, within a page
+const CHUNK_SIZE: u64 = 512;
+
+type Reader = EndianArcSlice<LittleEndian>;
+
+// device, inode and modification time (s, ns) of a file
+type FileId = (u64, u64, i64, i64);
+
+lazy_static! {
+    static ref EH_FRAMES: Mutex<HashMap<FileId, Option<Arc<EhFrame>>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// registers of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnwindRegs {
+    pub pc: u64,
+    pub sp: u64,
+    pub fp: u64,
+}
+
+/// call frame information of a binary, its `.eh_frame` section
+#[derive(Debug, Clone)]
+pub struct EhFrame {
+    section: gimli::EhFrame<Reader>,
+    bases: BaseAddresses,
+    /// by initial address
+    fdes: Vec<FrameDescriptionEntry<Reader>>,
+    /// loadable segments: file offset, size, vaddr
+    segments: Vec<(u64, u64, u64)>,
+}
+
+impl EhFrame {
+    /// parse `.eh_frame` `bytes` at vaddr `addr`
+    pub fn parse(bytes: Vec<u8>, addr: u64) -> Option<Self> {
+        let reader = EndianArcSlice::new(Arc::from(bytes), LittleEndian);
+        let section = gimli::EhFrame::from(reader);
+        let bases = BaseAddresses::default().set_eh_frame(addr);
+        let mut fdes = Vec::new();
+        let mut entries = section.entries(&bases);
+        while let Some(entry) = entries.next().ok()? {
+            if let CieOrFde::Fde(partial) = entry {
+                let fde = partial.parse(UnwindSection::cie_from_offset).ok()?;
+                fdes.push(fde);
+            }
+        }
+        fdes.sort_by_key(|fde| fde.initial_address());
+        Some(EhFrame {
+            section,
+            bases,
+            fdes,
+            segments: Vec::new(),
+        })
+    }
+
+    /// call frame information of ELF `path`, if it has any
+    pub fn load(path: &Path) -> Option<Self> {
+        let bytes = std::fs::read(path).ok()?;
+        let elf = Elf::parse(&bytes).ok()?;
+        let section = elf.section_headers.iter().find(|sh| {
+            elf.shdr_strtab.get(sh.sh_name).and_then(|name| name.ok())
+                == Some(".eh_frame")
+        })?;
+        let (offset, size) = (section.sh_offset as usize, section.sh_size);
+        let data = bytes.get(offset..offset + size as usize)?.to_vec();
+        let mut eh_frame = EhFrame::parse(data, section.sh_addr)?;
+        eh_frame.segments = elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == PT_LOAD)
+            .map(|ph| (ph.p_offset, ph.p_filesz, ph.p_vaddr))
+            .collect();
+        Some(eh_frame)
+    }
+
+    // vaddr of file `offset`
+    fn vaddr(&self, offset: u64) -> Option<u64> {
+        self.segments
+            .iter()
+            .find(|(start, size, _)| offset >= *start && offset < start + size)
+            .map(|(start, _, vaddr)| offset - start + vaddr)
+    }
+
+    /// registers of the caller of the frame of `regs`, the pc at `vaddr`
+    pub fn step<F>(
+        &self,
+        vaddr: u64,
+        regs: UnwindRegs,
+        read: F,
+    ) -> Option<UnwindRegs>
+    where
+        F: Fn(u64) -> Option<u64>,
+    {
+        let at = match self
+            .fdes
+            .binary_search_by_key(&vaddr, |fde| fde.initial_address())
+        {
+            Ok(at) => at,
+            Err(0) => return None,
+            Err(at) => at - 1,
+        };
+        let fde = &self.fdes[at];
+        if !fde.contains(vaddr) {
+            return None;
+        }
+        let mut ctx = UnwindContext::new();
+        let row = fde
+            .unwind_info_for_address(
+                &self.section,
+                &self.bases,
+                &mut ctx,
+                vaddr,
+            )
+            .ok()?;
+        let cfa = match row.cfa() {
+            CfaRule::RegisterAndOffset { register, offset }
+                if *register == X86_64::RSP =>
+            {
+                (regs.sp as i64 + offset) as u64
+            }
+            CfaRule::RegisterAndOffset { register, offset }
+                if *register == X86_64::RBP =>
+            {
+                (regs.fp as i64 + offset) as u64
+            }
+            _ => return None,
+        };
+        let saved = |reg: Register| match row.register(reg) {
+            RegisterRule::Offset(off) => read((cfa as i64 + off) as u64),
+            _ => None,
+        };
+        // registers without a rule keep their value
+        let fp = match row.register(X86_64::RBP) {
+            RegisterRule::Undefined | RegisterRule::SameValue => regs.fp,
+            _ => saved(X86_64::RBP)?,
+        };
+        Some(UnwindRegs {
+            pc: saved(fde.cie().return_address_register())?,
+            sp: cfa,
+            fp,
+        })
+    }
+}
+
+// identity of the file mapped by `region`, unless the file at its path is
+// no longer the one mapped
+fn file_id(region: &Region) -> Option<FileId> {
+    let meta = std::fs::metadata(&region.path).ok()?;
+    if (meta.dev(), meta.ino()) != (region.dev, region.inode) {
+        return None;
+    }
+    Some((meta.dev(), meta.ino(), meta.mtime(), meta.mtime_nsec()))
+}
+
+// call frame information of the file mapped by `region`, parsed once
+fn eh_frame(region: &Region) -> Option<Arc<EhFrame>> {
+    let id = file_id(region)?;
+    let mut eh_frames = EH_FRAMES.lock().unwrap_or_else(|e| e.into_inner());
+    eh_frames
+        .entry(id)
+        .or_insert_with(|| EhFrame::load(Path::new(&region.path)).map(Arc::new))
+        .clone()
+}
+
+fn region_of(regions: &[Region], addr: u64) -> Option<&Region> {
+    regions
+        .iter()
+        .find(|region| region.start <= addr && addr < region.end)
+}
+
+fn is_executable(regions: &[Region], addr: u64) -> bool {
+    region_of(regions, addr).map_or(false, |region| region.perms.contains('x'))
+}
+
+// caller of the frame of `regs` by the frame pointer
+fn fp_step<F>(
+    regs: UnwindRegs,
+    read: F,
+    regions: &[Region],
+) -> Option<UnwindRegs>
+where
+    F: Fn(u64) -> Option<u64>,
+{
+    if regs.fp < regs.sp {
+        return None;
+    }
+    let (fp, pc) = (read(regs.fp)?, read(regs.fp + 8)?);
+    if (fp != 0 && fp <= regs.fp) || !is_executable(regions, pc) {
+        return None;
+    }
+    Some(UnwindRegs {
+        pc,
+        sp: regs.fp + 16,
+        fp,
+    })
+}
+
+// call frame information of the binaries mapped, by region start
+type EhFrames = RefCell<HashMap<u64, Option<Arc<EhFrame>>>>;
+
+// caller of the frame of `regs` by call frame information, `pc` being a
+// return address but for the first frame
+fn cfi_step<F>(
+    regs: UnwindRegs,
+    first: bool,
+    read: F,
+    regions: &[Region],
+    eh_frames: &EhFrames,
+) -> Option<UnwindRegs>
+where
+    F: Fn(u64) -> Option<u64>,
+{
+    // the call, rather than what follows it
+    let pc = if first { regs.pc } else { regs.pc - 1 };
+    let region = region_of(regions, pc)?;
+    if !region.path.starts_with('/') {
+        return None;
+    }
+    let eh_frame = eh_frames
+        .borrow_mut()
+        .entry(region.start)
+        .or_insert_with(|| eh_frame(region))
+        .clone()?;
+    let vaddr = eh_frame.vaddr(pc - region.start + region.offset)?;
+    let caller = eh_frame.step(vaddr, regs, read)?;
+    if is_executable(regions, caller.pc) {
+        Some(caller)
+    } else {
+        None
+    }
+}
+
+// words of a stack, read by chunks
+struct StackReader<'a> {
+    memory: &'a dyn TaskMemory,
+    /// by address, `None` if not readable
+    chunks: RefCell<HashMap<u64, Option<Vec<u8>>>>,
+}
+
+impl<'a> StackReader<'a> {
+    fn new(memory: &'a dyn TaskMemory) -> Self {
+        StackReader {
+            memory,
+            chunks: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn read(&self, addr: u64) -> Option<u64> {
+        let chunk = addr & !(CHUNK_SIZE - 1);
+        let at = (addr - chunk) as usize;
+        let mut word = [0u8; 8];
+        if at + 8 > CHUNK_SIZE as usize {
+            // across chunks
+            let bytes = self.memory.read_bytes(addr, 8).ok()?;
+            word.copy_from_slice(bytes.get(..8)?);
+            return Some(u64::from_ne_bytes(word));
+        }
+        // chunks are within a page, readable as their words
+        let mut chunks = self.chunks.borrow_mut();
+        let bytes = chunks.entry(chunk).or_insert_with(|| {
+            self.memory.read_bytes(chunk, CHUNK_SIZE as usize).ok()
+        });
+        word.copy_from_slice(bytes.as_ref()?.get(at..at + 8)?);
+        Some(u64::from_ne_bytes(word))
+    }
+}
+
+/// whether `syscall` may change the regions of a process, read again
+/// after it, see `capture`
+pub fn changes_regions(syscall: SyscallNo) -> bool {
+    mapping::is_mapping_syscall(syscall)
+        || match syscall {
+            SyscallNo::SYS_shmat | SyscallNo::SYS_shmdt => true,
+            _ => false,
+        }
+}
+
+/// pcs of the user-space stack of a task with `regions` (of
+/// `/proc/<pid>/maps`), stopped with `regs`, its pc first, then return
+/// addresses, `max_frames` at most
+pub fn capture(
+    regions: &[Region],
+    memory: &dyn TaskMemory,
+    regs: &libc::user_regs_struct,
+    max_frames: usize,
+) -> Vec<u64> {
+    let stack = StackReader::new(memory);
+    let read = |addr: u64| stack.read(addr);
+    let eh_frames = EhFrames::default();
+    let mut frame = UnwindRegs {
+        pc: regs.rip,
+        sp: regs.rsp,
+        fp: regs.rbp,
+    };
+    let mut frames = Vec::new();
+    while frames.len() < max_frames {
+        frames.push(frame.pc);
+        let caller = if frames.len() == 1 {
+            cfi_step(frame, true, read, regions, &eh_frames)
+                .or_else(|| fp_step(frame, read, regions))
+        } else {
+            fp_step(frame, read, regions)
+                .or_else(|| cfi_step(frame, false, read, regions, &eh_frames))
+        };
+        match caller {
+            // callers are up the stack
+            Some(caller) if caller.sp > frame.sp => frame = caller,
+            _ => break,
+        }
+    }
+    frames
+}
+
+#[test]
+fn unwind_sanity_check() {
+    // CIE: zR, code align 1, data align -8, ra 16, pcrel|sdata4,
+    // def_cfa rsp+8, offset ra at cfa-8
+    let mut bytes: Vec<u8> = vec![
+        0x14, 0, 0, 0, 0, 0, 0, 0, 1, b'z', b'R', 0, 1, 0x78, 16, 1, 0x1b,
+        0x0c, 7, 8, 0x90, 1, 0, 0,
+    ];
+    // FDE of [0x1000, 0x1040): push rbp; mov rbp,rsp, at vaddr 0x1000 of
+    // the section at 0x2000
+    let fde_at = bytes.len() as i32;
+    let start = 0x1000 - (0x2000 + fde_at + 8);
+    bytes.extend(&[0x18, 0, 0, 0]);
+    bytes.extend(&(fde_at + 4).to_le_bytes());
+    bytes.extend(&start.to_le_bytes());
+    bytes.extend(&0x40u32.to_le_bytes());
+    bytes.push(0);
+    // advance 1, def_cfa_offset 16, offset rbp at cfa-16, advance 3,
+    // def_cfa_register rbp
+    bytes.extend(&[0x41, 0x0e, 16, 0x86, 2, 0x43, 0x0d, 6, 0, 0, 0]);
+    bytes.extend(&[0, 0, 0, 0]);
+    let eh_frame = EhFrame::parse(bytes, 0x2000).unwrap();
+    assert_eq!(eh_frame.fdes.len(), 1);
+    let fde = &eh_frame.fdes[0];
+    assert_eq!((fde.initial_address(), fde.len()), (0x1000, 0x40));
+
+    let stack: HashMap<u64, u64> = [(0x7ff0, 0x7fff_0000), (0x7ff8, 0x4242)]
+        .iter()
+        .cloned()
+        .collect();
+    let read = |addr| stack.get(&addr).cloned();
+    // entry: ra at rsp
+    let regs = UnwindRegs {
+        pc: 0x1000,
+        sp: 0x7ff8,
+        fp: 0x7fff_0000,
+    };
+    let caller = eh_frame.step(0x1000, regs, read).unwrap();
+    assert_eq!(
+        caller,
+        UnwindRegs {
+            pc: 0x4242,
+            sp: 0x8000,
+            fp: 0x7fff_0000,
+        }
+    );
+    // rbp pushed, and set as the cfa register
+    let regs = UnwindRegs {
+        pc: 0x1010,
+        sp: 0x7fd0,
+        fp: 0x7ff0,
+    };
+    assert_eq!(eh_frame.step(0x1010, regs, read), Some(caller));
+    assert_eq!(eh_frame.step(0x1040, regs, read), None);
+
+    // words of 0x1000 bytes at 0, counting reads
+    use std::io::Result;
+
+    struct Memory(std::cell::Cell<usize>);
+    impl TaskMemory for Memory {
+        fn read_bytes(&self, addr: u64, size: usize) -> Result<Vec<u8>> {
+            self.0.set(self.0.get() + 1);
+            let end = std::cmp::min(addr as usize + size, 0x1000);
+            Ok((addr as usize..end).map(|at| (at / 8) as u8).collect())
+        }
+        fn write_bytes(&self, _addr: u64, _bytes: &[u8]) -> Result<()> {
+            Ok(())
+        }
+    }
+    let memory = Memory(Default::default());
+    let stack = StackReader::new(&memory);
+    assert_eq!(stack.read(0x10), Some(0x0202_0202_0202_0202));
+    assert_eq!(stack.read(0x1f8), Some(0x3f3f_3f3f_3f3f_3f3f));
+    assert_eq!(memory.0.get(), 1);
+    assert_eq!(stack.read(0x1fc), Some(0x4040_4040_3f3f_3f3f));
+    assert_eq!(memory.0.get(), 2);
+}
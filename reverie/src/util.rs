@@ -13,6 +13,9 @@
 use std::error::Error;
 use std::str::FromStr;
 
+use reverie::reverie_common::sampling::SyscallSampler;
+use reverie::reverie_common::tiers::TierTable;
+
 /// Parses an environment variable command-line argument.
 pub fn parse_env<T, U>(s: &str) -> Result<(T, U), Box<dyn Error>>
 where
@@ -32,3 +35,17 @@ where
 
     Ok((key.parse()?, value))
 }
+
+/// Validates a syscall sampling spec, see `SyscallSampler::parse`.
+pub fn parse_sampling(s: &str) -> Result<String, String> {
+    SyscallSampler::parse(s)
+        .map(|_| s.to_string())
+        .ok_or_else(|| format!("invalid sampling spec: {}", s))
+}
+
+/// Validates a syscall handling tiers spec, see `TierTable::parse`.
+pub fn parse_tiers(s: &str) -> Result<String, String> {
+    TierTable::parse(s)
+        .map(|_| s.to_string())
+        .ok_or_else(|| format!("invalid handling tiers spec: {}", s))
+}
@@ -143,6 +143,14 @@ fn vdso_get_symbols_info() -> HashMap<String, (u64, usize)> {
     res
 }
 
+/// whether `offset` (from vdso base) is in a function rewritten by
+/// `vdso_patch`, whose syscalls can be patched as any other.
+pub fn is_patched_vdso_offset(offset: u64) -> bool {
+    VDSO_PATCH_INFO
+        .values()
+        .any(|(base, size, _)| offset >= *base && offset < base + *size as u64)
+}
+
 #[test]
 fn can_find_vdso() {
     assert!(procfs::process::Process::new(unistd::getpid().as_raw())
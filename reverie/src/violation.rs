@@ -0,0 +1,97 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! sandbox kill mode, see `ViolationAction::Kill`
+//!
+//! the tracer keeps recent events of each task (`record_event`), and
+//! emulators denying a syscall flag it as a violation (`flag_violation`).
+//! `kill_violating` reports a `Violation`, and kills the offending
+//! process, by `SIGKILL` to its thread group only.
+
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use syscalls::SyscallNo;
+
+use reverie_api::emulate::TaskMemory;
+use reverie_api::event::TimedEvent;
+use reverie_api::remote::SyscallArgs;
+use reverie_api::search::read_regions;
+use reverie_api::task::Task;
+use reverie_api::violation::*;
+
+lazy_static! {
+    static ref RECENT: Mutex<RecentEvents> = Mutex::new(RecentEvents::new());
+    static ref FLAGGED: Mutex<HashMap<Pid, String>> =
+        Mutex::new(HashMap::new());
+    static ref VIOLATIONS: Mutex<Vec<Violation>> = Mutex::new(Vec::new());
+}
+
+/// keep `event` for violation reports
+pub fn record_event(event: &TimedEvent) {
+    RECENT.lock().unwrap_or_else(|e| e.into_inner()).push(event);
+}
+
+/// the syscall `tid` is in is denied by a policy, for `reason`. called by
+/// emulators (i.e.: `Hermetic`), see `take_flagged`.
+pub fn flag_violation(tid: Pid, reason: String) {
+    FLAGGED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(tid, reason);
+}
+
+/// reason the syscall `tid` is in was denied, if flagged
+pub fn take_flagged(tid: Pid) -> Option<String> {
+    FLAGGED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&tid)
+}
+
+/// violations which killed processes so far
+pub fn violations() -> Vec<Violation> {
+    VIOLATIONS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// report the syscall of `regs` as denied for `reason`, and kill the
+/// process of `task`.
+pub fn kill_violating(
+    task: &dyn Task,
+    memory: &dyn TaskMemory,
+    regs: &libc::user_regs_struct,
+    reason: &str,
+) {
+    let (pid, tid) = (task.getpid(), task.gettid());
+    let regions = read_regions(pid).unwrap_or_default();
+    let violation = Violation {
+        pid,
+        tid,
+        syscall: SyscallNo::from(regs.orig_rax as i32),
+        args: SyscallArgs::from(
+            regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9,
+        ),
+        reason: String::from(reason),
+        backtrace: backtrace(memory, regs, &regions),
+        recent: RECENT.lock().unwrap_or_else(|e| e.into_inner()).of(tid),
+    };
+    log::error!("{}", violation);
+    VIOLATIONS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(violation);
+    if let Err(err) = signal::kill(pid, Signal::SIGKILL) {
+        log::warn!("[pid {}] failed to kill: {:?}", pid, err);
+    }
+}
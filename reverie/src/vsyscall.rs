@@ -0,0 +1,75 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! syscalls from special mappings
+//!
+//! legacy binaries call into the vsyscall page, which the kernel emulates:
+//! the syscall is reported at the vsyscall address, where there is no
+//! `syscall` instruction to patch (the page might not even be readable).
+//! vdso functions fall back to `syscall` for unsupported clocks, from code
+//! the patcher must not modify. syscalls from these mappings are serviced
+//! by ptrace rather than patched.
+
+use procfs::process::{MMapPath, MemoryMap};
+
+/// legacy vsyscall page, at a fixed address
+pub const VSYSCALL_START: u64 = 0xffff_ffff_ff60_0000;
+pub const VSYSCALL_END: u64 = VSYSCALL_START + 0x1000;
+
+/// special mapping a syscall is made from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialMapping {
+    /// emulated vsyscall page
+    Vsyscall,
+    /// vdso, the `u64` is the offset of the syscall site in vdso
+    Vdso(u64),
+}
+
+/// special mapping `rip` belongs to, given memory `maps` of the process
+pub fn special_mapping_at(
+    maps: &[MemoryMap],
+    rip: u64,
+) -> Option<SpecialMapping> {
+    if (VSYSCALL_START..VSYSCALL_END).contains(&rip) {
+        return Some(SpecialMapping::Vsyscall);
+    }
+    maps.iter()
+        .find(|e| (e.address.0..e.address.1).contains(&rip))
+        .and_then(|e| match e.pathname {
+            MMapPath::Vsyscall => Some(SpecialMapping::Vsyscall),
+            MMapPath::Vdso => Some(SpecialMapping::Vdso(rip - e.address.0)),
+            _ => None,
+        })
+}
+
+#[test]
+fn special_mapping_sanity_check() {
+    let vdso = MemoryMap {
+        address: (0x7fff_0000_0000, 0x7fff_0000_2000),
+        perms: String::from("r-xp"),
+        offset: 0,
+        dev: (0, 0),
+        inode: 0,
+        pathname: MMapPath::Vdso,
+    };
+    let maps = [vdso];
+    assert_eq!(
+        special_mapping_at(&[], VSYSCALL_START + 0x400),
+        Some(SpecialMapping::Vsyscall)
+    );
+    assert_eq!(
+        special_mapping_at(&maps, 0x7fff_0000_0a10),
+        Some(SpecialMapping::Vdso(0xa10))
+    );
+    assert_eq!(special_mapping_at(&maps, 0x7fff_0000_2000), None);
+}
@@ -0,0 +1,91 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! wait status virtualization
+//!
+//! when a `WaitFilterFn` is set, `wait4`/`waitid` are never patched, so
+//! that they always stop at syscall exit, where the child status written
+//! to the tracee is passed to the filter, and rewritten as it returns.
+
+use nix::unistd::Pid;
+use std::io::Result;
+use syscalls::*;
+
+use reverie_api::remote::*;
+use reverie_api::wait::*;
+
+use crate::traced_task::TracedTask;
+
+// offsets in `siginfo_t` for `SIGCHLD`
+const SI_CODE: isize = 8;
+const SI_PID: isize = 16;
+const SI_STATUS: isize = 24;
+
+/// syscalls to be stopped (and never patched) when a filter is set
+pub fn is_wait_syscall(syscall: SyscallNo) -> bool {
+    syscall == SYS_wait4 || syscall == SYS_waitid
+}
+
+/// pass child status returned by a (successful) `wait4`/`waitid` to
+/// `filter`, and write back the new status. `regs` are from the syscall
+/// exit stop.
+pub fn filter_wait_status(
+    task: &TracedTask,
+    regs: &libc::user_regs_struct,
+    filter: &mut WaitFilterFn,
+) -> Result<()> {
+    let retval = regs.rax as i64;
+    match SyscallNo::from(regs.orig_rax as i32) {
+        // wait4(pid, *wstatus, options, *rusage), returns child pid
+        SYS_wait4 if retval > 0 => {
+            let wstatus = match Remoteable::remote(regs.rsi as *mut i32) {
+                Some(wstatus) => wstatus,
+                None => return Ok(()),
+            };
+            let status = task.peek(wstatus)?;
+            if let Some(old) = ChildStatus::from_wstatus(status) {
+                let new = filter(task, Pid::from_raw(retval as i32), old);
+                if new != old {
+                    task.poke(wstatus, &new.to_wstatus())?;
+                }
+            }
+        }
+        // waitid(idtype, id, *infop, options, *rusage), returns 0
+        SYS_waitid if retval == 0 => {
+            let infop = match Remoteable::remote(regs.rdx as *mut i32) {
+                Some(infop) => infop,
+                None => return Ok(()),
+            };
+            let field = |offset: isize| unsafe {
+                infop.cast::<u8>().offset(offset).cast::<i32>()
+            };
+            // `WNOHANG` and no child changed state
+            let pid = task.peek(field(SI_PID))?;
+            if pid == 0 {
+                return Ok(());
+            }
+            let code = task.peek(field(SI_CODE))?;
+            let status = task.peek(field(SI_STATUS))?;
+            if let Some(old) = ChildStatus::from_siginfo(code, status) {
+                let new = filter(task, Pid::from_raw(pid), old);
+                if new != old {
+                    let (code, status) = new.to_siginfo();
+                    task.poke(field(SI_CODE), &code)?;
+                    task.poke(field(SI_STATUS), &status)?;
+                }
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
@@ -0,0 +1,77 @@
+/*
+ * Copyright (c) 2018-2019, Trustees of Indiana University
+ *     ("University Works" via Baojun Wang)
+ * Copyright (c) 2018-2019, Ryan Newton
+ *     ("Traditional Works of Scholarship")
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! the tracer's worker pool, see `reverie_api::workers`
+//!
+//! jobs failing, or panicking, are logged when reaped by the scheduler
+//! loop. jobs are run inline when the queue is full: the scheduler loop is
+//! only delayed once workers are behind, rather than jobs dropped. all
+//! jobs are done before the tracer exits.
+
+use std::io::Result;
+use std::sync::Mutex;
+
+use reverie_api::workers::*;
+
+/// worker threads
+const WORKERS: usize = 2;
+/// jobs queued at most
+const QUEUE_CAPACITY: usize = 64;
+
+lazy_static! {
+    static ref POOL: Mutex<WorkerPool<Result<()>>> =
+        Mutex::new(WorkerPool::new(WORKERS, QUEUE_CAPACITY));
+}
+
+fn log_completion(completion: &Completion<Result<()>>) {
+    match &completion.result {
+        Some(Ok(())) => (),
+        Some(Err(err)) => {
+            log::warn!("[workers] {} failed: {}", completion.name, err)
+        }
+        None => log::warn!("[workers] {} panicked", completion.name),
+    }
+}
+
+/// run `job` by a worker, or inline if workers are behind
+pub fn spawn(name: &str, job: Job<Result<()>>) {
+    let queued = POOL
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .submit(name, job);
+    if let Err(job) = queued {
+        log::debug!("[workers] queue full, {} run inline", name);
+        let result = Some(job());
+        let name = name.to_string();
+        log_completion(&Completion {
+            job: 0,
+            name,
+            result,
+        });
+    }
+}
+
+/// log jobs completed, without blocking
+pub fn reap() {
+    for completion in POOL.lock().unwrap_or_else(|e| e.into_inner()).completed()
+    {
+        log_completion(&completion);
+    }
+}
+
+/// wait for all jobs to complete
+pub fn finish() {
+    for completion in POOL.lock().unwrap_or_else(|e| e.into_inner()).wait() {
+        log_completion(&completion);
+    }
+}
@@ -0,0 +1,57 @@
+/*
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+import java.nio.file.Files;
+import java.nio.file.Path;
+
+/* jit, implicit null checks (SIGSEGV), threads and I/O of the jvm. */
+public class Hello {
+  static int nullChecks(Object[] objs) {
+    int caught = 0;
+    for (Object obj : objs) {
+      try {
+        caught += obj.hashCode() & 0;
+      } catch (NullPointerException e) {
+        caught++;
+      }
+    }
+    return caught;
+  }
+
+  public static void main(String[] args) throws Exception {
+    Object[] objs = new Object[100000];
+    for (int i = 0; i < objs.length; i += 2) {
+      objs[i] = new Object();
+    }
+    int caught = 0;
+    for (int k = 0; k < 20; k++) {
+      caught += nullChecks(objs);
+    }
+    if (caught != 20 * objs.length / 2) {
+      throw new AssertionError("caught " + caught);
+    }
+
+    Thread[] threads = new Thread[4];
+    for (int i = 0; i < threads.length; i++) {
+      threads[i] = new Thread(() -> nullChecks(objs));
+      threads[i].start();
+    }
+    for (Thread thread : threads) {
+      thread.join();
+    }
+
+    Path tmp = Files.createTempFile("reverie", ".txt");
+    Files.writeString(tmp, "hello, world");
+    if (!Files.readString(tmp).equals("hello, world")) {
+      throw new AssertionError("read back");
+    }
+    Files.delete(tmp);
+    System.out.println("hello, world from the jvm");
+  }
+}
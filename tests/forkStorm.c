@@ -0,0 +1,80 @@
+#include <sys/types.h>
+#include <sys/wait.h>
+#include <unistd.h>
+#include <stdlib.h>
+#include <stdio.h>
+#include <signal.h>
+#include <assert.h>
+#include <pthread.h>
+
+// fork thousands of short-lived children, racing the tracer with task
+// death: children exit with live threads (`exit_group` kills them while
+// they may be in ptrace stops), or are killed by the parent right away.
+//
+// usage: forkStorm [children [threads]], see also forkStorm.sh
+
+#define NR_CHILDREN 4096
+#define NR_THREADS  4
+#define MAX_PENDING 64
+
+static int nr_threads = NR_THREADS;
+
+static void* thread_routine(void* param)
+{
+  for (;;) {
+    getppid();
+  }
+  return NULL;
+}
+
+static void child(int i)
+{
+  pthread_t threads[nr_threads];
+
+  if (i % 2 == 0) {
+    for (int j = 0; j < nr_threads; j++) {
+      assert(pthread_create(&threads[j], NULL, thread_routine, NULL) == 0);
+    }
+  }
+  // exit_group, while threads (if any) are still running.
+  exit(0);
+}
+
+int main(int argc, char* argv[])
+{
+  int pending = 0, reaped = 0, status;
+  int nr_children = argc > 1 ? atoi(argv[1]) : NR_CHILDREN;
+  pid_t pid;
+
+  if (argc > 2) {
+    nr_threads = atoi(argv[2]);
+  }
+  assert(nr_children > 0 && nr_threads > 0);
+
+  for (int i = 0; i < nr_children; i++) {
+    pid = fork();
+    if (pid == 0) {
+      child(i);
+    } else if (pid < 0) {
+      perror("fork: ");
+      exit(1);
+    }
+    if (i % 3 == 0) {
+      kill(pid, SIGKILL);
+    }
+    pending++;
+    while (pending >= MAX_PENDING) {
+      if (waitpid(-1, &status, 0) > 0) {
+        pending--;
+        reaped++;
+      }
+    }
+  }
+
+  while (waitpid(-1, &status, 0) > 0) {
+    reaped++;
+  }
+
+  printf("children: expected: %d reaped: %d\n", nr_children, reaped);
+  return reaped == nr_children ? 0 : 1;
+}
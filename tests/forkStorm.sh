@@ -0,0 +1,30 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+# stress harness of the tracer against task death: runs `forkStorm` under
+# reverie for a number of rounds, with more children and threads each
+# round. a round fails if the tracer crashes, hangs, or loses a child.
+#
+# usage: ./forkStorm.sh <reverie command...>, i.e.:
+#   ./forkStorm.sh ../bin/reverie run --tool=... --preloader=... --
+#
+# ROUNDS (default 8) and TIMEOUT (per round, default 120s) can be set in
+# the environment.
+
+ROUNDS=${ROUNDS:-8}
+TIMEOUT=${TIMEOUT:-120s}
+
+if [ $# -eq 0 ]; then
+    echo "usage: $0 <reverie command...>" >&2
+    exit 2
+fi
+
+for round in $(seq 1 "$ROUNDS"); do
+    children=$((1024 * round))
+    threads=$((1 + round % 8))
+    echo "round $round/$ROUNDS: $children children, $threads threads"
+    if ! timeout "$TIMEOUT" "$@" ./forkStorm "$children" "$threads"; then
+        echo "round $round failed" >&2
+        exit 1
+    fi
+done
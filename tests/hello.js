@@ -0,0 +1,34 @@
+/*
+ * Copyright (c) 2020-, Facebook, Inc. and its affiliates.
+ *
+ * All rights reserved.
+ *
+ * This source code is licensed under the BSD-style license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+// jit, timers, the thread pool (fs) and child processes of node/v8.
+const assert = require('assert');
+const fs = require('fs');
+const os = require('os');
+const path = require('path');
+const { execFileSync } = require('child_process');
+
+function fib(n) {
+  return n < 2 ? n : fib(n - 1) + fib(n - 2);
+}
+
+assert.strictEqual(fib(25), 75025);
+
+const tmp = path.join(os.tmpdir(), `reverie-${process.pid}.txt`);
+fs.writeFile(tmp, 'hello, world', (err) => {
+  assert.ifError(err);
+  const text = fs.readFileSync(tmp, 'utf8');
+  assert.strictEqual(text, 'hello, world');
+  fs.unlinkSync(tmp);
+  setTimeout(() => {
+    const out = execFileSync('/bin/echo', ['child']).toString();
+    assert.strictEqual(out, 'child\n');
+    console.log('hello, world from node');
+  }, 10);
+});
@@ -0,0 +1,51 @@
+#include <sys/types.h>
+#include <unistd.h>
+#include <stdlib.h>
+#include <stdio.h>
+#include <string.h>
+#include <assert.h>
+#include <pthread.h>
+
+// exec from a non-leader thread: the kernel kills all other threads
+// (including the leader), and the exec'ing thread takes over the
+// leader's tid.
+
+#define NR_THREADS 4
+
+static char* self;
+
+static void* spin(void* param)
+{
+  for (;;) {
+    getppid();
+  }
+  return NULL;
+}
+
+static void* do_exec(void* param)
+{
+  char* const argv[] = {self, "--execed", NULL};
+  execv(self, argv);
+  perror("execv: ");
+  exit(1);
+}
+
+int main(int argc, char* argv[])
+{
+  pthread_t threads[NR_THREADS];
+
+  if (argc == 2 && strcmp(argv[1], "--execed") == 0) {
+    printf("exec'ed from thread, pid: %u\n", getpid());
+    return 0;
+  }
+
+  self = argv[0];
+  for (int i = 0; i < NR_THREADS - 1; i++) {
+    assert(pthread_create(&threads[i], NULL, spin, NULL) == 0);
+  }
+  assert(pthread_create(&threads[NR_THREADS - 1], NULL, do_exec, NULL) == 0);
+  for (int i = 0; i < NR_THREADS; i++) {
+    pthread_join(threads[i], NULL);
+  }
+  return 1;
+}
@@ -0,0 +1,52 @@
+#include <sys/types.h>
+#include <sys/wait.h>
+#include <unistd.h>
+#include <stdlib.h>
+#include <stdio.h>
+#include <assert.h>
+
+// children which stay zombies for a while before being reaped, and a
+// child which exits without reaping (nor waiting for) its own children.
+
+#define NR_CHILDREN 8
+
+static void orphaner(void)
+{
+  for (int i = 0; i < 2; i++) {
+    pid_t pid = fork();
+    assert(pid >= 0);
+    if (pid == 0) {
+      usleep(i * 100000);
+      exit(0);
+    }
+  }
+  // first grandchild is a zombie by now, the other is still running.
+  usleep(50000);
+  exit(0);
+}
+
+int main(int argc, char* argv[])
+{
+  int reaped = 0, status;
+
+  for (int i = 0; i < NR_CHILDREN; i++) {
+    pid_t pid = fork();
+    assert(pid >= 0);
+    if (pid == 0) {
+      if (i == 0) {
+        orphaner();
+      }
+      exit(i);
+    }
+  }
+
+  // let the children become zombies.
+  usleep(200000);
+
+  while (waitpid(-1, &status, 0) > 0) {
+    reaped++;
+  }
+
+  printf("children: expected: %d reaped: %d\n", NR_CHILDREN, reaped);
+  return reaped == NR_CHILDREN ? 0 : 1;
+}